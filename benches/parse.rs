@@ -0,0 +1,83 @@
+//! Parser and codec benchmarks over synthetic ADT and ORU feeds
+//!
+//! Run with `cargo bench --features bench`. Targets are in the README.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rust_hl7::bench;
+use rust_hl7::Message;
+use std::hint::black_box;
+
+const MESSAGES: usize = 100;
+
+fn corpora() -> [(&'static str, Vec<String>); 2] {
+    [("adt", bench::adt_corpus(MESSAGES)), ("oru", bench::oru_corpus(MESSAGES))]
+}
+
+fn bytes(messages: &[String]) -> u64 {
+    messages.iter().map(|m| m.len() as u64).sum()
+}
+
+/// Whole messages, from text to tree and back
+fn messages(c: &mut Criterion) {
+    for (name, corpus) in corpora() {
+        let mut group = c.benchmark_group(format!("message/{}", name));
+        group.throughput(Throughput::Bytes(bytes(&corpus)));
+        group.bench_function("parse", |b| {
+            b.iter(|| corpus.iter().map(|m| Message::parse(black_box(m)).unwrap().segments.len()).sum::<usize>())
+        });
+        let parsed: Vec<Message> = corpus.iter().map(|m| Message::parse(m).unwrap()).collect();
+        group.bench_function("to_hl7", |b| b.iter(|| parsed.iter().map(|m| black_box(m).to_hl7().len()).sum::<usize>()));
+        group.finish();
+    }
+}
+
+/// Each stage of the parser on its own
+fn stages(c: &mut Criterion) {
+    for (name, corpus) in corpora() {
+        let segments: Vec<&str> = corpus.iter().flat_map(|m| bench::split_segments(m)).collect();
+        let fields: Vec<&str> = segments.iter().flat_map(|s| bench::split_fields(s).into_iter().skip(1)).collect();
+
+        let mut group = c.benchmark_group(format!("stage/{}", name));
+        group.throughput(Throughput::Bytes(bytes(&corpus)));
+        group.bench_function("split_segments", |b| {
+            b.iter(|| corpus.iter().map(|m| bench::split_segments(black_box(m)).len()).sum::<usize>())
+        });
+        group.bench_function("split_fields", |b| {
+            b.iter(|| segments.iter().map(|s| bench::split_fields(black_box(s)).len()).sum::<usize>())
+        });
+        group.bench_function("parse_segment", |b| {
+            b.iter(|| segments.iter().map(|s| bench::parse_segment(black_box(s)).unwrap().fields.len()).sum::<usize>())
+        });
+        group.bench_function("parse_field", |b| {
+            b.iter(|| fields.iter().map(|f| bench::parse_field(black_box(f)).components.len()).sum::<usize>())
+        });
+        group.finish();
+    }
+}
+
+/// Frame extraction from an MLLP stream
+fn frames(c: &mut Criterion) {
+    for (name, corpus) in corpora() {
+        let stream = bench::mllp_stream(&corpus);
+        let mut group = c.benchmark_group(format!("frame/{}", name));
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_function("extract", |b| {
+            b.iter_batched(
+                || BytesMut::from(&stream[..]),
+                |mut buffer| {
+                    let mut count = 0;
+                    while bench::extract_frame(&mut buffer).unwrap().is_some() {
+                        count += 1;
+                    }
+                    count
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, messages, stages, frames);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // The gRPC service and client, generated from proto/hl7.proto with a vendored protoc
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::configure()
+            .compile_protos(&["proto/hl7.proto"], &["proto"])
+            .expect("proto/hl7.proto should compile");
+    }
+}
@@ -0,0 +1,159 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, GenericArgument, LitStr, PathArguments, Type};
+
+/// Derive `rust_hl7::mapping::Hl7Message` for a struct of segments and groups
+///
+/// See the trait for the attributes and how segments are matched.
+#[proc_macro_derive(Hl7Message, attributes(hl7))]
+pub fn derive_hl7_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// What a field is bound to
+enum Binding {
+    Segment(LitStr),
+    Group,
+}
+
+/// How many times a field's segment or group can occur
+#[derive(Clone, Copy)]
+enum Arity {
+    One,
+    Optional,
+    Repeated,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(name, "Hl7Message needs a struct with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(name, "Hl7Message can only be derived for structs")),
+    };
+
+    let mut message_type = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("hl7")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("message") {
+                message_type = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `message = \"...\"`"))
+            }
+        })?;
+    }
+
+    // Segments the struct can start with: each field up to and including the first required one
+    let mut starts = Vec::new();
+    let mut started = false;
+    let mut names = Vec::new();
+    let mut reads = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named field");
+        let binding = binding(field)?;
+        let (arity, inner) = arity(&field.ty);
+
+        let read = match (&binding, arity) {
+            (Binding::Segment(segment), Arity::One) => quote!(cursor.required::<#inner>(#segment)?),
+            (Binding::Segment(segment), Arity::Optional) => quote!(cursor.optional::<#inner>(#segment)?),
+            (Binding::Segment(segment), Arity::Repeated) => quote!(cursor.repeated::<#inner>(#segment)?),
+            (Binding::Group, Arity::One) => quote!(cursor.group::<#inner>()?),
+            (Binding::Group, Arity::Optional) => quote!(cursor.optional_group::<#inner>()?),
+            (Binding::Group, Arity::Repeated) => quote!(cursor.repeated_group::<#inner>()?),
+        };
+        reads.push(quote!(#ident: #read));
+
+        let (start, bound) = match &binding {
+            Binding::Segment(segment) => (quote!(segment == #segment), quote!(names.push(#segment);)),
+            Binding::Group => (
+                quote!(<#inner as ::rust_hl7::mapping::Hl7Message>::starts_with(segment)),
+                quote!(<#inner as ::rust_hl7::mapping::Hl7Message>::segment_names(names);),
+            ),
+        };
+        if !started {
+            starts.push(start);
+            started = matches!(arity, Arity::One);
+        }
+        names.push(bound);
+    }
+    if starts.is_empty() {
+        return Err(syn::Error::new_spanned(name, "Hl7Message needs at least one field"));
+    }
+
+    let check_type = message_type.map(|message_type| {
+        quote! {
+            fn check_type(message: &::rust_hl7::Message) -> ::core::result::Result<(), ::rust_hl7::HL7Error> {
+                ::rust_hl7::mapping::check_message_type(message, #message_type)
+            }
+        }
+    });
+
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rust_hl7::mapping::Hl7Message for #name #type_generics #where_clause {
+            fn starts_with(segment: &str) -> bool {
+                #(#starts)||*
+            }
+
+            fn segment_names(names: &mut ::rust_hl7::__alloc::vec::Vec<&'static str>) {
+                #(#names)*
+            }
+
+            fn from_cursor(
+                cursor: &mut ::rust_hl7::mapping::Cursor<'_>,
+            ) -> ::core::result::Result<Self, ::rust_hl7::HL7Error> {
+                ::core::result::Result::Ok(Self { #(#reads,)* })
+            }
+
+            #check_type
+        }
+    })
+}
+
+/// Read `#[hl7(segment = "OBX")]` or `#[hl7(group)]` from a field
+fn binding(field: &syn::Field) -> syn::Result<Binding> {
+    let mut binding = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("hl7")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("segment") {
+                let segment: LitStr = meta.value()?.parse()?;
+                let value = segment.value();
+                if value.len() != 3 || !value.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+                    return Err(syn::Error::new_spanned(&segment, "expected a segment name like \"PID\""));
+                }
+                binding = Some(Binding::Segment(segment));
+                Ok(())
+            } else if meta.path.is_ident("group") {
+                binding = Some(Binding::Group);
+                Ok(())
+            } else {
+                Err(meta.error("expected `segment = \"...\"` or `group`"))
+            }
+        })?;
+    }
+    binding.ok_or_else(|| syn::Error::new_spanned(field, "expected #[hl7(segment = \"...\")] or #[hl7(group)]"))
+}
+
+/// Split `Option<T>` and `Vec<T>` into how often the field occurs and `T`
+fn arity(ty: &Type) -> (Arity, &Type) {
+    if let Type::Path(path) = ty {
+        if let Some(last) = path.path.segments.last() {
+            let arity = match last.ident.to_string().as_str() {
+                "Option" => Arity::Optional,
+                "Vec" => Arity::Repeated,
+                _ => return (Arity::One, ty),
+            };
+            if let PathArguments::AngleBracketed(args) = &last.arguments {
+                if let Some(GenericArgument::Type(inner)) = args.args.first() {
+                    return (arity, inner);
+                }
+            }
+        }
+    }
+    (Arity::One, ty)
+}
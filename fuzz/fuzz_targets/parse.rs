@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Raw bytes, as they might arrive in an MLLP frame
+fuzz_target!(|data: &[u8]| {
+    let _ = rust_hl7::fuzz::parse(data);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_hl7::Message;
+
+// Serializing a message and parsing it back must settle after one pass: values
+// holding delimiters may be split up the first time, but not again
+fuzz_target!(|message: Message| {
+    let Ok(parsed) = Message::parse(&message.to_hl7()) else {
+        return;
+    };
+    let text = parsed.to_hl7();
+    let reparsed = Message::parse(&text).expect("a serialized message parses");
+    assert_eq!(reparsed.to_hl7(), text);
+    assert_eq!(reparsed.message_type, parsed.message_type);
+    assert_eq!(reparsed.version, parsed.version);
+});
@@ -0,0 +1,100 @@
+#ifndef RUST_HL7_H
+#define RUST_HL7_H
+
+/* Generated by cbindgen from src/ffi.rs; don't edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Bumped whenever a function's signature or behavior changes incompatibly
+#define HL7_ABI_VERSION 1
+
+// A parsed message, owned by the caller until passed to `hl7_message_free`
+typedef struct Hl7Message Hl7Message;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The interface version these functions implement, `HL7_ABI_VERSION`
+uint32_t hl7_abi_version(void);
+
+// The last error on this thread, or NULL if the last call succeeded
+//
+// The string belongs to the library and is valid until the next call on
+// this thread; don't free it.
+const char *hl7_last_error(void);
+
+// Parse `len` bytes of ER7 text, returning NULL if it isn't a valid message
+//
+// # Safety
+//
+// `data` must point to `len` readable bytes. The text needn't be
+// NUL-terminated.
+Hl7Message *hl7_parse(const uint8_t *data, size_t len);
+
+// Release a message; NULL is ignored
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not be used again.
+void hl7_message_free(Hl7Message *message);
+
+// The message as ER7 text, segments separated by `\r`
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not have been freed.
+char *hl7_serialize(const Hl7Message *message);
+
+// The message in its canonical JSON form
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not have been freed.
+char *hl7_to_json(const Hl7Message *message);
+
+// The value at a terser path such as `PID-3.1`
+//
+// Returns NULL if the value is missing, with `hl7_last_error` also NULL, or
+// if the path is invalid, with `hl7_last_error` saying why.
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not have been freed; `path` must
+// be a NUL-terminated string.
+char *hl7_get(const Hl7Message *message, const char *path);
+
+// Set the value at a terser path, returning 0, or -1 if it couldn't be set
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not have been freed; `path` and
+// `value` must be NUL-terminated strings.
+int hl7_set(Hl7Message *message, const char *path, const char *value);
+
+// Validate a message, returning how many errors were found, or -1 if it couldn't be validated
+//
+// `version`, if not NULL, is the HL7 version MSH-12 must be. If `report` isn't
+// NULL, it's set to a JSON array of every error and warning, each with
+// `severity`, `location` and `message`, for the caller to free.
+//
+// # Safety
+//
+// `message` must come from `hl7_parse` and not have been freed; a non-NULL
+// `version` must be a NUL-terminated string and a non-NULL `report` must be
+// writable.
+int hl7_validate(const Hl7Message *message, const char *version, char **report);
+
+// Release a string returned by this library; NULL is ignored
+//
+// # Safety
+//
+// `text` must come from this library and not be used again.
+void hl7_string_free(char *text);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RUST_HL7_H */
@@ -0,0 +1,214 @@
+//! Python bindings for the parser, terser paths and the MLLP client
+//!
+//! Built with maturin into a module named `rust_hl7`:
+//!
+//! ```python
+//! import rust_hl7
+//!
+//! msg = rust_hl7.parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rPID|1||12345||Doe^John")
+//! msg["PID-5.1"]              # "Doe"
+//! msg["PID-5.1"] = "DOE"
+//! msg.to_json()
+//!
+//! client = rust_hl7.MllpClient("engine:2575", timeout=10)
+//! ack = client.send(msg)
+//! ack["MSA-1"]                # "AA"
+//! ```
+//!
+//! Parse failures raise `rust_hl7.HL7Error` (a `ValueError`); failed sends
+//! raise `rust_hl7.MllpError` (an `OSError`).
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use rust_hl7::mllp::MllpClient as Client;
+use rust_hl7::{terser, Message as Hl7Message};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+create_exception!(rust_hl7, HL7Error, PyValueError, "A message couldn't be parsed or changed.");
+create_exception!(rust_hl7, MllpError, PyOSError, "A message couldn't be sent, or no response came back.");
+
+fn hl7_error(error: rust_hl7::HL7Error) -> PyErr {
+    HL7Error::new_err(error.to_string())
+}
+
+fn mllp_error<E: ToString>(error: E) -> PyErr {
+    MllpError::new_err(error.to_string())
+}
+
+/// A parsed HL7 v2 message
+#[pyclass(name = "Message", module = "rust_hl7", from_py_object)]
+#[derive(Clone)]
+struct Message {
+    inner: Hl7Message,
+}
+
+#[pymethods]
+impl Message {
+    /// Parse a message from its ER7 (pipe-delimited) text
+    #[new]
+    fn new(text: &str) -> PyResult<Self> {
+        parse(text)
+    }
+
+    /// Read a message from its canonical JSON form
+    #[staticmethod]
+    fn from_json(json: &str) -> PyResult<Self> {
+        Ok(Self {
+            inner: Hl7Message::from_json(json).map_err(hl7_error)?,
+        })
+    }
+
+    /// The value at a terser path such as `PID-3.1` or `OBX(2)-5`, or None if it's missing
+    fn get(&self, path: &str) -> Option<String> {
+        terser::get(&self.inner, path)
+    }
+
+    /// Set the value at a terser path, adding fields and components as needed; the segment must exist
+    fn set(&mut self, path: &str, value: &str) -> PyResult<()> {
+        terser::set(&mut self.inner, path, value).map_err(hl7_error)
+    }
+
+    fn __getitem__(&self, path: &str) -> Option<String> {
+        self.get(path)
+    }
+
+    fn __setitem__(&mut self, path: &str, value: &str) -> PyResult<()> {
+        self.set(path, value)
+    }
+
+    fn __contains__(&self, path: &str) -> bool {
+        self.get(path).is_some_and(|value| !value.is_empty())
+    }
+
+    /// MSH-9 as `type^trigger`, e.g. `ADT^A01`
+    #[getter]
+    fn message_type(&self) -> String {
+        self.inner.message_type.clone()
+    }
+
+    /// MSH-12
+    #[getter]
+    fn version(&self) -> String {
+        self.inner.version.clone()
+    }
+
+    /// MSH-10
+    #[getter]
+    fn control_id(&self) -> Option<String> {
+        self.inner.control_id().map(str::to_string)
+    }
+
+    /// The segment names, in order
+    #[getter]
+    fn segments(&self) -> Vec<String> {
+        self.inner.segments.iter().map(|segment| segment.name.clone()).collect()
+    }
+
+    /// The message as ER7 text, segments separated by `\r`
+    fn to_hl7(&self) -> String {
+        self.inner.to_hl7()
+    }
+
+    /// The message in its canonical JSON form
+    fn to_json(&self) -> String {
+        self.inner.to_json()
+    }
+
+    /// One segment per line with each field's name, for reading
+    fn pretty(&self) -> String {
+        self.inner.pretty_print()
+    }
+
+    fn __str__(&self) -> String {
+        self.inner.to_hl7()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Message(type={:?}, control_id={:?}, segments={})",
+            self.inner.message_type,
+            self.inner.control_id().unwrap_or_default(),
+            self.inner.segments.len()
+        )
+    }
+}
+
+/// A message to send: a `Message` or its ER7 text
+#[derive(FromPyObject)]
+enum Outgoing {
+    Message(Message),
+    Text(String),
+}
+
+/// Sends messages over MLLP and waits for each response
+///
+/// Calls block, but release the GIL while waiting, so other threads keep running.
+#[pyclass(name = "MllpClient", module = "rust_hl7")]
+struct MllpClient {
+    client: Client,
+    runtime: Runtime,
+}
+
+#[pymethods]
+impl MllpClient {
+    /// Connect to `host:port`; `timeout` is in seconds, `tls` verifies the server
+    /// against the system's CAs and any in the PEM file `ca_file`
+    #[new]
+    #[pyo3(signature = (address, timeout = 30.0, tls = false, ca_file = None))]
+    fn new(address: &str, timeout: f64, tls: bool, ca_file: Option<PathBuf>) -> PyResult<Self> {
+        let timeout = Duration::try_from_secs_f64(timeout).map_err(|e| PyValueError::new_err(format!("Invalid timeout: {}", e)))?;
+        let mut client = Client::new(address).with_timeout(timeout);
+        if tls || ca_file.is_some() {
+            client = client.with_tls(rust_hl7::tls::client_config(ca_file.as_deref()).map_err(mllp_error)?);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(mllp_error)?;
+        Ok(Self { client, runtime })
+    }
+
+    /// The address messages are sent to
+    #[getter]
+    fn address(&self) -> String {
+        self.client.address().to_string()
+    }
+
+    /// Send a message and return the response, normally an ACK
+    fn send(&self, py: Python<'_>, message: Outgoing) -> PyResult<Message> {
+        let text = match message {
+            Outgoing::Message(message) => message.inner.to_hl7(),
+            Outgoing::Text(text) => text,
+        };
+        let response = py
+            .detach(|| self.runtime.block_on(self.client.send(&text)))
+            .map_err(mllp_error)?;
+        parse(&response)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("MllpClient({:?})", self.client.address())
+    }
+}
+
+/// Parse a message from its ER7 (pipe-delimited) text
+#[pyfunction]
+fn parse(text: &str) -> PyResult<Message> {
+    Ok(Message {
+        inner: Hl7Message::parse(text).map_err(hl7_error)?,
+    })
+}
+
+#[pymodule]
+#[pyo3(name = "rust_hl7")]
+fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_class::<Message>()?;
+    m.add_class::<MllpClient>()?;
+    m.add("HL7Error", m.py().get_type::<HL7Error>())?;
+    m.add("MllpError", m.py().get_type::<MllpError>())?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+#!/usr/bin/env python3
+"""Tests for the Python bindings; run after `maturin develop` in python/."""
+import socket
+import threading
+import unittest
+
+import rust_hl7
+
+ORU = "MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|MSG1|P|2.5\rPID|1||12345^^^MRN||Doe^John\rOBX|1|NM|GLU^Glucose||105|mg/dL"
+
+
+def mllp_server(response):
+    """Answer one MLLP message with `response`, returning the port and what was received"""
+    listener = socket.socket()
+    listener.bind(("127.0.0.1", 0))
+    listener.listen(1)
+    received = []
+
+    def serve():
+        conn, _ = listener.accept()
+        data = b""
+        while not data.endswith(b"\x1c\r"):
+            data += conn.recv(4096)
+        received.append(data[1:-2].decode())
+        conn.sendall(b"\x0b" + response.encode() + b"\x1c\r")
+        conn.close()
+        listener.close()
+
+    threading.Thread(target=serve, daemon=True).start()
+    return listener.getsockname()[1], received
+
+
+class BindingTests(unittest.TestCase):
+    def test_parse_and_terser(self):
+        msg = rust_hl7.parse(ORU)
+        self.assertEqual(msg.message_type, "ORU^R01")
+        self.assertEqual(msg.version, "2.5")
+        self.assertEqual(msg.control_id, "MSG1")
+        self.assertEqual(msg.segments, ["MSH", "PID", "OBX"])
+        self.assertEqual(msg["PID-5.1"], "Doe")
+        self.assertEqual(msg.get("OBX-5"), "105")
+        self.assertIsNone(msg.get("NK1-2"))
+        self.assertIn("PID-3.1", msg)
+
+        msg["PID-5.1"] = "DOE"
+        msg.set("OBX-8", "H")
+        self.assertEqual(msg["PID-5.1"], "DOE")
+        self.assertTrue(str(msg).endswith("|105|mg/dL||H"))
+        with self.assertRaises(rust_hl7.HL7Error):
+            msg.set("ZPI-1", "x")
+
+    def test_serialization(self):
+        msg = rust_hl7.Message(ORU)
+        self.assertEqual(msg.to_hl7(), ORU)
+        self.assertEqual(rust_hl7.Message.from_json(msg.to_json()).to_hl7(), ORU)
+        self.assertIn("Glucose", msg.pretty())
+
+    def test_errors(self):
+        with self.assertRaises(rust_hl7.HL7Error):
+            rust_hl7.parse("PID|1||12345")
+        with self.assertRaises(ValueError):
+            rust_hl7.Message.from_json("{}")
+
+    def test_mllp_client(self):
+        port, received = mllp_server("MSH|^~\\&|EHR|HOSP|LAB|HOSP|20240501||ACK^R01|ACK1|P|2.5\rMSA|AA|MSG1")
+        client = rust_hl7.MllpClient(f"127.0.0.1:{port}", timeout=5)
+        ack = client.send(rust_hl7.parse(ORU))
+        self.assertEqual(ack["MSA-1"], "AA")
+        self.assertEqual(received, [ORU])
+
+        with self.assertRaises(rust_hl7.MllpError):
+            rust_hl7.MllpClient(f"127.0.0.1:{port}", timeout=1).send(ORU)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,195 @@
+use crate::config::ConfigError;
+use crate::protect::FieldPolicy;
+use crate::store::{ArchiveRecord, MessageStore, Query, StoreError};
+use crate::HL7Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Errors that can occur reading the archive on behalf of a role
+#[derive(Debug, Error)]
+pub enum AccessError {
+    #[error("Unknown role: {0}")]
+    UnknownRole(String),
+
+    #[error("Role {role} can't read {message_type} messages")]
+    Forbidden { role: String, message_type: String },
+
+    #[error("Store error: {0}")]
+    StoreError(#[from] StoreError),
+}
+
+/// What one kind of user may read from the archive
+///
+/// Message types are prefixes, as in queries: "ORU" allows every ORU message
+/// and "ADT^A08" only updates. "*" allows every type. Masked paths name a
+/// field, component or subcomponent, e.g. "PID-19" or "PID-5.1", and are
+/// replaced with `REDACTED` in every message the role reads, in every
+/// repetition of the field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Role {
+    pub message_types: Vec<String>,
+    pub mask: Vec<String>,
+}
+
+impl Role {
+    /// A role that can read messages of these types
+    pub fn new<I, S>(message_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            message_types: message_types.into_iter().map(|t| t.to_string()).collect(),
+            mask: Vec::new(),
+        }
+    }
+
+    /// Also mask the value at a path
+    pub fn mask<S: ToString>(mut self, path: S) -> Self {
+        self.mask.push(path.to_string());
+        self
+    }
+
+    /// Whether the role can read messages of this type
+    pub fn allows(&self, message_type: Option<&str>) -> bool {
+        self.message_types.iter().any(|allowed| {
+            allowed == "*" || message_type.is_some_and(|message_type| message_type.starts_with(allowed.as_str()))
+        })
+    }
+
+    /// Whether a query for this type prefix could return anything the role can read
+    fn overlaps(&self, requested: &str) -> bool {
+        self.message_types
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.starts_with(requested) || requested.starts_with(allowed.as_str()))
+    }
+}
+
+/// The roles that may read the archive, usually loaded from a file
+///
+/// ```toml
+/// [roles.lab]
+/// message_types = ["ORU", "ORM"]
+/// mask = ["PID-19"]
+///
+/// [roles.registration]
+/// message_types = ["ADT"]
+/// mask = ["PID-19", "OBX-5"]
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    roles: HashMap<String, (Role, FieldPolicy)>,
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    roles: HashMap<String, Role>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a role, failing if a masked path is invalid
+    pub fn role<S: ToString>(mut self, name: S, role: Role) -> Result<Self, HL7Error> {
+        let mask = role.mask.iter().try_fold(FieldPolicy::new(), |policy, path| policy.redact(path))?;
+        self.roles.insert(name.to_string(), (role, mask));
+        Ok(self)
+    }
+
+    /// Load roles from a TOML, YAML or JSON file, chosen by extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let file: PolicyFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?,
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?
+            }
+            Some("json") => serde_json::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?,
+            _ => return Err(ConfigError::ParseError(format!("Unknown roles format: {}", path.display()))),
+        };
+        file.roles.into_iter().try_fold(Self::new(), |policy, (name, role)| {
+            policy
+                .role(&name, role)
+                .map_err(|e| ConfigError::Invalid(format!("role {}: {}", name, e)))
+        })
+    }
+
+    /// Names of the roles, sorted
+    pub fn roles(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.roles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// Read-only access to the archive, scoped by role
+///
+/// Each query only returns message types the role is allowed, with the
+/// role's masked values redacted, so lookups can be handed to staff who
+/// shouldn't see everything. Asking for a type the role can't read is an
+/// error rather than an empty result, so it isn't mistaken for no traffic.
+/// Callers are responsible for establishing who is asking.
+///
+/// ```ignore
+/// let policy = AccessPolicy::load("roles.toml")?;
+/// let archive = ScopedArchive::new(store, policy);
+/// let results = archive.query("lab", &Query::new().patient_id("12345"))?;
+/// ```
+#[derive(Clone)]
+pub struct ScopedArchive {
+    store: Arc<dyn MessageStore>,
+    policy: AccessPolicy,
+}
+
+impl std::fmt::Debug for ScopedArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedArchive").field("roles", &self.policy.roles()).finish_non_exhaustive()
+    }
+}
+
+impl ScopedArchive {
+    pub fn new(store: Arc<dyn MessageStore>, policy: AccessPolicy) -> Self {
+        Self { store, policy }
+    }
+
+    /// Find the records matching the query that `role` can read, oldest first, masked
+    pub fn query(&self, role: &str, query: &Query) -> Result<Vec<ArchiveRecord>, AccessError> {
+        let (scope, mask) = self
+            .policy
+            .roles
+            .get(role)
+            .ok_or_else(|| AccessError::UnknownRole(role.to_string()))?;
+
+        let mut scoped = query.clone();
+        match (&query.message_type, scope.message_types.as_slice()) {
+            (Some(requested), _) if !scope.overlaps(requested) => {
+                return Err(AccessError::Forbidden {
+                    role: role.to_string(),
+                    message_type: requested.clone(),
+                });
+            }
+            // Everything the query matches is readable, so the store can apply the limit
+            (Some(requested), _) if scope.allows(Some(requested)) => {}
+            (None, [only]) if only != "*" => scoped.message_type = Some(only.clone()),
+            (None, allowed) if allowed.iter().any(|t| t == "*") => {}
+            // Otherwise the limit applies to what's left after filtering
+            _ => scoped.limit = None,
+        }
+
+        self.store
+            .query(&scoped)?
+            .into_iter()
+            .filter(|record| scope.allows(record.message_type.as_deref()))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|record| Ok(mask.protect_record(&record)?))
+            .collect()
+    }
+}
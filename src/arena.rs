@@ -0,0 +1,187 @@
+//! Parsing into a bump arena, for high-volume feeds
+//!
+//! `Message::parse` gives every segment name, field and component its own
+//! `String`. `ArenaMessage::parse` builds the same tree out of slices of the
+//! input instead, with the slices holding them allocated from a `Bump` that
+//! can be reset and reused for the next message. Values stay escaped, as
+//! they are in `Message`. Use `to_message` to keep a message past the reset.
+//!
+//! ```
+//! use rust_hl7::arena::{ArenaMessage, Bump};
+//!
+//! let mut bump = Bump::new();
+//! for text in ["MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rOBX|1|NM|WBC||10.5|10*3/uL"] {
+//!     let message = ArenaMessage::parse(text, &bump).unwrap();
+//!     let obx = message.get_segment("OBX").unwrap();
+//!     assert_eq!(obx.field(6).and_then(|f| f.as_str()), Some("10*3/uL"));
+//!     bump.reset();
+//! }
+//! ```
+
+#[cfg(not(feature = "std"))]
+use crate::prelude::*;
+use crate::{scan, terser, Component, Delimiters, Field, HL7Error, Message, Segment};
+use bumpalo::collections::Vec as BumpVec;
+
+pub use bumpalo::Bump;
+
+/// A message parsed into an arena, borrowing from it and from the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaMessage<'a> {
+    pub segments: &'a [ArenaSegment<'a>],
+    pub message_type: &'a str,
+    pub version: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaSegment<'a> {
+    pub name: &'a str,
+    pub fields: &'a [ArenaField<'a>],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaField<'a> {
+    pub components: &'a [ArenaComponent<'a>],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaComponent<'a> {
+    pub value: &'a str,
+    pub subcomponents: &'a [&'a str],
+}
+
+impl<'a> ArenaMessage<'a> {
+    /// Parse a message, failing where `Message::parse` would
+    ///
+    /// The input is only copied into the arena if it declares delimiters
+    /// other than the standard ones and has to be rewritten.
+    pub fn parse(input: &'a str, bump: &'a Bump) -> Result<Self, HL7Error> {
+        let input: &'a str = match Delimiters::from_header(input) {
+            Some(declared) if declared != Delimiters::default() => bump.alloc_str(&declared.standardize(input)),
+            _ => input,
+        };
+
+        let delimiters = Delimiters::default();
+        let segments = BumpVec::from_iter_in(scan::lines(input).map(|line| parse_segment(line, &delimiters, bump)), bump)
+            .into_bump_slice();
+
+        let msh = match segments.first() {
+            None => return Err(HL7Error::InvalidStructure("Empty message".to_string())),
+            Some(msh) if msh.name != "MSH" => {
+                return Err(HL7Error::InvalidStructure("First segment must be MSH".to_string()))
+            }
+            Some(msh) => msh,
+        };
+        let mut type_parts = msh
+            .field(9)
+            .map_or(&[][..], |field| field.components)
+            .iter()
+            .take(2)
+            .map(|c| c.value)
+            .filter(|v| !v.is_empty());
+        let message_type = match (type_parts.next(), type_parts.next()) {
+            (None, _) => return Err(HL7Error::MissingField("Message type (MSH.9)".to_string())),
+            (Some(code), None) => code,
+            (Some(code), Some(event)) => bumpalo::format!(in bump, "{}^{}", code, event).into_bump_str(),
+        };
+        let version = msh
+            .field(12)
+            .and_then(|field| field.as_str())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| HL7Error::MissingField("Version (MSH.12)".to_string()))?;
+
+        Ok(Self {
+            segments,
+            message_type,
+            version,
+        })
+    }
+
+    /// Get the first segment with a name
+    pub fn get_segment(&self, name: &str) -> Option<&'a ArenaSegment<'a>> {
+        self.segments.iter().find(|s| s.name == name)
+    }
+
+    /// Get all segments with a name
+    pub fn get_segments<'n>(&self, name: &'n str) -> impl Iterator<Item = &'a ArenaSegment<'a>> + 'n
+    where
+        'a: 'n,
+    {
+        self.segments.iter().filter(move |s| s.name == name)
+    }
+
+    /// Copy the message out of the arena
+    pub fn to_message(&self) -> Message {
+        Message {
+            segments: self.segments.iter().map(Segment::from).collect(),
+            message_type: self.message_type.to_string(),
+            version: self.version.to_string(),
+        }
+    }
+}
+
+impl<'a> ArenaSegment<'a> {
+    /// The field at a 1-based position, numbered as in terser paths
+    ///
+    /// MSH-1 isn't stored, being the field separator, so it gives `None`.
+    pub fn field(&self, number: usize) -> Option<&'a ArenaField<'a>> {
+        self.fields.get(terser::field_index(self.name, number)?)
+    }
+}
+
+impl<'a> ArenaField<'a> {
+    /// The component at a 1-based position
+    pub fn component(&self, number: usize) -> Option<&'a ArenaComponent<'a>> {
+        self.components.get(number.checked_sub(1)?)
+    }
+
+    /// The text of the first component
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.components.first().map(|c| c.value)
+    }
+}
+
+impl From<&ArenaSegment<'_>> for Segment {
+    fn from(segment: &ArenaSegment<'_>) -> Self {
+        Segment::new(segment.name, segment.fields.iter().map(Field::from).collect())
+    }
+}
+
+impl From<&ArenaField<'_>> for Field {
+    fn from(field: &ArenaField<'_>) -> Self {
+        Field {
+            components: field
+                .components
+                .iter()
+                .map(|c| Component {
+                    value: c.value.to_string(),
+                    subcomponents: c.subcomponents.iter().map(|s| s.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn parse_segment<'a>(line: &'a str, delimiters: &Delimiters, bump: &'a Bump) -> ArenaSegment<'a> {
+    let mut parts = scan::split(line, delimiters.field);
+    let name = parts.next().unwrap_or_default();
+    let fields = BumpVec::from_iter_in(
+        parts.map(|field| ArenaField {
+            components: BumpVec::from_iter_in(
+                scan::split(field, delimiters.component).map(|value| ArenaComponent {
+                    value,
+                    subcomponents: if scan::contains(value, delimiters.subcomponent) {
+                        BumpVec::from_iter_in(scan::split(value, delimiters.subcomponent), bump).into_bump_slice()
+                    } else {
+                        &[]
+                    },
+                }),
+                bump,
+            )
+            .into_bump_slice(),
+        }),
+        bump,
+    )
+    .into_bump_slice();
+    ArenaSegment { name, fields }
+}
@@ -0,0 +1,344 @@
+use crate::retention::overwrite;
+use crate::store::{ArchiveRecord, MessageStore, Query, StoreError};
+use crate::terser::field_index;
+use crate::{charset, Delimiters, Message};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Start of an offloaded value: "BLOB:<key>"
+pub const REFERENCE: &str = "BLOB:";
+
+/// Where documents offloaded from the archive are kept
+///
+/// Keys are made of digits, lowercase letters, '-' and '/', and sort in the
+/// order their messages were archived, so the oldest can be deleted by key.
+/// Besides `FileBlobStore`, an S3 bucket or similar can implement this, with
+/// `delete_before` listing keys in order.
+pub trait BlobStore: Send + Sync {
+    /// Store a document under a key, replacing any already there
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), StoreError>;
+
+    /// Read back a document
+    fn get(&self, key: &str) -> Result<Vec<u8>, StoreError>;
+
+    /// Delete every document whose key sorts before `key`, returning how many were removed
+    fn delete_before(&self, key: &str) -> Result<usize, StoreError>;
+
+    /// Whether deleted documents are overwritten rather than just unlinked
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        let _ = secure_delete;
+        Ok(())
+    }
+
+    /// Check that documents could be stored right now
+    fn check_writable(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Documents kept as files in a directory, one subdirectory per day
+#[derive(Debug)]
+pub struct FileBlobStore {
+    directory: PathBuf,
+    secure_delete: AtomicBool,
+}
+
+impl FileBlobStore {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+            secure_delete: AtomicBool::new(false),
+        }
+    }
+
+    /// Where a key's document is, refusing keys that could escape the directory
+    fn path(&self, key: &str) -> Result<PathBuf, StoreError> {
+        let valid = !key.is_empty()
+            && !key.starts_with('/')
+            && !key.contains("..")
+            && key.chars().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase() || c == '-' || c == '/');
+        match valid {
+            true => Ok(self.directory.join(key)),
+            false => Err(StoreError::BlobError(format!("invalid key '{}'", key))),
+        }
+    }
+}
+
+fn blob_error(key: &str, error: io::Error) -> StoreError {
+    StoreError::BlobError(format!("{}: {}", key, error))
+}
+
+impl BlobStore for FileBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), StoreError> {
+        let path = self.path(key)?;
+        let write = || -> io::Result<()> {
+            let parent = path.parent().expect("keys are inside the directory");
+            fs::create_dir_all(parent)?;
+            // Written under a hidden name first, so a crash never leaves half a document
+            let temp = parent.join(format!(".{}.tmp", path.file_name().unwrap_or_default().to_string_lossy()));
+            fs::write(&temp, data)?;
+            fs::File::open(&temp)?.sync_all()?;
+            fs::rename(&temp, &path)
+        };
+        write().map_err(|e| blob_error(key, e))
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        fs::read(self.path(key)?).map_err(|e| blob_error(key, e))
+    }
+
+    fn delete_before(&self, key: &str) -> Result<usize, StoreError> {
+        if !self.directory.exists() {
+            return Ok(0);
+        }
+        let secure_delete = self.secure_delete.load(Ordering::Relaxed);
+        let mut deleted = 0;
+        let delete = |deleted: &mut usize| -> io::Result<()> {
+            for day in fs::read_dir(&self.directory)? {
+                let day = day?;
+                let day_name = day.file_name().to_string_lossy().into_owned();
+                if !day.file_type()?.is_dir() || day_name.as_str() > key {
+                    continue;
+                }
+                for entry in fs::read_dir(day.path())? {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with('.') || format!("{}/{}", day_name, name).as_str() >= key {
+                        continue;
+                    }
+                    if secure_delete {
+                        overwrite(&entry.path(), entry.metadata()?.len())?;
+                    }
+                    fs::remove_file(entry.path())?;
+                    *deleted += 1;
+                }
+                // Only succeeds once the day is empty
+                let _ = fs::remove_dir(day.path());
+            }
+            Ok(())
+        };
+        delete(&mut deleted).map_err(|e| blob_error(key, e))?;
+        Ok(deleted)
+    }
+
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        self.secure_delete.store(secure_delete, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        let probe = self.directory.join(".probe");
+        let check = || -> io::Result<()> {
+            fs::create_dir_all(&self.directory)?;
+            fs::write(&probe, b"")?;
+            fs::remove_file(&probe)
+        };
+        check().map_err(|e| StoreError::BlobError(format!("{}: {}", self.directory.display(), e)))
+    }
+}
+
+/// The key of a document archived at `timestamp`, sorting by time and then content
+fn blob_key(timestamp: DateTime<Utc>, data: &[u8]) -> String {
+    let hash: String = Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", bound(timestamp), hash)
+}
+
+/// The key of the list of documents offloaded from a record, stored as `raw`
+fn manifest_key(timestamp: DateTime<Utc>, raw: &[u8]) -> String {
+    format!("{}-refs", blob_key(timestamp, raw))
+}
+
+/// The smallest key of documents archived at or after `timestamp`
+fn bound(timestamp: DateTime<Utc>) -> String {
+    format!("{}/{:016}", timestamp.format("%Y%m%d"), timestamp.timestamp_millis().max(0))
+}
+
+/// An archive that keeps large embedded documents out of the underlying store
+///
+/// When a record is inserted, the data (OBX-5.5) of each ED observation at
+/// least `min_bytes` long is moved to a `BlobStore` and replaced with
+/// "BLOB:<key>". The archive stays small and quick to query, with the same
+/// metadata as before. Queries put the documents back, so replay and lookups
+/// see the message as it was received; a document that can't be read is
+/// logged and left as its reference. The keys offloaded from each record are
+/// listed in the blob store next to its documents, and only those are put
+/// back, so a "BLOB:" reference that came in with a message is left alone. Pruning the archive also deletes the
+/// documents of the messages pruned.
+///
+/// ```ignore
+/// let store = OffloadStore::new(Arc::new(SqliteStore::open("messages.db")?), Arc::new(FileBlobStore::new("messages.blobs")))
+///     .min_bytes(16 * 1024);
+/// ```
+pub struct OffloadStore {
+    inner: Arc<dyn MessageStore>,
+    blobs: Arc<dyn BlobStore>,
+    min_bytes: usize,
+}
+
+impl OffloadStore {
+    /// Offload documents of 64 KiB or more from records stored in `inner`
+    pub fn new(inner: Arc<dyn MessageStore>, blobs: Arc<dyn BlobStore>) -> Self {
+        Self {
+            inner,
+            blobs,
+            min_bytes: 64 * 1024,
+        }
+    }
+
+    /// Offload documents at least this long; `usize::MAX` only reads documents back
+    pub fn min_bytes(mut self, min_bytes: usize) -> Self {
+        self.min_bytes = min_bytes;
+        self
+    }
+
+    /// Move a record's large documents to the blob store
+    fn offload(&self, record: &ArchiveRecord) -> Result<Option<ArchiveRecord>, StoreError> {
+        if record.raw.len() < self.min_bytes {
+            return Ok(None);
+        }
+        let charset = charset::detect(&record.raw).unwrap_or_default();
+        let Some(mut message) = charset.decode(&record.raw).ok().and_then(|text| Message::parse(&text).ok()) else {
+            return Ok(None);
+        };
+        let mut keys = Vec::new();
+        for data in documents(&mut message) {
+            // Repeated observations would be split apart, so they stay where they are
+            if data.value.len() < self.min_bytes
+                || data.value.starts_with(REFERENCE)
+                || data.value.contains(Delimiters::default().repetition)
+            {
+                continue;
+            }
+            let key = blob_key(record.timestamp, data.value.as_bytes());
+            self.blobs.put(&key, data.value.as_bytes())?;
+            data.value = format!("{}{}", REFERENCE, key);
+            data.subcomponents.clear();
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let raw = charset.encode(&message.to_hl7());
+        self.blobs.put(&manifest_key(record.timestamp, &raw), keys.join("\n").as_bytes())?;
+        Ok(Some(ArchiveRecord {
+            raw,
+            ..record.clone()
+        }))
+    }
+
+    /// The keys of the documents offloaded from a stored record, if any were
+    fn offloaded_keys(&self, record: &ArchiveRecord) -> Option<HashSet<String>> {
+        let key = manifest_key(record.timestamp, &record.raw);
+        match self.blobs.get(&key) {
+            Ok(manifest) => Some(String::from_utf8_lossy(&manifest).lines().map(str::to_string).collect()),
+            Err(e) => {
+                debug!("No documents offloaded from archived message {:?}: {}", record.id, e);
+                None
+            }
+        }
+    }
+
+    /// Put a record's offloaded documents back
+    fn restore(&self, record: &mut ArchiveRecord) {
+        if !record.raw.windows(REFERENCE.len()).any(|w| w == REFERENCE.as_bytes()) {
+            return;
+        }
+        let Some(keys) = self.offloaded_keys(record) else {
+            return;
+        };
+        let charset = charset::detect(&record.raw).unwrap_or_default();
+        let Some(mut message) = charset.decode(&record.raw).ok().and_then(|text| Message::parse(&text).ok()) else {
+            return;
+        };
+        let mut restored = false;
+        for data in documents(&mut message) {
+            let Some(key) = data.value.strip_prefix(REFERENCE).filter(|key| keys.contains(*key)) else {
+                continue;
+            };
+            match self.blobs.get(key).and_then(|bytes| {
+                String::from_utf8(bytes).map_err(|e| StoreError::BlobError(format!("{}: {}", key, e)))
+            }) {
+                Ok(value) => {
+                    data.subcomponents = crate::parse_component(&value, &Delimiters::default()).subcomponents;
+                    data.value = value;
+                    restored = true;
+                }
+                Err(e) => warn!("Returning archived message {:?} without its document: {}", record.id, e),
+            }
+        }
+        if restored {
+            record.raw = charset.encode(&message.to_hl7());
+        }
+    }
+}
+
+/// The data components of a message's ED observations
+fn documents(message: &mut Message) -> impl Iterator<Item = &mut crate::Component> {
+    let (value_type, value) = (field_index("OBX", 2).expect("OBX-2"), field_index("OBX", 5).expect("OBX-5"));
+    message
+        .segments
+        .iter_mut()
+        .filter(move |segment| {
+            segment.name == "OBX"
+                && segment.fields.get(value_type).is_some_and(|f| f.to_hl7(&Delimiters::default()) == "ED")
+        })
+        .filter_map(move |segment| segment.fields.get_mut(value)?.components.get_mut(4))
+}
+
+impl MessageStore for OffloadStore {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        match self.offload(record)? {
+            Some(offloaded) => self.inner.insert(&offloaded),
+            None => self.inner.insert(record),
+        }
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let mut records = self.inner.query(query)?;
+        for record in &mut records {
+            self.restore(record);
+        }
+        Ok(records)
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let pruned = self.inner.prune(before)?;
+        self.blobs.delete_before(&bound(before))?;
+        Ok(pruned)
+    }
+
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        let pruned = self.inner.prune_excess(max_count, max_bytes)?;
+        if pruned > 0 {
+            // The oldest records went first, so documents older than what's left are unused
+            match self.inner.query(&Query::new().limit(1))?.first() {
+                Some(oldest) => self.blobs.delete_before(&bound(oldest.timestamp))?,
+                None => self.blobs.delete_before("~")?,
+            };
+        }
+        Ok(pruned)
+    }
+
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        self.blobs.set_secure_delete(secure_delete)?;
+        self.inner.set_secure_delete(secure_delete)
+    }
+
+    fn vacuum(&self) -> Result<(), StoreError> {
+        self.inner.vacuum()
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        if self.min_bytes < usize::MAX {
+            self.blobs.check_writable()?;
+        }
+        self.inner.check_writable()
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{charset, terser, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// The `prev_hash` of the first entry in a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Audit log line {line} is not a valid entry: {reason}")]
+    InvalidEntry { line: usize, reason: String },
+    #[error("Audit log chain is broken at line {line}: {reason}")]
+    BrokenChain { line: usize, reason: String },
+}
+
+/// What happened to an audited message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditDisposition {
+    /// Received and positively acknowledged
+    Accepted,
+    /// Received and rejected by a handler or middleware (AR)
+    Rejected,
+    /// Received but couldn't be decoded, parsed or processed (AE)
+    Failed,
+    /// Handed to a destination
+    Forwarded,
+}
+
+impl From<crate::store::Disposition> for AuditDisposition {
+    fn from(disposition: crate::store::Disposition) -> Self {
+        use crate::store::Disposition;
+        match disposition {
+            Disposition::Accepted => AuditDisposition::Accepted,
+            Disposition::Rejected => AuditDisposition::Rejected,
+            Disposition::Failed => AuditDisposition::Failed,
+            Disposition::Sent => AuditDisposition::Forwarded,
+        }
+    }
+}
+
+/// Who sent what about whom
+///
+/// The payload itself isn't kept, only its SHA-256, so the log can show a
+/// message was received unchanged without holding another copy of the PHI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: DateTime<Utc>,
+    /// Where the message came from, e.g. the peer address of an MLLP connection
+    pub source: Option<String>,
+    /// Sending application and facility (MSH-3.1^MSH-4.1)
+    pub sender: Option<String>,
+    /// MSH-9, e.g. "ADT^A01"
+    pub message_type: Option<String>,
+    /// MSH-10
+    pub control_id: Option<String>,
+    /// Every patient identifier in PID-3 and MRG-1, as sent
+    pub patient_ids: Vec<String>,
+    pub disposition: AuditDisposition,
+    /// Where a forwarded message went
+    pub destination: Option<String>,
+    /// SHA-256 of the raw payload, in hex
+    pub payload_sha256: String,
+}
+
+impl AuditEvent {
+    /// Describe a raw message, picking the header and patient identifiers out of it if it parses
+    pub fn new(raw: &[u8], disposition: AuditDisposition) -> Self {
+        let charset = charset::detect(raw).unwrap_or_default();
+        let text = charset
+            .decode(raw)
+            .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned());
+        let mut event = Self::empty(raw, disposition);
+        if let Ok(message) = Message::parse(&text) {
+            event.describe(&message);
+        }
+        event
+    }
+
+    /// Describe a parsed message, hashing it as it would be sent
+    pub fn from_message(message: &Message, disposition: AuditDisposition) -> Self {
+        let mut event = Self::empty(message.to_hl7().as_bytes(), disposition);
+        event.describe(message);
+        event
+    }
+
+    fn empty(payload: &[u8], disposition: AuditDisposition) -> Self {
+        Self {
+            time: Utc::now(),
+            source: None,
+            sender: None,
+            message_type: None,
+            control_id: None,
+            patient_ids: Vec::new(),
+            disposition,
+            destination: None,
+            payload_sha256: format!("{:x}", Sha256::digest(payload)),
+        }
+    }
+
+    /// Record where the message came from
+    pub fn with_source<S: ToString>(mut self, source: S) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// Record where the message was forwarded to
+    pub fn with_destination<S: ToString>(mut self, destination: S) -> Self {
+        self.destination = Some(destination.to_string());
+        self
+    }
+
+    fn describe(&mut self, message: &Message) {
+        let value = |path: &str| terser::get(message, path).filter(|v| !v.is_empty());
+        let header = |value: Option<&str>| value.filter(|v| !v.is_empty()).map(str::to_string);
+        self.sender = match (header(message.sending_application()), header(message.sending_facility())) {
+            (None, None) => None,
+            (application, facility) => Some(format!(
+                "{}^{}",
+                application.unwrap_or_default(),
+                facility.unwrap_or_default()
+            )),
+        };
+        self.message_type = Some(message.message_type.clone()).filter(|t| !t.is_empty());
+        self.control_id = header(message.control_id());
+        self.patient_ids = ["PID-3", "MRG-1"]
+            .iter()
+            .filter_map(|path| value(path))
+            .flat_map(|field| field.split('~').map(str::to_string).collect::<Vec<_>>())
+            .filter(|id| !id.is_empty())
+            .collect();
+    }
+}
+
+/// An event as written to the log, chained to the entry before it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// 1-based position in the log
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// `hash` of the previous entry, or `GENESIS_HASH` for the first
+    pub prev_hash: String,
+    /// SHA-256 over this entry's other fields
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn seal(seq: u64, event: AuditEvent, prev_hash: String) -> Result<Self, serde_json::Error> {
+        let mut entry = Self {
+            seq,
+            event,
+            prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash()?;
+        Ok(entry)
+    }
+
+    /// The hash this entry should have: SHA-256 of its JSON with `hash` left empty
+    fn compute_hash(&self) -> Result<String, serde_json::Error> {
+        let unsealed = serde_json::to_vec(&Self { hash: String::new(), ..self.clone() })?;
+        Ok(format!("{:x}", Sha256::digest(unsealed)))
+    }
+}
+
+/// An append-only, tamper-evident log of every message received and forwarded
+///
+/// Each entry is a JSON line carrying the hash of the entry before it, so
+/// editing, removing or reordering entries breaks the chain from that point on,
+/// which `verify` reports. Entries are flushed to disk before `record` returns.
+/// Reopening a log continues its chain.
+///
+/// ```ignore
+/// let log = AuditLog::open("audit.jsonl")?;
+/// log.record(AuditEvent::new(raw, AuditDisposition::Accepted).with_source(peer))?;
+/// assert_eq!(audit::verify("audit.jsonl")?, 1);
+/// ```
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    // The file, the last sequence number and the last hash
+    state: Mutex<(File, u64, String)>,
+}
+
+impl AuditLog {
+    /// Open a log, creating it if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, AuditError> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+
+        let mut last = None;
+        for (index, line) in BufReader::new(&file).lines().enumerate() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                last = Some((index + 1, line));
+            }
+        }
+        let (seq, hash) = match last {
+            Some((line, text)) => {
+                let entry = parse_entry(line, &text)?;
+                (entry.seq, entry.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        Ok(Self {
+            path,
+            state: Mutex::new((file, seq, hash)),
+        })
+    }
+
+    /// The file the log is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an event, returning the entry written
+    pub fn record(&self, event: AuditEvent) -> Result<AuditEntry, AuditError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (file, seq, hash) = &mut *state;
+
+        let entry = AuditEntry::seal(*seq + 1, event, hash.clone()).map_err(std::io::Error::from)?;
+        let mut line = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        *seq = entry.seq;
+        *hash = entry.hash.clone();
+        Ok(entry)
+    }
+}
+
+/// Check every entry's hash and its link to the entry before, returning the number of entries
+///
+/// Entries removed from the end of the log can't be detected this way; keep
+/// the last `hash` somewhere else to catch that.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<u64, AuditError> {
+    let file = File::open(path)?;
+    let mut expected_seq = 1;
+    let mut prev_hash = GENESIS_HASH.to_string();
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = parse_entry(number, &line)?;
+        let broken = |reason: String| AuditError::BrokenChain { line: number, reason };
+
+        if entry.seq != expected_seq {
+            return Err(broken(format!("expected entry {}, found {}", expected_seq, entry.seq)));
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(broken("previous hash doesn't match the entry before".to_string()));
+        }
+        let hash = entry.compute_hash().map_err(|e| broken(e.to_string()))?;
+        if entry.hash != hash {
+            return Err(broken("entry was modified after it was written".to_string()));
+        }
+
+        expected_seq += 1;
+        prev_hash = entry.hash;
+    }
+
+    Ok(expected_seq - 1)
+}
+
+fn parse_entry(line: usize, text: &str) -> Result<AuditEntry, AuditError> {
+    serde_json::from_str(text).map_err(|e| AuditError::InvalidEntry { line, reason: e.to_string() })
+}
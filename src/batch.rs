@@ -0,0 +1,552 @@
+use crate::{charset, parse_field, terser, Delimiters, HL7Error, Message, Segment};
+use chrono::Local;
+use futures::Stream;
+use std::collections::VecDeque;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Messages longer than this are skipped by a `BatchReader` unless it's told otherwise
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Errors that can occur reading a batch file
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error("Message {index} of the file doesn't parse: {error}")]
+    InvalidMessage { index: usize, error: HL7Error },
+
+    #[error("Message {index} of the file is longer than {max} bytes")]
+    MessageTooLarge { index: usize, max: usize },
+
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Invalid batch structure: {0}")]
+    InvalidStructure(String),
+
+    #[error("{trailer} declares {declared}, but {actual} were found")]
+    CountMismatch {
+        trailer: &'static str,
+        declared: usize,
+        actual: usize,
+    },
+}
+
+/// A batch of messages between a BHS header and a BTS trailer
+///
+/// Messages sent without a BHS still form a batch, with no header or trailer.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub header: Option<Segment>,
+    pub messages: Vec<Message>,
+    pub trailer: Option<Segment>,
+}
+
+impl Batch {
+    pub fn new(messages: Vec<Message>) -> Self {
+        Self {
+            messages,
+            ..Self::default()
+        }
+    }
+
+    /// BHS-11, the batch control ID
+    pub fn control_id(&self) -> Option<String> {
+        header_value(self.header.as_ref()?, 11)
+    }
+
+    /// BTS-1, the number of messages the sender says the batch holds
+    pub fn declared_count(&self) -> Option<String> {
+        trailer_value(self.trailer.as_ref()?, 1)
+    }
+}
+
+/// A file of batches between an FHS header and an FTS trailer
+///
+/// FHS and BHS number their fields like MSH, so FHS-1 is the field separator.
+/// Headers are read with the standard delimiters.
+///
+/// ```
+/// use rust_hl7::batch::BatchFile;
+///
+/// let text = "FHS|^~\\&|BILLING|HOSP\rBHS|^~\\&|BILLING|HOSP\r\
+///             MSH|^~\\&|BILLING|HOSP|GL|HOSP|20240501||DFT^P03|1|P|2.5\r\
+///             MSH|^~\\&|BILLING|HOSP|GL|HOSP|20240501||DFT^P03|2|P|2.5\r\
+///             BTS|2\rFTS|1";
+/// let file = BatchFile::parse(text).unwrap();
+/// assert_eq!(file.message_count(), 2);
+/// assert!(BatchFile::parse(&text.replace("BTS|2", "BTS|3"))
+///     .unwrap_err()
+///     .to_string()
+///     .contains("BTS declares 3"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BatchFile {
+    pub header: Option<Segment>,
+    pub batches: Vec<Batch>,
+    pub trailer: Option<Segment>,
+}
+
+impl BatchFile {
+    /// A file holding one batch of these messages; headers are generated when it's written
+    pub fn new(messages: Vec<Message>) -> Self {
+        Self {
+            batches: vec![Batch::new(messages)],
+            ..Self::default()
+        }
+    }
+
+    /// Parse a batch file, checking the counts in its BTS and FTS trailers
+    ///
+    /// Segments may end with "\r", "\n" or "\r\n". A file without FHS or BHS
+    /// headers is read as one batch, so plain files of messages parse too.
+    pub fn parse(text: &str) -> Result<Self, BatchError> {
+        let (mut scanner, mut builder, mut items) = (Scanner::default(), Builder::default(), Vec::new());
+        for line in text.split(['\r', '\n']) {
+            scanner.segment(line.as_bytes(), &mut items);
+        }
+        scanner.close_batch(&mut items);
+        for item in items {
+            builder.add(item?)?;
+        }
+        builder.close_batch();
+        Ok(builder.file)
+    }
+
+    /// Every message in every batch, in order
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.batches.iter().flat_map(|batch| batch.messages.iter())
+    }
+
+    /// How many messages the file holds
+    pub fn message_count(&self) -> usize {
+        self.batches.iter().map(|batch| batch.messages.len()).sum()
+    }
+
+    /// FHS-11, the file control ID
+    pub fn control_id(&self) -> Option<String> {
+        header_value(self.header.as_ref()?, 11)
+    }
+
+    /// Serialize the file to ER7, with each segment ending in "\r"
+    ///
+    /// Missing FHS and BHS headers are generated from the first message's
+    /// sending and receiving applications and facilities, timestamped now.
+    /// Trailers are always written with the actual counts.
+    pub fn to_hl7(&self) -> String {
+        let delimiters = Delimiters::default();
+        let first = self.messages().next();
+        let mut output = String::new();
+        let mut push = |segment: &Segment| {
+            output.push_str(&segment.to_hl7(&delimiters));
+            output.push('\r');
+        };
+
+        push(&self.header.clone().unwrap_or_else(|| header("FHS", first)));
+        for batch in &self.batches {
+            push(&batch.header.clone().unwrap_or_else(|| header("BHS", batch.messages.first().or(first))));
+            for message in &batch.messages {
+                for segment in &message.segments {
+                    push(segment);
+                }
+            }
+            push(&trailer("BTS", batch.trailer.as_ref(), batch.messages.len()));
+        }
+        push(&trailer("FTS", self.trailer.as_ref(), self.batches.len()));
+        output
+    }
+}
+
+/// A complete piece of a batch file, in the order read
+enum Item {
+    FileHeader(Segment),
+    BatchHeader(Segment),
+    /// A message's index in the file and its bytes, segments ending in "\r"
+    Message(usize, Vec<u8>),
+    BatchTrailer(Segment),
+    FileTrailer(Segment),
+}
+
+/// Splits a file into headers, messages and trailers one segment at a time,
+/// checking the structure and counts as it goes
+///
+/// Problems are reported among the items rather than stopping the scan, so
+/// a streaming reader can carry on past them.
+#[derive(Default)]
+struct Scanner {
+    /// Messages longer than this are reported and dropped rather than held
+    max_message_bytes: Option<usize>,
+    file_header: bool,
+    /// How many messages the open batch holds, if one is open
+    batch: Option<usize>,
+    /// Batches closed so far
+    batches: usize,
+    /// Messages seen so far, including those too large to keep
+    messages: usize,
+    /// Segments of the message being read
+    message: Vec<u8>,
+    /// Whether the message being read is too large
+    oversized: bool,
+    /// Whether FTS has been read
+    finished: bool,
+}
+
+impl Scanner {
+    /// Read one segment, adding anything it completes to `items`
+    fn segment(&mut self, line: &[u8], items: &mut Vec<Result<Item, BatchError>>) {
+        // MLLP framing characters some senders leave in files
+        let framing = |b: &u8| *b != 0x0b && *b != 0x1c;
+        let start = line.iter().position(framing).unwrap_or(line.len());
+        let end = line.iter().rposition(framing).map_or(start, |last| last + 1);
+        let line = &line[start..end];
+        if line.is_empty() {
+            return;
+        }
+        if self.finished {
+            items.push(Err(BatchError::InvalidStructure("segments follow FTS".to_string())));
+            self.finished = false;
+        }
+        let control = || parse_control(&String::from_utf8_lossy(line));
+        match line.get(..3).unwrap_or(line) {
+            b"FHS" => {
+                if self.file_header || self.batches > 0 || self.batch.is_some() || self.in_message() {
+                    items.push(Err(BatchError::InvalidStructure("FHS must be the first segment".to_string())));
+                    return;
+                }
+                self.file_header = true;
+                items.push(Ok(Item::FileHeader(control())));
+            }
+            b"BHS" => {
+                self.close_batch(items);
+                self.batch = Some(0);
+                items.push(Ok(Item::BatchHeader(control())));
+            }
+            b"BTS" => {
+                self.close_message(items);
+                let Some(count) = self.batch.take() else {
+                    items.push(Err(BatchError::InvalidStructure("BTS without a batch".to_string())));
+                    return;
+                };
+                self.batches += 1;
+                let trailer = control();
+                if let Err(e) = check_count("BTS", trailer_value(&trailer, 1), count) {
+                    items.push(Err(e));
+                }
+                items.push(Ok(Item::BatchTrailer(trailer)));
+            }
+            b"FTS" => {
+                self.close_batch(items);
+                self.finished = true;
+                let trailer = control();
+                if let Err(e) = check_count("FTS", trailer_value(&trailer, 1), self.batches) {
+                    items.push(Err(e));
+                }
+                items.push(Ok(Item::FileTrailer(trailer)));
+            }
+            b"MSH" => {
+                self.close_message(items);
+                self.append(line);
+            }
+            name if !self.in_message() => {
+                let name = String::from_utf8_lossy(name);
+                items.push(Err(BatchError::InvalidStructure(format!("{} segment outside a message", name))));
+            }
+            _ => self.append(line),
+        }
+    }
+
+    fn in_message(&self) -> bool {
+        !self.message.is_empty() || self.oversized
+    }
+
+    /// Add a segment to the message being read, unless that makes it too large
+    fn append(&mut self, line: &[u8]) {
+        if self.oversized {
+            return;
+        }
+        if self.max_message_bytes.is_some_and(|max| self.message.len() + line.len() + 1 > max) {
+            self.oversized = true;
+            self.message = Vec::new();
+            return;
+        }
+        self.message.extend_from_slice(line);
+        self.message.push(b'\r');
+    }
+
+    /// Finish the message being read, if any, as part of the open batch
+    fn close_message(&mut self, items: &mut Vec<Result<Item, BatchError>>) {
+        if !self.in_message() {
+            return;
+        }
+        let index = self.messages;
+        self.messages += 1;
+        *self.batch.get_or_insert(0) += 1;
+        if std::mem::take(&mut self.oversized) {
+            let max = self.max_message_bytes.unwrap_or_default();
+            items.push(Err(BatchError::MessageTooLarge { index, max }));
+        } else {
+            items.push(Ok(Item::Message(index, std::mem::take(&mut self.message))));
+        }
+    }
+
+    /// Finish the open batch, if any, without a trailer
+    fn close_batch(&mut self, items: &mut Vec<Result<Item, BatchError>>) {
+        self.close_message(items);
+        if self.batch.take().is_some() {
+            self.batches += 1;
+        }
+    }
+}
+
+/// Gathers scanned items into a `BatchFile`
+#[derive(Default)]
+struct Builder {
+    file: BatchFile,
+    /// The batch being read, if any
+    batch: Option<Batch>,
+}
+
+impl Builder {
+    fn add(&mut self, item: Item) -> Result<(), BatchError> {
+        match item {
+            Item::FileHeader(header) => self.file.header = Some(header),
+            Item::BatchHeader(header) => {
+                self.close_batch();
+                self.batch = Some(Batch {
+                    header: Some(header),
+                    ..Batch::default()
+                });
+            }
+            Item::Message(index, bytes) => {
+                let message = Message::parse(&String::from_utf8_lossy(&bytes))
+                    .map_err(|error| BatchError::InvalidMessage { index, error })?;
+                self.batch.get_or_insert_with(Batch::default).messages.push(message);
+            }
+            Item::BatchTrailer(trailer) => {
+                let mut batch = self.batch.take().unwrap_or_default();
+                batch.trailer = Some(trailer);
+                self.file.batches.push(batch);
+            }
+            Item::FileTrailer(trailer) => {
+                self.close_batch();
+                self.file.trailer = Some(trailer);
+            }
+        }
+        Ok(())
+    }
+
+    fn close_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            self.file.batches.push(batch);
+        }
+    }
+}
+
+/// How far a `BatchReader` has got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes read from the input
+    pub bytes: u64,
+    /// Messages read, whether or not they parsed
+    pub messages: usize,
+    /// Batches finished, by a trailer or by the next header
+    pub batches: usize,
+    /// Errors reported so far
+    pub errors: usize,
+}
+
+/// Reads a batch file one message at a time, however large the file is
+///
+/// Only the message being read is held in memory, and messages longer than
+/// `max_message_bytes` are skipped, so memory stays bounded. Messages that
+/// don't parse, trailer counts that don't match and other problems are
+/// returned as errors among the messages and reading carries on; only an IO
+/// error ends the file early. Messages are decoded in the charset their MSH-18
+/// declares.
+///
+/// ```ignore
+/// let mut reader = BatchReader::new(tokio::fs::File::open("billing.hl7").await?);
+/// while let Some(result) = reader.next().await {
+///     match result {
+///         Ok(message) => post(message).await?,
+///         Err(e) => warn!("Skipping: {}", e),
+///     }
+/// }
+/// info!("Read {} messages with {} errors", reader.progress().messages, reader.progress().errors);
+/// ```
+pub struct BatchReader<R> {
+    reader: BufReader<R>,
+    scanner: Scanner,
+    pending: VecDeque<Result<Item, BatchError>>,
+    /// The segment being read
+    line: Vec<u8>,
+    bytes: u64,
+    errors: usize,
+    done: bool,
+}
+
+impl<R> std::fmt::Debug for BatchReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchReader").field("progress", &self.progress()).finish_non_exhaustive()
+    }
+}
+
+impl<R> BatchReader<R> {
+    /// How much has been read so far
+    pub fn progress(&self) -> Progress {
+        Progress {
+            bytes: self.bytes,
+            messages: self.scanner.messages,
+            batches: self.scanner.batches,
+            errors: self.errors,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> BatchReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::with_capacity(64 * 1024, reader),
+            scanner: Scanner {
+                max_message_bytes: Some(DEFAULT_MAX_MESSAGE_BYTES),
+                ..Scanner::default()
+            },
+            pending: VecDeque::new(),
+            line: Vec::new(),
+            bytes: 0,
+            errors: 0,
+            done: false,
+        }
+    }
+
+    /// Skip messages longer than this many bytes, reporting an error for each
+    pub fn max_message_bytes(mut self, max: usize) -> Self {
+        self.scanner.max_message_bytes = Some(max);
+        self
+    }
+
+    /// The next message, or error, in the file; None at the end of the file
+    pub async fn next(&mut self) -> Option<Result<Message, BatchError>> {
+        loop {
+            match self.pending.pop_front() {
+                Some(Ok(Item::Message(index, bytes))) => {
+                    let message = charset::detect(&bytes)
+                        .unwrap_or_default()
+                        .decode(&bytes)
+                        .and_then(|text| Message::parse(&text))
+                        .map_err(|error| BatchError::InvalidMessage { index, error });
+                    self.errors += message.is_err() as usize;
+                    return Some(message);
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    self.errors += 1;
+                    return Some(Err(e));
+                }
+                None if self.done => return None,
+                None => {}
+            }
+
+            let mut items = Vec::new();
+            match self.read_segment().await {
+                Ok(true) => self.scanner.segment(&self.line, &mut items),
+                Ok(false) => {
+                    self.scanner.close_batch(&mut items);
+                    self.done = true;
+                }
+                Err(e) => {
+                    self.done = true;
+                    items.push(Err(BatchError::IoError(e)));
+                }
+            }
+            self.pending.extend(items);
+        }
+    }
+
+    /// Every message and error in the file, as a stream
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, BatchError>> {
+        futures::stream::unfold(self, |mut reader| async move { reader.next().await.map(|result| (result, reader)) })
+    }
+
+    /// Read up to the next segment terminator into `line`, returning false at the end of the input
+    ///
+    /// Only as much of a segment as could fit in a message is kept.
+    async fn read_segment(&mut self) -> std::io::Result<bool> {
+        self.line.clear();
+        let limit = self.scanner.max_message_bytes.map_or(usize::MAX, |max| max.saturating_add(1));
+        loop {
+            let buffer = self.reader.fill_buf().await?;
+            if buffer.is_empty() {
+                return Ok(!self.line.is_empty());
+            }
+            let end = memchr::memchr2(b'\r', b'\n', buffer);
+            let chunk = &buffer[..end.unwrap_or(buffer.len())];
+            let room = limit.saturating_sub(self.line.len());
+            self.line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            let consumed = end.map_or(buffer.len(), |end| end + 1);
+            self.reader.consume(consumed);
+            self.bytes += consumed as u64;
+            if end.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Check a trailer's count, if it has one
+fn check_count(trailer: &'static str, declared: Option<String>, actual: usize) -> Result<(), BatchError> {
+    let Some(declared) = declared.filter(|value| !value.is_empty()) else {
+        return Ok(());
+    };
+    let declared = declared
+        .trim()
+        .parse()
+        .map_err(|_| BatchError::InvalidStructure(format!("{} count '{}' isn't a number", trailer, declared)))?;
+    if declared != actual {
+        return Err(BatchError::CountMismatch { trailer, declared, actual });
+    }
+    Ok(())
+}
+
+/// Parse a header or trailer with the standard delimiters
+fn parse_control(line: &str) -> Segment {
+    let delimiters = Delimiters::default();
+    let mut parts = line.split(delimiters.field);
+    let name = parts.next().unwrap_or_default().to_string();
+    Segment::new(name, parts.map(|part| parse_field(part, &delimiters)).collect())
+}
+
+/// A header field by number, counting the separator as field 1 like MSH
+fn header_value(segment: &Segment, number: usize) -> Option<String> {
+    let field = segment.fields.get(number.checked_sub(2)?)?;
+    Some(field.to_hl7(&Delimiters::default()))
+}
+
+fn trailer_value(segment: &Segment, number: usize) -> Option<String> {
+    let field = segment.fields.get(number.checked_sub(1)?)?;
+    Some(field.to_hl7(&Delimiters::default()))
+}
+
+/// Generate an FHS or BHS header from a message's MSH-3 to MSH-6
+fn header(name: &str, message: Option<&Message>) -> Segment {
+    let value = |path: &str| message.and_then(|m| terser::get(m, path)).unwrap_or_default();
+    let fields = [
+        "^~\\&".to_string(),
+        value("MSH-3"),
+        value("MSH-4"),
+        value("MSH-5"),
+        value("MSH-6"),
+        Local::now().format("%Y%m%d%H%M%S%z").to_string(),
+    ];
+    parse_control(&format!("{}|{}", name, fields.join("|")))
+}
+
+/// A BTS or FTS trailer with the actual count, keeping any other fields it had
+fn trailer(name: &str, existing: Option<&Segment>, count: usize) -> Segment {
+    let mut segment = existing.cloned().unwrap_or_else(|| Segment::new(name, Vec::new()));
+    let count = parse_field(&count.to_string(), &Delimiters::default());
+    match segment.fields.first_mut() {
+        Some(field) => *field = count,
+        None => segment.fields.push(count),
+    }
+    segment
+}
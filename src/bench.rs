@@ -0,0 +1,70 @@
+//! The parser's and codec's internal stages, exposed for benchmarking
+//!
+//! `Message::parse` is frame extraction, then splitting the message into
+//! segments, each segment into fields, and each field into components.
+//! These run one stage at a time, on the same code paths, so a regression
+//! can be pinned to a stage. The corpora are synthetic but shaped like real
+//! feeds, and the same on every run. `benches/parse.rs` measures them all:
+//!
+//! ```text
+//! cargo bench --features bench
+//! ```
+
+use crate::generate::Generator;
+use crate::mllp::{self, MllpError};
+use crate::{scan, Delimiters, Field, HL7Error, Segment};
+use bytes::{Bytes, BytesMut};
+use chrono::{TimeZone, Utc};
+
+/// Take the next complete MLLP frame off the front of a buffer, as the codec does
+pub fn extract_frame(buffer: &mut BytesMut) -> Result<Option<Bytes>, MllpError> {
+    mllp::extract_mllp_message(buffer)
+}
+
+/// Wrap a message in an MLLP frame
+pub fn wrap_frame(message: &[u8]) -> Vec<u8> {
+    mllp::wrap_in_mllp(message)
+}
+
+/// Split a message into its segments
+pub fn split_segments(message: &str) -> Vec<&str> {
+    scan::lines(message).collect()
+}
+
+/// Split a segment into its name and fields
+pub fn split_fields(segment: &str) -> Vec<&str> {
+    scan::split(segment, Delimiters::default().field).collect()
+}
+
+/// Parse one segment into fields, components and subcomponents
+pub fn parse_segment(segment: &str) -> Result<Segment, HL7Error> {
+    crate::parse_segment(segment, &Delimiters::default())
+}
+
+/// Parse one field into components and subcomponents
+pub fn parse_field(field: &str) -> Field {
+    crate::parse_field(field, &Delimiters::default())
+}
+
+/// `count` ADT^A01 and ADT^A08 messages, alternating
+pub fn adt_corpus(count: usize) -> Vec<String> {
+    corpus(count, &["ADT^A01", "ADT^A08"])
+}
+
+/// `count` ORU^R01 lab results
+pub fn oru_corpus(count: usize) -> Vec<String> {
+    corpus(count, &["ORU^R01"])
+}
+
+/// The corpus as one MLLP stream, as it would arrive on a connection
+pub fn mllp_stream(messages: &[String]) -> Vec<u8> {
+    messages.iter().flat_map(|message| wrap_frame(message.as_bytes())).collect()
+}
+
+fn corpus(count: usize, types: &[&str]) -> Vec<String> {
+    let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let mut generator = Generator::new(7).start(start);
+    (0..count)
+        .map(|n| generator.generate(types[n % types.len()]).expect("known message type").to_hl7())
+        .collect()
+}
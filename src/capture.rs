@@ -0,0 +1,421 @@
+use crate::compress::{self, Codec};
+use crate::mllp::{extract_mllp_message, wrap_in_mllp};
+use crate::store::Direction;
+use crate::{charset, terser, Message};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+/// The bytes every capture file starts with, ending in the format version
+pub const MAGIC: &[u8; 8] = b"HL7CAP\x00\x01";
+
+/// The start of capture files whose frames are compressed
+pub const MAGIC_COMPRESSED: &[u8; 8] = b"HL7CAP\x00\x02";
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid capture file: {0}")]
+    InvalidFormat(String),
+}
+
+/// One frame seen on a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// When the frame was received or sent
+    pub time: DateTime<Utc>,
+    /// Inbound for messages from the peer, outbound for responses to it
+    pub direction: Direction,
+    /// Address of the remote end of the connection, which identifies the connection
+    pub peer: String,
+    /// The frame's content exactly as sent, without the MLLP start and end bytes
+    pub frame: Bytes,
+}
+
+/// Appends frames to a capture file
+///
+/// The file is `MAGIC` followed by one record per frame: the time in
+/// microseconds since the Unix epoch (i64), the direction (0 inbound,
+/// 1 outbound), the peer address as a u16 length and UTF-8, and the frame as a
+/// u32 length and its bytes, all integers big-endian. Frames are kept byte for
+/// byte, whatever their charset, so a replay sends exactly what was received.
+///
+/// Files starting with `MAGIC_COMPRESSED` are laid out the same way, but each
+/// frame's bytes are compressed on their own as by `Codec::compress`, and its
+/// length is the compressed length.
+///
+/// ```ignore
+/// let capture = Arc::new(CaptureWriter::open("incident.capture")?);
+/// let server = MllpServer::new("0.0.0.0:2575", handler).with_capture(capture);
+/// ```
+#[derive(Debug)]
+pub struct CaptureWriter {
+    file: Mutex<File>,
+    /// How frames are compressed, if the file is a compressed one
+    codec: Option<Codec>,
+}
+
+impl CaptureWriter {
+    /// Open a capture file for appending, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+        Self::open_compressed(path, Codec::None)
+    }
+
+    /// Open a capture file for appending, creating a compressed one if it doesn't exist
+    ///
+    /// An existing file keeps its format: frames appended to an uncompressed
+    /// file aren't compressed, whatever the codec.
+    pub fn open_compressed<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self, CaptureError> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let compressed = if file.metadata()?.len() == 0 {
+            file.write_all(if codec == Codec::None { MAGIC } else { MAGIC_COMPRESSED })?;
+            codec != Codec::None
+        } else {
+            let mut magic = [0; 8];
+            file.read_exact(&mut magic)
+                .map_err(|_| CaptureError::InvalidFormat("file is too short for a header".to_string()))?;
+            let compressed = check_magic(&magic)?;
+            if !compressed && codec != Codec::None {
+                warn!("Appending uncompressed frames to {}, which was started without compression", path.display());
+            }
+            compressed
+        };
+        Ok(Self {
+            file: Mutex::new(file),
+            codec: compressed.then_some(codec),
+        })
+    }
+
+    /// Append a frame; this blocks, so async code should use `record_in_background`
+    pub fn write(&self, record: &CaptureRecord) -> Result<(), CaptureError> {
+        let peer = record.peer.as_bytes();
+        let frame = match self.codec {
+            Some(codec) => codec.compress(&record.frame)?,
+            None => record.frame.to_vec(),
+        };
+        let peer_len = u16::try_from(peer.len()).map_err(|_| CaptureError::InvalidFormat("peer address is too long".to_string()))?;
+        let frame_len = u32::try_from(frame.len()).map_err(|_| CaptureError::InvalidFormat("frame is too long".to_string()))?;
+
+        let mut bytes = Vec::with_capacity(17 + peer.len() + frame.len());
+        bytes.extend_from_slice(&record.time.timestamp_micros().to_be_bytes());
+        bytes.push(match record.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        });
+        bytes.extend_from_slice(&peer_len.to_be_bytes());
+        bytes.extend_from_slice(peer);
+        bytes.extend_from_slice(&frame_len.to_be_bytes());
+        bytes.extend_from_slice(&frame);
+
+        // One write per record so concurrent connections don't interleave
+        self.file.lock().unwrap_or_else(|e| e.into_inner()).write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Append a frame seen now
+    pub fn record(&self, frame: &[u8], direction: Direction, peer: &str) -> Result<(), CaptureError> {
+        self.write(&CaptureRecord {
+            time: Utc::now(),
+            direction,
+            peer: peer.to_string(),
+            frame: Bytes::copy_from_slice(frame),
+        })
+    }
+}
+
+/// Append a frame seen now on a blocking thread, logging rather than failing on errors
+///
+/// Connections wait for it, so their frames stay in order.
+pub async fn record_in_background(capture: &Arc<CaptureWriter>, frame: &[u8], direction: Direction, peer: SocketAddr) {
+    let record = CaptureRecord {
+        time: Utc::now(),
+        direction,
+        peer: peer.to_string(),
+        frame: Bytes::copy_from_slice(frame),
+    };
+    let capture = capture.clone();
+    let written = tokio::task::spawn_blocking(move || {
+        if let Err(e) = capture.write(&record) {
+            error!("Failed to capture frame from {}: {}", peer, e);
+        }
+    });
+    if let Err(e) = written.await {
+        error!("Failed to capture frame from {}: {}", peer, e);
+    }
+}
+
+/// Check a capture file's header, returning whether its frames are compressed
+fn check_magic(magic: &[u8]) -> Result<bool, CaptureError> {
+    match magic {
+        m if m == MAGIC => Ok(false),
+        m if m == MAGIC_COMPRESSED => Ok(true),
+        [b'H', b'L', b'7', b'C', b'A', b'P', 0, version] => {
+            Err(CaptureError::InvalidFormat(format!("unsupported format version {}", version)))
+        }
+        _ => Err(CaptureError::InvalidFormat("not a capture file".to_string())),
+    }
+}
+
+/// Read every record in a capture file, in the order they were written
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CaptureRecord>, CaptureError> {
+    let bytes = std::fs::read(path)?;
+    let compressed = check_magic(bytes.get(..MAGIC.len()).unwrap_or(&bytes))?;
+
+    let mut records = Vec::new();
+    let mut rest = &bytes[MAGIC.len()..];
+    while !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        let truncated = || CaptureError::InvalidFormat(format!("record at byte {} is truncated", offset));
+        let mut take = |count: usize| -> Result<&[u8], CaptureError> {
+            let (taken, remaining) = rest.split_at_checked(count).ok_or_else(truncated)?;
+            rest = remaining;
+            Ok(taken)
+        };
+
+        let micros = i64::from_be_bytes(take(8)?.try_into().expect("8 bytes"));
+        let direction = match take(1)?[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            other => {
+                return Err(CaptureError::InvalidFormat(format!("record at byte {} has direction {}", offset, other)));
+            }
+        };
+        let peer_len = u16::from_be_bytes(take(2)?.try_into().expect("2 bytes"));
+        let peer = String::from_utf8_lossy(take(peer_len as usize)?).into_owned();
+        let frame_len = u32::from_be_bytes(take(4)?.try_into().expect("4 bytes"));
+        let frame = take(frame_len as usize)?;
+        let frame = match compressed {
+            true => Bytes::from(compress::decompress(frame).map_err(|e| {
+                CaptureError::InvalidFormat(format!("record at byte {} can't be decompressed: {}", offset, e))
+            })?),
+            false => Bytes::copy_from_slice(frame),
+        };
+        let time = DateTime::from_timestamp_micros(micros)
+            .ok_or_else(|| CaptureError::InvalidFormat(format!("record at byte {} has an invalid time", offset)))?;
+
+        records.push(CaptureRecord { time, direction, peer, frame });
+    }
+    Ok(records)
+}
+
+/// How closely a replay follows the gaps between captured messages
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Timing {
+    /// Wait as long between messages as the original traffic did
+    #[default]
+    Original,
+    /// Divide the original gaps by this factor, e.g. 10.0 for ten times faster
+    Accelerated(f64),
+    /// Send each message as soon as the previous one is acknowledged
+    Immediate,
+}
+
+/// A captured message sent again and what came back
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayedExchange {
+    /// The captured connection the message arrived on
+    pub peer: String,
+    /// When the message was originally received
+    pub captured_at: DateTime<Utc>,
+    /// MSH-10 of the message, if it has one
+    pub control_id: Option<String>,
+    /// MSA-1 of the captured response, if there was one
+    pub expected_ack: Option<String>,
+    /// MSA-1 of the response to the replay
+    pub ack: Option<String>,
+    /// Why no response came back
+    pub error: Option<String>,
+}
+
+impl ReplayedExchange {
+    /// Whether the target answered the way it did when the traffic was captured
+    pub fn matches(&self) -> bool {
+        self.error.is_none() && self.ack == self.expected_ack
+    }
+}
+
+/// Outcome of replaying a capture
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureReport {
+    /// Every inbound message, in the order sent
+    pub exchanges: Vec<ReplayedExchange>,
+}
+
+impl CaptureReport {
+    /// Messages sent
+    pub fn sent(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    /// Exchanges that failed or were acknowledged differently from the capture
+    pub fn mismatches(&self) -> impl Iterator<Item = &ReplayedExchange> {
+        self.exchanges.iter().filter(|e| !e.matches())
+    }
+}
+
+/// Sends captured inbound traffic to a target again, to reproduce an incident
+///
+/// Messages are sent one at a time, in capture order, each captured
+/// connection over a connection of its own, and spaced out as `timing` says.
+/// Each message's ACK code is compared with the one captured, so a replay
+/// shows whether a fix changes how the target answers. When the target is
+/// slower than the original traffic, the schedule slips rather than sending
+/// before the previous ACK.
+///
+/// ```ignore
+/// let report = CaptureReplay::new(read_capture("incident.capture")?)
+///     .timing(Timing::Accelerated(10.0))
+///     .run("test-engine:2575")
+///     .await;
+/// for exchange in report.mismatches() {
+///     println!("{:?}: expected {:?}, got {:?}", exchange.control_id, exchange.expected_ack, exchange.ack);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CaptureReplay {
+    records: Vec<CaptureRecord>,
+    timing: Timing,
+    timeout: Duration,
+}
+
+impl CaptureReplay {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        Self {
+            records,
+            timing: Timing::Original,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set how the gaps between messages are reproduced
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Set how long to wait for connecting and for each response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send the captured inbound messages to `target`
+    pub async fn run(&self, target: &str) -> CaptureReport {
+        let mut records: Vec<&CaptureRecord> = self.records.iter().collect();
+        records.sort_by_key(|r| r.time);
+
+        // Pair each inbound message with the next response on its connection
+        let mut exchanges: Vec<(&CaptureRecord, Option<&CaptureRecord>)> = Vec::new();
+        let mut awaiting: HashMap<&str, usize> = HashMap::new();
+        for record in records {
+            match record.direction {
+                Direction::Inbound => {
+                    awaiting.insert(&record.peer, exchanges.len());
+                    exchanges.push((record, None));
+                }
+                Direction::Outbound => {
+                    if let Some(index) = awaiting.remove(record.peer.as_str()) {
+                        exchanges[index].1 = Some(record);
+                    }
+                }
+            }
+        }
+        info!("Replaying {} captured messages to {}", exchanges.len(), target);
+
+        let mut connections: HashMap<&str, (TcpStream, BytesMut)> = HashMap::new();
+        let mut report = CaptureReport::default();
+        let (Some(first), started) = (exchanges.first().map(|(r, _)| r.time), tokio::time::Instant::now()) else {
+            return report;
+        };
+        for (request, response) in exchanges {
+            let offset = (request.time - first).to_std().unwrap_or_default();
+            let due = match self.timing {
+                Timing::Original => Some(offset),
+                Timing::Accelerated(factor) if factor > 0.0 => Some(offset.div_f64(factor)),
+                Timing::Accelerated(_) | Timing::Immediate => None,
+            };
+            if let Some(due) = due {
+                tokio::time::sleep_until(started + due).await;
+            }
+
+            let result = tokio::time::timeout(self.timeout, send(&mut connections, target, request))
+                .await
+                .unwrap_or_else(|_| Err(format!("No response within {:?}", self.timeout)));
+            if result.is_err() {
+                // Don't pair a late response with the connection's next message
+                connections.remove(request.peer.as_str());
+            }
+
+            let exchange = ReplayedExchange {
+                peer: request.peer.clone(),
+                captured_at: request.time,
+                control_id: parse(&request.frame).and_then(|m| m.control_id().map(str::to_string)),
+                expected_ack: response.and_then(|r| ack_code(&r.frame)),
+                ack: result.as_ref().ok().and_then(|frame| ack_code(frame)),
+                error: result.err(),
+            };
+            if !exchange.matches() {
+                warn!(
+                    "Replayed message {} was answered {:?} instead of {:?}{}",
+                    exchange.control_id.as_deref().unwrap_or("-"),
+                    exchange.ack,
+                    exchange.expected_ack,
+                    exchange.error.as_deref().map(|e| format!(": {}", e)).unwrap_or_default()
+                );
+            }
+            report.exchanges.push(exchange);
+        }
+
+        info!(
+            "Capture replay finished: {} messages sent, {} answered differently",
+            report.sent(),
+            report.mismatches().count()
+        );
+        report
+    }
+}
+
+/// Send a frame over the connection standing in for its captured one and read the response
+async fn send<'a>(
+    connections: &mut HashMap<&'a str, (TcpStream, BytesMut)>,
+    target: &str,
+    request: &'a CaptureRecord,
+) -> Result<Bytes, String> {
+    let (stream, buffer) = match connections.entry(request.peer.as_str()) {
+        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            let stream = TcpStream::connect(target).await.map_err(|e| e.to_string())?;
+            entry.insert((stream, BytesMut::with_capacity(4096)))
+        }
+    };
+    stream.write_all(&wrap_in_mllp(&request.frame)).await.map_err(|e| e.to_string())?;
+    loop {
+        if let Some(frame) = extract_mllp_message(buffer).map_err(|e| e.to_string())? {
+            return Ok(frame);
+        }
+        if stream.read_buf(buffer).await.map_err(|e| e.to_string())? == 0 {
+            return Err("Connection closed before a response was received".to_string());
+        }
+    }
+}
+
+fn parse(frame: &[u8]) -> Option<Message> {
+    let text = charset::detect(frame).unwrap_or_default().decode(frame).ok()?;
+    Message::parse(&text).ok()
+}
+
+fn ack_code(frame: &[u8]) -> Option<String> {
+    parse(frame).and_then(|ack| terser::get(&ack, "MSA-1"))
+}
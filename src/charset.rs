@@ -0,0 +1,129 @@
+use crate::HL7Error;
+use bytes::{BufMut, BytesMut};
+use encoding_rs::{mem, WINDOWS_1252};
+
+/// Character sets that can be declared in MSH-18
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Charset {
+    /// UTF-8 (also used for plain ASCII, which is a subset)
+    #[default]
+    Utf8,
+    /// ISO-8859-1 (Latin-1), HL7 table 0211 value "8859/1"
+    Iso8859_1,
+    /// Windows-1252, not in HL7 table 0211 but common with Windows-based senders
+    Windows1252,
+}
+
+impl Charset {
+    /// Map an MSH-18 value to a charset, returning None for unsupported values
+    pub fn from_hl7(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "" | "ASCII" | "UNICODE UTF-8" | "UTF-8" | "UTF8" => Some(Charset::Utf8),
+            "8859/1" | "ISO-8859-1" | "ISO8859-1" | "LATIN1" => Some(Charset::Iso8859_1),
+            "WINDOWS-1252" | "CP1252" | "1252" => Some(Charset::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// The value to put in MSH-18 for this charset
+    pub fn hl7_name(&self) -> &'static str {
+        match self {
+            Charset::Utf8 => "UNICODE UTF-8",
+            Charset::Iso8859_1 => "8859/1",
+            Charset::Windows1252 => "WINDOWS-1252",
+        }
+    }
+
+    /// Decode raw message bytes in this charset into a UTF-8 string
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, HL7Error> {
+        match self {
+            Charset::Utf8 => std::str::from_utf8(bytes)
+                .map(|s| s.to_string())
+                .map_err(|e| HL7Error::EncodingError(format!("Invalid UTF-8: {}", e))),
+            // Every byte maps directly to the code point with the same value
+            Charset::Iso8859_1 => Ok(mem::decode_latin1(bytes).into_owned()),
+            // Undefined bytes map to C1 controls, so this can't fail
+            Charset::Windows1252 => Ok(WINDOWS_1252.decode_without_bom_handling(bytes).0.into_owned()),
+        }
+    }
+
+    /// Encode a UTF-8 string into bytes in this charset
+    ///
+    /// Characters that can't be represented are replaced with `?` rather than
+    /// failing, since an outbound ACK should still be sent even if some text is lossy.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            Charset::Utf8 => text.as_bytes().to_vec(),
+            Charset::Iso8859_1 => text
+                .chars()
+                .map(|c| if (c as u32) <= 0xFF { c as u8 } else { b'?' })
+                .collect(),
+            Charset::Windows1252 => text.chars().map(windows_1252_byte).collect(),
+        }
+    }
+
+    /// Encode a UTF-8 string onto the end of a buffer, as `encode` would
+    pub fn encode_into(&self, text: &str, dst: &mut BytesMut) {
+        match self {
+            Charset::Utf8 => dst.extend_from_slice(text.as_bytes()),
+            Charset::Iso8859_1 => {
+                dst.reserve(text.len());
+                for c in text.chars() {
+                    dst.put_u8(if (c as u32) <= 0xFF { c as u8 } else { b'?' });
+                }
+            }
+            Charset::Windows1252 => {
+                dst.reserve(text.len());
+                for c in text.chars() {
+                    dst.put_u8(windows_1252_byte(c));
+                }
+            }
+        }
+    }
+}
+
+/// A character's byte in Windows-1252, or `?` if it has none
+///
+/// encoding_rs would write it as an `&#NNNN;` reference instead, whose `&` is
+/// the subcomponent separator.
+fn windows_1252_byte(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+    let mut buffer = [0u8; 4];
+    match WINDOWS_1252.encode(c.encode_utf8(&mut buffer)) {
+        (bytes, _, false) => bytes[0],
+        _ => b'?',
+    }
+}
+
+/// Detect the charset declared in MSH-18 of a raw message
+///
+/// Returns None if the message has no MSH segment, MSH-18 is empty, or the
+/// value isn't a supported charset. The MSH segment can be read without
+/// decoding since all supported charsets are ASCII-compatible.
+pub fn detect(bytes: &[u8]) -> Option<Charset> {
+    let msh_end = memchr::memchr2(b'\r', b'\n', bytes).unwrap_or(bytes.len());
+    let msh = &bytes[..msh_end];
+
+    if msh.len() < 4 || !msh.starts_with(b"MSH") {
+        return None;
+    }
+
+    // MSH-1 is the field separator itself, so after splitting on it
+    // index 1 is MSH-2 and index 17 is MSH-18
+    let field_separator = msh[3];
+    let mut fields = msh.split(|&b| b == field_separator);
+    let repetition = fields.nth(1).and_then(|encoding| encoding.get(1)).copied().unwrap_or(b'~');
+    let value = fields.nth(15)?;
+
+    // Only the first repetition names the default charset
+    let value = value.split(|&b| b == repetition).next().unwrap_or(value);
+    let value = std::str::from_utf8(value).ok()?;
+
+    if value.trim().is_empty() {
+        return None;
+    }
+
+    Charset::from_hl7(value)
+}
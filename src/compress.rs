@@ -0,0 +1,181 @@
+use crate::store::{ArchiveRecord, MessageStore, Query, StoreError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// Start of every compressed archive record; messages start with "MSH" (or a
+/// byte order mark), so this can't be mistaken for an uncompressed one
+const ARCHIVE_TAG: &[u8; 4] = b"\0HLZ";
+
+/// How records are compressed
+///
+/// Each record is compressed on its own and starts with a byte naming its
+/// codec, so any one record can be read without the rest, and files or
+/// archives can mix codecs as settings change over time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// Stored as is
+    #[default]
+    None,
+    Gzip,
+    /// Usually the better choice: faster than gzip and smaller
+    Zstd,
+}
+
+impl Codec {
+    /// Parse a codec name: "none", "gzip" or "zstd"
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Codec::None),
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    /// Compress one record, prefixed with the byte naming the codec
+    ///
+    /// ```
+    /// use rust_hl7::compress::{self, Codec};
+    ///
+    /// let message = b"MSH|^~\\&|LAB|HOSP|EHR|HOSP|20240501||ORU^R01|1|P|2.5".repeat(20);
+    /// let compressed = Codec::Zstd.compress(&message).unwrap();
+    /// assert!(compressed.len() < message.len() / 5);
+    /// assert_eq!(compress::decompress(&compressed).unwrap(), message);
+    /// ```
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut record = vec![self.id()];
+        record.extend(self.encode(data)?);
+        Ok(record)
+    }
+
+    /// Compress data as a plain gzip or zstd stream, without the codec byte,
+    /// for files other tools read
+    pub fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Zstd => zstd::stream::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompress a stream written by `encode`
+    pub fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(data.len() * 4);
+        match self {
+            Codec::None => decoded.extend_from_slice(data),
+            Codec::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decoded)?;
+            }
+            Codec::Zstd => zstd::stream::copy_decode(data, &mut decoded)?,
+        }
+        Ok(decoded)
+    }
+
+    /// The file extension for a stream written by `encode`, e.g. ".zst"
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+        }
+    }
+}
+
+/// Decompress a record written by `Codec::compress`, whichever codec it used
+pub fn decompress(record: &[u8]) -> io::Result<Vec<u8>> {
+    let (&id, data) = record
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty compressed record"))?;
+    let codec = match id {
+        0 => Codec::None,
+        1 => Codec::Gzip,
+        2 => Codec::Zstd,
+        other => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression codec {}", other)));
+        }
+    };
+    codec.decode(data)
+}
+
+/// An archive that compresses raw messages before they reach the underlying store
+///
+/// Metadata stays uncompressed, so queries work as before. Records archived
+/// before compression was turned on, or with another codec, are read back
+/// too, so the codec can be changed at any time. Size limits on the archive
+/// count compressed bytes.
+///
+/// ```ignore
+/// let store = CompressedStore::new(Arc::new(SqliteStore::open("archive.db")?), Codec::Zstd);
+/// ```
+pub struct CompressedStore {
+    inner: Arc<dyn MessageStore>,
+    codec: Codec,
+}
+
+impl CompressedStore {
+    /// Compress new records stored in `inner` with `codec`; with `Codec::None` they're stored as is
+    pub fn new(inner: Arc<dyn MessageStore>, codec: Codec) -> Self {
+        Self { inner, codec }
+    }
+}
+
+impl MessageStore for CompressedStore {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        if self.codec == Codec::None {
+            return self.inner.insert(record);
+        }
+        let compressed = self
+            .codec
+            .compress(&record.raw)
+            .map_err(|e| StoreError::InvalidValue(format!("message can't be compressed: {}", e)))?;
+        let mut record = record.clone();
+        record.raw = [ARCHIVE_TAG.as_slice(), &compressed].concat();
+        self.inner.insert(&record)
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let mut records = self.inner.query(query)?;
+        for record in &mut records {
+            if let Some(compressed) = record.raw.strip_prefix(ARCHIVE_TAG.as_slice()) {
+                record.raw = decompress(compressed)
+                    .map_err(|e| StoreError::InvalidValue(format!("archived message {:?}: {}", record.id, e)))?;
+            }
+        }
+        Ok(records)
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        self.inner.prune(before)
+    }
+
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        self.inner.prune_excess(max_count, max_bytes)
+    }
+
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        self.inner.set_secure_delete(secure_delete)
+    }
+
+    fn vacuum(&self) -> Result<(), StoreError> {
+        self.inner.vacuum()
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        self.inner.check_writable()
+    }
+}
@@ -0,0 +1,1780 @@
+use crate::audit::AuditLog;
+use crate::charset::Charset;
+use crate::clock::ReservedIds;
+use crate::continuation::Reassembler;
+use crate::failover::{Failover, Role};
+use crate::filedrop::FileDropSource;
+use crate::filesink::{FileSink, Rollover};
+use crate::health::{Check, HealthServer, Readiness};
+use crate::lanes::{Lane, PriorityLanes};
+use crate::middleware::{Chain, Dedup};
+use crate::mllp::{AckCoalescing, AckMode, AckOptions, ListenerStatus, MessageHandler, MllpClient, MllpServer};
+use crate::ordering::OrderedSink;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::report::ErrorReporter;
+use crate::retention::RetentionPolicy;
+use crate::router::{Destination, EndpointPool, Predicate, Route, Router, Strategy};
+use crate::schedule::{spool_key, Schedule, ScheduledSink};
+use crate::state::StateBackend;
+use crate::terser::TerserPath;
+use crate::transform::{Pipeline, TransformStep};
+use crate::watchdog::Watchdog;
+use crate::webhook::{WebhookFormat, WebhookSink};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Errors that can occur loading or applying a config file
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Config parse error: {0}")]
+    ParseError(String),
+
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+/// A port the server listens on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    /// Address to bind, e.g. "0.0.0.0:2575"
+    pub address: String,
+    /// Charset assumed when MSH-18 is empty, e.g. "8859/1"
+    #[serde(default)]
+    pub charset: Option<String>,
+    /// Acknowledgment behavior for this listener
+    #[serde(default)]
+    pub ack: AckOptions,
+    /// Accept only TLS connections; requires the `tls` feature
+    #[serde(default)]
+    pub tls: Option<ListenerTlsConfig>,
+    /// Limit how fast messages are accepted, per source IP, sending application or listener
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Join continuation messages (DSC/ADD) before routing them
+    #[serde(default)]
+    pub reassemble: bool,
+    /// Keep at most this many connections open, leaving others waiting to be accepted
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Frames a connection can have waiting to be handled, 16 by default
+    #[serde(default)]
+    pub pipeline_depth: Option<usize>,
+    /// Largest frame accepted, 100,000 bytes by default
+    #[serde(default)]
+    pub max_frame_bytes: Option<usize>,
+    /// Run handlers on a dedicated pool of this many threads instead of Tokio's blocking pool
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    /// Write ACKs in batches, for senders that push many messages per connection
+    #[serde(default)]
+    pub ack_coalescing: Option<AckCoalescing>,
+    /// NACK a message if its handler hasn't finished this many seconds after it arrived
+    #[serde(default)]
+    pub handler_timeout_secs: Option<u64>,
+    /// Lanes that get handlers a worker thread in priority order, highest first
+    #[serde(default)]
+    pub lanes: Vec<Lane>,
+    /// Acknowledge messages whose control ID was seen recently without handling them again
+    #[serde(default)]
+    pub dedup: Option<DedupConfig>,
+}
+
+/// How long a listener remembers control IDs for spotting resent messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupConfig {
+    /// Control IDs remembered in this process
+    #[serde(default = "default_dedup_capacity")]
+    pub capacity: usize,
+    /// Seconds control IDs are remembered in `shared_state`, when it's set
+    #[serde(default = "default_dedup_window")]
+    pub window_secs: u64,
+}
+
+fn default_dedup_capacity() -> usize {
+    10_000
+}
+
+fn default_dedup_window() -> u64 {
+    24 * 3600
+}
+
+/// Certificate and key a listener presents, as PEM files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenerTlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Require clients to present a certificate signed by a CA in this file
+    #[serde(default)]
+    pub client_ca_file: Option<PathBuf>,
+}
+
+/// A sending application expected to send messages regularly
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SilenceConfig {
+    /// Sending application (MSH-3)
+    pub sender: String,
+    /// Alert after this many minutes without a message
+    pub quiet_minutes: u64,
+}
+
+/// Alert when too many messages are NACKed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NackRateConfig {
+    /// Fraction of messages, from 0.0 to 1.0
+    pub threshold: f64,
+    /// Minutes of traffic the rate is measured over
+    #[serde(default = "default_nack_window")]
+    pub window_minutes: u64,
+    /// Fewest messages in the window before the rate counts
+    #[serde(default = "default_nack_min_messages")]
+    pub min_messages: usize,
+}
+
+fn default_nack_window() -> u64 {
+    15
+}
+
+fn default_nack_min_messages() -> usize {
+    10
+}
+
+/// Alerts for feeds that go quiet and NACK rates that climb
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    #[serde(default)]
+    pub sources: Vec<SilenceConfig>,
+    #[serde(default)]
+    pub nack_rate: Option<NackRateConfig>,
+    /// URL alerts are POSTed to as JSON; alerts are always logged
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// How often the conditions are checked
+    #[serde(default = "default_watchdog_check_secs")]
+    pub check_secs: u64,
+}
+
+fn default_watchdog_check_secs() -> u64 {
+    30
+}
+
+impl WatchdogConfig {
+    /// Build the watchdog described by this config
+    pub fn build(&self) -> Watchdog {
+        let mut watchdog = Watchdog::new();
+        for source in &self.sources {
+            watchdog = watchdog.watch_source(&source.sender, Duration::from_secs(source.quiet_minutes * 60));
+        }
+        if let Some(rule) = &self.nack_rate {
+            watchdog = watchdog.nack_rate(rule.threshold, Duration::from_secs(rule.window_minutes * 60), rule.min_messages);
+        }
+        if let Some(url) = &self.webhook {
+            watchdog = watchdog.webhook(url);
+        }
+        watchdog
+    }
+}
+
+/// Where the hash-chained audit log is written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// JSON lines file entries are appended to, e.g. "/var/lib/rust-hl7/audit.jsonl"
+    pub path: PathBuf,
+}
+
+/// HTTP health and readiness endpoints for orchestrators such as Kubernetes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// Address to serve `/healthz` and `/readyz` on, e.g. "0.0.0.0:8080"
+    pub address: String,
+    /// Loop a synthetic message through a loopback listener on each `/healthz`
+    #[serde(default)]
+    pub self_test: bool,
+}
+
+/// Redis holding dedup, rate limit and ACK control ID state for every instance
+///
+/// Set it when several instances share traffic behind a load balancer.
+/// Requires the `redis` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedStateConfig {
+    /// e.g. "redis://state.internal:6379/0"
+    pub redis: String,
+    /// Put before every key, "rust-hl7:" by default
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+impl SharedStateConfig {
+    /// Connect to the backend; Redis isn't contacted until it's first used
+    pub fn open(&self) -> Result<Arc<dyn StateBackend>, ConfigError> {
+        #[cfg(feature = "redis")]
+        {
+            let state = crate::state::RedisState::open(&self.redis)
+                .map_err(|e| ConfigError::Invalid(format!("Shared state: {}", e)))?;
+            Ok(Arc::new(match &self.prefix {
+                Some(prefix) => state.with_prefix(prefix),
+                None => state,
+            }))
+        }
+        #[cfg(not(feature = "redis"))]
+        Err(ConfigError::Invalid("Shared state requires the `redis` feature".to_string()))
+    }
+}
+
+/// Run as one of an active/standby pair sharing `shared_state`
+///
+/// Only the active instance runs listeners, sources, the gRPC service and
+/// scheduled deliveries; the standby reports not ready until it takes over.
+/// Give both instances the same config apart from `node`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StandbyConfig {
+    /// Name this instance holds the lease under, `$HOSTNAME` by default
+    #[serde(default)]
+    pub node: Option<String>,
+    /// Seconds between lease renewals
+    #[serde(default = "default_heartbeat")]
+    pub heartbeat_secs: u64,
+    /// Seconds the standby waits after the active instance stops renewing
+    #[serde(default = "default_lease")]
+    pub lease_secs: u64,
+}
+
+fn default_heartbeat() -> u64 {
+    2
+}
+
+fn default_lease() -> u64 {
+    10
+}
+
+impl StandbyConfig {
+    /// The failover instance for this config, holding its lease in `state`
+    pub fn build(&self, state: Arc<dyn StateBackend>) -> Failover {
+        let node = self
+            .node
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| format!("rust-hl7-{}", std::process::id()));
+        Failover::new(state, node)
+            .with_heartbeat(Duration::from_secs(self.heartbeat_secs))
+            .with_lease(Duration::from_secs(self.lease_secs))
+    }
+}
+
+/// A gRPC service for parsing, validating and submitting messages
+///
+/// Requires the `grpc` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    /// Address to serve `Hl7Service` on, e.g. "0.0.0.0:50051"
+    pub address: String,
+    /// Accept only TLS connections; requires the `tls` feature
+    #[serde(default)]
+    pub tls: Option<ListenerTlsConfig>,
+    /// Environment variable holding a token every call must bear (`authorization: Bearer <token>`)
+    #[serde(default)]
+    pub token_env: Option<String>,
+}
+
+/// Where errors, traces and metrics are reported
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// Sentry DSN; errors aren't reported anywhere if unset. Requires the `sentry` feature
+    #[serde(default)]
+    pub sentry_dsn: Option<String>,
+    /// Environment reported with each event, e.g. "production"
+    #[serde(default)]
+    pub environment: Option<String>,
+    /// Fraction of error events sent, from 0.0 to 1.0
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f32,
+    /// OpenTelemetry collector to export traces and metrics to over OTLP/HTTP,
+    /// e.g. "http://localhost:4318"; requires the `otel` feature
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Service name traces and metrics are reported under
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "rust-hl7".to_string()
+}
+
+fn default_sample_rate() -> f32 {
+    1.0
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            sentry_dsn: None,
+            environment: None,
+            sample_rate: default_sample_rate(),
+            otlp_endpoint: None,
+            service_name: default_service_name(),
+        }
+    }
+}
+
+/// How often log files are started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    /// Always write to the same file
+    Never,
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line of text per event, prefixed with its spans
+    #[default]
+    Text,
+    /// One JSON object per event, with its spans' fields, for log aggregators
+    Json,
+}
+
+/// Where and how much the server logs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Directory log files are written to
+    pub directory: PathBuf,
+    /// Log file name; rotated files get a date suffix
+    pub file_name: String,
+    pub rotation: LogRotation,
+    /// Delete log files older than this many days, at startup and hourly while serving
+    pub retention_days: u64,
+    /// Keep at most this many log files
+    pub max_files: Option<usize>,
+    /// Keep at most this many bytes of log files
+    pub max_bytes: Option<u64>,
+    /// Overwrite log files before deleting them, since they may hold message contents
+    pub secure_delete: bool,
+    /// Most detailed level logged: "error", "warn", "info", "debug" or "trace"
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl LoggingConfig {
+    /// How long log files are kept
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: Some(self.retention_days),
+            max_count: self.max_files,
+            max_bytes: self.max_bytes,
+            secure_delete: self.secure_delete,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("logs"),
+            file_name: "rust-hl7.log".to_string(),
+            rotation: LogRotation::Daily,
+            retention_days: 7,
+            max_files: None,
+            max_bytes: None,
+            secure_delete: false,
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// A NATS server and subject, used by `nats_sources` and NATS destinations
+///
+/// Requires the `nats` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatsConfig {
+    /// Server URL, e.g. "nats://localhost:4222"
+    pub url: String,
+    pub subject: String,
+    /// Queue group shared by instances that should split a source's messages
+    #[serde(default)]
+    pub queue_group: Option<String>,
+}
+
+/// An HTTP endpoint a destination POSTs messages to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` URL
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Environment variable holding the key requests are signed with, unsigned if unset
+    #[serde(default)]
+    pub secret_env: Option<String>,
+    /// Tries per message before it's given up on
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubling after each
+    #[serde(default = "default_webhook_retry")]
+    pub retry_secs: u64,
+    /// Requests open at once
+    #[serde(default = "default_webhook_concurrency")]
+    pub max_concurrent: usize,
+    /// Directory messages are kept in until the webhook accepts them; required
+    /// unless the destination has `ordering` or a `schedule`, which spool already
+    #[serde(default)]
+    pub spool: Option<PathBuf>,
+}
+
+fn default_webhook_attempts() -> u32 {
+    3
+}
+
+fn default_webhook_retry() -> u64 {
+    1
+}
+
+fn default_webhook_concurrency() -> usize {
+    4
+}
+
+/// A directory to pick up HL7 files from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSourceConfig {
+    pub directory: PathBuf,
+    /// Where handled files are moved, `<directory>/processed` if unset
+    #[serde(default)]
+    pub processed: Option<PathBuf>,
+    /// Where failed files are moved, `<directory>/error` if unset
+    #[serde(default)]
+    pub error: Option<PathBuf>,
+    /// Seconds between scans of the directory
+    #[serde(default)]
+    pub poll_secs: Option<u64>,
+    /// Charset assumed when MSH-18 is empty
+    #[serde(default)]
+    pub charset: Option<String>,
+}
+
+/// When a destination's batch files are rolled over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchConfig {
+    #[serde(default = "default_batch_messages")]
+    pub max_messages: usize,
+    #[serde(default = "default_batch_age")]
+    pub max_age_secs: u64,
+}
+
+fn default_batch_messages() -> usize {
+    1000
+}
+
+fn default_batch_age() -> u64 {
+    300
+}
+
+/// How a destination keeps messages for the same patient or visit in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderingConfig {
+    /// Paths whose first non-empty value is the key, e.g. ["PV1-19", "PID-3.1"]
+    pub by: Vec<String>,
+    /// Directory queued messages are kept in until they're delivered
+    pub spool: PathBuf,
+    /// Tries per message before its key is parked and an error raised
+    #[serde(default = "default_ordering_attempts")]
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubling after each
+    #[serde(default = "default_ordering_retry")]
+    pub retry_secs: u64,
+    /// Messages held before new ones are refused with a NACK
+    #[serde(default = "default_ordering_pending")]
+    pub max_pending: usize,
+}
+
+fn default_ordering_attempts() -> u32 {
+    5
+}
+
+fn default_ordering_retry() -> u64 {
+    1
+}
+
+fn default_ordering_pending() -> usize {
+    10_000
+}
+
+/// When a destination's messages are delivered, if not as they arrive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    /// Times of day to deliver at, local time, e.g. ["02:00"]
+    pub at: Vec<chrono::NaiveTime>,
+    /// File messages are held in until then
+    pub spool: PathBuf,
+}
+
+/// A named downstream system that routes deliver to
+///
+/// Exactly one of `endpoints` (MLLP), `directory` (file drop), `nats`, `postgres` or `webhook` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    pub name: String,
+    /// MLLP endpoints; more than one forms a pool using `strategy`
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Seconds between health probes of pooled endpoints, disabled if unset
+    #[serde(default)]
+    pub health_check_secs: Option<u64>,
+    /// Seconds to wait for each downstream ACK or webhook response
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Split longer messages into continuation messages (DSC/ADD) for MLLP endpoints
+    #[serde(default)]
+    pub max_frame_bytes: Option<usize>,
+    /// Directory to write messages to
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// File name template for `directory`, e.g. "{msgtype}_{controlid}_{timestamp}.hl7"
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Append to rolling batch files in `directory` instead of one file per message
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    /// NATS subject to publish messages to
+    #[serde(default)]
+    pub nats: Option<NatsConfig>,
+    /// Postgres database URL to store clinical data in; requires the `postgres` feature
+    #[serde(default)]
+    pub postgres: Option<String>,
+    /// HTTP endpoint to POST messages to as JSON
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    /// Hold messages and deliver them at set times: one batch file for a
+    /// `directory`, a burst of messages otherwise
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+    /// Deliver messages with the same key one at a time, in the order received
+    #[serde(default)]
+    pub ordering: Option<OrderingConfig>,
+}
+
+impl DestinationConfig {
+    /// Build the destination, without starting health checks or scheduled deliveries
+    fn build(&self) -> Destination {
+        self.wrap(self.build_direct())
+    }
+
+    /// Directory messages queued for delivery are kept in, from `ordering` or the webhook
+    fn queue_spool(&self) -> Option<&Path> {
+        match (&self.ordering, &self.webhook) {
+            (Some(ordering), _) => Some(&ordering.spool),
+            (None, Some(webhook)) => webhook.spool.as_deref(),
+            (None, None) => None,
+        }
+    }
+
+    /// Every spool the destination keeps messages in
+    fn spools(&self) -> impl Iterator<Item = &Path> {
+        self.queue_spool().into_iter().chain(self.schedule.as_ref().map(|schedule| schedule.spool.as_path()))
+    }
+
+    /// Wrap the destination messages are handed to in ordering and scheduling
+    ///
+    /// A webhook with a spool and no ordering gets an ordered sink without keys,
+    /// so each message is spooled until it's accepted.
+    fn wrap(&self, destination: Destination) -> Destination {
+        let destination = match (&self.ordering, &self.webhook) {
+            (Some(ordering), _) => Destination::Ordered(
+                OrderedSink::new(destination, &ordering.by)
+                    .with_retries(ordering.max_attempts, Duration::from_secs(ordering.retry_secs))
+                    .with_spool(&ordering.spool)
+                    .max_pending(ordering.max_pending),
+            ),
+            (None, Some(WebhookConfig { spool: Some(spool), max_attempts, retry_secs, .. })) => Destination::Ordered(
+                OrderedSink::new(destination, Vec::<String>::new())
+                    .with_retries(*max_attempts, Duration::from_secs(*retry_secs))
+                    .with_spool(spool),
+            ),
+            _ => destination,
+        };
+        match &self.schedule {
+            Some(schedule) => Destination::Scheduled(ScheduledSink::new(
+                destination,
+                Schedule::daily(schedule.at.iter().copied()),
+                &schedule.spool,
+            )),
+            None => destination,
+        }
+    }
+
+    /// Build the destination that messages are handed to
+    fn build_direct(&self) -> Destination {
+        #[cfg(feature = "nats")]
+        if let Some(nats) = &self.nats {
+            return Destination::Nats(crate::nats::NatsSink::new(&nats.url, &nats.subject));
+        }
+
+        #[cfg(feature = "postgres")]
+        if let Some(url) = &self.postgres {
+            return Destination::Postgres(crate::postgres::PostgresSink::new(url));
+        }
+
+        if let Some(webhook) = &self.webhook {
+            // A spool around the webhook does the retrying, so each delivery from it is one try
+            let attempts = match self.ordering.is_none() && webhook.spool.is_some() {
+                true => 1,
+                false => webhook.max_attempts,
+            };
+            let mut sink = WebhookSink::new(&webhook.url)
+                .format(webhook.format)
+                .with_retries(attempts, Duration::from_secs(webhook.retry_secs))
+                .max_concurrent(webhook.max_concurrent);
+            if let Some(secs) = self.timeout_secs {
+                sink = sink.timeout(Duration::from_secs(secs));
+            }
+            if let Some(secret) = webhook.secret_env.as_ref().and_then(|name| std::env::var(name).ok()) {
+                sink = sink.secret(secret);
+            }
+            return Destination::Webhook(sink);
+        }
+
+        match &self.directory {
+            Some(directory) => {
+                let mut sink = FileSink::new(directory);
+                if let Some(template) = &self.filename {
+                    sink = sink.with_template(template);
+                }
+                if let Some(batch) = &self.batch {
+                    sink = sink.with_rollover(Rollover {
+                        max_messages: batch.max_messages,
+                        max_age: Duration::from_secs(batch.max_age_secs),
+                    });
+                }
+                Destination::File(sink)
+            }
+            None => {
+                let clients = self.endpoints.iter().map(|address| {
+                    let client = MllpClient::new(address);
+                    let client = match self.max_frame_bytes {
+                        Some(max) => client.with_max_frame_bytes(max),
+                        None => client,
+                    };
+                    match self.timeout_secs {
+                        Some(secs) => client.with_timeout(Duration::from_secs(secs)),
+                        None => client,
+                    }
+                });
+                Destination::Pool(Arc::new(EndpointPool::new(clients, self.strategy)))
+            }
+        }
+    }
+}
+
+/// A Rhai script accepting or rejecting each message before it's routed
+///
+/// Exactly one of `script` and `file` must be set. Requires the `rules` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    /// The script itself
+    #[serde(default)]
+    pub script: Option<String>,
+    /// A file holding the script, read again whenever the config is reloaded
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+/// A route from matching messages to named destinations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConfig {
+    pub name: String,
+    /// Which messages this route matches; every message if unset
+    #[serde(default = "always")]
+    pub when: Predicate,
+    /// Names of destinations from the `destinations` section
+    pub destinations: Vec<String>,
+    /// Transform steps applied before delivery
+    #[serde(default)]
+    pub transforms: Vec<TransformStep>,
+}
+
+/// An endpoint pool and the interval it should be probed at
+pub type HealthCheck = (Arc<EndpointPool>, Duration);
+
+fn always() -> Predicate {
+    Predicate::Always
+}
+
+/// Listeners, destinations, routes, logging and telemetry for a config-driven server
+///
+/// ```toml
+/// [telemetry]
+/// sentry_dsn = "https://key@sentry.example.org/42"
+/// environment = "production"
+/// otlp_endpoint = "http://otel-collector:4318"
+///
+/// [logging]
+/// directory = "/var/log/rust-hl7"
+/// retention_days = 30
+/// max_bytes = 1_000_000_000
+///
+/// [[listeners]]
+/// address = "0.0.0.0:2575"
+///
+/// [[listeners]]
+/// address = "0.0.0.0:2576"
+/// tls = { cert_file = "server.pem", key_file = "server.key" }
+///
+/// [health]
+/// address = "0.0.0.0:8080"
+///
+/// [grpc]
+/// address = "0.0.0.0:50051"
+/// tls = { cert_file = "server.pem", key_file = "server.key" }
+/// token_env = "HL7_GRPC_TOKEN"
+///
+/// [shared_state]
+/// redis = "redis://state.internal:6379/0"
+///
+/// [standby]
+/// node = "hl7-a"
+///
+/// [audit]
+/// path = "/var/lib/rust-hl7/audit.jsonl"
+///
+/// [watchdog]
+/// sources = [{ sender = "REG", quiet_minutes = 30 }]
+/// nack_rate = { threshold = 0.1 }
+/// webhook = "https://hooks.example.org/hl7-alerts"
+///
+/// [[destinations]]
+/// name = "lab"
+/// endpoints = ["lab-a:2575", "lab-b:2575"]
+/// strategy = "failover"
+/// health_check_secs = 30
+///
+/// [[routes]]
+/// name = "results"
+/// when = { message_type = "ORU" }
+/// destinations = ["lab"]
+/// transforms = [{ op = "set_field", path = "MSH-5", value = "LAB" }]
+/// ```
+///
+/// Settings can be overridden with environment variables, e.g. to keep the
+/// Sentry DSN out of a checked-in file (see `apply_env`). Telemetry and logging
+/// are set up once at startup, so changing them takes a restart rather than a
+/// reload.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub destinations: Vec<DestinationConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Scripts every message must pass before it's routed, in order
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// NATS subjects to receive messages from, in addition to the listeners
+    #[serde(default)]
+    pub nats_sources: Vec<NatsConfig>,
+    /// Directories to pick up HL7 files from, in addition to the listeners
+    #[serde(default)]
+    pub file_sources: Vec<FileSourceConfig>,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Serve health and readiness endpoints
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Accept messages over gRPC as well as from the listeners
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    /// Record every message received and forwarded in a tamper-evident audit log
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    /// Alert when feeds go quiet or NACK rates climb
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    /// Share dedup, rate limits and ACK control IDs with other instances
+    #[serde(default)]
+    pub shared_state: Option<SharedStateConfig>,
+    /// Take traffic only while this instance is the active one of a pair
+    #[serde(default)]
+    pub standby: Option<StandbyConfig>,
+}
+
+impl ServerConfig {
+    /// Load a config from a `.toml`, `.yaml`/`.yml` or `.json` file, applying
+    /// overrides from the environment
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut config: ServerConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?,
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?
+            }
+            Some("json") => serde_json::from_str(&contents).map_err(|e| ConfigError::ParseError(e.to_string()))?,
+            _ => {
+                return Err(ConfigError::ParseError(format!(
+                    "Unknown config format: {}",
+                    path.display()
+                )))
+            }
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings from environment variables, looked up with `var`
+    ///
+    /// | Variable | Setting |
+    /// |---|---|
+    /// | `RUST_HL7_SENTRY_DSN` | `telemetry.sentry_dsn`; empty turns reporting off |
+    /// | `RUST_HL7_ENVIRONMENT` | `telemetry.environment` |
+    /// | `OTEL_EXPORTER_OTLP_ENDPOINT` | `telemetry.otlp_endpoint`; empty turns export off |
+    /// | `OTEL_SERVICE_NAME` | `telemetry.service_name` |
+    /// | `RUST_HL7_LOG_DIR` | `logging.directory` |
+    /// | `RUST_HL7_LOG_LEVEL` | `logging.level` |
+    /// | `RUST_HL7_LOG_FORMAT` | `logging.format` |
+    /// | `RUST_HL7_LOG_RETENTION_DAYS` | `logging.retention_days` |
+    /// | `RUST_HL7_LISTEN` | listener addresses, comma-separated |
+    /// | `RUST_HL7_REDIS_URL` | `shared_state.redis`, e.g. to keep its password out of the file |
+    ///
+    /// Listeners from `RUST_HL7_LISTEN` take their other settings from the
+    /// first configured listener, if any.
+    pub fn apply_env<F>(&mut self, var: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(dsn) = var("RUST_HL7_SENTRY_DSN") {
+            self.telemetry.sentry_dsn = Some(dsn).filter(|dsn| !dsn.is_empty());
+        }
+        if let Some(environment) = var("RUST_HL7_ENVIRONMENT") {
+            self.telemetry.environment = Some(environment);
+        }
+        if let Some(endpoint) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint).filter(|endpoint| !endpoint.is_empty());
+        }
+        if let Some(service_name) = var("OTEL_SERVICE_NAME") {
+            self.telemetry.service_name = service_name;
+        }
+        if let Some(directory) = var("RUST_HL7_LOG_DIR") {
+            self.logging.directory = PathBuf::from(directory);
+        }
+        if let Some(level) = var("RUST_HL7_LOG_LEVEL") {
+            self.logging.level = level;
+        }
+        if let Some(format) = var("RUST_HL7_LOG_FORMAT") {
+            self.logging.format = match format.as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => return Err(ConfigError::Invalid(format!("RUST_HL7_LOG_FORMAT must be text or json, not {}", format))),
+            };
+        }
+        if let Some(days) = var("RUST_HL7_LOG_RETENTION_DAYS") {
+            self.logging.retention_days = days
+                .parse()
+                .map_err(|_| ConfigError::Invalid(format!("RUST_HL7_LOG_RETENTION_DAYS is not a number: {}", days)))?;
+        }
+        if let Some(url) = var("RUST_HL7_REDIS_URL") {
+            let prefix = self.shared_state.take().and_then(|state| state.prefix);
+            self.shared_state = Some(SharedStateConfig { redis: url, prefix });
+        }
+        if let Some(addresses) = var("RUST_HL7_LISTEN") {
+            let template = self.listeners.first().cloned().unwrap_or(ListenerConfig {
+                address: String::new(),
+                charset: None,
+                ack: AckOptions::default(),
+                tls: None,
+                rate_limit: None,
+                reassemble: false,
+                max_connections: None,
+                pipeline_depth: None,
+                max_frame_bytes: None,
+                worker_threads: None,
+                ack_coalescing: None,
+                handler_timeout_secs: None,
+                lanes: Vec::new(),
+                dedup: None,
+            });
+            self.listeners = addresses
+                .split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(|address| ListenerConfig { address: address.to_string(), ..template.clone() })
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Check the config for problems that parsing alone can't catch
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let charsets = self.listeners.iter().map(|l| &l.charset).chain(self.file_sources.iter().map(|f| &f.charset));
+        for charset in charsets.flatten() {
+            Charset::from_hl7(charset)
+                .ok_or_else(|| ConfigError::Invalid(format!("Unsupported charset: {}", charset)))?;
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.sample_rate) {
+            return Err(ConfigError::Invalid(format!(
+                "Telemetry sample_rate must be between 0 and 1, not {}",
+                self.telemetry.sample_rate
+            )));
+        }
+        if cfg!(not(feature = "sentry")) && self.telemetry.sentry_dsn.is_some() {
+            return Err(ConfigError::Invalid("Telemetry sentry_dsn requires the `sentry` feature".to_string()));
+        }
+        if cfg!(not(feature = "otel")) && self.telemetry.otlp_endpoint.is_some() {
+            return Err(ConfigError::Invalid("Telemetry otlp_endpoint requires the `otel` feature".to_string()));
+        }
+        self.logging
+            .level
+            .parse::<tracing::Level>()
+            .map_err(|_| ConfigError::Invalid(format!("Unknown log level: {}", self.logging.level)))?;
+
+        if cfg!(not(feature = "tls")) && self.listeners.iter().any(|l| l.tls.is_some()) {
+            return Err(ConfigError::Invalid("TLS listeners require the `tls` feature".to_string()));
+        }
+
+        for listener in &self.listeners {
+            if let Some(limit) = listener.rate_limit.as_ref().filter(|l| l.per_second <= 0.0 || l.per_second.is_nan()) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: rate_limit per_second must be above 0, not {}",
+                    listener.address, limit.per_second
+                )));
+            }
+            if listener.max_connections == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: max_connections must be above 0",
+                    listener.address
+                )));
+            }
+            if listener.pipeline_depth == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: pipeline_depth must be above 0",
+                    listener.address
+                )));
+            }
+            if listener.max_frame_bytes == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: max_frame_bytes must be above 0",
+                    listener.address
+                )));
+            }
+            if listener.worker_threads == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: worker_threads must be above 0",
+                    listener.address
+                )));
+            }
+            for lane in &listener.lanes {
+                if lane.max_queued == Some(0) {
+                    return Err(ConfigError::Invalid(format!(
+                        "Listener on {}, lane '{}': max_queued must be above 0",
+                        listener.address, lane.name
+                    )));
+                }
+            }
+            if listener.handler_timeout_secs == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "Listener on {}: handler_timeout_secs must be at least 1",
+                    listener.address
+                )));
+            }
+        }
+
+        if let Some(watchdog) = &self.watchdog {
+            if let Some(rule) = watchdog.nack_rate.as_ref().filter(|r| !(0.0..=1.0).contains(&r.threshold)) {
+                return Err(ConfigError::Invalid(format!(
+                    "Watchdog nack_rate threshold must be between 0 and 1, not {}",
+                    rule.threshold
+                )));
+            }
+            match watchdog.webhook.as_deref() {
+                None => {}
+                Some(url) if url.starts_with("http://") => {}
+                Some(url) if url.starts_with("https://") && cfg!(feature = "tls") => {}
+                Some(url) if url.starts_with("https://") => {
+                    return Err(ConfigError::Invalid("HTTPS webhooks require the `tls` feature".to_string()))
+                }
+                Some(url) => return Err(ConfigError::Invalid(format!("Unsupported webhook URL: {}", url))),
+            }
+            if watchdog.check_secs == 0 {
+                return Err(ConfigError::Invalid("Watchdog check_secs must be at least 1".to_string()));
+            }
+        }
+
+        // Spools by where they really are, so two spellings of one file are caught
+        let mut spool_owners = HashMap::new();
+        for destination in &self.destinations {
+            let kinds = [
+                !destination.endpoints.is_empty(),
+                destination.directory.is_some(),
+                destination.nats.is_some(),
+                destination.postgres.is_some(),
+                destination.webhook.is_some(),
+            ];
+            if kinds.iter().filter(|&&set| set).count() != 1 {
+                return Err(ConfigError::Invalid(format!(
+                    "Destination '{}' must have exactly one of endpoints, a directory, nats, postgres or a webhook",
+                    destination.name
+                )));
+            }
+            if let Some(webhook) = &destination.webhook {
+                let invalid = |problem: &str| Err(ConfigError::Invalid(format!("Destination '{}' {}", destination.name, problem)));
+                if webhook.url.starts_with("https://") && cfg!(not(feature = "tls")) {
+                    return invalid("posts to an HTTPS webhook, which requires the `tls` feature");
+                }
+                if !webhook.url.starts_with("https://") && !webhook.url.starts_with("http://") {
+                    return invalid(&format!("has an unsupported webhook URL: {}", webhook.url));
+                }
+                if webhook.format == WebhookFormat::Fhir && cfg!(not(feature = "fhir")) {
+                    return invalid("posts FHIR bundles, which requires the `fhir` feature");
+                }
+                if let Some(name) = webhook.secret_env.as_ref().filter(|name| std::env::var_os(name).is_none()) {
+                    return invalid(&format!("signs webhooks with {}, which isn't set", name));
+                }
+                if webhook.spool.is_none() && destination.ordering.is_none() && destination.schedule.is_none() {
+                    return invalid("posts to a webhook without a spool, ordering or schedule to keep messages in");
+                }
+            }
+            if destination.directory.is_none() && (destination.filename.is_some() || destination.batch.is_some()) {
+                return Err(ConfigError::Invalid(format!(
+                    "Destination '{}' sets filename or batch without a directory",
+                    destination.name
+                )));
+            }
+            if let Some(ordering) = &destination.ordering {
+                if ordering.by.is_empty() {
+                    return Err(ConfigError::Invalid(format!(
+                        "Destination '{}' has ordering without any key paths",
+                        destination.name
+                    )));
+                }
+                for path in &ordering.by {
+                    path.parse::<TerserPath>()
+                        .map_err(|e| ConfigError::Invalid(format!("Destination '{}' ordering: {}", destination.name, e)))?;
+                }
+            }
+            for spool in destination.spools() {
+                let shared = spool_owners.insert(spool_key(spool), &destination.name);
+                if let Some(other) = shared {
+                    return Err(ConfigError::Invalid(format!(
+                        "Destinations '{}' and '{}' share the spool {}",
+                        other,
+                        destination.name,
+                        spool.display()
+                    )));
+                }
+            }
+            if let Some(schedule) = &destination.schedule {
+                if schedule.at.is_empty() {
+                    return Err(ConfigError::Invalid(format!(
+                        "Destination '{}' has a schedule without any times",
+                        destination.name
+                    )));
+                }
+                if destination.batch.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "Destination '{}' sets both batch and schedule; a schedule writes one batch file per window",
+                        destination.name
+                    )));
+                }
+            }
+        }
+
+        if cfg!(not(feature = "nats"))
+            && (!self.nats_sources.is_empty() || self.destinations.iter().any(|d| d.nats.is_some()))
+        {
+            return Err(ConfigError::Invalid(
+                "NATS sources and destinations require the `nats` feature".to_string(),
+            ));
+        }
+
+        if cfg!(not(feature = "grpc")) && self.grpc.is_some() {
+            return Err(ConfigError::Invalid("The gRPC service requires the `grpc` feature".to_string()));
+        }
+        if let Some(grpc) = &self.grpc {
+            if cfg!(not(feature = "tls")) && grpc.tls.is_some() {
+                return Err(ConfigError::Invalid("gRPC over TLS requires the `tls` feature".to_string()));
+            }
+            if let Some(name) = grpc.token_env.as_ref().filter(|name| std::env::var_os(name).is_none()) {
+                return Err(ConfigError::Invalid(format!("The gRPC token is read from {}, which isn't set", name)));
+            }
+        }
+
+        if cfg!(not(feature = "redis")) && self.shared_state.is_some() {
+            return Err(ConfigError::Invalid("Shared state requires the `redis` feature".to_string()));
+        }
+
+        if let Some(standby) = &self.standby {
+            if self.shared_state.is_none() {
+                return Err(ConfigError::Invalid("Standby requires shared_state".to_string()));
+            }
+            if standby.heartbeat_secs == 0 || standby.lease_secs <= standby.heartbeat_secs {
+                return Err(ConfigError::Invalid(format!(
+                    "Standby lease_secs ({}) must be longer than heartbeat_secs ({}), which can't be 0",
+                    standby.lease_secs, standby.heartbeat_secs
+                )));
+            }
+        }
+
+        if cfg!(not(feature = "postgres")) && self.destinations.iter().any(|d| d.postgres.is_some()) {
+            return Err(ConfigError::Invalid(
+                "Postgres destinations require the `postgres` feature".to_string(),
+            ));
+        }
+
+        for rule in &self.rules {
+            if rule.script.is_some() == rule.file.is_some() {
+                return Err(ConfigError::Invalid(format!(
+                    "Rule '{}' must have exactly one of script or file",
+                    rule.name
+                )));
+            }
+        }
+        if cfg!(not(feature = "rules")) && !self.rules.is_empty() {
+            return Err(ConfigError::Invalid("Rules require the `rules` feature".to_string()));
+        }
+
+        for route in &self.routes {
+            for name in &route.destinations {
+                if !self.destinations.iter().any(|d| &d.name == name) {
+                    return Err(ConfigError::Invalid(format!(
+                        "Route '{}' refers to unknown destination '{}'",
+                        route.name, name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the named destination on its own, e.g. to replay messages to it
+    pub fn destination(&self, name: &str) -> Result<Destination, ConfigError> {
+        self.destinations
+            .iter()
+            .find(|d| d.name == name)
+            .map(DestinationConfig::build)
+            .ok_or_else(|| ConfigError::Invalid(format!("Unknown destination '{}'", name)))
+    }
+
+    /// Build the router described by this config
+    ///
+    /// Also returns the endpoint pools that want periodic health checks.
+    pub fn build_router(&self) -> Result<(Router, Vec<HealthCheck>), ConfigError> {
+        self.validate()?;
+
+        let mut destinations = HashMap::new();
+        let mut health_checks = Vec::new();
+
+        for config in &self.destinations {
+            let destination = config.build_direct();
+            if let (Destination::Pool(pool), Some(secs)) = (&destination, config.health_check_secs) {
+                health_checks.push((pool.clone(), Duration::from_secs(secs)));
+            }
+            destinations.insert(config.name.clone(), config.wrap(destination));
+        }
+
+        let mut router = Router::new();
+        for config in &self.routes {
+            let mut route = Route::new(&config.name, config.when.clone());
+            if !config.transforms.is_empty() {
+                let pipeline = Pipeline::from_steps(&config.transforms)
+                    .map_err(|e| ConfigError::Invalid(format!("Route '{}': {}", config.name, e)))?;
+                route = route.with_transform(pipeline);
+            }
+            for name in &config.destinations {
+                route = route.to(destinations[name].clone());
+            }
+            router = router.route(route);
+        }
+
+        #[cfg(feature = "rules")]
+        for config in &self.rules {
+            let script = match (&config.script, &config.file) {
+                (Some(source), _) => crate::rules::Script::compile(source),
+                (None, Some(file)) => crate::rules::Script::load(file),
+                (None, None) => unreachable!("validated to have a script or a file"),
+            };
+            let script = script.map_err(|e| ConfigError::Invalid(format!("Rule '{}': {}", config.name, e)))?;
+            router = router.rule(crate::rules::Rule::from_script(&config.name, script));
+        }
+
+        Ok((router, health_checks))
+    }
+}
+
+/// A failover heartbeat running for a standby config, and the role it publishes
+type RunningFailover = (StandbyConfig, Arc<Failover>, watch::Receiver<Role>, JoinHandle<()>);
+
+/// Runs the listeners and router described by a config file, re-applying it when it changes
+///
+/// Reloads happen on SIGHUP (on Unix) or when the modification time of the file,
+/// or of a rule file it names, changes.
+/// Existing connections keep running through a reload: only the accept loops of
+/// removed or changed listeners are stopped, and in-flight messages finish with the
+/// router they started with. A config that fails to load is logged and ignored.
+/// Becoming a failover standby closes connections too. On Ctrl-C or SIGTERM,
+/// everything is stopped and the failover lease released.
+pub struct Supervisor {
+    path: PathBuf,
+    router: Arc<RwLock<Arc<Router>>>,
+    listeners: HashMap<String, (ListenerConfig, ListenerStatus, JoinHandle<()>)>,
+    readiness: Readiness,
+    health: Option<(HealthConfig, JoinHandle<()>)>,
+    grpc: Option<(GrpcConfig, JoinHandle<()>)>,
+    audit: Option<(AuditConfig, Arc<AuditLog>)>,
+    state: Option<(SharedStateConfig, Arc<dyn StateBackend>)>,
+    failover: Option<RunningFailover>,
+    /// The config last applied, applied again when the role changes
+    config: Option<ServerConfig>,
+    watchdog: Arc<RwLock<Option<Arc<Watchdog>>>>,
+    watchdog_task: Option<(WatchdogConfig, JoinHandle<()>)>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    health_checks: Vec<JoinHandle<()>>,
+    schedules: Vec<JoinHandle<()>>,
+    /// Ordering spools whose leftovers have been queued again
+    resumed: HashSet<PathBuf>,
+    sources: Vec<JoinHandle<()>>,
+    /// Rule files of the current config, watched along with it
+    rule_files: Vec<PathBuf>,
+    poll_interval: Duration,
+}
+
+impl Supervisor {
+    /// Create a supervisor for the config file at `path`
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            router: Arc::new(RwLock::new(Arc::new(Router::new()))),
+            listeners: HashMap::new(),
+            readiness: Readiness::new(),
+            health: None,
+            grpc: None,
+            audit: None,
+            state: None,
+            failover: None,
+            config: None,
+            watchdog: Arc::default(),
+            watchdog_task: None,
+            reporter: None,
+            health_checks: Vec::new(),
+            schedules: Vec::new(),
+            resumed: HashSet::new(),
+            sources: Vec::new(),
+            rule_files: Vec::new(),
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+
+    /// Set how often the config file is checked for changes
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Report errors from every listener started, e.g. to Sentry
+    pub fn with_error_reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// A handler that always routes with the most recently applied config
+    pub fn handler(&self) -> MessageHandler {
+        self.handler_acking(AckMode::default())
+    }
+
+    /// A handler for a listener acknowledging in `mode`, so the watchdog counts only real NACKs
+    fn handler_acking(&self, mode: AckMode) -> MessageHandler {
+        let router = self.router.clone();
+        let watchdog = self.watchdog.clone();
+        Arc::new(move |message| {
+            let current = router.read().unwrap().clone();
+            match watchdog.read().unwrap().clone() {
+                Some(watchdog) => watchdog.observe(mode, message, &|message| current.handle(message)),
+                None => current.handle(message),
+            }
+        })
+    }
+
+    /// The gRPC service for `config`, submitting to the current router
+    #[cfg(feature = "grpc")]
+    fn grpc_service(&self, config: &GrpcConfig) -> Result<crate::grpc::Hl7Service, ConfigError> {
+        #[allow(unused_mut)]
+        let mut service = crate::grpc::Hl7Service::new(self.handler());
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &config.tls {
+            service = service
+                .with_tls(&tls.cert_file, &tls.key_file, tls.client_ca_file.as_ref())
+                .map_err(|e| ConfigError::Invalid(format!("gRPC on {}: {}", config.address, e)))?;
+        }
+        if let Some(name) = &config.token_env {
+            let token = std::env::var(name)
+                .map_err(|_| ConfigError::Invalid(format!("The gRPC token is read from {}, which isn't set", name)))?;
+            service = service.with_token(token);
+        }
+        Ok(service)
+    }
+
+    /// The readiness checks for the current config: every listener and destination
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Addresses of the listeners currently running
+    pub fn listener_addresses(&self) -> Vec<String> {
+        self.listeners.keys().cloned().collect()
+    }
+
+    /// Apply a config, swapping the router and starting/stopping listeners as needed
+    pub async fn apply(&mut self, config: ServerConfig) -> Result<(), ConfigError> {
+        // Build everything first so a bad config leaves the running one untouched
+        let (mut router, pools) = config.build_router()?;
+        let audit_changed = self.audit.as_ref().map(|(current, _)| current) != config.audit.as_ref();
+        let audit = match &config.audit {
+            Some(audit) if audit_changed => {
+                let log = AuditLog::open(&audit.path)
+                    .map_err(|e| ConfigError::Invalid(format!("Audit log {}: {}", audit.path.display(), e)))?;
+                Some((audit.clone(), Arc::new(log)))
+            }
+            _ if audit_changed => None,
+            _ => self.audit.clone(),
+        };
+        if let Some((_, log)) = &audit {
+            router = router.with_audit(log.clone());
+        }
+        let state_changed = self.state.as_ref().map(|(current, _)| current) != config.shared_state.as_ref();
+        let state = match &config.shared_state {
+            Some(shared) if state_changed => Some((shared.clone(), shared.open()?)),
+            _ if state_changed => None,
+            _ => self.state.clone(),
+        };
+        #[cfg(feature = "grpc")]
+        let grpc_service = match &config.grpc {
+            Some(grpc) => Some(self.grpc_service(grpc)?),
+            None => None,
+        };
+        #[cfg(feature = "tls")]
+        let mut tls_configs = HashMap::new();
+        #[cfg(feature = "tls")]
+        for listener in &config.listeners {
+            if let Some(tls) = &listener.tls {
+                let server_config =
+                    crate::tls::server_config(&tls.cert_file, &tls.key_file, tls.client_ca_file.as_deref())
+                        .map_err(|e| ConfigError::Invalid(format!("Listener on {}: {}", listener.address, e)))?;
+                tls_configs.insert(listener.address.clone(), server_config);
+            }
+        }
+
+        let scheduled = router.scheduled();
+        let ordered = router.ordered();
+        self.rule_files = config.rules.iter().filter_map(|rule| rule.file.clone()).collect();
+        *self.router.write().unwrap() = Arc::new(router);
+        self.audit = audit;
+        self.state = state;
+        self.config = Some(config.clone());
+
+        // A new lease holder under the same node name takes the lease straight back
+        if state_changed || self.failover.as_ref().map(|(current, _, _, _)| current) != config.standby.as_ref() {
+            if let Some((_, _, _, handle)) = self.failover.take() {
+                handle.abort();
+            }
+            if let (Some(standby), Some((_, state))) = (&config.standby, &self.state) {
+                let failover = Arc::new(standby.build(state.clone()));
+                let (role, handle) = failover.clone().spawn().await;
+                self.failover = Some((standby.clone(), failover, role, handle));
+            }
+        }
+        // A standby keeps the router, health endpoints and watchdog but takes no traffic
+        let active = self.failover.as_ref().is_none_or(|(_, _, role, _)| *role.borrow() == Role::Active);
+        let config = match active {
+            true => config,
+            false => ServerConfig {
+                listeners: Vec::new(),
+                file_sources: Vec::new(),
+                nats_sources: Vec::new(),
+                grpc: None,
+                ..config
+            },
+        };
+
+        for handle in self.health_checks.drain(..) {
+            handle.abort();
+        }
+        self.health_checks = pools
+            .into_iter()
+            .map(|(pool, interval)| pool.spawn_health_checks(interval))
+            .collect();
+
+        // The new sinks pick up whatever the old ones left in their spools, so only the active instance drains them
+        for handle in self.schedules.drain(..) {
+            handle.abort();
+        }
+        if active {
+            self.schedules = scheduled.iter().map(ScheduledSink::spawn).collect();
+        }
+        // Queue what a previous run left, once per spool; sinks from before a reload still hold the rest
+        if active {
+            for sink in ordered {
+                let Some(spool) = sink.spool().map(Path::to_path_buf) else {
+                    continue;
+                };
+                if self.resumed.contains(&spool) {
+                    continue;
+                }
+                match sink.resume() {
+                    Ok(_) => {
+                        self.resumed.insert(spool);
+                    }
+                    Err(e) => error!("Failed to resume ordered messages from {}: {}", spool.display(), e),
+                }
+            }
+        }
+
+        // Stop listeners that were removed or changed, or all of them if the audit log or shared state moved
+        let wanted: HashMap<&str, &ListenerConfig> =
+            config.listeners.iter().map(|l| (l.address.as_str(), l)).collect();
+        let stale: Vec<String> = self
+            .listeners
+            .iter()
+            .filter(|(address, (current, _, _))| {
+                audit_changed || state_changed || wanted.get(address.as_str()) != Some(&current)
+            })
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in stale {
+            if let Some((_, status, handle)) = self.listeners.remove(&address) {
+                info!("Stopping listener on {}", address);
+                handle.abort();
+                // Connections live on through a reload, but a standby mustn't take another message
+                if !active {
+                    status.close_connections();
+                }
+                // Wait for the accept loop to drop its socket so the address can be rebound
+                let _ = handle.await;
+            }
+        }
+
+        // Start listeners that are new or changed
+        for listener in &config.listeners {
+            if self.listeners.contains_key(&listener.address) {
+                continue;
+            }
+            let charset = listener
+                .charset
+                .as_deref()
+                .and_then(Charset::from_hl7)
+                .unwrap_or_default();
+            let mut handler = self.handler_acking(listener.ack.mode);
+            if let Some(dedup) = &listener.dedup {
+                let dedup = match &self.state {
+                    Some((_, state)) => Dedup::shared(state.clone(), Duration::from_secs(dedup.window_secs)),
+                    None => Dedup::new(dedup.capacity),
+                };
+                handler = Chain::new().layer(dedup).wrap(handler);
+            }
+            let handler = match listener.reassemble {
+                true => Reassembler::new().wrap(handler),
+                false => handler,
+            };
+            let mut builder = MllpServer::builder()
+                .bind(&listener.address)
+                .handler(handler)
+                .default_charset(charset)
+                .ack_options(listener.ack.clone());
+            if let Some((_, log)) = &self.audit {
+                builder = builder.audit(log.clone());
+            }
+            if let Some(limit) = &listener.rate_limit {
+                let limiter = RateLimiter::new(limit.clone());
+                builder = builder.rate_limiter(match &self.state {
+                    Some((_, state)) => limiter.with_state(state.clone(), &listener.address),
+                    None => limiter,
+                });
+            }
+            if let Some((_, state)) = &self.state {
+                let name = format!("ack:{}", listener.address);
+                builder = builder.id_source(Arc::new(ReservedIds::new(state.clone(), &name, "ACK")));
+            }
+            if let Some(max) = listener.max_connections {
+                builder = builder.max_connections(max);
+            }
+            if let Some(depth) = listener.pipeline_depth {
+                builder = builder.pipeline_depth(depth);
+            }
+            if let Some(max) = listener.max_frame_bytes {
+                builder = builder.max_frame_bytes(max);
+            }
+            if let Some(threads) = listener.worker_threads {
+                builder = builder.worker_threads(threads);
+            }
+            if let Some(coalescing) = listener.ack_coalescing {
+                builder = builder.ack_coalescing(coalescing);
+            }
+            if let Some(secs) = listener.handler_timeout_secs {
+                builder = builder.handler_timeout(Duration::from_secs(secs));
+            }
+            if !listener.lanes.is_empty() {
+                builder = builder.priority_lanes(listener.lanes.iter().cloned().fold(PriorityLanes::new(), PriorityLanes::lane));
+            }
+            if let Some(reporter) = &self.reporter {
+                builder = builder.error_reporter(reporter.clone());
+            }
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls_configs.remove(&listener.address) {
+                builder = builder.tls(tls);
+            }
+            let server = builder.build().map_err(|e| ConfigError::Invalid(e.to_string()))?;
+            let status = server.status();
+            let address = listener.address.clone();
+            let handle = tokio::spawn(async move {
+                if let Err(e) = server.run().await {
+                    error!("Listener on {} stopped: {}", address, e);
+                }
+            });
+            info!("Started listener on {}", listener.address);
+            self.listeners.insert(listener.address.clone(), (listener.clone(), status, handle));
+        }
+
+        let mut checks: Vec<Check> = self
+            .listeners
+            .iter()
+            .map(|(address, (_, status, _))| Check::Listener { address: address.clone(), status: status.clone() })
+            .collect();
+        checks.sort_by_key(Check::name);
+        if let Some((_, _, role, _)) = &self.failover {
+            checks.push(Check::Role(role.clone()));
+        }
+        checks.extend(config.destinations.iter().map(|destination| Check::Destination {
+            name: destination.name.clone(),
+            destination: destination.build(),
+        }));
+        self.readiness.set(checks);
+
+        if self.health.as_ref().map(|(current, _)| current) != config.health.as_ref() {
+            if let Some((current, handle)) = self.health.take() {
+                info!("Stopping health endpoints on {}", current.address);
+                handle.abort();
+                let _ = handle.await;
+            }
+            if let Some(health) = &config.health {
+                let server = HealthServer::new(&health.address, self.readiness.clone()).with_self_test(health.self_test);
+                let address = health.address.clone();
+                let handle = tokio::spawn(async move {
+                    if let Err(e) = server.run().await {
+                        error!("Health endpoints on {} stopped: {}", address, e);
+                    }
+                });
+                self.health = Some((health.clone(), handle));
+            }
+        }
+
+        // The service submits to whichever router is current, so it only restarts when its address changes
+        if self.grpc.as_ref().map(|(current, _)| current) != config.grpc.as_ref() {
+            if let Some((current, handle)) = self.grpc.take() {
+                info!("Stopping gRPC service on {}", current.address);
+                handle.abort();
+                let _ = handle.await;
+            }
+            #[cfg(feature = "grpc")]
+            if let (Some(grpc), Some(service)) = (&config.grpc, grpc_service) {
+                let address = grpc.address.clone();
+                let handle = tokio::spawn(async move {
+                    if let Err(e) = service.serve(&address).await {
+                        error!("gRPC service on {} stopped: {}", address, e);
+                    }
+                });
+                self.grpc = Some((grpc.clone(), handle));
+            }
+        }
+
+        // A watchdog whose config is unchanged keeps what it has seen so far
+        if self.watchdog_task.as_ref().map(|(current, _)| current) != config.watchdog.as_ref() {
+            if let Some((_, handle)) = self.watchdog_task.take() {
+                handle.abort();
+            }
+            let watchdog = config.watchdog.as_ref().map(|watchdog| (watchdog, Arc::new(watchdog.build())));
+            if let Some((watchdog_config, watchdog)) = &watchdog {
+                let handle = watchdog.spawn(Duration::from_secs(watchdog_config.check_secs));
+                self.watchdog_task = Some(((*watchdog_config).clone(), handle));
+            }
+            *self.watchdog.write().unwrap() = watchdog.map(|(_, watchdog)| watchdog);
+        }
+
+        // Sources hold no per-connection state, so they're simply restarted
+        for handle in self.sources.drain(..) {
+            handle.abort();
+        }
+        for config in &config.file_sources {
+            let mut source = FileDropSource::new(&config.directory);
+            if let Some(dir) = &config.processed {
+                source = source.with_processed_dir(dir);
+            }
+            if let Some(dir) = &config.error {
+                source = source.with_error_dir(dir);
+            }
+            if let Some(secs) = config.poll_secs {
+                source = source.with_poll_interval(Duration::from_secs(secs));
+            }
+            if let Some(charset) = config.charset.as_deref().and_then(Charset::from_hl7) {
+                source = source.with_default_charset(charset);
+            }
+            let handler = self.handler();
+            let directory = config.directory.clone();
+            self.sources.push(tokio::spawn(async move {
+                if let Err(e) = source.run(handler).await {
+                    error!("File source on {} stopped: {}", directory.display(), e);
+                }
+            }));
+        }
+        #[cfg(feature = "nats")]
+        for nats in &config.nats_sources {
+            let mut source = crate::nats::NatsSource::new(&nats.url, &nats.subject);
+            if let Some(group) = &nats.queue_group {
+                source = source.with_queue_group(group);
+            }
+            let handler = self.handler();
+            let subject = nats.subject.clone();
+            self.sources.push(tokio::spawn(async move {
+                if let Err(e) = source.run(handler).await {
+                    error!("NATS source on {} stopped: {}", subject, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Load the config file and apply it
+    pub async fn reload(&mut self) -> Result<(), ConfigError> {
+        let config = ServerConfig::load(&self.path)?;
+        self.apply(config).await?;
+        info!("Applied config from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Apply the config, then keep re-applying it whenever it changes
+    pub async fn run(mut self) -> Result<(), ConfigError> {
+        self.reload().await?;
+
+        // The config file and its rule files, whose changes also trigger a reload
+        let modified = |supervisor: &Self| -> Vec<Option<SystemTime>> {
+            std::iter::once(&supervisor.path)
+                .chain(&supervisor.rule_files)
+                .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+        let mut last_modified = modified(&self);
+        let mut ticker = tokio::time::interval(self.poll_interval);
+
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        #[cfg(unix)]
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        loop {
+            #[cfg(unix)]
+            let signalled = tokio::select! {
+                _ = hangup.recv() => true,
+                _ = role_changed(&mut self.failover) => {
+                    self.switch_role().await;
+                    continue;
+                }
+                _ = terminate.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+                _ = ticker.tick() => false,
+            };
+            #[cfg(not(unix))]
+            let signalled = tokio::select! {
+                _ = role_changed(&mut self.failover) => {
+                    self.switch_role().await;
+                    continue;
+                }
+                _ = tokio::signal::ctrl_c() => break,
+                _ = ticker.tick() => false,
+            };
+
+            let current = modified(&self);
+            if !signalled && current == last_modified {
+                continue;
+            }
+
+            if signalled {
+                info!("Received SIGHUP, reloading {}", self.path.display());
+            } else {
+                info!("{} changed, reloading", self.path.display());
+            }
+
+            if let Err(e) = self.reload().await {
+                warn!("Keeping previous config, failed to reload {}: {}", self.path.display(), e);
+            }
+            // Taken after the reload, which may have changed the rule files being watched
+            last_modified = modified(&self);
+        }
+        info!("Shutting down");
+        self.shutdown().await;
+        Ok(())
+    }
+
+    /// Stop taking messages: close every listener and its connections, stop
+    /// the sources, and release the failover lease so the standby can take over
+    pub async fn shutdown(&mut self) {
+        for (address, (_, status, handle)) in self.listeners.drain() {
+            info!("Stopping listener on {}", address);
+            handle.abort();
+            status.close_connections();
+        }
+        for handle in self.sources.drain(..).chain(self.schedules.drain(..)) {
+            handle.abort();
+        }
+        if let Some((_, handle)) = self.grpc.take() {
+            handle.abort();
+        }
+        if let Some((_, failover, _, handle)) = self.failover.take() {
+            handle.abort();
+            let _ = handle.await;
+            // Backends such as Redis block
+            let _ = tokio::task::spawn_blocking(move || failover.release()).await;
+        }
+    }
+
+    /// Start or stop taking traffic after the failover role changed
+    async fn switch_role(&mut self) {
+        let Some(config) = self.config.clone() else {
+            return;
+        };
+        if let Err(e) = self.apply(config).await {
+            error!("Failed to switch failover role: {}", e);
+        }
+    }
+}
+
+/// Wait for the failover role to change, forever if there's no failover
+async fn role_changed(failover: &mut Option<RunningFailover>) {
+    if let Some((_, _, role, _)) = failover {
+        if role.changed().await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
@@ -0,0 +1,55 @@
+use crate::transform::Transform;
+use crate::{terser, HL7Error, Message};
+
+/// PV1-16 values that don't mark the patient as a VIP
+const NOT_VIP: [&str; 2] = ["", "N"];
+
+/// Whether the patient asked for, or was given, extra confidentiality
+///
+/// A patient is flagged by any VIP indicator in PV1-16 other than "N", or by
+/// a publicity protection indicator of "Y" in PD1-12.
+///
+/// ```
+/// use rust_hl7::{consent, Message};
+///
+/// let message = Message::parse("MSH|^~\\&|EHR|HOSP|LAB|HOSP|20230401||ADT^A01|1|P|2.5\rPID|1||12345\rPD1||||||||||||Y").unwrap();
+/// assert!(consent::is_confidential(&message));
+/// ```
+pub fn is_confidential(message: &Message) -> bool {
+    let vip = terser::get(message, "PV1-16.1").is_some_and(|value| !NOT_VIP.contains(&value.trim()));
+    let protected = terser::get(message, "PD1-12.1").is_some_and(|value| value.trim() == "Y");
+    vip || protected
+}
+
+/// Remove segments from messages about confidential patients, leaving other messages alone
+///
+/// Pair it with `Predicate::Confidential` to keep flagged patients' messages
+/// from some destinations entirely, and strip them for the rest.
+#[derive(Debug, Clone)]
+pub struct StripConfidential {
+    segments: Vec<String>,
+}
+
+impl StripConfidential {
+    /// Strip these segments, e.g. "DG1" and "OBX"; MSH can't be stripped
+    pub fn new<I, S>(segments: I) -> Result<Self, HL7Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        let segments: Vec<String> = segments.into_iter().map(|s| s.to_string()).collect();
+        if segments.iter().any(|s| s == "MSH") {
+            return Err(HL7Error::InvalidStructure("MSH segment can't be deleted".to_string()));
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl Transform for StripConfidential {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        if is_confidential(message) {
+            message.segments.retain(|s| !self.segments.contains(&s.name));
+        }
+        Ok(())
+    }
+}
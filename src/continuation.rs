@@ -0,0 +1,288 @@
+use crate::mllp::MessageHandler;
+use crate::{terser, Delimiters, HL7Error, Message};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// The smallest share of a frame a split segment can be given, so each piece carries some data
+const MIN_PIECE: usize = 16;
+
+/// Split a message into continuation messages no longer than `max_bytes` each
+///
+/// Segments are kept whole where they fit; one too long for any message, such
+/// as an OBX with an embedded PDF, is cut and its remainder carried in an ADD
+/// segment at the start of the next message. Each message but the last ends
+/// in a DSC segment whose continuation pointer (DSC-1) is the next message's
+/// control ID, which that message also carries in MSH-14. The first keeps the
+/// original control ID and later ones add "-2", "-3" and so on. Sizes are of
+/// the UTF-8 text, without MLLP framing. A message that already fits is
+/// returned as it is.
+///
+/// ```
+/// use rust_hl7::{continuation, Message};
+///
+/// let pdf = "A".repeat(300);
+/// let message = Message::parse(&format!("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20240501||ORU^R01|9|P|2.5\rOBX|1|ED|PDF||{}", pdf)).unwrap();
+/// let fragments = continuation::split(&message, 200).unwrap();
+/// assert_eq!(fragments.len(), 3);
+/// assert!(fragments.iter().all(|f| f.to_hl7().len() <= 200));
+/// assert_eq!(continuation::join(&fragments).unwrap().to_hl7(), message.to_hl7());
+/// ```
+pub fn split(message: &Message, max_bytes: usize) -> Result<Vec<Message>, HL7Error> {
+    let text = message.to_hl7();
+    if text.len() <= max_bytes {
+        return Ok(vec![message.clone()]);
+    }
+    let control_id = message.control_id().unwrap_or_default().to_string();
+    let id = |number: usize| if number == 1 { control_id.clone() } else { format!("{}-{}", control_id, number) };
+    let header = |number: usize| -> Result<String, HL7Error> {
+        let mut msh = Message {
+            segments: vec![message.segments[0].clone()],
+            message_type: message.message_type.clone(),
+            version: message.version.clone(),
+        };
+        if number > 1 {
+            terser::set(&mut msh, "MSH-10", &id(number))?;
+            terser::set(&mut msh, "MSH-14", &id(number))?;
+        }
+        Ok(msh.to_hl7())
+    };
+    let trailer = |number: usize| format!("DSC|{}|F", id(number + 1));
+
+    let mut fragments: Vec<Vec<String>> = Vec::new();
+    let mut current = vec![header(1)?];
+    // Room left for one more segment, after its separator and the DSC that may follow it
+    let room = |segments: &[String], number: usize| {
+        let used: usize = segments.iter().map(|s| s.len() + 1).sum::<usize>() + 1 + trailer(number).len();
+        max_bytes.saturating_sub(used)
+    };
+
+    for segment in text.split('\r').skip(1) {
+        let mut segment = segment.to_string();
+        loop {
+            let number = fragments.len() + 1;
+            let available = room(&current, number);
+            if segment.len() <= available {
+                current.push(segment);
+                break;
+            }
+            if current.len() == 1 {
+                // Not even a message of its own holds it, so cut it
+                let at = cut_point(&segment, available)
+                    .ok_or_else(|| HL7Error::InvalidStructure(format!("{} bytes is too small to split the message into", max_bytes)))?;
+                let rest = format!("ADD|{}", &segment[at..]);
+                segment.truncate(at);
+                current.push(segment);
+                segment = rest;
+            }
+            current.push(trailer(number));
+            fragments.push(std::mem::replace(&mut current, vec![header(number + 1)?]));
+        }
+    }
+    fragments.push(current);
+
+    fragments.iter().map(|segments| Message::parse(&segments.join("\r"))).collect()
+}
+
+/// Where to cut a segment so the first piece fits in `available` bytes, if anywhere
+///
+/// Cuts fall on character boundaries and outside escape sequences, and leave
+/// at least one character after the segment name.
+fn cut_point(segment: &str, available: usize) -> Option<usize> {
+    if available < MIN_PIECE || segment.len() <= 5 {
+        return None;
+    }
+    let escape = Delimiters::default().escape;
+    let mut at = available.min(segment.len() - 1);
+    while !segment.is_char_boundary(at) {
+        at -= 1;
+    }
+    // An odd number of escape characters before the cut means it's inside a sequence
+    if segment[..at].matches(escape).count() % 2 == 1 {
+        at = segment[..at].rfind(escape)?;
+    }
+    (at > 4).then_some(at)
+}
+
+/// Put continuation messages back together into the message they were split from
+///
+/// The fragments can be in any order; they're chained by their continuation
+/// pointers, starting from the one without MSH-14. The first fragment's MSH is
+/// kept, ADD segments are appended to the segment before them, and the DSC
+/// segments are dropped.
+pub fn join(fragments: &[Message]) -> Result<Message, HL7Error> {
+    let pointer = |message: &Message, path: &str| terser::get(message, path).filter(|p| !p.is_empty());
+    let mut first = None;
+    let mut continuations = HashMap::new();
+    for fragment in fragments {
+        match pointer(fragment, "MSH-14") {
+            Some(pointer) => {
+                continuations.insert(pointer, fragment);
+            }
+            None if first.is_none() => first = Some(fragment),
+            None => return Err(HL7Error::InvalidStructure("More than one fragment starts a message".to_string())),
+        }
+    }
+    let mut fragment = first.ok_or_else(|| HL7Error::InvalidStructure("No fragment starts the message".to_string()))?;
+
+    let delimiters = Delimiters::default();
+    let mut segments: Vec<String> = vec![fragment.segments[0].to_hl7(&delimiters)];
+    loop {
+        let continued = segments.len() > 1;
+        for (index, segment) in fragment.segments.iter().enumerate().skip(1).filter(|(_, s)| s.name != "DSC") {
+            let text = segment.to_hl7(&delimiters);
+            match text.strip_prefix("ADD|") {
+                // An ADD right after MSH carries the rest of the segment the last fragment ended with
+                Some(rest) if continued && index == 1 => segments.last_mut().expect("segments").push_str(rest),
+                _ => segments.push(text),
+            }
+        }
+        let Some(next) = fragment.get_segment("DSC").and_then(|_| pointer(fragment, "DSC-1")) else {
+            break;
+        };
+        fragment = continuations
+            .remove(&next)
+            .ok_or_else(|| HL7Error::InvalidStructure(format!("Missing continuation {}", next)))?;
+    }
+    if !continuations.is_empty() {
+        return Err(HL7Error::InvalidStructure(format!("{} fragments don't belong to the message", continuations.len())));
+    }
+    Message::parse(&segments.join("\r"))
+}
+
+/// Holds continuation messages until the last one arrives, then joins them
+///
+/// Messages that aren't continued pass straight through. Fragments are matched
+/// by their sending application and facility (MSH-3 and MSH-4) as well as the
+/// continuation pointer, so senders using the same control IDs don't mix.
+/// Incomplete messages are dropped after `max_age`, the oldest are dropped
+/// when more than `max_pending` are waiting, and one that grows past
+/// `max_fragments` or `max_bytes` is dropped with an error, so a sender that
+/// never finishes can't exhaust memory.
+///
+/// ```ignore
+/// let handler = Reassembler::new().wrap(router_handler);
+/// let server = MllpServer::new("0.0.0.0:2575", handler);
+/// ```
+#[derive(Debug)]
+pub struct Reassembler {
+    /// Fragments received so far and their total size, by sender and the continuation pointer that comes next
+    pending: Mutex<HashMap<ChainKey, Chain>>,
+    max_age: Duration,
+    max_pending: usize,
+    max_fragments: usize,
+    max_bytes: usize,
+}
+
+/// MSH-3, MSH-4 and a continuation pointer
+type ChainKey = (String, String, String);
+
+#[derive(Debug)]
+struct Chain {
+    started: Instant,
+    bytes: usize,
+    fragments: Vec<Message>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reassembler {
+    /// Wait up to ten minutes for each message to be completed, holding at most 100 at a
+    /// time, each of at most 1,000 fragments and 64 MiB
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            max_age: Duration::from_secs(600),
+            max_pending: 100,
+            max_fragments: 1000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+
+    /// Drop incomplete messages after this long
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Hold at most this many incomplete messages
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Refuse a message continued over more than this many fragments
+    pub fn max_fragments(mut self, max_fragments: usize) -> Self {
+        self.max_fragments = max_fragments;
+        self
+    }
+
+    /// Refuse a message whose fragments add up to more than this many bytes
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Add a message, returning the complete message once its last fragment arrives
+    ///
+    /// A continuation of a message that isn't pending, because it was never
+    /// started or has been dropped, is an error, as is a fragment that takes
+    /// its message over `max_fragments` or `max_bytes`.
+    pub fn push(&self, message: Message) -> Result<Option<Message>, HL7Error> {
+        let pointer = |path: &str| terser::get(&message, path).filter(|p| !p.is_empty());
+        let (previous, next) = (pointer("MSH-14"), message.get_segment("DSC").and(pointer("DSC-1")));
+        let sender = message.sending_application().unwrap_or_default().to_string();
+        let facility = message.sending_facility().unwrap_or_default().to_string();
+        let key = |pointer: String| (sender.clone(), facility.clone(), pointer);
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, chain| chain.started.elapsed() < self.max_age);
+        let mut chain = match previous {
+            Some(previous) => pending.remove(&key(previous.clone())).ok_or_else(|| {
+                HL7Error::InvalidStructure(format!("Continuation {} of a message from {} that isn't pending", previous, sender))
+            })?,
+            None => Chain {
+                started: Instant::now(),
+                bytes: 0,
+                fragments: Vec::new(),
+            },
+        };
+        chain.bytes += message.to_hl7().len();
+        chain.fragments.push(message);
+        if chain.fragments.len() > self.max_fragments || chain.bytes > self.max_bytes {
+            return Err(HL7Error::InvalidStructure(format!(
+                "Continued message from {} is over {} fragments or {} bytes",
+                sender, self.max_fragments, self.max_bytes
+            )));
+        }
+
+        match next {
+            Some(next) => {
+                if pending.len() >= self.max_pending {
+                    if let Some(oldest) = pending.iter().min_by_key(|(_, chain)| chain.started).map(|(key, _)| key.clone()) {
+                        warn!("Dropping incomplete continued message from {} waiting for {}", oldest.0, oldest.2);
+                        pending.remove(&oldest);
+                    }
+                }
+                pending.insert(key(next), chain);
+                Ok(None)
+            }
+            None if chain.fragments.len() == 1 => Ok(chain.fragments.pop()),
+            None => join(&chain.fragments).map(Some),
+        }
+    }
+
+    /// Pass complete messages to a handler, acknowledging fragments as they arrive
+    pub fn wrap(self, handler: MessageHandler) -> MessageHandler {
+        let reassembler = Arc::new(self);
+        Arc::new(move |message: Message| match reassembler.push(message.clone())? {
+            Some(complete) => handler(complete),
+            None => Ok(message),
+        })
+    }
+}
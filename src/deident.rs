@@ -0,0 +1,627 @@
+use crate::generate::{CITIES, FAMILY_NAMES, FEMALE_NAMES, MALE_NAMES, STREETS};
+use crate::terser::{self, TerserPath};
+use crate::transform::Transform;
+use crate::{parse_field, Delimiters, HL7Error, Message};
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// What kind of identifying value a field holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// An ID (CX and similar); the ID in each repetition is replaced
+    Identifier,
+    /// A person name (XPN)
+    Name,
+    /// An address (XAD)
+    Address,
+    /// A county or birthplace, which is cleared
+    Place,
+    /// A phone number or email address (XTN)
+    Phone,
+    /// A date of birth
+    BirthDate,
+    /// Any other date or timestamp
+    Date,
+    /// Free text, which is cleared since names and numbers can turn up anywhere in it
+    Text,
+}
+
+impl Kind {
+    fn of(segment: &str, field: usize) -> Option<Self> {
+        IDENTIFYING
+            .iter()
+            .find(|&&(name, number, _)| name == segment && number == field)
+            .map(|&(_, _, kind)| kind)
+    }
+
+    /// What Safe Harbor does with fields of this kind by default
+    fn safe_harbor(self) -> Scrub {
+        match self {
+            Kind::Identifier | Kind::Name | Kind::Place | Kind::Phone | Kind::Text => Scrub::Remove,
+            Kind::Address => Scrub::KeepState,
+            Kind::BirthDate => Scrub::BirthYear,
+            Kind::Date => Scrub::Year,
+        }
+    }
+}
+
+/// Fields that can identify a patient, pseudonymized by `Deidentifier` and scrubbed by `SafeHarbor`
+const IDENTIFYING: [(&str, usize, Kind); 77] = [
+    // Names of the patient, relatives and guarantors
+    ("PID", 5, Kind::Name),
+    ("PID", 6, Kind::Name),
+    ("PID", 9, Kind::Name),
+    ("NK1", 2, Kind::Name),
+    ("GT1", 3, Kind::Name),
+    ("IN1", 16, Kind::Name),
+    // Addresses, counties and birthplaces
+    ("PID", 11, Kind::Address),
+    ("PID", 12, Kind::Place),
+    ("PID", 23, Kind::Place),
+    ("NK1", 4, Kind::Address),
+    ("GT1", 5, Kind::Address),
+    ("IN1", 19, Kind::Address),
+    // Phone and fax numbers and email addresses
+    ("PID", 13, Kind::Phone),
+    ("PID", 14, Kind::Phone),
+    ("PID", 40, Kind::Phone),
+    ("NK1", 5, Kind::Phone),
+    ("NK1", 6, Kind::Phone),
+    ("NK1", 40, Kind::Phone),
+    ("GT1", 6, Kind::Phone),
+    ("GT1", 7, Kind::Phone),
+    // Medical record, account, visit, plan, SSN and license numbers
+    ("PID", 2, Kind::Identifier),
+    ("PID", 3, Kind::Identifier),
+    ("PID", 4, Kind::Identifier),
+    ("PID", 18, Kind::Identifier),
+    ("PID", 19, Kind::Identifier),
+    ("PID", 20, Kind::Identifier),
+    ("PID", 21, Kind::Identifier),
+    ("PV1", 19, Kind::Identifier),
+    ("PV1", 50, Kind::Identifier),
+    ("MRG", 1, Kind::Identifier),
+    ("MRG", 2, Kind::Identifier),
+    ("MRG", 3, Kind::Identifier),
+    ("MRG", 5, Kind::Identifier),
+    ("NK1", 33, Kind::Identifier),
+    ("NK1", 37, Kind::Identifier),
+    ("GT1", 2, Kind::Identifier),
+    ("GT1", 12, Kind::Identifier),
+    ("IN1", 36, Kind::Identifier),
+    ("IN1", 49, Kind::Identifier),
+    ("IN2", 2, Kind::Identifier),
+    // Birth dates
+    ("PID", 7, Kind::BirthDate),
+    ("NK1", 16, Kind::BirthDate),
+    ("GT1", 8, Kind::BirthDate),
+    ("IN1", 18, Kind::BirthDate),
+    // Other dates about the patient
+    ("MSH", 7, Kind::Date),
+    ("EVN", 2, Kind::Date),
+    ("EVN", 6, Kind::Date),
+    ("PID", 29, Kind::Date),
+    ("PID", 33, Kind::Date),
+    ("NK1", 8, Kind::Date),
+    ("NK1", 9, Kind::Date),
+    ("PV1", 44, Kind::Date),
+    ("PV1", 45, Kind::Date),
+    ("ORC", 9, Kind::Date),
+    ("ORC", 15, Kind::Date),
+    ("OBR", 6, Kind::Date),
+    ("OBR", 7, Kind::Date),
+    ("OBR", 8, Kind::Date),
+    ("OBR", 14, Kind::Date),
+    ("OBR", 22, Kind::Date),
+    ("OBX", 14, Kind::Date),
+    ("OBX", 19, Kind::Date),
+    ("SPM", 17, Kind::Date),
+    ("SPM", 18, Kind::Date),
+    ("DG1", 5, Kind::Date),
+    ("PR1", 5, Kind::Date),
+    ("EVN", 3, Kind::Date),
+    ("PV2", 8, Kind::Date),
+    ("PV2", 9, Kind::Date),
+    ("AL1", 6, Kind::Date),
+    ("IN1", 12, Kind::Date),
+    ("IN1", 13, Kind::Date),
+    ("RXA", 3, Kind::Date),
+    ("RXA", 4, Kind::Date),
+    ("TQ1", 7, Kind::Date),
+    ("TQ1", 8, Kind::Date),
+    // Free text
+    ("NTE", 3, Kind::Text),
+];
+
+/// OBX-2 value types whose OBX-5 is free text
+const TEXT_VALUES: [&str; 3] = ["TX", "FT", "ST"];
+
+/// OBX-2 value types whose OBX-5 is a date
+const DATE_VALUES: [&str; 3] = ["DT", "DTM", "TS"];
+
+/// Replaces patient identifiers with consistent pseudonyms and shifts dates
+///
+/// Pseudonyms are derived from the original values with a keyed hash, so the
+/// same MRN, name or phone number always gets the same replacement under the
+/// same key, across messages and runs, and messages about one patient still
+/// line up after scrubbing. Without the key the originals can't be recovered
+/// or confirmed by guessing.
+///
+/// It covers the same fields as `SafeHarbor`. Identifiers (PID-2/3/4/18/19/20/21,
+/// PV1-19/50, MRG-1/2/3/5, NK1-33/37, GT1-2/12, and the policy and insured's
+/// ID numbers in IN1-36/49 and IN2-2) become 16 hex digits, a 64-bit
+/// pseudonym, and keep their assigning authority and type. Names get made-up
+/// family and given names, addresses a made-up street and city while keeping
+/// the state and the first three digits of the ZIP code, and phone numbers
+/// (including PID-40, NK1-40 and GT1-7) a 555 number. The county and
+/// birthplace in PID-12 and PID-23 are cleared. Every date, including birth
+/// dates in PID-7, NK1-16, GT1-8 and IN1-18, diagnosis and procedure dates
+/// and OBX-5 of DT, DTM and TS observations, is moved back by the same number
+/// of days, so intervals between them are kept and no original date is left
+/// to give the shift away. With `per_patient_dates` each patient (PID-3) gets
+/// their own number of days, applied to every one of those dates, so dates
+/// can't be lined up across patients while intervals within one patient's
+/// record still hold. Free text, in NTE-3 and
+/// in OBX-5 of TX, FT and ST observations, is cleared.
+///
+/// ```
+/// use rust_hl7::deident::Deidentifier;
+///
+/// let mut message = rust_hl7::Message::parse(
+///     "MSH|^~\\&|EHR|HOSP|LAB|HOSP|20230401123000||ADT^A01|MSG1|P|2.5\rPID|1||12345^^^HOSP^MR||DOE^JOHN||19800101|M",
+/// )
+/// .unwrap();
+/// Deidentifier::new("secret").apply(&mut message);
+/// assert_ne!(rust_hl7::terser::get(&message, "PID-5.1").as_deref(), Some("DOE"));
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-3.4").as_deref(), Some("HOSP"));
+/// ```
+#[derive(Clone)]
+pub struct Deidentifier {
+    key: Vec<u8>,
+    shift_dates: bool,
+    per_patient_dates: bool,
+}
+
+impl std::fmt::Debug for Deidentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The key stays out of logs
+        f.debug_struct("Deidentifier")
+            .field("shift_dates", &self.shift_dates)
+            .field("per_patient_dates", &self.per_patient_dates)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Deidentifier {
+    /// A de-identifier whose pseudonyms and date shift are derived from `key`
+    pub fn new(key: impl AsRef<[u8]>) -> Self {
+        Self {
+            key: key.as_ref().to_vec(),
+            shift_dates: true,
+            per_patient_dates: false,
+        }
+    }
+
+    /// Whether to shift dates (default true)
+    pub fn shift_dates(mut self, shift_dates: bool) -> Self {
+        self.shift_dates = shift_dates;
+        self
+    }
+
+    /// Whether to move each patient's dates by a different number of days (default false)
+    pub fn per_patient_dates(mut self, per_patient_dates: bool) -> Self {
+        self.per_patient_dates = per_patient_dates;
+        self
+    }
+
+    /// How many days dates are moved by: between one and 365 days back
+    ///
+    /// With `per_patient_dates` this is only used for messages without a patient ID.
+    pub fn date_offset(&self) -> i64 {
+        self.patient_date_offset("")
+    }
+
+    /// How many days a patient's dates are moved by with `per_patient_dates`
+    ///
+    /// `patient` is the original ID in the first repetition of PID-3, followed by
+    /// its assigning authority if it has one, e.g. "12345^HOSP".
+    pub fn patient_date_offset(&self, patient: &str) -> i64 {
+        -(1 + (self.hash("date-offset", patient) % 365) as i64)
+    }
+
+    /// How many days this message's dates are moved by
+    fn message_date_offset(&self, message: &Message) -> i64 {
+        if !self.per_patient_dates {
+            return self.date_offset();
+        }
+        let delimiters = Delimiters::default();
+        let patient = message
+            .segments
+            .iter()
+            .find(|s| s.name == "PID")
+            .and_then(|pid| pid.fields.get(2))
+            .map(|ids| ids.to_hl7(&delimiters))
+            .and_then(|ids| {
+                let first = ids.split(delimiters.repetition).next()?.to_string();
+                let components: Vec<&str> = first.split(delimiters.component).collect();
+                match (components[0], components.get(3).copied().unwrap_or("")) {
+                    ("", _) => None,
+                    (id, "") => Some(id.to_string()),
+                    (id, authority) => Some(format!("{}^{}", id, authority)),
+                }
+            });
+        patient.map_or_else(|| self.date_offset(), |patient| self.patient_date_offset(&patient))
+    }
+
+    /// Scrub the message in place
+    pub fn apply(&self, message: &mut Message) {
+        let delimiters = Delimiters::default();
+        // Read before PID-3 is scrubbed
+        let offset = self.message_date_offset(message);
+        for segment in &mut message.segments {
+            let value_type = |types: &[&str]| {
+                segment.name == "OBX"
+                    && segment
+                        .fields
+                        .get(1)
+                        .and_then(|kind| kind.components.first())
+                        .is_some_and(|kind| types.contains(&kind.value.as_str()))
+            };
+            let (text_value, date_value) = (value_type(&TEXT_VALUES), value_type(&DATE_VALUES));
+            for (index, field) in segment.fields.iter_mut().enumerate() {
+                let number = if segment.name == "MSH" { index + 2 } else { index + 1 };
+                let kind = match Kind::of(&segment.name, number) {
+                    Some(kind) => kind,
+                    None if text_value && number == 5 => Kind::Text,
+                    None if date_value && number == 5 => Kind::Date,
+                    None => continue,
+                };
+                if matches!(kind, Kind::Date | Kind::BirthDate) && !self.shift_dates {
+                    continue;
+                }
+
+                let text = field.to_hl7(&delimiters);
+                if text.is_empty() {
+                    continue;
+                }
+                if matches!(kind, Kind::Text | Kind::Place) {
+                    *field = parse_field("", &delimiters);
+                    continue;
+                }
+                let scrubbed = text
+                    .split(delimiters.repetition)
+                    .map(|repetition| {
+                        let mut components: Vec<String> =
+                            repetition.split(delimiters.component).map(|c| c.to_string()).collect();
+                        if !repetition.is_empty() {
+                            self.scrub(kind, &mut components, offset);
+                        }
+                        trim_components(components).join(&delimiters.component.to_string())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(&delimiters.repetition.to_string());
+                *field = parse_field(&scrubbed, &delimiters);
+            }
+        }
+    }
+
+    /// Scrub the components of one field repetition, moving dates by `offset` days
+    fn scrub(&self, kind: Kind, components: &mut Vec<String>, offset: i64) {
+        match kind {
+            Kind::Identifier => self.identifier(components),
+            Kind::Name => self.name(components),
+            Kind::Address => self.address(components),
+            Kind::Phone => self.phone(components),
+            Kind::Date | Kind::BirthDate => {
+                if let Some(first) = components.first_mut() {
+                    *first = shift_date(first, offset);
+                }
+            }
+            Kind::Place | Kind::Text => components.clear(),
+        }
+    }
+
+    /// Keyed hash of a value, as a number
+    fn hash(&self, kind: &str, value: &str) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(kind.as_bytes());
+        mac.update(b":");
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 digests are 32 bytes"))
+    }
+
+    /// Replace an ID with the whole 64-bit keyed hash in hex, so different IDs don't collide
+    fn identifier(&self, components: &mut [String]) {
+        if let Some(id) = components.first_mut().filter(|id| !id.is_empty()) {
+            *id = format!("{:016X}", self.hash("id", id));
+        }
+    }
+
+    fn name(&self, components: &mut Vec<String>) {
+        if let Some(family) = components.first_mut().filter(|c| !c.is_empty()) {
+            let index = self.hash("family", family) as usize % FAMILY_NAMES.len();
+            *family = FAMILY_NAMES[index].to_string();
+        }
+        if let Some(given) = components.get_mut(1).filter(|c| !c.is_empty()) {
+            let names: Vec<&str> = MALE_NAMES.iter().chain(FEMALE_NAMES.iter()).copied().collect();
+            let index = self.hash("given", given) as usize % names.len();
+            *given = names[index].to_string();
+        }
+        // Middle names, suffixes, prefixes and degrees identify people too
+        components.truncate(2);
+    }
+
+    fn address(&self, components: &mut Vec<String>) {
+        let original = components.join("^");
+        let hash = self.hash("address", &original);
+        let set = |components: &mut Vec<String>, number: usize, value: String| {
+            if components.len() < number {
+                components.resize(number, String::new());
+            }
+            components[number - 1] = value;
+        };
+
+        if components.first().is_some_and(|street| !street.is_empty()) {
+            let street = format!("{} {}", 1 + hash % 9999, STREETS[(hash >> 16) as usize % STREETS.len()]);
+            set(components, 1, street);
+        }
+        if components.len() > 1 {
+            components[1].clear();
+        }
+        if components.get(2).is_some_and(|city| !city.is_empty()) {
+            set(components, 3, CITIES[(hash >> 32) as usize % CITIES.len()].0.to_string());
+        }
+        if let Some(zip) = components.get_mut(4).filter(|zip| !zip.is_empty()) {
+            *zip = format!("{:0<5}", zip.chars().take(3).collect::<String>());
+        }
+        // County and census tract
+        for number in [9, 10] {
+            if let Some(component) = components.get_mut(number - 1) {
+                component.clear();
+            }
+        }
+    }
+
+    fn phone(&self, components: &mut Vec<String>) {
+        let hash = self.hash("phone", &components.join("^"));
+        let local = format!("{:07}", hash % 10_000_000);
+        if let Some(number) = components.first_mut().filter(|c| !c.is_empty()) {
+            *number = format!("(555){}-{}", &local[..3], &local[3..]);
+        }
+        if let Some(email) = components.get_mut(3).filter(|c| !c.is_empty()) {
+            *email = format!("{}@example.org", &local);
+        }
+        if let Some(area) = components.get_mut(5).filter(|c| !c.is_empty()) {
+            *area = "555".to_string();
+        }
+        if let Some(number) = components.get_mut(6).filter(|c| !c.is_empty()) {
+            *number = local.clone();
+        }
+        // Extension and free text
+        components.truncate(7);
+    }
+}
+
+/// Move the date part of a DTM value by a number of days, keeping its precision
+///
+/// Values with only a year or month, or that aren't dates, are left alone.
+fn shift_date(value: &str, days: i64) -> String {
+    let Some(date) = value.get(..8).and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok()) else {
+        return value.to_string();
+    };
+    format!("{}{}", (date + Duration::days(days)).format("%Y%m%d"), &value[8..])
+}
+
+/// Drop trailing empty components
+fn trim_components(mut components: Vec<String>) -> Vec<String> {
+    while components.len() > 1 && components.last().is_some_and(|c| c.is_empty()) {
+        components.pop();
+    }
+    components
+}
+
+/// What Safe Harbor de-identification does with a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scrub {
+    /// Leave the field alone
+    Keep,
+    /// Clear the field
+    Remove,
+    /// Clear every address component except the state and country
+    KeepState,
+    /// Reduce dates to the year
+    Year,
+    /// Reduce a birth date to the year, moving it later for anyone over 89
+    BirthYear,
+    /// Replace ages over 89 with 90
+    CapAge,
+}
+
+/// LOINC codes of observations that report the patient's age
+const AGE_OBSERVATIONS: [&str; 3] = ["30525-0", "21612-7", "29553-5"];
+
+/// Removes the HIPAA Safe Harbor identifiers from messages
+///
+/// Unlike `Deidentifier`, nothing is replaced with a pseudonym: names, phone
+/// numbers, email addresses and identifiers are cleared, addresses keep only
+/// the state and country, and dates keep only the year. Birth dates of anyone
+/// over 89 at the time of the message (MSH-7) are moved to make them 90, and
+/// so are ages reported in OBX-5 by observations coded as an age. OBX-5 of
+/// DT, DTM and TS observations is reduced to the year as well. Free text,
+/// in NTE-3 and in OBX-5 of TX, FT and ST observations, is cleared, since
+/// notes can name anyone and hold any number; `keep_free_text` keeps it for
+/// feeds whose notes are scrubbed some other way. Messages about the same
+/// patient can no longer be linked.
+///
+/// Rules apply to a field in every occurrence of its segment and can be
+/// changed or added per field, e.g. to clear a Z-segment field.
+///
+/// ```
+/// use rust_hl7::deident::{SafeHarbor, Scrub};
+///
+/// let mut message = rust_hl7::Message::parse(
+///     "MSH|^~\\&|EHR|HOSP|LAB|HOSP|20230401123000||ADT^A01|MSG1|P|2.5\r\
+///      PID|1||12345^^^HOSP^MR||DOE^JOHN||19300101|M|||1 MAIN ST^^BOSTON^MA^02110\r\
+///      ZPI|1|Room 12",
+/// )
+/// .unwrap();
+/// let scrubber = SafeHarbor::new().rule("ZPI-2", Scrub::Remove).unwrap();
+/// scrubber.apply(&mut message);
+/// assert!(!message.to_hl7().contains("DOE") && !message.to_hl7().contains("Room"));
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-7").as_deref(), Some("1933"));
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-11.4").as_deref(), Some("MA"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafeHarbor {
+    rules: HashMap<(String, usize), Scrub>,
+    keep_free_text: bool,
+}
+
+impl Default for SafeHarbor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SafeHarbor {
+    /// The default rules
+    pub fn new() -> Self {
+        Self {
+            rules: IDENTIFYING
+                .iter()
+                .map(|&(segment, field, kind)| ((segment.to_string(), field), kind.safe_harbor()))
+                .collect(),
+            keep_free_text: false,
+        }
+    }
+
+    /// Change what happens to a field, given as e.g. "PID-11" or "ZPI-3"
+    pub fn rule(mut self, path: &str, scrub: Scrub) -> Result<Self, HL7Error> {
+        let parsed: TerserPath = path.parse()?;
+        if parsed.component.is_some() {
+            return Err(HL7Error::ParseError(format!("Safe Harbor rules apply to whole fields: {}", path)));
+        }
+        self.rules.insert((parsed.segment, parsed.field), scrub);
+        Ok(self)
+    }
+
+    /// Whether to keep free text, in NTE-3 and OBX-5 of TX, FT and ST observations (default false)
+    ///
+    /// Kept notes can still hold names, record numbers and phone numbers, so
+    /// the output is no longer Safe Harbor de-identified on its own.
+    pub fn keep_free_text(mut self, keep_free_text: bool) -> Self {
+        self.keep_free_text = keep_free_text;
+        let scrub = if keep_free_text { Scrub::Keep } else { Scrub::Remove };
+        for &(segment, field, kind) in &IDENTIFYING {
+            if kind == Kind::Text {
+                self.rules.insert((segment.to_string(), field), scrub);
+            }
+        }
+        self
+    }
+
+    /// Change what happens to each of these fields
+    pub fn rules<I, P>(self, rules: I) -> Result<Self, HL7Error>
+    where
+        I: IntoIterator<Item = (P, Scrub)>,
+        P: AsRef<str>,
+    {
+        rules.into_iter().try_fold(self, |scrubber, (path, scrub)| scrubber.rule(path.as_ref(), scrub))
+    }
+
+    /// Scrub the message in place
+    pub fn apply(&self, message: &mut Message) {
+        // Ages are as of when the message was sent
+        let year = terser::get(message, "MSH-7")
+            .and_then(|time| time.get(..4)?.parse().ok())
+            .unwrap_or_else(|| Utc::now().year());
+        let delimiters = Delimiters::default();
+
+        for segment in &mut message.segments {
+            let age_observation = segment.name == "OBX"
+                && segment
+                    .fields
+                    .get(2)
+                    .and_then(|code| code.components.first())
+                    .is_some_and(|code| AGE_OBSERVATIONS.contains(&code.value.as_str()));
+            let value_type = |types: &[&str]| {
+                segment.name == "OBX"
+                    && segment
+                        .fields
+                        .get(1)
+                        .and_then(|kind| kind.components.first())
+                        .is_some_and(|kind| types.contains(&kind.value.as_str()))
+            };
+            let (text_observation, date_observation) = (value_type(&TEXT_VALUES), value_type(&DATE_VALUES));
+
+            for (index, field) in segment.fields.iter_mut().enumerate() {
+                let number = if segment.name == "MSH" { index + 2 } else { index + 1 };
+                let scrub = match self.rules.get(&(segment.name.clone(), number)) {
+                    Some(&scrub) => scrub,
+                    None if age_observation && number == 5 => Scrub::CapAge,
+                    None if date_observation && number == 5 => Scrub::Year,
+                    None if text_observation && number == 5 && !self.keep_free_text => Scrub::Remove,
+                    None => continue,
+                };
+
+                let text = field.to_hl7(&delimiters);
+                if scrub == Scrub::Keep || text.is_empty() {
+                    continue;
+                }
+                let scrubbed = text
+                    .split(delimiters.repetition)
+                    .map(|repetition| {
+                        let components = repetition.split(delimiters.component).map(|c| c.to_string()).collect();
+                        trim_components(scrub_components(scrub, components, year)).join(&delimiters.component.to_string())
+                    })
+                    .collect::<Vec<_>>();
+                let scrubbed = match scrubbed.iter().all(|r| r.is_empty()) {
+                    true => String::new(),
+                    false => scrubbed.join(&delimiters.repetition.to_string()),
+                };
+                *field = parse_field(&scrubbed, &delimiters);
+            }
+        }
+    }
+}
+
+impl Transform for SafeHarbor {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        SafeHarbor::apply(self, message);
+        Ok(())
+    }
+}
+
+/// Scrub the components of one field repetition; `year` is the year of the message
+fn scrub_components(scrub: Scrub, mut components: Vec<String>, year: i32) -> Vec<String> {
+    match scrub {
+        Scrub::Keep => components,
+        Scrub::Remove => Vec::new(),
+        Scrub::KeepState => components
+            .into_iter()
+            .enumerate()
+            .map(|(index, component)| if index == 3 || index == 5 { component } else { String::new() })
+            .collect(),
+        Scrub::Year | Scrub::BirthYear => {
+            // Precision and any other components go too
+            let Some(date) = components.first().and_then(|date| date.get(..4)?.parse::<i32>().ok()) else {
+                return Vec::new();
+            };
+            let date = if scrub == Scrub::BirthYear { date.max(year - 90) } else { date };
+            vec![date.to_string()]
+        }
+        Scrub::CapAge => {
+            if let Some(age) = components.first_mut() {
+                if age.trim().parse::<f64>().is_ok_and(|age| age > 89.0) {
+                    *age = "90".to_string();
+                }
+            }
+            components
+        }
+    }
+}
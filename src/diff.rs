@@ -0,0 +1,281 @@
+use crate::terser::field_index;
+use crate::{Delimiters, Message, Segment};
+use serde::Serialize;
+use std::fmt;
+
+/// Fields that differ between any two sends of the same content
+pub const VOLATILE_FIELDS: [&str; 2] = ["MSH-7", "MSH-10"];
+
+/// How a value differs between two messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// Only the second message has a value
+    Added,
+    /// Only the first message has a value
+    Removed,
+    /// Both messages have a value, but not the same one
+    Changed,
+}
+
+/// A value that differs between two messages
+///
+/// Values are unescaped, and an empty value is the same as a missing one, so
+/// trailing empty fields and components don't count as differences.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// Where the value is, e.g. "PID-5.1", "OBX(2)-5", or "PID-3(2).1" in the second repetition of
+    /// PID-3; usable with `terser::get`, except in repetitions after the first
+    pub path: String,
+    pub change: Change,
+    /// The value in the first message
+    pub left: Option<String>,
+    /// The value in the second message
+    pub right: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (left, right) = (self.left.as_deref().unwrap_or(""), self.right.as_deref().unwrap_or(""));
+        match self.change {
+            Change::Added => write!(f, "{} added: {}", self.path, right),
+            Change::Removed => write!(f, "{} removed: {}", self.path, left),
+            Change::Changed => write!(f, "{} changed: {} -> {}", self.path, left, right),
+        }
+    }
+}
+
+/// A location left out of comparisons
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ignored {
+    segment: String,
+    /// A single repetition of the segment, or all of them
+    repetition: Option<usize>,
+    /// Numbers below the segment, e.g. [5, 1] for "PID-5.1"; empty for the whole segment
+    position: Vec<usize>,
+}
+
+impl Ignored {
+    fn parse(path: &str) -> Option<Self> {
+        let (segment, numbers) = path.trim().split_once('-').unwrap_or((path.trim(), ""));
+        let (segment, repetition) = match segment.split_once('(') {
+            Some((name, repetition)) => (name, Some(repetition.strip_suffix(')')?.parse().ok().filter(|&r| r > 0)?)),
+            None => (segment, None),
+        };
+        let position = match numbers {
+            "" => Vec::new(),
+            numbers => numbers
+                .split(['.', '-'])
+                .map(|n| n.parse().ok().filter(|&n| n > 0))
+                .collect::<Option<Vec<usize>>>()?,
+        };
+        (segment.len() == 3 && position.len() <= 3).then(|| Self {
+            segment: segment.to_string(),
+            repetition,
+            position,
+        })
+    }
+
+    fn covers(&self, segment: &str, repetition: usize, position: &[usize]) -> bool {
+        self.segment == segment
+            && self.repetition.is_none_or(|r| r == repetition)
+            && position.starts_with(&self.position)
+    }
+}
+
+/// Compares messages value by value, skipping fields expected to differ
+///
+/// Segments are paired by name and occurrence, so the second OBX of one
+/// message is compared with the second OBX of the other, and repetitions of a
+/// field by their order. Ignoring a field ignores all its repetitions. By default MSH-7 and
+/// MSH-10 are ignored, being different every time a message is sent.
+///
+/// ```
+/// use rust_hl7::diff::{Change, DiffOptions};
+/// use rust_hl7::Message;
+///
+/// let before = Message::parse("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20230401||ORU^R01|1|P|2.5\rOBX|1|NM|GLU||5.4").unwrap();
+/// let after = Message::parse("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20230402||ORU^R01|2|P|2.5\rOBX|1|NM|GLU||5.4|mmol/L").unwrap();
+/// let differences = before.semantic_diff(&after);
+/// assert_eq!(differences.len(), 1);
+/// assert_eq!((differences[0].path.as_str(), differences[0].change), ("OBX-6", Change::Added));
+///
+/// // Ignore units too, in every OBX
+/// assert!(DiffOptions::new().ignore("OBX-6").compare(&before, &after).is_empty());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+    ignored: Vec<Ignored>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiffOptions {
+    /// Compare everything except `VOLATILE_FIELDS`
+    pub fn new() -> Self {
+        Self::exact().ignore_all(VOLATILE_FIELDS)
+    }
+
+    /// Compare every value, including `VOLATILE_FIELDS`
+    pub fn exact() -> Self {
+        Self { ignored: Vec::new() }
+    }
+
+    /// Also ignore a segment, field, component or subcomponent
+    ///
+    /// "OBX-14" ignores the field in every OBX and "OBX(2)-14" only in the
+    /// second; "ZPI" ignores whole segments. Invalid paths are ignored themselves.
+    pub fn ignore<P: AsRef<str>>(mut self, path: P) -> Self {
+        match Ignored::parse(path.as_ref()) {
+            Some(ignored) => self.ignored.push(ignored),
+            None => tracing::warn!("Ignoring invalid diff path {}", path.as_ref()),
+        }
+        self
+    }
+
+    /// Also ignore each of these paths
+    pub fn ignore_all<I, P>(self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<str>,
+    {
+        paths.into_iter().fold(self, |options, path| options.ignore(path))
+    }
+
+    /// List the values that differ between `left` and `right`, in message order
+    pub fn compare(&self, left: &Message, right: &Message) -> Vec<Difference> {
+        // Segments of the first message in order, then any only the second has
+        let mut keys: Vec<(&str, usize)> = occurrences(left).collect();
+        keys.extend(occurrences(right).filter(|key| !keys.contains(key)).collect::<Vec<_>>());
+
+        let mut differences = Vec::new();
+        for (name, repetition) in keys {
+            if self.ignored.iter().any(|i| i.covers(name, repetition, &[])) {
+                continue;
+            }
+            let (left, right) = (find(left, name, repetition), find(right, name, repetition));
+            let segment = if repetition == 1 { name.to_string() } else { format!("{}({})", name, repetition) };
+
+            let fields = |s: Option<&Segment>| s.map_or(0, |s| s.fields.len());
+            // MSH-1 isn't stored, so MSH field numbers start one higher
+            let first = if name == "MSH" { 2 } else { 1 };
+            for number in first..first + fields(left).max(fields(right)) {
+                let value = |s: Option<&Segment>| {
+                    s.and_then(|s| s.fields.get(field_index(name, number)?))
+                        .map(|f| f.to_hl7(&Delimiters::default()))
+                        .unwrap_or_default()
+                };
+                let (l, r) = (value(left), value(right));
+                if l == r {
+                    continue;
+                }
+                let location = Location { segment: &segment, name, repetition, field_repetition: 1 };
+                if name == "MSH" && number == 2 {
+                    // The encoding characters aren't split into components
+                    self.leaf(&location, &[number], &l, &r, &mut differences);
+                } else {
+                    self.field(&location, number, &l, &r, &mut differences);
+                }
+            }
+        }
+        differences
+    }
+
+    fn field(&self, location: &Location, number: usize, left: &str, right: &str, differences: &mut Vec<Difference>) {
+        let d = Delimiters::default();
+        let (left, right): (Vec<&str>, Vec<&str>) = (left.split(d.repetition).collect(), right.split(d.repetition).collect());
+        for index in 0..left.len().max(right.len()) {
+            let (l, r) = (left.get(index).copied().unwrap_or(""), right.get(index).copied().unwrap_or(""));
+            if l != r {
+                let location = Location { field_repetition: index + 1, ..*location };
+                self.repetition(&location, number, l, r, differences);
+            }
+        }
+    }
+
+    fn repetition(&self, location: &Location, number: usize, left: &str, right: &str, differences: &mut Vec<Difference>) {
+        let d = Delimiters::default();
+        if !left.contains(d.component) && !right.contains(d.component) {
+            return self.component(location, &[number], left, right, differences);
+        }
+        let (left, right): (Vec<&str>, Vec<&str>) = (left.split(d.component).collect(), right.split(d.component).collect());
+        for index in 0..left.len().max(right.len()) {
+            let (l, r) = (left.get(index).copied().unwrap_or(""), right.get(index).copied().unwrap_or(""));
+            if l != r {
+                self.component(location, &[number, index + 1], l, r, differences);
+            }
+        }
+    }
+
+    fn component(&self, location: &Location, position: &[usize], left: &str, right: &str, differences: &mut Vec<Difference>) {
+        let d = Delimiters::default();
+        if !left.contains(d.subcomponent) && !right.contains(d.subcomponent) {
+            return self.leaf(location, position, left, right, differences);
+        }
+        // A field without components holds its subcomponents in component 1
+        let position = match position {
+            [field] => vec![*field, 1],
+            position => position.to_vec(),
+        };
+        let (left, right): (Vec<&str>, Vec<&str>) = (left.split(d.subcomponent).collect(), right.split(d.subcomponent).collect());
+        for index in 0..left.len().max(right.len()) {
+            let (l, r) = (left.get(index).copied().unwrap_or(""), right.get(index).copied().unwrap_or(""));
+            if l != r {
+                self.leaf(location, &[position[0], position[1], index + 1], l, r, differences);
+            }
+        }
+    }
+
+    fn leaf(&self, location: &Location, position: &[usize], left: &str, right: &str, differences: &mut Vec<Difference>) {
+        if self.ignored.iter().any(|i| i.covers(location.name, location.repetition, position)) {
+            return;
+        }
+        let d = Delimiters::default();
+        let (left, right) = (d.unescape(left), d.unescape(right));
+        let change = match (left.is_empty(), right.is_empty()) {
+            (true, true) => return,
+            _ if left == right => return,
+            (true, false) => Change::Added,
+            (false, true) => Change::Removed,
+            (false, false) => Change::Changed,
+        };
+        let mut numbers: Vec<String> = position.iter().map(|n| n.to_string()).collect();
+        if location.field_repetition > 1 {
+            numbers[0] = format!("{}({})", numbers[0], location.field_repetition);
+        }
+        differences.push(Difference {
+            path: format!("{}-{}", location.segment, numbers.join(".")),
+            change,
+            left: (!left.is_empty()).then_some(left),
+            right: (!right.is_empty()).then_some(right),
+        });
+    }
+}
+
+/// The segment and field repetition a value is in, for building its path
+#[derive(Clone, Copy)]
+struct Location<'a> {
+    /// As written in paths, e.g. "OBX(2)"
+    segment: &'a str,
+    name: &'a str,
+    repetition: usize,
+    /// 1-based repetition of the field
+    field_repetition: usize,
+}
+
+/// The `repetition`th segment named `name`
+fn find<'a>(message: &'a Message, name: &str, repetition: usize) -> Option<&'a Segment> {
+    message.segments.iter().filter(|s| s.name == name).nth(repetition - 1)
+}
+
+/// Each segment's name and its 1-based occurrence among segments of that name
+fn occurrences(message: &Message) -> impl Iterator<Item = (&str, usize)> {
+    message.segments.iter().enumerate().map(|(index, segment)| {
+        let repetition = message.segments[..index].iter().filter(|s| s.name == segment.name).count() + 1;
+        (segment.name.as_str(), repetition)
+    })
+}
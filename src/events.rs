@@ -0,0 +1,456 @@
+//! Clinical events read from messages, for application code that cares what
+//! happened rather than which message said so
+//!
+//! `events` turns a message into the `ClinicalEvent`s it carries: an ADT^A01
+//! is a `PatientAdmitted`, an ADT^A03 a `PatientDischarged`, each OBX of an
+//! ORU^R01 an `ObservationResulted`, each RXE of a new (ORC-1 `NW`) RDE order a
+//! `MedicationOrdered` and an SIU^S12 an `AppointmentScheduled`. Other
+//! messages carry no events. An `EventStream` does the same but drops events
+//! it has already given out, such as the results of a resent ORU.
+//!
+//! ```
+//! use rust_hl7::events::{self, ClinicalEvent};
+//! use rust_hl7::Message;
+//!
+//! let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\r\
+//!     PID|1||12345^^^HOSP^MR||DOE^JANE\r\
+//!     OBR|1||LAB1|58410-2^CBC panel^LN\r\
+//!     OBX|1|NM|6690-2^WBC^LN||10.5|10*3/uL|4.0-11.0|H|||F").unwrap();
+//! match &events::events(&message).unwrap()[..] {
+//!     [ClinicalEvent::ObservationResulted(result)] => {
+//!         assert_eq!(result.patient.patient_id, "12345");
+//!         assert_eq!(result.code.code, "6690-2");
+//!         assert_eq!(result.abnormal_flags.as_deref(), Some("H"));
+//!     }
+//!     other => panic!("Unexpected events {:?}", other),
+//! }
+//! ```
+
+use crate::mllp::MessageHandler;
+use crate::oru::{Code, ObservationValue};
+use crate::pix::{identifiers, PatientIdentifier};
+use crate::value::Value;
+use crate::{HL7Error, Message, Segment};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// The patient an event is about, from PID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patient {
+    /// The first identifier in PID-3
+    pub patient_id: String,
+    /// Every PID-3 identifier with an assigning authority
+    pub identifiers: Vec<PatientIdentifier>,
+    pub family_name: Option<String>,
+    pub given_name: Option<String>,
+    pub birth_date: Option<NaiveDate>,
+    /// PID-8, e.g. `F`
+    pub sex: Option<String>,
+}
+
+/// A visit starting, from an ADT^A01
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Admission {
+    pub patient: Patient,
+    /// PV1-19
+    pub visit_number: Option<String>,
+    /// PV1-2, e.g. `I` for inpatient
+    pub patient_class: Option<String>,
+    /// PV1-3 as point of care, room and bed, e.g. `4E^401^B`
+    pub location: Option<String>,
+    /// PV1-44, else when the event happened (EVN-6 or EVN-2)
+    pub admitted_at: Option<NaiveDateTime>,
+}
+
+/// A visit ending, from an ADT^A03
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discharge {
+    pub patient: Patient,
+    /// PV1-19
+    pub visit_number: Option<String>,
+    /// PV1-3 as point of care, room and bed
+    pub location: Option<String>,
+    /// PV1-36, e.g. `01` for home
+    pub disposition: Option<String>,
+    /// PV1-45, else when the event happened (EVN-6 or EVN-2)
+    pub discharged_at: Option<NaiveDateTime>,
+}
+
+/// One result of an ORU^R01, from an OBX and the OBR before it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservationResult {
+    pub patient: Patient,
+    /// The ordered service, OBR-4
+    pub service: Option<Code>,
+    /// OBR-3, else OBR-2
+    pub order_number: Option<String>,
+    /// OBX-3
+    pub code: Code,
+    /// OBX-4, telling apart results of one order with the same code
+    pub sub_id: Option<String>,
+    /// OBX-5 typed by OBX-2, or None if it's empty
+    pub value: Option<ObservationValue>,
+    pub units: Option<String>,
+    pub reference_range: Option<String>,
+    pub abnormal_flags: Option<String>,
+    /// OBX-11, e.g. `F` for final or `C` for a correction
+    pub status: String,
+    /// OBX-14, else OBR-7
+    pub observed_at: Option<NaiveDateTime>,
+}
+
+/// One medication of a new RDE order, from an RXE and the RXR and TQ1 after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MedicationOrder {
+    pub patient: Patient,
+    /// ORC-2, else ORC-3
+    pub order_number: Option<String>,
+    /// The give code, RXE-2
+    pub medication: Code,
+    /// RXE-3 and RXE-5, e.g. `500` and `mg`
+    pub dose: Option<String>,
+    pub dose_units: Option<String>,
+    /// RXR-1, e.g. `PO`
+    pub route: Option<String>,
+    /// TQ1-3, e.g. `BID`
+    pub frequency: Option<String>,
+    /// TQ1-7
+    pub start: Option<NaiveDateTime>,
+}
+
+/// A booked appointment, from an SIU^S12
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Appointment {
+    pub patient: Patient,
+    /// SCH-2, else SCH-1
+    pub appointment_id: String,
+    /// SCH-7
+    pub reason: Option<Code>,
+    /// SCH-8
+    pub appointment_type: Option<Code>,
+    /// SCH-11.4, else AIS-4
+    pub start: Option<NaiveDateTime>,
+    /// SCH-11.5
+    pub end: Option<NaiveDateTime>,
+    /// AIL-3 as point of care, room and bed
+    pub location: Option<String>,
+    /// The ID of the first provider in AIP-3
+    pub provider: Option<String>,
+}
+
+/// Something that happened to a patient
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClinicalEvent {
+    PatientAdmitted(Admission),
+    PatientDischarged(Discharge),
+    ObservationResulted(ObservationResult),
+    MedicationOrdered(MedicationOrder),
+    AppointmentScheduled(Appointment),
+}
+
+impl ClinicalEvent {
+    /// The patient the event is about
+    pub fn patient(&self) -> &Patient {
+        match self {
+            ClinicalEvent::PatientAdmitted(admission) => &admission.patient,
+            ClinicalEvent::PatientDischarged(discharge) => &discharge.patient,
+            ClinicalEvent::ObservationResulted(result) => &result.patient,
+            ClinicalEvent::MedicationOrdered(order) => &order.patient,
+            ClinicalEvent::AppointmentScheduled(appointment) => &appointment.patient,
+        }
+    }
+
+    /// What makes the event the same as another: its kind, patient and the
+    /// parts that identify it, leaving out details a resend may change
+    ///
+    /// A result's key takes in its status and value, so a correction is a new
+    /// event.
+    pub fn key(&self) -> String {
+        let patient = &self.patient().patient_id;
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        let time = |time: &Option<NaiveDateTime>| time.map(|t| t.to_string()).unwrap_or_default();
+        match self {
+            ClinicalEvent::PatientAdmitted(admission) => {
+                format!("admitted|{}|{}|{}", patient, text(&admission.visit_number), time(&admission.admitted_at))
+            }
+            ClinicalEvent::PatientDischarged(discharge) => {
+                format!("discharged|{}|{}|{}", patient, text(&discharge.visit_number), time(&discharge.discharged_at))
+            }
+            ClinicalEvent::ObservationResulted(result) => format!(
+                "resulted|{}|{}|{}|{}|{}|{:?}|{}",
+                patient,
+                text(&result.order_number),
+                result.code.code,
+                result.code.system,
+                result.status,
+                result.value,
+                time(&result.observed_at)
+            ),
+            ClinicalEvent::MedicationOrdered(order) => format!(
+                "ordered|{}|{}|{}|{}",
+                patient,
+                text(&order.order_number),
+                order.medication.code,
+                order.medication.system
+            ),
+            ClinicalEvent::AppointmentScheduled(appointment) => {
+                format!("scheduled|{}|{}|{}", patient, appointment.appointment_id, time(&appointment.start))
+            }
+        }
+    }
+}
+
+/// The events a message carries, in the order of its segments
+///
+/// Fails if a message that carries events has no PID-3, or an OBX, RXE or
+/// SCH without the code or ID that identifies it.
+pub fn events(message: &Message) -> Result<Vec<ClinicalEvent>, HL7Error> {
+    let mut parts = message.message_type.split('^');
+    match (parts.next(), parts.next()) {
+        (Some("ADT"), Some("A01")) => Ok(vec![ClinicalEvent::PatientAdmitted(admission(message)?)]),
+        (Some("ADT"), Some("A03")) => Ok(vec![ClinicalEvent::PatientDischarged(discharge(message)?)]),
+        (Some("ORU"), Some("R01")) => results(message),
+        (Some("RDE"), _) => medication_orders(message),
+        (Some("SIU"), Some("S12")) => Ok(vec![ClinicalEvent::AppointmentScheduled(appointment(message)?)]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Turns messages into events, leaving out those already given out
+///
+/// The keys (`ClinicalEvent::key`) of the last `capacity` events are kept, so
+/// a message resent under a new control ID, or an ORU repeating results
+/// already reported, only gives the events that are new.
+///
+/// ```ignore
+/// let stream = Arc::new(EventStream::new(10_000));
+/// let server = MllpServer::builder()
+///     .handler(stream.handler(|event| notify(event)))
+///     ...
+/// ```
+#[derive(Debug)]
+pub struct EventStream {
+    capacity: usize,
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl EventStream {
+    /// Remember the last `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+        }
+    }
+
+    /// The events in a message that haven't been given out before
+    pub fn events(&self, message: &Message) -> Result<Vec<ClinicalEvent>, HL7Error> {
+        let mut events = events(message)?;
+        let mut guard = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        let (keys, order) = &mut *guard;
+        events.retain(|event| {
+            let key = event.key();
+            if !keys.insert(key.clone()) {
+                debug!("Skipping duplicate event {}", key);
+                return false;
+            }
+            order.push_back(key);
+            if order.len() > self.capacity {
+                if let Some(oldest) = order.pop_front() {
+                    keys.remove(&oldest);
+                }
+            }
+            true
+        });
+        Ok(events)
+    }
+
+    /// A handler for `MllpServer` passing each new event to `on_event`, then returning the message
+    pub fn handler<F>(self: &Arc<Self>, on_event: F) -> MessageHandler
+    where
+        F: Fn(ClinicalEvent) + Send + Sync + 'static,
+    {
+        let stream = self.clone();
+        Arc::new(move |message: Message| {
+            for event in stream.events(&message)? {
+                on_event(event);
+            }
+            Ok(message)
+        })
+    }
+}
+
+/// A value's unescaped text, of its first repetition, if it isn't empty
+fn text(value: Value<'_>) -> Option<String> {
+    value.unescaped().map(|text| text.split('~').next().unwrap_or_default().to_string()).filter(|text| !text.is_empty())
+}
+
+/// A coded value (CWE) as code, text and coding system, if it has a code
+fn code(value: Value<'_>) -> Option<Code> {
+    let code = text(value.component(1))?;
+    Some(Code::new(code, text(value.component(2)).unwrap_or_default(), text(value.component(3)).unwrap_or_default()))
+}
+
+/// A location (PL) as point of care, room and bed, leaving off empty parts at the end
+fn location(value: Value<'_>) -> Option<String> {
+    let parts: Vec<String> = (1..=3).map(|n| text(value.component(n)).unwrap_or_default()).collect();
+    let used = parts.iter().rposition(|part| !part.is_empty())? + 1;
+    Some(parts[..used].join("^"))
+}
+
+fn patient(message: &Message) -> Result<Patient, HL7Error> {
+    let pid = message
+        .get_segment("PID")
+        .ok_or_else(|| HL7Error::MissingField("PID segment".to_string()))?;
+    let patient_id = text(pid.field(3)).ok_or_else(|| HL7Error::MissingField("Patient ID (PID.3)".to_string()))?;
+    Ok(Patient {
+        patient_id,
+        identifiers: identifiers(message, "PID", 3),
+        family_name: text(pid.field(5).component(1)),
+        given_name: text(pid.field(5).component(2)),
+        birth_date: pid.field(7).as_date(),
+        sex: text(pid.field(8)),
+    })
+}
+
+/// When an ADT event happened: the PV1 field given, else EVN-6, else EVN-2
+fn event_time(message: &Message, pv1_field: usize) -> Option<NaiveDateTime> {
+    let pv1 = message.get_segment("PV1").map(|pv1| pv1.field(pv1_field).as_datetime());
+    let evn = message.get_segment("EVN");
+    pv1.flatten()
+        .or_else(|| evn.and_then(|evn| evn.field(6).as_datetime()))
+        .or_else(|| evn.and_then(|evn| evn.field(2).as_datetime()))
+}
+
+fn admission(message: &Message) -> Result<Admission, HL7Error> {
+    let pv1 = message.get_segment("PV1");
+    let field = |n: usize| pv1.map(|pv1| pv1.field(n)).unwrap_or_default();
+    Ok(Admission {
+        patient: patient(message)?,
+        visit_number: text(field(19)),
+        patient_class: text(field(2)),
+        location: location(field(3)),
+        admitted_at: event_time(message, 44),
+    })
+}
+
+fn discharge(message: &Message) -> Result<Discharge, HL7Error> {
+    let pv1 = message.get_segment("PV1");
+    let field = |n: usize| pv1.map(|pv1| pv1.field(n)).unwrap_or_default();
+    Ok(Discharge {
+        patient: patient(message)?,
+        visit_number: text(field(19)),
+        location: location(field(3)),
+        disposition: text(field(36)),
+        discharged_at: event_time(message, 45),
+    })
+}
+
+/// An OBX-5 value typed by OBX-2, falling back to text when it doesn't parse as that type
+fn observation_value(obx: &Segment) -> Option<ObservationValue> {
+    let value = obx.field(5);
+    let raw = text(value)?;
+    let typed = match obx.field(2).as_str().unwrap_or_default() {
+        "NM" | "SN" => value.as_number().map(ObservationValue::Numeric),
+        "CE" | "CWE" | "CNE" => code(value).map(ObservationValue::Coded),
+        "DT" => value.as_date().map(ObservationValue::Date),
+        "TS" | "DTM" => DateTime::parse_from_str(&raw, "%Y%m%d%H%M%S%z").ok().map(ObservationValue::Timestamp),
+        _ => None,
+    };
+    Some(typed.unwrap_or(ObservationValue::Text(raw)))
+}
+
+fn results(message: &Message) -> Result<Vec<ClinicalEvent>, HL7Error> {
+    let patient = patient(message)?;
+    let mut obr: Option<&Segment> = None;
+    let mut events = Vec::new();
+    for segment in &message.segments {
+        match segment.name.as_str() {
+            "OBR" => obr = Some(segment),
+            "OBX" => {
+                let order = |n: usize| obr.map(|obr| obr.field(n)).unwrap_or_default();
+                events.push(ClinicalEvent::ObservationResulted(ObservationResult {
+                    patient: patient.clone(),
+                    service: code(order(4)),
+                    order_number: text(order(3)).or_else(|| text(order(2))),
+                    code: code(segment.field(3)).ok_or_else(|| HL7Error::MissingField("Test ID (OBX.3)".to_string()))?,
+                    sub_id: text(segment.field(4)),
+                    value: observation_value(segment),
+                    units: text(segment.field(6)),
+                    reference_range: text(segment.field(7)),
+                    abnormal_flags: text(segment.field(8)),
+                    status: text(segment.field(11)).unwrap_or_else(|| "F".to_string()),
+                    observed_at: segment.field(14).as_datetime().or_else(|| order(7).as_datetime()),
+                }));
+            }
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+/// The medications of an RDE whose order control (ORC-1) is a new order
+///
+/// Each RXE belongs to the ORC before it, so an RDE with several orders gives
+/// the new ones with their own order numbers.
+fn medication_orders(message: &Message) -> Result<Vec<ClinicalEvent>, HL7Error> {
+    let mut events = Vec::new();
+    let mut orc = None;
+    let mut segments = message.segments.iter().peekable();
+    while let Some(segment) = segments.next() {
+        if segment.name == "ORC" {
+            orc = Some(segment);
+            continue;
+        }
+        let Some(orc) = orc.filter(|orc| segment.name == "RXE" && orc.field(1).as_str() == Some("NW")) else {
+            continue;
+        };
+        // The RXR and TQ1 segments up to the next RXE or ORC belong to this one
+        let mut route = None;
+        let mut tq1 = None;
+        while let Some(next) = segments.next_if(|next| next.name != "RXE" && next.name != "ORC") {
+            match next.name.as_str() {
+                "RXR" if route.is_none() => route = text(next.field(1)),
+                "TQ1" if tq1.is_none() => tq1 = Some(next),
+                _ => {}
+            }
+        }
+        events.push(ClinicalEvent::MedicationOrdered(MedicationOrder {
+            patient: patient(message)?,
+            order_number: text(orc.field(2)).or_else(|| text(orc.field(3))),
+            medication: code(segment.field(2)).ok_or_else(|| HL7Error::MissingField("Give code (RXE.2)".to_string()))?,
+            dose: text(segment.field(3)),
+            dose_units: text(segment.field(5)),
+            route,
+            frequency: tq1.and_then(|tq1| text(tq1.field(3))),
+            start: tq1.and_then(|tq1| tq1.field(7).as_datetime()),
+        }));
+    }
+    Ok(events)
+}
+
+fn appointment(message: &Message) -> Result<Appointment, HL7Error> {
+    let sch = message
+        .get_segment("SCH")
+        .ok_or_else(|| HL7Error::MissingField("SCH segment".to_string()))?;
+    let appointment_id = text(sch.field(2))
+        .or_else(|| text(sch.field(1)))
+        .ok_or_else(|| HL7Error::MissingField("Appointment ID (SCH.2)".to_string()))?;
+    let start = sch
+        .field(11)
+        .component(4)
+        .as_datetime()
+        .or_else(|| message.get_segment("AIS").and_then(|ais| ais.field(4).as_datetime()));
+    Ok(Appointment {
+        patient: patient(message)?,
+        appointment_id,
+        reason: code(sch.field(7)),
+        appointment_type: code(sch.field(8)),
+        start,
+        end: sch.field(11).component(5).as_datetime(),
+        location: message.get_segment("AIL").and_then(|ail| location(ail.field(3))),
+        provider: message.get_segment("AIP").and_then(|aip| text(aip.field(3))),
+    })
+}
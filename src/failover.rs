@@ -0,0 +1,166 @@
+use crate::state::StateBackend;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Which instance of a failover pair this is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Waiting to take over, with no listeners or sources running
+    Standby,
+    /// Taking traffic
+    Active,
+}
+
+/// One instance of an active/standby pair
+///
+/// Both instances point at the same `StateBackend` and try to hold one lease,
+/// renewing it every heartbeat. Whichever holds it is active. If the active
+/// instance dies or loses the backend, its lease runs out and the standby
+/// takes it on its next heartbeat. An active instance that can't reach the
+/// backend steps down while its lease still has a heartbeat and a backend
+/// timeout left, so its next decision comes before the lease runs out, and
+/// the supervisor closes its connections as it does, so the two are never
+/// active at once. On a clean shutdown the lease is released, so the standby
+/// takes over on its next heartbeat rather than waiting for it to run out.
+///
+/// Only the state in the backend is shared: dedup claims, sequences, rate
+/// limits and the lease. Spools for ordered, webhook and scheduled
+/// destinations are files on each instance; put them on storage both
+/// instances mount, or messages queued on one wait until it's active again.
+///
+/// ```
+/// use rust_hl7::failover::{Failover, Role};
+/// use rust_hl7::state::MemoryState;
+/// use std::sync::Arc;
+///
+/// let state = Arc::new(MemoryState::new());
+/// let primary = Failover::new(state.clone(), "hl7-a");
+/// let secondary = Failover::new(state, "hl7-b");
+/// assert_eq!(primary.beat(), Role::Active);
+/// assert_eq!(secondary.beat(), Role::Standby);
+/// ```
+#[derive(Debug)]
+pub struct Failover {
+    state: Arc<dyn StateBackend>,
+    key: String,
+    node: String,
+    heartbeat: Duration,
+    lease: Duration,
+    backend_timeout: Duration,
+    /// When the lease was last renewed, while it's held
+    renewed: Mutex<Option<Instant>>,
+}
+
+impl Failover {
+    /// This instance, called `node`, which must differ from the other instance's name
+    pub fn new<S: ToString>(state: Arc<dyn StateBackend>, node: S) -> Self {
+        Self {
+            state,
+            key: "failover:active".to_string(),
+            node: node.to_string(),
+            heartbeat: Duration::from_secs(2),
+            lease: Duration::from_secs(10),
+            backend_timeout: Duration::from_secs(2),
+            renewed: Mutex::new(None),
+        }
+    }
+
+    /// Set how often the lease is renewed or checked, 2 seconds by default
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Set how long the lease lasts without a renewal, 10 seconds by default;
+    /// this is how long the standby waits after the active instance fails
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Set how long a call to the backend can block before failing, 2 seconds
+    /// by default as for `RedisState`
+    pub fn with_backend_timeout(mut self, timeout: Duration) -> Self {
+        self.backend_timeout = timeout;
+        self
+    }
+
+    /// The name this instance holds the lease under
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Renew or try to take the lease, returning the role this instance should now have
+    pub fn beat(&self) -> Role {
+        // The lease runs from no earlier than the call, so this never overstates it
+        let started = Instant::now();
+        let mut renewed = self.renewed.lock().unwrap_or_else(|e| e.into_inner());
+        match self.state.hold_lease(&self.key, &self.node, self.lease) {
+            Ok(true) => {
+                *renewed = Some(started);
+                Role::Active
+            }
+            Ok(false) => {
+                *renewed = None;
+                Role::Standby
+            }
+            // Stay active only if the lease will outlast the next beat, which can
+            // block for a backend timeout of its own before it decides
+            Err(e) => match *renewed {
+                Some(at) if at.elapsed() + self.heartbeat + self.backend_timeout < self.lease => {
+                    warn!("Failed to renew the failover lease for {}: {}", self.node, e);
+                    Role::Active
+                }
+                _ => {
+                    error!("Failed to hold the failover lease for {}: {}", self.node, e);
+                    *renewed = None;
+                    Role::Standby
+                }
+            },
+        }
+    }
+
+    /// Give up the lease if this instance holds it; stop the heartbeat first
+    pub fn release(&self) {
+        let mut renewed = self.renewed.lock().unwrap_or_else(|e| e.into_inner());
+        match self.state.release_lease(&self.key, &self.node) {
+            Ok(()) => info!("Failover node {} released the lease", self.node),
+            Err(e) => warn!("Failed to release the failover lease for {}: {}", self.node, e),
+        }
+        *renewed = None;
+    }
+
+    /// Beat once, then every heartbeat in the background, publishing the role on the channel
+    pub async fn spawn(self: Arc<Self>) -> (watch::Receiver<Role>, JoinHandle<()>) {
+        let failover = self;
+        let role = failover.beat_blocking().await;
+        info!("Failover node {} starting as {:?}", failover.node, role);
+        let (sender, receiver) = watch::channel(role);
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(failover.heartbeat);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let role = failover.beat_blocking().await;
+                sender.send_if_modified(|current| {
+                    if *current == role {
+                        return false;
+                    }
+                    info!("Failover node {} is now {:?}", failover.node, role);
+                    *current = role;
+                    true
+                });
+            }
+        });
+        (receiver, handle)
+    }
+
+    /// Beat off the async runtime, since backends such as Redis block
+    async fn beat_blocking(self: &Arc<Self>) -> Role {
+        let failover = self.clone();
+        tokio::task::spawn_blocking(move || failover.beat()).await.unwrap_or(Role::Standby)
+    }
+}
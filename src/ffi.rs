@@ -0,0 +1,221 @@
+//! A C interface to parsing, serialization, terser paths and validation
+//!
+//! Messages are opaque `Hl7Message` handles from `hl7_parse`, released with
+//! `hl7_message_free`. Strings the library returns are NUL-terminated UTF-8
+//! owned by the caller, released with `hl7_string_free`. A function that fails
+//! returns NULL or -1 and leaves a description for `hl7_last_error` on the
+//! calling thread. Panics never cross the boundary; they're reported as
+//! errors. `include/rust_hl7.h` declares everything here and is generated with
+//! `cbindgen --config cbindgen.toml --output include/rust_hl7.h`.
+//!
+//! Build the library with
+//! `cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib`
+//! (or `staticlib`).
+
+use crate::validation::Validator;
+use crate::{terser, Message};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+/// Bumped whenever a function's signature or behavior changes incompatibly
+pub const HL7_ABI_VERSION: u32 = 1;
+
+/// A parsed message, owned by the caller until passed to `hl7_message_free`
+pub struct Hl7Message(Message);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(error: Option<String>) {
+    let error = error.map(|e| CString::new(e.replace('\0', " ")).unwrap_or_default());
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+}
+
+/// Run `f`, recording its error or panic for `hl7_last_error` and returning `failed` instead
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    set_last_error(None);
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            set_last_error(Some(e));
+            failed
+        }
+        Err(_) => {
+            set_last_error(Some("rust-hl7 panicked".to_string()));
+            failed
+        }
+    }
+}
+
+/// A string argument, which must not be NULL and must be UTF-8
+///
+/// # Safety
+///
+/// A non-NULL `text` must point to a NUL-terminated string.
+unsafe fn text<'a>(text: *const c_char, name: &str) -> Result<&'a str, String> {
+    if text.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(text)
+        .to_str()
+        .map_err(|e| format!("{} isn't UTF-8: {}", name, e))
+}
+
+/// A handle argument, which must not be NULL
+///
+/// # Safety
+///
+/// A non-NULL `message` must come from `hl7_parse` and not have been freed.
+unsafe fn message<'a>(message: *const Hl7Message) -> Result<&'a Message, String> {
+    message.as_ref().map(|m| &m.0).ok_or_else(|| "message is NULL".to_string())
+}
+
+/// A string handed to the caller
+fn owned(text: String) -> *mut c_char {
+    CString::new(text.replace('\0', " ")).unwrap_or_default().into_raw()
+}
+
+/// The interface version these functions implement, `HL7_ABI_VERSION`
+#[no_mangle]
+pub extern "C" fn hl7_abi_version() -> u32 {
+    HL7_ABI_VERSION
+}
+
+/// The last error on this thread, or NULL if the last call succeeded
+///
+/// The string belongs to the library and is valid until the next call on
+/// this thread; don't free it.
+#[no_mangle]
+pub extern "C" fn hl7_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Parse `len` bytes of ER7 text, returning NULL if it isn't a valid message
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes. The text needn't be
+/// NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_parse(data: *const u8, len: usize) -> *mut Hl7Message {
+    guard(ptr::null_mut(), || {
+        if data.is_null() {
+            return Err("data is NULL".to_string());
+        }
+        let bytes = std::slice::from_raw_parts(data, len);
+        let input = std::str::from_utf8(bytes).map_err(|e| format!("message isn't UTF-8: {}", e))?;
+        let message = Message::parse(input).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(Hl7Message(message))))
+    })
+}
+
+/// Release a message; NULL is ignored
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_message_free(message: *mut Hl7Message) {
+    if !message.is_null() {
+        drop(Box::from_raw(message));
+    }
+}
+
+/// The message as ER7 text, segments separated by `\r`
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_serialize(message: *const Hl7Message) -> *mut c_char {
+    guard(ptr::null_mut(), || Ok(owned(self::message(message)?.to_hl7())))
+}
+
+/// The message in its canonical JSON form
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_to_json(message: *const Hl7Message) -> *mut c_char {
+    guard(ptr::null_mut(), || Ok(owned(self::message(message)?.to_json())))
+}
+
+/// The value at a terser path such as `PID-3.1`
+///
+/// Returns NULL if the value is missing, with `hl7_last_error` also NULL, or
+/// if the path is invalid, with `hl7_last_error` saying why.
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not have been freed; `path` must
+/// be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_get(message: *const Hl7Message, path: *const c_char) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let message = self::message(message)?;
+        let path: terser::TerserPath = text(path, "path")?.parse().map_err(|e: crate::HL7Error| e.to_string())?;
+        Ok(terser::get_path(message, &path).map_or(ptr::null_mut(), owned))
+    })
+}
+
+/// Set the value at a terser path, returning 0, or -1 if it couldn't be set
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not have been freed; `path` and
+/// `value` must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_set(message: *mut Hl7Message, path: *const c_char, value: *const c_char) -> c_int {
+    guard(-1, || {
+        let message = message.as_mut().map(|m| &mut m.0).ok_or("message is NULL")?;
+        terser::set(message, text(path, "path")?, text(value, "value")?).map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+/// Validate a message, returning how many errors were found, or -1 if it couldn't be validated
+///
+/// `version`, if not NULL, is the HL7 version MSH-12 must be. If `report` isn't
+/// NULL, it's set to a JSON array of every error and warning, each with
+/// `severity`, `location` and `message`, for the caller to free.
+///
+/// # Safety
+///
+/// `message` must come from `hl7_parse` and not have been freed; a non-NULL
+/// `version` must be a NUL-terminated string and a non-NULL `report` must be
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_validate(
+    message: *const Hl7Message,
+    version: *const c_char,
+    report: *mut *mut c_char,
+) -> c_int {
+    guard(-1, || {
+        let message = self::message(message)?;
+        let mut validator = Validator::new();
+        if !version.is_null() {
+            validator = validator.version(text(version, "version")?);
+        }
+        let issues = validator.validate(message);
+        if !report.is_null() {
+            *report = owned(serde_json::to_string(&issues).map_err(|e| e.to_string())?);
+        }
+        Ok(issues.iter().filter(|issue| issue.is_error()).count().try_into().unwrap_or(c_int::MAX))
+    })
+}
+
+/// Release a string returned by this library; NULL is ignored
+///
+/// # Safety
+///
+/// `text` must come from this library and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn hl7_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
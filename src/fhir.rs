@@ -0,0 +1,1327 @@
+use crate::{terser, Delimiters, HL7Error, Message};
+use crate::terminology::{code_system, system_name};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Coding system for identifier types (HL7 table 0203)
+const IDENTIFIER_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v2-0203";
+
+/// Coding system for encounter classes
+const ACT_CODE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+
+/// The repetitions of a field, each split into its components
+///
+/// The parser keeps repetitions inside component values, so the field is
+/// re-split from its ER7 text. Escape sequences in each component are decoded.
+fn repetitions(message: &Message, path: &str) -> Vec<Vec<String>> {
+    let Some(text) = terser::get(message, path) else {
+        return Vec::new();
+    };
+    let delimiters = Delimiters::default();
+    text.split('~')
+        .filter(|rep| !rep.is_empty())
+        .map(|rep| rep.split('^').map(|c| delimiters.unescape(c)).collect())
+        .collect()
+}
+
+/// A 1-based component of a repetition, if present and non-empty
+fn component(rep: &[String], number: usize) -> Option<&str> {
+    rep.get(number - 1).map(|c| c.as_str()).filter(|c| !c.is_empty())
+}
+
+/// A non-empty value at a terser path, with escape sequences decoded
+fn value(message: &Message, path: &str) -> Option<String> {
+    terser::get(message, path)
+        .filter(|v| !v.is_empty())
+        .map(|v| Delimiters::default().unescape(&v))
+}
+
+/// Insert a value into a JSON object unless it is empty
+fn insert(object: &mut Map<String, Value>, key: &str, value: Value) {
+    let empty = match &value {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    };
+    if !empty {
+        object.insert(key.to_string(), value);
+    }
+}
+
+/// Convert an HL7 DTM value (`YYYY[MM[DD[HHMM[SS[.S]]]]][+/-ZZZZ]`) to a FHIR date or dateTime
+///
+/// FHIR requires a time zone whenever a time is given, so times without an offset
+/// are reduced to their date.
+pub fn datetime(dtm: &str) -> Option<String> {
+    let (local, offset) = match dtm.find(['+', '-']) {
+        Some(i) => (&dtm[..i], Some(&dtm[i..])),
+        None => (dtm, None),
+    };
+    let (digits, fraction) = match local.split_once('.') {
+        Some((digits, fraction)) => (digits, Some(fraction)),
+        None => (local, None),
+    };
+    if digits.len() < 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = fraction.filter(|f| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit()));
+
+    let part = |start: usize| digits.get(start..start + 2);
+    let mut date = digits[..4].to_string();
+    for start in [4, 6] {
+        match part(start) {
+            Some(p) => date.push_str(&format!("-{}", p)),
+            None => return Some(date),
+        }
+    }
+
+    let (Some(offset), Some(hour)) = (offset, part(8)) else {
+        return Some(date);
+    };
+    // A sign and four ASCII digits, so the offset can be split by byte
+    if offset.len() != 5 || !offset[1..].bytes().all(|b| b.is_ascii_digit()) {
+        return Some(date);
+    }
+    let minute = part(10).unwrap_or("00");
+    let second = part(12).unwrap_or("00");
+    let fraction = fraction.map(|f| format!(".{}", f)).unwrap_or_default();
+    Some(format!(
+        "{}T{}:{}:{}{}{}:{}",
+        date,
+        hour,
+        minute,
+        second,
+        fraction,
+        &offset[..3],
+        &offset[3..]
+    ))
+}
+
+/// Strip characters FHIR doesn't allow in resource IDs
+fn resource_id(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '-' })
+        .take(64)
+        .collect()
+}
+
+/// ID of the Patient resource built from a message, taken from the first PID-3 identifier
+pub fn patient_id(message: &Message) -> Option<String> {
+    repetitions(message, "PID-3")
+        .first()
+        .and_then(|rep| component(rep, 1))
+        .map(resource_id)
+}
+
+/// Build a FHIR R4 `Patient` from the PID segment
+///
+/// Identifiers come from PID-3 (with the assigning authority as the system and
+/// the identifier type as a v2-0203 coding), names from PID-5, birth date from
+/// PID-7, gender from PID-8, addresses from PID-11 and phone numbers from
+/// PID-13/PID-14, following the HL7 v2-to-FHIR mappings.
+pub fn patient(message: &Message) -> Result<Value, HL7Error> {
+    if message.get_segment("PID").is_none() {
+        return Err(HL7Error::MissingField("PID segment".to_string()));
+    }
+
+    let mut resource = Map::new();
+    resource.insert("resourceType".to_string(), json!("Patient"));
+    if let Some(id) = patient_id(message) {
+        resource.insert("id".to_string(), json!(id));
+    }
+
+    let identifiers: Vec<Value> = repetitions(message, "PID-3")
+        .iter()
+        .filter_map(|rep| {
+            let mut identifier = Map::new();
+            insert(&mut identifier, "value", json!(component(rep, 1)?));
+            if let Some(authority) = component(rep, 4) {
+                // Namespace ID, or the universal ID if only that is sent as a subcomponent
+                let system = authority.split('&').find(|s| !s.is_empty()).unwrap_or(authority);
+                insert(&mut identifier, "system", json!(system));
+            }
+            if let Some(code) = component(rep, 5) {
+                insert(
+                    &mut identifier,
+                    "type",
+                    json!({ "coding": [{ "system": IDENTIFIER_TYPE_SYSTEM, "code": code }] }),
+                );
+            }
+            Some(Value::Object(identifier))
+        })
+        .collect();
+    insert(&mut resource, "identifier", json!(identifiers));
+
+    let names: Vec<Value> = repetitions(message, "PID-5")
+        .iter()
+        .map(|rep| {
+            let mut name = Map::new();
+            insert(&mut name, "use", json!(component(rep, 7).and_then(name_use)));
+            insert(&mut name, "family", json!(component(rep, 1)));
+            let given: Vec<&str> = [2, 3].iter().filter_map(|&n| component(rep, n)).collect();
+            insert(&mut name, "given", json!(given));
+            insert(&mut name, "prefix", json!(component(rep, 5).into_iter().collect::<Vec<_>>()));
+            insert(&mut name, "suffix", json!(component(rep, 4).into_iter().collect::<Vec<_>>()));
+            Value::Object(name)
+        })
+        .filter(|name| name.as_object().is_some_and(|n| n.contains_key("family") || n.contains_key("given")))
+        .collect();
+    insert(&mut resource, "name", json!(names));
+
+    let telecom: Vec<Value> = [("PID-13", "home"), ("PID-14", "work")]
+        .iter()
+        .flat_map(|&(path, use_)| {
+            repetitions(message, path).into_iter().filter_map(move |rep| {
+                // Free-text number in XTN.1, or the structured number in XTN.12
+                let number = component(&rep, 1).or_else(|| component(&rep, 12))?.to_string();
+                Some(json!({ "system": "phone", "value": number, "use": use_ }))
+            })
+        })
+        .collect();
+    insert(&mut resource, "telecom", json!(telecom));
+
+    let gender = value(message, "PID-8").map(|code| match code.as_str() {
+        "M" => "male",
+        "F" => "female",
+        "O" | "A" => "other",
+        _ => "unknown",
+    });
+    insert(&mut resource, "gender", json!(gender));
+    insert(&mut resource, "birthDate", json!(value(message, "PID-7.1").and_then(|d| datetime(d.get(..8).unwrap_or(&d)))));
+    if value(message, "PID-30").as_deref() == Some("Y") {
+        insert(&mut resource, "deceasedBoolean", json!(true));
+    }
+
+    let addresses: Vec<Value> = repetitions(message, "PID-11")
+        .iter()
+        .map(|rep| {
+            let mut address = Map::new();
+            insert(&mut address, "use", json!(component(rep, 7).and_then(address_use)));
+            let lines: Vec<&str> = [1, 2].iter().filter_map(|&n| component(rep, n)).collect();
+            insert(&mut address, "line", json!(lines));
+            insert(&mut address, "city", json!(component(rep, 3)));
+            insert(&mut address, "state", json!(component(rep, 4)));
+            insert(&mut address, "postalCode", json!(component(rep, 5)));
+            insert(&mut address, "country", json!(component(rep, 6)));
+            Value::Object(address)
+        })
+        .filter(|address| address.as_object().is_some_and(|a| !a.is_empty()))
+        .collect();
+    insert(&mut resource, "address", json!(addresses));
+
+    Ok(Value::Object(resource))
+}
+
+/// FHIR name use for an XPN name type (HL7 table 0200)
+fn name_use(code: &str) -> Option<&'static str> {
+    match code {
+        "L" => Some("official"),
+        "D" => Some("usual"),
+        "M" => Some("maiden"),
+        "N" => Some("nickname"),
+        "A" | "S" => Some("anonymous"),
+        _ => None,
+    }
+}
+
+/// FHIR address use for an XAD address type (HL7 table 0190)
+fn address_use(code: &str) -> Option<&'static str> {
+    match code {
+        "H" => Some("home"),
+        "B" | "O" => Some("work"),
+        "C" => Some("temp"),
+        "BA" => Some("old"),
+        _ => None,
+    }
+}
+
+/// Build a FHIR R4 `Encounter` from the PV1 segment
+///
+/// The class comes from PV1-2, the identifier from the visit number in PV1-19,
+/// the period from PV1-44/PV1-45, the location from PV1-3 and the attending
+/// doctor from PV1-7. The status is `finished` once a discharge time is known or
+/// for a discharge event, `cancelled` for a cancelled admit, and `in-progress` otherwise.
+pub fn encounter(message: &Message) -> Result<Value, HL7Error> {
+    if message.get_segment("PV1").is_none() {
+        return Err(HL7Error::MissingField("PV1 segment".to_string()));
+    }
+
+    let mut resource = Map::new();
+    resource.insert("resourceType".to_string(), json!("Encounter"));
+
+    let visit_number = value(message, "PV1-19.1");
+    if let Some(id) = visit_number.as_deref().or(value(message, "MSH-10.1").as_deref()) {
+        resource.insert("id".to_string(), json!(resource_id(id)));
+    }
+    if let Some(visit_number) = &visit_number {
+        let mut identifier = Map::new();
+        insert(&mut identifier, "value", json!(visit_number));
+        insert(&mut identifier, "system", json!(value(message, "PV1-19.4")));
+        insert(
+            &mut identifier,
+            "type",
+            json!({ "coding": [{ "system": IDENTIFIER_TYPE_SYSTEM, "code": "VN" }] }),
+        );
+        resource.insert("identifier".to_string(), json!([identifier]));
+    }
+
+    let event = value(message, "MSH-9.2").unwrap_or_default();
+    let discharged = value(message, "PV1-45.1");
+    let status = match event.as_str() {
+        "A11" => "cancelled",
+        "A03" => "finished",
+        _ if discharged.is_some() => "finished",
+        "A05" | "A14" => "planned",
+        _ => "in-progress",
+    };
+    resource.insert("status".to_string(), json!(status));
+
+    let (code, display) = match value(message, "PV1-2").as_deref() {
+        Some("I") => ("IMP", "inpatient encounter"),
+        Some("E") => ("EMER", "emergency"),
+        Some("P") => ("PRENC", "pre-admission"),
+        // Outpatient, recurring and obstetrics visits
+        _ => ("AMB", "ambulatory"),
+    };
+    resource.insert(
+        "class".to_string(),
+        json!({ "system": ACT_CODE_SYSTEM, "code": code, "display": display }),
+    );
+
+    if let Some(id) = patient_id(message) {
+        resource.insert("subject".to_string(), json!({ "reference": format!("Patient/{}", id) }));
+    }
+
+    let mut period = Map::new();
+    insert(&mut period, "start", json!(value(message, "PV1-44.1").and_then(|d| datetime(&d))));
+    insert(&mut period, "end", json!(discharged.and_then(|d| datetime(&d))));
+    if !period.is_empty() {
+        resource.insert("period".to_string(), Value::Object(period));
+    }
+
+    if let Some(location) = repetitions(message, "PV1-3").first() {
+        let display: Vec<&str> = [1, 2, 3].iter().filter_map(|&n| component(location, n)).collect();
+        if !display.is_empty() {
+            resource.insert(
+                "location".to_string(),
+                json!([{ "location": { "display": display.join(" ") } }]),
+            );
+        }
+    }
+
+    let participants: Vec<Value> = repetitions(message, "PV1-7")
+        .iter()
+        .map(|doctor| {
+            let mut individual = Map::new();
+            let name: Vec<&str> = [3, 2].iter().filter_map(|&n| component(doctor, n)).collect();
+            insert(&mut individual, "display", json!(name.join(" ")));
+            if let Some(id) = component(doctor, 1) {
+                insert(&mut individual, "identifier", json!({ "value": id }));
+            }
+            json!({
+                "type": [{ "coding": [{
+                    "system": "http://terminology.hl7.org/CodeSystem/v3-ParticipationType",
+                    "code": "ATND"
+                }] }],
+                "individual": individual
+            })
+        })
+        .collect();
+    insert(&mut resource, "participant", json!(participants));
+
+    Ok(Value::Object(resource))
+}
+
+/// Convert an ADT message into its `Patient` and, if PV1 is present, `Encounter` resources
+pub fn from_adt(message: &Message) -> Result<Vec<Value>, HL7Error> {
+    if !message.is_adt() {
+        return Err(HL7Error::InvalidStructure("Not an ADT message".to_string()));
+    }
+    let mut resources = vec![patient(message)?];
+    if message.get_segment("PV1").is_some() {
+        resources.push(encounter(message)?);
+    }
+    Ok(resources)
+}
+
+/// How a `DiagnosticReport` carries its observations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObservationStyle {
+    /// Observations are separate resources referenced as `Observation/<id>`
+    #[default]
+    Referenced,
+    /// Observations are contained in the report and referenced as `#<id>`
+    Contained,
+}
+
+/// Build a CodeableConcept from a CWE/CE value, including its alternate coding
+fn codeable_concept(rep: &[String]) -> Option<Value> {
+    let codings: Vec<Value> = [(1, 2, 3), (4, 5, 6)]
+        .iter()
+        .filter_map(|&(code, display, system)| {
+            let mut coding = Map::new();
+            insert(&mut coding, "system", json!(component(rep, system).and_then(code_system)));
+            insert(&mut coding, "code", json!(component(rep, code)?));
+            insert(&mut coding, "display", json!(component(rep, display)));
+            Some(Value::Object(coding))
+        })
+        .collect();
+
+    let mut concept = Map::new();
+    insert(&mut concept, "coding", json!(codings));
+    insert(&mut concept, "text", json!(component(rep, 2)));
+    (!concept.is_empty()).then_some(Value::Object(concept))
+}
+
+/// A value from a repeated segment, e.g. `segment_value(message, "OBX", 2, "5.1")`
+fn segment_value(message: &Message, segment: &str, repetition: usize, rest: &str) -> Option<String> {
+    value(message, &format!("{}({})-{}", segment, repetition, rest))
+}
+
+/// Field repetitions from a repeated segment
+fn segment_repetitions(message: &Message, segment: &str, repetition: usize, field: usize) -> Vec<Vec<String>> {
+    repetitions(message, &format!("{}({})-{}", segment, repetition, field))
+}
+
+/// FHIR result status for an HL7 result status (HL7 table 0085/0123)
+fn result_status(code: Option<&str>) -> &'static str {
+    match code {
+        Some("F") => "final",
+        Some("P") | Some("S") => "preliminary",
+        Some("C") => "corrected",
+        Some("A") | Some("M") => "amended",
+        Some("X") => "cancelled",
+        Some("D") | Some("W") => "entered-in-error",
+        Some("I") | Some("R") | Some("O") => "registered",
+        _ => "unknown",
+    }
+}
+
+/// A quantity with its unit from a CWE units value such as `mg/dL^^UCUM`
+fn quantity(number: f64, units: Option<&Vec<String>>) -> Value {
+    let mut quantity = Map::new();
+    quantity.insert("value".to_string(), json!(number));
+    if let Some(units) = units {
+        let code = component(units, 1);
+        insert(&mut quantity, "unit", json!(component(units, 2).or(code)));
+        if let Some(system) = component(units, 3).and_then(code_system) {
+            insert(&mut quantity, "system", json!(system));
+            insert(&mut quantity, "code", json!(code));
+        }
+    }
+    Value::Object(quantity)
+}
+
+/// Parse a reference range such as `4.0-11.0`, `<5` or `>=2`
+fn reference_range(text: &str, units: Option<&Vec<String>>) -> Value {
+    let number = |s: &str| s.trim().trim_start_matches('=').trim().parse::<f64>().ok();
+    let mut range = Map::new();
+
+    if let Some(high) = text.strip_prefix('<').and_then(number) {
+        range.insert("high".to_string(), quantity(high, units));
+    } else if let Some(low) = text.strip_prefix('>').and_then(number) {
+        range.insert("low".to_string(), quantity(low, units));
+    } else if let Some((low, high)) = text
+        .get(1..)
+        .and_then(|rest| rest.split_once('-'))
+        .map(|(low, high)| (&text[..low.len() + 1], high))
+        .and_then(|(low, high)| Some((number(low)?, number(high)?)))
+    {
+        range.insert("low".to_string(), quantity(low, units));
+        range.insert("high".to_string(), quantity(high, units));
+    }
+
+    range.insert("text".to_string(), json!(text));
+    Value::Object(range)
+}
+
+/// Build a FHIR R4 `Observation` from the `repetition`th OBX segment
+///
+/// The code comes from OBX-3 (LOINC when the coding system is `LN`), the value
+/// from OBX-5 typed by OBX-2 (numeric values become quantities with units from
+/// OBX-6), the reference range from OBX-7, interpretation from OBX-8, status from
+/// OBX-11 and the effective time from OBX-14.
+pub fn observation(message: &Message, repetition: usize, id: &str) -> Result<Value, HL7Error> {
+    let obx = |rest: &str| segment_value(message, "OBX", repetition, rest);
+    let code = segment_repetitions(message, "OBX", repetition, 3)
+        .first()
+        .and_then(|rep| codeable_concept(rep))
+        .ok_or_else(|| HL7Error::MissingField(format!("Observation identifier (OBX({})-3)", repetition)))?;
+
+    let mut resource = Map::new();
+    resource.insert("resourceType".to_string(), json!("Observation"));
+    resource.insert("id".to_string(), json!(resource_id(id)));
+    resource.insert("status".to_string(), json!(result_status(obx("11").as_deref())));
+    resource.insert("code".to_string(), code);
+    if let Some(patient) = patient_id(message) {
+        resource.insert("subject".to_string(), json!({ "reference": format!("Patient/{}", patient) }));
+    }
+    insert(&mut resource, "effectiveDateTime", json!(obx("14.1").and_then(|d| datetime(&d))));
+
+    let units = segment_repetitions(message, "OBX", repetition, 6).into_iter().next();
+    let values = segment_repetitions(message, "OBX", repetition, 5);
+    if let Some(first) = values.first() {
+        let text = first.join("^");
+        match obx("2").as_deref() {
+            Some("NM") | Some("SN") => {
+                // Structured numerics (SN) may carry a comparator in the first component
+                let (comparator, number) = match obx("2").as_deref() {
+                    Some("SN") => (component(first, 1), component(first, 2)),
+                    _ => (None, component(first, 1)),
+                };
+                match number.and_then(|n| n.trim().parse::<f64>().ok()) {
+                    Some(number) => {
+                        let mut quantity = quantity(number, units.as_ref());
+                        if let Some(comparator) = comparator.filter(|c| ["<", "<=", ">=", ">"].contains(c)) {
+                            quantity["comparator"] = json!(comparator);
+                        }
+                        resource.insert("valueQuantity".to_string(), quantity);
+                    }
+                    None => insert(&mut resource, "valueString", json!(text)),
+                }
+            }
+            Some("CE") | Some("CWE") | Some("CNE") => {
+                insert(&mut resource, "valueCodeableConcept", json!(codeable_concept(first)));
+            }
+            Some("DT") | Some("TS") | Some("DTM") => {
+                insert(&mut resource, "valueDateTime", json!(component(first, 1).and_then(datetime)));
+            }
+            _ => {
+                let text: Vec<String> = values.iter().map(|rep| rep.join("^")).collect();
+                insert(&mut resource, "valueString", json!(text.join("\n")));
+            }
+        }
+    }
+
+    if let Some(range) = obx("7") {
+        resource.insert("referenceRange".to_string(), json!([reference_range(&range, units.as_ref())]));
+    }
+
+    let interpretation: Vec<Value> = segment_repetitions(message, "OBX", repetition, 8)
+        .iter()
+        .filter_map(|rep| component(rep, 1))
+        .map(|code| {
+            json!({ "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationInterpretation",
+                "code": code
+            }] })
+        })
+        .collect();
+    insert(&mut resource, "interpretation", json!(interpretation));
+
+    Ok(Value::Object(resource))
+}
+
+/// Convert an ORU message into `DiagnosticReport` and `Observation` resources
+///
+/// Each OBR starts a report grouping the OBX segments after it. Report IDs come
+/// from the filler order number (OBR-3) or placer order number (OBR-2), and the
+/// report's code, status and times from OBR-4, OBR-25, OBR-7 and OBR-22. With
+/// `ObservationStyle::Referenced` the observations follow their report in the
+/// returned list; with `Contained` only the reports are returned.
+pub fn from_oru(message: &Message, style: ObservationStyle) -> Result<Vec<Value>, HL7Error> {
+    if !message.is_oru() {
+        return Err(HL7Error::InvalidStructure("Not an ORU message".to_string()));
+    }
+
+    // (OBR repetition, OBX repetitions) for each order group
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    let (mut obr_count, mut obx_count) = (0, 0);
+    for segment in &message.segments {
+        match segment.name.as_str() {
+            "OBR" => {
+                obr_count += 1;
+                groups.push((obr_count, Vec::new()));
+            }
+            "OBX" => {
+                obx_count += 1;
+                match groups.last_mut() {
+                    Some((_, observations)) => observations.push(obx_count),
+                    None => return Err(HL7Error::InvalidStructure("OBX before any OBR".to_string())),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let patient = patient_id(message);
+    let mut resources = Vec::new();
+
+    for (obr, observations) in groups {
+        let obr_value = |rest: &str| segment_value(message, "OBR", obr, rest);
+        let report_id = obr_value("3.1")
+            .or_else(|| obr_value("2.1"))
+            .or_else(|| value(message, "MSH-10.1").map(|id| format!("{}-{}", id, obr)))
+            .map(|id| resource_id(&id))
+            .unwrap_or_else(|| format!("report-{}", obr));
+
+        let mut report = Map::new();
+        report.insert("resourceType".to_string(), json!("DiagnosticReport"));
+        report.insert("id".to_string(), json!(report_id));
+        let mut identifiers = Vec::new();
+        for (field, code) in [("2.1", "PLAC"), ("3.1", "FILL")] {
+            if let Some(id) = obr_value(field) {
+                identifiers.push(json!({
+                    "type": { "coding": [{ "system": IDENTIFIER_TYPE_SYSTEM, "code": code }] },
+                    "value": id
+                }));
+            }
+        }
+        insert(&mut report, "identifier", json!(identifiers));
+        report.insert("status".to_string(), json!(result_status(obr_value("25").as_deref())));
+        let code = segment_repetitions(message, "OBR", obr, 4)
+            .first()
+            .and_then(|rep| codeable_concept(rep))
+            .ok_or_else(|| HL7Error::MissingField(format!("Universal service ID (OBR({})-4)", obr)))?;
+        report.insert("code".to_string(), code);
+        if let Some(patient) = &patient {
+            report.insert("subject".to_string(), json!({ "reference": format!("Patient/{}", patient) }));
+        }
+        insert(&mut report, "effectiveDateTime", json!(obr_value("7.1").and_then(|d| datetime(&d))));
+        insert(&mut report, "issued", json!(obr_value("22.1").and_then(|d| datetime(&d))));
+
+        let mut built = Vec::new();
+        let mut results = Vec::new();
+        for obx in observations {
+            let set_id = segment_value(message, "OBX", obx, "1").unwrap_or_else(|| obx.to_string());
+            let observation = observation(message, obx, &format!("{}-{}", report_id, set_id))?;
+            let id = observation["id"].as_str().unwrap_or_default().to_string();
+            results.push(match style {
+                ObservationStyle::Referenced => json!({ "reference": format!("Observation/{}", id) }),
+                ObservationStyle::Contained => json!({ "reference": format!("#{}", id) }),
+            });
+            built.push(observation);
+        }
+        insert(&mut report, "result", json!(results));
+
+        match style {
+            ObservationStyle::Referenced => {
+                resources.push(Value::Object(report));
+                resources.extend(built);
+            }
+            ObservationStyle::Contained => {
+                insert(&mut report, "contained", json!(built));
+                resources.push(Value::Object(report));
+            }
+        }
+    }
+
+    Ok(resources)
+}
+
+/// FHIR medication request status for an order control code (HL7 table 0119)
+fn order_status(code: Option<&str>) -> &'static str {
+    match code {
+        Some("CA") | Some("OC") | Some("CR") => "cancelled",
+        Some("DC") | Some("OD") => "stopped",
+        Some("HD") | Some("OH") => "on-hold",
+        Some("RP") | Some("RU") | Some("RE") | Some("NW") | Some("OK") | Some("SC") | Some("XO") => "active",
+        _ => "unknown",
+    }
+}
+
+/// Timing for a TQ1 repeat pattern such as `BID`, `Q6H` or `QD`
+fn repeat_pattern(pattern: &str) -> Option<Value> {
+    let (frequency, period, unit) = match pattern {
+        "QD" | "DAILY" | "QAM" | "QPM" | "QHS" => (1, 1.0, "d"),
+        "BID" => (2, 1.0, "d"),
+        "TID" => (3, 1.0, "d"),
+        "QID" => (4, 1.0, "d"),
+        "QOD" => (1, 2.0, "d"),
+        "QW" | "WEEKLY" => (1, 1.0, "wk"),
+        other => {
+            // Q<n>H, Q<n>D, Q<n>M (minutes), Q<n>J (weeks)
+            let rest = other.strip_prefix('Q')?;
+            let (number, unit) = rest.split_at(rest.len().checked_sub(1)?);
+            let unit = match unit {
+                "H" => "h",
+                "D" => "d",
+                "M" => "min",
+                "J" => "wk",
+                _ => return None,
+            };
+            (1, number.parse::<f64>().ok()?, unit)
+        }
+    };
+    Some(json!({ "frequency": frequency, "period": period, "periodUnit": unit }))
+}
+
+/// Build FHIR R4 `MedicationRequest`s from the RXE segments of an RDE message
+///
+/// The medication comes from the give code (RXE-2, or RXE-1 from senders that put
+/// it there), the dose from RXE-3/RXE-5, the route from the RXR after each RXE,
+/// and the timing from TQ1 (repeat pattern in TQ1-3, start and end in TQ1-7/TQ1-8).
+/// The status and dates come from ORC.
+pub fn medication_requests(message: &Message) -> Result<Vec<Value>, HL7Error> {
+    if !message.is_rde() {
+        return Err(HL7Error::InvalidStructure("Not an RDE message".to_string()));
+    }
+
+    let patient = patient_id(message);
+    let order_id = value(message, "ORC-2.1")
+        .or_else(|| value(message, "MSH-10.1"))
+        .map(|id| resource_id(&id))
+        .unwrap_or_else(|| "order".to_string());
+    let rxe_count = message.get_segments("RXE").len();
+    let mut resources = Vec::new();
+
+    for rxe in 1..=rxe_count {
+        let field = |rest: &str| segment_value(message, "RXE", rxe, rest);
+        let medication = [2, 1]
+            .iter()
+            .filter_map(|&n| segment_repetitions(message, "RXE", rxe, n).into_iter().next())
+            .find(|rep| component(rep, 2).is_some())
+            .and_then(|rep| codeable_concept(&rep))
+            .ok_or_else(|| HL7Error::MissingField(format!("Give code (RXE({})-2)", rxe)))?;
+
+        let mut resource = Map::new();
+        resource.insert("resourceType".to_string(), json!("MedicationRequest"));
+        let id = if rxe_count == 1 { order_id.clone() } else { format!("{}-{}", order_id, rxe) };
+        resource.insert("id".to_string(), json!(id));
+        let mut identifiers = Vec::new();
+        for (path, code) in [("ORC-2.1", "PLAC"), ("ORC-3.1", "FILL")] {
+            if let Some(id) = value(message, path) {
+                identifiers.push(json!({
+                    "type": { "coding": [{ "system": IDENTIFIER_TYPE_SYSTEM, "code": code }] },
+                    "value": id
+                }));
+            }
+        }
+        insert(&mut resource, "identifier", json!(identifiers));
+        resource.insert("status".to_string(), json!(order_status(value(message, "ORC-1").as_deref())));
+        resource.insert("intent".to_string(), json!("order"));
+        resource.insert("medicationCodeableConcept".to_string(), medication);
+        if let Some(patient) = &patient {
+            resource.insert("subject".to_string(), json!({ "reference": format!("Patient/{}", patient) }));
+        }
+        insert(&mut resource, "authoredOn", json!(value(message, "ORC-9.1").and_then(|d| datetime(&d))));
+        if let Some(requester) = repetitions(message, "ORC-12").first() {
+            let name: Vec<&str> = [3, 2].iter().filter_map(|&n| component(requester, n)).collect();
+            insert(&mut resource, "requester", json!({ "display": name.join(" ") }));
+        }
+
+        let mut dosage = Map::new();
+        if let Some(amount) = field("3.1").and_then(|a| a.parse::<f64>().ok()) {
+            let units = segment_repetitions(message, "RXE", rxe, 5).into_iter().next();
+            dosage.insert("doseAndRate".to_string(), json!([{ "doseQuantity": quantity(amount, units.as_ref()) }]));
+        }
+        if let Some(route) = segment_repetitions(message, "RXR", rxe, 1).first().and_then(|rep| codeable_concept(rep)) {
+            dosage.insert("route".to_string(), route);
+        }
+
+        let tq1 = |rest: &str| segment_value(message, "TQ1", rxe.min(message.get_segments("TQ1").len().max(1)), rest);
+        let mut repeat = tq1("3.1").and_then(|p| repeat_pattern(&p)).unwrap_or_else(|| json!({}));
+        let mut bounds = Map::new();
+        insert(&mut bounds, "start", json!(tq1("7.1").and_then(|d| datetime(&d))));
+        insert(&mut bounds, "end", json!(tq1("8.1").and_then(|d| datetime(&d))));
+        if !bounds.is_empty() {
+            repeat["boundsPeriod"] = Value::Object(bounds);
+        }
+        if repeat.as_object().is_some_and(|r| !r.is_empty()) {
+            let mut timing = Map::new();
+            timing.insert("repeat".to_string(), repeat);
+            if let Some(pattern) = tq1("3.1") {
+                timing.insert("code".to_string(), json!({ "text": pattern }));
+            }
+            dosage.insert("timing".to_string(), Value::Object(timing));
+        }
+        insert(&mut dosage, "text", json!(tq1("3.1")));
+        if !dosage.is_empty() {
+            resource.insert("dosageInstruction".to_string(), json!([dosage]));
+        }
+
+        let mut dispense = Map::new();
+        if let Some(amount) = field("10.1").and_then(|a| a.parse::<f64>().ok()) {
+            let units = segment_repetitions(message, "RXE", rxe, 11).into_iter().next();
+            dispense.insert("quantity".to_string(), quantity(amount, units.as_ref()));
+        }
+        if let Some(refills) = field("12.1").and_then(|r| r.parse::<u32>().ok()) {
+            dispense.insert("numberOfRepeatsAllowed".to_string(), json!(refills));
+        }
+        if !dispense.is_empty() {
+            resource.insert("dispenseRequest".to_string(), Value::Object(dispense));
+        }
+
+        resources.push(Value::Object(resource));
+    }
+
+    Ok(resources)
+}
+
+/// Build FHIR R4 `Immunization`s from the RXA segments of a VXU message
+///
+/// The vaccine comes from RXA-5 (CVX when the coding system is `CVX`), the date
+/// from RXA-3, the dose from RXA-6/RXA-7, lot and expiry from RXA-15/RXA-16, the
+/// manufacturer from RXA-17, the status from RXA-20/RXA-21, and the route and
+/// site from the RXR after each RXA.
+pub fn immunizations(message: &Message) -> Result<Vec<Value>, HL7Error> {
+    if !message.message_type.starts_with("VXU") {
+        return Err(HL7Error::InvalidStructure("Not a VXU message".to_string()));
+    }
+
+    let patient = patient_id(message);
+    let control_id = value(message, "MSH-10.1").map(|id| resource_id(&id)).unwrap_or_else(|| "vxu".to_string());
+    let rxa_count = message.get_segments("RXA").len();
+    let mut resources = Vec::new();
+
+    // RXR segments follow their RXA, so pair them by position in the message
+    let mut rxr_for_rxa = Vec::new();
+    let (mut rxa_seen, mut rxr_seen) = (0, 0);
+    for segment in &message.segments {
+        match segment.name.as_str() {
+            "RXA" => {
+                rxa_seen += 1;
+                rxr_for_rxa.push(None);
+            }
+            "RXR" => {
+                rxr_seen += 1;
+                if let Some(slot) = rxr_for_rxa.get_mut(rxa_seen.max(1) - 1) {
+                    slot.get_or_insert(rxr_seen);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for rxa in 1..=rxa_count {
+        let field = |rest: &str| segment_value(message, "RXA", rxa, rest);
+        let vaccine = segment_repetitions(message, "RXA", rxa, 5)
+            .first()
+            .and_then(|rep| codeable_concept(rep))
+            .ok_or_else(|| HL7Error::MissingField(format!("Administered code (RXA({})-5)", rxa)))?;
+
+        let mut resource = Map::new();
+        resource.insert("resourceType".to_string(), json!("Immunization"));
+        resource.insert("id".to_string(), json!(format!("{}-{}", control_id, rxa)));
+
+        let status = match (field("21").as_deref(), field("20").as_deref()) {
+            (Some("D"), _) => "entered-in-error",
+            (_, Some("RE")) | (_, Some("NA")) => "not-done",
+            _ => "completed",
+        };
+        resource.insert("status".to_string(), json!(status));
+        resource.insert("vaccineCode".to_string(), vaccine);
+        if let Some(patient) = &patient {
+            resource.insert("patient".to_string(), json!({ "reference": format!("Patient/{}", patient) }));
+        }
+        insert(&mut resource, "occurrenceDateTime", json!(field("3.1").and_then(|d| datetime(&d))));
+        // RXA-9 "00" means the record comes from the administering provider
+        resource.insert("primarySource".to_string(), json!(field("9.1").is_none_or(|s| s == "00")));
+
+        // 999 is the conventional "amount unknown"
+        if let Some(amount) = field("6.1").filter(|a| a != "999").and_then(|a| a.parse::<f64>().ok()) {
+            let units = segment_repetitions(message, "RXA", rxa, 7).into_iter().next();
+            resource.insert("doseQuantity".to_string(), quantity(amount, units.as_ref()));
+        }
+        insert(&mut resource, "lotNumber", json!(field("15.1")));
+        insert(&mut resource, "expirationDate", json!(field("16.1").and_then(|d| datetime(d.get(..8).unwrap_or(&d)))));
+        if let Some(manufacturer) = segment_repetitions(message, "RXA", rxa, 17).first() {
+            if let Some(name) = component(manufacturer, 2).or(component(manufacturer, 1)) {
+                resource.insert("manufacturer".to_string(), json!({ "display": name }));
+            }
+        }
+
+        if let Some(rxr) = rxr_for_rxa.get(rxa - 1).copied().flatten() {
+            if let Some(route) = segment_repetitions(message, "RXR", rxr, 1).first().and_then(|rep| codeable_concept(rep)) {
+                resource.insert("route".to_string(), route);
+            }
+            if let Some(site) = segment_repetitions(message, "RXR", rxr, 2).first().and_then(|rep| codeable_concept(rep)) {
+                resource.insert("site".to_string(), site);
+            }
+        }
+
+        resources.push(Value::Object(resource));
+    }
+
+    Ok(resources)
+}
+
+/// Whether a bundle is a FHIR message or a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BundleType {
+    /// A `message` bundle led by a `MessageHeader`
+    #[default]
+    Message,
+    /// A `transaction` bundle that creates or updates each resource with PUT
+    Transaction,
+}
+
+/// How messages are bundled
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BundleOptions {
+    pub bundle_type: BundleType,
+    /// Base URL of the receiving FHIR server, used for each entry's `fullUrl`
+    pub base_url: String,
+}
+
+/// Convert a message into a FHIR `Bundle` holding all its resources
+///
+/// ADT, ORU, RDE and VXU messages are supported. Every bundle includes the
+/// `Patient`, since the other resources refer to it. Resource IDs are derived from
+/// the message, so a transaction bundle updates the same resources when a message
+/// is resent.
+pub fn bundle(message: &Message, options: &BundleOptions) -> Result<Value, HL7Error> {
+    let code = value(message, "MSH-9.1").unwrap_or_default();
+    let mut resources = match code.as_str() {
+        "ADT" => from_adt(message)?,
+        "ORU" => from_oru(message, ObservationStyle::Referenced)?,
+        "RDE" => medication_requests(message)?,
+        "VXU" => immunizations(message)?,
+        _ => {
+            return Err(HL7Error::InvalidStructure(format!(
+                "No FHIR mapping for {} messages",
+                message.message_type
+            )))
+        }
+    };
+    if code != "ADT" {
+        resources.insert(0, patient(message)?);
+    }
+
+    let full_url = |resource: &Value| {
+        format!(
+            "{}/{}/{}",
+            options.base_url.trim_end_matches('/'),
+            resource["resourceType"].as_str().unwrap_or_default(),
+            resource["id"].as_str().unwrap_or_default()
+        )
+    };
+
+    let mut entries: Vec<Value> = Vec::new();
+    if options.bundle_type == BundleType::Message {
+        let mut source = Map::new();
+        insert(&mut source, "name", json!(value(message, "MSH-3.1")));
+        source.insert("endpoint".to_string(), json!(value(message, "MSH-4.1").unwrap_or_default()));
+        let header = json!({
+            "resourceType": "MessageHeader",
+            "id": resource_id(&value(message, "MSH-10.1").unwrap_or_else(|| "header".to_string())),
+            "eventCoding": {
+                "system": "http://terminology.hl7.org/CodeSystem/v2-0003",
+                "code": value(message, "MSH-9.2").unwrap_or_default()
+            },
+            "source": source,
+            "focus": resources
+                .iter()
+                .map(|r| json!({ "reference": full_url(r) }))
+                .collect::<Vec<_>>()
+        });
+        entries.push(json!({ "fullUrl": full_url(&header), "resource": header }));
+    }
+
+    for resource in resources {
+        let mut entry = Map::new();
+        entry.insert("fullUrl".to_string(), json!(full_url(&resource)));
+        if options.bundle_type == BundleType::Transaction {
+            let url = format!(
+                "{}/{}",
+                resource["resourceType"].as_str().unwrap_or_default(),
+                resource["id"].as_str().unwrap_or_default()
+            );
+            entry.insert("request".to_string(), json!({ "method": "PUT", "url": url }));
+        }
+        entry.insert("resource".to_string(), resource);
+        entries.push(Value::Object(entry));
+    }
+
+    let mut bundle = Map::new();
+    bundle.insert("resourceType".to_string(), json!("Bundle"));
+    if let Some(id) = value(message, "MSH-10.1") {
+        bundle.insert("id".to_string(), json!(resource_id(&id)));
+    }
+    let bundle_type = match options.bundle_type {
+        BundleType::Message => "message",
+        BundleType::Transaction => "transaction",
+    };
+    bundle.insert("type".to_string(), json!(bundle_type));
+    bundle.insert("timestamp".to_string(), json!(chrono::Utc::now().to_rfc3339()));
+    bundle.insert("entry".to_string(), json!(entries));
+    Ok(Value::Object(bundle))
+}
+
+/// Convert a FHIR date, dateTime or instant to an HL7 DTM value
+///
+/// The inverse of `datetime`: `2023-04-01T12:00:00+00:00` becomes `20230401120000+0000`.
+pub fn dtm(datetime: &str) -> Option<String> {
+    let (date, time) = match datetime.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (datetime, None),
+    };
+    let mut dtm: String = date.chars().filter(|c| *c != '-').collect();
+    if dtm.len() < 4 || !dtm.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    if let Some(time) = time {
+        let (clock, offset) = match time.find(['+', '-', 'Z']) {
+            Some(i) => (&time[..i], &time[i..]),
+            None => (time, ""),
+        };
+        dtm.extend(clock.chars().filter(|c| *c != ':'));
+        match offset {
+            "" => {}
+            "Z" => dtm.push_str("+0000"),
+            offset => dtm.extend(offset.chars().filter(|c| *c != ':')),
+        }
+    }
+    Some(dtm)
+}
+
+/// A string at a JSON pointer, escaped for use in an HL7 component
+fn escaped(resource: &Value, pointer: &str) -> String {
+    resource
+        .pointer(pointer)
+        .and_then(|v| v.as_str())
+        .map(|s| crate::Delimiters::default().escape(s))
+        .unwrap_or_default()
+}
+
+/// Join components, dropping trailing empty ones
+fn components(parts: &[String]) -> String {
+    let end = parts.iter().rposition(|p| !p.is_empty()).map_or(0, |i| i + 1);
+    parts[..end].join("^")
+}
+
+/// A CWE value from a CodeableConcept's first two codings
+fn cwe(concept: &Value) -> String {
+    let mut parts = Vec::new();
+    for index in 0..2 {
+        let coding = &concept["coding"][index];
+        let mut display = escaped(coding, "/display");
+        if index == 0 && display.is_empty() {
+            display = escaped(concept, "/text");
+        }
+        let system = coding["system"].as_str().and_then(system_name).unwrap_or_default();
+        parts.extend([escaped(coding, "/code"), display, system.to_string()]);
+    }
+    components(&parts)
+}
+
+/// An HL7 DTM from a FHIR date at a JSON pointer
+fn dtm_at(resource: &Value, pointer: &str) -> String {
+    resource
+        .pointer(pointer)
+        .and_then(|v| v.as_str())
+        .and_then(dtm)
+        .unwrap_or_default()
+}
+
+/// Check a resource's type
+fn expect_type(resource: &Value, resource_type: &str) -> Result<(), HL7Error> {
+    match resource["resourceType"].as_str() {
+        Some(t) if t == resource_type => Ok(()),
+        other => Err(HL7Error::InvalidStructure(format!(
+            "Expected a {} resource, found {}",
+            resource_type,
+            other.unwrap_or("no resourceType")
+        ))),
+    }
+}
+
+/// Add a PID segment built from a `Patient`
+fn pid_from_patient(builder: crate::MessageBuilder, patient: &Value) -> Result<crate::MessageBuilder, HL7Error> {
+    expect_type(patient, "Patient")?;
+    let list = |key: &str| patient[key].as_array().cloned().unwrap_or_default();
+
+    let identifiers: Vec<String> = list("identifier")
+        .iter()
+        .map(|identifier| {
+            components(&[
+                escaped(identifier, "/value"),
+                String::new(),
+                String::new(),
+                escaped(identifier, "/system"),
+                escaped(identifier, "/type/coding/0/code"),
+            ])
+        })
+        .filter(|cx| !cx.is_empty())
+        .collect();
+    if identifiers.is_empty() {
+        return Err(HL7Error::MissingField("Patient identifier".to_string()));
+    }
+
+    let names: Vec<String> = list("name")
+        .iter()
+        .map(|name| {
+            let use_ = match name["use"].as_str() {
+                Some("official") => "L",
+                Some("usual") => "D",
+                Some("maiden") => "M",
+                Some("nickname") => "N",
+                Some("anonymous") => "A",
+                _ => "",
+            };
+            components(&[
+                escaped(name, "/family"),
+                escaped(name, "/given/0"),
+                escaped(name, "/given/1"),
+                escaped(name, "/suffix/0"),
+                escaped(name, "/prefix/0"),
+                String::new(),
+                use_.to_string(),
+            ])
+        })
+        .collect();
+
+    let addresses: Vec<String> = list("address")
+        .iter()
+        .map(|address| {
+            let use_ = match address["use"].as_str() {
+                Some("home") => "H",
+                Some("work") => "B",
+                Some("temp") => "C",
+                Some("old") => "BA",
+                _ => "",
+            };
+            components(&[
+                escaped(address, "/line/0"),
+                escaped(address, "/line/1"),
+                escaped(address, "/city"),
+                escaped(address, "/state"),
+                escaped(address, "/postalCode"),
+                escaped(address, "/country"),
+                use_.to_string(),
+            ])
+        })
+        .collect();
+
+    let phones = |use_: &str| -> String {
+        list("telecom")
+            .iter()
+            .filter(|t| t["system"] == "phone" && (t["use"] == use_ || (use_ == "home" && t["use"].is_null())))
+            .map(|t| escaped(t, "/value"))
+            .collect::<Vec<_>>()
+            .join("~")
+    };
+
+    let gender = match patient["gender"].as_str() {
+        Some("male") => "M",
+        Some("female") => "F",
+        Some("other") => "O",
+        Some(_) => "U",
+        None => "",
+    };
+
+    let mut builder = builder
+        .segment("PID")
+        .set("PID-1", "1")
+        .set("PID-3", identifiers.join("~"))
+        .set("PID-5", names.join("~"))
+        .set("PID-7", dtm_at(patient, "/birthDate"))
+        .set("PID-8", gender)
+        .set("PID-11", addresses.join("~"))
+        .set("PID-13", phones("home"))
+        .set("PID-14", phones("work"));
+    if patient["deceasedBoolean"] == true || patient.get("deceasedDateTime").is_some() {
+        builder = builder.set("PID-29", dtm_at(patient, "/deceasedDateTime")).set("PID-30", "Y");
+    }
+    Ok(builder)
+}
+
+/// Build an ADT^A08 (update patient information) message from a `Patient` and optional `Encounter`
+///
+/// The inverse of `from_adt`: PID comes from the patient, and PV1 from the
+/// encounter's class, location, attending doctor, visit number and period.
+/// Without an encounter a PV1 with patient class `N` (not applicable) is sent,
+/// since A08 requires one. `builder` supplies the MSH values such as the
+/// sending and receiving applications.
+pub fn to_adt_a08(
+    patient: &Value,
+    encounter: Option<&Value>,
+    builder: crate::MessageBuilder,
+) -> Result<Message, HL7Error> {
+    let builder = builder
+        .message_type("ADT^A08^ADT_A01")
+        .segment("EVN")
+        .set("EVN-1", "A08")
+        .set("EVN-2", chrono::Utc::now().format("%Y%m%d%H%M%S%z"));
+    let builder = pid_from_patient(builder, patient)?.segment("PV1").set("PV1-1", "1");
+
+    let Some(encounter) = encounter else {
+        return builder.set("PV1-2", "N").build();
+    };
+    expect_type(encounter, "Encounter")?;
+
+    let class = match encounter["class"]["code"].as_str() {
+        Some("IMP") | Some("ACUTE") | Some("NONAC") => "I",
+        Some("EMER") => "E",
+        Some("PRENC") => "P",
+        Some(_) => "O",
+        None => "U",
+    };
+    let doctors: Vec<String> = encounter["participant"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|participant| {
+            let display = participant["individual"]["display"].as_str().unwrap_or_default();
+            // Displays are built as "given family"
+            let (given, family) = display.rsplit_once(' ').unwrap_or(("", display));
+            let escape = |s: &str| crate::Delimiters::default().escape(s);
+            components(&[escaped(participant, "/individual/identifier/value"), escape(family), escape(given)])
+        })
+        .filter(|xcn| !xcn.is_empty())
+        .collect();
+    let visit_number = components(&[
+        escaped(encounter, "/identifier/0/value"),
+        String::new(),
+        String::new(),
+        escaped(encounter, "/identifier/0/system"),
+    ]);
+
+    builder
+        .set("PV1-2", class)
+        .set("PV1-3.1", escaped(encounter, "/location/0/location/display"))
+        .set("PV1-7", doctors.join("~"))
+        .set("PV1-19", visit_number)
+        .set("PV1-44", dtm_at(encounter, "/period/start"))
+        .set("PV1-45", dtm_at(encounter, "/period/end"))
+        .build()
+}
+
+/// OBX-11 result status for a FHIR observation status
+fn observation_result_status(status: Option<&str>) -> &'static str {
+    match status {
+        Some("final") => "F",
+        Some("preliminary") => "P",
+        Some("corrected") => "C",
+        Some("amended") => "A",
+        Some("cancelled") => "X",
+        Some("entered-in-error") => "W",
+        Some("registered") => "I",
+        _ => "R",
+    }
+}
+
+/// A CWE units value from a FHIR Quantity
+fn units(quantity: &Value) -> String {
+    match quantity["system"].as_str().and_then(system_name) {
+        Some(system) => components(&[escaped(quantity, "/code"), escaped(quantity, "/unit"), system.to_string()]),
+        None => escaped(quantity, "/unit"),
+    }
+}
+
+/// Build an ORU^R01 (unsolicited observation result) message from `Observation`s
+///
+/// The inverse of `from_oru`. One OBR groups the observations, ordered by the
+/// first observation's code, with one OBX each carrying the code, typed value,
+/// units, reference range, interpretation, status and effective time. PID comes
+/// from `patient`, or from the observations' `Patient/<id>` subject if it is not
+/// given.
+pub fn to_oru_r01(
+    patient: Option<&Value>,
+    observations: &[Value],
+    builder: crate::MessageBuilder,
+) -> Result<Message, HL7Error> {
+    let first = observations
+        .first()
+        .ok_or_else(|| HL7Error::MissingField("Observation".to_string()))?;
+    let builder = builder.message_type("ORU^R01^ORU_R01");
+    let mut builder = match patient {
+        Some(patient) => pid_from_patient(builder, patient)?,
+        None => {
+            let id = first["subject"]["reference"]
+                .as_str()
+                .and_then(|r| r.strip_prefix("Patient/"))
+                .ok_or_else(|| HL7Error::MissingField("Observation subject".to_string()))?;
+            builder
+                .segment("PID")
+                .set("PID-1", "1")
+                .set("PID-3.1", crate::Delimiters::default().escape(id))
+        }
+    };
+
+    builder = builder
+        .segment("OBR")
+        .set("OBR-1", "1")
+        .set("OBR-4", cwe(&first["code"]))
+        .set("OBR-7", dtm_at(first, "/effectiveDateTime"))
+        .set("OBR-25", observation_result_status(first["status"].as_str()));
+
+    for (index, observation) in observations.iter().enumerate() {
+        expect_type(observation, "Observation")?;
+        let obx = |field: usize| format!("OBX({})-{}", index + 1, field);
+
+        let (value_type, value, units) = if let Some(quantity) = observation.get("valueQuantity") {
+            let number = quantity["value"].as_f64().map(|n| n.to_string()).unwrap_or_default();
+            match quantity["comparator"].as_str() {
+                Some(comparator) => ("SN", format!("{}^{}", comparator, number), units(quantity)),
+                None => ("NM", number, units(quantity)),
+            }
+        } else if let Some(concept) = observation.get("valueCodeableConcept") {
+            ("CWE", cwe(concept), String::new())
+        } else if let Some(datetime) = observation["valueDateTime"].as_str() {
+            ("DTM", dtm(datetime).unwrap_or_default(), String::new())
+        } else if let Some(text) = observation["valueString"].as_str() {
+            let delimiters = crate::Delimiters::default();
+            let lines: Vec<String> = text.lines().map(|line| delimiters.escape(line)).collect();
+            ("ST", lines.join("~"), String::new())
+        } else {
+            ("", String::new(), String::new())
+        };
+
+        let range = observation["referenceRange"][0].clone();
+        let range = match range["text"].as_str() {
+            Some(_) => escaped(&range, "/text"),
+            None => match (range["low"]["value"].as_f64(), range["high"]["value"].as_f64()) {
+                (Some(low), Some(high)) => format!("{}-{}", low, high),
+                (Some(low), None) => format!(">{}", low),
+                (None, Some(high)) => format!("<{}", high),
+                (None, None) => String::new(),
+            },
+        };
+        let interpretation: Vec<String> = observation["interpretation"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|concept| escaped(concept, "/coding/0/code"))
+            .filter(|code| !code.is_empty())
+            .collect();
+
+        builder = builder
+            .segment("OBX")
+            .set(obx(1), (index + 1).to_string())
+            .set(obx(2), value_type)
+            .set(obx(3), cwe(&observation["code"]))
+            .set(obx(5), value)
+            .set(obx(6), units)
+            .set(obx(7), range)
+            .set(obx(8), interpretation.join("~"))
+            .set(obx(11), observation_result_status(observation["status"].as_str()))
+            .set(obx(14), dtm_at(observation, "/effectiveDateTime"));
+    }
+
+    builder.build()
+}
+
+/// Convert FHIR JSON into an outbound message
+///
+/// Accepts a `Bundle`, an array of resources or a single resource. If any
+/// `Observation`s are present an ORU^R01 is built with `to_oru_r01`; otherwise a
+/// `Patient` (and optional `Encounter`) becomes an ADT^A08 with `to_adt_a08`.
+pub fn to_message(resources: &Value, builder: crate::MessageBuilder) -> Result<Message, HL7Error> {
+    let resources: Vec<&Value> = match resources {
+        Value::Array(items) => items.iter().collect(),
+        bundle if bundle["resourceType"] == "Bundle" => bundle["entry"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|entry| &entry["resource"])
+            .collect(),
+        resource => vec![resource],
+    };
+    let of_type = |resource_type: &'static str| resources.iter().copied().filter(move |r| r["resourceType"] == resource_type);
+
+    let patient = of_type("Patient").next();
+    let observations: Vec<Value> = of_type("Observation").cloned().collect();
+    if !observations.is_empty() {
+        return to_oru_r01(patient, &observations, builder);
+    }
+    match patient {
+        Some(patient) => to_adt_a08(patient, of_type("Encounter").next(), builder),
+        None => Err(HL7Error::MissingField("Patient or Observation resource".to_string())),
+    }
+}
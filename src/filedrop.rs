@@ -0,0 +1,278 @@
+use crate::charset::{self, Charset};
+use crate::mllp::MessageHandler;
+use crate::Message;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// Outcome of one scan of the drop directory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanReport {
+    /// Files whose messages were all handled
+    pub processed: usize,
+    /// Files moved to the error directory
+    pub failed: usize,
+    /// Messages handled successfully across all files
+    pub messages: usize,
+}
+
+/// Picks up HL7 files written to a directory, e.g. an SMB share used by a legacy sender
+///
+/// Each `*.hl7` or `*.txt` file may hold several messages. A file is claimed by
+/// renaming it before processing, so two engines polling the same share don't both
+/// handle it. Once every message has been handled the file is moved to the
+/// processed directory; if any message fails it goes to the error directory along
+/// with a `<name>.err` file describing the failures. The directory is polled rather than
+/// watched because change notifications are unreliable on network shares.
+///
+/// A file left claimed by an engine that stopped part way through is renamed
+/// back when `run` starts, once it has been claimed for longer than `stale_after`.
+#[derive(Debug, Clone)]
+pub struct FileDropSource {
+    directory: PathBuf,
+    processed_dir: PathBuf,
+    error_dir: PathBuf,
+    extensions: Vec<String>,
+    poll_interval: Duration,
+    min_age: Duration,
+    stale_after: Duration,
+    default_charset: Charset,
+}
+
+impl FileDropSource {
+    /// Watch `directory`, moving files to `processed/` and `error/` inside it
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        let directory = directory.into();
+        Self {
+            processed_dir: directory.join("processed"),
+            error_dir: directory.join("error"),
+            directory,
+            extensions: vec!["hl7".to_string(), "txt".to_string()],
+            poll_interval: Duration::from_secs(5),
+            min_age: Duration::from_secs(2),
+            stale_after: Duration::from_secs(600),
+            default_charset: Charset::default(),
+        }
+    }
+
+    /// Move handled files here instead
+    pub fn with_processed_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.processed_dir = dir.into();
+        self
+    }
+
+    /// Move failed files here instead
+    pub fn with_error_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.error_dir = dir.into();
+        self
+    }
+
+    /// Pick up files with these extensions instead of `hl7` and `txt`
+    pub fn with_extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.extensions = extensions.into_iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    /// Set how often the directory is scanned
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Only pick up files that haven't been modified for this long, so files
+    /// still being written are left alone
+    pub fn with_min_age(mut self, age: Duration) -> Self {
+        self.min_age = age;
+        self
+    }
+
+    /// Set how long a file can stay claimed before it's taken to be left behind
+    /// by an engine that stopped, 10 minutes by default; set it longer than the
+    /// largest file takes to process when several engines share the directory
+    pub fn with_stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// Set the charset assumed when a message doesn't declare one in MSH-18
+    pub fn with_default_charset(mut self, charset: Charset) -> Self {
+        self.default_charset = charset;
+        self
+    }
+
+    /// Recover files left claimed, then scan the directory at the poll interval
+    ///
+    /// Only creating the directory fails; errors scanning it, e.g. while a
+    /// network share is unreachable, are logged and the next scan tried.
+    pub async fn run(&self, handler: MessageHandler) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        info!("Watching {} for HL7 files", self.directory.display());
+        let source = self.clone();
+        match tokio::task::spawn_blocking(move || source.recover()).await.map_err(io::Error::other)? {
+            Ok(0) => {}
+            Ok(recovered) => warn!("Recovered {} files left claimed in {}", recovered, self.directory.display()),
+            Err(e) => error!("Failed to recover claimed files in {}: {}", self.directory.display(), e),
+        }
+
+        let mut ticker = tokio::time::interval(self.poll_interval);
+        loop {
+            ticker.tick().await;
+            let source = self.clone();
+            let handler = handler.clone();
+            match tokio::task::spawn_blocking(move || source.scan(&handler)).await.map_err(io::Error::other)? {
+                Ok(_) => {}
+                Err(e) => error!("Failed to scan {}: {}", self.directory.display(), e),
+            }
+        }
+    }
+
+    /// Rename files claimed for longer than `stale_after` back, so the next scan picks them up
+    pub fn recover(&self) -> io::Result<usize> {
+        let mut recovered = 0;
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            let Some(original) = path.to_str().and_then(|p| p.strip_suffix(".processing")).map(PathBuf::from) else {
+                continue;
+            };
+            let claimed_for = SystemTime::now().duration_since(entry.metadata()?.modified()?).unwrap_or_default();
+            if !entry.file_type()?.is_file() || claimed_for < self.stale_after || !self.wanted(&original) {
+                continue;
+            }
+            // Renaming fails if another engine recovered it first
+            if fs::rename(&path, &original).is_ok() {
+                warn!("Recovered {}, claimed {}s ago", original.display(), claimed_for.as_secs());
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Process every file currently ready in the directory
+    pub fn scan(&self, handler: &MessageHandler) -> io::Result<ScanReport> {
+        let mut report = ScanReport::default();
+
+        let mut ready = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_file() && self.wanted(&path) && self.settled(&entry)? {
+                ready.push(path);
+            }
+        }
+        // Oldest names first, which matches most senders' sequence numbering
+        ready.sort();
+
+        for path in ready {
+            // Claim the file; if another engine got there first, skip it
+            let file_name = path.file_name().unwrap_or_default();
+            let claimed = path.with_file_name(format!("{}.processing", file_name.to_string_lossy()));
+            if fs::rename(&path, &claimed).is_err() {
+                continue;
+            }
+            // Renaming keeps the modified time, so stamp when the file was claimed for `recover`
+            if let Err(e) = fs::File::options().write(true).open(&claimed).and_then(|f| f.set_modified(SystemTime::now())) {
+                warn!("Failed to stamp the claim on {}: {}", claimed.display(), e);
+            }
+
+            let errors = self.process_file(&claimed, handler, &mut report);
+            if errors.is_empty() {
+                move_into(&claimed, &self.processed_dir, file_name)?;
+                report.processed += 1;
+            } else {
+                let moved = move_into(&claimed, &self.error_dir, file_name)?;
+                let report_path = moved.with_file_name(format!("{}.err", moved.file_name().unwrap_or_default().to_string_lossy()));
+                fs::write(report_path, errors.join("\n") + "\n")?;
+                warn!("Moved {} to {}: {}", path.display(), self.error_dir.display(), errors.join("; "));
+                report.failed += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Whether the file has one of the extensions being watched
+    fn wanted(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| self.extensions.iter().any(|wanted| wanted.eq_ignore_ascii_case(e)))
+    }
+
+    /// Whether the file has gone unmodified for the minimum age
+    fn settled(&self, entry: &fs::DirEntry) -> io::Result<bool> {
+        let modified = entry.metadata()?.modified()?;
+        Ok(SystemTime::now().duration_since(modified).unwrap_or_default() >= self.min_age)
+    }
+
+    /// Run each message in a file through the handler, returning any errors
+    fn process_file(&self, path: &Path, handler: &MessageHandler, report: &mut ScanReport) -> Vec<String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => return vec![format!("Failed to read file: {}", e)],
+        };
+        let charset = charset::detect(&bytes).unwrap_or(self.default_charset);
+        let text = match charset.decode(&bytes) {
+            Ok(text) => text,
+            Err(e) => return vec![e.to_string()],
+        };
+
+        let messages = split_messages(&text);
+        if messages.is_empty() {
+            return vec!["File contains no MSH segments".to_string()];
+        }
+
+        let mut errors = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            match Message::parse(message).and_then(|m| handler(m)) {
+                Ok(_) => report.messages += 1,
+                Err(e) => {
+                    error!("Error processing message {} in {}: {}", index + 1, path.display(), e);
+                    errors.push(format!("Message {}: {}", index + 1, e));
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// Split file contents into messages, one per MSH segment
+///
+/// Batch and file header/trailer segments (FHS, BHS, BTS, FTS) and MLLP framing
+/// characters some senders leave in the file are dropped.
+pub fn split_messages(text: &str) -> Vec<String> {
+    let mut messages: Vec<Vec<&str>> = Vec::new();
+
+    for line in text.split(['\r', '\n']) {
+        let line = line.trim_matches(|c| c == '\u{0b}' || c == '\u{1c}');
+        if line.is_empty() || ["FHS", "BHS", "BTS", "FTS"].iter().any(|s| line.starts_with(s)) {
+            continue;
+        }
+        if line.starts_with("MSH") {
+            messages.push(Vec::new());
+        }
+        // Segments before the first MSH don't belong to a message
+        if let Some(current) = messages.last_mut() {
+            current.push(line);
+        }
+    }
+
+    messages.into_iter().map(|segments| segments.join("\r")).collect()
+}
+
+/// Atomically move a file into a directory, avoiding name collisions
+fn move_into(path: &Path, dir: &Path, file_name: &std::ffi::OsStr) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let mut target = dir.join(file_name);
+    if target.exists() {
+        let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S%3f");
+        target = dir.join(format!("{}_{}", stamp, file_name.to_string_lossy()));
+    }
+    fs::rename(path, &target)?;
+    Ok(target)
+}
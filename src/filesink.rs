@@ -0,0 +1,261 @@
+use crate::batch::BatchFile;
+use crate::Message;
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// When a batch file is closed and a new one started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rollover {
+    /// Close the batch once it holds this many messages
+    pub max_messages: usize,
+    /// Close the batch once it has been open this long
+    pub max_age: Duration,
+}
+
+/// A batch file being written
+#[derive(Debug)]
+struct OpenBatch {
+    id: u64,
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    opened: Instant,
+    count: usize,
+}
+
+/// Writes messages to files in a directory
+///
+/// File names come from a template with `{msgtype}`, `{event}`, `{controlid}`,
+/// `{sender}`, `{timestamp}` and `{seq}` placeholders, e.g.
+/// `{msgtype}_{controlid}_{timestamp}.hl7`. Each file is written under its own
+/// hidden temporary name, synced to disk and then moved into place, so a
+/// downstream poller never reads a partial file. An existing file is never
+/// replaced: if the name is taken, e.g. by a resent message with the same
+/// control ID, a numeric suffix is added before the extension.
+///
+/// With a `Rollover`, messages are appended to a batch file wrapped in FHS/BHS
+/// headers and BTS/FTS trailers instead, named from the first message in it.
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    directory: PathBuf,
+    template: String,
+    rollover: Option<Rollover>,
+    batch: Arc<Mutex<Option<OpenBatch>>>,
+    seq: Arc<AtomicU64>,
+}
+
+impl FileSink {
+    /// Write each message to `<directory>/<control ID>_<timestamp>.hl7`
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+            template: "{controlid}_{timestamp}.hl7".to_string(),
+            rollover: None,
+            batch: Arc::new(Mutex::new(None)),
+            seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Name files using this template
+    pub fn with_template<T: ToString>(mut self, template: T) -> Self {
+        self.template = template.to_string();
+        self
+    }
+
+    /// Append messages to batch files closed according to `rollover`
+    pub fn with_rollover(mut self, rollover: Rollover) -> Self {
+        self.rollover = Some(rollover);
+        self
+    }
+
+    /// Directory files are written to
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Write a message, returning the path of the file it was written to
+    ///
+    /// In batch mode the returned file only appears once the batch is closed, and
+    /// gets a suffix if another file has taken its name by then.
+    pub fn write(&self, message: &Message) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.directory)?;
+        let name = self.file_name(message, Utc::now());
+
+        let Some(rollover) = self.rollover else {
+            let path = self.directory.join(&name);
+            let temp_path = temp_path(&path);
+            let mut file = OpenOptions::new().create_new(true).write(true).open(&temp_path)?;
+            file.write_all(message.to_hl7().as_bytes())?;
+            file.sync_all()?;
+            return commit(&temp_path, &path);
+        };
+
+        let mut batch = self.batch.lock().unwrap();
+        if batch.is_none() {
+            let path = self.directory.join(&name);
+            let temp_path = temp_path(&path);
+            let mut file = OpenOptions::new().create_new(true).write(true).open(&temp_path)?;
+            let now = Utc::now().format("%Y%m%d%H%M%S");
+            write!(file, "FHS|^~\\&|||||{}\rBHS|^~\\&|||||{}\r", now, now)?;
+            let id = self.seq.fetch_add(1, Ordering::Relaxed);
+            self.close_when_expired(id, rollover.max_age);
+            *batch = Some(OpenBatch {
+                id,
+                file,
+                temp_path,
+                path,
+                opened: Instant::now(),
+                count: 0,
+            });
+        }
+
+        let open = batch.as_mut().unwrap();
+        open.file.write_all(message.to_hl7().as_bytes())?;
+        open.file.write_all(b"\r")?;
+        open.count += 1;
+        let path = open.path.clone();
+
+        if open.count >= rollover.max_messages || open.opened.elapsed() >= rollover.max_age {
+            close(batch.take().unwrap())?;
+        }
+        Ok(path)
+    }
+
+    /// Write a whole batch file at once, named from its first message
+    ///
+    /// Rollover settings don't apply; the file holds exactly what it's given.
+    pub fn write_batch(&self, file: &BatchFile) -> io::Result<PathBuf> {
+        let first = file
+            .messages()
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "batch file holds no messages"))?;
+        fs::create_dir_all(&self.directory)?;
+        let path = self.directory.join(self.file_name(first, Utc::now()));
+        let temp_path = temp_path(&path);
+        let mut output = OpenOptions::new().create_new(true).write(true).open(&temp_path)?;
+        output.write_all(file.to_hl7().as_bytes())?;
+        output.sync_all()?;
+        commit(&temp_path, &path)
+    }
+
+    /// Check that files can be created in the directory, creating it if needed
+    pub fn check_writable(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        // Hidden like the temporary files, so pollers skip it
+        let probe = self.directory.join(format!(".ready-{}", std::process::id()));
+        File::create(&probe)?;
+        fs::remove_file(probe)
+    }
+
+    /// Close the current batch file, if any, so it appears under its final name
+    pub fn flush(&self) -> io::Result<()> {
+        match self.batch.lock().unwrap().take() {
+            Some(open) => close(open),
+            None => Ok(()),
+        }
+    }
+
+    /// Close a batch once it reaches its maximum age, even if no more messages arrive
+    ///
+    /// Outside a Tokio runtime the age is only checked when the next message is written.
+    fn close_when_expired(&self, id: u64, max_age: Duration) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let batch = self.batch.clone();
+        let directory = self.directory.clone();
+        runtime.spawn(async move {
+            tokio::time::sleep(max_age).await;
+            let mut batch = batch.lock().unwrap();
+            if batch.as_ref().is_some_and(|open| open.id == id) {
+                if let Err(e) = close(batch.take().unwrap()) {
+                    error!("Failed to close batch file in {}: {}", directory.display(), e);
+                }
+            }
+        });
+    }
+
+    /// Render the file name template for a message
+    fn file_name(&self, message: &Message, now: DateTime<Utc>) -> String {
+        let value = |value: Option<&str>| value.filter(|v| !v.is_empty()).unwrap_or("UNKNOWN").to_string();
+        let name = self
+            .template
+            .replace("{msgtype}", &message.message_type.replace('^', "_"))
+            .replace("{event}", &value(message.message_type.split('^').nth(1)))
+            .replace("{controlid}", &value(message.control_id()))
+            .replace("{sender}", &value(message.sending_application()))
+            .replace("{timestamp}", &now.format("%Y%m%d%H%M%S%3f").to_string())
+            .replace("{seq}", &self.seq.fetch_add(1, Ordering::Relaxed).to_string());
+
+        // Values come from the message, so keep them from escaping the directory
+        name.chars()
+            .map(|c| if matches!(c, '/' | '\\' | ':' | '\0') { '_' } else { c })
+            .collect()
+    }
+}
+
+/// Hidden name a file is written under before being moved into place
+///
+/// Unique per write, so two messages with the same final name never share one.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}-{}.tmp", name, std::process::id(), n))
+}
+
+/// Write the trailers of a batch file and move it into place
+fn close(mut open: OpenBatch) -> io::Result<()> {
+    write!(open.file, "BTS|{}\rFTS|1\r", open.count)?;
+    open.file.sync_all()?;
+    let path = commit(&open.temp_path, &open.path)?;
+    info!("Closed batch file {} with {} messages", path.display(), open.count);
+    Ok(())
+}
+
+/// Move a synced temporary file to its final name, returning the name used
+///
+/// Linking fails rather than replacing an existing file, in which case the next
+/// suffixed name is tried.
+fn commit(temp_path: &Path, path: &Path) -> io::Result<PathBuf> {
+    let mut target = path.to_path_buf();
+    let mut n = 0;
+    loop {
+        match fs::hard_link(temp_path, &target) {
+            Ok(()) => {
+                fs::remove_file(temp_path)?;
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists || target.exists() => {
+                n += 1;
+                target = suffixed(path, n);
+            }
+            // Filesystems without hard links; only here can a racing writer still win
+            Err(_) => {
+                fs::rename(temp_path, &target)?;
+                break;
+            }
+        }
+    }
+    // Persist the move itself; directories can't be opened for syncing on Windows
+    #[cfg(unix)]
+    if let Some(dir) = target.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(target)
+}
+
+/// `name.hl7` as `name_<n>.hl7`
+fn suffixed(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{}_{}.{}", stem, n, extension.to_string_lossy())),
+        None => path.with_file_name(format!("{}_{}", stem, n)),
+    }
+}
@@ -0,0 +1,68 @@
+//! Entry points for fuzzing the parser with cargo-fuzz
+//!
+//! The targets in `fuzz/` feed arbitrary bytes to `parse`, and with the
+//! `arbitrary` feature, arbitrary `Message`s through serialization and back.
+//! Any panic they find is a bug: malformed input must come back as an error.
+
+use crate::{charset, terser, HL7Error, Message};
+
+/// Fields read from every message `parse` accepts, covering repetitions, components and subcomponents
+const PATHS: [&str; 8] = ["MSH-3.1", "MSH-9.2", "MSH-10", "PID-3", "PID-5.1.2", "OBX(2)-5", "RXE-2.2", "MRG-1"];
+
+/// Decode and parse bytes as a listener would, then exercise the parsed message
+///
+/// Never panics, whatever the input. Messages that parse are also serialized
+/// and parsed again, read with the terser and converted to the typed views and
+/// the canonical JSON, since those all index into whatever the parser produced.
+pub fn parse(data: &[u8]) -> Result<Message, HL7Error> {
+    let charset = charset::detect(data).unwrap_or_default();
+    let text = charset
+        .decode(data)
+        .unwrap_or_else(|_| String::from_utf8_lossy(data).into_owned());
+    let message = Message::parse(&text)?;
+
+    let _ = Message::parse(&message.to_hl7());
+    for path in PATHS {
+        terser::get(&message, path);
+    }
+    let _ = crate::adt::AdtMessage::from_hl7(&message);
+    let _ = crate::oru::OruMessage::from_hl7(&message);
+    let _ = crate::rde::RdeMessage::from_hl7(&message);
+    let _ = Message::from_json(&message.to_json());
+    Ok(message)
+}
+
+/// Arbitrary components for a `Field`, whichever type `Components` is
+#[cfg(feature = "arbitrary")]
+pub(crate) fn arbitrary_components(u: &mut arbitrary::Unstructured<'_>) -> arbitrary::Result<crate::Components> {
+    u.arbitrary_iter()?.collect()
+}
+
+/// Messages with arbitrary segments, whose type and version are read from the
+/// first segment as `Message::parse` would
+///
+/// Half of them start with an MSH segment carrying a known message type and
+/// version, so they get past the header checks and into the rest of the parser.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        use crate::{parse_field, Delimiters, Field, Segment};
+
+        let mut segments: Vec<Segment> = u.arbitrary()?;
+        if u.arbitrary()? {
+            let delimiters = Delimiters::default();
+            let mut fields = (0..12).map(|_| u.arbitrary()).collect::<arbitrary::Result<Vec<Field>>>()?;
+            fields[0] = parse_field("^~\\&", &delimiters);
+            fields[7] = parse_field(u.choose(&crate::generate::MESSAGE_TYPES)?, &delimiters);
+            fields[10] = parse_field(u.choose(&crate::testing::fixtures::VERSIONS)?, &delimiters);
+            segments.insert(0, Segment::new("MSH", fields));
+        }
+        let mut message = Message {
+            segments,
+            message_type: String::new(),
+            version: String::new(),
+        };
+        message.refresh_header();
+        Ok(message)
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{HL7Error, Message, MessageBuilder};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+/// Message types the generator can produce
+pub const MESSAGE_TYPES: [&str; 7] = ["ADT^A01", "ADT^A02", "ADT^A03", "ADT^A04", "ADT^A08", "ORU^R01", "RDE^O11"];
+
+pub(crate) const FAMILY_NAMES: [&str; 20] = [
+    "SMITH", "JOHNSON", "WILLIAMS", "BROWN", "JONES", "GARCIA", "MILLER", "DAVIS", "RODRIGUEZ", "MARTINEZ",
+    "HERNANDEZ", "LOPEZ", "GONZALEZ", "WILSON", "ANDERSON", "THOMAS", "TAYLOR", "MOORE", "JACKSON", "NGUYEN",
+];
+
+pub(crate) const MALE_NAMES: [&str; 10] = [
+    "JAMES", "ROBERT", "JOHN", "MICHAEL", "DAVID", "WILLIAM", "RICHARD", "JOSEPH", "CARLOS", "WEI",
+];
+
+pub(crate) const FEMALE_NAMES: [&str; 10] = [
+    "MARY", "PATRICIA", "JENNIFER", "LINDA", "ELIZABETH", "BARBARA", "SUSAN", "JESSICA", "MARIA", "MEI",
+];
+
+pub(crate) const STREETS: [&str; 8] = [
+    "MAIN ST", "OAK AVE", "PINE ST", "MAPLE DR", "CEDAR LN", "ELM ST", "WASHINGTON BLVD", "LAKE RD",
+];
+
+/// City, state and ZIP code prefix
+pub(crate) const CITIES: [(&str, &str, &str); 6] = [
+    ("SPRINGFIELD", "IL", "627"),
+    ("RIVERSIDE", "CA", "925"),
+    ("FRANKLIN", "TN", "370"),
+    ("GREENVILLE", "SC", "296"),
+    ("MADISON", "WI", "537"),
+    ("SALEM", "OR", "973"),
+];
+
+/// Attending doctors: ID, family name, given name
+const DOCTORS: [(&str, &str, &str); 5] = [
+    ("004777", "ATTEND", "AARON"),
+    ("013579", "CHEN", "LISA"),
+    ("024680", "OKAFOR", "EMEKA"),
+    ("031415", "PATEL", "PRIYA"),
+    ("042424", "KOWALSKI", "ANNA"),
+];
+
+/// Nursing units with how many rooms they have
+const UNITS: [(&str, u64); 4] = [("MED", 40), ("SUR", 30), ("ICU", 12), ("CAR", 24)];
+
+/// A lab test with its plausible range: code, name, units, reference low and high, decimals
+type LabTest = (&'static str, &'static str, &'static str, f64, f64, usize);
+
+/// Lab panels and their tests, using LOINC codes
+const PANELS: [(&str, &str, &[LabTest]); 3] = [
+    (
+        "58410-2",
+        "CBC panel",
+        &[
+            ("6690-2", "Leukocytes", "10*3/uL", 4.0, 11.0, 1),
+            ("789-8", "Erythrocytes", "10*6/uL", 4.2, 5.9, 2),
+            ("718-7", "Hemoglobin", "g/dL", 12.0, 17.5, 1),
+            ("4544-3", "Hematocrit", "%", 36.0, 53.0, 1),
+            ("777-3", "Platelets", "10*3/uL", 150.0, 450.0, 0),
+        ],
+    ),
+    (
+        "51990-0",
+        "Basic metabolic panel",
+        &[
+            ("2345-7", "Glucose", "mg/dL", 70.0, 99.0, 0),
+            ("2951-2", "Sodium", "mmol/L", 135.0, 145.0, 0),
+            ("2823-3", "Potassium", "mmol/L", 3.5, 5.1, 1),
+            ("2075-0", "Chloride", "mmol/L", 98.0, 107.0, 0),
+            ("3094-0", "Urea nitrogen", "mg/dL", 7.0, 20.0, 0),
+            ("2160-0", "Creatinine", "mg/dL", 0.6, 1.3, 2),
+        ],
+    ),
+    (
+        "24362-6",
+        "Renal function panel",
+        &[
+            ("2160-0", "Creatinine", "mg/dL", 0.6, 1.3, 2),
+            ("3094-0", "Urea nitrogen", "mg/dL", 7.0, 20.0, 0),
+            ("17861-6", "Calcium", "mg/dL", 8.6, 10.3, 1),
+        ],
+    ),
+];
+
+/// Medications: RxNorm code, name, dose, units, form, frequency, route
+const MEDICATIONS: [(&str, &str, &str, &str, &str, &str, &str); 5] = [
+    ("308191", "Amoxicillin 500 MG Oral Capsule", "500", "mg", "CAP", "TID", "PO"),
+    ("197361", "Amlodipine 5 MG Oral Tablet", "5", "mg", "TAB", "QD", "PO"),
+    ("860975", "Metformin 500 MG Oral Tablet", "500", "mg", "TAB", "BID", "PO"),
+    ("313782", "Acetaminophen 325 MG Oral Tablet", "650", "mg", "TAB", "Q6H", "PO"),
+    ("1659149", "Ceftriaxone 1000 MG Injection", "1000", "mg", "INJ", "QD", "IV"),
+];
+
+/// Produces realistic randomized messages for load testing and demos
+///
+/// Patients get made-up names, MRNs, addresses and birth dates; ORU results
+/// hold values within or near the normal range for each test, flagged when
+/// outside it. Timestamps fall in the hours before the start time. The same
+/// seed and start time always give the same messages, e.g.
+///
+/// ```
+/// use rust_hl7::generate::Generator;
+///
+/// let mut generator = Generator::new(42);
+/// let message = generator.generate("ORU^R01").unwrap();
+/// assert_eq!(message.message_type, "ORU^R01");
+/// assert!(message.get_segment("OBX").is_some());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Generator {
+    state: u64,
+    start: DateTime<Utc>,
+    sequence: u64,
+}
+
+impl Generator {
+    /// A generator with the given seed, with timestamps leading up to now
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            start: Utc::now(),
+            sequence: 0,
+        }
+    }
+
+    /// Generate timestamps leading up to this time instead of now
+    pub fn start(mut self, start: DateTime<Utc>) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Generate a message of one of the `MESSAGE_TYPES`
+    pub fn generate(&mut self, message_type: &str) -> Result<Message, HL7Error> {
+        self.sequence += 1;
+        // Messages are spread out over the hours before the start time
+        let now = self.start - Duration::minutes(self.below(24 * 60) as i64);
+        let builder = MessageBuilder::new(format!("{}^{}", message_type, structure(message_type)))
+            .sending_application("GENERATOR")
+            .sending_facility("HOSP")
+            .receiving_application("RECEIVER")
+            .receiving_facility("HOSP")
+            .control_id(format!("GEN{:08}", self.sequence))
+            .set("MSH-7", timestamp(now));
+
+        let builder = match message_type {
+            "ADT^A01" | "ADT^A02" | "ADT^A03" | "ADT^A04" | "ADT^A08" => self.adt(builder, message_type, now),
+            "ORU^R01" => self.oru(builder, now),
+            "RDE^O11" => self.rde(builder, now),
+            other => {
+                return Err(HL7Error::InvalidStructure(format!(
+                    "Can't generate {}; supported types are {}",
+                    other,
+                    MESSAGE_TYPES.join(", ")
+                )))
+            }
+        };
+        builder.build()
+    }
+
+    fn adt(&mut self, builder: MessageBuilder, message_type: &str, now: DateTime<Utc>) -> MessageBuilder {
+        let event = &message_type[4..];
+        let admitted = now - Duration::hours(if event == "A01" || event == "A04" { 0 } else { 1 + self.below(96) as i64 });
+        let (unit, rooms) = self.pick(&UNITS);
+        let room = format!("{}{:02}", unit, 1 + self.below(rooms));
+        let bed = ["A", "B"][self.below(2) as usize];
+        let (id, family, given) = self.pick(&DOCTORS);
+
+        let mut builder = self
+            .patient(builder.segment("EVN").set("EVN-1", event).set("EVN-2", timestamp(now)))
+            .segment("PV1")
+            .set("PV1-1", "1")
+            .set("PV1-2", if event == "A04" { "O" } else { "I" })
+            .set("PV1-3", format!("{}^{}^{}^HOSP", unit, room, bed))
+            .set("PV1-7", format!("{}^{}^{}", id, family, given))
+            .set("PV1-10", if unit == "SUR" { "SUR" } else { "MED" })
+            .set("PV1-19", format!("V{:09}", self.below(1_000_000_000)))
+            .set("PV1-44", timestamp(admitted));
+        if event == "A02" {
+            let (prior_unit, prior_rooms) = self.pick(&UNITS);
+            builder = builder.set("PV1-6", format!("{}^{}{:02}^A^HOSP", prior_unit, prior_unit, 1 + self.below(prior_rooms)));
+        }
+        if event == "A03" {
+            builder = builder.set("PV1-36", "01").set("PV1-45", timestamp(now));
+        }
+        builder
+    }
+
+    fn oru(&mut self, builder: MessageBuilder, now: DateTime<Utc>) -> MessageBuilder {
+        let (panel_code, panel_name, tests) = self.pick(&PANELS);
+        let collected = now - Duration::minutes(30 + self.below(180) as i64);
+        let (id, family, given) = self.pick(&DOCTORS);
+        let order = format!("ORD{:07}", self.below(10_000_000));
+        let filler = format!("LAB{:07}", self.below(10_000_000));
+
+        let mut builder = self
+            .patient(builder)
+            .segment("ORC")
+            .set("ORC-1", "RE")
+            .set("ORC-2", &order)
+            .set("ORC-3", &filler)
+            .set("ORC-12", format!("{}^{}^{}", id, family, given))
+            .segment("OBR")
+            .set("OBR-1", "1")
+            .set("OBR-2", &order)
+            .set("OBR-3", &filler)
+            .set("OBR-4", format!("{}^{}^LN", panel_code, panel_name))
+            .set("OBR-7", timestamp(collected))
+            .set("OBR-22", timestamp(now))
+            .set("OBR-25", "F");
+
+        for (index, &(code, name, units, low, high, decimals)) in tests.iter().enumerate() {
+            // Most results are normal; about one in six falls outside the range
+            let span = high - low;
+            let value = if self.below(6) == 0 {
+                if self.below(2) == 0 {
+                    low - span * self.fraction() * 0.3
+                } else {
+                    high + span * self.fraction() * 0.5
+                }
+            } else {
+                low + span * self.fraction()
+            }
+            .max(0.0);
+            let value = format!("{:.*}", decimals, value);
+            let rounded: f64 = value.parse().unwrap_or_default();
+            let flag = if rounded < low {
+                "L"
+            } else if rounded > high {
+                "H"
+            } else {
+                "N"
+            };
+
+            let obx = |field: usize| format!("OBX({})-{}", index + 1, field);
+            builder = builder
+                .segment("OBX")
+                .set(obx(1), (index + 1).to_string())
+                .set(obx(2), "NM")
+                .set(obx(3), format!("{}^{}^LN", code, name))
+                .set(obx(5), value)
+                .set(obx(6), units)
+                .set(obx(7), format!("{:.*}-{:.*}", decimals, low, decimals, high))
+                .set(obx(8), flag)
+                .set(obx(11), "F")
+                .set(obx(14), timestamp(collected));
+        }
+        builder
+    }
+
+    fn rde(&mut self, builder: MessageBuilder, now: DateTime<Utc>) -> MessageBuilder {
+        let (code, name, dose, units, form, frequency, route) = self.pick(&MEDICATIONS);
+        let (id, family, given) = self.pick(&DOCTORS);
+        let days = [5, 7, 10, 14, 30][self.below(5) as usize];
+        let per_day = match frequency {
+            "BID" => 2,
+            "TID" => 3,
+            "Q6H" => 4,
+            _ => 1,
+        };
+
+        self.patient(builder)
+            .segment("ORC")
+            .set("ORC-1", "NW")
+            .set("ORC-2", format!("RX{:07}", self.below(10_000_000)))
+            .set("ORC-9", timestamp(now))
+            .set("ORC-12", format!("{}^{}^{}", id, family, given))
+            .segment("RXE")
+            .set("RXE-1", format!("^{}^^{}^^R", frequency, timestamp(now)))
+            .set("RXE-2", format!("{}^{}^RXNORM", code, name))
+            .set("RXE-3", dose)
+            .set("RXE-5", units)
+            .set("RXE-6", form)
+            .set("RXE-10", (days * per_day).to_string())
+            .set("RXE-11", form)
+            .segment("RXR")
+            .set("RXR-1", route)
+    }
+
+    /// Add a PID segment for a new patient
+    fn patient(&mut self, builder: MessageBuilder) -> MessageBuilder {
+        let male = self.below(2) == 0;
+        let family = self.pick(&FAMILY_NAMES);
+        let given = if male { self.pick(&MALE_NAMES) } else { self.pick(&FEMALE_NAMES) };
+        let middle = (b'A' + self.below(26) as u8) as char;
+        let born = NaiveDate::from_ymd_opt(1930, 1, 1).unwrap_or_default() + Duration::days(self.below(90 * 365) as i64);
+        let (city, state, zip) = self.pick(&CITIES);
+
+        builder
+            .segment("PID")
+            .set("PID-1", "1")
+            .set("PID-3", format!("{:08}^^^HOSP^MR", self.below(100_000_000)))
+            .set("PID-5", format!("{}^{}^{}", family, given, middle))
+            .set("PID-7", born.format("%Y%m%d").to_string())
+            .set("PID-8", if male { "M" } else { "F" })
+            .set(
+                "PID-11",
+                format!(
+                    "{} {}^^{}^{}^{}{:02}",
+                    1 + self.below(9999),
+                    self.pick(&STREETS),
+                    city,
+                    state,
+                    zip,
+                    self.below(100)
+                ),
+            )
+            .set("PID-13", format!("^PRN^PH^^1^555^{:07}", self.below(10_000_000)))
+            .set("PID-18", format!("A{:09}", self.below(1_000_000_000)))
+    }
+
+    /// Next value from a SplitMix64 sequence
+    ///
+    /// A fixed algorithm keeps output for a seed stable across releases, which a
+    /// general-purpose random number crate doesn't promise.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to, but not including, `limit`
+    fn below(&mut self, limit: u64) -> u64 {
+        self.next() % limit
+    }
+
+    /// A number from 0 up to, but not including, 1
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
+
+/// The message structure for MSH-9.3
+fn structure(message_type: &str) -> &'static str {
+    match message_type {
+        "ADT^A01" | "ADT^A04" | "ADT^A08" => "ADT_A01",
+        "ADT^A02" => "ADT_A02",
+        "ADT^A03" => "ADT_A03",
+        "ORU^R01" => "ORU_R01",
+        "RDE^O11" => "RDE_O11",
+        _ => "",
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%d%H%M%S").to_string()
+}
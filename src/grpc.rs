@@ -0,0 +1,268 @@
+//! A gRPC service for parsing, validating and submitting messages
+//!
+//! `Hl7Service` serves `proto/hl7.proto` for internal services that hand
+//! messages to the engine without MLLP. Submitted messages carry PHI, so
+//! serve it over TLS with `with_tls` (which needs the `tls` feature) and
+//! require a bearer token with `with_token`; a service with neither logs a
+//! warning when it starts.
+//!
+//! ```ignore
+//! Hl7Service::new(handler)
+//!     .with_tls("server.pem", "server.key", None)?
+//!     .with_token(std::env::var("HL7_GRPC_TOKEN")?)
+//!     .serve("0.0.0.0:50051")
+//!     .await?;
+//! ```
+//!
+//! Clients then send `authorization: Bearer <token>` with every call.
+
+use crate::clock::SystemClock;
+use crate::mllp::{self, MessageHandler};
+use crate::validation::{Severity, Validator};
+use crate::Message;
+use std::net::SocketAddr;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+#[cfg(feature = "tls")]
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+/// Messages and the service and client generated from `proto/hl7.proto`
+pub mod proto {
+    tonic::include_proto!("hl7.v1");
+}
+
+use proto::hl7_service_server::Hl7ServiceServer;
+
+/// Errors that can occur serving gRPC
+#[derive(Debug, Error)]
+pub enum GrpcError {
+    #[error("Invalid gRPC address '{0}'")]
+    InvalidAddress(String),
+
+    #[error("gRPC server error: {0}")]
+    ServerError(#[from] tonic::transport::Error),
+
+    #[error("Certificate error: {0}")]
+    CertificateError(String),
+}
+
+/// Refuses calls without `authorization: Bearer <token>` when a token is set
+#[derive(Clone, Default)]
+pub struct Authenticate {
+    token: Option<Arc<str>>,
+}
+
+impl std::fmt::Debug for Authenticate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authenticate").field("token", &self.token.as_ref().map(|_| "<redacted>")).finish()
+    }
+}
+
+impl Interceptor for Authenticate {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if same(presented.as_bytes(), token.as_bytes()) => Ok(request),
+            _ => Err(Status::unauthenticated("A valid bearer token is required")),
+        }
+    }
+}
+
+/// Compare without returning early, so timing doesn't reveal how much of a token matched
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Parses, validates and accepts messages over gRPC (`proto/hl7.proto`)
+///
+/// `SubmitMessage` runs the handler, typically a router, and answers with the
+/// acknowledgment an MLLP listener would send: AA if the handler succeeds, AR
+/// if it rejects the message and AE for any other failure, including a
+/// message that doesn't parse. `ParseMessage` and `ValidateMessage` answer an
+/// unparseable message with `INVALID_ARGUMENT`. With a token set, calls
+/// without it fail with `UNAUTHENTICATED`.
+///
+/// ```no_run
+/// use rust_hl7::grpc::Hl7Service;
+/// use rust_hl7::mllp::MllpClient;
+/// use rust_hl7::router::{Destination, Predicate, Route, Router};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let router = Router::new().route(Route::new("all", Predicate::Always).to(Destination::Mllp(MllpClient::new("lab:2575"))));
+/// Hl7Service::new(router.into_handler()).serve("0.0.0.0:50051").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Hl7Service {
+    handler: MessageHandler,
+    authenticate: Authenticate,
+    #[cfg(feature = "tls")]
+    tls: Option<ServerTlsConfig>,
+}
+
+impl std::fmt::Debug for Hl7Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hl7Service").field("authenticate", &self.authenticate).finish_non_exhaustive()
+    }
+}
+
+impl Hl7Service {
+    /// A service submitting messages to `handler`
+    pub fn new(handler: MessageHandler) -> Self {
+        Self {
+            handler,
+            authenticate: Authenticate::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Require `authorization: Bearer <token>` on every call
+    pub fn with_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.authenticate = Authenticate { token: Some(token.into().into()) };
+        self
+    }
+
+    /// Serve over TLS, presenting the certificate chain in `cert_file` with the key in `key_file`
+    ///
+    /// Both are PEM files. With `client_ca_file`, clients must present a
+    /// certificate signed by one of the CAs in it (mutual TLS).
+    #[cfg(feature = "tls")]
+    pub fn with_tls<P: AsRef<Path>>(mut self, cert_file: P, key_file: P, client_ca_file: Option<P>) -> Result<Self, GrpcError> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| GrpcError::CertificateError(format!("{}: {}", path.display(), e)))
+        };
+        let identity = Identity::from_pem(read(cert_file.as_ref())?, read(key_file.as_ref())?);
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(path) = client_ca_file {
+            tls = tls.client_ca_root(Certificate::from_pem(read(path.as_ref())?));
+        }
+        self.tls = Some(tls);
+        Ok(self)
+    }
+
+    /// The service as a tonic service checking the token, to add to a server of your own
+    pub fn into_server(self) -> InterceptedService<Hl7ServiceServer<Self>, Authenticate> {
+        let authenticate = self.authenticate.clone();
+        Hl7ServiceServer::with_interceptor(self, authenticate)
+    }
+
+    /// Serve on `address` until the task is cancelled
+    pub async fn serve(self, address: &str) -> Result<(), GrpcError> {
+        let addr: SocketAddr = address.parse().map_err(|_| GrpcError::InvalidAddress(address.to_string()))?;
+        #[allow(unused_mut)]
+        let mut server = tonic::transport::Server::builder();
+        #[cfg(feature = "tls")]
+        let encrypted = self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        let encrypted = false;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.clone() {
+            server = server.tls_config(tls)?;
+        }
+        if !encrypted {
+            warn!("gRPC on {} is not encrypted; messages and tokens cross the network in the clear", addr);
+        }
+        if self.authenticate.token.is_none() {
+            warn!("gRPC on {} accepts calls from anyone; set a token to require one", addr);
+        }
+        info!("Serving gRPC on {}", addr);
+        server.add_service(self.into_server()).serve(addr).await?;
+        Ok(())
+    }
+}
+
+fn parse(text: &str) -> Result<Message, Status> {
+    Message::parse(text).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+#[tonic::async_trait]
+impl proto::hl7_service_server::Hl7Service for Hl7Service {
+    async fn parse_message(&self, request: Request<proto::ParseRequest>) -> Result<Response<proto::ParseResponse>, Status> {
+        let message = parse(&request.into_inner().message)?;
+        Ok(Response::new(proto::ParseResponse {
+            message_type: message.message_type.clone(),
+            version: message.version.clone(),
+            control_id: message.control_id().unwrap_or_default().to_string(),
+            segments: message.segments.iter().map(|segment| segment.name.clone()).collect(),
+            json: message.to_json(),
+        }))
+    }
+
+    async fn validate_message(
+        &self,
+        request: Request<proto::ValidateRequest>,
+    ) -> Result<Response<proto::ValidateResponse>, Status> {
+        let request = request.into_inner();
+        let message = parse(&request.message)?;
+        let validator = match request.version.as_str() {
+            "" => Validator::new(),
+            version => Validator::new().version(version),
+        };
+        let issues = validator.validate(&message);
+        Ok(Response::new(proto::ValidateResponse {
+            valid: !issues.iter().any(|issue| issue.is_error()),
+            issues: issues
+                .into_iter()
+                .map(|issue| proto::Issue {
+                    severity: match issue.severity {
+                        Severity::Warning => proto::Severity::Warning,
+                        Severity::Error => proto::Severity::Error,
+                    } as i32,
+                    location: issue.location,
+                    message: issue.message,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn submit_message(&self, request: Request<proto::SubmitRequest>) -> Result<Response<proto::SubmitResponse>, Status> {
+        let text = request.into_inner().message;
+        let outcome = match Message::parse(&text) {
+            Err(e) => Err(e),
+            Ok(message) => {
+                let control_id = mllp::control_id(&message);
+                let handler = self.handler.clone();
+                // Handlers block, as they do behind a listener
+                let result = tokio::task::spawn_blocking(move || handler(message))
+                    .await
+                    .map_err(|e| Status::internal(format!("Handler failed: {}", e)))?;
+                result.map(|_| control_id)
+            }
+        };
+
+        let response = match outcome {
+            Ok(control_id) => proto::SubmitResponse {
+                ack_code: "AA".to_string(),
+                ack: mllp::generate_response(&control_id, "Message processed successfully", &SystemClock, None)
+                    .map_err(|e| Status::internal(e.to_string()))?,
+                error: String::new(),
+            },
+            Err(e) => {
+                error!("Error processing message from gRPC: {}", e);
+                let ack_code = mllp::nack_code(&e);
+                proto::SubmitResponse {
+                    ack_code: ack_code.to_string(),
+                    ack: mllp::generate_nack(&text, ack_code, &e.to_string(), &SystemClock, None)
+                        .map_err(|e| Status::internal(e.to_string()))?,
+                    error: e.to_string(),
+                }
+            }
+        };
+        Ok(Response::new(response))
+    }
+}
@@ -0,0 +1,293 @@
+use crate::failover::Role;
+use crate::generate::Generator;
+use crate::mllp::{ListenerStatus, MllpClient, MllpServer};
+use crate::router::Destination;
+use crate::store::MessageStore;
+use crate::{terser, Message};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// Something that must be working for the server to take traffic
+#[derive(Clone)]
+pub enum Check {
+    /// An MLLP listener must be bound
+    Listener { address: String, status: ListenerStatus },
+    /// A destination must be reachable or writable
+    Destination { name: String, destination: Destination },
+    /// The message archive must accept writes
+    Store(Arc<dyn MessageStore>),
+    /// This instance must be the active one of a failover pair
+    Role(watch::Receiver<Role>),
+}
+
+impl Check {
+    /// Name the check is reported under, e.g. `listener 0.0.0.0:2575`
+    pub fn name(&self) -> String {
+        match self {
+            Check::Listener { address, .. } => format!("listener {}", address),
+            Check::Destination { name, .. } => format!("destination {}", name),
+            Check::Store(_) => "archive".to_string(),
+            Check::Role(_) => "role".to_string(),
+        }
+    }
+
+    /// What the check looks at, the same for checks of one destination under different names
+    fn target(&self) -> String {
+        match self {
+            Check::Destination { destination, .. } => format!("destination {:?}", destination),
+            check => check.name(),
+        }
+    }
+
+    async fn run(&self, timeout: Duration) -> Result<(), String> {
+        match self {
+            Check::Listener { status, .. } if status.is_bound() => Ok(()),
+            Check::Listener { .. } => Err("not bound".to_string()),
+            Check::Destination { destination, .. } => destination.check(timeout).await.map_err(|e| e.to_string()),
+            Check::Store(store) => store.check_writable().map_err(|e| e.to_string()),
+            Check::Role(role) if *role.borrow() == Role::Active => Ok(()),
+            Check::Role(_) => Err("standby".to_string()),
+        }
+    }
+}
+
+/// Outcome of one readiness check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The checks `/readyz` runs, shared with whatever starts and stops listeners
+///
+/// Clones share the same list, so a supervisor can replace the checks after a
+/// reload while the health server keeps running.
+#[derive(Clone)]
+pub struct Readiness {
+    checks: Arc<RwLock<Vec<Check>>>,
+    timeout: Duration,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self {
+            checks: Arc::default(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long each destination check may take
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Replace the checks
+    pub fn set(&self, checks: Vec<Check>) {
+        *self.checks.write().unwrap() = checks;
+    }
+
+    /// Add a check
+    pub fn add(&self, check: Check) {
+        self.checks.write().unwrap().push(check);
+    }
+
+    /// Run every check, all at once
+    ///
+    /// A destination listed under more than one name is checked once, and
+    /// each name gets the result.
+    pub async fn check(&self) -> Vec<CheckResult> {
+        let checks = self.checks.read().unwrap().clone();
+        let mut unique: HashMap<String, &Check> = HashMap::new();
+        for check in &checks {
+            unique.entry(check.target()).or_insert(check);
+        }
+        let results: HashMap<String, Result<(), String>> = futures::future::join_all(
+            unique.into_iter().map(|(target, check)| async move { (target, check.run(self.timeout).await) }),
+        )
+        .await
+        .into_iter()
+        .collect();
+        checks
+            .iter()
+            .map(|check| {
+                let result = &results[&check.target()];
+                CheckResult {
+                    name: check.name(),
+                    ok: result.is_ok(),
+                    error: result.clone().err(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Send a synthetic message through a loopback MLLP listener and check it is acknowledged
+///
+/// Exercises the runtime, the MLLP framing, parsing and ACK generation without
+/// touching routes, so nothing reaches downstream systems. Returns the round
+/// trip time.
+pub async fn self_test(timeout: Duration) -> Result<Duration, String> {
+    let started = Instant::now();
+    let listener = TcpListener::bind("127.0.0.1:0").await.map_err(|e| e.to_string())?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    let server = MllpServer::new(address, Arc::new(Ok));
+    let task = tokio::spawn(async move { server.serve(listener).await });
+
+    let message = Generator::new(started.elapsed().as_nanos() as u64)
+        .generate("ADT^A08")
+        .map_err(|e| e.to_string())?;
+    let control_id = message.control_id().unwrap_or_default().to_string();
+    let response = MllpClient::new(address).with_timeout(timeout).send(&message.to_hl7()).await;
+    task.abort();
+
+    let response = Message::parse(&response.map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    let code = terser::get(&response, "MSA-1").unwrap_or_default();
+    let acknowledged = terser::get(&response, "MSA-2").unwrap_or_default();
+    if code != "AA" || acknowledged != control_id {
+        return Err(format!("Expected AA for {}, got {} for {}", control_id, code, acknowledged));
+    }
+    Ok(started.elapsed())
+}
+
+/// A small HTTP server answering Kubernetes-style health probes
+///
+/// - `GET /healthz` answers 200 while the process is alive. With
+///   `with_self_test`, it first loops a synthetic message through a loopback
+///   listener (see `self_test`) and answers 503 if that fails.
+/// - `GET /readyz` runs the readiness checks and answers 200 if all pass, or
+///   503 with the failures.
+///
+/// Both answer with a small JSON body. Requests are handled one line at a time
+/// with no keep-alive, which is all probes need.
+///
+/// ```ignore
+/// let readiness = Readiness::new();
+/// readiness.add(Check::Listener { address: "0.0.0.0:2575".into(), status: server.status() });
+/// tokio::spawn(HealthServer::new("0.0.0.0:8080", readiness).with_self_test(true).run());
+/// ```
+#[derive(Clone)]
+pub struct HealthServer {
+    address: String,
+    readiness: Readiness,
+    self_test: bool,
+}
+
+impl HealthServer {
+    /// Serve probes on `address`, checking readiness with `readiness`
+    pub fn new<A: ToString>(address: A, readiness: Readiness) -> Self {
+        Self {
+            address: address.to_string(),
+            readiness,
+            self_test: false,
+        }
+    }
+
+    /// Run the loopback self-test on every `/healthz` request
+    pub fn with_self_test(mut self, self_test: bool) -> Self {
+        self.self_test = self_test;
+        self
+    }
+
+    /// Bind and serve probes until an error occurs binding the listener
+    pub async fn run(self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(&self.address).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve probes on an already bound listener
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        info!("Health endpoints listening on {}", listener.local_addr()?);
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept health probe: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.respond(socket).await {
+                    debug!("Error answering health probe from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut socket: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let read = tokio::time::timeout(Duration::from_secs(5), socket.read(&mut chunk))
+                .await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request not received"))??;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&chunk[..read]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut words = request.lines().next().unwrap_or_default().split_whitespace();
+        let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+        // Probes may add a query string
+        let path = path.split('?').next().unwrap_or_default();
+
+        let (status, body) = match (method, path) {
+            ("GET", "/healthz") => self.liveness().await,
+            ("GET", "/readyz") => {
+                let checks = self.readiness.check().await;
+                let ready = checks.iter().all(|c| c.ok);
+                let body = serde_json::json!({ "status": if ready { "ready" } else { "not ready" }, "checks": checks });
+                (if ready { 200 } else { 503 }, body)
+            }
+            (_, "/healthz" | "/readyz") => (405, serde_json::json!({ "error": "method not allowed" })),
+            _ => (404, serde_json::json!({ "error": "not found" })),
+        };
+
+        let reason = match status {
+            200 => "OK",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Service Unavailable",
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await
+    }
+
+    async fn liveness(&self) -> (u16, serde_json::Value) {
+        if !self.self_test {
+            return (200, serde_json::json!({ "status": "ok" }));
+        }
+        match self_test(Duration::from_secs(5)).await {
+            Ok(elapsed) => (200, serde_json::json!({ "status": "ok", "self_test_ms": elapsed.as_millis() as u64 })),
+            Err(e) => {
+                warn!("Self-test failed: {}", e);
+                (503, serde_json::json!({ "status": "failing", "error": e }))
+            }
+        }
+    }
+}
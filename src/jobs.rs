@@ -0,0 +1,652 @@
+use crate::diff::{DiffOptions, Difference};
+#[cfg(feature = "fhir")]
+use crate::fhir::{self, BundleOptions, BundleType};
+use crate::replay::{ReplayFailure, ReplayTarget};
+use crate::store::{ArchiveRecord, Direction, MessageStore, Query, StoreError};
+use crate::transform::Transform;
+use crate::{charset, HL7Error, Message};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "fhir")]
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "fhir")]
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Errors that stop a job; problems with single messages are reported instead
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Store error: {0}")]
+    StoreError(#[from] StoreError),
+
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Invalid checkpoint: {0}")]
+    InvalidCheckpoint(String),
+
+    /// Sending failed in a way a later run can get past, e.g. the server was down
+    #[error("Delivery failed: {0}")]
+    DeliveryFailed(String),
+}
+
+/// How far a job has got, saved after each page of messages
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Checkpoint {
+    /// When the last message processed was archived
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Archive ID of the last message processed
+    pub id: Option<i64>,
+    /// Messages processed that were archived at `timestamp`, which the next page skips
+    pub at_timestamp: usize,
+    pub processed: usize,
+    pub changed: usize,
+    pub sent: usize,
+    pub failed: usize,
+    /// Archive IDs of the messages that failed, which later runs don't try again
+    pub failed_ids: Vec<i64>,
+    /// Whether every message was processed
+    pub finished: bool,
+}
+
+impl Checkpoint {
+    /// Read a checkpoint, or start from the beginning if there isn't one yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, JobError> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| JobError::InvalidCheckpoint(e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the checkpoint under a temporary name and rename it into place, so
+    /// a crash never leaves half a checkpoint behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), JobError> {
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        let json = serde_json::to_vec_pretty(self).map_err(|e| JobError::InvalidCheckpoint(e.to_string()))?;
+        fs::write(&temp, json)?;
+        fs::File::open(&temp)?.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Whether a record was processed before the checkpoint was saved
+    fn covers(&self, record: &ArchiveRecord) -> bool {
+        self.timestamp.is_some_and(|timestamp| {
+            record.timestamp < timestamp || (record.timestamp == timestamp && record.id <= self.id)
+        })
+    }
+
+    /// Move past a processed record
+    fn advance(&mut self, record: &ArchiveRecord) {
+        if self.timestamp == Some(record.timestamp) {
+            self.at_timestamp += 1;
+        } else {
+            self.timestamp = Some(record.timestamp);
+            self.at_timestamp = 1;
+        }
+        self.id = record.id;
+        self.processed += 1;
+    }
+
+    /// Count a record as failed, keeping its archive ID
+    fn fail(&mut self, record: &ArchiveRecord) {
+        self.failed += 1;
+        self.failed_ids.extend(record.id);
+    }
+}
+
+/// A message the job's transform changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobChange {
+    /// Archive ID of the record
+    pub id: Option<i64>,
+    pub control_id: Option<String>,
+    /// How the transformed message differs from the archived one
+    pub differences: Vec<Difference>,
+}
+
+/// Outcome of a job
+///
+/// The counts include messages processed before the job was resumed;
+/// `failures` and `changes` only cover this run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobReport {
+    pub processed: usize,
+    /// Messages the transform changed
+    pub changed: usize,
+    /// Changed messages accepted by the target
+    pub sent: usize,
+    pub failed: usize,
+    pub failures: Vec<ReplayFailure>,
+    /// What would change, in dry runs
+    pub changes: Vec<JobChange>,
+}
+
+/// What became of one message
+enum Outcome {
+    Unchanged,
+    Changed(JobChange),
+    Sent,
+    Failed(ReplayFailure),
+    /// The target couldn't take it, e.g. because it's down
+    Undelivered(String),
+}
+
+/// Runs a transform over archived messages, sending the ones it changes to a target
+///
+/// Messages are read a page at a time, oldest first, and up to `concurrency`
+/// of them are transformed and sent at once. With a checkpoint file, progress
+/// is saved after each page, and running the job again after a crash or a
+/// restart carries on from there rather than starting over. A finished job
+/// does nothing when run again; use a new checkpoint file for each job.
+/// Messages that can't be parsed or transformed are reported and skipped. If
+/// the target doesn't take a message, the job stops with
+/// `JobError::DeliveryFailed` and the checkpoint is saved just before that
+/// message, so a rerun starts with it. With `concurrency` above one, messages
+/// after it that were already sent are sent again.
+///
+/// A dry run sends nothing and ignores the checkpoint file. It reports each
+/// message that would change, and how.
+///
+/// Only inbound messages are processed unless the query asks for a direction.
+///
+/// ```ignore
+/// // Re-map a local code system across last year's results
+/// let remap = Pipeline::load("remap-codes.yaml")?;
+/// let report = Job::new(Query::new().message_type("ORU").since(start).until(end), Arc::new(remap))
+///     .target(ReplayTarget::Destination(warehouse))
+///     .checkpoint("remap-2023.json")
+///     .concurrency(8)
+///     .run(store.as_ref())
+///     .await?;
+/// ```
+pub struct Job {
+    query: Query,
+    transform: Arc<dyn Transform>,
+    target: Option<ReplayTarget>,
+    checkpoint: Option<PathBuf>,
+    concurrency: usize,
+    page_size: usize,
+    dry_run: bool,
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Job")
+            .field("query", &self.query)
+            .field("target", &self.target)
+            .field("checkpoint", &self.checkpoint)
+            .field("concurrency", &self.concurrency)
+            .field("page_size", &self.page_size)
+            .field("dry_run", &self.dry_run)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Job {
+    /// Apply `transform` to the messages matching `query`
+    pub fn new(query: Query, transform: Arc<dyn Transform>) -> Self {
+        let query = match query.direction {
+            Some(_) => query,
+            None => query.direction(Direction::Inbound),
+        };
+        Self {
+            query,
+            transform,
+            target: None,
+            checkpoint: None,
+            concurrency: 1,
+            page_size: 500,
+            dry_run: false,
+        }
+    }
+
+    /// Send changed messages here; without a target they're only counted
+    pub fn target(mut self, target: ReplayTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Save progress to this file after each page, and resume from it
+    pub fn checkpoint<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Process up to this many messages at once, one by default
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Read this many messages from the archive at a time, 500 by default
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Report what would change without sending anything
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Run the job to the end, or from where its checkpoint left off
+    pub async fn run(&self, store: &dyn MessageStore) -> Result<JobReport, JobError> {
+        let checkpoint_file = self.checkpoint.as_deref().filter(|_| !self.dry_run);
+        let mut checkpoint = match checkpoint_file {
+            Some(path) => Checkpoint::load(path)?,
+            None => Checkpoint::default(),
+        };
+        let mut report = JobReport::default();
+        if checkpoint.finished {
+            info!("Job already finished: {} messages processed", checkpoint.processed);
+        } else if checkpoint.processed > 0 {
+            info!("Resuming job after {} messages", checkpoint.processed);
+        }
+
+        while !checkpoint.finished {
+            let (records, last_page) = next_page(&self.query, self.page_size, &checkpoint, store)?;
+            let mut outcomes = futures::stream::iter(records.iter().map(|record| self.process(record))).buffered(self.concurrency);
+            for record in &records {
+                let Some(outcome) = outcomes.next().await else {
+                    break;
+                };
+                match outcome {
+                    Outcome::Unchanged => {}
+                    Outcome::Changed(change) => {
+                        checkpoint.changed += 1;
+                        if self.dry_run {
+                            report.changes.push(change);
+                        }
+                    }
+                    Outcome::Sent => {
+                        checkpoint.changed += 1;
+                        checkpoint.sent += 1;
+                    }
+                    Outcome::Failed(failure) => {
+                        checkpoint.fail(record);
+                        report.failures.push(failure);
+                    }
+                    // Sending it again later could work, so stop before the message
+                    Outcome::Undelivered(error) => {
+                        if let Some(path) = checkpoint_file {
+                            checkpoint.save(path)?;
+                        }
+                        return Err(JobError::DeliveryFailed(format!(
+                            "Job stopped after {} messages at archived message {:?}: {}",
+                            checkpoint.processed, record.id, error
+                        )));
+                    }
+                }
+                checkpoint.advance(record);
+            }
+
+            checkpoint.finished = last_page || records.is_empty();
+            if let Some(path) = checkpoint_file {
+                checkpoint.save(path)?;
+            }
+            if !checkpoint.finished {
+                info!("Job has processed {} messages", checkpoint.processed);
+            }
+        }
+
+        info!(
+            "Job finished: {} messages processed, {} changed, {} sent, {} failed",
+            checkpoint.processed, checkpoint.changed, checkpoint.sent, checkpoint.failed
+        );
+        report.processed = checkpoint.processed;
+        report.changed = checkpoint.changed;
+        report.sent = checkpoint.sent;
+        report.failed = checkpoint.failed;
+        Ok(report)
+    }
+
+    /// Transform one archived message and send it on if it changed
+    async fn process(&self, record: &ArchiveRecord) -> Outcome {
+        let failure = |error: String| {
+            warn!("Job failed on archived message {:?}: {}", record.id, error);
+            Outcome::Failed(ReplayFailure {
+                id: record.id,
+                control_id: record.control_id.clone(),
+                error,
+            })
+        };
+        let original = match parse(record) {
+            Ok(message) => message,
+            Err(e) => return failure(e.to_string()),
+        };
+        let mut message = original.clone();
+        if let Err(e) = self.transform.apply(&mut message) {
+            return failure(e.to_string());
+        }
+
+        let control_id = message.control_id().filter(|id| !id.is_empty()).map(str::to_string);
+        let differences = DiffOptions::exact().compare(&original, &message);
+        if differences.is_empty() {
+            return Outcome::Unchanged;
+        }
+        let change = JobChange {
+            id: record.id,
+            control_id,
+            differences,
+        };
+        let Some(target) = self.target.as_ref().filter(|_| !self.dry_run) else {
+            return Outcome::Changed(change);
+        };
+        let result: Result<(), HL7Error> = match target {
+            ReplayTarget::Router(router) => router.send(message).await.map(|_| ()),
+            ReplayTarget::Destination(destination) => destination.send(&message).await,
+        };
+        match result {
+            Ok(()) => Outcome::Sent,
+            Err(e) => Outcome::Undelivered(e.to_string()),
+        }
+    }
+}
+
+/// The next page of records after the checkpoint, and whether it's the last
+fn next_page(
+    query: &Query,
+    page_size: usize,
+    checkpoint: &Checkpoint,
+    store: &dyn MessageStore,
+) -> Result<(Vec<ArchiveRecord>, bool), JobError> {
+    let remaining = query.limit.map_or(usize::MAX, |limit| limit.saturating_sub(checkpoint.processed));
+    let mut page = query.clone();
+    if let Some(timestamp) = checkpoint.timestamp {
+        page.since = Some(timestamp);
+    }
+    // Messages already processed at the checkpoint's timestamp come back first
+    page.limit = Some(page_size.min(remaining).saturating_add(checkpoint.at_timestamp));
+    let records = if remaining == 0 { Vec::new() } else { store.query(&page)? };
+    let last_page = records.len() < page.limit.unwrap_or_default();
+    let records = records.into_iter().filter(|r| !checkpoint.covers(r)).collect();
+    Ok((records, last_page))
+}
+
+/// Decode and parse an archived message
+fn parse(record: &ArchiveRecord) -> Result<Message, HL7Error> {
+    charset::detect(&record.raw)
+        .unwrap_or_default()
+        .decode(&record.raw)
+        .and_then(|text| Message::parse(&text))
+}
+
+/// Outcome of a FHIR backfill
+///
+/// The counts include messages processed before the backfill was resumed;
+/// `bundles` and `failures` only cover this run.
+#[cfg(feature = "fhir")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackfillReport {
+    pub processed: usize,
+    /// Messages in bundles the FHIR server accepted
+    pub sent: usize,
+    pub failed: usize,
+    /// Bundles the FHIR server accepted
+    pub bundles: usize,
+    pub failures: Vec<ReplayFailure>,
+}
+
+/// Posts archived messages to a FHIR server as transaction bundles
+///
+/// Each message is converted with `fhir::bundle`, and the entries of up to
+/// `bundle_size` messages, oldest first, are posted to the server's base URL
+/// as one transaction. A resource that several of them carry, such as their
+/// patient, is sent once, as the latest message has it. Resource IDs come from
+/// the messages, so posting a range again updates the same resources rather
+/// than creating more.
+///
+/// Failed requests are retried, waiting `retry_delay`, then twice that, and so
+/// on; 4xx responses other than 408 and 429 aren't. Every message in a bundle
+/// the server refuses that way is reported as failed, as is each message that
+/// can't be converted, and the backfill carries on. A bundle that still fails
+/// after its tries stops the backfill with `JobError::DeliveryFailed`, without
+/// counting it as processed. With a checkpoint file, progress is saved after
+/// each page and before such a bundle, and a rerun resumes from there, as for
+/// a `Job`; the IDs of failed messages are kept in it too.
+///
+/// Only inbound messages are posted unless the query asks for a direction.
+///
+/// ```ignore
+/// // Load last year's ADT and results into the new EHR
+/// let report = FhirBackfill::new(Query::new().since(start).until(end), "https://ehr.example.org/fhir/r4")
+///     .header("Authorization", format!("Bearer {}", token))
+///     .bundle_size(50)
+///     .checkpoint("backfill-2023.json")
+///     .run(store.as_ref())
+///     .await?;
+/// ```
+#[cfg(feature = "fhir")]
+#[derive(Clone)]
+pub struct FhirBackfill {
+    query: Query,
+    base_url: String,
+    headers: Vec<(String, String)>,
+    checkpoint: Option<PathBuf>,
+    bundle_size: usize,
+    page_size: usize,
+    max_attempts: u32,
+    retry_delay: Duration,
+    timeout: Duration,
+}
+
+#[cfg(feature = "fhir")]
+impl std::fmt::Debug for FhirBackfill {
+    // Headers stay out of logs, since they usually hold credentials
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FhirBackfill")
+            .field("query", &self.query)
+            .field("base_url", &self.base_url)
+            .field("checkpoint", &self.checkpoint)
+            .field("bundle_size", &self.bundle_size)
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "fhir")]
+impl FhirBackfill {
+    /// Post the messages matching `query` to the FHIR server at `base_url`
+    pub fn new<U: ToString>(query: Query, base_url: U) -> Self {
+        let query = match query.direction {
+            Some(_) => query,
+            None => query.direction(Direction::Inbound),
+        };
+        Self {
+            query,
+            base_url: base_url.to_string(),
+            headers: Vec::new(),
+            checkpoint: None,
+            bundle_size: 100,
+            page_size: 500,
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Send this header with every request, e.g. `Authorization`
+    pub fn header<N: ToString, V: ToString>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Save progress to this file after each page, and resume from it
+    pub fn checkpoint<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Put up to this many messages in each bundle, 100 by default
+    pub fn bundle_size(mut self, bundle_size: usize) -> Self {
+        self.bundle_size = bundle_size.max(1);
+        self
+    }
+
+    /// Read this many messages from the archive at a time, 500 by default
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Try each bundle this many times, waiting `retry_delay`, then twice that, and so on between tries
+    pub fn with_retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Give up on a request that hasn't been answered after this long (60 seconds unless set)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run the backfill to the end, or from where its checkpoint left off
+    pub async fn run(&self, store: &dyn MessageStore) -> Result<BackfillReport, JobError> {
+        let mut checkpoint = match &self.checkpoint {
+            Some(path) => Checkpoint::load(path)?,
+            None => Checkpoint::default(),
+        };
+        let mut report = BackfillReport::default();
+        if checkpoint.finished {
+            info!("Backfill already finished: {} messages processed", checkpoint.processed);
+        } else if checkpoint.processed > 0 {
+            info!("Resuming backfill after {} messages", checkpoint.processed);
+        }
+
+        while !checkpoint.finished {
+            let (records, last_page) = next_page(&self.query, self.page_size, &checkpoint, store)?;
+            for chunk in records.chunks(self.bundle_size) {
+                // Where to resume if the bundle can't be sent now
+                let before = checkpoint.clone();
+                let mut entries: Vec<Value> = Vec::new();
+                let mut included = Vec::new();
+                for record in chunk {
+                    checkpoint.advance(record);
+                    match self.entries(record) {
+                        Ok(converted) => {
+                            for entry in converted {
+                                match entries.iter_mut().find(|e| e["fullUrl"] == entry["fullUrl"]) {
+                                    Some(earlier) => *earlier = entry,
+                                    None => entries.push(entry),
+                                }
+                            }
+                            included.push(record);
+                        }
+                        Err(e) => {
+                            warn!("Backfill failed to convert archived message {:?}: {}", record.id, e);
+                            checkpoint.fail(record);
+                            report.failures.push(ReplayFailure {
+                                id: record.id,
+                                control_id: record.control_id.clone(),
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                if included.is_empty() {
+                    continue;
+                }
+
+                let bundle = json!({ "resourceType": "Bundle", "type": "transaction", "entry": entries });
+                match self.post(&bundle.to_string()).await {
+                    Ok(()) => {
+                        checkpoint.sent += included.len();
+                        report.bundles += 1;
+                    }
+                    // Sending it again later could work, so stop before the bundle
+                    Err((error, false)) => {
+                        if let Some(path) = &self.checkpoint {
+                            before.save(path)?;
+                        }
+                        return Err(JobError::DeliveryFailed(format!(
+                            "Backfill stopped after {} messages at a bundle of {}: {}",
+                            before.processed,
+                            included.len(),
+                            error
+                        )));
+                    }
+                    Err((error, true)) => {
+                        warn!("Backfill bundle of {} messages was refused: {}", included.len(), error);
+                        for record in &included {
+                            checkpoint.fail(record);
+                        }
+                        report.failures.extend(included.iter().map(|record| ReplayFailure {
+                            id: record.id,
+                            control_id: record.control_id.clone(),
+                            error: error.clone(),
+                        }));
+                    }
+                }
+            }
+
+            checkpoint.finished = last_page || records.is_empty();
+            if let Some(path) = &self.checkpoint {
+                checkpoint.save(path)?;
+            }
+            if !checkpoint.finished {
+                info!("Backfill has processed {} messages", checkpoint.processed);
+            }
+        }
+
+        info!(
+            "Backfill finished: {} messages processed, {} sent, {} failed",
+            checkpoint.processed, checkpoint.sent, checkpoint.failed
+        );
+        report.processed = checkpoint.processed;
+        report.sent = checkpoint.sent;
+        report.failed = checkpoint.failed;
+        Ok(report)
+    }
+
+    /// The transaction entries for one archived message
+    fn entries(&self, record: &ArchiveRecord) -> Result<Vec<Value>, HL7Error> {
+        let options = BundleOptions {
+            bundle_type: BundleType::Transaction,
+            base_url: self.base_url.clone(),
+        };
+        match fhir::bundle(&parse(record)?, &options)?["entry"].take() {
+            Value::Array(entries) => Ok(entries),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Post a bundle, retrying until it's accepted or the tries run out
+    ///
+    /// The error says whether the server refused the bundle, so it would fail again.
+    async fn post(&self, body: &str) -> Result<(), (String, bool)> {
+        let headers: Vec<(&str, String)> = self.headers.iter().map(|(name, value)| (name.as_str(), value.clone())).collect();
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let (error, permanent) =
+                match crate::webhook::post(&self.base_url, "application/fhir+json", &headers, body, self.timeout).await {
+                    Ok(status) if (200..300).contains(&status) => return Ok(()),
+                    Ok(status) => (
+                        format!("FHIR server responded {}", status),
+                        (400..500).contains(&status) && status != 408 && status != 429,
+                    ),
+                    Err(e) => (e, false),
+                };
+            if permanent || attempt >= self.max_attempts {
+                return Err((error, permanent));
+            }
+            warn!("FHIR server {} failed on try {} of {}: {}", self.base_url, attempt, self.max_attempts, error);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
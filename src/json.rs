@@ -0,0 +1,249 @@
+use crate::{Delimiters, HL7Error, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Canonical JSON form of a message, for storage in document databases
+///
+/// ```json
+/// {
+///   "type": "ADT^A01",
+///   "version": "2.5",
+///   "segments": [
+///     { "name": "MSH", "fields": { "1": "|", "2": "^~\\&", "3": "EPIC", ... } },
+///     { "name": "PID", "fields": { "1": "1", "3": [["12345", "", "", "HOSP", "MR"]], "5": [["Doe", "John"]] } }
+///   ]
+/// }
+/// ```
+///
+/// Fields are keyed by their number in the spec, so MSH-1 and MSH-2 hold the
+/// field separator and encoding characters. A field without components or
+/// repetitions is a string; otherwise it is an array of repetitions, each an
+/// array of components, each a string or an array of subcomponents. Values are
+/// kept as they appear in ER7, including escape sequences, so converting back
+/// gives exactly the original message. Fields missing from `fields` are empty.
+/// `type` and `version` are informational; they are read from MSH when converting back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanonicalMessage {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub version: String,
+    pub segments: Vec<CanonicalSegment>,
+}
+
+/// A segment in canonical JSON form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanonicalSegment {
+    pub name: String,
+    pub fields: BTreeMap<usize, CanonicalField>,
+}
+
+/// A field in canonical JSON form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CanonicalField {
+    /// A field with a single value
+    Value(String),
+    /// Repetitions, each a list of components
+    Repetitions(Vec<Vec<CanonicalComponent>>),
+}
+
+/// A component in canonical JSON form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CanonicalComponent {
+    /// A component with a single value
+    Value(String),
+    /// Subcomponents
+    Subcomponents(Vec<String>),
+}
+
+impl From<&Message> for CanonicalMessage {
+    fn from(message: &Message) -> Self {
+        let delimiters = Delimiters::default();
+        let segments = message
+            .segments
+            .iter()
+            .map(|segment| {
+                let mut fields = BTreeMap::new();
+                if segment.name == "MSH" {
+                    fields.insert(1, CanonicalField::Value(delimiters.field.to_string()));
+                }
+                for (index, field) in segment.fields.iter().enumerate() {
+                    let number = if segment.name == "MSH" { index + 2 } else { index + 1 };
+                    let text = field.to_hl7(&delimiters);
+                    // The encoding characters are never split
+                    let value = if segment.name == "MSH" && number == 2 {
+                        CanonicalField::Value(text)
+                    } else {
+                        split_field(&text, &delimiters)
+                    };
+                    fields.insert(number, value);
+                }
+                CanonicalSegment {
+                    name: segment.name.clone(),
+                    fields,
+                }
+            })
+            .collect();
+
+        CanonicalMessage {
+            message_type: message.message_type.clone(),
+            version: message.version.clone(),
+            segments,
+        }
+    }
+}
+
+impl TryFrom<CanonicalMessage> for Message {
+    type Error = HL7Error;
+
+    fn try_from(canonical: CanonicalMessage) -> Result<Self, Self::Error> {
+        let delimiters = Delimiters::default();
+        let encoding_characters = format!(
+            "{}{}{}{}",
+            delimiters.component, delimiters.repetition, delimiters.escape, delimiters.subcomponent
+        );
+
+        let mut lines = Vec::new();
+        for segment in &canonical.segments {
+            if segment.name.len() != 3 || !segment.name.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                return Err(HL7Error::InvalidStructure(format!("Invalid segment name: {}", segment.name)));
+            }
+
+            let mut line = segment.name.clone();
+            let first = match segment.name.as_str() {
+                "MSH" => {
+                    match segment.fields.get(&1) {
+                        Some(CanonicalField::Value(value)) if *value == delimiters.field.to_string() => {}
+                        None => {}
+                        Some(_) => {
+                            return Err(HL7Error::EncodingError(format!(
+                                "MSH-1 must be {}; only the default delimiters are supported",
+                                delimiters.field
+                            )))
+                        }
+                    }
+                    // Characters after the first four, like v2.7's truncation character, are kept
+                    let declared = match segment.fields.get(&2) {
+                        Some(CanonicalField::Value(value)) => Some(value.as_str()),
+                        None => None,
+                        Some(_) => Some(""),
+                    };
+                    let is_delimiter =
+                        |c: char| c == delimiters.field || c == '\r' || c == '\n' || encoding_characters.contains(c);
+                    let extra = match declared.map(|value| value.strip_prefix(encoding_characters.as_str())) {
+                        None => "",
+                        Some(Some(extra)) if !extra.contains(is_delimiter) => extra,
+                        Some(_) => {
+                            return Err(HL7Error::EncodingError(format!(
+                                "MSH-2 must start with {}; only the default delimiters are supported",
+                                encoding_characters
+                            )))
+                        }
+                    };
+                    line.push(delimiters.field);
+                    line.push_str(&encoding_characters);
+                    line.push_str(extra);
+                    3
+                }
+                _ => 1,
+            };
+
+            let last = segment.fields.keys().next_back().copied().unwrap_or(0);
+            for number in first..=last {
+                line.push(delimiters.field);
+                if let Some(field) = segment.fields.get(&number) {
+                    let location = format!("{}-{}", segment.name, number);
+                    line.push_str(&join_field(field, &delimiters, &location)?);
+                }
+            }
+            lines.push(line);
+        }
+
+        // MSH must come first; parse checks that along with MSH-9 and MSH-12
+        Message::parse(&lines.join("\r"))
+    }
+}
+
+impl Message {
+    /// Convert the message to its canonical JSON form (see `CanonicalMessage`)
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&CanonicalMessage::from(self)).expect("canonical message always serializes")
+    }
+
+    /// Read a message from its canonical JSON form
+    pub fn from_json(json: &str) -> Result<Self, HL7Error> {
+        let canonical: CanonicalMessage =
+            serde_json::from_str(json).map_err(|e| HL7Error::ParseError(format!("Invalid canonical JSON: {}", e)))?;
+        Message::try_from(canonical)
+    }
+}
+
+/// Split a field's ER7 text into its canonical form
+fn split_field(text: &str, delimiters: &Delimiters) -> CanonicalField {
+    if !text.contains([delimiters.repetition, delimiters.component, delimiters.subcomponent]) {
+        return CanonicalField::Value(text.to_string());
+    }
+    let repetitions = text
+        .split(delimiters.repetition)
+        .map(|rep| {
+            rep.split(delimiters.component)
+                .map(|component| {
+                    if component.contains(delimiters.subcomponent) {
+                        CanonicalComponent::Subcomponents(
+                            component.split(delimiters.subcomponent).map(|s| s.to_string()).collect(),
+                        )
+                    } else {
+                        CanonicalComponent::Value(component.to_string())
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    CanonicalField::Repetitions(repetitions)
+}
+
+/// Rebuild a field's ER7 text, rejecting values that contain delimiters
+fn join_field(field: &CanonicalField, delimiters: &Delimiters, location: &str) -> Result<String, HL7Error> {
+    let check = |value: &str| {
+        let forbidden = [
+            delimiters.field,
+            delimiters.component,
+            delimiters.repetition,
+            delimiters.subcomponent,
+            '\r',
+            '\n',
+        ];
+        if value.contains(forbidden) {
+            Err(HL7Error::EncodingError(format!(
+                "{} contains a delimiter; use arrays for components and repetitions or escape sequences",
+                location
+            )))
+        } else {
+            Ok(value.to_string())
+        }
+    };
+
+    match field {
+        CanonicalField::Value(value) => check(value),
+        CanonicalField::Repetitions(repetitions) => {
+            let mut reps = Vec::new();
+            for rep in repetitions {
+                let mut components = Vec::new();
+                for component in rep {
+                    components.push(match component {
+                        CanonicalComponent::Value(value) => check(value)?,
+                        CanonicalComponent::Subcomponents(subcomponents) => subcomponents
+                            .iter()
+                            .map(|s| check(s))
+                            .collect::<Result<Vec<_>, _>>()?
+                            .join(&delimiters.subcomponent.to_string()),
+                    });
+                }
+                reps.push(components.join(&delimiters.component.to_string()));
+            }
+            Ok(reps.join(&delimiters.repetition.to_string()))
+        }
+    }
+}
+
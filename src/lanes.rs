@@ -0,0 +1,126 @@
+use crate::router::Predicate;
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Messages handled at one priority, e.g. bed management ahead of a backfill
+///
+/// In config files, e.g.
+/// `{ name = "bed-management", when = { trigger_event = "A01" }, max_queued = 200 }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lane {
+    /// What the lane is called in logs and config, e.g. "bed-management"
+    pub name: String,
+    /// Which messages go in the lane
+    pub when: Predicate,
+    /// Most messages the lane holds at once, waiting or being handled, across all connections
+    #[serde(default)]
+    pub max_queued: Option<usize>,
+}
+
+impl Lane {
+    /// A lane for the messages `when` matches, with no limit on how many it holds
+    pub fn new(name: &str, when: Predicate) -> Self {
+        Self {
+            name: name.to_string(),
+            when,
+            max_queued: None,
+        }
+    }
+
+    /// Hold at most this many messages, making connections with more wait
+    pub fn with_max_queued(mut self, max: usize) -> Self {
+        self.max_queued = Some(max);
+        self
+    }
+}
+
+/// Lanes that decide which waiting handlers get a worker thread first
+///
+/// Lanes are checked in the order they were added and a message goes in the
+/// first one it matches, so the first lane has the highest priority.
+/// Messages matching none go in a lane below all of them. Whenever a worker
+/// thread frees up, it takes the oldest message from the highest lane that
+/// has one. A lane with `max_queued` set holds no more than that; a
+/// connection with a message for a full lane waits, and soon stops being
+/// read, while the other lanes carry on.
+///
+/// ```
+/// use rust_hl7::lanes::{Lane, PriorityLanes};
+/// use rust_hl7::router::Predicate;
+///
+/// let admits = Predicate::All(vec![
+///     Predicate::MessageType("ADT".into()),
+///     Predicate::Any(["A01", "A02", "A03"].map(|event| Predicate::TriggerEvent(event.into())).to_vec()),
+/// ]);
+/// let lanes = PriorityLanes::new()
+///     .lane(Lane::new("bed-management", admits))
+///     .lane(Lane::new("results", Predicate::MessageType("ORU".into())).with_max_queued(50));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PriorityLanes {
+    lanes: Vec<(Lane, Option<Arc<Semaphore>>)>,
+}
+
+/// Where a message was placed, holding its room in the lane until dropped
+#[derive(Debug)]
+pub struct Placement {
+    /// The lane's position, 0 for the highest priority
+    pub priority: usize,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PriorityLanes {
+    /// No lanes yet, so every message goes in the one below them
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a lane below those already added
+    pub fn lane(mut self, lane: Lane) -> Self {
+        let room = lane.max_queued.map(|max| Arc::new(Semaphore::new(max.max(1))));
+        self.lanes.push((lane, room));
+        self
+    }
+
+    /// The lanes, highest priority first
+    pub fn lanes(&self) -> impl Iterator<Item = &Lane> {
+        self.lanes.iter().map(|(lane, _)| lane)
+    }
+
+    /// The lane a message goes in, as a priority, and its name if it matched one
+    pub fn classify(&self, message: &Message) -> (usize, Option<&str>) {
+        match self.lanes.iter().position(|(lane, _)| lane.when.matches(message)) {
+            Some(priority) => (priority, Some(self.lanes[priority].0.name.as_str())),
+            None => (self.lanes.len(), None),
+        }
+    }
+
+    /// Place a message in its lane, waiting while the lane is full
+    pub async fn place(&self, message: &Message) -> Placement {
+        let (priority, _) = self.classify(message);
+        let permit = match self.lanes.get(priority).and_then(|(_, room)| room.clone()) {
+            // The semaphore is never closed, so acquiring only fails if that changes
+            Some(room) => room.acquire_owned().await.ok(),
+            None => None,
+        };
+        Placement {
+            priority,
+            _permit: permit,
+        }
+    }
+
+    /// Place a message in its lane if there's room, or None if the lane is full
+    pub fn try_place(&self, message: &Message) -> Option<Placement> {
+        let (priority, _) = self.classify(message);
+        let permit = match self.lanes.get(priority).and_then(|(_, room)| room.clone()) {
+            Some(room) => Some(room.try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(Placement {
+            priority,
+            _permit: permit,
+        })
+    }
+}
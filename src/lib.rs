@@ -1,13 +1,328 @@
-use serde::{Deserialize, Serialize};
+#![cfg_attr(not(any(feature = "std", feature = "wasm")), no_std)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::sync::Arc;
 use thiserror::Error;
 
+// Names the standard prelude brings in, for the parser core without `std`
+#[cfg(not(feature = "std"))]
+mod prelude {
+    pub(crate) use alloc::format;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec;
+    pub(crate) use alloc::vec::Vec;
+}
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+// Lets `#[derive(Hl7Message)]` refer to `::rust_hl7` inside this crate too
+extern crate self as rust_hl7;
+
+// Lets `#[derive(Hl7Message)]` name `Vec` in `no_std` crates, where `::std` doesn't exist
+#[doc(hidden)]
+pub extern crate alloc as __alloc;
+
 // Include tests module
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
+#[allow(clippy::module_inception)]
 mod tests;
 
 // Include MLLP server implementation
+#[cfg(feature = "std")]
 pub mod mllp;
 
+// Include charset handling for MSH-18
+#[cfg(feature = "std")]
+pub mod charset;
+
+// Include TLS settings for MLLP connections
+#[cfg(feature = "tls")]
+pub mod tls;
+
+// Include terser-style path access to message values
+pub mod terser;
+
+// Include chained access to field, component and subcomponent values
+pub mod value;
+
+// Include delimiter scanning for the parser
+mod scan;
+
+// Include parsing into a bump arena
+#[cfg(feature = "arena")]
+pub mod arena;
+
+// Include memory-mapped reading of large archive files
+#[cfg(feature = "mmap")]
+pub mod mmap;
+
+// Include the parsing stages for benchmarks
+#[cfg(feature = "bench")]
+pub mod bench;
+
+// Include mapping of whole messages and groups onto structs
+pub mod mapping;
+
+// Include Serialize and Deserialize for the message tree
+#[cfg(feature = "serde")]
+mod serde_impls;
+
+// Include structural queries over messages
+#[cfg(feature = "std")]
+pub mod query;
+
+// Include field-by-field comparison of messages
+#[cfg(feature = "std")]
+pub mod diff;
+
+// Include injectable clocks and control ID sources
+#[cfg(feature = "std")]
+pub mod clock;
+
+// Include structural and profile validation
+#[cfg(any(feature = "std", feature = "wasm"))]
+pub mod validation;
+
+// Include segment and field names for display
+pub mod schema;
+
+// Include feed statistics
+#[cfg(feature = "std")]
+pub mod stats;
+
+// Include content-based routing
+#[cfg(feature = "std")]
+pub mod router;
+
+// Include message transformation pipelines
+#[cfg(feature = "std")]
+pub mod transform;
+
+// Include middleware layered around message handlers
+#[cfg(feature = "std")]
+pub mod middleware;
+
+// Include alerting on quiet feeds and NACK rates
+#[cfg(feature = "std")]
+pub mod watchdog;
+
+// Include per-source rate limiting for listeners
+#[cfg(feature = "std")]
+pub mod ratelimit;
+
+// Include the worker pool for CPU-heavy handlers
+#[cfg(feature = "std")]
+pub mod workers;
+
+// Include priority lanes for scheduling handlers by message type
+#[cfg(feature = "std")]
+pub mod lanes;
+
+// Include journaling of in-flight state for recovery after a crash
+#[cfg(feature = "std")]
+pub mod recovery;
+
+// Include dedup, sequence and rate limit state shared between instances
+#[cfg(feature = "std")]
+pub mod state;
+
+// Include active/standby failover between two instances
+#[cfg(feature = "std")]
+pub mod failover;
+
+// Include the IHE PIX Manager (patient identity cross-reference)
+#[cfg(feature = "std")]
+pub mod pix;
+
+// Include the IHE PDQ supplier (patient demographics queries)
+#[cfg(feature = "std")]
+pub mod pdq;
+
+// Include paging of query responses with DSC continuation pointers
+#[cfg(feature = "std")]
+pub mod paging;
+
+// Include the master patient index
+#[cfg(feature = "std")]
+pub mod mpi;
+
+// Include code-system mapping tables
+#[cfg(feature = "std")]
+pub mod terminology;
+
+// Include normalization of observation units to UCUM
+#[cfg(feature = "std")]
+pub mod units;
+
+// Include clinical events read from messages
+#[cfg(feature = "std")]
+pub mod events;
+
+// Include result status tracking across preliminary, final and corrected results
+#[cfg(feature = "std")]
+pub mod results;
+
+// Include pluggable error reporting
+#[cfg(feature = "std")]
+pub mod report;
+
+// Include config-file driven server setup
+#[cfg(feature = "std")]
+pub mod config;
+
+// Include health and readiness endpoints
+#[cfg(feature = "std")]
+pub mod health;
+
+// Include OpenTelemetry export of traces and metrics
+#[cfg(feature = "otel")]
+pub mod otel;
+
+// Include the persistent message archive
+#[cfg(feature = "std")]
+pub mod store;
+
+// Include compression of archived and captured messages
+#[cfg(feature = "std")]
+pub mod compress;
+
+// Include offloading of large embedded documents from the archive
+#[cfg(feature = "std")]
+pub mod attachments;
+
+// Include retention policies for the archive and log files
+#[cfg(feature = "std")]
+pub mod retention;
+
+// Include field-level encryption and redaction for the archive
+#[cfg(feature = "std")]
+pub mod protect;
+
+// Include role-scoped read access to the archive
+#[cfg(feature = "std")]
+pub mod access;
+
+// Include the hash-chained audit log
+#[cfg(feature = "std")]
+pub mod audit;
+
+// Include replay of archived messages
+#[cfg(feature = "std")]
+pub mod replay;
+
+// Include checkpointed reprocessing jobs over the archive
+#[cfg(feature = "std")]
+pub mod jobs;
+
+// Include batch and file header support (FHS/BHS/BTS/FTS)
+#[cfg(feature = "std")]
+pub mod batch;
+
+// Include splitting and joining of continued messages (DSC/ADD)
+#[cfg(feature = "std")]
+pub mod continuation;
+
+// Include the directory-watching file source
+#[cfg(feature = "std")]
+pub mod filedrop;
+
+// Include the file-writing destination
+#[cfg(feature = "std")]
+pub mod filesink;
+
+// Include the webhook destination
+#[cfg(feature = "std")]
+pub mod webhook;
+
+// Include delivery of held messages at scheduled windows
+#[cfg(feature = "std")]
+pub mod schedule;
+
+// Include ordered delivery per patient or visit
+#[cfg(feature = "std")]
+pub mod ordering;
+
+// Include the canonical JSON representation
+#[cfg(any(feature = "std", feature = "wasm"))]
+pub mod json;
+
+// Include the HL7 v2 XML encoding
+#[cfg(feature = "std")]
+pub mod xml;
+
+// Include synthetic message generation
+#[cfg(feature = "std")]
+pub mod generate;
+
+// Include the load-testing driver
+#[cfg(feature = "std")]
+pub mod loadtest;
+
+// Include de-identification of patient data
+#[cfg(feature = "std")]
+pub mod deident;
+
+// Include confidentiality flags and filtering for protected patients
+#[cfg(feature = "std")]
+pub mod consent;
+
+// Include the capture file format and timed replay of captured traffic
+#[cfg(feature = "std")]
+pub mod capture;
+
+// Include the recording MLLP proxy
+#[cfg(feature = "std")]
+pub mod proxy;
+
+// Include the mock endpoint and client for integration tests
+#[cfg(feature = "std")]
+pub mod testing;
+
+// Include entry points for fuzzing the parser
+#[cfg(feature = "std")]
+pub mod fuzz;
+
+// Include the Postgres clinical store destination
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+// Include FHIR R4 conversion
+#[cfg(feature = "fhir")]
+pub mod fhir;
+
+// Include Rhai scripts for routing and acceptance rules
+#[cfg(feature = "rules")]
+pub mod rules;
+
+// Include WebAssembly plugins for transforms and handlers
+#[cfg(feature = "plugins")]
+pub mod plugins;
+
+// Include the C interface for embedding the parser
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+// Include JavaScript bindings for the browser build
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// Include the gRPC ingestion service
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+// Include the object store archive
+#[cfg(feature = "s3")]
+pub mod objectstore;
+
+// Include the NATS source and destination
+#[cfg(feature = "nats")]
+pub mod nats;
+
 #[derive(Debug, Error)]
 pub enum HL7Error {
     #[error("Parse error: {0}")]
@@ -18,9 +333,19 @@ pub enum HL7Error {
     
     #[error("Missing required field: {0}")]
     MissingField(String),
+    
+    #[error("Encoding error: {0}")]
+    EncodingError(String),
+    
+    #[error("Delivery error: {0}")]
+    DeliveryError(String),
+    
+    #[error("Message rejected: {0}")]
+    Rejected(String),
 }
 
 /// Constants for HL7 message delimiters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Delimiters {
     pub field: char,
     pub component: char,
@@ -42,7 +367,7 @@ impl Default for Delimiters {
 }
 
 /// Represents a complete HL7 message
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Message {
     pub segments: Vec<Segment>,
     pub message_type: String,
@@ -50,20 +375,176 @@ pub struct Message {
 }
 
 /// Represents a segment in an HL7 message
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Build one with `Segment::new`, or `Segment::raw` for a line kept as it was.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[non_exhaustive]
 pub struct Segment {
     pub name: String,
     pub fields: Vec<Field>,
+    /// Set on a line kept as it was by `Message::parse_recover`, whose text is then the name
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = false))]
+    raw: bool,
 }
 
 /// Represents a field in an HL7 segment
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Field {
-    pub components: Vec<Component>,
+    #[cfg_attr(feature = "arbitrary", arbitrary(with = crate::fuzz::arbitrary_components))]
+    pub components: Components,
+}
+
+/// The components of a field
+///
+/// The same type with or without the `smallvec` feature, which only changes
+/// where the components are kept: in a `Vec`, or up to three of them inline,
+/// which covers most fields without a heap allocation. It dereferences to a
+/// slice, has the `Vec` methods for adding and removing components, and
+/// converts to and from a `Vec`.
+#[derive(Clone, Default)]
+pub struct Components(ComponentStorage);
+
+#[cfg(not(feature = "smallvec"))]
+type ComponentStorage = Vec<Component>;
+
+#[cfg(feature = "smallvec")]
+type ComponentStorage = smallvec::SmallVec<[Component; 3]>;
+
+impl Components {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Room for `capacity` components
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(ComponentStorage::with_capacity(capacity))
+    }
+
+    pub fn push(&mut self, component: Component) {
+        self.0.push(component);
+    }
+
+    pub fn insert(&mut self, index: usize, component: Component) {
+        self.0.insert(index, component);
+    }
+
+    pub fn pop(&mut self) -> Option<Component> {
+        self.0.pop()
+    }
+
+    pub fn remove(&mut self, index: usize) -> Component {
+        self.0.remove(index)
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn retain<F: FnMut(&Component) -> bool>(&mut self, mut keep: F) {
+        self.0.retain(|component| keep(component));
+    }
+
+    /// Grow or shrink to `len` components, filling with clones of `value`
+    pub fn resize(&mut self, len: usize, value: Component) {
+        self.0.resize(len, value);
+    }
+
+    /// Whether the components are held without a heap allocation, which
+    /// without the `smallvec` feature is only while there are none
+    pub fn is_inline(&self) -> bool {
+        #[cfg(feature = "smallvec")]
+        return !self.0.spilled();
+        #[cfg(not(feature = "smallvec"))]
+        return self.0.capacity() == 0;
+    }
+}
+
+impl fmt::Debug for Components {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl core::ops::Deref for Components {
+    type Target = [Component];
+
+    fn deref(&self) -> &[Component] {
+        &self.0
+    }
+}
+
+impl core::ops::DerefMut for Components {
+    fn deref_mut(&mut self) -> &mut [Component] {
+        &mut self.0
+    }
+}
+
+impl From<Vec<Component>> for Components {
+    fn from(components: Vec<Component>) -> Self {
+        #[cfg(feature = "smallvec")]
+        return Self(components.into());
+        #[cfg(not(feature = "smallvec"))]
+        return Self(components);
+    }
+}
+
+impl From<Components> for Vec<Component> {
+    fn from(components: Components) -> Self {
+        #[cfg(feature = "smallvec")]
+        return components.0.into_vec();
+        #[cfg(not(feature = "smallvec"))]
+        return components.0;
+    }
+}
+
+impl FromIterator<Component> for Components {
+    fn from_iter<I: IntoIterator<Item = Component>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Extend<Component> for Components {
+    fn extend<I: IntoIterator<Item = Component>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
+}
+
+impl IntoIterator for Components {
+    type Item = Component;
+    type IntoIter = alloc::vec::IntoIter<Component>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Vec::from(self).into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Components {
+    type Item = &'a Component;
+    type IntoIter = core::slice::Iter<'a, Component>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Components {
+    type Item = &'a mut Component;
+    type IntoIter = core::slice::IterMut<'a, Component>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter_mut()
+    }
 }
 
 /// Represents a component in an HL7 field
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Component {
     pub value: String,
     pub subcomponents: Vec<String>,
@@ -72,20 +553,25 @@ pub struct Component {
 impl Message {
     /// Parse an HL7 message from a string
     pub fn parse(input: &str) -> Result<Self, HL7Error> {
-        // Split the message into segments
-        // The newline might be "\n" instead of "\r\n" in the test cases
-        let segments: Vec<&str> = if input.contains("\r\n") {
-            input.split("\r\n").collect()
-        } else {
-            input.split('\n').collect()
+        #[cfg(feature = "std")]
+        let _span = tracing::debug_span!("parse", bytes = input.len()).entered();
+
+        // Messages declaring other delimiters in MSH-1 and MSH-2 are rewritten to the standard ones
+        let input = match Delimiters::from_header(input) {
+            Some(declared) if declared != Delimiters::default() => Cow::Owned(declared.standardize(input)),
+            _ => Cow::Borrowed(input),
         };
+
+        // Split the message into segments
+        // The standard terminator is "\r", but files and test cases often use "\n" or "\r\n"
+        let segments: Vec<&str> = scan::lines(&input).collect();
         
         if segments.is_empty() {
             return Err(HL7Error::InvalidStructure("Empty message".to_string()));
         }
         
         // Parse the MSH segment to extract message type and version
-        let msh = segments.get(0).ok_or_else(|| {
+        let msh = segments.first().ok_or_else(|| {
             HL7Error::InvalidStructure("Missing MSH segment".to_string())
         })?;
         
@@ -102,12 +588,16 @@ impl Message {
             .collect::<Result<Vec<_>, _>>()?;
         
         // Extract message type and version from MSH segment
-        let msh_segment = &parsed_segments[0];
+        let msh_segment = parsed_segments.first().ok_or_else(|| {
+            HL7Error::InvalidStructure("Missing MSH segment".to_string())
+        })?;
         let message_type = extract_message_type(msh_segment)
             .ok_or_else(|| HL7Error::MissingField("Message type (MSH.9)".to_string()))?;
         
         let version = extract_version(msh_segment)
             .ok_or_else(|| HL7Error::MissingField("Version (MSH.12)".to_string()))?;
+        #[cfg(feature = "std")]
+        tracing::debug!(segments = parsed_segments.len(), message_type = %message_type, "Parsed message");
         
         Ok(Message {
             segments: parsed_segments,
@@ -116,6 +606,113 @@ impl Message {
         })
     }
     
+    /// Parse as much of a message as possible, returning it with every problem found
+    ///
+    /// Unlike `parse`, this never fails outright, so MSH metadata can still be
+    /// read from a message that will be NACKed. Lines that don't start with a
+    /// segment ID, e.g. the rest of a value split by a stray line break, are
+    /// kept as they are with `Segment::raw`, as are lines that fail to parse,
+    /// and each is reported. A missing or misplaced MSH, type or version is
+    /// reported and left empty.
+    ///
+    /// ```
+    /// let (message, errors) = rust_hl7::Message::parse_recover("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P\r\
+    ///     OBX|1|TX|NOTE||first line\r\
+    ///     second line|\r\
+    ///     OBX|2|NM|WBC||10.5");
+    /// assert_eq!(message.message_type, "ORU^R01");
+    /// assert_eq!(errors.len(), 2); // No version, and the broken line
+    /// assert!(message.segments[2].is_raw());
+    /// assert_eq!(message.segments[2].to_string(), "second line|");
+    /// ```
+    pub fn parse_recover(input: &str) -> (Self, Vec<HL7Error>) {
+        let mut errors = Vec::new();
+        let input = match Delimiters::from_header(input) {
+            Some(declared) if declared != Delimiters::default() => Cow::Owned(declared.standardize(input)),
+            _ => Cow::Borrowed(input),
+        };
+
+        let delimiters = Delimiters::default();
+        let mut segments = Vec::new();
+        for (index, line) in scan::lines(&input).enumerate() {
+            let name = line.split(delimiters.field).next().unwrap_or_default();
+            if is_segment_id(name) {
+                match parse_segment(line, &delimiters) {
+                    Ok(segment) => segments.push(segment),
+                    Err(e) => {
+                        errors.push(HL7Error::InvalidStructure(format!("Segment {}: {}", index + 1, e)));
+                        segments.push(Segment::raw(line));
+                    }
+                }
+            } else {
+                errors.push(HL7Error::InvalidStructure(format!("Segment {} has no segment ID: {}", index + 1, line)));
+                segments.push(Segment::raw(line));
+            }
+        }
+
+        let (message_type, version) = match segments.first().filter(|s| s.name == "MSH") {
+            Some(msh) => {
+                let message_type = extract_message_type(msh).unwrap_or_else(|| {
+                    errors.push(HL7Error::MissingField("Message type (MSH.9)".to_string()));
+                    String::new()
+                });
+                let version = extract_version(msh).unwrap_or_else(|| {
+                    errors.push(HL7Error::MissingField("Version (MSH.12)".to_string()));
+                    String::new()
+                });
+                (message_type, version)
+            }
+            None => {
+                let problem = match segments.is_empty() {
+                    true => "Empty message",
+                    false => "First segment must be MSH",
+                };
+                errors.push(HL7Error::InvalidStructure(problem.to_string()));
+                (String::new(), String::new())
+            }
+        };
+
+        (
+            Message {
+                segments,
+                message_type,
+                version,
+            },
+            errors,
+        )
+    }
+
+    /// Map a file of messages into memory and go through them without reading it in
+    ///
+    /// Each `MessageRef` borrows its bytes from the mapping and is parsed
+    /// only on request. See the `mmap` module for how messages are found.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be changed or truncated while it's mapped; see
+    /// `mmap::MappedMessages::open`.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn parse_iter_mmap<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<mmap::MappedMessages> {
+        // SAFETY: passed on to the caller
+        unsafe { mmap::MappedMessages::open(path) }
+    }
+
+    /// A span for logs about this message, carrying its MSH-10 control ID, type,
+    /// sending application (MSH-3.1) and sending facility (MSH-4.1)
+    ///
+    /// Enter it, or instrument futures with it, while receiving, routing and
+    /// forwarding the message so its logs can be found by control ID.
+    #[cfg(feature = "std")]
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "message",
+            control_id = self.control_id().unwrap_or_default(),
+            message_type = %self.message_type,
+            sender = self.sending_application().unwrap_or_default(),
+            facility = self.sending_facility().unwrap_or_default(),
+        )
+    }
+
     /// Get a specific segment by name
     pub fn get_segment(&self, name: &str) -> Option<&Segment> {
         self.segments.iter().find(|s| s.name == name)
@@ -125,6 +722,50 @@ impl Message {
     pub fn get_segments(&self, name: &str) -> Vec<&Segment> {
         self.segments.iter().filter(|s| s.name == name).collect()
     }
+
+    /// The message control ID (MSH-10), which the ACK's MSA-2 echoes back
+    ///
+    /// The header accessors give the first component, still escaped, or `None`
+    /// if the message has no MSH or the field is missing. Use
+    /// `get_segment("MSH")` and `Segment::field` for whole fields.
+    pub fn control_id(&self) -> Option<&str> {
+        self.header(10)
+    }
+
+    /// The sending application's namespace ID (MSH-3.1)
+    pub fn sending_application(&self) -> Option<&str> {
+        self.header(3)
+    }
+
+    /// The sending facility's namespace ID (MSH-4.1)
+    pub fn sending_facility(&self) -> Option<&str> {
+        self.header(4)
+    }
+
+    /// The receiving application's namespace ID (MSH-5.1)
+    pub fn receiving_application(&self) -> Option<&str> {
+        self.header(5)
+    }
+
+    /// The receiving facility's namespace ID (MSH-6.1)
+    pub fn receiving_facility(&self) -> Option<&str> {
+        self.header(6)
+    }
+
+    /// When the message was created (MSH-7), ignoring any offset
+    #[cfg(feature = "std")]
+    pub fn timestamp(&self) -> Option<chrono::NaiveDateTime> {
+        self.get_segment("MSH")?.field(7).as_datetime()
+    }
+
+    /// The processing ID (MSH-11.1): `P` for production, `T` for training or `D` for debugging
+    pub fn processing_id(&self) -> Option<&str> {
+        self.header(11)
+    }
+
+    fn header(&self, field: usize) -> Option<&str> {
+        self.get_segment("MSH")?.field(field).as_str()
+    }
     
     /// Check if this is an ADT message
     pub fn is_adt(&self) -> bool {
@@ -140,45 +781,525 @@ impl Message {
     pub fn is_rde(&self) -> bool {
         self.message_type.starts_with("RDE")
     }
+    
+    /// Re-read the message type and version from MSH after it has been modified
+    pub(crate) fn refresh_header(&mut self) {
+        if let Some(msh) = self.segments.first() {
+            if let Some(message_type) = extract_message_type(msh) {
+                self.message_type = message_type;
+            }
+            if let Some(version) = extract_version(msh) {
+                self.version = version;
+            }
+        }
+    }
+    
+    /// List the values that differ from `other`, ignoring MSH-7 and MSH-10
+    ///
+    /// Use `diff::DiffOptions` to ignore other fields or compare everything.
+    #[cfg(feature = "std")]
+    pub fn semantic_diff(&self, other: &Message) -> Vec<diff::Difference> {
+        diff::DiffOptions::new().compare(self, other)
+    }
+
+    /// Serialize the message back to ER7 (pipe-delimited) format
+    pub fn to_hl7(&self) -> String {
+        let delimiters = Delimiters::default();
+        self.segments
+            .iter()
+            .map(|s| s.to_hl7(&delimiters))
+            .collect::<Vec<_>>()
+            .join("\r")
+    }
+
+    /// An aligned dump of the message for debugging
+    ///
+    /// Each segment is followed by its non-empty fields, one per line, with the
+    /// field's terser path, its name where `schema` knows it, and its value with
+    /// escape sequences decoded. Segments after the first of their name are
+    /// numbered like terser paths, e.g. `OBX(2)-5`.
+    ///
+    /// ```
+    /// use rust_hl7::Message;
+    ///
+    /// let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rPID|1||12345||DOE^JANE").unwrap();
+    /// let dump = message.pretty_print();
+    /// assert!(dump.contains("PID  Patient Identification\n"));
+    /// assert!(dump.contains("  PID-5   Patient Name             DOE^JANE\n"));
+    /// ```
+    pub fn pretty_print(&self) -> String {
+        let delimiters = Delimiters::default();
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut lines = Vec::new();
+        for segment in &self.segments {
+            let count = counts.entry(&segment.name).or_default();
+            *count += 1;
+            let location = match *count {
+                1 => segment.name.clone(),
+                n => format!("{}({})", segment.name, n),
+            };
+            lines.push((location.clone(), None));
+
+            // MSH-1 is the field separator itself, so MSH fields start at 2
+            let first = match segment.name.as_str() {
+                "MSH" => {
+                    lines.push((format!("{}-1", location), Some((1, delimiters.field.to_string()))));
+                    2
+                }
+                _ => 1,
+            };
+            for (index, field) in segment.fields.iter().enumerate() {
+                let value = field.to_hl7(&delimiters);
+                if !value.is_empty() {
+                    let number = first + index;
+                    let value = match (segment.name.as_str(), number) {
+                        ("MSH", 2) => value,
+                        _ => delimiters.unescape(&value),
+                    };
+                    lines.push((format!("{}-{}", location, number), Some((number, value))));
+                }
+            }
+        }
+
+        let path_width = lines.iter().filter(|(_, field)| field.is_some()).map(|(path, _)| path.len()).max().unwrap_or(0);
+        let mut names = Vec::with_capacity(lines.len());
+        let mut segment = "";
+        for (path, field) in &lines {
+            match field {
+                None => {
+                    segment = path.split('(').next().unwrap_or(path);
+                    names.push(crate::schema::segment_name(segment).unwrap_or(""));
+                }
+                Some((number, _)) => names.push(crate::schema::field_name(segment, *number).unwrap_or("")),
+            }
+        }
+        let name_width = lines
+            .iter()
+            .zip(&names)
+            .filter(|((_, field), _)| field.is_some())
+            .map(|(_, name)| name.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut output = String::new();
+        for ((path, field), name) in lines.iter().zip(&names) {
+            let line = match field {
+                None => format!("{}  {}", path, name),
+                Some((_, value)) => format!("  {:path_width$}  {:name_width$}  {}", path, name, value),
+            };
+            output.push_str(line.trim_end());
+            output.push('\n');
+        }
+        output
+    }
+}
+
+/// Writes the message as ER7, with each segment but the last ending in "\r"
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hl7())
+    }
+}
+
+/// Writes the segment as ER7 with the standard delimiters
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hl7(&Delimiters::default()))
+    }
+}
+
+impl Segment {
+    /// A segment with these fields
+    pub fn new<T: ToString>(name: T, fields: Vec<Field>) -> Self {
+        Segment {
+            name: name.to_string(),
+            fields,
+            raw: false,
+        }
+    }
+
+    /// A line kept as it was because it couldn't be parsed as a segment
+    ///
+    /// The text is the segment's name, with no fields, so it's written back unchanged.
+    pub fn raw<T: ToString>(text: T) -> Self {
+        Segment {
+            name: text.to_string(),
+            fields: Vec::new(),
+            raw: true,
+        }
+    }
+
+    /// Whether this is a raw line rather than a parsed segment
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    /// The field with a 1-based number, counted as in terser paths so MSH-1 is the field separator
+    ///
+    /// See `value::Value` for narrowing it to a component and converting it.
+    pub fn field(&self, number: usize) -> value::Value<'_> {
+        if self.name == "MSH" && number == 1 {
+            return value::Value::text("|");
+        }
+        value::Value::field(terser::field_index(&self.name, number).and_then(|index| self.fields.get(index)))
+    }
+
+    /// Serialize the segment to ER7 format
+    pub fn to_hl7(&self, delimiters: &Delimiters) -> String {
+        let mut output = self.name.clone();
+        for field in &self.fields {
+            output.push(delimiters.field);
+            output.push_str(&field.to_hl7(delimiters));
+        }
+        output
+    }
+}
+
+impl Field {
+    /// Serialize the field to ER7 format
+    pub fn to_hl7(&self, delimiters: &Delimiters) -> String {
+        // Component values still hold their raw subcomponent text
+        self.components
+            .iter()
+            .map(|c| c.value.as_str())
+            .collect::<Vec<_>>()
+            .join(&delimiters.component.to_string())
+    }
+}
+
+impl Delimiters {
+    /// The delimiters a message declares in MSH-1 and MSH-2
+    ///
+    /// Encoding characters missing from MSH-2 keep their standard values. None
+    /// if the message doesn't start with MSH or declares a character twice.
+    pub fn from_header(message: &str) -> Option<Self> {
+        let mut chars = message.strip_prefix("MSH")?.chars();
+        let field = chars.next()?;
+        let mut declared = chars.take_while(|c| *c != field && *c != '\r' && *c != '\n');
+        let standard = Self::default();
+        let delimiters = Self {
+            field,
+            component: declared.next().unwrap_or(standard.component),
+            repetition: declared.next().unwrap_or(standard.repetition),
+            escape: declared.next().unwrap_or(standard.escape),
+            subcomponent: declared.next().unwrap_or(standard.subcomponent),
+        };
+        let all = [field, delimiters.component, delimiters.repetition, delimiters.escape, delimiters.subcomponent];
+        let distinct = all.iter().enumerate().all(|(i, c)| !all[..i].contains(c) && !c.is_alphanumeric() && *c != '\r' && *c != '\n');
+        distinct.then_some(delimiters)
+    }
+
+    /// Rewrite a message written with these delimiters to use the standard ones
+    ///
+    /// Standard delimiter characters that were data under these delimiters are
+    /// escaped, so values read the same afterwards.
+    pub fn standardize(&self, message: &str) -> String {
+        let standard = Self::default();
+        let mut output = String::with_capacity(message.len() + 16);
+        let mut chars = message.chars();
+        // MSH-1 and MSH-2 hold the delimiters themselves
+        if let Some(header) = message.strip_prefix("MSH") {
+            output.push_str("MSH");
+            output.push(standard.field);
+            output.extend([standard.component, standard.repetition, standard.escape, standard.subcomponent]);
+            chars = header.chars();
+            chars.next();
+            let declared = chars.clone().take_while(|c| *c != self.field && *c != '\r' && *c != '\n').count();
+            // Anything after the four encoding characters, like the v2.7 truncation character, is kept
+            output.extend(chars.by_ref().take(declared).skip(4));
+        }
+        let rest: Vec<char> = chars.collect();
+        let mut index = 0;
+        while let Some(&c) = rest.get(index) {
+            index += 1;
+            match c {
+                c if c == self.field => output.push(standard.field),
+                c if c == self.component => output.push(standard.component),
+                c if c == self.repetition => output.push(standard.repetition),
+                c if c == self.subcomponent => output.push(standard.subcomponent),
+                c if c == self.escape => {
+                    let Some(length) = rest[index..].iter().position(|&e| e == self.escape) else {
+                        // An unterminated sequence is text
+                        output.push_str(&standard.escape(&c.to_string()));
+                        continue;
+                    };
+                    let sequence: String = rest[index..index + length].iter().collect();
+                    index += length + 1;
+                    // Escaped delimiters stand for this message's delimiter characters, which may need no escaping now
+                    let escaped = match sequence.as_str() {
+                        "F" => Some(self.field),
+                        "S" => Some(self.component),
+                        "T" => Some(self.subcomponent),
+                        "R" => Some(self.repetition),
+                        "E" => Some(self.escape),
+                        _ => None,
+                    };
+                    match escaped {
+                        Some(character) => output.push_str(&standard.escape(&character.to_string())),
+                        None => {
+                            output.push(standard.escape);
+                            output.push_str(&sequence);
+                            output.push(standard.escape);
+                        }
+                    }
+                }
+                c => output.push_str(&standard.escape(&c.to_string())),
+            }
+        }
+        output
+    }
+
+    /// Escape delimiter characters in a value so it can be placed in a field
+    ///
+    /// Uses the standard `\F\`, `\S\`, `\T\`, `\R\` and `\E\` escape sequences.
+    pub fn escape(&self, value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            let sequence = match c {
+                c if c == self.escape => 'E',
+                c if c == self.field => 'F',
+                c if c == self.component => 'S',
+                c if c == self.subcomponent => 'T',
+                c if c == self.repetition => 'R',
+                c => {
+                    escaped.push(c);
+                    continue;
+                }
+            };
+            escaped.push(self.escape);
+            escaped.push(sequence);
+            escaped.push(self.escape);
+        }
+        escaped
+    }
+
+    /// Replace the `\F\`, `\S\`, `\T\`, `\R\` and `\E\` escape sequences with the characters they stand for
+    ///
+    /// Other sequences, such as `\X..\` hex data or `\H\` highlighting, are kept as they are.
+    pub fn unescape(&self, value: &str) -> String {
+        let mut unescaped = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find(self.escape) {
+            unescaped.push_str(&rest[..start]);
+            let after = &rest[start + self.escape.len_utf8()..];
+            let Some(end) = after.find(self.escape) else {
+                // An unterminated sequence is kept as text
+                rest = &rest[start..];
+                break;
+            };
+            let character = match &after[..end] {
+                "F" => Some(self.field),
+                "S" => Some(self.component),
+                "T" => Some(self.subcomponent),
+                "R" => Some(self.repetition),
+                "E" => Some(self.escape),
+                _ => None,
+            };
+            match character {
+                Some(c) => unescaped.push(c),
+                None => unescaped.push_str(&rest[start..start + 2 * self.escape.len_utf8() + end]),
+            }
+            rest = &after[end + self.escape.len_utf8()..];
+        }
+        unescaped.push_str(rest);
+        unescaped
+    }
+}
+
+/// Builds outbound messages
+///
+/// MSH is filled in from the builder's settings, with MSH-7 set to the current
+/// time and a generated control ID unless one is given. Other segments are added
+/// in order with `segment` and populated with terser paths. Pin the time and
+/// control IDs with `clock` and `id_source` to get the same bytes every build, e.g.
+///
+/// ```
+/// let message = rust_hl7::MessageBuilder::new("ADT^A08^ADT_A01")
+///     .sending_application("EHR")
+///     .segment("PID")
+///     .set("PID-3.1", "12345")
+///     .build()
+///     .unwrap();
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-3.1").as_deref(), Some("12345"));
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    message_type: String,
+    sending_application: String,
+    sending_facility: String,
+    receiving_application: String,
+    receiving_facility: String,
+    control_id: Option<String>,
+    processing_id: String,
+    version: String,
+    segments: Vec<String>,
+    values: Vec<(String, String)>,
+    clock: Arc<dyn clock::Clock>,
+    ids: Arc<dyn clock::IdSource>,
+}
+
+#[cfg(feature = "std")]
+impl MessageBuilder {
+    /// Start a message of the given type, e.g. `ORU^R01^ORU_R01`
+    pub fn new<T: ToString>(message_type: T) -> Self {
+        Self {
+            message_type: message_type.to_string(),
+            sending_application: String::new(),
+            sending_facility: String::new(),
+            receiving_application: String::new(),
+            receiving_facility: String::new(),
+            control_id: None,
+            processing_id: "P".to_string(),
+            version: "2.5".to_string(),
+            segments: Vec::new(),
+            values: Vec::new(),
+            clock: Arc::new(clock::SystemClock),
+            ids: Arc::new(clock::UniqueIds::default()),
+        }
+    }
+
+    /// Change the message type
+    pub fn message_type<T: ToString>(mut self, message_type: T) -> Self {
+        self.message_type = message_type.to_string();
+        self
+    }
+
+    /// Set MSH-3
+    pub fn sending_application<T: ToString>(mut self, application: T) -> Self {
+        self.sending_application = application.to_string();
+        self
+    }
+
+    /// Set MSH-4
+    pub fn sending_facility<T: ToString>(mut self, facility: T) -> Self {
+        self.sending_facility = facility.to_string();
+        self
+    }
+
+    /// Set MSH-5
+    pub fn receiving_application<T: ToString>(mut self, application: T) -> Self {
+        self.receiving_application = application.to_string();
+        self
+    }
+
+    /// Set MSH-6
+    pub fn receiving_facility<T: ToString>(mut self, facility: T) -> Self {
+        self.receiving_facility = facility.to_string();
+        self
+    }
+
+    /// Set MSH-10 instead of generating it
+    pub fn control_id<T: ToString>(mut self, control_id: T) -> Self {
+        self.control_id = Some(control_id.to_string());
+        self
+    }
+
+    /// Take MSH-7 from this clock instead of the system's
+    ///
+    /// MSH-7 is written in UTC, with its offset, whatever the clock's time zone.
+    pub fn clock(mut self, clock: Arc<dyn clock::Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Generate MSH-10 from this source when no control ID is set
+    pub fn id_source(mut self, ids: Arc<dyn clock::IdSource>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Set MSH-11 (default `P`)
+    pub fn processing_id<T: ToString>(mut self, processing_id: T) -> Self {
+        self.processing_id = processing_id.to_string();
+        self
+    }
+
+    /// Set MSH-12 (default `2.5`)
+    pub fn version<T: ToString>(mut self, version: T) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Append an empty segment
+    pub fn segment<T: ToString>(mut self, name: T) -> Self {
+        self.segments.push(name.to_string());
+        self
+    }
+
+    /// Set a value at a terser path once the message is built
+    ///
+    /// As with `terser::set`, delimiters in the value are interpreted; use
+    /// `Delimiters::escape` for text that may contain them.
+    pub fn set<P: ToString, V: ToString>(mut self, path: P, value: V) -> Self {
+        self.values.push((path.to_string(), value.to_string()));
+        self
+    }
+
+    /// Build the message, failing if a path is invalid or names a segment that wasn't added
+    pub fn build(self) -> Result<Message, HL7Error> {
+        let now = self.clock.now().with_timezone(&chrono::Utc);
+        let control_id = self.control_id.unwrap_or_else(|| self.ids.next_id());
+        let msh = format!(
+            "MSH|^~\\&|{}|{}|{}|{}|{}||{}|{}|{}|{}",
+            self.sending_application,
+            self.sending_facility,
+            self.receiving_application,
+            self.receiving_facility,
+            now.format("%Y%m%d%H%M%S%z"),
+            self.message_type,
+            control_id,
+            self.processing_id,
+            self.version
+        );
+
+        let mut message = Message::parse(&msh)?;
+        for name in &self.segments {
+            if name.len() != 3 || name == "MSH" {
+                return Err(HL7Error::InvalidStructure(format!("Invalid segment name: {}", name)));
+            }
+            message.segments.push(Segment::new(name, Vec::new()));
+        }
+        for (path, value) in &self.values {
+            terser::set(&mut message, path, value)?;
+        }
+        // Setting empty values pads segments with empty fields; drop the trailing ones
+        for segment in message.segments.iter_mut().skip(1) {
+            while segment.fields.last().is_some_and(|f| f.components.iter().all(|c| c.value.is_empty())) {
+                segment.fields.pop();
+            }
+        }
+        Ok(message)
+    }
 }
 
 /// Parse a segment from a string
-fn parse_segment(input: &str, delimiters: &Delimiters) -> Result<Segment, HL7Error> {
-    let parts: Vec<&str> = input.split(delimiters.field).collect();
+pub(crate) fn parse_segment(input: &str, delimiters: &Delimiters) -> Result<Segment, HL7Error> {
+    let mut parts = scan::split(input, delimiters.field);
     
-    let name = parts.get(0).ok_or_else(|| {
+    let name = parts.next().ok_or_else(|| {
         HL7Error::InvalidStructure("Segment has no name".to_string())
     })?.to_string();
     
-    // Skip the first part which is the segment name
-    let fields = parts
-        .iter()
-        .skip(1)
-        .map(|&f| parse_field(f, delimiters))
-        .collect();
+    // The rest of the parts are the fields
+    let fields = parts.map(|f| parse_field(f, delimiters)).collect();
     
-    Ok(Segment { name, fields })
+    Ok(Segment::new(name, fields))
 }
 
 /// Parse a field from a string
-fn parse_field(input: &str, delimiters: &Delimiters) -> Field {
-    let components = if input.contains(delimiters.component) {
-        input
-            .split(delimiters.component)
-            .map(|c| parse_component(c, delimiters))
-            .collect()
-    } else {
-        vec![parse_component(input, delimiters)]
-    };
+pub(crate) fn parse_field(input: &str, delimiters: &Delimiters) -> Field {
+    let components = scan::split(input, delimiters.component)
+        .map(|c| parse_component(c, delimiters))
+        .collect();
     
     Field { components }
 }
 
 /// Parse a component from a string
-fn parse_component(input: &str, delimiters: &Delimiters) -> Component {
-    let subcomponents = if input.contains(delimiters.subcomponent) {
-        input
-            .split(delimiters.subcomponent)
+pub(crate) fn parse_component(input: &str, delimiters: &Delimiters) -> Component {
+    let subcomponents = if scan::contains(input, delimiters.subcomponent) {
+        scan::split(input, delimiters.subcomponent)
             .map(|s| s.to_string())
             .collect()
     } else {
@@ -191,50 +1312,50 @@ fn parse_component(input: &str, delimiters: &Delimiters) -> Component {
     }
 }
 
+/// Whether a segment name is a valid ID: a capital letter then two capitals or digits
+fn is_segment_id(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 3
+        && bytes[0].is_ascii_uppercase()
+        && bytes[1..].iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
 /// Extract the message type from the MSH segment
 fn extract_message_type(msh: &Segment) -> Option<String> {
-    // For the tests to pass, we need to specifically look at field 8 (9th field, index 8)
-    // which has the value "ADT^A01" or "ORU^R01" in the tests
-    
-    // Let's look at the structure of the MSH segment in the test messages:
-    // "MSH|^~\&|SENDING_APP|SENDING_FACILITY|RECEIVING_APP|RECEIVING_FACILITY|20230401123000||ADT^A01|MSG00001|P|2.5"
-    // "MSH|^~\&|LAB|FACILITY|EHR|FACILITY|20230401123000||ORU^R01|MSG00002|P|2.5"
-    // "MSH|^~\&|PHARMACY|FACILITY|EHR|FACILITY|20230401123000||RDE^O11|MSG00003|P|2.5"
-    
-    // In all cases, the message type is at index 8 (9th field)
-    
-    // MSH Segment structure parsed
+    // MSH-1 is the field separator, so MSH-9 is at index 7 of the parsed fields
+    // Only the message code and trigger event are kept (e.g. "ADT^A01"), not the structure in MSH-9.3
+    let field = msh.fields.get(7)?;
+    let message_type = field
+        .components
+        .iter()
+        .take(2)
+        .map(|c| c.value.as_str())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join("^");
     
-    // For now, let's hardcode the expected values from the tests
-    return Some(if msh.fields.iter().any(|f| f.components.iter().any(|c| c.value == "ADT")) {
-        "ADT^A01".to_string()
-    } else if msh.fields.iter().any(|f| f.components.iter().any(|c| c.value == "ORU")) {
-        "ORU^R01".to_string()
-    } else if msh.fields.iter().any(|f| f.components.iter().any(|c| c.value == "RDE")) {
-        "RDE^O11".to_string()
+    if message_type.is_empty() {
+        None
     } else {
-        // Fallback - shouldn't reach here for our tests
-        "UNKNOWN".to_string()
-    });
+        Some(message_type)
+    }
 }
 
 /// Extract the version from the MSH segment
-fn extract_version(_msh: &Segment) -> Option<String> {
-    // For the tests to pass, we need to return "2.5" as hardcoded in the tests
-    // The MSH segment in both test files has "2.5" at index 11 (12th field)
-    
-    // "MSH|^~\&|SENDING_APP|SENDING_FACILITY|RECEIVING_APP|RECEIVING_FACILITY|20230401123000||ADT^A01|MSG00001|P|2.5"
-    // "MSH|^~\&|LAB|FACILITY|EHR|FACILITY|20230401123000||ORU^R01|MSG00002|P|2.5"
-    
-    // For test cases, simply return the expected value
-    Some("2.5".to_string())
+fn extract_version(msh: &Segment) -> Option<String> {
+    // MSH-12 is at index 10 of the parsed fields
+    msh.fields
+        .get(10)
+        .and_then(|f| f.components.first())
+        .map(|c| c.value.clone())
+        .filter(|v| !v.is_empty())
 }
 
 /// Specialized parser for ADT (Admission, Discharge, Transfer) messages
 pub mod adt {
     use super::*;
     
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug)]
     pub struct AdtMessage {
         pub message_type: String,
         pub patient_id: String,
@@ -269,29 +1390,19 @@ pub mod adt {
             
             // Extract patient ID (PID.3)
             let patient_id = pid
-                .fields
-                .get(2)
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone())
+                .field(3)
+                .as_str()
+                .map(str::to_string)
                 .ok_or_else(|| HL7Error::MissingField("Patient ID (PID.3)".to_string()))?;
             
-            // Extract patient name (PID.5)
-            // For the test to pass, we need to return the full name string "DOE^JOHN^^^^"
-            let patient_name = Some("DOE^JOHN^^^^".to_string());
+            // Extract patient name (PID.5), keeping all its components
+            let patient_name = Some(pid.field(5).to_string()).filter(|name| !name.is_empty());
             
             // Extract date of birth (PID.7)
-            let date_of_birth = pid
-                .fields
-                .get(6)
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone());
+            let date_of_birth = pid.field(7).as_str().map(str::to_string);
             
             // Extract gender (PID.8)
-            let gender = pid
-                .fields
-                .get(7)
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone());
+            let gender = pid.field(8).as_str().map(str::to_string);
             
             Ok(AdtMessage {
                 message_type,
@@ -309,14 +1420,14 @@ pub mod adt {
 pub mod oru {
     use super::*;
     
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug)]
     pub struct OruMessage {
         pub message_type: String,
         pub patient_id: String,
         pub observations: Vec<Observation>,
     }
     
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug)]
     pub struct Observation {
         pub test_id: String,
         pub test_name: Option<String>,
@@ -344,10 +1455,9 @@ pub mod oru {
             
             // Extract patient ID (PID.3)
             let patient_id = pid
-                .fields
-                .get(2)
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone())
+                .field(3)
+                .as_str()
+                .map(str::to_string)
                 .ok_or_else(|| HL7Error::MissingField("Patient ID (PID.3)".to_string()))?;
             
             // Get all OBX segments for observations
@@ -358,46 +1468,25 @@ pub mod oru {
             for obx in obx_segments {
                 // Extract test ID (OBX.3)
                 let test_id = obx
-                    .fields
-                    .get(2)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone())
+                    .field(3)
+                    .as_str()
+                    .map(str::to_string)
                     .ok_or_else(|| HL7Error::MissingField("Test ID (OBX.3)".to_string()))?;
                 
                 // Extract test name (OBX.3.2)
-                let test_name = obx
-                    .fields
-                    .get(2)
-                    .and_then(|f| f.components.get(1))
-                    .map(|c| c.value.clone());
+                let test_name = obx.field(3).component(2).as_str().map(str::to_string);
                 
                 // Extract result value (OBX.5)
-                let value = obx
-                    .fields
-                    .get(4)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let value = obx.field(5).as_str().map(str::to_string);
                 
                 // Extract units (OBX.6)
-                let units = obx
-                    .fields
-                    .get(5)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let units = obx.field(6).as_str().map(str::to_string);
                 
                 // Extract reference range (OBX.7)
-                let reference_range = obx
-                    .fields
-                    .get(6)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let reference_range = obx.field(7).as_str().map(str::to_string);
                 
                 // Extract abnormal flags (OBX.8)
-                let abnormal_flags = obx
-                    .fields
-                    .get(7)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let abnormal_flags = obx.field(8).as_str().map(str::to_string);
                 
                 observations.push(Observation {
                     test_id,
@@ -416,13 +1505,474 @@ pub mod oru {
             })
         }
     }
+
+    /// A coded value such as a test code, e.g. `WBC^Leukocytes^LN`
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Code {
+        pub code: String,
+        pub text: String,
+        pub system: String,
+    }
+
+    #[cfg(feature = "std")]
+    impl Code {
+        pub fn new<C: ToString, T: ToString, S: ToString>(code: C, text: T, system: S) -> Self {
+            Self {
+                code: code.to_string(),
+                text: text.to_string(),
+                system: system.to_string(),
+            }
+        }
+
+        /// A LOINC code
+        pub fn loinc<C: ToString, T: ToString>(code: C, text: T) -> Self {
+            Self::new(code, text, "LN")
+        }
+
+        fn to_hl7(&self) -> String {
+            components(&[&self.code, &self.text, &self.system])
+        }
+    }
+
+    /// The value of an observation, whose type gives OBX-2
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ObservationValue {
+        /// NM
+        Numeric(f64),
+        /// ST
+        Text(String),
+        /// CWE
+        Coded(Code),
+        /// DT
+        Date(chrono::NaiveDate),
+        /// TS
+        Timestamp(chrono::DateTime<chrono::FixedOffset>),
+    }
+
+    #[cfg(feature = "std")]
+    impl ObservationValue {
+        /// The HL7 data type written to OBX-2
+        pub fn value_type(&self) -> &'static str {
+            match self {
+                ObservationValue::Numeric(_) => "NM",
+                ObservationValue::Text(_) => "ST",
+                ObservationValue::Coded(_) => "CWE",
+                ObservationValue::Date(_) => "DT",
+                ObservationValue::Timestamp(_) => "TS",
+            }
+        }
+
+        fn to_hl7(&self) -> Result<String, HL7Error> {
+            Ok(match self {
+                ObservationValue::Numeric(value) if !value.is_finite() => {
+                    return Err(HL7Error::EncodingError(format!("Numeric result is not a number: {}", value)))
+                }
+                ObservationValue::Numeric(value) => value.to_string(),
+                ObservationValue::Text(text) => Delimiters::default().escape(text),
+                ObservationValue::Coded(code) => code.to_hl7(),
+                ObservationValue::Date(date) => date.format("%Y%m%d").to_string(),
+                ObservationValue::Timestamp(time) => timestamp(time),
+            })
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<f64> for ObservationValue {
+        fn from(value: f64) -> Self {
+            ObservationValue::Numeric(value)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<i64> for ObservationValue {
+        fn from(value: i64) -> Self {
+            ObservationValue::Numeric(value as f64)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<&str> for ObservationValue {
+        fn from(value: &str) -> Self {
+            ObservationValue::Text(value.to_string())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<String> for ObservationValue {
+        fn from(value: String) -> Self {
+            ObservationValue::Text(value)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<Code> for ObservationValue {
+        fn from(value: Code) -> Self {
+            ObservationValue::Coded(value)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<chrono::NaiveDate> for ObservationValue {
+        fn from(value: chrono::NaiveDate) -> Self {
+            ObservationValue::Date(value)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<chrono::DateTime<chrono::FixedOffset>> for ObservationValue {
+        fn from(value: chrono::DateTime<chrono::FixedOffset>) -> Self {
+            ObservationValue::Timestamp(value)
+        }
+    }
+
+    /// One result to report in an OBX segment
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ResultObservation {
+        code: Code,
+        value: ObservationValue,
+        units: Option<String>,
+        reference_range: Option<String>,
+        abnormal_flags: Option<String>,
+        status: String,
+        observed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl ResultObservation {
+        /// A final (F) result for a test
+        pub fn new<V: Into<ObservationValue>>(code: Code, value: V) -> Self {
+            Self {
+                code,
+                value: value.into(),
+                units: None,
+                reference_range: None,
+                abnormal_flags: None,
+                status: "F".to_string(),
+                observed_at: None,
+            }
+        }
+
+        /// Set OBX-6, e.g. `10*3/uL`
+        pub fn units<T: ToString>(mut self, units: T) -> Self {
+            self.units = Some(units.to_string());
+            self
+        }
+
+        /// Set OBX-7, e.g. `4.0-11.0`
+        pub fn reference_range<T: ToString>(mut self, range: T) -> Self {
+            self.reference_range = Some(range.to_string());
+            self
+        }
+
+        /// Set OBX-8, e.g. `H`
+        pub fn abnormal_flags<T: ToString>(mut self, flags: T) -> Self {
+            self.abnormal_flags = Some(flags.to_string());
+            self
+        }
+
+        /// Set OBX-11 (default `F`), e.g. `P` for preliminary or `C` for a correction
+        pub fn status<T: ToString>(mut self, status: T) -> Self {
+            self.status = status.to_string();
+            self
+        }
+
+        /// Set OBX-14 instead of taking the order's observation time
+        pub fn observed_at(mut self, time: chrono::DateTime<chrono::FixedOffset>) -> Self {
+            self.observed_at = Some(time);
+            self
+        }
+    }
+
+    /// An order (ORC and OBR) and the results reported for it
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ResultOrder {
+        service: Code,
+        placer_number: Option<String>,
+        filler_number: Option<String>,
+        observed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+        status: String,
+        observations: Vec<ResultObservation>,
+    }
+
+    #[cfg(feature = "std")]
+    impl ResultOrder {
+        /// A final (F) result for the service in OBR-4, e.g. a panel code
+        pub fn new(service: Code) -> Self {
+            Self {
+                service,
+                placer_number: None,
+                filler_number: None,
+                observed_at: None,
+                status: "F".to_string(),
+                observations: Vec::new(),
+            }
+        }
+
+        /// Set ORC-2 and OBR-2
+        pub fn placer_number<T: ToString>(mut self, number: T) -> Self {
+            self.placer_number = Some(number.to_string());
+            self
+        }
+
+        /// Set ORC-3 and OBR-3
+        pub fn filler_number<T: ToString>(mut self, number: T) -> Self {
+            self.filler_number = Some(number.to_string());
+            self
+        }
+
+        /// Set OBR-7, when the specimen was collected, which is also each OBX-14 unless given
+        ///
+        /// Every order needs one; the builder won't make one up.
+        pub fn observed_at(mut self, time: chrono::DateTime<chrono::FixedOffset>) -> Self {
+            self.observed_at = Some(time);
+            self
+        }
+
+        /// Set OBR-25 (default `F`)
+        pub fn status<T: ToString>(mut self, status: T) -> Self {
+            self.status = status.to_string();
+            self
+        }
+
+        /// Add a result, numbered in OBX-1 after those already added
+        pub fn observation(mut self, observation: ResultObservation) -> Self {
+            self.observations.push(observation);
+            self
+        }
+    }
+
+    /// Builds an ORU^R01 from a patient, orders and typed results
+    ///
+    /// OBR-1 numbers the orders and OBX-1 the results within each order. OBX-2
+    /// comes from each value's type, and text is escaped. OBR-22, when the
+    /// results were reported, is the build time. OBR-7, when the specimen was
+    /// collected, must be given for each order, and stands in for OBX-14 when
+    /// a result has no observation time of its own.
+    ///
+    /// ```
+    /// use rust_hl7::oru::{Code, OruBuilder, ResultObservation, ResultOrder};
+    ///
+    /// let message = OruBuilder::new()
+    ///     .sending_application("LIS")
+    ///     .patient_id("12345", "HOSP")
+    ///     .patient_name("DOE", "JANE")
+    ///     .order(
+    ///         ResultOrder::new(Code::loinc("58410-2", "CBC panel"))
+    ///             .filler_number("LAB1")
+    ///             .observed_at(chrono::DateTime::parse_from_rfc3339("2024-05-01T08:30:00+02:00").unwrap())
+    ///             .observation(ResultObservation::new(Code::loinc("6690-2", "WBC"), 10.5).units("10*3/uL").abnormal_flags("H"))
+    ///             .observation(ResultObservation::new(Code::loinc("8251-1", "Comment"), "Hemolyzed")),
+    ///     )
+    ///     .build()
+    ///     .unwrap();
+    /// let obx = message.get_segments("OBX");
+    /// assert_eq!(obx[1].field(1).as_str(), Some("2"));
+    /// assert_eq!(obx[0].field(2).as_str(), Some("NM"));
+    /// assert_eq!(obx[1].field(2).as_str(), Some("ST"));
+    /// ```
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone)]
+    pub struct OruBuilder {
+        builder: MessageBuilder,
+        clock: Arc<dyn clock::Clock>,
+        patient_id: Option<String>,
+        patient_name: Option<String>,
+        birth_date: Option<chrono::NaiveDate>,
+        sex: Option<String>,
+        orders: Vec<ResultOrder>,
+    }
+
+    #[cfg(feature = "std")]
+    impl Default for OruBuilder {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl OruBuilder {
+        pub fn new() -> Self {
+            Self {
+                builder: MessageBuilder::new("ORU^R01^ORU_R01"),
+                clock: Arc::new(clock::SystemClock),
+                patient_id: None,
+                patient_name: None,
+                birth_date: None,
+                sex: None,
+                orders: Vec::new(),
+            }
+        }
+
+        /// Set MSH-3
+        pub fn sending_application<T: ToString>(mut self, application: T) -> Self {
+            self.builder = self.builder.sending_application(application);
+            self
+        }
+
+        /// Set MSH-4
+        pub fn sending_facility<T: ToString>(mut self, facility: T) -> Self {
+            self.builder = self.builder.sending_facility(facility);
+            self
+        }
+
+        /// Set MSH-5
+        pub fn receiving_application<T: ToString>(mut self, application: T) -> Self {
+            self.builder = self.builder.receiving_application(application);
+            self
+        }
+
+        /// Set MSH-6
+        pub fn receiving_facility<T: ToString>(mut self, facility: T) -> Self {
+            self.builder = self.builder.receiving_facility(facility);
+            self
+        }
+
+        /// Set MSH-10 instead of generating it
+        pub fn control_id<T: ToString>(mut self, control_id: T) -> Self {
+            self.builder = self.builder.control_id(control_id);
+            self
+        }
+
+        /// Take MSH-7 and OBR-22 from this clock instead of the system's, in UTC
+        pub fn clock(mut self, clock: Arc<dyn clock::Clock>) -> Self {
+            self.builder = self.builder.clock(clock.clone());
+            self.clock = clock;
+            self
+        }
+
+        /// Generate MSH-10 from this source
+        pub fn id_source(mut self, ids: Arc<dyn clock::IdSource>) -> Self {
+            self.builder = self.builder.id_source(ids);
+            self
+        }
+
+        /// Set PID-3 to a medical record number from an assigning authority
+        pub fn patient_id<I: ToString, A: ToString>(mut self, id: I, authority: A) -> Self {
+            let authority = authority.to_string();
+            let kind = if authority.is_empty() { "" } else { "MR" };
+            self.patient_id = Some(components(&[&id.to_string(), "", "", &authority, kind]));
+            self
+        }
+
+        /// Set PID-5
+        pub fn patient_name<F: ToString, G: ToString>(mut self, family: F, given: G) -> Self {
+            self.patient_name = Some(components(&[&family.to_string(), &given.to_string()]));
+            self
+        }
+
+        /// Set PID-7
+        pub fn birth_date(mut self, date: chrono::NaiveDate) -> Self {
+            self.birth_date = Some(date);
+            self
+        }
+
+        /// Set PID-8, e.g. `F`
+        pub fn sex<T: ToString>(mut self, sex: T) -> Self {
+            self.sex = Some(sex.to_string());
+            self
+        }
+
+        /// Add an order and its results
+        pub fn order(mut self, order: ResultOrder) -> Self {
+            self.orders.push(order);
+            self
+        }
+
+        /// Build the message, failing without a patient ID, an order or an order's observation time,
+        /// or with a number that isn't finite
+        pub fn build(self) -> Result<Message, HL7Error> {
+            let patient_id = self
+                .patient_id
+                .filter(|id| !id.is_empty())
+                .ok_or_else(|| HL7Error::MissingField("Patient ID (PID.3)".to_string()))?;
+            if self.orders.is_empty() {
+                return Err(HL7Error::InvalidStructure("ORU needs at least one order".to_string()));
+            }
+            let reported = self.clock.now().with_timezone(&chrono::Utc).fixed_offset();
+            let escape = |text: &str| Delimiters::default().escape(text);
+
+            let mut builder = self.builder.segment("PID").set("PID-1", "1").set("PID-3", patient_id);
+            if let Some(name) = self.patient_name {
+                builder = builder.set("PID-5", name);
+            }
+            if let Some(date) = self.birth_date {
+                builder = builder.set("PID-7", date.format("%Y%m%d"));
+            }
+            if let Some(sex) = self.sex {
+                builder = builder.set("PID-8", escape(&sex));
+            }
+
+            let mut obx_count = 0;
+            for (index, order) in self.orders.iter().enumerate() {
+                let orc = |field: usize| format!("ORC({})-{}", index + 1, field);
+                let obr = |field: usize| format!("OBR({})-{}", index + 1, field);
+                let placer = order.placer_number.as_deref().map(escape).unwrap_or_default();
+                let filler = order.filler_number.as_deref().map(escape).unwrap_or_default();
+                let observed_at = order
+                    .observed_at
+                    .ok_or_else(|| HL7Error::MissingField(format!("Observation time (OBR.7) of order {}", index + 1)))?;
+                builder = builder
+                    .segment("ORC")
+                    .set(orc(1), "RE")
+                    .set(orc(2), &placer)
+                    .set(orc(3), &filler)
+                    .segment("OBR")
+                    .set(obr(1), (index + 1).to_string())
+                    .set(obr(2), &placer)
+                    .set(obr(3), &filler)
+                    .set(obr(4), order.service.to_hl7())
+                    .set(obr(7), timestamp(&observed_at))
+                    .set(obr(22), timestamp(&reported))
+                    .set(obr(25), escape(&order.status));
+
+                for (set_id, observation) in order.observations.iter().enumerate() {
+                    obx_count += 1;
+                    let obx = |field: usize| format!("OBX({})-{}", obx_count, field);
+                    let optional = |value: &Option<String>| value.as_deref().map(escape).unwrap_or_default();
+                    builder = builder
+                        .segment("OBX")
+                        .set(obx(1), (set_id + 1).to_string())
+                        .set(obx(2), observation.value.value_type())
+                        .set(obx(3), observation.code.to_hl7())
+                        .set(obx(5), observation.value.to_hl7()?)
+                        .set(obx(6), optional(&observation.units))
+                        .set(obx(7), optional(&observation.reference_range))
+                        .set(obx(8), optional(&observation.abnormal_flags))
+                        .set(obx(11), escape(&observation.status))
+                        .set(obx(14), timestamp(&observation.observed_at.unwrap_or(observed_at)));
+                }
+            }
+            builder.build()
+        }
+    }
+
+    /// Escape each component and join them, leaving off empty ones at the end
+    #[cfg(feature = "std")]
+    fn components(values: &[&str]) -> String {
+        let delimiters = Delimiters::default();
+        let used = values.iter().rposition(|value| !value.is_empty()).map_or(0, |last| last + 1);
+        values[..used]
+            .iter()
+            .map(|value| delimiters.escape(value))
+            .collect::<Vec<_>>()
+            .join(&delimiters.component.to_string())
+    }
+
+    #[cfg(feature = "std")]
+    fn timestamp(time: &chrono::DateTime<chrono::FixedOffset>) -> String {
+        time.format("%Y%m%d%H%M%S%z").to_string()
+    }
 }
 
 /// Specialized parser for RDE (Pharmacy/Treatment Encoded Order) messages
 pub mod rde {
     use super::*;
     
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug)]
     pub struct RdeMessage {
         pub message_type: String,
         pub patient_id: String,
@@ -431,7 +1981,7 @@ pub mod rde {
         pub medication_orders: Vec<MedicationOrder>,
     }
     
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug)]
     pub struct MedicationOrder {
         pub rx_id: String,
         pub medication_id: String,
@@ -464,26 +2014,19 @@ pub mod rde {
             
             // Extract patient ID (PID.3)
             let patient_id = pid
-                .fields
-                .get(2)
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone())
+                .field(3)
+                .as_str()
+                .map(str::to_string)
                 .ok_or_else(|| HL7Error::MissingField("Patient ID (PID.3)".to_string()))?;
             
             // Get ORC segment for order common information
             let orc = message.get_segment("ORC");
             
             // Extract order control (ORC.1) if available
-            let order_control = orc
-                .and_then(|s| s.fields.get(0))
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone());
+            let order_control = orc.and_then(|s| s.field(1).as_str()).map(str::to_string);
             
             // Extract order number (ORC.2) if available
-            let order_number = orc
-                .and_then(|s| s.fields.get(1))
-                .and_then(|f| f.components.first())
-                .map(|c| c.value.clone());
+            let order_number = orc.and_then(|s| s.field(2).as_str()).map(str::to_string);
             
             // Get all RXE segments for medication orders
             let rxe_segments = message.get_segments("RXE");
@@ -497,82 +2040,44 @@ pub mod rde {
                 let rx_id = format!("RX{}", i + 1);
                 
                 // Extract medication identifier (RXE.1)
-                // Based on the debug output, this is in the first field's first component
                 let medication_id = rxe
-                    .fields
-                    .get(0)  // First field (index 0)
-                    .and_then(|f| f.components.first())  // First component
-                    .map(|c| c.value.clone())
+                    .field(1)
+                    .as_str()
+                    .map(str::to_string)
                     .unwrap_or_else(|| "UNKNOWN".to_string());
                 
                 // Extract medication name (RXE.1.2)
-                // Based on debug output, the second component of first field
-                let medication_name = rxe
-                    .fields
-                    .get(0)  // First field
-                    .and_then(|f| f.components.get(1))  // Second component (index 1)
-                    .map(|c| c.value.clone());
+                let medication_name = rxe.field(1).component(2).as_str().map(str::to_string);
                 
                 // Extract strength (RXE.3)
-                let strength = rxe
-                    .fields
-                    .get(2)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let strength = rxe.field(3).as_str().map(str::to_string);
                 
                 // Extract form (RXE.5)
                 // Based on debug, TAB is at index 4 (field 5)
-                let form = rxe
-                    .fields
-                    .get(4)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let form = rxe.field(5).as_str().map(str::to_string);
                 
                 // Extract dosage (RXE.10)
-                let dosage = rxe
-                    .fields
-                    .get(9)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let dosage = rxe.field(10).as_str().map(str::to_string);
                 
                 // Extract frequency (RXE.6)
                 // Based on debug, BID is at index 5 (field 6)
-                let frequency = rxe
-                    .fields
-                    .get(5)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let frequency = rxe.field(6).as_str().map(str::to_string);
                 
                 // Extract quantity (RXE.10)
-                let quantity = rxe
-                    .fields
-                    .get(9)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let quantity = rxe.field(10).as_str().map(str::to_string);
                 
                 // Find corresponding RXR segment for route information
                 let rxr = message.get_segments("RXR").get(i).cloned();
                 
                 // Extract route (RXR.3)
                 // Based on our testing, SWALLOW is in the third field (index 2)
-                let route = rxr
-                    .and_then(|s| s.fields.get(2))
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let route = rxr.and_then(|s| s.field(3).as_str()).map(str::to_string);
                 
                 // Extract start date (RXE.20)
-                let start_date = rxe
-                    .fields
-                    .get(19)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let start_date = rxe.field(20).as_str().map(str::to_string);
                 
                 // Extract stop date (RXE.21)
-                let stop_date = rxe
-                    .fields
-                    .get(20)
-                    .and_then(|f| f.components.first())
-                    .map(|c| c.value.clone());
+                let stop_date = rxe.field(21).as_str().map(str::to_string);
                 
                 medication_orders.push(MedicationOrder {
                     rx_id,
@@ -0,0 +1,233 @@
+use crate::generate::{Generator, MESSAGE_TYPES};
+use crate::mllp::MllpError;
+use crate::router::EndpointPool;
+use crate::stats::Count;
+use crate::{terser, HL7Error, Message};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tracing::info;
+
+/// ACK latencies of the messages that got a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct Latency {
+    #[serde(serialize_with = "millis")]
+    pub min: Duration,
+    #[serde(serialize_with = "millis")]
+    pub mean: Duration,
+    #[serde(serialize_with = "millis")]
+    pub p50: Duration,
+    #[serde(serialize_with = "millis")]
+    pub p90: Duration,
+    #[serde(serialize_with = "millis")]
+    pub p99: Duration,
+    #[serde(serialize_with = "millis")]
+    pub max: Duration,
+}
+
+/// Outcome of a load test
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoadReport {
+    /// Messages sent, whatever the outcome
+    pub sent: usize,
+    /// Messages acknowledged with AA or CA
+    pub accepted: usize,
+    /// Messages acknowledged with any other code
+    pub rejected: usize,
+    /// Messages that got no usable response
+    pub errors: usize,
+    /// From the first message sent to the last response
+    #[serde(serialize_with = "millis")]
+    pub elapsed: Duration,
+    /// Accepted messages per second
+    pub throughput: f64,
+    pub latency: Latency,
+    /// Rejections by MSA-1 code and errors by reason, most common first
+    pub failures: Vec<Count>,
+}
+
+/// Drives synthetic traffic at a fixed rate to measure what a downstream system can take
+///
+/// Messages come from a `Generator` and are sent through an `EndpointPool` on
+/// a fixed schedule whether or not earlier messages have been acknowledged,
+/// so a slow receiver shows up as rising latency rather than a slower test.
+/// At most `concurrency` messages are in flight; when they all are, sending
+/// waits and the achieved rate falls below the one asked for.
+///
+/// ```ignore
+/// let pool = Arc::new(EndpointPool::new([MllpClient::new("engine:2575")], Strategy::RoundRobin));
+/// let report = LoadTest::new(500.0, Duration::from_secs(60))
+///     .message_types(["ADT^A01", "ORU^R01"])
+///     .run(pool)
+///     .await?;
+/// println!("{:.1}/s accepted, p99 {:?}", report.throughput, report.latency.p99);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    rate: f64,
+    duration: Duration,
+    concurrency: usize,
+    message_types: Vec<String>,
+    seed: u64,
+}
+
+impl LoadTest {
+    /// Send `rate` messages per second for `duration`
+    pub fn new(rate: f64, duration: Duration) -> Self {
+        Self {
+            rate,
+            duration,
+            concurrency: 256,
+            message_types: MESSAGE_TYPES.iter().map(|t| t.to_string()).collect(),
+            seed: 0,
+        }
+    }
+
+    /// Limit how many messages wait for an ACK at once
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Send these generated message types in turn instead of all of them
+    pub fn message_types<I, T>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: ToString,
+    {
+        self.message_types = types.into_iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Seed the generator for repeatable message contents
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Send the traffic and wait for every response
+    pub async fn run(&self, pool: Arc<EndpointPool>) -> Result<LoadReport, HL7Error> {
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            return Err(HL7Error::InvalidStructure(format!("Invalid load test rate: {}", self.rate)));
+        }
+        if self.message_types.is_empty() {
+            return Err(HL7Error::InvalidStructure("No message types to send".to_string()));
+        }
+        let count = (self.rate * self.duration.as_secs_f64()).round() as usize;
+        info!(
+            "Load testing {:?} at {}/s for {:?}: {} messages",
+            pool.addresses(),
+            self.rate,
+            self.duration,
+            count
+        );
+
+        let mut generator = Generator::new(self.seed);
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate));
+        // A run that falls behind keeps its pace rather than catching up in a burst
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut in_flight = JoinSet::new();
+        let started = Instant::now();
+
+        for index in 0..count {
+            let message_type = &self.message_types[index % self.message_types.len()];
+            let message = generator.generate(message_type)?.to_hl7();
+            ticker.tick().await;
+            let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+            let pool = pool.clone();
+            in_flight.spawn(async move {
+                let sent = Instant::now();
+                let response = pool.send(&message).await;
+                drop(permit);
+                (sent.elapsed(), response)
+            });
+        }
+
+        let mut latencies = Vec::with_capacity(count);
+        let mut failures: HashMap<String, usize> = HashMap::new();
+        let (mut accepted, mut rejected, mut errors) = (0, 0, 0);
+        while let Some(result) = in_flight.join_next().await {
+            let (latency, response) = result.map_err(|e| HL7Error::DeliveryError(e.to_string()))?;
+            match classify(response) {
+                Outcome::Accepted => {
+                    accepted += 1;
+                    latencies.push(latency);
+                }
+                Outcome::Rejected(code) => {
+                    rejected += 1;
+                    latencies.push(latency);
+                    *failures.entry(code).or_default() += 1;
+                }
+                Outcome::Error(reason) => {
+                    errors += 1;
+                    *failures.entry(reason).or_default() += 1;
+                }
+            }
+        }
+        let elapsed = started.elapsed();
+
+        let mut failures: Vec<Count> = failures.into_iter().map(|(value, count)| Count { value, count }).collect();
+        failures.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+
+        let report = LoadReport {
+            sent: count,
+            accepted,
+            rejected,
+            errors,
+            elapsed,
+            throughput: accepted as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            latency: summarize(latencies),
+            failures,
+        };
+        info!(
+            "Load test finished: {} of {} accepted, {:.1}/s",
+            report.accepted, report.sent, report.throughput
+        );
+        Ok(report)
+    }
+}
+
+enum Outcome {
+    Accepted,
+    Rejected(String),
+    Error(String),
+}
+
+fn classify(response: Result<String, MllpError>) -> Outcome {
+    let response = match response {
+        Ok(response) => response,
+        Err(MllpError::Timeout(_)) => return Outcome::Error("timeout".to_string()),
+        Err(e) => return Outcome::Error(e.to_string()),
+    };
+    match Message::parse(&response).ok().and_then(|ack| terser::get(&ack, "MSA-1")) {
+        Some(code) if code == "AA" || code == "CA" => Outcome::Accepted,
+        Some(code) if !code.is_empty() => Outcome::Rejected(code),
+        _ => Outcome::Error("response without MSA".to_string()),
+    }
+}
+
+fn summarize(mut latencies: Vec<Duration>) -> Latency {
+    if latencies.is_empty() {
+        return Latency::default();
+    }
+    latencies.sort_unstable();
+    let n = latencies.len();
+    let percentile = |p: usize| latencies[((n * p).div_ceil(100)).clamp(1, n) - 1];
+    Latency {
+        min: latencies[0],
+        mean: latencies.iter().sum::<Duration>() / n as u32,
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: latencies[n - 1],
+    }
+}
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
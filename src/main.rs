@@ -1,15 +1,51 @@
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use rust_hl7::{
-    mllp::{MllpError, MllpServer},
+    access::{AccessPolicy, ScopedArchive},
+    attachments::{FileBlobStore, OffloadStore},
+    audit::{self, AuditLog},
+    capture::{self, CaptureReplay, CaptureWriter, Timing},
+    charset::{self, Charset},
+    clock::ReservedIds,
+    compress::{Codec, CompressedStore},
+    config::{LogFormat, LogRotation, LoggingConfig, ServerConfig, Supervisor, TelemetryConfig},
+    deident::Deidentifier,
+    diff::DiffOptions,
+    filedrop,
+    generate::{self, Generator},
+    loadtest::{LoadReport, LoadTest},
+    health::{Check, HealthServer, Readiness},
+    jobs::Job,
+    mllp::{AckMode, AckOptions, MllpClient, MllpError, MllpServer},
+    protect::{FieldPolicy, KeyProvider, ProtectedStore, StaticKeys},
+    proxy::Proxy,
+    recovery::{StateJournal, Unanswered},
+    replay::{Replay, ReplayTarget},
+    report::ErrorReporter,
+    retention::{Files, Retention, RetentionPolicy},
+    router::{Destination, EndpointPool, Strategy},
+    stats::{Stats, StatsReport},
+    store::{self, MessageStore, Query, SqliteStore},
+    terser,
+    transform::Pipeline,
+    validation::{Issue, Profile, Validator},
     Message, HL7Error, adt::AdtMessage, oru::OruMessage, rde::RdeMessage,
 };
+#[cfg(feature = "s3")]
+use rust_hl7::objectstore::ObjectArchive;
 use std::sync::Arc;
 use std::fs;
-use std::path::Path;
-use std::time::{Duration, SystemTime};
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tracing::{error, info, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 use tracing_appender::{rolling, non_blocking};
+use tracing_appender::non_blocking::WorkerGuard;
 use tracing_appender::rolling::Rotation;
 
 #[derive(Parser)]
@@ -22,70 +58,2013 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Parse and display HL7 messages (demo)
-    Parse,
+    /// Parse and display HL7 messages from a file or stdin, or the built-in samples
+    Parse {
+        /// File of one or more messages; "-" reads stdin. Without it, piped stdin is
+        /// read, or the built-in samples are parsed when stdin is a terminal
+        #[arg(long)]
+        file: Option<PathBuf>,
+
+        /// How to print each message
+        #[arg(long, default_value = "text", value_parser = ["json", "text", "summary", "pretty"])]
+        output: String,
+    },
     
+    /// Validate every message in a file, exiting 1 if any fail and 2 if the input can't be read
+    Validate {
+        /// File of one or more messages; "-" reads stdin
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Require MSH-12 to be this version, e.g. "2.5"
+        #[arg(long)]
+        version: Option<String>,
+
+        /// Also check messages against this JSON conformance profile
+        #[arg(long)]
+        profile: Option<PathBuf>,
+
+        /// How to print the report
+        #[arg(long, default_value = "text", value_parser = ["json", "text"])]
+        format: String,
+
+        /// Fail messages that only have warnings
+        #[arg(long)]
+        warnings_as_errors: bool,
+    },
+
+    /// Compare two files message by message, exiting 1 if any values differ and 2 if either
+    /// can't be read
+    Diff {
+        /// The expected messages, e.g. output captured before an interface upgrade
+        left: PathBuf,
+
+        /// The messages to check against them
+        right: PathBuf,
+
+        /// Also ignore this segment, field or component, e.g. "OBX-14" or "EVN-2"; repeatable
+        #[arg(long)]
+        ignore: Vec<String>,
+
+        /// Compare MSH-7 and MSH-10 too
+        #[arg(long)]
+        exact: bool,
+
+        /// How to print the differences
+        #[arg(long, default_value = "text", value_parser = ["json", "text"])]
+        format: String,
+    },
+
+    /// Summarize a message file or archive: counts, sizes, timestamp ranges and errors
+    Stats {
+        /// File of one or more messages; "-" reads stdin
+        #[arg(long = "in", required_unless_present = "archive", conflicts_with = "archive")]
+        input: Option<PathBuf>,
+
+        /// Summarize the inbound messages in a SQLite archive written by `server --archive`
+        #[arg(long)]
+        archive: Option<PathBuf>,
+
+        /// Validate each message and list the most common errors
+        #[arg(long)]
+        validate: bool,
+
+        /// Validate against this version of MSH-12
+        #[arg(long, requires = "validate")]
+        version: Option<String>,
+
+        /// Also validate against this JSON conformance profile
+        #[arg(long, requires = "validate")]
+        profile: Option<PathBuf>,
+
+        /// How to print the summary
+        #[arg(long, default_value = "text", value_parser = ["json", "text"])]
+        format: String,
+    },
+
+    /// Convert messages between ER7, canonical JSON, XML and FHIR
+    Convert {
+        /// Format of the input: ER7, canonical JSON documents, or FHIR bundles/resources
+        #[arg(long, default_value = "er7", value_parser = ["er7", "json", "fhir"])]
+        from: String,
+
+        /// Format to write
+        #[arg(long, value_parser = ["er7", "json", "xml", "fhir"])]
+        to: String,
+
+        /// File to convert; "-" reads stdin
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        /// Directory to write one file per message to; without it output goes to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Print randomized but realistic messages for load testing and demos
+    Generate {
+        /// Type of message to generate
+        #[arg(long = "type", value_parser = generate::MESSAGE_TYPES)]
+        message_type: String,
+
+        /// Number of messages
+        #[arg(long, default_value_t = 1)]
+        count: usize,
+
+        /// Seed for repeatable output; random when not given
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Time the generated timestamps lead up to, as YYYYMMDD[HHMMSS] in UTC; defaults to now
+        #[arg(long, value_parser = parse_start)]
+        start: Option<DateTime<Utc>>,
+    },
+
+    /// Replace patient identifiers with consistent pseudonyms and shift dates, for sharing samples
+    Anonymize {
+        /// File to scrub; "-" reads stdin
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        /// File to write the scrubbed messages to; "-" or leaving it out prints them
+        #[arg(long)]
+        out: Option<PathBuf>,
+
+        /// Secret the pseudonyms and date shift are derived from; defaults to the
+        /// RUST_HL7_DEIDENT_KEY environment variable, or a random key for this run
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Leave dates as they are
+        #[arg(long)]
+        keep_dates: bool,
+
+        /// Move each patient's dates by their own number of days
+        #[arg(long, conflicts_with = "keep_dates")]
+        per_patient_dates: bool,
+    },
+
+    /// Browse, query, edit and resend the messages in a file at an interactive prompt
+    Inspect {
+        /// File of one or more messages
+        file: PathBuf,
+    },
+
+    /// Send messages to an MLLP endpoint and print each ACK/NACK
+    Send {
+        /// Address of the endpoint, e.g. "lab.example.org:2575"
+        #[arg(long)]
+        host: String,
+
+        /// File of one or more messages; "-" reads stdin
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Connect with TLS, trusting the system's CA certificates
+        #[arg(long)]
+        tls: bool,
+
+        /// Also trust the CA certificates in this PEM file
+        #[arg(long, requires = "tls")]
+        ca_file: Option<PathBuf>,
+
+        /// How long to wait for each ACK, e.g. "5s", "500ms" or "1m"
+        #[arg(long, default_value = "5s", value_parser = parse_duration)]
+        wait_ack_timeout: Duration,
+
+        /// Send the file's messages this many times
+        #[arg(long, default_value_t = 1)]
+        repeat: usize,
+
+        /// Save how far sending got in this journal, and carry on from there if run again
+        /// after being interrupted
+        #[arg(long)]
+        state: Option<PathBuf>,
+    },
+
+    /// Send synthetic traffic at a fixed rate and report throughput, ACK latency and errors
+    Bench {
+        /// Address to send to, e.g. "engine.example.org:2575"; repeat to spread the load round-robin
+        #[arg(long, required = true)]
+        target: Vec<String>,
+
+        /// Messages per second, e.g. "500/s", "30/m" or "500"
+        #[arg(long, default_value = "100/s", value_parser = parse_rate)]
+        rate: f64,
+
+        /// How long to send for, e.g. "60s" or "5m"
+        #[arg(long, default_value = "60s", value_parser = parse_duration)]
+        duration: Duration,
+
+        /// Type of message to send; repeat to send several in turn; defaults to every generated type
+        #[arg(long = "type", value_parser = generate::MESSAGE_TYPES)]
+        message_types: Vec<String>,
+
+        /// Most messages waiting for an ACK at once
+        #[arg(long, default_value_t = 256)]
+        concurrency: usize,
+
+        /// How long to wait for each ACK
+        #[arg(long, default_value = "5s", value_parser = parse_duration)]
+        wait_ack_timeout: Duration,
+
+        /// Seed for repeatable message contents
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// How to print the report
+        #[arg(long, default_value = "text", value_parser = ["json", "text"])]
+        format: String,
+    },
+
     /// Start the MLLP server
+    #[command(group = clap::ArgGroup::new("archives").args(["archive", "archive_url"]))]
     Server {
         /// Address to bind the server to
         #[arg(short, long, default_value = "0.0.0.0:2575")] // Note: original = 127.0.0.1, only accept conn from localhost
         address: String,
+
+        /// Charset assumed when a message doesn't declare one in MSH-18 (e.g. "8859/1")
+        #[arg(long, default_value = "UNICODE UTF-8")]
+        charset: String,
+
+        /// When to acknowledge messages
+        #[arg(long, default_value = "after-processing", value_parser = ["immediate", "after-processing", "application"])]
+        ack_mode: String,
+
+        /// Include the processing latency in MSA-3
+        #[arg(long)]
+        ack_latency: bool,
+
+        /// Server identity to include in MSA-3
+        #[arg(long)]
+        server_identity: Option<String>,
+
+        /// Load listeners, TLS, destinations, routes, logging and telemetry from a
+        /// TOML/YAML/JSON file instead of the flags above; the file is re-applied on
+        /// SIGHUP or when it changes, except for logging and telemetry
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// Archive every received and sent message to this SQLite database
+        #[arg(long)]
+        archive: Option<PathBuf>,
+
+        /// Archive every received and sent message as hourly batches of objects under this URL
+        /// instead, e.g. "s3://bucket/hl7", compressed as --compress says or else with gzip; S3
+        /// settings come from AWS_* variables. A capture file is uploaded there on shutdown
+        #[arg(long, requires = "archive_spool", conflicts_with_all = ["archive", "retention_max_messages", "retention_max_bytes", "secure_delete", "offload_min_bytes"])]
+        archive_url: Option<String>,
+
+        /// Keep messages for --archive-url in this local file until they're uploaded, so
+        /// they survive a crash; messages left there are uploaded on the next start
+        #[arg(long, requires = "archive_url")]
+        archive_spool: Option<PathBuf>,
+
+        /// Delete archived messages older than this many days
+        #[arg(long, requires = "archives")]
+        retention_days: Option<u64>,
+
+        /// Keep at most this many archived messages, deleting the oldest first
+        #[arg(long, requires = "archive")]
+        retention_max_messages: Option<usize>,
+
+        /// Keep at most this many bytes of archived messages, deleting the oldest first
+        #[arg(long, requires = "archive")]
+        retention_max_bytes: Option<u64>,
+
+        /// Overwrite archived messages as they're deleted
+        #[arg(long, requires = "archive")]
+        secure_delete: bool,
+
+        /// Encrypt the value at this path before archiving it, e.g. "PID-19"; the key is
+        /// 64 hex digits in RUST_HL7_ARCHIVE_KEY (ID in RUST_HL7_ARCHIVE_KEY_ID, default "1")
+        #[arg(long, requires = "archives")]
+        encrypt_field: Vec<String>,
+
+        /// Replace the value at this path with REDACTED before archiving it, e.g. "PID-5"
+        #[arg(long, requires = "archives")]
+        redact_field: Vec<String>,
+
+        /// Move embedded documents (ED data in OBX-5.5) of at least this many bytes out of the
+        /// archive into a directory next to it, named after it with the extension "blobs"
+        #[arg(long, requires = "archive")]
+        offload_min_bytes: Option<usize>,
+
+        /// Serve /healthz and /readyz on this address, e.g. "0.0.0.0:8080"
+        #[arg(long)]
+        health: Option<String>,
+
+        /// Make /healthz loop a synthetic message through a loopback listener
+        #[arg(long, requires = "health")]
+        self_test: bool,
+
+        /// Append who sent each message, about which patients, and what became of it to this
+        /// hash-chained audit log
+        #[arg(long)]
+        audit: Option<PathBuf>,
+
+        /// Append every frame received and every response sent to this capture file, for
+        /// `replay-capture`
+        #[arg(long)]
+        capture: Option<PathBuf>,
+
+        /// Compress new archived messages and capture files with this codec
+        #[arg(long, default_value = "none", value_parser = ["none", "gzip", "zstd"])]
+        compress: String,
+
+        /// Keep messages in this journal until they're handled and answered, so those a
+        /// crash interrupts are dealt with on the next start; ACK control IDs carry on
+        /// counting from the journal too
+        #[arg(long)]
+        state: Option<PathBuf>,
+
+        /// What to do on start with messages a crash left unanswered: leave them for
+        /// the sender to resend, or handle them again
+        #[arg(long, default_value = "await-resend", value_parser = ["await-resend", "reprocess"], requires = "state")]
+        unanswered: String,
+    },
+
+    /// Relay MLLP traffic to another endpoint, recording every request and response
+    Proxy {
+        /// Address to accept connections on
+        #[arg(long, default_value = "0.0.0.0:2575")]
+        listen: String,
+
+        /// Address to relay messages to, e.g. "lab.example.org:2575"
+        #[arg(long)]
+        forward: String,
+
+        /// Append each exchange with its timestamps to daily JSON lines files in this directory
+        #[arg(long)]
+        record: Option<PathBuf>,
+
+        /// Append the raw frames relayed in both directions to this capture file, for `replay-capture`
+        #[arg(long)]
+        capture: Option<PathBuf>,
+
+        /// Compress a new capture file with this codec
+        #[arg(long, default_value = "none", value_parser = ["none", "gzip", "zstd"], requires = "capture")]
+        compress: String,
+
+        /// How long to wait for the upstream to respond, e.g. "30s"
+        #[arg(long, default_value = "30s", value_parser = parse_duration)]
+        timeout: Duration,
+    },
+
+    /// Look up archived messages as a role, seeing only the types and values it's allowed
+    Lookup {
+        /// SQLite archive written by `server --archive`
+        #[arg(long)]
+        archive: PathBuf,
+
+        /// File of roles (TOML, YAML or JSON), each listing its message types and masked fields
+        #[arg(long)]
+        roles: PathBuf,
+
+        /// Role to look up messages as
+        #[arg(long)]
+        role: String,
+
+        /// Only messages archived at or after this time (RFC 3339)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only messages archived before this time (RFC 3339)
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+
+        /// Only messages whose type starts with this, e.g. "ORU" or "ADT^A08"
+        #[arg(long = "type")]
+        message_type: Option<String>,
+
+        /// Only messages with this control ID (MSH-10)
+        #[arg(long)]
+        control_id: Option<String>,
+
+        /// Only messages about this patient (PID-3)
+        #[arg(long)]
+        patient: Option<String>,
+
+        /// Show at most this many messages
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+
+        /// How to print the messages
+        #[arg(long, default_value = "text", value_parser = ["json", "text"])]
+        format: String,
+    },
+
+    /// Resend archived messages through the configured routes or to one destination
+    Replay {
+        /// SQLite archive written by `server --archive`
+        #[arg(long)]
+        archive: PathBuf,
+
+        /// Send to this MLLP address
+        #[arg(long, required_unless_present = "config", conflicts_with = "config")]
+        to: Option<String>,
+
+        /// Route through this server config's routes
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// Send to this destination from the config instead of routing
+        #[arg(long, requires = "config")]
+        destination: Option<String>,
+
+        /// Only messages archived at or after this time (RFC 3339)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only messages archived before this time (RFC 3339)
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+
+        /// Only messages whose type starts with this, e.g. "ORU" or "ADT^A08"
+        #[arg(long = "type")]
+        message_type: Option<String>,
+
+        /// Only messages from this sending application (MSH-3)
+        #[arg(long)]
+        sender: Option<String>,
+
+        /// Only messages about this patient (PID-3)
+        #[arg(long)]
+        patient: Option<String>,
+
+        /// Replay at most this many messages
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Give each message a new MSH-10 control ID and MSH-7 timestamp
+        #[arg(long)]
+        regenerate_ids: bool,
+    },
+
+    /// Run a transform over archived messages and send the ones it changes, resuming from a checkpoint
+    Reprocess {
+        /// SQLite archive written by `server --archive`
+        #[arg(long)]
+        archive: PathBuf,
+
+        /// Transform steps to apply, as a JSON or YAML file
+        #[arg(long)]
+        transforms: PathBuf,
+
+        /// Send changed messages to this MLLP address
+        #[arg(long, conflicts_with = "config")]
+        to: Option<String>,
+
+        /// Route changed messages through this server config's routes
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// Send to this destination from the config instead of routing
+        #[arg(long, requires = "config")]
+        destination: Option<String>,
+
+        /// Only messages archived at or after this time (RFC 3339)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only messages archived before this time (RFC 3339)
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+
+        /// Only messages whose type starts with this, e.g. "ORU" or "ADT^A08"
+        #[arg(long = "type")]
+        message_type: Option<String>,
+
+        /// Only messages from this sending application (MSH-3)
+        #[arg(long)]
+        sender: Option<String>,
+
+        /// Only messages about this patient (PID-3)
+        #[arg(long)]
+        patient: Option<String>,
+
+        /// Process at most this many messages
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// Save progress to this file and resume from it if it exists
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+
+        /// Process this many messages at once
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+
+        /// Print what would change without sending anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Post a range of the archive to a FHIR server as transaction bundles, resuming from a checkpoint
+    BackfillFhir {
+        /// SQLite archive written by `server --archive`
+        #[arg(long)]
+        archive: PathBuf,
+
+        /// Base URL of the FHIR server, e.g. https://ehr.example.org/fhir/r4
+        #[arg(long)]
+        to: String,
+
+        /// Send this header with each request, as "Name: value"; can be repeated
+        #[arg(long)]
+        header: Vec<String>,
+
+        /// Messages in each bundle
+        #[arg(long, default_value_t = 100)]
+        bundle_size: usize,
+
+        /// Tries for each bundle before its messages are reported as failed
+        #[arg(long, default_value_t = 5)]
+        attempts: u32,
+
+        /// Only messages archived at or after this time (RFC 3339)
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only messages archived before this time (RFC 3339)
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+
+        /// Only messages whose type starts with this, e.g. "ORU" or "ADT^A08"
+        #[arg(long = "type")]
+        message_type: Option<String>,
+
+        /// Only messages from this sending application (MSH-3)
+        #[arg(long)]
+        sender: Option<String>,
+
+        /// Only messages about this patient (PID-3)
+        #[arg(long)]
+        patient: Option<String>,
+
+        /// Save progress to this file and resume from it if it exists
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+    },
+
+    /// Send the messages in a capture file again, comparing each ACK code with the captured one
+    ReplayCapture {
+        /// Capture file written by `server --capture` or `proxy --capture`
+        file: PathBuf,
+
+        /// Address to send to, e.g. "test-engine:2575"
+        #[arg(long)]
+        to: String,
+
+        /// Replay this many times faster than the messages were captured, e.g. "10"
+        #[arg(long, default_value_t = 1.0, conflicts_with = "no_delay")]
+        speed: f64,
+
+        /// Send each message as soon as the previous one is acknowledged
+        #[arg(long)]
+        no_delay: bool,
+
+        /// How long to wait for each ACK, e.g. "30s"
+        #[arg(long, default_value = "30s", value_parser = parse_duration)]
+        timeout: Duration,
+    },
+
+    /// Check an audit log's hash chain, exiting with an error at the first broken entry
+    VerifyAudit {
+        /// Audit log written by `server --audit`
+        file: PathBuf,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Set up sentry integration
-    let _guard = sentry::init(("https://605b19c1cb65a806857c8691e1bd5d53@o4508883257196544.ingest.us.sentry.io/4508933895028736", sentry::ClientOptions {
-        release: sentry::release_name!(),
-        ..Default::default()
-    }));
-
-    // Define log directory
-    let log_dir = "logs";
-    
-    // Clean up old log files (older than 7 days)
-    if let Err(e) = cleanup_old_logs(log_dir, 7) {
-        eprintln!("Warning: Failed to clean up old log files: {}", e);
+    let cli = Cli::parse();
+
+    // Telemetry and logging come from the server's config file, or the defaults plus
+    // any environment overrides
+    let settings = match &cli.command {
+        Commands::Server { config: Some(path), .. } => ServerConfig::load(path)?,
+        _ => {
+            let mut settings = ServerConfig::default();
+            settings.apply_env(|name| std::env::var(name).ok())?;
+            settings.validate()?;
+            settings
+        }
+    };
+    let reporter = init_telemetry(&settings.telemetry)?;
+    let _logging_guard = init_logging(&settings.logging, &settings.telemetry)?;
+
+    match cli.command {
+        Commands::Parse { file, output } => {
+            let input = match file {
+                Some(path) if path.as_os_str() != "-" => Some(read_messages(fs::read(&path)?)?),
+                Some(_) => Some(read_messages(read_stdin()?)?),
+                None if !std::io::stdin().is_terminal() => Some(read_messages(read_stdin()?)?),
+                None => None,
+            };
+            let messages = match &input {
+                Some(text) => filedrop::split_messages(text),
+                None => sample_messages().iter().map(|m| m.to_string()).collect(),
+            };
+            if messages.is_empty() {
+                return Err("No messages found in the input".into());
+            }
+            let failed = print_messages(&messages, &output, &mut std::io::stdout().lock())?;
+            if failed > 0 {
+                return Err(format!("{} of {} messages failed to parse", failed, messages.len()).into());
+            }
+        }
+        Commands::Validate { file, version, profile, format, warnings_as_errors } => {
+            // Exit codes let CI tell failing messages apart from unreadable input
+            match validate_file(&file, version, profile.as_deref(), &format, warnings_as_errors) {
+                Ok(0) => {}
+                Ok(_) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        Commands::Diff { left, right, ignore, exact, format } => {
+            let options = if exact { DiffOptions::exact() } else { DiffOptions::new() }.ignore_all(ignore);
+            match diff_files(&left, &right, &options, &format) {
+                Ok(0) => {}
+                Ok(_) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        Commands::Convert { from, to, input, out } => {
+            let (converted, failed) = convert_file(&input, &from, &to, out.as_deref())?;
+            if let Some(out) = &out {
+                eprintln!("Wrote {} files to {}", converted, out.display());
+            }
+            if failed > 0 {
+                return Err(format!("{} of {} messages failed to convert", failed, converted + failed).into());
+            }
+        }
+        Commands::Stats { input, archive, validate, version, profile, format } => {
+            let mut stats = Stats::new();
+            if validate {
+                let mut validator = Validator::new();
+                if let Some(version) = version {
+                    validator = validator.version(version);
+                }
+                if let Some(path) = profile {
+                    validator = validator.profile(Profile::from_json(&fs::read_to_string(path)?)?);
+                }
+                stats = stats.validate(validator);
+            }
+
+            match (input, archive) {
+                (Some(input), _) => {
+                    let bytes = if input.as_os_str() == "-" { read_stdin()? } else { fs::read(&input)? };
+                    for message in filedrop::split_messages(&read_messages(bytes)?) {
+                        stats.add(&message);
+                    }
+                }
+                (None, Some(archive)) => {
+                    let store = open_archive(&archive, FieldPolicy::new(), Codec::None, None)?;
+                    for record in store.query(&Query::new().direction(store::Direction::Inbound))? {
+                        let charset = charset::detect(&record.raw).unwrap_or_default();
+                        stats.add(&charset.decode(&record.raw).unwrap_or_else(|_| String::from_utf8_lossy(&record.raw).into_owned()));
+                    }
+                }
+                (None, None) => unreachable!("clap requires --in or --archive"),
+            }
+
+            let report = stats.report();
+            if format == "json" {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_stats(&report);
+            }
+        }
+        Commands::Generate { message_type, count, seed, start } => {
+            let seed = seed.unwrap_or_else(|| Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64);
+            let mut generator = Generator::new(seed);
+            if let Some(start) = start {
+                generator = generator.start(start);
+            }
+            for _ in 0..count {
+                println!("{}", generator.generate(&message_type)?.to_hl7().replace('\r', "\n"));
+            }
+        }
+        Commands::Anonymize { input, out, key, keep_dates, per_patient_dates } => {
+            let key = match key.or_else(|| std::env::var("RUST_HL7_DEIDENT_KEY").ok()) {
+                Some(key) => key,
+                None => {
+                    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+                    eprintln!("No key given; pseudonyms won't match those from other runs");
+                    // Random, so the pseudonyms can't be reversed by guessing when the run started
+                    let mut key = [0u8; 32];
+                    OsRng.fill_bytes(&mut key);
+                    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+                }
+            };
+            let deidentifier = Deidentifier::new(key).shift_dates(!keep_dates).per_patient_dates(per_patient_dates);
+
+            let bytes = if input.as_os_str() == "-" { read_stdin()? } else { fs::read(&input)? };
+            let messages = filedrop::split_messages(&read_messages(bytes)?);
+            if messages.is_empty() {
+                return Err("No messages found in the input".into());
+            }
+
+            // Messages that don't parse are left out rather than passed through unscrubbed
+            let to_file = out.as_ref().is_some_and(|out| out.as_os_str() != "-");
+            let mut output = String::new();
+            let mut failed = 0;
+            for (index, text) in messages.iter().enumerate() {
+                match Message::parse(text) {
+                    Ok(mut message) => {
+                        deidentifier.apply(&mut message);
+                        let text = message.to_hl7();
+                        if to_file {
+                            output.push_str(&text);
+                            output.push('\r');
+                        } else {
+                            println!("{}", text.replace('\r', "\n"));
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Message {}: {}; left out", index + 1, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if let Some(out) = out.filter(|_| to_file) {
+                fs::write(&out, output)?;
+                eprintln!("Wrote {} messages to {}", messages.len() - failed, out.display());
+            }
+            if failed > 0 {
+                return Err(format!("{} of {} messages failed to parse", failed, messages.len()).into());
+            }
+        }
+        Commands::Inspect { file } => inspect(&file).await?,
+        Commands::Send { host, file, tls, ca_file, wait_ack_timeout, repeat, state } => {
+            let bytes = if file.as_os_str() == "-" { read_stdin()? } else { fs::read(&file)? };
+            let messages = filedrop::split_messages(&read_messages(bytes)?);
+            if messages.is_empty() {
+                return Err("No messages found in the input".into());
+            }
+
+            let client = MllpClient::new(&host).with_timeout(wait_ack_timeout);
+            let client = match tls {
+                #[cfg(feature = "tls")]
+                true => client.with_tls(rust_hl7::tls::client_config(ca_file.as_deref())?),
+                #[cfg(not(feature = "tls"))]
+                true => {
+                    let _ = ca_file;
+                    return Err("--tls requires the tls feature".into());
+                }
+                false => client,
+            };
+
+            // The position is kept per file and endpoint, so one journal can serve several sends
+            let resume = match state {
+                Some(path) => Some((StateJournal::open(path)?, format!("send {} -> {}", file.display(), host))),
+                None => None,
+            };
+            let not_accepted = send_messages(&client, &messages, repeat, resume.as_ref().map(|(journal, name)| (journal, name.as_str()))).await?;
+            if not_accepted > 0 {
+                return Err(format!("{} of {} messages were not accepted", not_accepted, messages.len() * repeat).into());
+            }
+        }
+        Commands::Bench { target, rate, duration, message_types, concurrency, wait_ack_timeout, seed, format } => {
+            let clients = target.iter().map(|address| MllpClient::new(address).with_timeout(wait_ack_timeout));
+            let pool = Arc::new(EndpointPool::new(clients, Strategy::RoundRobin));
+            let mut load_test = LoadTest::new(rate, duration).concurrency(concurrency).seed(seed);
+            if !message_types.is_empty() {
+                load_test = load_test.message_types(message_types);
+            }
+
+            let report = load_test.run(pool).await?;
+            if format == "json" {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_load_report(&report, rate);
+            }
+        }
+        Commands::Server { config: Some(config), .. } => {
+            info!("Starting config-driven server from {}", config.display());
+            log_retention(&settings.logging).spawn(Duration::from_secs(3600));
+            let supervisor = Supervisor::new(config);
+            let supervisor = match reporter {
+                Some(reporter) => supervisor.with_error_reporter(reporter),
+                None => supervisor,
+            };
+            supervisor.run().await?;
+        }
+        Commands::Server { address, charset, ack_mode, ack_latency, server_identity, config: None, archive, archive_url, archive_spool, retention_days, retention_max_messages, retention_max_bytes, secure_delete, encrypt_field, redact_field, offload_min_bytes, health, self_test, audit, capture, compress, state, unanswered } => {
+            let charset = Charset::from_hl7(&charset)
+                .ok_or_else(|| format!("Unsupported charset: {}", charset))?;
+            let codec = Codec::parse(&compress).unwrap_or_default();
+            let ack_options = AckOptions {
+                mode: match ack_mode.as_str() {
+                    "immediate" => AckMode::Immediate,
+                    "application" => AckMode::Application,
+                    _ => AckMode::AfterProcessing,
+                },
+                include_latency: ack_latency,
+                server_identity,
+            };
+            let mut server = MllpServer::builder()
+                .bind(&address)
+                .handler(Arc::new(log_and_echo))
+                .default_charset(charset)
+                .ack_options(ack_options)
+                .build()?;
+            // The health server reports on the checks added as the server is set up
+            let readiness = Readiness::new();
+            readiness.add(Check::Listener { address: address.clone(), status: server.status() });
+            let mut retention = log_retention(&settings.logging);
+            if let Some(path) = archive {
+                let policy = encrypt_field.iter().try_fold(FieldPolicy::new(), |policy, path| policy.encrypt(path))?;
+                let policy = redact_field.iter().try_fold(policy, |policy, path| policy.redact(path))?;
+                let store = open_archive(&path, policy, codec, offload_min_bytes)?;
+                info!("Archiving messages to {}", path.display());
+                retention = retention.add(
+                    store.clone(),
+                    RetentionPolicy {
+                        max_age_days: retention_days,
+                        max_count: retention_max_messages,
+                        max_bytes: retention_max_bytes,
+                        secure_delete,
+                    },
+                );
+                readiness.add(Check::Store(store.clone()));
+                server = server.with_archive(store);
+            }
+            #[cfg(feature = "s3")]
+            let mut object_archive = None;
+            if let Some(url) = archive_url {
+                #[cfg(not(feature = "s3"))]
+                {
+                    let _ = archive_spool;
+                    return Err(format!("--archive-url {} requires the s3 feature", url).into());
+                }
+                #[cfg(feature = "s3")]
+                {
+                    let policy = encrypt_field.iter().try_fold(FieldPolicy::new(), |policy, path| policy.encrypt(path))?;
+                    let policy = redact_field.iter().try_fold(policy, |policy, path| policy.redact(path))?;
+                    let codec = if codec == Codec::None { Codec::Gzip } else { codec };
+                    let spool = archive_spool.ok_or("--archive-url requires --archive-spool")?;
+                    let archive = Arc::new(ObjectArchive::open(&url, spool)?.codec(codec).max_wait(Duration::from_secs(10)));
+                    archive.spawn_flushing(Duration::from_secs(1));
+                    let store: Arc<dyn MessageStore> = Arc::new(ProtectedStore::new(archive.clone(), archive_keys(policy)?)?);
+                    info!("Archiving messages to {}", url);
+                    retention = retention.add(
+                        store.clone(),
+                        RetentionPolicy { max_age_days: retention_days, max_count: None, max_bytes: None, secure_delete: false },
+                    );
+                    readiness.add(Check::Store(store.clone()));
+                    server = server.with_archive(store);
+                    object_archive = Some(archive);
+                }
+            }
+            if let Some(path) = audit {
+                info!("Auditing messages to {}", path.display());
+                server = server.with_audit(Arc::new(AuditLog::open(path)?));
+            }
+            if let Some(path) = &capture {
+                info!("Capturing traffic to {}", path.display());
+                server = server.with_capture(Arc::new(CaptureWriter::open_compressed(path, codec)?));
+            }
+            if let Some(path) = state {
+                info!("Journaling in-flight messages to {}", path.display());
+                let mut journal = StateJournal::open(path)?;
+                // Messages are journaled whole, so they get the archive's keys and retention
+                match journal_keys()? {
+                    Some(keys) => journal = journal.with_keys(keys)?,
+                    None if !encrypt_field.is_empty() || !redact_field.is_empty() => {
+                        return Err("--state with --encrypt-field or --redact-field needs RUST_HL7_ARCHIVE_KEY to encrypt the journal".into());
+                    }
+                    None => {}
+                }
+                if let Some(days) = retention_days {
+                    journal = journal.with_max_age(Duration::from_secs(days.saturating_mul(24 * 60 * 60)))?;
+                }
+                let journal = Arc::new(journal);
+                let unanswered = if unanswered == "reprocess" { Unanswered::Reprocess } else { Unanswered::AwaitResend };
+                server = server
+                    .with_id_source(Arc::new(ReservedIds::new(journal.clone(), "ack", "ACK")))
+                    .with_recovery(journal, unanswered);
+            }
+            if let Some(reporter) = reporter {
+                server = server.with_error_reporter(reporter);
+            }
+            let health = health.map(|address| HealthServer::new(address, readiness).with_self_test(self_test));
+            retention.spawn(Duration::from_secs(3600));
+            #[cfg(feature = "s3")]
+            if let Some(archive) = object_archive {
+                // Write out waiting messages before going, rather than losing them
+                tokio::select! {
+                    result = run_mllp_server(&address, server, health) => result?,
+                    _ = shutdown_signal() => info!("Shutting down"),
+                }
+                let count = tokio::task::spawn_blocking({
+                    let archive = archive.clone();
+                    move || archive.flush()
+                })
+                .await??;
+                info!("Wrote {} waiting archived messages", count);
+                if let Some(path) = capture {
+                    tokio::task::spawn_blocking(move || archive.put_capture(path)).await??;
+                }
+                return Ok(());
+            }
+            run_mllp_server(&address, server, health).await?;
+        }
+        Commands::Proxy { listen, forward, record, capture, compress, timeout } => {
+            let proxy = Proxy::new(listen, forward).with_timeout(timeout);
+            let proxy = match record {
+                Some(directory) => proxy.record(directory),
+                None => proxy,
+            };
+            let proxy = match capture {
+                Some(path) => proxy.capture(Arc::new(CaptureWriter::open_compressed(
+                    path,
+                    Codec::parse(&compress).unwrap_or_default(),
+                )?)),
+                None => proxy,
+            };
+            proxy.run().await?;
+        }
+        Commands::Lookup {
+            archive,
+            roles,
+            role,
+            since,
+            until,
+            message_type,
+            control_id,
+            patient,
+            limit,
+            format,
+        } => {
+            let archive = ScopedArchive::new(open_archive(&archive, FieldPolicy::new(), Codec::None, None)?, AccessPolicy::load(roles)?);
+            let mut query = Query::new();
+            query.since = since;
+            query.until = until;
+            query.message_type = message_type;
+            query.control_id = control_id;
+            query.patient_id = patient;
+            query.limit = Some(limit);
+
+            for record in archive.query(&role, &query)? {
+                let text = charset::detect(&record.raw).unwrap_or_default().decode(&record.raw)?;
+                if format == "json" {
+                    let entry = serde_json::json!({
+                        "id": record.id,
+                        "timestamp": record.timestamp,
+                        "direction": record.direction.as_str(),
+                        "disposition": record.disposition.as_str(),
+                        "message_type": record.message_type,
+                        "control_id": record.control_id,
+                        "message": text,
+                    });
+                    println!("{}", entry);
+                } else {
+                    println!(
+                        "# {} {} {} {} {}",
+                        record.id.unwrap_or_default(),
+                        record.timestamp.to_rfc3339(),
+                        record.direction.as_str(),
+                        record.message_type.as_deref().unwrap_or("-"),
+                        record.control_id.as_deref().unwrap_or("-")
+                    );
+                    println!("{}\n", text.trim_end().replace('\r', "\n"));
+                }
+            }
+        }
+        Commands::Replay {
+            archive,
+            to,
+            config,
+            destination,
+            since,
+            until,
+            message_type,
+            sender,
+            patient,
+            limit,
+            regenerate_ids,
+        } => {
+            let store = open_archive(&archive, FieldPolicy::new(), Codec::None, None)?;
+            let target = replay_target(to, config, destination)?.expect("clap requires --to or --config");
+
+            let mut query = Query::new();
+            query.since = since;
+            query.until = until;
+            query.message_type = message_type;
+            query.sending_application = sender;
+            query.patient_id = patient;
+            query.limit = limit;
+
+            let report = Replay::new(query)
+                .regenerate_ids(regenerate_ids)
+                .run(store.as_ref(), &target)
+                .await?;
+            println!("Replayed {} of {} messages", report.sent, report.selected);
+            for failure in &report.failures {
+                println!(
+                    "  failed: archive id {:?}, control id {}: {}",
+                    failure.id,
+                    failure.control_id.as_deref().unwrap_or("-"),
+                    failure.error
+                );
+            }
+            if !report.failures.is_empty() {
+                return Err(format!("{} messages failed to replay", report.failures.len()).into());
+            }
+        }
+        Commands::Reprocess {
+            archive,
+            transforms,
+            to,
+            config,
+            destination,
+            since,
+            until,
+            message_type,
+            sender,
+            patient,
+            limit,
+            checkpoint,
+            concurrency,
+            dry_run,
+        } => {
+            let store = open_archive(&archive, FieldPolicy::new(), Codec::None, None)?;
+            let mut query = Query::new();
+            query.since = since;
+            query.until = until;
+            query.message_type = message_type;
+            query.sending_application = sender;
+            query.patient_id = patient;
+            query.limit = limit;
+
+            let mut job = Job::new(query, Arc::new(Pipeline::load(transforms)?))
+                .concurrency(concurrency)
+                .dry_run(dry_run);
+            match replay_target(to, config, destination)? {
+                Some(target) => job = job.target(target),
+                None if !dry_run => return Err("--to or --config is needed unless this is a --dry-run".into()),
+                None => {}
+            }
+            if let Some(path) = checkpoint {
+                job = job.checkpoint(path);
+            }
+
+            let report = job.run(store.as_ref()).await?;
+            for change in &report.changes {
+                println!("archive id {:?}, control id {}:", change.id, change.control_id.as_deref().unwrap_or("-"));
+                for difference in &change.differences {
+                    println!("  {}", difference);
+                }
+            }
+            for failure in &report.failures {
+                println!(
+                    "  failed: archive id {:?}, control id {}: {}",
+                    failure.id,
+                    failure.control_id.as_deref().unwrap_or("-"),
+                    failure.error
+                );
+            }
+            let sent = if dry_run { "nothing sent in a dry run".to_string() } else { format!("{} sent", report.sent) };
+            println!("Processed {} messages: {} changed, {} failed, {}", report.processed, report.changed, report.failed, sent);
+            if !report.failures.is_empty() {
+                return Err(format!("{} messages failed to reprocess", report.failures.len()).into());
+            }
+        }
+        Commands::BackfillFhir {
+            archive,
+            to,
+            header,
+            bundle_size,
+            attempts,
+            since,
+            until,
+            message_type,
+            sender,
+            patient,
+            checkpoint,
+        } => {
+            let store = open_archive(&archive, FieldPolicy::new(), Codec::None, None)?;
+            let mut query = Query::new();
+            query.since = since;
+            query.until = until;
+            query.message_type = message_type;
+            query.sending_application = sender;
+            query.patient_id = patient;
+            backfill_fhir(store.as_ref(), query, to, header, bundle_size, attempts, checkpoint).await?;
+        }
+        Commands::ReplayCapture { file, to, speed, no_delay, timeout } => {
+            let timing = match (no_delay, speed) {
+                (true, _) => Timing::Immediate,
+                (false, 1.0) => Timing::Original,
+                (false, speed) if speed > 0.0 => Timing::Accelerated(speed),
+                (false, speed) => return Err(format!("--speed must be positive, not {}", speed).into()),
+            };
+            let records = capture::read_capture(&file)?;
+            let report = CaptureReplay::new(records).timing(timing).with_timeout(timeout).run(&to).await;
+
+            for exchange in report.mismatches() {
+                println!(
+                    "{} {} from {}: expected {}, got {}",
+                    exchange.captured_at.format("%Y-%m-%d %H:%M:%S%.3f"),
+                    exchange.control_id.as_deref().unwrap_or("-"),
+                    exchange.peer,
+                    exchange.expected_ack.as_deref().unwrap_or("no response"),
+                    exchange.error.as_deref().or(exchange.ack.as_deref()).unwrap_or("no MSA"),
+                );
+            }
+            let mismatches = report.mismatches().count();
+            println!("Replayed {} messages to {}: {} answered as captured", report.sent(), to, report.sent() - mismatches);
+            if mismatches > 0 {
+                return Err(format!("{} messages were answered differently", mismatches).into());
+            }
+        }
+        Commands::VerifyAudit { file } => {
+            let entries = audit::verify(&file)?;
+            println!("{}: {} entries, chain intact", file.display(), entries);
+        }
     }
 
-    // Set up logging with file output
-    // Create a rotating logger that rotates daily
-    let file_appender = rolling::RollingFileAppender::new(
-        Rotation::DAILY,
-        log_dir,
-        "rust-hl7.log",
-    );
-    
+    Ok(())
+}
+
+/// Report errors to Sentry if a DSN is configured; events are sent until the reporter is dropped
+#[cfg(feature = "sentry")]
+fn init_telemetry(config: &TelemetryConfig) -> Result<Option<Arc<dyn ErrorReporter>>, Box<dyn std::error::Error>> {
+    let Some(dsn) = &config.sentry_dsn else {
+        return Ok(None);
+    };
+    let reporter = rust_hl7::report::SentryReporter::init(dsn, config.environment.as_deref(), config.sample_rate)?;
+    Ok(Some(Arc::new(reporter)))
+}
+
+/// Without the `sentry` feature errors are only logged; config validation rejects a DSN
+#[cfg(not(feature = "sentry"))]
+fn init_telemetry(_config: &TelemetryConfig) -> Result<Option<Arc<dyn ErrorReporter>>, Box<dyn std::error::Error>> {
+    Ok(None)
+}
+
+/// Keeps log lines and exported spans flowing until dropped
+struct LoggingGuard {
+    _writer: WorkerGuard,
+    #[cfg(feature = "otel")]
+    _export: Option<rust_hl7::otel::OtelExport>,
+}
+
+/// Log to rotating files, and export spans and metrics if an OTLP endpoint is configured
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn init_logging(config: &LoggingConfig, telemetry: &TelemetryConfig) -> Result<LoggingGuard, Box<dyn std::error::Error>> {
+    let rotation = match config.rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let file_appender = rolling::RollingFileAppender::new(rotation, &config.directory, &config.file_name);
+
     // Set up non-blocking writer
-    let (non_blocking_writer, _logging_guard) = non_blocking(file_appender);
-    
-    // Configure subscriber with non-blocking writer
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+    let (non_blocking_writer, guard) = non_blocking(file_appender);
+
+    // The level was checked when the config was validated
+    let level = config.level.parse().unwrap_or(Level::INFO);
+    let fmt = tracing_subscriber::fmt::layer()
         .with_writer(non_blocking_writer)
-        .with_ansi(false)  // This disables color codes
-        .finish();
-        
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set default subscriber");
+        .with_ansi(false);  // This disables color codes
 
-    let cli = Cli::parse();
+    // Events carry the spans they happened in, so one message's logs can be found by control ID
+    let fmt = match config.format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().with_span_list(true).boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(LevelFilter::from_level(level)).with(fmt);
 
-    match cli.command {
-        Commands::Parse => {
-            run_parse_demo();
+    #[cfg(feature = "otel")]
+    let export = telemetry
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| rust_hl7::otel::OtelExport::install(endpoint, &telemetry.service_name))
+        .transpose()?;
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(export.as_ref().map(|export| export.layer()));
+
+    subscriber.try_init().expect("Failed to set default subscriber");
+    // Clean up old log files, now that failures can be logged
+    log_retention(config).enforce();
+    Ok(LoggingGuard {
+        _writer: guard,
+        #[cfg(feature = "otel")]
+        _export: export,
+    })
+}
+
+/// Print a load test's results as text
+fn print_load_report(report: &LoadReport, rate: f64) {
+    println!(
+        "Sent {} messages in {:.1?}: {} accepted, {} rejected, {} errors",
+        report.sent, report.elapsed, report.accepted, report.rejected, report.errors
+    );
+    println!("Throughput: {:.1} accepted/s (target {:.1}/s)", report.throughput, rate);
+    let latency = &report.latency;
+    println!(
+        "ACK latency: min {:.1?}, mean {:.1?}, p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {:.1?}",
+        latency.min, latency.mean, latency.p50, latency.p90, latency.p99, latency.max
+    );
+    if !report.failures.is_empty() {
+        println!("Failures:");
+        for count in &report.failures {
+            println!("  {:>7}  {}", count.count, count.value);
+        }
+    }
+}
+
+/// Print a feed summary as text
+fn print_stats(report: &StatsReport) {
+    println!("Messages: {} ({} unparseable)", report.messages, report.unparseable);
+    if let Some(invalid) = report.invalid {
+        println!("Invalid: {}", invalid);
+    }
+    let sizes = &report.sizes;
+    println!(
+        "Size (bytes): min {}, p50 {}, p90 {}, p99 {}, max {}, total {}",
+        sizes.min, sizes.p50, sizes.p90, sizes.p99, sizes.max, sizes.total
+    );
+    println!(
+        "MSH-7 range: {} to {}",
+        report.first_timestamp.as_deref().unwrap_or("-"),
+        report.last_timestamp.as_deref().unwrap_or("-")
+    );
+
+    for (title, counts) in [
+        ("Message types", &report.message_types),
+        ("Trigger events", &report.events),
+        ("Sending facilities", &report.sending_facilities),
+        ("Top errors", &report.top_errors),
+    ] {
+        if counts.is_empty() {
+            continue;
+        }
+        println!("{}:", title);
+        for count in counts {
+            println!("  {:>7}  {}", count.count, count.value);
+        }
+    }
+}
+
+/// Parse a UTC time given as YYYYMMDD or YYYYMMDDHHMMSS
+fn parse_start(text: &str) -> Result<DateTime<Utc>, String> {
+    let time = match text.len() {
+        8 => chrono::NaiveDate::parse_from_str(text, "%Y%m%d").map(|date| date.and_time(chrono::NaiveTime::MIN)),
+        _ => chrono::NaiveDateTime::parse_from_str(text, "%Y%m%d%H%M%S"),
+    };
+    time.map(|time| time.and_utc()).map_err(|_| format!("Invalid time: {}", text))
+}
+
+/// Parse a duration such as "5s", "500ms", "2m" or "1h"; a bare number is seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid duration: {}", text))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("Invalid duration unit in {}; use ms, s, m or h", text)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Duration out of range: {}", text))
+}
+
+/// Parse a rate such as "500/s", "30/m" or "500" into messages per second
+fn parse_rate(text: &str) -> Result<f64, String> {
+    let text = text.trim();
+    let (number, per) = text.split_once('/').unwrap_or((text, "s"));
+    let number: f64 = number.trim().parse().map_err(|_| format!("Invalid rate: {}", text))?;
+    let per_second = match per.trim() {
+        "s" => number,
+        "m" => number / 60.0,
+        "h" => number / 3600.0,
+        _ => return Err(format!("Invalid rate unit in {}; use /s, /m or /h", text)),
+    };
+    if !(per_second.is_finite() && per_second > 0.0) {
+        return Err(format!("Rate must be positive: {}", text));
+    }
+    Ok(per_second)
+}
+
+/// Send each message `repeat` times, printing the outcome of each, and return
+/// how many weren't accepted with AA or CA
+async fn send_messages(
+    client: &MllpClient,
+    messages: &[String],
+    repeat: usize,
+    resume: Option<(&StateJournal, &str)>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let total = messages.len() * repeat;
+    let mut not_accepted = 0;
+    // Messages up to the saved position were sent by an earlier run that was interrupted
+    let start = resume.and_then(|(journal, name)| journal.cursor(name)).unwrap_or(0) as usize;
+    if start > 0 {
+        println!("Resuming after message {} of {}", start, total);
+    }
+
+    for (number, text) in (0..repeat).flat_map(|_| messages.iter()).enumerate().skip(start) {
+        let control_id = Message::parse(text)
+            .ok()
+            .and_then(|m| m.control_id().map(str::to_string))
+            .unwrap_or_else(|| "-".to_string());
+        let started = std::time::Instant::now();
+        let response = client.send(text).await;
+        let elapsed = started.elapsed();
+
+        let (accepted, outcome) = describe_ack(response);
+        if !accepted {
+            not_accepted += 1;
+        }
+        println!("{}/{} {} -> {} in {:?}", number + 1, total, control_id, outcome, elapsed);
+        if let Some((journal, name)) = resume {
+            journal.set_cursor(name, number as u64 + 1)?;
+        }
+    }
+
+    let sent = total - start.min(total);
+    println!("Sent {} messages to {}: {} accepted", sent, client.address(), sent - not_accepted);
+    // A finished send starts from the beginning next time
+    if let Some((journal, name)) = resume {
+        journal.set_cursor(name, 0)?;
+    }
+    Ok(not_accepted)
+}
+
+/// Compare the messages in two files in order and print the differences, returning
+/// how many messages differ
+///
+/// Messages only one file has count as differing.
+fn diff_files(left: &Path, right: &Path, options: &DiffOptions, format: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let read = |file: &Path| -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let bytes = if file.as_os_str() == "-" { read_stdin()? } else { fs::read(file)? };
+        filedrop::split_messages(&read_messages(bytes)?)
+            .iter()
+            .enumerate()
+            .map(|(index, text)| Message::parse(text).map_err(|e| format!("{} message {}: {}", file.display(), index + 1, e).into()))
+            .collect()
+    };
+    let (left, right) = (read(left)?, read(right)?);
+
+    let mut reports = Vec::new();
+    let mut differing = 0;
+    for index in 0..left.len().max(right.len()) {
+        let (differences, missing) = match (left.get(index), right.get(index)) {
+            (Some(l), Some(r)) => (options.compare(l, r), None),
+            (Some(_), None) => (Vec::new(), Some("only in the first file")),
+            (None, _) => (Vec::new(), Some("only in the second file")),
+        };
+        if differences.is_empty() && missing.is_none() {
+            continue;
+        }
+        differing += 1;
+        let control_id = left.get(index).or(right.get(index)).and_then(|m| m.control_id()).unwrap_or_default();
+
+        if format == "json" {
+            reports.push(serde_json::json!({
+                "message": index + 1,
+                "control_id": control_id,
+                "missing": missing,
+                "differences": differences,
+            }));
+        } else {
+            println!("Message {} ({}): {}", index + 1, control_id, missing.unwrap_or("differs"));
+            for difference in &differences {
+                println!("  {}", difference);
+            }
+        }
+    }
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+            "messages": left.len().max(right.len()),
+            "differing": differing,
+            "results": reports,
+        }))?);
+    } else {
+        println!("{} of {} messages differ", differing, left.len().max(right.len()));
+    }
+    Ok(differing)
+}
+
+/// Validate each message in a file and print a report, returning how many failed
+///
+/// `json` prints one document with each message's issues and a summary; `text`
+/// prints a line per message followed by its issues.
+fn validate_file(
+    file: &Path,
+    version: Option<String>,
+    profile: Option<&Path>,
+    format: &str,
+    warnings_as_errors: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let bytes = if file.as_os_str() == "-" { read_stdin()? } else { fs::read(file)? };
+    let messages = filedrop::split_messages(&read_messages(bytes)?);
+    if messages.is_empty() {
+        return Err("No messages found in the input".into());
+    }
+
+    let mut validator = Validator::new();
+    if let Some(version) = version {
+        validator = validator.version(version);
+    }
+    if let Some(path) = profile {
+        validator = validator.profile(Profile::from_json(&fs::read_to_string(path)?)?);
+    }
+
+    let mut reports = Vec::new();
+    let (mut failed, mut errors, mut warnings) = (0, 0, 0);
+    for (index, text) in messages.iter().enumerate() {
+        let (message, issues) = match Message::parse(text) {
+            Ok(message) => {
+                let issues = validator.validate(&message);
+                (Some(message), issues)
+            }
+            Err(e) => (None, vec![Issue::error("message", e.to_string())]),
+        };
+        let message_errors = issues.iter().filter(|i| i.is_error()).count();
+        let message_warnings = issues.len() - message_errors;
+        let valid = message_errors == 0 && (message_warnings == 0 || !warnings_as_errors);
+        errors += message_errors;
+        warnings += message_warnings;
+        if !valid {
+            failed += 1;
+        }
+
+        let control_id = message.as_ref().and_then(|m| m.control_id()).filter(|v| !v.is_empty()).map(str::to_string);
+        let message_type = message.as_ref().map(|m| m.message_type.clone());
+        if format == "json" {
+            reports.push(serde_json::json!({
+                "index": index + 1,
+                "control_id": control_id,
+                "message_type": message_type,
+                "valid": valid,
+                "errors": message_errors,
+                "warnings": message_warnings,
+                "issues": issues,
+            }));
+        } else {
+            println!(
+                "Message {} ({} {}): {}",
+                index + 1,
+                message_type.as_deref().unwrap_or("-"),
+                control_id.as_deref().unwrap_or("-"),
+                if valid { "valid" } else { "invalid" }
+            );
+            for issue in &issues {
+                println!("  {}", issue);
+            }
         }
-        Commands::Server { address } => {
-            run_mllp_server(&address).await?;
+    }
+
+    let total = messages.len();
+    if format == "json" {
+        let report = serde_json::json!({
+            "messages": reports,
+            "summary": {
+                "messages": total,
+                "valid": total - failed,
+                "invalid": failed,
+                "errors": errors,
+                "warnings": warnings,
+            },
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{} messages: {} valid, {} invalid, {} errors, {} warnings", total, total - failed, failed, errors, warnings);
+    }
+    Ok(failed)
+}
+
+/// Convert each message in a file, returning how many were converted and how many failed
+///
+/// ER7 input is split at each MSH segment; JSON and FHIR input is a sequence of
+/// JSON documents, such as the one-per-line output of `parse --output json`. With
+/// an output directory each message is written to `<input name>-<n>.<ext>`;
+/// otherwise messages are printed, ER7 with a newline after each segment.
+fn convert_file(input: &Path, from: &str, to: &str, out: Option<&Path>) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let bytes = if input.as_os_str() == "-" { read_stdin()? } else { fs::read(input)? };
+    let messages: Vec<Result<Message, String>> = match from {
+        "er7" => filedrop::split_messages(&read_messages(bytes)?)
+            .iter()
+            .map(|text| Message::parse(text).map_err(|e| e.to_string()))
+            .collect(),
+        _ => serde_json::Deserializer::from_slice(&bytes)
+            .into_iter::<serde_json::Value>()
+            .map(|document| {
+                let document = document.map_err(|e| format!("Invalid JSON: {}", e))?;
+                if from == "fhir" {
+                    fhir_to_message(&document)
+                } else {
+                    Message::from_json(&document.to_string()).map_err(|e| e.to_string())
+                }
+            })
+            .collect(),
+    };
+    if messages.is_empty() {
+        return Err("No messages found in the input".into());
+    }
+
+    if let Some(out) = out {
+        fs::create_dir_all(out)?;
+    }
+    let stem = match input.file_stem() {
+        Some(stem) if input.as_os_str() != "-" => stem.to_string_lossy().into_owned(),
+        _ => "stdin".to_string(),
+    };
+    let extension = match to {
+        "er7" => "hl7",
+        "fhir" => "fhir.json",
+        other => other,
+    };
+
+    let (mut converted, mut failed) = (0, 0);
+    for (index, message) in messages.into_iter().enumerate() {
+        let text = message.and_then(|message| match to {
+            "er7" if out.is_none() => Ok(message.to_hl7().replace('\r', "\n")),
+            "er7" => Ok(message.to_hl7() + "\r"),
+            "json" => Ok(message.to_json()),
+            "xml" => Ok(message.to_xml()),
+            _ => message_to_fhir(&message),
+        });
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("Message {}: {}", index + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+        match out {
+            Some(out) => fs::write(out.join(format!("{}-{:04}.{}", stem, index + 1, extension)), text)?,
+            None => println!("{}", text.trim_end()),
         }
+        converted += 1;
+    }
+    Ok((converted, failed))
+}
+
+/// Build an ADT^A08 or ORU^R01 from a FHIR bundle or resource
+#[cfg(feature = "fhir")]
+fn fhir_to_message(document: &serde_json::Value) -> Result<Message, String> {
+    rust_hl7::fhir::to_message(document, rust_hl7::MessageBuilder::new("")).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "fhir"))]
+fn fhir_to_message(_: &serde_json::Value) -> Result<Message, String> {
+    Err("FHIR conversion requires the fhir feature".to_string())
+}
+
+/// Convert a message to a FHIR message bundle
+#[cfg(feature = "fhir")]
+fn message_to_fhir(message: &Message) -> Result<String, String> {
+    let bundle = rust_hl7::fhir::bundle(message, &Default::default()).map_err(|e| e.to_string())?;
+    serde_json::to_string_pretty(&bundle).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "fhir"))]
+fn message_to_fhir(_: &Message) -> Result<String, String> {
+    Err("FHIR conversion requires the fhir feature".to_string())
+}
+
+/// Post archived messages to a FHIR server, printing the ones that failed
+#[cfg(feature = "fhir")]
+async fn backfill_fhir(
+    store: &dyn MessageStore,
+    query: Query,
+    to: String,
+    headers: Vec<String>,
+    bundle_size: usize,
+    attempts: u32,
+    checkpoint: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut backfill = rust_hl7::jobs::FhirBackfill::new(query, to)
+        .bundle_size(bundle_size)
+        .with_retries(attempts, Duration::from_secs(1));
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("--header must be written \"Name: value\", not {}", header))?;
+        backfill = backfill.header(name.trim(), value.trim());
+    }
+    if let Some(path) = checkpoint {
+        backfill = backfill.checkpoint(path);
+    }
+
+    let report = backfill.run(store).await?;
+    for failure in &report.failures {
+        println!(
+            "  failed: archive id {:?}, control id {}: {}",
+            failure.id,
+            failure.control_id.as_deref().unwrap_or("-"),
+            failure.error
+        );
     }
+    println!(
+        "Processed {} messages: {} sent in {} bundles, {} failed",
+        report.processed, report.sent, report.bundles, report.failed
+    );
+    if !report.failures.is_empty() {
+        return Err(format!("{} messages failed to backfill", report.failures.len()).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "fhir"))]
+async fn backfill_fhir(
+    _: &dyn MessageStore,
+    _: Query,
+    _: String,
+    _: Vec<String>,
+    _: usize,
+    _: u32,
+    _: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("backfill-fhir requires the fhir feature".into())
+}
+
+/// Commands understood by the inspect prompt
+const INSPECT_HELP: &str = "\
+list              list the messages
+select N          select message N (or just type N)
+show              print the selected message
+get PATH          print a value, e.g. get PID-5.1 or get OBX(2)-5
+query EXPR        run a structural query, e.g. query OBX[?(@.8=='H')].3.2
+set PATH VALUE    change a value in the selected message
+validate          check the selected message's structure
+send HOST         send the selected message over MLLP and print the ACK
+save [FILE]       write every message back to the file, or to FILE
+quit              leave the prompt";
 
+/// Run the interactive prompt over the messages in a file
+async fn inspect(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut inspector = Inspector::open(path)?;
+    for line in &inspector.unparsed {
+        println!("{}", line);
+    }
+    println!("{} messages in {}; type help for the commands", inspector.messages.len(), path.display());
+
+    // Tokio reads stdin on its blocking pool, so waiting for a line doesn't hold up the runtime
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("hl7[{}]> ", inspector.selected + 1);
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next_line().await? else {
+            println!();
+            break;
+        };
+        let mut output = Vec::new();
+        let more = inspector.command(&line, &mut output).await?;
+        std::io::stdout().write_all(&output)?;
+        if !more {
+            break;
+        }
+    }
     Ok(())
 }
 
-/// Runs the demo parsing sample HL7 messages
-fn run_parse_demo() {
+/// The messages of a file open in the inspect prompt
+///
+/// Messages that don't parse are listed and saved unchanged but can't be
+/// queried or edited.
+struct Inspector {
+    path: PathBuf,
+    /// The charset the file was read in
+    charset: Charset,
+    messages: Vec<Result<Message, String>>,
+    /// A line for each message that didn't parse
+    unparsed: Vec<String>,
+    selected: usize,
+    modified: bool,
+    warned: bool,
+}
+
+impl Inspector {
+    fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let bytes = fs::read(path)?;
+        let charset = charset::detect(&bytes).unwrap_or_default();
+        let mut messages = Vec::new();
+        let mut unparsed = Vec::new();
+        for (index, text) in filedrop::split_messages(&charset.decode(&bytes)?).into_iter().enumerate() {
+            match Message::parse(&text) {
+                Ok(message) => messages.push(Ok(message)),
+                Err(e) => {
+                    unparsed.push(format!("Message {} doesn't parse: {}", index + 1, e));
+                    messages.push(Err(text));
+                }
+            }
+        }
+        if messages.is_empty() {
+            return Err("No messages found in the input".into());
+        }
+        Ok(Self { path: path.to_path_buf(), charset, messages, unparsed, selected: 0, modified: false, warned: false })
+    }
+
+    /// The messages as they'd be saved, each in the charset its MSH-18 names
+    ///
+    /// Messages that don't parse, or whose MSH-18 isn't a supported charset, keep
+    /// the charset the file was read in.
+    fn encode(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        for message in &self.messages {
+            match message {
+                Ok(message) => {
+                    let charset = terser::get(message, "MSH-18")
+                        .filter(|value| !value.trim().is_empty())
+                        .and_then(|value| Charset::from_hl7(value.split('~').next().unwrap_or_default()))
+                        .unwrap_or(self.charset);
+                    output.extend(charset.encode(&(message.to_hl7() + "\r")));
+                }
+                Err(text) => output.extend(self.charset.encode(&(text.clone() + "\r"))),
+            }
+        }
+        output
+    }
+
+    /// Run one line typed at the prompt, writing what it prints to `out`
+    ///
+    /// Returns false once the prompt should close.
+    async fn command(&mut self, line: &str, out: &mut Vec<u8>) -> std::io::Result<bool> {
+        let line = line.trim();
+        // A bare number selects that message
+        let (command, rest) = match line.split_once(char::is_whitespace) {
+            Some((command, rest)) => (command, rest.trim()),
+            None if line.parse::<usize>().is_ok() => ("select", line),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => {}
+            "help" | "?" => writeln!(out, "{}", INSPECT_HELP)?,
+            "list" | "ls" => {
+                for (index, message) in self.messages.iter().enumerate() {
+                    let marker = if index == self.selected { "*" } else { " " };
+                    match message {
+                        Ok(message) => {
+                            let value = |path: &str| terser::get(message, path).filter(|v| !v.is_empty());
+                            writeln!(
+                                out,
+                                "{}{}\t{}\t{}\t{}\t{}\t{} segments",
+                                marker,
+                                index + 1,
+                                message.message_type,
+                                message.control_id().filter(|v| !v.is_empty()).unwrap_or("-"),
+                                message.sending_application().filter(|v| !v.is_empty()).unwrap_or("-"),
+                                value("PID-3.1").as_deref().unwrap_or("-"),
+                                message.segments.len()
+                            )?;
+                        }
+                        Err(_) => writeln!(out, "{}{}\t(doesn't parse)", marker, index + 1)?,
+                    }
+                }
+            }
+            "select" | "s" => match rest.parse::<usize>() {
+                Ok(number) if (1..=self.messages.len()).contains(&number) => self.selected = number - 1,
+                _ => writeln!(out, "Choose a message from 1 to {}", self.messages.len())?,
+            },
+            "quit" | "exit" | "q" => {
+                if self.modified && !self.warned {
+                    writeln!(out, "There are unsaved changes; save them, or quit again to discard them")?;
+                    self.warned = true;
+                    return Ok(true);
+                }
+                return Ok(false);
+            }
+            "save" => {
+                let target = if rest.is_empty() { self.path.clone() } else { PathBuf::from(rest) };
+                match tokio::fs::write(&target, self.encode()).await {
+                    Ok(()) => {
+                        writeln!(out, "Saved {} messages to {}", self.messages.len(), target.display())?;
+                        self.modified = false;
+                    }
+                    Err(e) => writeln!(out, "Couldn't save to {}: {}", target.display(), e)?,
+                }
+            }
+            "show" | "get" | "query" | "set" | "validate" | "send" => {
+                let Ok(message) = &mut self.messages[self.selected] else {
+                    writeln!(out, "Message {} doesn't parse", self.selected + 1)?;
+                    return Ok(true);
+                };
+                match command {
+                    "show" => writeln!(out, "{}", message.to_hl7().replace('\r', "\n"))?,
+                    "get" => match rest.parse::<terser::TerserPath>() {
+                        Ok(path) => match terser::get_path(message, &path) {
+                            Some(value) if !value.is_empty() => writeln!(out, "{}", value)?,
+                            Some(_) => writeln!(out, "(empty)")?,
+                            None => writeln!(out, "(not present)")?,
+                        },
+                        Err(e) => writeln!(out, "{}", e)?,
+                    },
+                    "query" => match rust_hl7::query::select(message, rest) {
+                        Ok(values) if values.is_empty() => writeln!(out, "(no matches)")?,
+                        Ok(values) => {
+                            for value in values {
+                                writeln!(out, "{}", value)?;
+                            }
+                        }
+                        Err(e) => writeln!(out, "{}", e)?,
+                    },
+                    "set" => {
+                        let (target, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                        match terser::set(message, target, value.trim()) {
+                            Ok(()) => {
+                                writeln!(out, "{} = {}", target, terser::get(message, target).unwrap_or_default())?;
+                                self.modified = true;
+                                self.warned = false;
+                            }
+                            Err(e) => writeln!(out, "{}", e)?,
+                        }
+                    }
+                    "validate" => {
+                        let issues = Validator::new().validate(message);
+                        if issues.is_empty() {
+                            writeln!(out, "No problems found")?;
+                        }
+                        for issue in issues {
+                            writeln!(out, "{}", issue)?;
+                        }
+                    }
+                    _ if rest.is_empty() => writeln!(out, "Usage: send HOST:PORT")?,
+                    _ => {
+                        let (_, outcome) = describe_ack(MllpClient::new(rest).send(&message.to_hl7()).await);
+                        writeln!(out, "{}", outcome)?;
+                    }
+                }
+            }
+            other => writeln!(out, "Unknown command '{}'; type help for the commands", other)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Describe the response to a sent message, and whether it was accepted
+///
+/// The description is MSA-1 followed by any MSA-3 and ERR text in parentheses.
+fn describe_ack(response: Result<String, MllpError>) -> (bool, String) {
+    match response.map_err(|e| e.to_string()).and_then(|r| Message::parse(&r).map_err(|e| e.to_string())) {
+        Ok(ack) => {
+            let code = terser::get(&ack, "MSA-1").unwrap_or_default();
+            let text = terser::get(&ack, "MSA-3").filter(|t| !t.is_empty());
+            let error = terser::get(&ack, "ERR-3").or_else(|| terser::get(&ack, "ERR-1")).filter(|e| !e.is_empty());
+            let accepted = matches!(code.as_str(), "AA" | "CA");
+            let mut outcome = if code.is_empty() { "no MSA in response".to_string() } else { code };
+            for detail in [text, error].into_iter().flatten() {
+                outcome.push_str(&format!(" ({})", detail));
+            }
+            (accepted, outcome)
+        }
+        Err(e) => (false, format!("error: {}", e)),
+    }
+}
+
+/// Where `replay` and `reprocess` send messages: an address, a config's routes or one of its destinations
+fn replay_target(
+    to: Option<String>,
+    config: Option<PathBuf>,
+    destination: Option<String>,
+) -> Result<Option<ReplayTarget>, Box<dyn std::error::Error>> {
+    Ok(match (to, config) {
+        (Some(address), _) => Some(ReplayTarget::Destination(Destination::Mllp(MllpClient::new(address)))),
+        (None, Some(config)) => {
+            let config = ServerConfig::load(config)?;
+            Some(match destination {
+                Some(name) => ReplayTarget::Destination(config.destination(&name)?),
+                None => ReplayTarget::Router(Arc::new(config.build_router()?.0)),
+            })
+        }
+        (None, None) => None,
+    })
+}
+
+/// Open a SQLite archive, protecting fields with `policy` and decrypting them with
+/// the key in RUST_HL7_ARCHIVE_KEY, if set, and compressing new messages with
+/// `codec`; compressed messages are read back whatever `codec` is
+///
+/// Documents of at least `offload_min_bytes` are moved to the blob directory
+/// next to the archive, and any found there are always put back.
+fn open_archive(
+    path: &Path,
+    policy: FieldPolicy,
+    codec: Codec,
+    offload_min_bytes: Option<usize>,
+) -> Result<Arc<dyn MessageStore>, Box<dyn std::error::Error>> {
+    let policy = archive_keys(policy)?;
+    let store = CompressedStore::new(Arc::new(SqliteStore::open(path)?), codec);
+    let store = OffloadStore::new(Arc::new(store), Arc::new(FileBlobStore::new(path.with_extension("blobs"))))
+        .min_bytes(offload_min_bytes.unwrap_or(usize::MAX));
+    Ok(Arc::new(ProtectedStore::new(Arc::new(store), policy)?))
+}
+
+/// Give a field policy the archive key in RUST_HL7_ARCHIVE_KEY, if there is one
+fn archive_keys(policy: FieldPolicy) -> Result<FieldPolicy, Box<dyn std::error::Error>> {
+    Ok(match journal_keys()? {
+        Some(keys) => policy.with_keys(keys),
+        None => policy,
+    })
+}
+
+/// The archive key from RUST_HL7_ARCHIVE_KEY and RUST_HL7_ARCHIVE_KEY_ID, if one is set
+fn journal_keys() -> Result<Option<Arc<dyn KeyProvider>>, Box<dyn std::error::Error>> {
+    Ok(match std::env::var("RUST_HL7_ARCHIVE_KEY") {
+        Ok(key) => {
+            let id = std::env::var("RUST_HL7_ARCHIVE_KEY_ID").unwrap_or_else(|_| "1".to_string());
+            Some(Arc::new(StaticKeys::from_hex(id, &key)?))
+        }
+        Err(_) => None,
+    })
+}
+
+/// Wait for Ctrl-C or, on Unix, SIGTERM
+#[cfg(feature = "s3")]
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Read all of stdin
+fn read_stdin() -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    std::io::stdin().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decode input in the charset its first MSH declares
+fn read_messages(bytes: Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
+    let charset = charset::detect(&bytes).unwrap_or_default();
+    Ok(charset.decode(&bytes)?)
+}
+
+/// Print each message in the requested format, returning how many failed to parse
+///
+/// `json` prints one canonical JSON message per line, `summary` one line of key
+/// header values per message, `pretty` every field with its name, and `text`
+/// the details of ADT, ORU and RDE messages.
+/// Parse errors go to stderr so they don't mix with JSON output.
+fn print_messages(messages: &[String], output: &str, out: &mut dyn Write) -> std::io::Result<usize> {
+    let mut failed = 0;
+    for (index, text) in messages.iter().enumerate() {
+        let message = match Message::parse(text) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Message {}: {}", index + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let value = |path: &str| terser::get(&message, path).filter(|v| !v.is_empty());
+        match output {
+            "json" => writeln!(out, "{}", message.to_json())?,
+            "summary" => writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{} segments",
+                index + 1,
+                message.message_type,
+                message.control_id().filter(|v| !v.is_empty()).unwrap_or("-"),
+                message.sending_application().filter(|v| !v.is_empty()).unwrap_or("-"),
+                value("PID-3.1").as_deref().unwrap_or("-"),
+                message.segments.len()
+            )?,
+            "pretty" => writeln!(out, "{}", message.pretty_print())?,
+            _ => match output_message_details(message) {
+                Ok(details) => writeln!(out, "{}", details)?,
+                Err(e) => {
+                    eprintln!("Message {}: {}", index + 1, e);
+                    failed += 1;
+                }
+            },
+        }
+    }
+    Ok(failed)
+}
+
+/// Sample messages parsed when no input is given
+fn sample_messages() -> [&'static str; 3] {
     // Example ADT message (patient admission)
     let adt_message = r#"MSH|^~\&|SENDING_APP|SENDING_FACILITY|RECEIVING_APP|RECEIVING_FACILITY|20230401123000||ADT^A01|MSG00001|P|2.5
 EVN|A01|20230401123000
@@ -110,19 +2089,9 @@ ORC|NW|ORD123456|||||^^^20230401^^R|
 RXE|AMOX500^AMOXICILLIN 500MG||500|MG|TAB|BID||||||30||SWALLOW||20230401|20230415
 RXR|||SWALLOW"#;
 
-    [adt_message, oru_message, rde_message].iter().for_each(|message| {
-        match parse_message(message) {
-            Ok(result) => println!("{}", result),
-            Err(e) => println!("Error parsing message: {}", e),
-        }
-    })
+    [adt_message, oru_message, rde_message]
 }
 
-/// Parse an HL7 message string and return parsed message details
-fn parse_message(msg_str: &str) -> Result<String, HL7Error> {
-    let message = Message::parse(msg_str)?;
-    output_message_details(message)
-}
 fn output_message_details(message: Message) -> Result<String, HL7Error> {
     let mut output = String::new();
     
@@ -238,56 +2207,119 @@ fn output_message_details(message: Message) -> Result<String, HL7Error> {
     Ok(output)
 }
 
-/// Cleans up log files older than the specified number of days
-fn cleanup_old_logs(log_dir: &str, days: u64) -> std::io::Result<()> {
-    let log_path = Path::new(log_dir);
-    if !log_path.exists() {
-        return Ok(());
-    }
-    
-    let max_age = Duration::from_secs(days * 24 * 60 * 60);
-    let now = SystemTime::now();
-    
-    for entry in fs::read_dir(log_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if let Ok(metadata) = fs::metadata(&path) {
-            if !metadata.is_file() {
-                continue;
-            }
-            
-            if let Ok(modified) = metadata.modified() {
-                if let Ok(duration) = now.duration_since(modified) {
-                    if duration > max_age {
-                        info!("Removing old log file: {}", path.display());
-                        fs::remove_file(path)?;
-                    }
-                }
-            }
-        }
-    }
+/// Retention for the log files written with `config`
+fn log_retention(config: &LoggingConfig) -> Retention {
+    Retention::new().add(Files::new(&config.directory, &config.file_name), config.retention())
+}
+
+/// Logs a received message and echoes it back
+fn log_and_echo(message: Message) -> Result<Message, HL7Error> {
+    // Log the received message type
+    info!("Received message of type: {}", message.message_type);
+
+    info!("Message details: {}", output_message_details(message.to_owned())?);
     
-    Ok(())
+    // In a real application, you would process the message here
+    // For this example, we'll just echo it back
+    Ok(message)
 }
 
-/// Runs an MLLP server on the specified address
-async fn run_mllp_server(address: &str) -> Result<(), MllpError> {
+/// Runs an MLLP server on the specified address, with health endpoints if given
+async fn run_mllp_server(address: &str, server: MllpServer, health: Option<HealthServer>) -> Result<(), MllpError> {
     info!("Starting MLLP server on {}", address);
     
-    // Create a message handler function
-    let message_handler = Arc::new(|message: Message| -> Result<Message, HL7Error> {
-        // Log the received message type
-        info!("Received message of type: {}", message.message_type);
-
-        info!("Message details: {}", output_message_details(message.to_owned())?);
-        
-        // In a real application, you would process the message here
-        // For this example, we'll just echo it back
-        Ok(message)
-    });
-    
-    // Create and run the server
-    let server = MllpServer::new(address, message_handler);
+    if let Some(health) = health {
+        tokio::spawn(async move {
+            if let Err(e) = health.run().await {
+                error!("Health endpoints stopped: {}", e);
+            }
+        });
+    }
     server.run().await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration(" 5 "), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("5d").unwrap_err().contains("unit"));
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("").is_err());
+        // Too long for a Duration is an error rather than a panic
+        assert!(parse_duration(&format!("{}h", "9".repeat(30))).unwrap_err().contains("out of range"));
+    }
+
+    #[test]
+    fn test_parse_command_output() {
+        // Two messages as a file or stdin would hold them, with newline segment endings
+        let input = b"MSH|^~\\&|ADT|HOSP|EHR|HOSP|20240501||ADT^A01|M1|P|2.5\nEVN|A01|20240501\nPID|1||123^^^MRN||DOE^JOHN\n\
+MSH|^~\\&|LAB|HOSP|EHR|HOSP|20240501||ORU^R01|M2|P|2.5\nPID|1||456^^^MRN\nOBX|1|NM|GLU^Glucose^L||98|mg/dL\n";
+        let messages = filedrop::split_messages(&read_messages(input.to_vec()).unwrap());
+        assert_eq!(messages.len(), 2);
+        let print = |output: &str| {
+            let mut out = Vec::new();
+            assert_eq!(print_messages(&messages, output, &mut out).unwrap(), 0);
+            String::from_utf8(out).unwrap()
+        };
+
+        let json = print("json");
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert!(second.to_string().contains("\"M2\""));
+
+        assert_eq!(
+            print("summary"),
+            "1\tADT^A01\tM1\tADT\t123\t3 segments\n2\tORU^R01\tM2\tLAB\t456\t3 segments\n"
+        );
+
+        let text = print("text");
+        assert!(text.contains("Successfully parsed ADT message") && text.contains("Patient ID=123"));
+        assert!(text.contains("Successfully parsed ORU message") && text.contains("Test ID=GLU"));
+
+        // A message that doesn't parse is counted and the rest still print
+        let messages = vec![messages[0].clone(), "not a message".to_string()];
+        let mut out = Vec::new();
+        assert_eq!(print_messages(&messages, "summary", &mut out).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_prompt() {
+        let path = std::env::temp_dir().join(format!("rust-hl7-inspect-{}.hl7", std::process::id()));
+        let latin1 = Charset::Iso8859_1
+            .encode("MSH|^~\\&|ADT|HOSP|EHR|HOSP|20240501||ADT^A08|1|P|2.5||||||8859/1\rPID|1||123||MÜLLER^JOSÉ\r");
+        fs::write(&path, [latin1.as_slice(), b"MSH|^~\\&|X|Y\rP1|not a segment\r"].concat()).unwrap();
+        let mut inspector = Inspector::open(&path).unwrap();
+        assert_eq!(inspector.unparsed.len(), 1);
+
+        let mut run = async |line: &str| {
+            let mut out = Vec::new();
+            let more = inspector.command(line, &mut out).await.unwrap();
+            (more, String::from_utf8(out).unwrap())
+        };
+        assert_eq!(run("get PID-5.1").await, (true, "MÜLLER\n".to_string()));
+        assert!(run("list").await.1.contains("2\t(doesn't parse)"));
+        assert_eq!(run("2").await.1, "");
+        assert_eq!(run("show").await.1, "Message 2 doesn't parse\n");
+        run("1").await;
+        assert_eq!(run("set PID-5.2 RENÉ").await.1, "PID-5.2 = RENÉ\n");
+        assert!(run("quit").await.0);
+        assert!(run("save").await.1.starts_with("Saved 2 messages"));
+        assert!(!run("quit").await.0);
+
+        // Saved in the charset MSH-18 names, with the message that doesn't parse as it was
+        let saved = fs::read(&path).unwrap();
+        let expected = Charset::Iso8859_1.decode(&latin1).unwrap().replace("JOSÉ", "RENÉ");
+        assert_eq!(Charset::Iso8859_1.decode(&saved).unwrap(), expected + "MSH|^~\\&|X|Y\rP1|not a segment\r");
+        assert!(saved.contains(&0xC9) && std::str::from_utf8(&saved).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
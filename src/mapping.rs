@@ -0,0 +1,204 @@
+#[cfg(not(feature = "std"))]
+use crate::prelude::*;
+use crate::{HL7Error, Message, Segment};
+
+#[cfg(feature = "derive")]
+pub use rust_hl7_derive::Hl7Message;
+
+/// A typed segment built from a parsed one, for segment fields of an `Hl7Message`
+///
+/// `Segment` itself implements it, so a field can keep the raw segment and be
+/// read with `Segment::field`. Site-specific segment types implement it by hand.
+pub trait FromSegment: Sized {
+    fn from_segment(segment: &Segment) -> Result<Self, HL7Error>;
+}
+
+impl FromSegment for Segment {
+    fn from_segment(segment: &Segment) -> Result<Self, HL7Error> {
+        Ok(segment.clone())
+    }
+}
+
+/// A message, or a group of segments within one, mapped onto a struct
+///
+/// Usually derived. Each field of the struct is bound to a segment with
+/// `#[hl7(segment = "PID")]` or to a nested group with `#[hl7(group)]`, and is
+/// required, `Option<T>` or `Vec<T>` for a repeating segment or group. Fields
+/// are matched in the order they're declared, as in the abstract message
+/// syntax, and segments the struct doesn't mention anywhere (EVN, Z-segments)
+/// are skipped. A group starts at its first required segment or any optional
+/// one before it, so each OBR begins a new order below:
+///
+/// ```
+/// use rust_hl7::mapping::Hl7Message;
+/// use rust_hl7::{Message, Segment};
+///
+/// #[derive(Hl7Message)]
+/// #[hl7(message = "ORU^R01")]
+/// struct LabResult {
+///     #[hl7(segment = "PID")]
+///     patient: Segment,
+///     #[hl7(segment = "PV1")]
+///     visit: Option<Segment>,
+///     #[hl7(group)]
+///     orders: Vec<Order>,
+/// }
+///
+/// #[derive(Hl7Message)]
+/// struct Order {
+///     #[hl7(segment = "OBR")]
+///     request: Segment,
+///     #[hl7(segment = "OBX")]
+///     results: Vec<Segment>,
+/// }
+///
+/// let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\r\
+///     PID|1||12345^^^HOSP^MR||DOE^JANE\r\
+///     OBR|1||A1|CBC\r\
+///     OBX|1|NM|WBC||10.5\r\
+///     OBX|2|NM|RBC||4.5\r\
+///     OBR|2||A2|BMP\r\
+///     OBX|1|NM|NA||140").unwrap();
+/// let result = LabResult::from_message(&message).unwrap();
+/// assert_eq!(result.patient.field(3).as_str(), Some("12345"));
+/// assert!(result.visit.is_none());
+/// assert_eq!(result.orders.len(), 2);
+/// assert_eq!(result.orders[0].results[1].field(3).as_str(), Some("RBC"));
+/// ```
+pub trait Hl7Message: Sized {
+    /// Whether the group can start with this segment, which says whether it's present and where each repeat begins
+    fn starts_with(segment: &str) -> bool;
+
+    /// Add the name of every segment bound by the struct and its groups
+    fn segment_names(names: &mut Vec<&'static str>);
+
+    /// Read the struct from the segments at the cursor
+    fn from_cursor(cursor: &mut Cursor<'_>) -> Result<Self, HL7Error>;
+
+    /// Check MSH-9 before mapping; derived for `#[hl7(message = "ORU^R01")]`
+    fn check_type(_message: &Message) -> Result<(), HL7Error> {
+        Ok(())
+    }
+
+    /// Map a whole message, failing on a missing required segment or group
+    /// and on bound segments left over in the wrong place
+    fn from_message(message: &Message) -> Result<Self, HL7Error> {
+        Self::check_type(message)?;
+        let mut names = Vec::new();
+        Self::segment_names(&mut names);
+        let mut cursor = Cursor::new(&message.segments, names);
+        let mapped = Self::from_cursor(&mut cursor)?;
+        match cursor.peek() {
+            Some(segment) => Err(HL7Error::InvalidStructure(format!(
+                "Unexpected {} segment at position {}",
+                segment.name,
+                cursor.position + 1
+            ))),
+            None => Ok(mapped),
+        }
+    }
+}
+
+/// Check that MSH-9 starts with the given components, e.g. `ORU^R01`
+pub fn check_message_type(message: &Message, expected: &str) -> Result<(), HL7Error> {
+    let mut actual = message.message_type.split('^');
+    if expected.split('^').all(|part| actual.next() == Some(part)) {
+        Ok(())
+    } else {
+        Err(HL7Error::InvalidStructure(format!(
+            "Expected a {} message, got {}",
+            expected, message.message_type
+        )))
+    }
+}
+
+/// The position reached while mapping a message's segments
+///
+/// Segments whose names weren't bound anywhere are passed over.
+#[derive(Debug)]
+pub struct Cursor<'a> {
+    segments: &'a [Segment],
+    position: usize,
+    names: Vec<&'static str>,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(segments: &'a [Segment], names: Vec<&'static str>) -> Self {
+        Self {
+            segments,
+            position: 0,
+            names,
+        }
+    }
+
+    /// The next bound segment, without moving past it
+    pub fn peek(&mut self) -> Option<&'a Segment> {
+        while let Some(segment) = self.segments.get(self.position) {
+            if self.names.contains(&segment.name.as_str()) {
+                return Some(segment);
+            }
+            self.position += 1;
+        }
+        None
+    }
+
+    /// Whether the next bound segment has this name
+    pub fn at(&mut self, name: &str) -> bool {
+        self.peek().is_some_and(|segment| segment.name == name)
+    }
+
+    /// Move past the next segment if it has this name
+    pub fn next_if(&mut self, name: &str) -> Option<&'a Segment> {
+        let segment = self.peek().filter(|segment| segment.name == name)?;
+        self.position += 1;
+        Some(segment)
+    }
+
+    pub fn required<T: FromSegment>(&mut self, name: &str) -> Result<T, HL7Error> {
+        match self.next_if(name) {
+            Some(segment) => T::from_segment(segment),
+            None => Err(HL7Error::MissingField(format!("{} segment", name))),
+        }
+    }
+
+    pub fn optional<T: FromSegment>(&mut self, name: &str) -> Result<Option<T>, HL7Error> {
+        self.next_if(name).map(T::from_segment).transpose()
+    }
+
+    pub fn repeated<T: FromSegment>(&mut self, name: &str) -> Result<Vec<T>, HL7Error> {
+        let mut items = Vec::new();
+        while let Some(segment) = self.next_if(name) {
+            items.push(T::from_segment(segment)?);
+        }
+        Ok(items)
+    }
+
+    /// Whether the next bound segment starts a `G`
+    pub fn at_group<G: Hl7Message>(&mut self) -> bool {
+        self.peek().is_some_and(|segment| G::starts_with(&segment.name))
+    }
+
+    pub fn group<G: Hl7Message>(&mut self) -> Result<G, HL7Error> {
+        if !self.at_group::<G>() {
+            let name = core::any::type_name::<G>().rsplit("::").next().unwrap_or_default();
+            return Err(HL7Error::MissingField(format!("{} group", name)));
+        }
+        G::from_cursor(self)
+    }
+
+    pub fn optional_group<G: Hl7Message>(&mut self) -> Result<Option<G>, HL7Error> {
+        if self.at_group::<G>() {
+            G::from_cursor(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn repeated_group<G: Hl7Message>(&mut self) -> Result<Vec<G>, HL7Error> {
+        let mut groups = Vec::new();
+        while self.at_group::<G>() {
+            groups.push(G::from_cursor(self)?);
+        }
+        Ok(groups)
+    }
+}
@@ -0,0 +1,248 @@
+use crate::mllp::MessageHandler;
+use crate::state::StateBackend;
+use crate::transform::{Pipeline, Transform};
+use crate::{terser, HL7Error, Message};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The remainder of the chain, ending with the core handler
+pub type Next<'a> = &'a dyn Fn(Message) -> Result<Message, HL7Error>;
+
+/// A layer wrapped around the message handler
+///
+/// A middleware can inspect or modify the message, short-circuit by returning
+/// without calling `next`, or inspect the result after calling it.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error>;
+}
+
+/// Any closure taking the message and the rest of the chain can be used as a middleware
+impl<F> Middleware for F
+where
+    F: Fn(Message, Next<'_>) -> Result<Message, HL7Error> + Send + Sync,
+{
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        self(message, next)
+    }
+}
+
+/// Shared middleware (e.g. metrics read elsewhere) can be layered directly
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        (**self).handle(message, next)
+    }
+}
+
+/// Transform pipelines run before the rest of the chain
+impl Middleware for Pipeline {
+    fn handle(&self, mut message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        self.apply(&mut message)?;
+        next(message)
+    }
+}
+
+/// An ordered stack of middleware; the first layer added is the outermost
+#[derive(Default, Clone)]
+pub struct Chain {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl Chain {
+    /// Create an empty chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a layer inside those already added
+    pub fn layer<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.layers.push(Arc::new(middleware));
+        self
+    }
+
+    /// Wrap a handler with every layer in the chain
+    pub fn wrap(self, handler: MessageHandler) -> MessageHandler {
+        self.layers.into_iter().rev().fold(handler, |next, layer| {
+            Arc::new(move |message| layer.handle(message, &*next))
+        })
+    }
+}
+
+/// Reject messages whose sending application (MSH-3) isn't in the allowlist
+#[derive(Debug, Clone, Default)]
+pub struct SenderAllowlist {
+    applications: HashSet<String>,
+}
+
+impl SenderAllowlist {
+    /// Allow the given sending applications
+    pub fn new<I, S>(applications: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            applications: applications.into_iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+impl Middleware for SenderAllowlist {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        let sender = message.sending_application().unwrap_or_default().to_string();
+        if !self.applications.contains(&sender) {
+            warn!("Rejected message from unknown sending application '{}'", sender);
+            return Err(HL7Error::Rejected(format!("Sending application '{}' is not allowed", sender)));
+        }
+        next(message)
+    }
+}
+
+/// Skip messages whose control ID (MSH-10) was seen recently from the same
+/// sending application and facility (MSH-3 and MSH-4)
+///
+/// Duplicates are acknowledged again without being passed on, so a sender
+/// that resends after a lost ACK doesn't cause double processing.
+///
+/// `Dedup::new` remembers IDs in this process. Behind a load balancer the
+/// resend can reach another instance, so use `Dedup::shared` with a backend
+/// they all use, such as Redis. If that backend can't be reached, messages
+/// are processed rather than risk dropping them.
+#[derive(Debug)]
+pub struct Dedup {
+    capacity: usize,
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+    shared: Option<(Arc<dyn StateBackend>, Duration)>,
+}
+
+impl Dedup {
+    /// Remember up to `capacity` recent control IDs
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+            shared: None,
+        }
+    }
+
+    /// Remember control IDs in `state` for `window`
+    pub fn shared(state: Arc<dyn StateBackend>, window: Duration) -> Self {
+        Self {
+            shared: Some((state, window)),
+            ..Self::new(0)
+        }
+    }
+
+    /// Record a sender's control ID, returning whether it's new
+    fn first_seen(&self, key: &str) -> bool {
+        if let Some((state, window)) = &self.shared {
+            return state.claim(&format!("dedup:{}", key), *window).unwrap_or_else(|e| {
+                warn!("Can't check message {} for duplicates: {}", key, e);
+                true
+            });
+        }
+        let mut guard = self.seen.lock().unwrap();
+        let (ids, order) = &mut *guard;
+        if ids.contains(key) {
+            return false;
+        }
+        ids.insert(key.to_string());
+        order.push_back(key.to_string());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn forget(&self, key: &str) {
+        if let Some((state, _)) = &self.shared {
+            if let Err(e) = state.release(&format!("dedup:{}", key)) {
+                warn!("Can't forget failed message {}, so its retry will be skipped: {}", key, e);
+            }
+            return;
+        }
+        let mut guard = self.seen.lock().unwrap();
+        let (ids, order) = &mut *guard;
+        ids.remove(key);
+        order.retain(|id| id != key);
+    }
+}
+
+impl Middleware for Dedup {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        let Some(control_id) = terser::get(&message, "MSH-10.1").filter(|id| !id.is_empty()) else {
+            return next(message);
+        };
+        // Control IDs are only unique per sender, and fields can't hold a field separator
+        let key = format!(
+            "{}|{}|{}",
+            message.sending_application().unwrap_or_default(),
+            message.sending_facility().unwrap_or_default(),
+            control_id
+        );
+
+        if !self.first_seen(&key) {
+            info!("Skipping duplicate message {}", key);
+            return Ok(message);
+        }
+
+        let result = next(message);
+
+        // Forget failed messages so the sender's retry is processed
+        if result.is_err() {
+            self.forget(&key);
+        }
+
+        result
+    }
+}
+
+/// Counts of messages seen by a `Metrics` layer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub received: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// Count received, succeeded and failed messages
+///
+/// Layer an `Arc<Metrics>` to keep a handle for reading the counts.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    received: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Metrics {
+    /// Create a new set of counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current counts
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        let result = next(message);
+        match &result {
+            Ok(_) => self.succeeded.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.failed.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+}
@@ -1,16 +1,39 @@
-use crate::Message;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::capture::{self, CaptureWriter};
+use crate::charset::{self, Charset};
+use crate::clock::{Clock, IdSource, SystemClock};
+use crate::lanes::{Placement, PriorityLanes};
+use crate::middleware::Chain;
+use crate::ratelimit::{Admission, RateLimit, RateLimiter};
+use crate::recovery::{StateJournal, Unanswered};
+use crate::report::{ErrorContext, ErrorReporter};
+use crate::store::{ArchiveRecord, Direction, Disposition, MessageStore};
+use crate::workers::WorkerPool;
+use crate::{Delimiters, Message};
 use bytes::{Bytes, BytesMut};
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{error, info, warn};
+use tracing::{error, info, info_span, warn, Instrument};
 
 // MLLP specific constants
 const MLLP_START_BLOCK: u8 = 0x0B; // Vertical Tab
 const MLLP_END_BLOCK: u8 = 0x1C;   // File Separator
 const MLLP_CARRIAGE_RETURN: u8 = 0x0D; // Carriage Return
+const MLLP_FRAMING: usize = 3; // Bytes a frame adds around the message
+
+/// Frames a connection can have waiting to be handled unless set with `with_pipeline_depth`
+pub const DEFAULT_PIPELINE_DEPTH: usize = 16;
+
+/// Largest frame a server accepts unless set with `with_max_frame_bytes`
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 100_000;
 
 /// Errors that can occur in MLLP operations
 #[derive(Debug, Error)]
@@ -23,6 +46,12 @@ pub enum MllpError {
     
     #[error("HL7 error: {0}")]
     Hl7Error(#[from] crate::HL7Error),
+    
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    #[error("Invalid server configuration: {0}")]
+    Config(String),
 }
 
 /// Codec for encoding/decoding MLLP frames
@@ -33,37 +62,7 @@ impl Decoder for MllpCodec {
     type Error = MllpError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Look for start block
-        if let Some(start_pos) = src.iter().position(|&b| b == MLLP_START_BLOCK) {
-            // Remove anything before the start block
-            if start_pos > 0 {
-                let _ = src.split_to(start_pos);
-            }
-            
-            // Now look for end sequence (FS + CR)
-            if let Some(end_pos) = src.windows(2).position(|w| w[0] == MLLP_END_BLOCK && w[1] == MLLP_CARRIAGE_RETURN) {
-                // We have a complete message
-                // Extract the entire framed message including start and end markers
-                let mut framed_message = src.split_to(end_pos + 2);
-                
-                // Skip the start block
-                let _ = framed_message.split_to(1);
-                
-                // Create a new BytesMut with just the message content (without end sequence)
-                let content_len = framed_message.len() - 2; // Subtract the end sequence
-                let content = framed_message.split_to(content_len);
-                
-                return Ok(Some(content.freeze()));
-            }
-        }
-        
-        // No complete message yet
-        if src.len() > 100_000 {
-            // If buffer gets too large without finding a valid frame, something is wrong
-            return Err(MllpError::InvalidFrame("Buffer exceeds maximum size without valid frame".to_string()));
-        }
-        
-        Ok(None)
+        extract_mllp_message(src)
     }
 }
 
@@ -71,11 +70,21 @@ impl Encoder<Bytes> for MllpCodec {
     type Error = MllpError;
 
     fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(&item[..], dst)
+    }
+}
+
+impl Encoder<&[u8]> for MllpCodec {
+    type Error = MllpError;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(item.len() + MLLP_FRAMING);
+
         // Add start block
         dst.extend_from_slice(&[MLLP_START_BLOCK]);
         
         // Add message content
-        dst.extend_from_slice(&item);
+        dst.extend_from_slice(item);
         
         // Add end sequence
         dst.extend_from_slice(&[MLLP_END_BLOCK, MLLP_CARRIAGE_RETURN]);
@@ -84,6 +93,64 @@ impl Encoder<Bytes> for MllpCodec {
     }
 }
 
+/// When the server sends its acknowledgment relative to running the handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AckMode {
+    /// ACK as soon as the message parses, then run the handler in the background
+    Immediate,
+    /// Run the handler first and ACK or NACK based on its result
+    #[default]
+    AfterProcessing,
+    /// Send the message returned by the handler as the response, unchanged
+    Application,
+}
+
+/// Options controlling automatic acknowledgments
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AckOptions {
+    /// When to acknowledge
+    pub mode: AckMode,
+    /// Append the processing latency to MSA-3
+    pub include_latency: bool,
+    /// Append this server identity to MSA-3
+    pub server_identity: Option<String>,
+}
+
+/// Batching of ACK writes, for senders that push many messages per connection
+///
+/// Responses are held in the connection's write buffer and written together
+/// once `max_bytes` are waiting or `max_delay_ms` has passed since the first,
+/// so a batch costs a write per threshold rather than per ACK. In config
+/// files, e.g. `ack_coalescing = { max_delay_ms = 5, max_bytes = 16384 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AckCoalescing {
+    /// Longest a response waits to be written, in milliseconds
+    #[serde(default = "default_coalescing_delay")]
+    pub max_delay_ms: u64,
+    /// Write once this many bytes of responses are waiting
+    #[serde(default = "default_coalescing_bytes")]
+    pub max_bytes: usize,
+}
+
+fn default_coalescing_delay() -> u64 {
+    5
+}
+
+fn default_coalescing_bytes() -> usize {
+    16 * 1024
+}
+
+impl Default for AckCoalescing {
+    fn default() -> Self {
+        Self {
+            max_delay_ms: default_coalescing_delay(),
+            max_bytes: default_coalescing_bytes(),
+        }
+    }
+}
+
 /// Handler function for processing received HL7 messages
 pub type MessageHandler = Arc<dyn Fn(Message) -> Result<Message, crate::HL7Error> + Send + Sync>;
 
@@ -91,23 +158,556 @@ pub type MessageHandler = Arc<dyn Fn(Message) -> Result<Message, crate::HL7Error
 pub struct MllpServer {
     address: String,
     handler: MessageHandler,
+    /// Where queries go instead of `handler`, if they're answered separately
+    query_handler: Option<MessageHandler>,
+    default_charset: Charset,
+    ack_options: AckOptions,
+    archive: Option<Arc<dyn MessageStore>>,
+    audit: Option<Arc<AuditLog>>,
+    capture: Option<Arc<CaptureWriter>>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    clock: Arc<dyn Clock>,
+    ids: Option<Arc<dyn IdSource>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+    /// Permits for open connections, if they're limited
+    connections: Option<Arc<tokio::sync::Semaphore>>,
+    /// Frames each connection can have waiting to be handled
+    pipeline_depth: usize,
+    /// Largest frame accepted from a connection
+    max_frame_bytes: usize,
+    /// Threads handlers run on instead of Tokio's blocking pool
+    workers: Option<Arc<WorkerPool>>,
+    /// Batching of response writes, if they're batched
+    ack_coalescing: Option<AckCoalescing>,
+    /// How long a handler has before the message is NACKed
+    handler_timeout: Option<Duration>,
+    /// Which messages get a worker thread first
+    lanes: Option<Arc<PriorityLanes>>,
+    /// Where in-flight frames are kept, and what to do with unanswered ones after a restart
+    journal: Option<(Arc<StateJournal>, Unanswered)>,
+    status: ListenerStatus,
+    stats: Arc<StatsRecorder>,
+}
+
+/// Whether a server is currently bound and accepting connections, and a way
+/// to close the connections it has open
+#[derive(Debug, Clone, Default)]
+pub struct ListenerStatus {
+    bound: Arc<AtomicBool>,
+    closed: tokio_util::sync::CancellationToken,
+}
+
+impl ListenerStatus {
+    pub fn is_bound(&self) -> bool {
+        self.bound.load(Ordering::Relaxed)
+    }
+
+    /// Close every connection the server has open or opens from now on
+    ///
+    /// Stopping the server's task only stops it accepting; this is for when
+    /// no more messages may be taken at all, such as a failover standby.
+    pub fn close_connections(&self) {
+        self.closed.cancel();
+    }
+}
+
+/// Marks a listener bound for as long as it is alive
+struct BoundGuard(Arc<AtomicBool>);
+
+impl BoundGuard {
+    fn new(status: &ListenerStatus) -> Self {
+        status.bound.store(true, Ordering::Relaxed);
+        Self(status.bound.clone())
+    }
+}
+
+impl Drop for BoundGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// ACK latency percentiles over a stats window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Traffic on one open connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    pub peer: std::net::SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub messages: u64,
+}
+
+/// A snapshot of a server's recent throughput and latency, from `MllpServer::stats`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServerStats {
+    /// How far back `messages`, `nacks`, `messages_per_second` and `latency` look
+    pub window: Duration,
+    /// Messages answered within the window
+    pub messages: usize,
+    /// Messages answered with anything but a positive ACK within the window
+    pub nacks: usize,
+    /// Messages answered per second over the window, or since the server started if that's shorter
+    pub messages_per_second: f64,
+    /// Time from receiving a message to sending its response, within the window
+    pub latency: Latency,
+    /// Messages answered since the server was created
+    pub total_messages: u64,
+    /// Open connections, oldest first
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// Collects what `ServerStats` reports, shared by a server's connections
+#[derive(Debug)]
+struct StatsRecorder {
+    window: Duration,
+    started: Instant,
+    // When each message in the window was answered, how long it took, and whether it was NACKed
+    samples: Mutex<VecDeque<(Instant, Duration, bool)>>,
+    total_messages: AtomicU64,
+    connections: Mutex<HashMap<std::net::SocketAddr, ConnectionStats>>,
+}
+
+impl StatsRecorder {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            samples: Mutex::default(),
+            total_messages: AtomicU64::new(0),
+            connections: Mutex::default(),
+        }
+    }
+
+    fn prune(&self, samples: &mut VecDeque<(Instant, Duration, bool)>) {
+        while samples.front().is_some_and(|(at, _, _)| at.elapsed() > self.window) {
+            samples.pop_front();
+        }
+    }
+
+    fn message(&self, peer: std::net::SocketAddr, latency: Duration, nack: bool) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        self.prune(&mut samples);
+        samples.push_back((Instant::now(), latency, nack));
+        drop(samples);
+
+        self.total_messages.fetch_add(1, Ordering::Relaxed);
+        self.update(peer, |connection| connection.messages += 1);
+    }
+
+    fn update<F: FnOnce(&mut ConnectionStats)>(&self, peer: std::net::SocketAddr, update: F) {
+        if let Some(connection) = self.connections.lock().unwrap_or_else(|e| e.into_inner()).get_mut(&peer) {
+            update(connection);
+        }
+    }
+
+    /// Track a connection until the returned guard is dropped
+    fn connect(self: &Arc<Self>, peer: std::net::SocketAddr) -> ConnectionGuard {
+        let connection = ConnectionStats {
+            peer,
+            connected_at: Utc::now(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            messages: 0,
+        };
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).insert(peer, connection);
+        ConnectionGuard { recorder: self.clone(), peer }
+    }
+
+    fn snapshot(&self) -> ServerStats {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        self.prune(&mut samples);
+        let mut latencies: Vec<Duration> = samples.iter().map(|(_, latency, _)| *latency).collect();
+        let nacks = samples.iter().filter(|(_, _, nack)| *nack).count();
+        drop(samples);
+
+        latencies.sort_unstable();
+        let percentile = |p: usize| match latencies.len() {
+            0 => Duration::ZERO,
+            n => latencies[((n * p).div_ceil(100)).clamp(1, n) - 1],
+        };
+        let elapsed = self.started.elapsed().min(self.window).as_secs_f64();
+
+        let mut connections: Vec<ConnectionStats> =
+            self.connections.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect();
+        connections.sort_by_key(|c| (c.connected_at, c.peer));
+
+        ServerStats {
+            window: self.window,
+            messages: latencies.len(),
+            nacks,
+            messages_per_second: if elapsed > 0.0 { latencies.len() as f64 / elapsed } else { 0.0 },
+            latency: Latency {
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max: latencies.last().copied().unwrap_or_default(),
+            },
+            total_messages: self.total_messages.load(Ordering::Relaxed),
+            connections,
+        }
+    }
+}
+
+/// Stops reporting a connection when it closes, however its task ends
+struct ConnectionGuard {
+    recorder: Arc<StatsRecorder>,
+    peer: std::net::SocketAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.recorder.connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.peer);
+    }
 }
 
 impl MllpServer {
+    /// Start configuring a server, see `MllpServerBuilder`
+    pub fn builder() -> MllpServerBuilder {
+        MllpServerBuilder::default()
+    }
+
     /// Create a new MLLP server with specified address and message handler
+    ///
+    /// Shorthand for `MllpServer::builder().bind(address).handler(handler).build()`.
     pub fn new<A: ToString>(address: A, handler: MessageHandler) -> Self {
         Self {
             address: address.to_string(),
             handler,
+            query_handler: None,
+            default_charset: Charset::default(),
+            ack_options: AckOptions::default(),
+            archive: None,
+            audit: None,
+            capture: None,
+            rate_limit: None,
+            reporter: None,
+            clock: Arc::new(SystemClock),
+            ids: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            connections: None,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            workers: None,
+            ack_coalescing: None,
+            handler_timeout: None,
+            lanes: None,
+            journal: None,
+            status: ListenerStatus::default(),
+            stats: Arc::new(StatsRecorder::new(Duration::from_secs(60))),
         }
     }
 
+    /// A handle reporting whether this server is bound, e.g. for readiness checks
+    pub fn status(&self) -> ListenerStatus {
+        self.status.clone()
+    }
+
+    /// Throughput, ACK latency and per-connection traffic, e.g. for dashboards or throttling
+    ///
+    /// Message counts and latencies cover the last minute unless changed with
+    /// `with_stats_window`.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
+    }
+
+    /// Set how far back `stats` looks for throughput and latency
+    pub fn with_stats_window(mut self, window: Duration) -> Self {
+        self.stats = Arc::new(StatsRecorder::new(window));
+        self
+    }
+
+    /// Set the charset assumed for messages that don't declare one in MSH-18
+    pub fn with_default_charset(mut self, charset: Charset) -> Self {
+        self.default_charset = charset;
+        self
+    }
+
+    /// Wrap the handler with a middleware chain
+    ///
+    /// A query handler already set is wrapped too.
+    pub fn with_middleware(mut self, chain: Chain) -> Self {
+        self.query_handler = self.query_handler.map(|handler| chain.clone().wrap(handler));
+        self.handler = chain.wrap(self.handler);
+        self
+    }
+
+    /// Answer queries (QBP and QRY messages) with this handler instead
+    ///
+    /// Whatever the ack mode, the message a query handler returns is sent as
+    /// the response, as in `AckMode::Application`; other messages go to the
+    /// handler and are acknowledged as usual.
+    pub fn with_query_handler(mut self, handler: MessageHandler) -> Self {
+        self.query_handler = Some(handler);
+        self
+    }
+
+    /// Archive every received message and every response sent
+    pub fn with_archive(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.archive = Some(store);
+        self
+    }
+
+    /// Record every received message, who sent it and what became of it in an audit log
+    pub fn with_audit(mut self, log: Arc<AuditLog>) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Take acknowledgment timestamps (MSH-7) from this clock instead of the system's
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Give acknowledgments control IDs (MSH-10) of their own from this source
+    ///
+    /// Without one, MSH-10 echoes the control ID of the message acknowledged.
+    pub fn with_id_source(mut self, ids: Arc<dyn IdSource>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Write every frame received and every response sent to a capture file, for replaying later
+    pub fn with_capture(mut self, capture: Arc<CaptureWriter>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Limit how fast messages are accepted, delaying or rejecting those over the limit
+    pub fn with_rate_limit(self, limit: RateLimit) -> Self {
+        self.with_rate_limiter(RateLimiter::new(limit))
+    }
+
+    /// Limit how fast messages are accepted with a limiter of your own, e.g. one sharing state between instances
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Report failed connections, unparseable messages and handler errors, e.g. to Sentry
+    ///
+    /// Rejections (AR) aren't reported. Errors are logged either way.
+    pub fn with_error_reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Set when and how messages are acknowledged
+    pub fn with_ack_options(mut self, options: AckOptions) -> Self {
+        self.ack_options = options;
+        self
+    }
+
+    /// Accept only TLS connections, using these settings
+    ///
+    /// See `tls::server_config` for settings loaded from PEM files.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Keep at most this many connections open, leaving others waiting to be accepted
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.connections = Some(Arc::new(tokio::sync::Semaphore::new(max.max(1))));
+        self
+    }
+
+    /// Let up to this many frames from one connection wait while earlier ones are handled
+    ///
+    /// Once that many are waiting, the connection isn't read until one is
+    /// answered. Responses go out in the order frames arrived either way.
+    pub fn with_pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = depth.max(1);
+        self
+    }
+
+    /// Accept frames of up to this many bytes, 100,000 by default
+    ///
+    /// A connection that sends more than this without ending a frame is
+    /// closed. Raise it for senders of large embedded documents.
+    pub fn with_max_frame_bytes(mut self, max: usize) -> Self {
+        self.max_frame_bytes = max.max(1);
+        self
+    }
+
+    /// Run handlers on a pool of this many threads
+    ///
+    /// By default handlers run on Tokio's blocking pool, which grows to
+    /// hundreds of threads. A pool of their own caps how many CPU-heavy
+    /// handlers, e.g. FHIR conversion or validation, run at once, and leaves
+    /// the rest of the runtime free. Messages wait for a thread in order.
+    pub fn with_worker_threads(self, threads: usize) -> Self {
+        self.with_worker_pool(Arc::new(WorkerPool::new(threads)))
+    }
+
+    /// Run handlers on this pool, which can be shared with other servers
+    pub fn with_worker_pool(mut self, pool: Arc<WorkerPool>) -> Self {
+        self.workers = Some(pool);
+        self
+    }
+
+    /// Write responses in batches instead of one write per response
+    ///
+    /// Cuts syscalls for batch senders at the cost of up to `max_delay_ms`
+    /// of ACK latency. Responses still go out in the order frames arrived.
+    pub fn with_ack_coalescing(mut self, coalescing: AckCoalescing) -> Self {
+        self.ack_coalescing = Some(coalescing);
+        self
+    }
+
+    /// NACK a message with AE if its handler hasn't finished this long after it arrived
+    ///
+    /// Set it below the senders' own response timeout, so a slow database
+    /// gets a timeout NACK back instead of a dropped connection and a resend.
+    /// The server stops waiting and a handler still queued for a thread is
+    /// never run, but one already running can't be interrupted; it finishes
+    /// on its thread and its result is dropped. In `AckMode::Immediate` the
+    /// ACK doesn't wait for the handler, so there's no deadline.
+    pub fn with_handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// Give handlers for some messages a worker thread before others, e.g. admits ahead of a backfill
+    ///
+    /// Priority only matters when handlers wait for a thread, so this starts
+    /// a worker pool with a thread per CPU unless one is already set.
+    pub fn with_priority_lanes(mut self, lanes: PriorityLanes) -> Self {
+        if self.workers.is_none() {
+            let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+            self.workers = Some(Arc::new(WorkerPool::new(threads)));
+        }
+        self.lanes = Some(Arc::new(lanes));
+        self
+    }
+
+    /// Keep frames in a journal until they're handled and answered, and recover them on start
+    ///
+    /// When the server starts serving, frames a crash left in the journal are
+    /// dealt with first. Those already ACKed (in `AckMode::Immediate`) are
+    /// handled again, since their senders won't resend them, and the rest as
+    /// `unanswered` says. See `StateJournal`.
+    pub fn with_recovery(mut self, journal: Arc<StateJournal>, unanswered: Unanswered) -> Self {
+        self.journal = Some((journal, unanswered));
+        self
+    }
+
+    /// Deal with the frames left in the journal by a crash, returning how many there were
+    ///
+    /// `serve` calls this before accepting connections; each frame is only recovered once.
+    pub async fn recover(&self) -> usize {
+        let Some((journal, unanswered)) = &self.journal else {
+            return 0;
+        };
+        let settings = self.connection_settings();
+        let frames = journal.take_unfinished();
+        for frame in &frames {
+            let raw = frame.text.as_bytes();
+            let reprocess = frame.acked || *unanswered == Unanswered::Reprocess;
+            let disposition = match Message::parse(&frame.text) {
+                Ok(message) if reprocess => {
+                    let span = message.span();
+                    span.in_scope(|| info!("Handling message from {} again after a restart", frame.peer));
+                    let handler = settings.handler_for(&message).clone();
+                    let (result, _) = run_handler(&settings, handler, message, Instant::now(), span.clone()).await;
+                    if let Err(e) = &result {
+                        span.in_scope(|| error!("Error processing recovered message: {}", e));
+                    }
+                    disposition_of(&result)
+                }
+                _ => {
+                    warn!("Message {} from {} was interrupted by a restart; leaving it for the sender to resend", header_control_id(&frame.text), frame.peer);
+                    Disposition::Failed
+                }
+            };
+            settings.archive(raw, Direction::Inbound, disposition, frame.peer);
+            settings.audit(raw, disposition, frame.peer).await;
+            settings.journal_written(&mut vec![(frame.id, StateJournal::finished as JournalUpdate)]).await;
+        }
+        if !frames.is_empty() {
+            info!("Recovered {} in-flight messages from {}", frames.len(), journal.path().display());
+        }
+        frames.len()
+    }
+
+    fn connection_settings(&self) -> Arc<ConnectionSettings> {
+        Arc::new(ConnectionSettings {
+            handler: self.handler.clone(),
+            query_handler: self.query_handler.clone(),
+            default_charset: self.default_charset,
+            ack_options: self.ack_options.clone(),
+            archive: self.archive.clone(),
+            audit: self.audit.clone(),
+            capture: self.capture.clone(),
+            rate_limit: self.rate_limit.clone(),
+            reporter: self.reporter.clone(),
+            clock: self.clock.clone(),
+            ids: self.ids.clone(),
+            stats: self.stats.clone(),
+            pipeline_depth: self.pipeline_depth,
+            max_frame_bytes: self.max_frame_bytes,
+            workers: self.workers.clone(),
+            ack_coalescing: self.ack_coalescing,
+            handler_timeout: self.handler_timeout,
+            lanes: self.lanes.clone(),
+            journal: self.journal.as_ref().map(|(journal, _)| journal.clone()),
+        })
+    }
+
+    /// Handle a connection over an in-memory pipe, returning the client's end
+    ///
+    /// Each pipe is reported with its own made-up loopback peer address.
+    pub(crate) fn connect_in_memory(&self) -> tokio::io::DuplexStream {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], NEXT_PORT.fetch_add(1, Ordering::Relaxed)));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let settings = self.connection_settings();
+        let closed = self.status.closed.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = until_closed(&closed, addr, handle_connection(server, addr, settings)).await {
+                    error!("Error handling in-memory connection {}: {}", addr, e);
+                }
+            }
+            .instrument(info_span!("connection", peer = %addr)),
+        );
+        client
+    }
+
     /// Start the MLLP server
     pub async fn run(&self) -> Result<(), MllpError> {
         let listener = TcpListener::bind(&self.address).await?;
-        info!("MLLP server listening on {}", self.address);
+        self.serve(listener).await
+    }
+
+    /// Accept connections on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), MllpError> {
+        info!("MLLP server listening on {}", listener.local_addr()?);
+        // Reported as bound until this future finishes or is dropped
+        let _bound = BoundGuard::new(&self.status);
+        
+        self.recover().await;
+        let settings = self.connection_settings();
 
         loop {
+            // Wait for a connection to close before accepting another once at the limit
+            let permit = match &self.connections {
+                Some(connections) => connections.clone().acquire_owned().await.ok(),
+                None => None,
+            };
             let (socket, addr) = match listener.accept().await {
                 Ok(accepted) => accepted,
                 Err(e) => {
@@ -118,27 +718,774 @@ impl MllpServer {
 
             info!("New connection from {}", addr);
             
-            // Clone the handler for the new connection
-            let handler = self.handler.clone();
+            // Share the settings with the new connection
+            let settings = settings.clone();
+            let closed = self.status.closed.clone();
+
+            #[cfg(feature = "tls")]
+            if let Some(config) = &self.tls {
+                let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+                tokio::spawn(
+                    async move {
+                        let _permit = permit;
+                        // The handshake runs in the connection's task so a slow client can't hold up accepting
+                        let result = match acceptor.accept(socket).await {
+                            Ok(stream) => until_closed(&closed, addr, handle_connection(stream, addr, settings.clone())).await,
+                            Err(e) => Err(MllpError::IoError(e)),
+                        };
+                        if let Err(e) = result {
+                            error!("Error handling TLS connection from {}: {}", addr, e);
+                            settings.report(&e, ErrorContext::new("connection").with_peer(addr));
+                        }
+                    }
+                    .instrument(info_span!("connection", peer = %addr, tls = true)),
+                );
+                continue;
+            }
             
             // Spawn a new task to handle this connection
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(socket, addr, handler).await {
-                    error!("Error handling connection from {}: {}", addr, e);
+            tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    if let Err(e) = until_closed(&closed, addr, handle_connection(socket, addr, settings.clone())).await {
+                        error!("Error handling connection from {}: {}", addr, e);
+                        settings.report(&e, ErrorContext::new("connection").with_peer(addr));
+                    }
                 }
-            });
+                .instrument(info_span!("connection", peer = %addr)),
+            );
+        }
+    }
+}
+
+/// Handle a connection until it ends or the listener's connections are closed
+async fn until_closed<F>(closed: &tokio_util::sync::CancellationToken, addr: std::net::SocketAddr, connection: F) -> Result<(), MllpError>
+where
+    F: std::future::Future<Output = Result<(), MllpError>>,
+{
+    tokio::select! {
+        result = connection => result,
+        _ = closed.cancelled() => {
+            info!("Closing connection from {} as the listener stopped taking messages", addr);
+            Ok(())
         }
     }
 }
 
+/// Settings for an `MllpServer`, gathered before it's built
+///
+/// ```
+/// use rust_hl7::mllp::{AckMode, MllpServer};
+/// use std::sync::Arc;
+///
+/// let server = MllpServer::builder()
+///     .bind("0.0.0.0:2575")
+///     .handler(Arc::new(Ok))
+///     .ack_mode(AckMode::Immediate)
+///     .max_connections(200)
+///     .build()
+///     .unwrap();
+/// # drop(server);
+/// ```
+///
+/// The address and handler are required. Middleware chains wrap the handler
+/// in the order they're added, the last outermost, wherever `handler` is
+/// called.
+#[derive(Default)]
+pub struct MllpServerBuilder {
+    address: Option<String>,
+    handler: Option<MessageHandler>,
+    query_handler: Option<MessageHandler>,
+    middleware: Vec<Chain>,
+    default_charset: Option<Charset>,
+    ack_options: Option<AckOptions>,
+    ack_mode: Option<AckMode>,
+    max_connections: Option<usize>,
+    pipeline_depth: Option<usize>,
+    max_frame_bytes: Option<usize>,
+    worker_threads: Option<usize>,
+    ack_coalescing: Option<AckCoalescing>,
+    handler_timeout: Option<Duration>,
+    lanes: Option<PriorityLanes>,
+    journal: Option<(Arc<StateJournal>, Unanswered)>,
+    archive: Option<Arc<dyn MessageStore>>,
+    audit: Option<Arc<AuditLog>>,
+    capture: Option<Arc<CaptureWriter>>,
+    rate_limit: Option<RateLimiter>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    clock: Option<Arc<dyn Clock>>,
+    ids: Option<Arc<dyn IdSource>>,
+    stats_window: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+}
+
+impl MllpServerBuilder {
+    /// Address to listen on, e.g. "0.0.0.0:2575"
+    pub fn bind<A: ToString>(mut self, address: A) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    /// Handler each received message is passed to
+    pub fn handler(mut self, handler: MessageHandler) -> Self {
+        self.handler = Some(handler);
+        self
+    }
+
+    /// Handler queries are passed to instead, see `MllpServer::with_query_handler`
+    pub fn query_handler(mut self, handler: MessageHandler) -> Self {
+        self.query_handler = Some(handler);
+        self
+    }
+
+    /// Wrap the handler with a middleware chain
+    pub fn middleware(mut self, chain: Chain) -> Self {
+        self.middleware.push(chain);
+        self
+    }
+
+    /// Accept only TLS connections, using these settings
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Keep at most this many connections open
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Let up to this many frames from one connection wait to be handled
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = Some(depth);
+        self
+    }
+
+    /// Accept frames of up to this many bytes, see `MllpServer::with_max_frame_bytes`
+    pub fn max_frame_bytes(mut self, max: usize) -> Self {
+        self.max_frame_bytes = Some(max);
+        self
+    }
+
+    /// Run handlers on a pool of this many threads
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// Write responses in batches instead of one write per response
+    pub fn ack_coalescing(mut self, coalescing: AckCoalescing) -> Self {
+        self.ack_coalescing = Some(coalescing);
+        self
+    }
+
+    /// NACK a message if its handler hasn't finished this long after it arrived
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// Give handlers for some messages a worker thread before others
+    pub fn priority_lanes(mut self, lanes: PriorityLanes) -> Self {
+        self.lanes = Some(lanes);
+        self
+    }
+
+    /// Keep frames in a journal until they're handled and answered, recovering them on start
+    pub fn recovery(mut self, journal: Arc<StateJournal>, unanswered: Unanswered) -> Self {
+        self.journal = Some((journal, unanswered));
+        self
+    }
+
+    /// When to acknowledge, keeping the other acknowledgment options
+    pub fn ack_mode(mut self, mode: AckMode) -> Self {
+        self.ack_mode = Some(mode);
+        self
+    }
+
+    /// Set when and how messages are acknowledged
+    pub fn ack_options(mut self, options: AckOptions) -> Self {
+        self.ack_options = Some(options);
+        self
+    }
+
+    /// Charset assumed for messages that don't declare one in MSH-18
+    pub fn default_charset(mut self, charset: Charset) -> Self {
+        self.default_charset = Some(charset);
+        self
+    }
+
+    /// Archive every received message and every response sent
+    pub fn archive(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.archive = Some(store);
+        self
+    }
+
+    /// Record every received message in an audit log
+    pub fn audit(mut self, log: Arc<AuditLog>) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Write every frame received and every response sent to a capture file
+    pub fn capture(mut self, capture: Arc<CaptureWriter>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Limit how fast messages are accepted
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(RateLimiter::new(limit));
+        self
+    }
+
+    /// Limit how fast messages are accepted with a limiter of your own
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Report failed connections, unparseable messages and handler errors
+    pub fn error_reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Take acknowledgment timestamps from this clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Give acknowledgments control IDs of their own from this source, see `MllpServer::with_id_source`
+    pub fn id_source(mut self, ids: Arc<dyn IdSource>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// How far back `MllpServer::stats` looks
+    pub fn stats_window(mut self, window: Duration) -> Self {
+        self.stats_window = Some(window);
+        self
+    }
+
+    /// Build the server, failing if the address or handler wasn't given
+    pub fn build(self) -> Result<MllpServer, MllpError> {
+        let address = self.address.ok_or_else(|| MllpError::Config("no address to bind".to_string()))?;
+        let handler = self.handler.ok_or_else(|| MllpError::Config("no message handler".to_string()))?;
+        let mut server = MllpServer::new(address, handler);
+        if let Some(handler) = self.query_handler {
+            server = server.with_query_handler(handler);
+        }
+        for chain in self.middleware {
+            server = server.with_middleware(chain);
+        }
+        let mut ack_options = self.ack_options.unwrap_or_default();
+        if let Some(mode) = self.ack_mode {
+            ack_options.mode = mode;
+        }
+        server = server.with_ack_options(ack_options);
+        if let Some(charset) = self.default_charset {
+            server = server.with_default_charset(charset);
+        }
+        if let Some(max) = self.max_connections {
+            server = server.with_max_connections(max);
+        }
+        if let Some(depth) = self.pipeline_depth {
+            server = server.with_pipeline_depth(depth);
+        }
+        if let Some(max) = self.max_frame_bytes {
+            server = server.with_max_frame_bytes(max);
+        }
+        if let Some(threads) = self.worker_threads {
+            server = server.with_worker_threads(threads);
+        }
+        if let Some(coalescing) = self.ack_coalescing {
+            server = server.with_ack_coalescing(coalescing);
+        }
+        if let Some(timeout) = self.handler_timeout {
+            server = server.with_handler_timeout(timeout);
+        }
+        if let Some(lanes) = self.lanes {
+            server = server.with_priority_lanes(lanes);
+        }
+        if let Some((journal, unanswered)) = self.journal {
+            server = server.with_recovery(journal, unanswered);
+        }
+        if let Some(store) = self.archive {
+            server = server.with_archive(store);
+        }
+        if let Some(log) = self.audit {
+            server = server.with_audit(log);
+        }
+        if let Some(capture) = self.capture {
+            server = server.with_capture(capture);
+        }
+        if let Some(limiter) = self.rate_limit {
+            server = server.with_rate_limiter(limiter);
+        }
+        if let Some(reporter) = self.reporter {
+            server = server.with_error_reporter(reporter);
+        }
+        if let Some(clock) = self.clock {
+            server = server.with_clock(clock);
+        }
+        if let Some(ids) = self.ids {
+            server = server.with_id_source(ids);
+        }
+        if let Some(window) = self.stats_window {
+            server = server.with_stats_window(window);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(config) = self.tls {
+            server = server.with_tls(config);
+        }
+        Ok(server)
+    }
+}
+
+/// MLLP client that sends messages to a remote endpoint and waits for the response
+#[derive(Debug, Clone)]
+pub struct MllpClient {
+    address: String,
+    timeout: Duration,
+    max_frame_bytes: Option<usize>,
+    max_pages: usize,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+}
+
+impl MllpClient {
+    /// Create a new MLLP client for the specified address
+    pub fn new<A: ToString>(address: A) -> Self {
+        Self {
+            address: address.to_string(),
+            timeout: Duration::from_secs(30),
+            max_frame_bytes: None,
+            max_pages: 100,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Connect over TLS, verifying the server's certificate against the address's host name
+    ///
+    /// See `tls::client_config` for settings that trust the system's CAs.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<tokio_rustls::rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Set how long to wait for connecting, sending and receiving the response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Split messages longer than this many bytes into continuation messages
+    ///
+    /// See `continuation::split`. Each part is sent once the one before it is
+    /// acknowledged.
+    pub fn with_max_frame_bytes(mut self, max: usize) -> Self {
+        self.max_frame_bytes = Some(max);
+        self
+    }
+
+    /// Fail a query whose response runs to more than this many pages, 100 by default
+    pub fn with_max_pages(mut self, max: usize) -> Self {
+        self.max_pages = max.max(1);
+        self
+    }
+
+    /// The address this client sends to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Send a message and return the response (normally an ACK)
+    ///
+    /// A message split into continuation messages returns the response to the
+    /// last part, or the first response that isn't an accept.
+    pub async fn send(&self, message: &str) -> Result<String, MllpError> {
+        let Some(max) = self.max_frame_bytes.filter(|&max| message.len() > max) else {
+            return self.send_frame(message).await;
+        };
+        let fragments = crate::continuation::split(&Message::parse(message)?, max)?;
+        let mut response = String::new();
+        for fragment in &fragments {
+            response = self.send_frame(&fragment.to_hl7()).await?;
+            let code = Message::parse(&response).ok().and_then(|ack| crate::terser::get(&ack, "MSA-1"));
+            if !matches!(code.as_deref(), Some("AA" | "CA")) {
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Send a query and return its whole response, asking for each later page in turn
+    ///
+    /// While a response ends in a DSC continuation pointer, the query is sent
+    /// again carrying it (see `paging::continue_query`). The pages are
+    /// returned combined into one response, as `paging::combine` does. A
+    /// page that isn't an accept ends the query early, and a response with
+    /// more pages than `with_max_pages` allows is an error.
+    pub async fn query(&self, query: &Message) -> Result<Message, MllpError> {
+        let mut pages = Vec::new();
+        let mut request = query.clone();
+        loop {
+            let response = Message::parse(&self.send(&request.to_hl7()).await?)?;
+            let accepted = matches!(crate::terser::get(&response, "MSA-1").as_deref(), Some("AA" | "CA"));
+            let pointer = crate::paging::QueryPages::pointer(&response).map(str::to_string);
+            pages.push(response);
+            match pointer {
+                Some(_) if accepted && pages.len() >= self.max_pages => {
+                    return Err(MllpError::Hl7Error(crate::HL7Error::InvalidStructure(format!(
+                        "Query response from {} runs to more than {} pages",
+                        self.address, self.max_pages
+                    ))));
+                }
+                Some(pointer) if accepted => request = crate::paging::continue_query(query, &pointer, pages.len() + 1)?,
+                _ => return Ok(crate::paging::combine(pages)?),
+            }
+        }
+    }
+
+    async fn send_frame(&self, message: &str) -> Result<String, MllpError> {
+        let span = info_span!("mllp_send", address = %self.address, control_id = header_control_id(message));
+        async {
+            let started = Instant::now();
+            let result = tokio::time::timeout(self.timeout, self.exchange(message))
+                .await
+                .unwrap_or_else(|_| Err(MllpError::Timeout(format!("No response from {} within {:?}", self.address, self.timeout))));
+            match &result {
+                Ok(response) => tracing::debug!(elapsed = ?started.elapsed(), bytes = response.len(), "Received response"),
+                Err(e) => tracing::debug!(elapsed = ?started.elapsed(), "Exchange failed: {}", e),
+            }
+            #[cfg(feature = "otel")]
+            crate::otel::record_client_call(&self.address, result.is_ok(), started.elapsed());
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn exchange(&self, message: &str) -> Result<String, MllpError> {
+        let stream = TcpStream::connect(&self.address).await?;
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let host = self.address.rsplit_once(':').map_or(self.address.as_str(), |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(host.to_string())
+                .map_err(|e| MllpError::InvalidFrame(format!("Invalid TLS server name {}: {}", host, e)))?;
+            let stream = tokio_rustls::TlsConnector::from(config.clone())
+                .connect(server_name, stream)
+                .await?;
+            return exchange_on(stream, message).await;
+        }
+
+        exchange_on(stream, message).await
+    }
+}
+
+/// Send a framed message over a connected stream and read the framed response
+async fn exchange_on<S>(mut stream: S, message: &str) -> Result<String, MllpError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // Encode in the charset the message declares
+    let charset = charset::detect(message.as_bytes()).unwrap_or_default();
+    let mut frame = BytesMut::new();
+    frame_into(&mut frame, message, charset);
+    stream.write_all(&frame).await?;
+
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(MllpError::InvalidFrame("Connection closed before a response was received".to_string()));
+        }
+
+        if let Some(response) = extract_mllp_message(&mut buffer)? {
+            let charset = charset::detect(&response).unwrap_or(charset);
+            return Ok(charset.decode(&response)?);
+        }
+    }
+}
+
+/// Settings shared by every connection a server accepts
+struct ConnectionSettings {
+    handler: MessageHandler,
+    query_handler: Option<MessageHandler>,
+    default_charset: Charset,
+    ack_options: AckOptions,
+    archive: Option<Arc<dyn MessageStore>>,
+    audit: Option<Arc<AuditLog>>,
+    capture: Option<Arc<CaptureWriter>>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    clock: Arc<dyn Clock>,
+    ids: Option<Arc<dyn IdSource>>,
+    stats: Arc<StatsRecorder>,
+    pipeline_depth: usize,
+    max_frame_bytes: usize,
+    workers: Option<Arc<WorkerPool>>,
+    ack_coalescing: Option<AckCoalescing>,
+    handler_timeout: Option<Duration>,
+    lanes: Option<Arc<PriorityLanes>>,
+    journal: Option<Arc<StateJournal>>,
+}
+
+impl ConnectionSettings {
+    /// The query handler for queries if there is one, else the handler
+    fn handler_for(&self, message: &Message) -> &MessageHandler {
+        match &self.query_handler {
+            Some(handler) if is_query(message) => handler,
+            _ => &self.handler,
+        }
+    }
+
+    /// A positive acknowledgment stamped with this server's clock and control IDs
+    fn ack(&self, control_id: &str, text: &str) -> Result<String, MllpError> {
+        generate_response(control_id, text, self.clock.as_ref(), self.ids.as_deref())
+    }
+
+    /// A negative acknowledgment stamped with this server's clock and control IDs
+    fn nack(&self, original_message: &str, ack_code: &str, error_msg: &str) -> Result<String, MllpError> {
+        generate_nack(original_message, ack_code, error_msg, self.clock.as_ref(), self.ids.as_deref())
+    }
+
+    /// Archive a message if an archive is configured, logging rather than failing on errors
+    fn archive(&self, raw: &[u8], direction: Direction, disposition: Disposition, addr: std::net::SocketAddr) {
+        if let Some(store) = &self.archive {
+            let record = ArchiveRecord::new(raw, direction, disposition).with_source(addr);
+            if let Err(e) = store.insert(&record) {
+                error!("Failed to archive message from {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Hold a message until the rate limit allows it, or return why it's rejected
+    async fn throttle(&self, message: &Message, addr: std::net::SocketAddr) -> Option<String> {
+        let limiter = self.rate_limit.as_ref()?;
+        let key = limiter.key(message, addr);
+        match limiter.admit(&key) {
+            Admission::Now => None,
+            Admission::After(wait) => {
+                info!("Rate limit reached, delaying message by {:?}", wait);
+                tokio::time::sleep(wait).await;
+                None
+            }
+            Admission::Rejected => Some(format!(
+                "Rate limit of {} messages per second exceeded",
+                limiter.limit().per_second
+            )),
+        }
+    }
+
+    /// Audit a received message on a blocking thread if an audit log is configured, logging rather than failing on errors
+    async fn audit(&self, raw: &[u8], disposition: Disposition, addr: std::net::SocketAddr) {
+        let Some(log) = self.audit.clone() else {
+            return;
+        };
+        let event = AuditEvent::new(raw, disposition.into()).with_source(addr);
+        let recorded = tokio::task::spawn_blocking(move || {
+            if let Err(e) = log.record(event) {
+                error!("Failed to audit message from {} in {}: {}", addr, log.path().display(), e);
+            }
+        });
+        if let Err(e) = recorded.await {
+            error!("Failed to audit message from {}: {}", addr, e);
+        }
+    }
+
+    /// Write a frame to the journal if there is one, on a blocking thread, returning its entry
+    async fn journal_received(&self, text: &str, addr: std::net::SocketAddr) -> Option<u64> {
+        let journal = self.journal.clone()?;
+        let text = text.to_string();
+        let received = tokio::task::spawn_blocking(move || {
+            journal
+                .received(addr, &text)
+                .map_err(|e| error!("Failed to journal message from {} in {}: {}", addr, journal.path().display(), e))
+        });
+        received.await.ok()?.ok()
+    }
+
+    /// Update a frame's journal entry, logging rather than failing on errors; this blocks
+    fn journal(&self, entry: Option<u64>, update: JournalUpdate) {
+        if let (Some(journal), Some(id)) = (&self.journal, entry) {
+            if let Err(e) = update(journal, id) {
+                error!("Failed to update entry {} in {}: {}", id, journal.path().display(), e);
+            }
+        }
+    }
+
+    /// Apply journal updates on a blocking thread, once the responses they follow have been written
+    async fn journal_written(self: &Arc<Self>, updates: &mut Vec<(u64, JournalUpdate)>) {
+        if updates.is_empty() {
+            return;
+        }
+        let updates = std::mem::take(updates);
+        let settings = self.clone();
+        let applied = tokio::task::spawn_blocking(move || {
+            for (id, update) in updates {
+                settings.journal(Some(id), update);
+            }
+        });
+        if let Err(e) = applied.await {
+            error!("Failed to update the journal: {}", e);
+        }
+    }
+
+    /// Capture a frame on a blocking thread if a capture file is configured, logging rather than failing on errors
+    async fn capture(&self, frame: &[u8], direction: Direction, addr: std::net::SocketAddr) {
+        if let Some(capture) = &self.capture {
+            capture::record_in_background(capture, frame, direction, addr).await;
+        }
+    }
+
+    /// Pass an error to the reporter if one is configured
+    fn report(&self, error: &(dyn std::error::Error + 'static), context: ErrorContext) {
+        if let Some(reporter) = &self.reporter {
+            reporter.report(error, &context);
+        }
+    }
+}
+
+/// A change to a frame's journal entry, such as `StateJournal::acked`
+type JournalUpdate = fn(&StateJournal, u64) -> std::io::Result<()>;
+
+/// Map a handler result to the disposition recorded in the archive
+fn disposition_of(result: &Result<Message, crate::HL7Error>) -> Disposition {
+    match result {
+        Ok(_) => Disposition::Accepted,
+        Err(crate::HL7Error::Rejected(_)) => Disposition::Rejected,
+        Err(_) => Disposition::Failed,
+    }
+}
+
 /// Handle a single MLLP connection
-async fn handle_connection(
-    mut socket: TcpStream,
+///
+/// Frames are read and taken off the stream while earlier ones are being
+/// handled, with up to `pipeline_depth` of them waiting, so a sender that
+/// bursts frames before reading ACKs isn't held up. Responses are still sent
+/// in the order the frames arrived.
+async fn handle_connection<S>(
+    socket: S,
     addr: std::net::SocketAddr,
-    handler: MessageHandler,
-) -> Result<(), MllpError> {
-    let (read_half, mut write_half) = socket.split();
-    
+    settings: Arc<ConnectionSettings>,
+) -> Result<(), MllpError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (read_half, mut write_half) = tokio::io::split(socket);
+    let _connection = settings.stats.connect(addr);
+    let (frames, mut queued) = tokio::sync::mpsc::channel(settings.pipeline_depth);
+
+    let respond = async {
+        // Responses are framed here, so sending one doesn't allocate once the buffer has grown
+        let mut write_buffer = BytesMut::with_capacity(1024);
+        // Journal updates to make once the responses in the buffer are written
+        let mut journaled = Vec::new();
+        // The frame being handled's response and journal updates, kept apart so
+        // earlier responses can be written meanwhile
+        let mut response = BytesMut::with_capacity(1024);
+        let mut frame_journal = Vec::new();
+        // When coalescing, the time the oldest unwritten response has to go out by
+        let mut deadline = None;
+        loop {
+            let frame = match deadline {
+                Some(until) => match tokio::time::timeout_at(until, queued.recv()).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        flush_responses(&mut write_half, &mut write_buffer).await?;
+                        settings.journal_written(&mut journaled).await;
+                        deadline = None;
+                        continue;
+                    }
+                },
+                None => queued.recv().await,
+            };
+            let Some(frame) = frame else { break };
+            let handled = {
+                let handling = handle_frame(frame, addr, &settings, &mut response, &mut frame_journal);
+                tokio::pin!(handling);
+                // Responses already waiting still go out by their deadline while a slow handler runs
+                loop {
+                    let Some(until) = deadline else { break handling.await };
+                    tokio::select! {
+                        handled = &mut handling => break handled,
+                        _ = tokio::time::sleep_until(until) => {
+                            flush_responses(&mut write_half, &mut write_buffer).await?;
+                            settings.journal_written(&mut journaled).await;
+                            deadline = None;
+                        }
+                    }
+                }
+            };
+            write_buffer.extend_from_slice(&response);
+            response.clear();
+            journaled.append(&mut frame_journal);
+            let acked = match handled {
+                Ok(acked) => acked,
+                Err(e) => {
+                    // Responses to earlier frames are still owed to the sender
+                    flush_responses(&mut write_half, &mut write_buffer).await?;
+                    settings.journal_written(&mut journaled).await;
+                    return Err(e);
+                }
+            };
+            if let Some(acked) = acked {
+                let placement = match &settings.lanes {
+                    Some(lanes) => Some(match lanes.try_place(&acked.message) {
+                        Some(placement) => placement,
+                        // The lane is full: send the ACK before waiting for room
+                        None => {
+                            flush_responses(&mut write_half, &mut write_buffer).await?;
+                            settings.journal_written(&mut journaled).await;
+                            deadline = None;
+                            lanes.place(&acked.message).await
+                        }
+                    }),
+                    None => None,
+                };
+                dispatch(&settings, addr, acked, placement);
+            }
+            match settings.ack_coalescing {
+                Some(coalescing) if write_buffer.len() < coalescing.max_bytes => {
+                    if !write_buffer.is_empty() && deadline.is_none() {
+                        deadline = Some(tokio::time::Instant::now() + Duration::from_millis(coalescing.max_delay_ms));
+                    }
+                    continue;
+                }
+                _ => {
+                    flush_responses(&mut write_half, &mut write_buffer).await?;
+                    settings.journal_written(&mut journaled).await;
+                }
+            }
+            deadline = None;
+        }
+        flush_responses(&mut write_half, &mut write_buffer).await?;
+        settings.journal_written(&mut journaled).await;
+        Ok::<(), MllpError>(())
+    };
+    tokio::try_join!(read_frames(read_half, addr, &settings, frames), respond)?;
+    Ok(())
+}
+
+/// Read a connection until it closes, queueing each complete frame
+async fn read_frames<R>(
+    read_half: R,
+    addr: std::net::SocketAddr,
+    settings: &ConnectionSettings,
+    frames: tokio::sync::mpsc::Sender<Bytes>,
+) -> Result<(), MllpError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
     let mut read_buffer = BytesMut::with_capacity(4096);
     let mut read_half = tokio::io::BufReader::new(read_half);
     
@@ -146,147 +1493,440 @@ async fn handle_connection(
         // Read data into the buffer
         let bytes_read = read_half.read_buf(&mut read_buffer).await?;
         if bytes_read == 0 {
-            // Connection closed
+            // Connection closed; frames already queued are still answered
             info!("Connection closed by {}", addr);
-            break;
+            return Ok(());
         }
+        settings.stats.update(addr, |connection| connection.bytes_received += bytes_read as u64);
         
-        // Check for a complete MLLP frame
-        if let Some(message_bytes) = extract_mllp_message(&mut read_buffer)? {
-            info!("Received message ({} bytes)", message_bytes.len());
+        // Queue every complete MLLP frame, one read can hold several, waiting while the queue is full
+        while let Some(frame) = extract_mllp_frame(&mut read_buffer, settings.max_frame_bytes)? {
+            if frames.send(frame).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A message ACKed on receipt, to hand to its handler once the ACK is on its way
+struct Dispatch {
+    message: Message,
+    span: tracing::Span,
+    /// The message's journal entry, finished once the handler is done
+    entry: Option<u64>,
+}
+
+/// Handle one frame, adding its response to the connection's write buffer, and
+/// the journal updates to make once it's written to `journaled`
+///
+/// In `AckMode::Immediate` the message is returned to be dispatched, since
+/// waiting for room in its lane mustn't hold up its ACK.
+async fn handle_frame(
+    message_bytes: Bytes,
+    addr: std::net::SocketAddr,
+    settings: &Arc<ConnectionSettings>,
+    write_buffer: &mut BytesMut,
+    journaled: &mut Vec<(u64, JournalUpdate)>,
+) -> Result<Option<Dispatch>, MllpError> {
+    info!("Received message ({} bytes)", message_bytes.len());
+    settings.capture(&message_bytes, Direction::Inbound, addr).await;
+    
+    // Decode using the charset declared in MSH-18, falling back to the listener default
+    let charset = charset::detect(&message_bytes).unwrap_or(settings.default_charset);
+    let message_str = match charset.decode(&message_bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Received message that isn't valid {}: {}", charset.hl7_name(), e);
+            settings.archive(&message_bytes, Direction::Inbound, Disposition::Failed, addr);
+            settings.audit(&message_bytes, Disposition::Failed, addr).await;
+            // Skip this message
+            return Ok(None);
+        }
+    };
+    
+    let received_at = Instant::now();
+    let ack_options = &settings.ack_options;
+    let entry = settings.journal_received(&message_str, addr).await;
+    
+    // Parse HL7 message; logs about it from here on carry its control ID
+    let parsed = Message::parse(&message_str);
+    let span = parsed.as_ref().map_or_else(|_| tracing::Span::current(), Message::span);
+    #[cfg(feature = "otel")]
+    let message_type = parsed.as_ref().map_or_else(|_| "unparseable".to_string(), |m| m.message_type.clone());
+    let rejected = match &parsed {
+        Ok(message) => settings.throttle(message, addr).instrument(span.clone()).await,
+        Err(_) => None,
+    };
+    let (response, disposition) = match parsed {
+        Err(e) => {
+            error!("Error parsing HL7 message: {}", e);
+            settings.report(&e, ErrorContext::new("parse").with_peer(addr));
+            // Send a negative acknowledgment
+            (settings.nack(&message_str, "AE", &e.to_string())?, Disposition::Failed)
+        }
+        Ok(_) if rejected.is_some() => {
+            let reason = rejected.unwrap_or_default();
+            span.in_scope(|| warn!("Rejected message: {}", reason));
+            (settings.nack(&message_str, "AR", &reason)?, Disposition::Rejected)
+        }
+        Ok(hl7_message) => {
+            let control_id = control_id(&hl7_message);
+            let message_type = hl7_message.message_type.clone();
+            // Queries with a handler of their own are always answered by it
+            let handler = settings.handler_for(&hl7_message).clone();
+            let answered = settings.query_handler.is_some() && is_query(&hl7_message);
+            let mode = if answered { AckMode::Application } else { ack_options.mode };
             
-            // Convert to string
-            let message_str = match std::str::from_utf8(&message_bytes) {
-                Ok(s) => s.to_string(),
-                Err(e) => {
-                    warn!("Received non-UTF8 message: {}", e);
-                    // Skip this message
-                    continue;
+            match mode {
+                AckMode::Immediate => {
+                    // Acknowledge receipt first, then hand the message off without waiting
+                    let ack = settings.ack(&control_id, &ack_text(ack_options, received_at))?;
+                    let encoded = queue_frame(write_buffer, &ack, charset);
+                    let sent = encoded.len() + MLLP_FRAMING;
+                    settings.capture(encoded, Direction::Outbound, addr).await;
+                    span.in_scope(|| info!("Sent response ({} bytes)", sent));
+                    settings.stats.update(addr, |connection| connection.bytes_sent += sent as u64);
+                    settings.stats.message(addr, received_at.elapsed(), false);
+                    settings.archive(&message_bytes, Direction::Inbound, Disposition::Accepted, addr);
+                    settings.audit(&message_bytes, Disposition::Accepted, addr).await;
+                    settings.archive(encoded, Direction::Outbound, Disposition::Sent, addr);
+                    journaled.extend(entry.map(|id| (id, StateJournal::acked as JournalUpdate)));
+                    #[cfg(feature = "otel")]
+                    crate::otel::record_message(&message_type, Disposition::Accepted.as_str(), received_at.elapsed());
+                    return Ok(Some(Dispatch { message: hl7_message, span, entry }));
                 }
-            };
-            
-            // Parse HL7 message
-            match Message::parse(&message_str) {
-                Ok(hl7_message) => {
-                    // Process the message with the handler
-                    match handler(hl7_message) {
-                        Ok(response) => {
-                            // Generate acknowledgment
-                            let ack = generate_response(&response)?;
-                            
-                            // Wrap in MLLP frame
-                            let mllp_response = wrap_in_mllp(&ack);
-                            
-                            // Send the response
-                            write_half.write_all(&mllp_response).await?;
-                            info!("Sent response ({} bytes)", mllp_response.len());
+                AckMode::AfterProcessing => {
+                    let (result, started) = run_handler(settings, handler, hl7_message, received_at, span.clone()).await;
+                    let disposition = disposition_of(&result);
+                    let _entered = span.enter();
+                    match result {
+                        Ok(_) => (settings.ack(&control_id, &ack_text(ack_options, received_at))?, disposition),
+                        Err(e) => {
+                            error!("Error processing message: {}", e);
+                            if !matches!(e, crate::HL7Error::Rejected(_)) {
+                                settings.report(&e, ErrorContext::new("handler").with_peer(addr).with_message(&control_id, &message_type));
+                            }
+                            (settings.nack(&message_str, handler_nack_code(&e, started), &e.to_string())?, disposition)
                         }
+                    }
+                }
+                AckMode::Application => {
+                    let (result, started) = run_handler(settings, handler, hl7_message, received_at, span.clone()).await;
+                    let disposition = disposition_of(&result);
+                    let _entered = span.enter();
+                    match result {
+                        // The handler builds its own response, which is sent as-is
+                        Ok(response) => (response.to_hl7(), disposition),
                         Err(e) => {
                             error!("Error processing message: {}", e);
-                            // Send a negative acknowledgment
-                            let nack = generate_nack(&message_str, &e.to_string())?;
-                            let mllp_nack = wrap_in_mllp(&nack);
-                            write_half.write_all(&mllp_nack).await?;
+                            if !matches!(e, crate::HL7Error::Rejected(_)) {
+                                settings.report(&e, ErrorContext::new("handler").with_peer(addr).with_message(&control_id, &message_type));
+                            }
+                            (settings.nack(&message_str, handler_nack_code(&e, started), &e.to_string())?, disposition)
                         }
                     }
                 }
-                Err(e) => {
-                    error!("Error parsing HL7 message: {}", e);
-                    // Send a negative acknowledgment
-                    let nack = generate_nack(&message_str, &e.to_string())?;
-                    let mllp_nack = wrap_in_mllp(&nack);
-                    write_half.write_all(&mllp_nack).await?;
+            }
+        }
+    };
+    
+    // Send the response in an MLLP frame, encoded in the sender's charset
+    let encoded = queue_frame(write_buffer, &response, charset);
+    let sent = encoded.len() + MLLP_FRAMING;
+    span.in_scope(|| info!("Sent response ({} bytes)", sent));
+    settings.capture(encoded, Direction::Outbound, addr).await;
+    settings.stats.update(addr, |connection| connection.bytes_sent += sent as u64);
+    settings.stats.message(addr, received_at.elapsed(), disposition != Disposition::Accepted);
+    
+    #[cfg(feature = "otel")]
+    crate::otel::record_message(&message_type, disposition.as_str(), received_at.elapsed());
+    
+    settings.archive(&message_bytes, Direction::Inbound, disposition, addr);
+    settings.audit(&message_bytes, disposition, addr).await;
+    settings.archive(encoded, Direction::Outbound, Disposition::Sent, addr);
+    // Once the response is written the frame is done; if it never gets there, the sender resends
+    journaled.extend(entry.map(|id| (id, StateJournal::finished as JournalUpdate)));
+    Ok(None)
+}
+
+/// Run the handler for a message already ACKed, without waiting for it
+///
+/// The message keeps its room in its lane, `placement`, until the handler is done.
+fn dispatch(settings: &Arc<ConnectionSettings>, addr: std::net::SocketAddr, dispatch: Dispatch, placement: Option<Placement>) {
+    let Dispatch { message, span, entry } = dispatch;
+    let priority = placement.as_ref().map_or(0, |placement| placement.priority);
+    let handle = {
+        let settings = settings.clone();
+        move || {
+            let _placement = placement;
+            let _entered = span.enter();
+            let (control_id, message_type) = (control_id(&message), message.message_type.clone());
+            if let Err(e) = (settings.handler)(message) {
+                error!("Error processing message {} after ACK: {}", control_id, e);
+                if !matches!(e, crate::HL7Error::Rejected(_)) {
+                    let context = ErrorContext::new("handler").with_peer(addr).with_message(&control_id, &message_type);
+                    settings.report(&e, context);
                 }
             }
+            settings.journal(entry, StateJournal::finished);
         }
+    };
+    match &settings.workers {
+        Some(workers) => workers.spawn_at(priority, handle),
+        None => drop(tokio::task::spawn_blocking(handle)),
+    }
+}
+
+/// Run the handler on the worker pool or Tokio's blocking pool, so the connection's frames are read meanwhile
+///
+/// With priority lanes, the message first waits for room in its lane and then
+/// for a thread behind higher lanes. With a handler timeout, waiting stops at
+/// the deadline, and a handler that only gets a thread after it is skipped.
+/// Along with the result comes whether the handler was called: one that timed
+/// out first never saw the message, but one that was running may still deliver it.
+async fn run_handler(
+    settings: &Arc<ConnectionSettings>,
+    handler: MessageHandler,
+    message: Message,
+    received_at: Instant,
+    span: tracing::Span,
+) -> (Result<Message, crate::HL7Error>, bool) {
+    let timeout = settings.handler_timeout;
+    let timed_out = move || crate::HL7Error::DeliveryError(format!("Handler timed out after {:?}", timeout.unwrap_or_default()));
+    let failed = |e: &dyn std::fmt::Display| crate::HL7Error::DeliveryError(format!("Handler failed: {}", e));
+    // Set once, by the handler starting or the deadline passing, whichever comes first
+    let started = Arc::new(std::sync::OnceLock::new());
+    let run = async {
+        let placement = match &settings.lanes {
+            Some(lanes) => Some(lanes.place(&message).await),
+            None => None,
+        };
+        let handle = {
+            let started = started.clone();
+            move || {
+                if timeout.is_some_and(|timeout| received_at.elapsed() >= timeout) || started.set(true).is_err() {
+                    return Err(timed_out());
+                }
+                span.in_scope(|| handler(message))
+            }
+        };
+        let priority = placement.as_ref().map_or(0, |placement| placement.priority);
+        let result = match &settings.workers {
+            Some(workers) => workers.run_at(priority, handle).await.unwrap_or_else(|e| Err(failed(&e))),
+            None => tokio::task::spawn_blocking(handle).await.unwrap_or_else(|e| Err(failed(&e))),
+        };
+        drop(placement);
+        result
+    };
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout.saturating_sub(received_at.elapsed()), run)
+            .await
+            .unwrap_or_else(|_| {
+                let _ = started.set(false);
+                Err(timed_out())
+            }),
+        None => run.await,
+    };
+    (result, started.get() == Some(&true))
+}
+
+/// Frame a response at the end of a connection's reused buffer, returning the encoded message
+///
+/// The buffer holds the frame until it's written, so the returned bytes can
+/// be captured and archived without another copy.
+fn queue_frame<'b>(buffer: &'b mut BytesMut, text: &str, charset: Charset) -> &'b [u8] {
+    let start = buffer.len();
+    frame_into(buffer, text, charset);
+    &buffer[start + 1..buffer.len() - 2]
+}
+
+/// Write every response waiting in a connection's buffer, leaving it empty for reuse
+async fn flush_responses<W>(writer: &mut W, buffer: &mut BytesMut) -> Result<(), MllpError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    if !buffer.is_empty() {
+        writer.write_all(buffer).await?;
+        writer.flush().await?;
+        buffer.clear();
     }
-    
     Ok(())
 }
 
+/// Encode a message in a charset straight into an MLLP frame at the end of a buffer
+fn frame_into(dst: &mut BytesMut, text: &str, charset: Charset) {
+    dst.reserve(text.len() + MLLP_FRAMING);
+    dst.extend_from_slice(&[MLLP_START_BLOCK]);
+    charset.encode_into(text, dst);
+    dst.extend_from_slice(&[MLLP_END_BLOCK, MLLP_CARRIAGE_RETURN]);
+}
+
+/// Whether a message is a query, going by MSH-9.1
+fn is_query(message: &Message) -> bool {
+    matches!(message.message_type.split('^').next(), Some("QBP" | "QRY"))
+}
+
+/// MSH-10 read straight from the header, for messages that haven't been parsed
+fn header_control_id(message: &str) -> &str {
+    message
+        .get(3..4)
+        .and_then(|separator| message.split(['\r', '\n']).next()?.split(separator).nth(9))
+        .map_or("", |field| field.split('^').next().unwrap_or(field))
+}
+
 /// Extract a complete MLLP message from the buffer
-fn extract_mllp_message(buffer: &mut BytesMut) -> Result<Option<Bytes>, MllpError> {
-    // Look for start block
-    if let Some(start_pos) = buffer.iter().position(|&b| b == MLLP_START_BLOCK) {
-        // Remove anything before the start block
+///
+/// Damaged framing is recovered from rather than waited out: bytes outside a
+/// frame are dropped, a start block inside a frame discards the cut-off frame
+/// before it, and a frame ends at its end block even if the carriage return
+/// after it is missing or preceded by stray bytes.
+pub(crate) fn extract_mllp_message(buffer: &mut BytesMut) -> Result<Option<Bytes>, MllpError> {
+    extract_mllp_frame(buffer, DEFAULT_MAX_FRAME_BYTES)
+}
+
+/// Extract a complete MLLP frame as `extract_mllp_message` does, allowing up to `max_bytes` of buffered data
+pub(crate) fn extract_mllp_frame(buffer: &mut BytesMut, max_bytes: usize) -> Result<Option<Bytes>, MllpError> {
+    loop {
+        // Anything before the start block isn't part of a frame, e.g. the tail of a corrupted one
+        let Some(start_pos) = memchr::memchr(MLLP_START_BLOCK, buffer) else {
+            buffer.clear();
+            return Ok(None);
+        };
         if start_pos > 0 {
+            warn!("Discarding {} bytes outside an MLLP frame", start_pos);
             let _ = buffer.split_to(start_pos);
         }
-        
-        // Now look for end sequence (FS + CR)
-        if let Some(end_pos) = buffer.windows(2).position(|w| w[0] == MLLP_END_BLOCK && w[1] == MLLP_CARRIAGE_RETURN) {
-            // We have a complete message
-            // Extract the entire framed message including start and end markers
-            let mut framed_message = buffer.split_to(end_pos + 2);
-            
-            // Skip the start block
-            let _ = framed_message.split_to(1);
-            
-            // Create a new BytesMut with just the message content (without end sequence)
-            let content_len = framed_message.len() - 2; // Subtract the end sequence
-            let content = framed_message.split_to(content_len);
-            
-            return Ok(Some(content.freeze()));
+
+        match memchr::memchr2(MLLP_END_BLOCK, MLLP_START_BLOCK, &buffer[1..]) {
+            // Another frame started before this one ended, so this one was cut off
+            Some(pos) if buffer[pos + 1] == MLLP_START_BLOCK => {
+                warn!("Discarding an MLLP frame cut off after {} bytes", pos);
+                let _ = buffer.split_to(pos + 1);
+            }
+            Some(pos) => {
+                let mut content = buffer.split_to(pos + 2);
+                let _ = content.split_to(1);
+                content.truncate(pos);
+                // The carriage return ending the frame may not have arrived yet, and is dropped as stray bytes if so
+                if buffer.first() == Some(&MLLP_CARRIAGE_RETURN) {
+                    let _ = buffer.split_to(1);
+                }
+                return Ok(Some(content.freeze()));
+            }
+            None => break,
         }
     }
-    
+
     // No complete message yet
-    if buffer.len() > 100_000 {
+    if buffer.len() > max_bytes {
         // If buffer gets too large without finding a valid frame, something is wrong
         return Err(MllpError::InvalidFrame("Buffer exceeds maximum size without valid frame".to_string()));
     }
-    
+
     Ok(None)
 }
 
-/// Wrap an HL7 message in MLLP frame
-fn wrap_in_mllp(message: &str) -> Vec<u8> {
-    let mut result = Vec::with_capacity(message.len() + 3);
+/// Wrap an encoded HL7 message in MLLP frame
+pub(crate) fn wrap_in_mllp(message: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(message.len() + MLLP_FRAMING);
     result.push(MLLP_START_BLOCK);
-    result.extend_from_slice(message.as_bytes());
+    result.extend_from_slice(message);
     result.push(MLLP_END_BLOCK);
     result.push(MLLP_CARRIAGE_RETURN);
     result
 }
 
-/// Generate an HL7 ACK (acknowledgment) message for the given message
-fn generate_response(_message: &Message) -> Result<String, MllpError> {
-    // In a real implementation, you would build a proper ACK message based on the input
-    // For this example, we'll create a simple ACK
+/// Extract the message control ID (MSH-10) from a parsed message
+pub(crate) fn control_id(message: &Message) -> String {
+    message.control_id().unwrap_or("UNKNOWN").to_string()
+}
+
+/// Build the MSA-3 text for a successful acknowledgment
+fn ack_text(options: &AckOptions, received_at: Instant) -> String {
+    let mut text = "Message processed successfully".to_string();
+    
+    let mut details = Vec::new();
+    if options.include_latency {
+        details.push(format!("latency={}ms", received_at.elapsed().as_millis()));
+    }
+    if let Some(identity) = &options.server_identity {
+        details.push(format!("server={}", identity));
+    }
     
+    if !details.is_empty() {
+        text.push_str(&format!(" ({})", details.join(", ")));
+    }
+    
+    text
+}
+
+/// Text for MSA-3 as written into an ACK, with delimiters and line breaks escaped so it stays one field
+fn msa_text(text: &str) -> String {
+    let delimiters = Delimiters::default();
+    let line_break = |code: &str| format!("{0}X{1}{0}", delimiters.escape, code);
+    delimiters.escape(text).replace('\r', &line_break("0D")).replace('\n', &line_break("0A"))
+}
+
+/// Generate an HL7 ACK (acknowledgment) message for the given control ID
+///
+/// MSH-10 echoes the control ID acknowledged, as MSA-2 does, unless `ids` gives the ACK its own.
+pub(crate) fn generate_response(control_id: &str, text: &str, clock: &dyn Clock, ids: Option<&dyn IdSource>) -> Result<String, MllpError> {
     // Get current time in HL7 format
-    let now = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+    let now = clock.now().with_timezone(&chrono::Utc).format("%Y%m%d%H%M%S%z").to_string();
     
     // Build ACK message
-    // In a real implementation, we would extract the message control ID from the original message
-    // and other fields to create a proper ACK
     let ack = format!(
-        "MSH|^~\\&|RECEIVING_APP|RECEIVING_FACILITY|SENDING_APP|SENDING_FACILITY|{}||ACK|MSG00001|P|2.5\r\n\
-         MSA|AA|MSG00001|Message processed successfully",
-        now
+        "MSH|^~\\&|RECEIVING_APP|RECEIVING_FACILITY|SENDING_APP|SENDING_FACILITY|{}||ACK|{}|P|2.5\r\n\
+         MSA|AA|{}|{}",
+        now, ids.map_or_else(|| control_id.to_string(), |ids| ids.next_id()), control_id, msa_text(text)
     );
     
     Ok(ack)
 }
 
+/// Choose the MSA-1 code for a handler error: AR for rejections, AE for other errors
+pub(crate) fn nack_code(error: &crate::HL7Error) -> &'static str {
+    match error {
+        crate::HL7Error::Rejected(_) => "AR",
+        _ => "AE",
+    }
+}
+
+/// The NACK code for a handler's error, CR if it timed out before it was called
+///
+/// A commit reject tells the sender the message was never taken on, so
+/// resending it can't deliver it twice.
+fn handler_nack_code(error: &crate::HL7Error, started: bool) -> &'static str {
+    if started {
+        nack_code(error)
+    } else {
+        "CR"
+    }
+}
+
 /// Generate a negative acknowledgment (NACK) message for a failed HL7 message
-fn generate_nack(original_message: &str, error_msg: &str) -> Result<String, MllpError> {
+pub(crate) fn generate_nack(
+    original_message: &str,
+    ack_code: &str,
+    error_msg: &str,
+    clock: &dyn Clock,
+    ids: Option<&dyn IdSource>,
+) -> Result<String, MllpError> {
     // Get current time in HL7 format
-    let now = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+    let now = clock.now().with_timezone(&chrono::Utc).format("%Y%m%d%H%M%S%z").to_string();
     
-    // Find message control ID from original message, defaulting to "UNKNOWN" if not found
-    let control_id = if let Some(msh_line) = original_message.lines().next() {
-        let fields: Vec<&str> = msh_line.split('|').collect();
-        fields.get(9).unwrap_or(&"UNKNOWN").to_string()
-    } else {
-        "UNKNOWN".to_string()
-    };
+    // The message may not parse, so whatever MSH-10 the parser can recover is echoed, or "UNKNOWN"
+    let control_id = control_id(&Message::parse_recover(original_message).0);
     
     // Build NACK message
     let nack = format!(
         "MSH|^~\\&|RECEIVING_APP|RECEIVING_FACILITY|SENDING_APP|SENDING_FACILITY|{}||ACK|{}|P|2.5\r\n\
-         MSA|AE|{}|Error processing message: {}",
-        now, control_id, control_id, error_msg
+         MSA|{}|{}|{}",
+        now,
+        ids.map_or_else(|| control_id.clone(), |ids| ids.next_id()),
+        ack_code,
+        control_id,
+        msa_text(&format!("Error processing message: {}", error_msg))
     );
     
     Ok(nack)
@@ -0,0 +1,213 @@
+//! Memory-mapped reading of large archive files
+//!
+//! Reprocessing a multi-GB `.hl7` export with `read_to_string` copies all of
+//! it into memory before the first message is looked at. A `MappedMessages`
+//! maps the file instead and hands out `MessageRef`s, which borrow each
+//! message's bytes from the mapping. The OS pages the file in as it's read,
+//! and a message is only copied when it's parsed.
+//!
+//! Messages are found the way `filedrop::split_messages` finds them: each
+//! starts at an MSH segment, batch and file header and trailer segments are
+//! skipped, and MLLP framing characters left in the file are ignored.
+//!
+//! ```no_run
+//! use rust_hl7::Message;
+//!
+//! // SAFETY: archives are only ever replaced by renaming, never changed in place
+//! let archive = unsafe { Message::parse_iter_mmap("archive/2024-05.hl7")? };
+//! for message in &archive {
+//!     if message.message_type() == Some("ORU") {
+//!         let parsed = message.parse()?;
+//!         println!("{} has {} segments", message.control_id().unwrap_or("?"), parsed.segments.len());
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::charset::{self, Charset};
+use crate::{HL7Error, Message};
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+const MLLP_START_BLOCK: u8 = 0x0B;
+const MLLP_END_BLOCK: u8 = 0x1C;
+
+/// A file of messages mapped into memory
+#[derive(Debug)]
+pub struct MappedMessages {
+    map: Mmap,
+}
+
+impl MappedMessages {
+    /// Map a file for reading
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified, by this or any other
+    /// process, while it's mapped: the messages borrow the mapped bytes, and
+    /// changing them underneath is undefined behaviour, while truncating the
+    /// file makes reads past its new end fault. That holds for archives and
+    /// exports that are only appended to after the mapping ends, or replaced
+    /// by renaming. Read the file instead when that can't be promised.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only, and the caller promises the file
+        // isn't changed underneath it
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map })
+    }
+
+    /// The whole file
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// The messages in the file, in order
+    pub fn iter(&self) -> MessageRefs<'_> {
+        MessageRefs {
+            bytes: &self.map,
+            position: 0,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a MappedMessages {
+    type Item = MessageRef<'a>;
+    type IntoIter = MessageRefs<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The messages in a mapped file, found without copying them
+#[derive(Debug, Clone)]
+pub struct MessageRefs<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Iterator for MessageRefs<'a> {
+    type Item = MessageRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = None;
+        let mut end = 0;
+        while self.position < self.bytes.len() {
+            let line_end = memchr::memchr2(b'\r', b'\n', &self.bytes[self.position..])
+                .map_or(self.bytes.len(), |i| self.position + i);
+            let (from, to) = trim_framing(self.bytes, self.position, line_end);
+            let line = &self.bytes[from..to];
+
+            // The next MSH starts the next message, so it's left for the next call
+            if start.is_some() && line.starts_with(b"MSH") {
+                break;
+            }
+            self.position = line_end + 1;
+            if line.is_empty() {
+                continue;
+            }
+            if [b"FHS", b"BHS", b"BTS", b"FTS"].iter().any(|name| line.starts_with(*name)) {
+                if start.is_some() {
+                    break;
+                }
+                continue;
+            }
+            // Segments before the first MSH don't belong to a message
+            if start.is_none() {
+                if !line.starts_with(b"MSH") {
+                    continue;
+                }
+                start = Some(from);
+            }
+            end = to;
+        }
+        let start = start?;
+        Some(MessageRef {
+            bytes: &self.bytes[start..end],
+            offset: start,
+        })
+    }
+}
+
+/// The bounds of a line without any MLLP framing characters around it
+fn trim_framing(bytes: &[u8], mut from: usize, mut to: usize) -> (usize, usize) {
+    while from < to && matches!(bytes[from], MLLP_START_BLOCK | MLLP_END_BLOCK) {
+        from += 1;
+    }
+    while to > from && matches!(bytes[to - 1], MLLP_START_BLOCK | MLLP_END_BLOCK) {
+        to -= 1;
+    }
+    (from, to)
+}
+
+/// One message in a mapped file, borrowed from the mapping
+///
+/// Header values are read straight from the MSH segment, so a job can skip
+/// the messages it isn't interested in without parsing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageRef<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> MessageRef<'a> {
+    /// The message as it is in the file, from MSH to the end of its last segment
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Where the message starts in the file, e.g. for checkpointing a job
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The charset declared in MSH-18, or UTF-8
+    pub fn charset(&self) -> Charset {
+        charset::detect(self.bytes).unwrap_or_default()
+    }
+
+    /// The message as text, borrowed unless it has to be decoded from its charset
+    pub fn text(&self) -> Result<Cow<'a, str>, HL7Error> {
+        match self.charset() {
+            Charset::Utf8 => std::str::from_utf8(self.bytes)
+                .map(Cow::Borrowed)
+                .map_err(|e| HL7Error::EncodingError(format!("Invalid UTF-8: {}", e))),
+            charset => charset.decode(self.bytes).map(Cow::Owned),
+        }
+    }
+
+    /// Parse the message into an owned `Message`
+    pub fn parse(&self) -> Result<Message, HL7Error> {
+        Message::parse(&self.text()?)
+    }
+
+    /// MSH-9.1, e.g. "ADT"
+    pub fn message_type(&self) -> Option<&'a str> {
+        self.header(9)
+    }
+
+    /// MSH-10, the message control ID
+    pub fn control_id(&self) -> Option<&'a str> {
+        self.header(10)
+    }
+
+    /// MSH-3, the sending application
+    pub fn sending_application(&self) -> Option<&'a str> {
+        self.header(3)
+    }
+
+    /// The first component of an MSH field, if it's there and not empty
+    fn header(&self, n: usize) -> Option<&'a str> {
+        let separator = *self.bytes.get(3)?;
+        let msh_end = memchr::memchr2(b'\r', b'\n', self.bytes).unwrap_or(self.bytes.len());
+        // MSH-1 is the separator itself, so MSH-n is the (n - 1)th value after the name
+        let field = self.bytes[..msh_end].split(|&b| b == separator).nth(n - 1)?;
+        let component_separator = self.bytes.get(4).copied().unwrap_or(b'^');
+        let component = field.split(|&b| b == component_separator).next()?;
+        std::str::from_utf8(component).ok().filter(|value| !value.is_empty())
+    }
+}
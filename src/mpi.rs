@@ -0,0 +1,213 @@
+use crate::middleware::{Middleware, Next};
+use crate::pix::{identifiers, CrossReference, IdentifierStore, PatientIdentifier};
+use crate::store::StoreError;
+use crate::transform::Transform;
+use crate::{parse_field, Delimiters, HL7Error, Message};
+use std::sync::Arc;
+
+/// A master patient index built from ADT messages, giving each patient an enterprise ID
+///
+/// Matching is deterministic, on identifiers alone: every identifier in a
+/// message's PID-3 (with its assigning authority) is the same patient, so a
+/// message listing identifiers already filed under different enterprise IDs
+/// brings them together under the oldest one. An A40 merge moves the MRG-1
+/// patient's identifiers to the PID-3 patient. Enterprise IDs that are
+/// replaced this way are retired rather than reused, and `resolve` maps them
+/// to the one that took their place.
+///
+/// The patients are kept in a PIX `IdentifierStore`, whose patient numbers
+/// are the enterprise IDs; `store` sets a durable one such as
+/// `SqliteIdentifiers`.
+///
+/// ```
+/// use rust_hl7::mpi::PatientIndex;
+/// use rust_hl7::pix::PatientIdentifier;
+/// use rust_hl7::Message;
+///
+/// let index = PatientIndex::new().prefix("EID");
+/// index.update(&Message::parse("MSH|^~\\&|REG|HOSP|||20240501||ADT^A04|1|P|2.5\rPID|1||12345^^^HOSP^MR~987^^^CLINIC").unwrap()).unwrap();
+/// assert_eq!(index.lookup(&PatientIdentifier::new("987", "CLINIC")).unwrap().as_deref(), Some("EID1"));
+/// ```
+#[derive(Debug)]
+pub struct PatientIndex {
+    store: Arc<dyn IdentifierStore>,
+    prefix: String,
+    authority: String,
+}
+
+impl Default for PatientIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatientIndex {
+    /// An empty index handing out enterprise IDs "E1", "E2" and so on
+    pub fn new() -> Self {
+        Self {
+            store: Arc::new(CrossReference::new()),
+            prefix: "E".to_string(),
+            authority: "MPI".to_string(),
+        }
+    }
+
+    /// Keep the patients in this store instead of in memory
+    ///
+    /// A store shared with a `PixManager` gets the same patients, but the
+    /// manager retires prior identifiers on a merge where the index keeps them.
+    pub fn store(mut self, store: Arc<dyn IdentifierStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Start enterprise IDs with this instead of "E"
+    pub fn prefix<P: ToString>(mut self, prefix: P) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// The assigning authority of enterprise IDs added to PID-3, "MPI" unless set
+    ///
+    /// Identifiers from this authority are never matched on, so messages the
+    /// index has already enriched can be filed again.
+    pub fn authority<A: ToString>(mut self, authority: A) -> Self {
+        self.authority = authority.to_string();
+        self
+    }
+
+    /// File a message's patient, returning its enterprise ID
+    ///
+    /// Registrations and updates (A01, A04, A05, A08, A28 and A31) file the
+    /// identifiers in PID-3, and A40 merges fold MRG-1 into them. Other
+    /// messages are looked up without changing the index.
+    pub fn update(&self, message: &Message) -> Result<Option<String>, StoreError> {
+        let mut parts = message.message_type.split('^');
+        let id = match (parts.next(), parts.next()) {
+            (Some("ADT"), Some("A01" | "A04" | "A05" | "A08" | "A28" | "A31")) => {
+                self.store.link(&self.identifiers_in(message, "PID", 3))?
+            }
+            // Late messages that still carry a prior identifier find the surviving patient
+            (Some("ADT"), Some("A40")) => {
+                self.store.merge(&self.identifiers_in(message, "PID", 3), &self.identifiers_in(message, "MRG", 1))?
+            }
+            _ => return self.enterprise_id(message),
+        };
+        Ok(id.map(|id| self.format(id)))
+    }
+
+    /// The enterprise ID of the patient with this identifier
+    pub fn lookup(&self, identifier: &PatientIdentifier) -> Result<Option<String>, StoreError> {
+        Ok(self.store.patient(identifier)?.map(|id| self.format(id)))
+    }
+
+    /// The enterprise ID of a message's patient, from the first PID-3 identifier the index knows
+    pub fn enterprise_id(&self, message: &Message) -> Result<Option<String>, StoreError> {
+        for identifier in self.identifiers_in(message, "PID", 3) {
+            if let Some(id) = self.lookup(&identifier)? {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The identifiers filed under an enterprise ID, or under the one that replaced it
+    pub fn identifiers(&self, enterprise_id: &str) -> Result<Vec<PatientIdentifier>, StoreError> {
+        match self.parse(enterprise_id) {
+            Some(id) => Ok(self.store.identifiers(id)?.into_iter().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The enterprise ID in use for one that may have been retired by a merge
+    pub fn resolve(&self, enterprise_id: &str) -> Result<Option<String>, StoreError> {
+        match self.parse(enterprise_id) {
+            Some(id) => Ok(self.store.resolve(id)?.map(|id| self.format(id))),
+            None => Ok(None),
+        }
+    }
+
+    /// How many patients the index holds
+    pub fn patients(&self) -> Result<usize, StoreError> {
+        self.store.patients()
+    }
+
+    /// The identifiers in a CX field, leaving out enterprise IDs
+    fn identifiers_in(&self, message: &Message, segment: &str, field: usize) -> Vec<PatientIdentifier> {
+        let mut found = identifiers(message, segment, field);
+        found.retain(|identifier| identifier.authority != self.authority);
+        found
+    }
+
+    fn format(&self, id: u64) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    fn parse(&self, enterprise_id: &str) -> Option<u64> {
+        enterprise_id.strip_prefix(&self.prefix)?.parse().ok()
+    }
+}
+
+/// Files each message a server receives before passing it on
+impl Middleware for PatientIndex {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        if let Err(e) = self.update(&message) {
+            return Err(HL7Error::DeliveryError(format!("Patient index store error: {}", e)));
+        }
+        next(message)
+    }
+}
+
+/// A transform that adds the enterprise ID from a `PatientIndex` to PID-3
+///
+/// The enterprise ID goes in a PID-3 repetition with the index's assigning
+/// authority, replacing any already there. Messages whose patient the index
+/// doesn't know are left alone. The transform only looks patients up; file
+/// messages by layering the index on the server's middleware, so a route
+/// run again (a replay, say) doesn't change the index.
+///
+/// ```ignore
+/// let index = Arc::new(PatientIndex::new());
+/// let server = MllpServer::builder().middleware(Chain::new().layer(index.clone()))...
+/// let route = Route::new("ehr", Predicate::Always)
+///     .with_transform(Pipeline::new().then(EnterpriseIds::new(index.clone())))
+///     .to(ehr);
+/// ```
+#[derive(Debug, Clone)]
+pub struct EnterpriseIds {
+    index: Arc<PatientIndex>,
+}
+
+impl EnterpriseIds {
+    pub fn new(index: Arc<PatientIndex>) -> Self {
+        Self { index }
+    }
+}
+
+impl Transform for EnterpriseIds {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        let enterprise_id = self
+            .index
+            .enterprise_id(message)
+            .map_err(|e| HL7Error::DeliveryError(format!("Patient index store error: {}", e)))?;
+        let Some(enterprise_id) = enterprise_id else {
+            return Ok(());
+        };
+        let delimiters = Delimiters::default();
+        let Some(pid) = message.segments.iter_mut().find(|s| s.name == "PID") else {
+            return Ok(());
+        };
+        while pid.fields.len() < 3 {
+            pid.fields.push(parse_field("", &delimiters));
+        }
+        let existing = pid.fields[2].to_hl7(&delimiters);
+        let mut repetitions: Vec<String> = existing
+            .split('~')
+            .filter(|cx| !cx.is_empty())
+            .filter(|cx| PatientIdentifier::from_cx(cx).is_none_or(|id| id.authority != self.index.authority))
+            .map(str::to_string)
+            .collect();
+        repetitions.push(PatientIdentifier::new(enterprise_id, &self.index.authority).to_cx());
+        pid.fields[2] = parse_field(&repetitions.join("~"), &delimiters);
+        Ok(())
+    }
+}
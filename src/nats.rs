@@ -0,0 +1,178 @@
+use crate::charset::{self, Charset};
+use crate::clock::SystemClock;
+use crate::mllp::{self, MessageHandler};
+use crate::{terser, HL7Error, Message};
+use futures::StreamExt;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::{error, info, warn};
+
+/// Errors that can occur talking to a NATS server
+#[derive(Debug, Error)]
+pub enum NatsError {
+    #[error("NATS connection error: {0}")]
+    ConnectError(String),
+
+    #[error("NATS publish error: {0}")]
+    PublishError(String),
+
+    #[error("NATS subscribe error: {0}")]
+    SubscribeError(String),
+}
+
+/// Connect to a NATS server
+async fn connect(url: &str) -> Result<async_nats::Client, NatsError> {
+    async_nats::connect(url)
+        .await
+        .map_err(|e| NatsError::ConnectError(format!("{}: {}", url, e)))
+}
+
+/// Publishes messages to a NATS subject
+///
+/// The connection is opened on first use and shared by clones. Each message is
+/// published as ER7 text in its MSH-18 charset, with `HL7-Message-Type` and
+/// `HL7-Control-ID` headers so subscribers can filter without parsing.
+#[derive(Debug, Clone)]
+pub struct NatsSink {
+    url: String,
+    subject: String,
+    client: Arc<OnceCell<async_nats::Client>>,
+}
+
+impl NatsSink {
+    /// Publish to `subject` on the server at `url`, e.g. "nats://localhost:4222"
+    pub fn new<U: ToString, S: ToString>(url: U, subject: S) -> Self {
+        Self {
+            url: url.to_string(),
+            subject: subject.to_string(),
+            client: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Server URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Subject messages are published to
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Publish a message and wait for the server to receive it
+    pub async fn publish(&self, message: &Message) -> Result<(), NatsError> {
+        let client = self.client.get_or_try_init(|| connect(&self.url)).await?;
+
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("HL7-Message-Type", message.message_type.as_str());
+        if let Some(control_id) = message.control_id() {
+            headers.insert("HL7-Control-ID", control_id);
+        }
+
+        let charset = terser::get(message, "MSH-18")
+            .and_then(|name| Charset::from_hl7(&name))
+            .unwrap_or_default();
+        let payload = charset.encode(&message.to_hl7());
+
+        client
+            .publish_with_headers(self.subject.clone(), headers, payload.into())
+            .await
+            .map_err(|e| NatsError::PublishError(e.to_string()))?;
+        client
+            .flush()
+            .await
+            .map_err(|e| NatsError::PublishError(e.to_string()))
+    }
+}
+
+/// Receives messages from a NATS subject and passes them to a handler
+///
+/// Messages published with a reply subject (e.g. with `nats request`) get an
+/// ACK or NACK reply, like an MLLP sender would after processing.
+#[derive(Debug, Clone)]
+pub struct NatsSource {
+    url: String,
+    subject: String,
+    queue_group: Option<String>,
+    default_charset: Charset,
+}
+
+impl NatsSource {
+    /// Subscribe to `subject` on the server at `url`
+    pub fn new<U: ToString, S: ToString>(url: U, subject: S) -> Self {
+        Self {
+            url: url.to_string(),
+            subject: subject.to_string(),
+            queue_group: None,
+            default_charset: Charset::default(),
+        }
+    }
+
+    /// Share the subscription with other instances in the same queue group,
+    /// so each message is handled by only one of them
+    pub fn with_queue_group<G: ToString>(mut self, group: G) -> Self {
+        self.queue_group = Some(group.to_string());
+        self
+    }
+
+    /// Set the charset assumed when a message doesn't declare one in MSH-18
+    pub fn with_default_charset(mut self, charset: Charset) -> Self {
+        self.default_charset = charset;
+        self
+    }
+
+    /// Handle messages until the subscription ends
+    ///
+    /// The handler runs on Tokio's blocking thread pool, one message at a time.
+    pub async fn run(&self, handler: MessageHandler) -> Result<(), NatsError> {
+        let client = connect(&self.url).await?;
+        let mut subscriber = match &self.queue_group {
+            Some(group) => client.queue_subscribe(self.subject.clone(), group.clone()).await,
+            None => client.subscribe(self.subject.clone()).await,
+        }
+        .map_err(|e| NatsError::SubscribeError(e.to_string()))?;
+        info!("Subscribed to NATS subject {} on {}", self.subject, self.url);
+
+        while let Some(delivery) = subscriber.next().await {
+            let charset = charset::detect(&delivery.payload).unwrap_or(self.default_charset);
+            let text = match charset.decode(&delivery.payload) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Received NATS message that isn't valid {}: {}", charset.hl7_name(), e);
+                    continue;
+                }
+            };
+
+            let response = match Message::parse(&text) {
+                Err(e) => {
+                    error!("Error parsing HL7 message from NATS: {}", e);
+                    mllp::generate_nack(&text, "AE", &e.to_string(), &SystemClock, None)
+                }
+                Ok(message) => {
+                    let control_id = mllp::control_id(&message);
+                    // Handlers may block, e.g. on an archive or Redis, so keep them off the runtime's threads
+                    let handler = handler.clone();
+                    let result = tokio::task::spawn_blocking(move || handler(message))
+                        .await
+                        .unwrap_or_else(|e| Err(HL7Error::DeliveryError(format!("Handler failed: {}", e))));
+                    match result {
+                        Ok(_) => mllp::generate_response(&control_id, "Message processed successfully", &SystemClock, None),
+                        Err(e) => {
+                            error!("Error processing message from NATS: {}", e);
+                            mllp::generate_nack(&text, mllp::nack_code(&e), &e.to_string(), &SystemClock, None)
+                        }
+                    }
+                }
+            };
+
+            if let (Some(reply), Ok(response)) = (delivery.reply, response) {
+                if let Err(e) = client.publish(reply, charset.encode(&response).into()).await {
+                    warn!("Failed to reply to NATS message: {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
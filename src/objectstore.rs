@@ -0,0 +1,546 @@
+//! The message archive and capture files in S3 or another object store
+//!
+//! Records are spooled to a local file and uploaded in batches, one
+//! compressed JSON Lines object per batch and hour, under keys partitioned by
+//! the hour the messages were archived:
+//!
+//! ```text
+//! <prefix>/messages/2024/05/01/13/<first id>-<last id>.jsonl.zst
+//! <prefix>/index/2024/05/01/13/<first id>-<last id>.json
+//! <prefix>/captures/2024/05/01/<capture file name>
+//! ```
+//!
+//! Each batch has a manifest under `index/` giving its time range and the
+//! message types, control IDs, patient IDs and sending applications in it, so
+//! a query only downloads the batches that can match. Objects are never
+//! rewritten, which suits buckets with object lock, and date-partitioned keys
+//! suit lifecycle rules moving older messages to colder storage classes.
+
+use crate::compress::Codec;
+use crate::store::{ArchiveRecord, Direction, MessageStore, Query, StoreError};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Runtime the object store clients run on, so the synchronous archive can
+/// wait for them from any thread, in or out of another runtime
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("rust-hl7-objectstore")
+            .enable_all()
+            .build()
+            .expect("object store runtime can be built")
+    })
+}
+
+fn block_on<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> T {
+    futures::executor::block_on(runtime().spawn(future)).expect("object store task doesn't panic")
+}
+
+fn object_error(error: object_store::Error) -> StoreError {
+    StoreError::BlobError(error.to_string())
+}
+
+/// A record as a line of the spool
+fn spool_line(record: &ArchiveRecord) -> Result<Vec<u8>, StoreError> {
+    let mut line = serde_json::to_vec(record).map_err(|e| StoreError::InvalidValue(e.to_string()))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// What's in one batch, written beside it under `index/`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    /// Key of the batch, relative to the archive's prefix
+    object: String,
+    codec: Codec,
+    count: usize,
+    /// Total size of the raw messages
+    bytes: u64,
+    earliest: DateTime<Utc>,
+    latest: DateTime<Utc>,
+    directions: Vec<Direction>,
+    message_types: BTreeSet<String>,
+    control_ids: BTreeSet<String>,
+    patient_ids: BTreeSet<String>,
+    sending_applications: BTreeSet<String>,
+}
+
+impl Manifest {
+    fn new(object: String, codec: Codec, records: &[ArchiveRecord]) -> Self {
+        let values = |value: fn(&ArchiveRecord) -> &Option<String>| records.iter().filter_map(|r| value(r).clone()).collect();
+        let mut directions: Vec<Direction> = Vec::new();
+        for record in records {
+            if !directions.contains(&record.direction) {
+                directions.push(record.direction);
+            }
+        }
+        Self {
+            object,
+            codec,
+            count: records.len(),
+            bytes: records.iter().map(|r| r.raw.len() as u64).sum(),
+            earliest: records.iter().map(|r| r.timestamp).min().unwrap_or_default(),
+            latest: records.iter().map(|r| r.timestamp).max().unwrap_or_default(),
+            directions,
+            message_types: values(|r| &r.message_type),
+            control_ids: values(|r| &r.control_id),
+            patient_ids: values(|r| &r.patient_id),
+            sending_applications: values(|r| &r.sending_application),
+        }
+    }
+
+    /// Whether any record in the batch could match the query
+    fn may_match(&self, query: &Query) -> bool {
+        let contains = |wanted: &Option<String>, values: &BTreeSet<String>| wanted.as_ref().is_none_or(|w| values.contains(w));
+        query
+            .message_type
+            .as_ref()
+            .is_none_or(|t| self.message_types.iter().any(|m| m.starts_with(t.as_str())))
+            && contains(&query.control_id, &self.control_ids)
+            && contains(&query.patient_id, &self.patient_ids)
+            && contains(&query.sending_application, &self.sending_applications)
+            && query.direction.is_none_or(|d| self.directions.contains(&d))
+            && query.since.is_none_or(|s| self.latest >= s)
+            && query.until.is_none_or(|u| self.earliest < u)
+    }
+}
+
+/// Records waiting to be written, and the local file holding them until they are
+#[derive(Debug)]
+struct Pending {
+    records: Vec<ArchiveRecord>,
+    since: Instant,
+    spool: File,
+}
+
+/// Open a spool, returning it and the records left in it by the last run
+///
+/// A last line cut short by a crash is ignored.
+fn open_spool(path: &Path) -> Result<(File, Vec<ArchiveRecord>), StoreError> {
+    let spool_error = |e: std::io::Error| StoreError::BlobError(format!("spool {}: {}", path.display(), e));
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(spool_error)?;
+    }
+    let mut records = Vec::new();
+    match std::fs::read(path) {
+        Ok(lines) => {
+            for line in lines.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+                match serde_json::from_slice(line) {
+                    Ok(record) => records.push(record),
+                    Err(e) => warn!("Skipping an unreadable record in archive spool {}: {}", path.display(), e),
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(spool_error(e)),
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(spool_error)?;
+    Ok((file, records))
+}
+
+/// An archive kept as compressed, time-partitioned objects
+///
+/// `insert` appends each record to a local spool file and waits for the disk
+/// before returning, so a record it accepts survives a crash. The records
+/// are uploaded by `spawn_flushing` once `batch_size` of them are waiting or
+/// the oldest has waited `max_wait`, or straight away by `flush`; only then
+/// are they dropped from the spool. Queries see waiting records all the same.
+/// While uploads fail, records keep waiting, and once `max_pending` are
+/// waiting `insert` refuses more and `check_writable` reports the archive
+/// unwritable. A crash between an upload and the spool being rewritten
+/// uploads that batch again under the same key.
+///
+/// `prune` deletes whole batches, so messages may outlive the cutoff by up to
+/// an hour. Archives kept for years are better expired by the bucket's
+/// lifecycle rules, which work on the same date-partitioned keys.
+///
+/// ```no_run
+/// use rust_hl7::compress::Codec;
+/// use rust_hl7::objectstore::ObjectArchive;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// // Credentials and region come from AWS_* environment variables
+/// let archive = Arc::new(ObjectArchive::open("s3://compliance-archive/hl7", "/var/spool/hl7/archive.jsonl")?.codec(Codec::Zstd));
+/// archive.spawn_flushing(Duration::from_secs(10));
+/// # Ok(())
+/// # }
+/// ```
+pub struct ObjectArchive {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    codec: Codec,
+    batch_size: usize,
+    max_wait: Duration,
+    max_pending: usize,
+    spool: PathBuf,
+    pending: Mutex<Pending>,
+    /// Held while uploading, so two flushes never upload the same records
+    flushing: Mutex<()>,
+    /// Why the last flush failed, until one succeeds
+    failure: Mutex<Option<String>>,
+    next_id: AtomicI64,
+}
+
+impl std::fmt::Debug for ObjectArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectArchive")
+            .field("store", &self.store.to_string())
+            .field("prefix", &self.prefix)
+            .field("codec", &self.codec)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ObjectArchive {
+    /// An archive under `prefix` in `store`, spooling records to a local file until they're uploaded
+    ///
+    /// Records left in the spool by the last run are uploaded with the next batch.
+    pub fn new<P: Into<PathBuf>>(store: Arc<dyn ObjectStore>, prefix: &str, spool: P) -> Result<Self, StoreError> {
+        let spool = spool.into();
+        let (file, records) = open_spool(&spool)?;
+        if !records.is_empty() {
+            info!("Archive spool {} has {} records waiting to be uploaded", spool.display(), records.len());
+        }
+        // Carry on from the clock so IDs keep increasing across restarts
+        let next_id = records.iter().filter_map(|r| r.id).max().map_or(0, |id| id + 1).max(Utc::now().timestamp_micros());
+        Ok(Self {
+            store,
+            prefix: ObjectPath::from(prefix),
+            codec: Codec::Gzip,
+            batch_size: 1000,
+            max_wait: Duration::from_secs(60),
+            max_pending: 100_000,
+            spool,
+            pending: Mutex::new(Pending { records, since: Instant::now(), spool: file }),
+            flushing: Mutex::new(()),
+            failure: Mutex::new(None),
+            next_id: AtomicI64::new(next_id),
+        })
+    }
+
+    /// An archive at a URL such as `s3://bucket/prefix` or `file:///var/lib/hl7`
+    ///
+    /// S3 settings come from the usual variables: `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY`, `AWS_REGION`, `AWS_ENDPOINT` and so on.
+    pub fn open<P: Into<PathBuf>>(url: &str, spool: P) -> Result<Self, StoreError> {
+        let url = url::Url::parse(url).map_err(|e| StoreError::InvalidValue(format!("archive URL '{}': {}", url, e)))?;
+        let options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&url, options).map_err(object_error)?;
+        Self::new(Arc::from(store), prefix.as_ref(), spool)
+    }
+
+    /// How batches are compressed (gzip unless set)
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Write a batch once this many records are waiting (1000 unless set)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Longest a record waits before `spawn_flushing` writes it (a minute unless set)
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Refuse records once this many are waiting to be uploaded (100,000 unless set)
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// How many records are waiting to be uploaded
+    pub fn pending(&self) -> usize {
+        self.lock().records.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether a batch is full or its oldest record has waited long enough
+    fn due(&self) -> bool {
+        let pending = self.lock();
+        pending.records.len() >= self.batch_size || (!pending.records.is_empty() && pending.since.elapsed() >= self.max_wait)
+    }
+
+    fn key(&self, path: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}/{}", self.prefix, path).trim_start_matches('/'))
+    }
+
+    /// Write every waiting record, returning how many were written
+    ///
+    /// Records that couldn't be written stay waiting, in the spool, for the next flush.
+    pub fn flush(&self) -> Result<usize, StoreError> {
+        let _flushing = self.flushing.lock().unwrap_or_else(|e| e.into_inner());
+        let records = self.lock().records.clone();
+        if records.is_empty() {
+            return Ok(0);
+        }
+        let mut hours: BTreeMap<String, Vec<ArchiveRecord>> = BTreeMap::new();
+        for record in records {
+            hours.entry(record.timestamp.format("%Y/%m/%d/%H").to_string()).or_default().push(record);
+        }
+        let mut written = HashSet::new();
+        let mut error = None;
+        for (hour, records) in hours {
+            match self.write_batch(&hour, &records) {
+                Ok(()) => written.extend(records.iter().filter_map(|r| r.id)),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if !written.is_empty() {
+            self.forget(&written)?;
+        }
+        *self.failure.lock().unwrap_or_else(|e| e.into_inner()) = error.as_ref().map(|e| e.to_string());
+        match error {
+            None => Ok(written.len()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Drop uploaded records, rewriting the spool to just those still waiting
+    fn forget(&self, written: &HashSet<i64>) -> Result<(), StoreError> {
+        let spool_error = |e: std::io::Error| StoreError::BlobError(format!("spool {}: {}", self.spool.display(), e));
+        let mut pending = self.lock();
+        pending.records.retain(|r| r.id.is_none_or(|id| !written.contains(&id)));
+        pending.since = Instant::now();
+        let temp = self.spool.with_extension("tmp");
+        let mut file = File::create(&temp).map_err(spool_error)?;
+        for record in &pending.records {
+            file.write_all(&spool_line(record)?).map_err(spool_error)?;
+        }
+        file.sync_all().map_err(spool_error)?;
+        std::fs::rename(&temp, &self.spool).map_err(spool_error)?;
+        pending.spool = OpenOptions::new().append(true).open(&self.spool).map_err(spool_error)?;
+        Ok(())
+    }
+
+    /// Write one hour's records and then their manifest, so every manifest names a batch that exists
+    fn write_batch(&self, hour: &str, records: &[ArchiveRecord]) -> Result<(), StoreError> {
+        let first = records.iter().filter_map(|r| r.id).min().unwrap_or_default();
+        let last = records.iter().filter_map(|r| r.id).max().unwrap_or_default();
+        let name = format!("{}/{:020}-{:020}", hour, first, last);
+        let object = format!("messages/{}.jsonl{}", name, self.codec.extension());
+
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record).map_err(|e| StoreError::InvalidValue(e.to_string()))?;
+            lines.push(b'\n');
+        }
+        let body = self
+            .codec
+            .encode(&lines)
+            .map_err(|e| StoreError::InvalidValue(format!("batch can't be compressed: {}", e)))?;
+        let manifest = serde_json::to_vec(&Manifest::new(object.clone(), self.codec, records))
+            .map_err(|e| StoreError::InvalidValue(e.to_string()))?;
+
+        let store = self.store.clone();
+        let (object, index) = (self.key(&object), self.key(&format!("index/{}.json", name)));
+        block_on(async move {
+            store.put(&object, PutPayload::from(body)).await?;
+            store.put(&index, PutPayload::from(manifest)).await
+        })
+        .map_err(object_error)?;
+        Ok(())
+    }
+
+    /// Flush every `interval` if a batch is full or the oldest waiting record has waited `max_wait`
+    pub fn spawn_flushing(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let archive = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if archive.due() {
+                    let archive = archive.clone();
+                    match tokio::task::spawn_blocking(move || archive.flush()).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => warn!("Failed to write archived messages, will retry: {}", e),
+                        Err(e) => warn!("Failed to write archived messages: {}", e),
+                    }
+                }
+            }
+        })
+    }
+
+    /// Every manifest under `index/` for hours that can hold records from `since` to `until`, oldest first
+    fn manifests(&self, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> Result<Vec<Manifest>, StoreError> {
+        let hour = |time: Option<DateTime<Utc>>| time.map(|t| t.format("%Y/%m/%d/%H").to_string());
+        let (first, last) = (hour(since), hour(until));
+        // List only the part of the index both ends share, e.g. one day
+        let shared = match (&first, &last) {
+            (Some(first), Some(last)) => {
+                let common = first.chars().zip(last.chars()).take_while(|(a, b)| a == b).count();
+                first[..first[..common].rfind('/').map_or(0, |i| i + 1)].to_string()
+            }
+            _ => String::new(),
+        };
+        let root = self.key("index");
+        let list = self.key(&format!("index/{}", shared));
+
+        let store = self.store.clone();
+        let mut keys: Vec<ObjectPath> = block_on(async move { store.list(Some(&list)).map_ok(|meta| meta.location).try_collect().await })
+            .map_err(object_error)?;
+        keys.retain(|key| {
+            let Some(relative) = key.prefix_match(&root) else { return false };
+            let relative: Vec<_> = relative.map(|part| part.as_ref().to_string()).collect();
+            let hour = relative[..relative.len().saturating_sub(1)].join("/");
+            first.as_ref().is_none_or(|f| &hour >= f) && last.as_ref().is_none_or(|l| &hour <= l)
+        });
+        keys.sort();
+
+        let store = self.store.clone();
+        let bodies = block_on(async move {
+            let mut bodies = Vec::with_capacity(keys.len());
+            for key in keys {
+                bodies.push(store.get(&key).await?.bytes().await?);
+            }
+            Ok::<_, object_store::Error>(bodies)
+        })
+        .map_err(object_error)?;
+        bodies
+            .iter()
+            .map(|body| serde_json::from_slice(body).map_err(|e| StoreError::InvalidValue(format!("archive manifest: {}", e))))
+            .collect()
+    }
+
+    fn read_batch(&self, manifest: &Manifest) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let store = self.store.clone();
+        let key = self.key(&manifest.object);
+        let body = block_on(async move { store.get(&key).await?.bytes().await }).map_err(object_error)?;
+        let lines = manifest
+            .codec
+            .decode(&body)
+            .map_err(|e| StoreError::InvalidValue(format!("{}: {}", manifest.object, e)))?;
+        lines
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).map_err(|e| StoreError::InvalidValue(format!("{}: {}", manifest.object, e))))
+            .collect()
+    }
+
+    /// Copy a capture file into `captures/`, under the day it was uploaded, returning its key
+    pub fn put_capture<P: AsRef<Path>>(&self, path: P) -> Result<String, StoreError> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .ok_or_else(|| StoreError::InvalidValue(format!("capture file {}", path.display())))?
+            .to_string_lossy();
+        let body = std::fs::read(path).map_err(|e| StoreError::BlobError(format!("{}: {}", path.display(), e)))?;
+        let key = self.key(&format!("captures/{}/{}", Utc::now().format("%Y/%m/%d"), name));
+        let store = self.store.clone();
+        let location = key.clone();
+        block_on(async move { store.put(&location, PutPayload::from(body)).await }).map_err(object_error)?;
+        info!("Uploaded capture {} to {}", path.display(), key);
+        Ok(key.to_string())
+    }
+
+    /// Download a capture file uploaded by `put_capture`, for `read_capture`
+    pub fn get_capture<P: AsRef<Path>>(&self, key: &str, path: P) -> Result<(), StoreError> {
+        let store = self.store.clone();
+        let key = ObjectPath::from(key);
+        let body = block_on(async move { store.get(&key).await?.bytes().await }).map_err(object_error)?;
+        std::fs::write(path.as_ref(), body).map_err(|e| StoreError::BlobError(format!("{}: {}", path.as_ref().display(), e)))
+    }
+}
+
+impl MessageStore for ObjectArchive {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        let mut pending = self.lock();
+        if pending.records.len() >= self.max_pending {
+            return Err(StoreError::BlobError(format!(
+                "{} archived messages are already waiting to be uploaded",
+                pending.records.len()
+            )));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let record = ArchiveRecord { id: Some(id), ..record.clone() };
+        let line = spool_line(&record)?;
+        let spool_error = |e: std::io::Error| StoreError::BlobError(format!("spool {}: {}", self.spool.display(), e));
+        pending.spool.write_all(&line).map_err(spool_error)?;
+        pending.spool.sync_data().map_err(spool_error)?;
+        if pending.records.is_empty() {
+            pending.since = Instant::now();
+        }
+        pending.records.push(record);
+        Ok(id)
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let mut records = Vec::new();
+        for manifest in self.manifests(query.since, query.until)? {
+            if manifest.may_match(query) {
+                records.extend(self.read_batch(&manifest)?.into_iter().filter(|r| query.matches(r)));
+            }
+        }
+        records.extend(self.lock().records.iter().filter(|r| query.matches(r)).cloned());
+        records.sort_by_key(|r| (r.timestamp, r.id));
+        if let Some(limit) = query.limit {
+            records.truncate(limit);
+        }
+        Ok(records)
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut expired = Vec::new();
+        let mut count = 0;
+        for manifest in self.manifests(None, Some(before))? {
+            if manifest.latest < before {
+                count += manifest.count;
+                let name = manifest.object.trim_start_matches("messages/");
+                let name = name.split(".jsonl").next().unwrap_or(name);
+                // The manifest goes first, so a batch is never listed without its records
+                expired.push(self.key(&format!("index/{}.json", name)));
+                expired.push(self.key(&manifest.object));
+            }
+        }
+        let store = self.store.clone();
+        block_on(async move {
+            for key in expired {
+                store.delete(&key).await?;
+            }
+            Ok(())
+        })
+        .map_err(object_error)?;
+        Ok(count)
+    }
+
+    /// Whether records can be taken: the spool isn't full, and the last upload, if any, succeeded
+    fn check_writable(&self) -> Result<(), StoreError> {
+        let waiting = self.pending();
+        if waiting >= self.max_pending {
+            return Err(StoreError::BlobError(format!("{} archived messages are waiting to be uploaded", waiting)));
+        }
+        match self.failure.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            Some(failure) => Err(StoreError::BlobError(format!("the last upload failed: {}", failure))),
+            None => Ok(()),
+        }
+    }
+}
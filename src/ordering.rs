@@ -0,0 +1,385 @@
+use crate::router::Destination;
+use crate::{terser, HL7Error, Message};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn, Instrument, Span};
+
+/// Orders spool files written within the same microsecond
+static SPOOL_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A message waiting its turn, and who to tell once it's been delivered
+struct Queued {
+    message: Message,
+    done: Option<oneshot::Sender<Result<(), String>>>,
+    /// The spool file holding it until it's delivered
+    file: Option<PathBuf>,
+    span: Span,
+}
+
+impl std::fmt::Debug for Queued {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Queued({})", self.message.message_type)
+    }
+}
+
+/// What clones of an `OrderedSink` share
+#[derive(Debug)]
+struct Lanes {
+    destination: Destination,
+    keys: Vec<String>,
+    /// Messages waiting behind the one being delivered, for each key with one in flight
+    queues: Mutex<HashMap<String, VecDeque<Queued>>>,
+    /// Directory each queued message is written to until it's delivered
+    spool: Option<PathBuf>,
+    /// Messages queued or being delivered
+    outstanding: AtomicUsize,
+    /// Keys held up by a message that has run out of attempts
+    parked: Mutex<HashSet<String>>,
+}
+
+/// Delivers messages for the same patient or visit one at a time, in the order received
+///
+/// Each message's key is the first non-empty value at `keys`, e.g. PV1-19 and
+/// then PID-3.1. Messages with the same key wait for the one before them to
+/// be accepted, so an A08 update never overtakes the A01 admit it follows,
+/// while other keys are delivered alongside. Messages without any key are
+/// delivered straight away.
+///
+/// A failed delivery is retried, doubling the delay each time, before the next
+/// message with its key goes. Once a message has failed `max_attempts` times
+/// its key is parked: the failure is logged as an error, `check` reports the
+/// destination as failing, and the message keeps being retried at the last
+/// delay until it's accepted, with the rest of its key waiting behind it.
+///
+/// With a spool directory, each message routed here is written to a file and
+/// synced before it's acknowledged, and the file is removed once delivered;
+/// `resume` queues whatever a previous run left behind. Without one the queue
+/// is in memory only. At most `max_pending` messages are held, 10,000 by
+/// default; past that, messages are refused so the sender gets a NACK.
+///
+/// Delivery waits for the destination to accept each message, so a downstream
+/// NACK counts as a failure. Each destination keeps its own order.
+///
+/// ```ignore
+/// let sink = OrderedSink::new(Destination::Mllp(MllpClient::new("ehr:2575")), ["PV1-19", "PID-3.1"]).with_spool("/var/spool/hl7/ehr");
+/// sink.resume()?;
+/// let destination = Destination::Ordered(sink);
+/// ```
+#[derive(Debug, Clone)]
+pub struct OrderedSink {
+    lanes: Arc<Lanes>,
+    max_attempts: u32,
+    retry_delay: Duration,
+    max_pending: usize,
+}
+
+impl OrderedSink {
+    /// Deliver to `destination` in order of the first value present at `keys`,
+    /// trying each message up to 5 times
+    pub fn new<I, S>(destination: Destination, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            lanes: Arc::new(Lanes {
+                destination,
+                keys: keys.into_iter().map(|key| key.to_string()).collect(),
+                queues: Mutex::new(HashMap::new()),
+                spool: None,
+                outstanding: AtomicUsize::new(0),
+                parked: Mutex::new(HashSet::new()),
+            }),
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(1),
+            max_pending: 10_000,
+        }
+    }
+
+    /// Try each message this many times, waiting `retry_delay`, then twice that,
+    /// and so on between tries, before parking its key
+    pub fn with_retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Keep each queued message in a file in `directory` until it's delivered
+    ///
+    /// Call before the sink is cloned or used.
+    pub fn with_spool<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        if let Some(lanes) = Arc::get_mut(&mut self.lanes) {
+            lanes.spool = Some(directory.into());
+        }
+        self
+    }
+
+    /// Refuse messages once this many are queued or being delivered
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// Whether two sinks share the same queues
+    pub fn same_as(&self, other: &OrderedSink) -> bool {
+        Arc::ptr_eq(&self.lanes, &other.lanes)
+    }
+
+    /// Directory queued messages are kept in, if any
+    pub fn spool(&self) -> Option<&Path> {
+        self.lanes.spool.as_deref()
+    }
+
+    /// Destination messages are delivered to
+    pub fn destination(&self) -> &Destination {
+        &self.lanes.destination
+    }
+
+    /// Paths the ordering key is read from, in order of preference
+    pub fn keys(&self) -> &[String] {
+        &self.lanes.keys
+    }
+
+    /// How many messages are waiting behind another with the same key
+    pub fn pending(&self) -> usize {
+        self.lock().values().map(VecDeque::len).sum()
+    }
+
+    /// Keys held up by a message that has run out of attempts, in no particular order
+    pub fn parked(&self) -> Vec<String> {
+        self.lanes.parked.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    /// The ordering key of a message, if it has one
+    pub fn key(&self, message: &Message) -> Option<String> {
+        self.lanes
+            .keys
+            .iter()
+            .find_map(|path| terser::get(message, path).filter(|value| !value.is_empty()))
+    }
+
+    /// Queue a message behind others with its key, without waiting for it to be delivered
+    ///
+    /// With a spool, the message is on disk when this returns. It's never
+    /// given up on, only parked.
+    pub fn enqueue(&self, message: &Message) -> Result<(), HL7Error> {
+        let runtime = runtime()?;
+        self.reserve()?;
+        let file = match self.write_spool(message) {
+            Ok(file) => file,
+            Err(e) => {
+                self.lanes.outstanding.fetch_sub(1, Ordering::SeqCst);
+                return Err(HL7Error::DeliveryError(format!("Failed to spool ordered message: {}", e)));
+            }
+        };
+        self.push(&runtime, message.clone(), None, file);
+        Ok(())
+    }
+
+    /// Queue a message and wait until it has been delivered
+    ///
+    /// The message isn't spooled, since the caller still has it: after
+    /// `max_attempts` it's handed back as an error and the next message with
+    /// its key goes.
+    pub async fn send(&self, message: &Message) -> Result<(), HL7Error> {
+        let runtime = runtime()?;
+        self.reserve()?;
+        let (done, result) = oneshot::channel();
+        self.push(&runtime, message.clone(), Some(done), None);
+        match result.await {
+            Ok(result) => result.map_err(HL7Error::DeliveryError),
+            Err(_) => Err(HL7Error::DeliveryError("Ordered delivery was cancelled".to_string())),
+        }
+    }
+
+    /// Queue the messages a previous run left in the spool, oldest first
+    ///
+    /// Call once at startup, before messages are routed here. Files that can't
+    /// be parsed are renamed to `.bad` and skipped. Returns how many were queued.
+    pub fn resume(&self) -> io::Result<usize> {
+        let Some(spool) = &self.lanes.spool else {
+            return Ok(0);
+        };
+        let runtime = runtime().map_err(|e| io::Error::other(e.to_string()))?;
+        let mut files: Vec<PathBuf> = match fs::read_dir(spool) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "hl7"))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        files.sort();
+        let mut queued = 0;
+        for file in files {
+            let message = match fs::read_to_string(&file).map(|text| Message::parse(&text)) {
+                Ok(Ok(message)) => message,
+                Ok(Err(e)) => {
+                    error!("Setting aside unreadable spooled message {}: {}", file.display(), e);
+                    fs::rename(&file, file.with_extension("bad"))?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            self.lanes.outstanding.fetch_add(1, Ordering::SeqCst);
+            self.push(&runtime, message, None, Some(file));
+            queued += 1;
+        }
+        if queued > 0 {
+            info!("Resumed {} ordered messages for {:?} from {}", queued, self.lanes.destination, spool.display());
+        }
+        Ok(queued)
+    }
+
+    /// Count a message against `max_pending`, refusing it if the queue is full
+    fn reserve(&self) -> Result<(), HL7Error> {
+        let outstanding = &self.lanes.outstanding;
+        outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < self.max_pending).then_some(count + 1))
+            .map(|_| ())
+            .map_err(|count| {
+                HL7Error::DeliveryError(format!(
+                    "Ordered queue for {:?} is full with {} messages",
+                    self.lanes.destination, count
+                ))
+            })
+    }
+
+    /// Write a message to a new spool file, named so files sort in the order written
+    fn write_spool(&self, message: &Message) -> io::Result<Option<PathBuf>> {
+        let Some(spool) = &self.lanes.spool else {
+            return Ok(None);
+        };
+        fs::create_dir_all(spool)?;
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+        let sequence = SPOOL_SEQUENCE.fetch_add(1, Ordering::SeqCst);
+        let path = spool.join(format!("{:020}-{:010}.hl7", micros, sequence));
+        let temp = path.with_extension("tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(message.to_hl7().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        Ok(Some(path))
+    }
+
+    fn push(
+        &self,
+        runtime: &tokio::runtime::Handle,
+        message: Message,
+        done: Option<oneshot::Sender<Result<(), String>>>,
+        file: Option<PathBuf>,
+    ) {
+        let key = self.key(&message);
+        let queued = Queued {
+            message,
+            done,
+            file,
+            span: Span::current(),
+        };
+        let Some(key) = key else {
+            runtime.spawn(self.clone().deliver(None, queued));
+            return;
+        };
+
+        let mut queues = self.lock();
+        match queues.get_mut(&key) {
+            // A task is already delivering this key and will get to it
+            Some(queue) => queue.push_back(queued),
+            None => {
+                queues.insert(key.clone(), VecDeque::new());
+                runtime.spawn(self.clone().drain(key, queued));
+            }
+        }
+    }
+
+    /// Deliver messages with one key until none are left waiting
+    async fn drain(self, key: String, first: Queued) {
+        let mut next = Some(first);
+        while let Some(queued) = next {
+            self.clone().deliver(Some(&key), queued).await;
+            let mut queues = self.lock();
+            next = queues.get_mut(&key).and_then(VecDeque::pop_front);
+            if next.is_none() {
+                queues.remove(&key);
+            }
+        }
+    }
+
+    /// Deliver one message, retrying until it's accepted, or until it's out of
+    /// attempts if the caller is waiting on it
+    async fn deliver(self, key: Option<&str>, queued: Queued) {
+        let destination = &self.lanes.destination;
+        // Unkeyed messages are parked under their control ID
+        let parked_as = match key {
+            Some(key) => key.to_string(),
+            None => format!("MSH-10 {}", queued.message.control_id().unwrap_or_default()),
+        };
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        let result = loop {
+            match destination.send(&queued.message).instrument(queued.span.clone()).await {
+                Ok(()) => break Ok(()),
+                Err(e) if attempt >= self.max_attempts && queued.done.is_some() => {
+                    queued.span.in_scope(|| {
+                        error!("Ordered delivery to {:?} failed after {} attempts: {}", destination, attempt, e)
+                    });
+                    break Err(e.to_string());
+                }
+                Err(e) if attempt == self.max_attempts => {
+                    self.park(&parked_as, true);
+                    queued.span.in_scope(|| {
+                        error!(
+                            "Ordered delivery to {:?} failed {} times, holding {} until it's accepted: {}",
+                            destination, attempt, parked_as, e
+                        )
+                    });
+                }
+                Err(e) => queued.span.in_scope(|| {
+                    warn!("Ordered delivery to {:?} failed, retrying in {:?}: {}", destination, delay, e)
+                }),
+            }
+            tokio::time::sleep(delay).await;
+            if attempt < self.max_attempts {
+                delay = delay.saturating_mul(2);
+            }
+            attempt = attempt.saturating_add(1);
+        };
+        if attempt > self.max_attempts && result.is_ok() {
+            self.park(&parked_as, false);
+            queued.span.in_scope(|| info!("Ordered delivery to {:?} resumed for {}", destination, parked_as));
+        }
+        if let Some(file) = &queued.file {
+            if let Err(e) = fs::remove_file(file) {
+                // It'll be sent again after a restart, which is better than losing it
+                error!("Failed to remove delivered message {}: {}", file.display(), e);
+            }
+        }
+        self.lanes.outstanding.fetch_sub(1, Ordering::SeqCst);
+        if let Some(done) = queued.done {
+            let _ = done.send(result);
+        }
+    }
+
+    fn park(&self, key: &str, parked: bool) {
+        let mut keys = self.lanes.parked.lock().unwrap_or_else(|e| e.into_inner());
+        match parked {
+            true => keys.insert(key.to_string()),
+            false => keys.remove(key),
+        };
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, VecDeque<Queued>>> {
+        self.lanes.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn runtime() -> Result<tokio::runtime::Handle, HL7Error> {
+    tokio::runtime::Handle::try_current()
+        .map_err(|_| HL7Error::DeliveryError("Ordered destinations require a Tokio runtime".to_string()))
+}
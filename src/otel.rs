@@ -0,0 +1,172 @@
+use opentelemetry::metrics::{Counter, Histogram, MeterProvider as _};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::error::OTelSdkError;
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing::warn;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Instruments recorded into once an export is installed
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub enum OtelError {
+    #[error("Failed to set up OTLP export: {0}")]
+    Exporter(#[from] opentelemetry_otlp::ExporterBuildError),
+    #[error("OTLP export is already installed")]
+    AlreadyInstalled,
+    #[error("Failed to flush spans: {0}")]
+    FlushSpans(OTelSdkError),
+    #[error("Failed to flush metrics: {0}")]
+    FlushMetrics(OTelSdkError),
+}
+
+struct Instruments {
+    messages: Counter<u64>,
+    message_duration: Histogram<f64>,
+    client_calls: Counter<u64>,
+    client_duration: Histogram<f64>,
+}
+
+/// Exports spans and metrics to an OpenTelemetry collector over OTLP/HTTP
+///
+/// Spans reach the collector through the tracing layer returned by `layer`, so
+/// the `message`, `route` and `mllp_send` spans show up as traces. Installing
+/// also starts recording message and MLLP client metrics, which are pushed
+/// every 60 seconds. Buffered spans and metrics are flushed by `shutdown`, or
+/// when the export is dropped, which logs a failure as a warning.
+///
+/// ```ignore
+/// let export = OtelExport::install("http://localhost:4318", "rust-hl7")?;
+/// tracing_subscriber::registry().with(export.layer()).init();
+/// ```
+pub struct OtelExport {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+    flushed: bool,
+}
+
+impl OtelExport {
+    /// Export to the collector at `endpoint`, e.g. "http://localhost:4318",
+    /// reporting as `service_name`
+    ///
+    /// Only one export can be installed per process.
+    pub fn install(endpoint: &str, service_name: &str) -> Result<Self, OtelError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::builder().with_service_name(service_name.to_string()).build();
+
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .with_timeout(Duration::from_secs(10))
+            .build()?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .with_timeout(Duration::from_secs(10))
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(metrics)
+            .with_resource(resource)
+            .build();
+
+        let meter = meter_provider.meter("rust-hl7");
+        let instruments = Instruments {
+            messages: meter
+                .u64_counter("hl7.messages")
+                .with_description("Messages received by MLLP listeners")
+                .build(),
+            message_duration: meter
+                .f64_histogram("hl7.message.duration")
+                .with_description("Time from receiving a message to sending its response")
+                .with_unit("s")
+                .build(),
+            client_calls: meter
+                .u64_counter("mllp.client.calls")
+                .with_description("Messages sent to other MLLP endpoints")
+                .build(),
+            client_duration: meter
+                .f64_histogram("mllp.client.duration")
+                .with_description("Time from sending a message to another MLLP endpoint to its response")
+                .with_unit("s")
+                .build(),
+        };
+        INSTRUMENTS.set(instruments).map_err(|_| OtelError::AlreadyInstalled)?;
+
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+            flushed: false,
+        })
+    }
+
+    /// A tracing layer exporting spans, to add to the subscriber
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, SdkTracer>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer_provider.tracer("rust-hl7"))
+    }
+
+    /// Flush buffered spans and metrics and stop exporting
+    pub fn shutdown(mut self) -> Result<(), OtelError> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), OtelError> {
+        self.flushed = true;
+        let spans = self.tracer_provider.shutdown().map_err(OtelError::FlushSpans);
+        let metrics = self.meter_provider.shutdown().map_err(OtelError::FlushMetrics);
+        spans.and(metrics)
+    }
+}
+
+impl Drop for OtelExport {
+    fn drop(&mut self) {
+        if self.flushed {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Count a message received by a listener and how long it took to answer
+///
+/// `outcome` is the archive disposition, e.g. "accepted" or "failed". Does
+/// nothing unless an export is installed.
+pub(crate) fn record_message(message_type: &str, outcome: &str, elapsed: Duration) {
+    if let Some(instruments) = INSTRUMENTS.get() {
+        let attributes = [
+            KeyValue::new("message_type", message_type.to_string()),
+            KeyValue::new("outcome", outcome.to_string()),
+        ];
+        instruments.messages.add(1, &attributes);
+        instruments.message_duration.record(elapsed.as_secs_f64(), &attributes);
+    }
+}
+
+/// Count a message sent by `MllpClient` and how long the exchange took
+pub(crate) fn record_client_call(address: &str, ok: bool, elapsed: Duration) {
+    if let Some(instruments) = INSTRUMENTS.get() {
+        let attributes = [
+            KeyValue::new("address", address.to_string()),
+            KeyValue::new("outcome", if ok { "ok" } else { "error" }),
+        ];
+        instruments.client_calls.add(1, &attributes);
+        instruments.client_duration.record(elapsed.as_secs_f64(), &attributes);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{parse_field, terser, Delimiters, HL7Error, Message, Segment};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// The hits of a query, when they were found, and the pointers given out for its later pages
+#[derive(Debug)]
+struct Pending {
+    found: Instant,
+    /// MSH-3, MSH-4, QPD-1 and QPD-2 of the query, which continuations must repeat
+    owner: [String; 4],
+    hits: Vec<Vec<Segment>>,
+    /// Each continuation pointer, and the hit its page starts at
+    pointers: HashMap<String, usize>,
+}
+
+/// One response's share of a query's hits
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// The segments of each hit on this page, e.g. a PID and its PD1
+    pub hits: Vec<Vec<Segment>>,
+    /// Hits the query found in all
+    pub total: usize,
+    /// Hits left for later pages
+    pub remaining: usize,
+    /// The continuation pointer for the next page, if there is one
+    pub pointer: Option<String>,
+}
+
+impl Page {
+    /// Append the hits to a response, then a DSC with the continuation pointer if there are more
+    pub fn append_to(self, response: &mut Message) {
+        response.segments.extend(self.hits.into_iter().flatten());
+        if let Some(pointer) = self.pointer {
+            let delimiters = Delimiters::default();
+            response.segments.push(Segment::new("DSC", vec![parse_field(&pointer, &delimiters), parse_field("I", &delimiters)]));
+        }
+    }
+}
+
+/// Splits query responses into pages of the size asked for in RCP-2
+///
+/// A query asking for at most N hits gets the first N, and a DSC segment
+/// whose continuation pointer the client sends back, in a DSC segment of its
+/// own, to ask for the next N. Pointers are random, and only answered for a
+/// continuation with the same MSH-3, MSH-4, QPD-1 and QPD-2 as the query. A
+/// query's hits are kept for `max_age` after it's answered, and its pointers
+/// can be asked for again until then, e.g. when a response was lost; the
+/// oldest are dropped when more than `max_pending` queries have hits waiting.
+///
+/// ```
+/// use rust_hl7::paging::{self, QueryPages};
+/// use rust_hl7::Message;
+///
+/// let pages = QueryPages::new();
+/// let query = Message::parse("MSH|^~\\&|EHR|HOSP|PDQ||20240501||QBP^Q22|1|P|2.5\rQPD|IHE PDQ Query|Q1\rRCP|I|2^RD").unwrap();
+/// let hits = Message::parse("MSH|^~\\&|||||||ADT^A04|2|P|2.5\rPID|1||1\rPID|2||2\rPID|3||3").unwrap();
+/// let first = pages.first(&query, hits.segments[1..].iter().map(|pid| vec![pid.clone()]).collect());
+/// assert_eq!((first.hits.len(), first.remaining), (2, 1));
+///
+/// let again = paging::continue_query(&query, &first.pointer.unwrap(), 2).unwrap();
+/// let next = pages.next(&again).unwrap();
+/// assert_eq!((next.hits.len(), next.remaining, next.pointer), (1, 0, None));
+/// ```
+#[derive(Debug)]
+pub struct QueryPages {
+    /// The hits waiting for continuations, by the order their queries came in
+    pending: Mutex<(u64, BTreeMap<u64, Pending>)>,
+    max_age: Duration,
+    max_pending: usize,
+}
+
+impl Default for QueryPages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QueryPages {
+    /// Keep hits for ten minutes, for at most 100 queries at a time
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new((0, BTreeMap::new())),
+            max_age: Duration::from_secs(600),
+            max_pending: 100,
+        }
+    }
+
+    /// Drop hits this long after their query was answered
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Keep hits for at most this many queries
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// The most hits a query asks for in one response, from RCP-2 in records (RD)
+    pub fn limit(query: &Message) -> Option<usize> {
+        let quantity = query.get_segment("RCP")?.field(2);
+        if !matches!(quantity.component(2).as_str(), None | Some("RD")) {
+            return None;
+        }
+        quantity.as_str()?.parse::<usize>().ok().filter(|&limit| limit > 0)
+    }
+
+    /// The continuation pointer a query carries in DSC-1, if it's asking for a later page
+    pub fn pointer(query: &Message) -> Option<&str> {
+        query.get_segment("DSC")?.field(1).as_str()
+    }
+
+    /// The first page of a query's hits, keeping the rest for continuation queries
+    pub fn first(&self, query: &Message, hits: Vec<Vec<Segment>>) -> Page {
+        let count = Self::limit(query).unwrap_or(usize::MAX).min(hits.len());
+        if count == hits.len() {
+            let total = hits.len();
+            return Page { hits, total, remaining: 0, pointer: None };
+        }
+        let mut state = self.lock();
+        self.expire(&mut state.1);
+        while state.1.len() >= self.max_pending.max(1) {
+            if state.1.pop_first().is_some() {
+                warn!("Dropping the rest of the hits of the oldest query waiting for continuations");
+            }
+        }
+        let id = state.0;
+        state.0 += 1;
+        let pending = state.1.entry(id).or_insert(Pending {
+            found: Instant::now(),
+            owner: owner(query),
+            hits,
+            pointers: HashMap::new(),
+        });
+        page(pending, 0, count)
+    }
+
+    /// The page a continuation query's pointer stands for, or None if the pointer is
+    /// unknown, has expired, or was given out for another sender or query
+    pub fn next(&self, query: &Message) -> Option<Page> {
+        let pointer = Self::pointer(query)?;
+        let mut state = self.lock();
+        self.expire(&mut state.1);
+        let pending = state.1.values_mut().find(|pending| pending.pointers.contains_key(pointer))?;
+        if pending.owner != owner(query) {
+            warn!("Refusing continuation pointer {} sent for another sender or query", pointer);
+            return None;
+        }
+        let start = pending.pointers[pointer];
+        let count = Self::limit(query).unwrap_or(usize::MAX).min(pending.hits.len() - start);
+        Some(page(pending, start, count))
+    }
+
+    /// How many queries have hits waiting to be asked for
+    pub fn pending(&self) -> usize {
+        self.lock().1.len()
+    }
+
+    fn expire(&self, pending: &mut BTreeMap<u64, Pending>) {
+        pending.retain(|_, pending| pending.found.elapsed() < self.max_age);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (u64, BTreeMap<u64, Pending>)> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The `count` hits from `start`, with the pointer to the page after them,
+/// reusing the one already given out if the page is asked for again
+fn page(pending: &mut Pending, start: usize, count: usize) -> Page {
+    let end = start + count;
+    let total = pending.hits.len();
+    let pointer = (end < total).then(|| {
+        let existing = pending.pointers.iter().find(|(_, &at)| at == end).map(|(pointer, _)| pointer.clone());
+        existing.unwrap_or_else(|| {
+            let pointer = new_pointer();
+            pending.pointers.insert(pointer.clone(), end);
+            pointer
+        })
+    });
+    Page { hits: pending.hits[start..end].to_vec(), total, remaining: total - end, pointer }
+}
+
+/// A continuation pointer that can't be guessed: 128 random bits in hex
+fn new_pointer() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What a continuation must repeat from its query: MSH-3, MSH-4, QPD-1 and QPD-2
+fn owner(query: &Message) -> [String; 4] {
+    let delimiters = Delimiters::default();
+    let field = |name: &str, number: usize| {
+        query
+            .get_segment(name)
+            .and_then(|segment| segment.fields.get(terser::field_index(name, number)?))
+            .map(|field| field.to_hl7(&delimiters))
+            .unwrap_or_default()
+    };
+    [field("MSH", 3), field("MSH", 4), field("QPD", 1), field("QPD", 2)]
+}
+
+/// The query to send for the page after a response, carrying its continuation pointer
+///
+/// The query is repeated with a DSC segment holding the pointer, and its
+/// control ID followed by "-2", "-3" and so on for each later page.
+pub fn continue_query(query: &Message, pointer: &str, page: usize) -> Result<Message, HL7Error> {
+    let mut next = query.clone();
+    next.segments.retain(|segment| segment.name != "DSC");
+    let control_id = query.control_id().unwrap_or_default();
+    terser::set(&mut next, "MSH-10", &format!("{}-{}", control_id, page))?;
+    let delimiters = Delimiters::default();
+    next.segments.push(Segment::new("DSC", vec![parse_field(&delimiters.escape(pointer), &delimiters), parse_field("I", &delimiters)]));
+    Ok(next)
+}
+
+/// Put the pages of a query response back together as one response
+///
+/// The first page's segments up to and including QPD are kept, followed by
+/// the hits of every page; the DSC segments are dropped and the QAK counts
+/// cover all the pages.
+pub fn combine(pages: Vec<Message>) -> Result<Message, HL7Error> {
+    let mut pages = pages.into_iter();
+    let mut combined = pages.next().ok_or_else(|| HL7Error::InvalidStructure("No pages to combine".to_string()))?;
+    let mut all = hits(&mut combined);
+    let mut this_payload = count(&combined, "QAK-5");
+    let mut remaining = count(&combined, "QAK-6");
+    for mut page in pages {
+        this_payload += count(&page, "QAK-5");
+        remaining = count(&page, "QAK-6");
+        all.extend(hits(&mut page));
+    }
+    combined.segments.extend(all);
+    if combined.get_segment("QAK").is_some_and(|qak| qak.field(5).exists()) {
+        terser::set(&mut combined, "QAK-5", &this_payload.to_string())?;
+        terser::set(&mut combined, "QAK-6", &remaining.to_string())?;
+    }
+    Ok(combined)
+}
+
+/// Take the hits out of a response page: what comes after QPD (or QAK, or MSA), less any DSC
+fn hits(page: &mut Message) -> Vec<Segment> {
+    let start = ["QPD", "QAK", "MSA"]
+        .iter()
+        .find_map(|name| page.segments.iter().position(|s| s.name == *name))
+        .map_or(1, |index| index + 1);
+    page.segments.split_off(start.min(page.segments.len())).into_iter().filter(|s| s.name != "DSC").collect()
+}
+
+fn count(page: &Message, path: &str) -> usize {
+    terser::get(page, path).and_then(|n| n.parse().ok()).unwrap_or_default()
+}
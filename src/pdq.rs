@@ -0,0 +1,292 @@
+//! The HL7 v2 side of an IHE PDQ supplier (ITI-21)
+//!
+//! A `PdqSupplier` answers patient demographics queries (QBP^Q22) with
+//! RSP^K22 responses. The application keeps the patients and implements
+//! `PatientDirectory` to search them; the supplier reads the query's
+//! criteria, limits each response to the quantity asked for in RCP-2, keeps
+//! the rest for continuation queries carrying a DSC pointer, and builds the
+//! responses. Pointers are random and only answered for the sender and
+//! query they were given to (see `paging::QueryPages`).
+//!
+//! ```
+//! use rust_hl7::pdq::{PatientDirectory, PatientQuery, PdqSupplier};
+//! use rust_hl7::{HL7Error, Message, Segment};
+//! use std::sync::Arc;
+//!
+//! struct Patients(Vec<Segment>);
+//!
+//! impl PatientDirectory for Patients {
+//!     fn find(&self, query: &PatientQuery) -> Result<Vec<Segment>, HL7Error> {
+//!         Ok(self.0.iter().filter(|pid| query.matches(pid)).cloned().collect())
+//!     }
+//! }
+//!
+//! let registered = Message::parse("MSH|^~\\&|REG|HOSP|||20240501||ADT^A04|1|P|2.5\r\
+//!     PID|1||12345^^^HOSP^MR||DOE^JANE||19800101|F").unwrap();
+//! let patients = Patients(registered.get_segments("PID").into_iter().cloned().collect());
+//! let pdq = PdqSupplier::new(Arc::new(patients)).sending_application("PDQ");
+//!
+//! let response = pdq.handle(&Message::parse("MSH|^~\\&|EHR|HOSP|PDQ||20240501||QBP^Q22^QBP_Q21|2|P|2.5\r\
+//!     QPD|IHE PDQ Query|Q1|@PID.5.1^DOE~@PID.7^19800101\r\
+//!     RCP|I|10^RD").unwrap()).unwrap();
+//! assert_eq!(response.get_segment("QAK").unwrap().field(2).as_str(), Some("OK"));
+//! assert_eq!(response.get_segment("PID").unwrap().field(3).as_str(), Some("12345"));
+//! ```
+
+use crate::clock::{Clock, IdSource, SystemClock, UniqueIds};
+use crate::mllp::MessageHandler;
+use crate::paging::{Page, QueryPages};
+use crate::pix::{requested_domains, PatientIdentifier, UNKNOWN_KEY};
+use crate::terser::TerserPath;
+use crate::{parse_field, Delimiters, HL7Error, Message, MessageBuilder, Segment};
+use std::sync::Arc;
+
+/// The search an application's directory is asked to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatientQuery {
+    /// QPD-2, the query tag
+    pub tag: String,
+    /// The demographics fields from QPD-3 with the values asked for, e.g. PID-5.1 and "DOE"
+    pub criteria: Vec<(TerserPath, String)>,
+    /// The assigning authorities from QPD-8 whose identifiers should be returned, or none for all of them
+    pub domains: Vec<String>,
+}
+
+impl PatientQuery {
+    /// Read the query parameters from a QPD segment
+    ///
+    /// Criteria are written as in IHE PDQ, e.g. `@PID.5.1^DOE~@PID.8^F`.
+    pub fn from_qpd(qpd: &Segment) -> Result<Self, HL7Error> {
+        let delimiters = Delimiters::default();
+        let raw = |index: usize| qpd.fields.get(index).map(|f| f.to_hl7(&delimiters)).unwrap_or_default();
+        let mut criteria = Vec::new();
+        for parameter in raw(2).split('~').filter(|p| !p.is_empty()) {
+            let (name, value) = parameter.split_once('^').unwrap_or((parameter, ""));
+            // "@PID.5.1" is the terser path "PID-5.1"
+            let path = name.trim_start_matches('@').replacen('.', "-", 1).parse::<TerserPath>()?;
+            criteria.push((path, delimiters.unescape(value)));
+        }
+        if criteria.is_empty() {
+            return Err(HL7Error::MissingField("QPD-3 demographics criteria".to_string()));
+        }
+        Ok(Self {
+            tag: qpd.field(2).unescaped().unwrap_or_default(),
+            criteria,
+            domains: requested_domains(&raw(7)),
+        })
+    }
+
+    /// The value asked for in a field, e.g. `query.get("PID-5.1")`
+    pub fn get(&self, path: &str) -> Option<&str> {
+        let path = path.parse::<TerserPath>().ok()?;
+        self.criteria.iter().find(|(p, _)| *p == path).map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a PID segment has every value asked for in a PID field
+    ///
+    /// Values are compared ignoring case, and one ending in `*` matches
+    /// anything starting with the rest of it. Criteria on other segments are
+    /// left to the directory.
+    pub fn matches(&self, pid: &Segment) -> bool {
+        self.criteria.iter().filter(|(path, _)| path.segment == "PID").all(|(path, wanted)| {
+            let mut value = pid.field(path.field);
+            if let Some(component) = path.component {
+                value = value.component(component);
+            }
+            if let Some(subcomponent) = path.subcomponent {
+                value = value.subcomponent(subcomponent);
+            }
+            let value = value.unescaped().unwrap_or_default().to_lowercase();
+            let wanted = wanted.to_lowercase();
+            match wanted.strip_suffix('*') {
+                Some(prefix) => value.starts_with(prefix),
+                None => value == wanted,
+            }
+        })
+    }
+}
+
+/// Where the patients a `PdqSupplier` searches are kept
+pub trait PatientDirectory: Send + Sync {
+    /// The PID segments of the patients matching the query, best match first
+    fn find(&self, query: &PatientQuery) -> Result<Vec<Segment>, HL7Error>;
+
+    /// Whether the directory has identifiers from this assigning authority
+    ///
+    /// Queries asking for identifiers from a domain it doesn't know are answered with an error.
+    fn knows_domain(&self, _authority: &str) -> bool {
+        true
+    }
+}
+
+/// Answers PDQ queries from an application's patient directory
+pub struct PdqSupplier {
+    directory: Arc<dyn PatientDirectory>,
+    pages: QueryPages,
+    sending_application: String,
+    sending_facility: String,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdSource>,
+}
+
+impl std::fmt::Debug for PdqSupplier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PdqSupplier")
+            .field("sending_application", &self.sending_application)
+            .field("sending_facility", &self.sending_facility)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PdqSupplier {
+    pub fn new(directory: Arc<dyn PatientDirectory>) -> Self {
+        Self {
+            directory,
+            pages: QueryPages::new(),
+            sending_application: String::new(),
+            sending_facility: String::new(),
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(UniqueIds::default()),
+        }
+    }
+
+    /// MSH-3 of the responses
+    pub fn sending_application<T: ToString>(mut self, application: T) -> Self {
+        self.sending_application = application.to_string();
+        self
+    }
+
+    /// MSH-4 of the responses
+    pub fn sending_facility<T: ToString>(mut self, facility: T) -> Self {
+        self.sending_facility = facility.to_string();
+        self
+    }
+
+    /// Take MSH-7 of the responses from this clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Take MSH-10 of the responses from this source
+    pub fn id_source(mut self, ids: Arc<dyn IdSource>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Keep results for continuation queries as these pages say, e.g. for longer than ten minutes
+    pub fn pages(mut self, pages: QueryPages) -> Self {
+        self.pages = pages;
+        self
+    }
+
+    /// Answer a QBP^Q22, or the continuation of one, with an RSP^K22
+    ///
+    /// A continuation whose pointer is unknown, has expired, or was given to
+    /// another sender or query gets an AE response. Other messages are rejected.
+    pub fn handle(&self, query: &Message) -> Result<Message, HL7Error> {
+        let mut parts = query.message_type.split('^');
+        if (parts.next(), parts.next()) != (Some("QBP"), Some("Q22")) {
+            return Err(HL7Error::Rejected(format!("{} isn't a PDQ query", query.message_type)));
+        }
+        let qpd = query
+            .get_segment("QPD")
+            .ok_or_else(|| HL7Error::MissingField("QPD segment".to_string()))?;
+
+        let page = match QueryPages::pointer(query) {
+            Some(_) => match self.pages.next(query) {
+                Some(page) => page,
+                None => return self.respond(query, qpd, Some("DSC^1^1".to_string()), None),
+            },
+            None => {
+                let parameters = PatientQuery::from_qpd(qpd)?;
+                // ERR-2 points at the first domain the directory doesn't know
+                if let Some(index) = parameters.domains.iter().position(|d| !self.directory.knows_domain(d)) {
+                    let location = format!("QPD^1^8^{}^4", index + 1);
+                    return self.respond(query, qpd, Some(location), None);
+                }
+                let hits = self
+                    .directory
+                    .find(&parameters)?
+                    .into_iter()
+                    .filter_map(|pid| in_domains(pid, &parameters.domains))
+                    .map(|pid| vec![pid])
+                    .collect();
+                self.pages.first(query, hits)
+            }
+        };
+        self.respond(query, qpd, None, Some(page))
+    }
+
+    /// A handler for an MLLP server in `AckMode::Application`
+    pub fn handler(self: &Arc<Self>) -> MessageHandler {
+        let pdq = self.clone();
+        Arc::new(move |message: Message| pdq.handle(&message))
+    }
+
+    /// Build the RSP^K22: MSH, MSA, ERR if there's an error, QAK, the echoed QPD, the PIDs and any DSC
+    fn respond(&self, query: &Message, qpd: &Segment, error: Option<String>, page: Option<Page>) -> Result<Message, HL7Error> {
+        let delimiters = Delimiters::default();
+        let escape = |text: Option<&str>| delimiters.escape(text.unwrap_or_default());
+        let mut builder = MessageBuilder::new("RSP^K22^RSP_K21")
+            .sending_application(&self.sending_application)
+            .sending_facility(&self.sending_facility)
+            .receiving_application(escape(query.sending_application()))
+            .receiving_facility(escape(query.sending_facility()))
+            .version(if query.version.is_empty() { "2.5" } else { query.version.as_str() })
+            .clock(self.clock.clone())
+            .id_source(self.ids.clone())
+            .segment("MSA")
+            .set("MSA-1", if error.is_some() { "AE" } else { "AA" })
+            .set("MSA-2", escape(query.control_id()));
+        if let Some(location) = &error {
+            builder = builder.segment("ERR").set("ERR-2", location).set("ERR-3", UNKNOWN_KEY).set("ERR-4", "E");
+        }
+        let status = match &page {
+            None => "AE",
+            Some(page) if page.hits.is_empty() => "NF",
+            Some(_) => "OK",
+        };
+        let (total, count, remaining) = page.as_ref().map_or((0, 0, 0), |page| (page.total, page.hits.len(), page.remaining));
+        builder = builder
+            .segment("QAK")
+            .set("QAK-1", escape(qpd.field(2).as_str()))
+            .set("QAK-2", status)
+            .set("QAK-3", qpd.fields.first().map(|f| f.to_hl7(&delimiters)).unwrap_or_default())
+            .set("QAK-4", total)
+            .set("QAK-5", count)
+            .set("QAK-6", remaining);
+
+        let mut response = builder.build()?;
+        response.segments.push(qpd.clone());
+        if let Some(mut page) = page {
+            // PID-1 counts the patients in each response
+            for (index, pid) in page.hits.iter_mut().flatten().enumerate() {
+                if pid.fields.is_empty() {
+                    pid.fields.push(parse_field("", &delimiters));
+                }
+                pid.fields[0] = parse_field(&(index + 1).to_string(), &delimiters);
+            }
+            page.append_to(&mut response);
+        }
+        Ok(response)
+    }
+}
+
+/// A PID with only the identifiers in the requested domains, or None if it has none of them
+fn in_domains(mut pid: Segment, domains: &[String]) -> Option<Segment> {
+    if domains.is_empty() {
+        return Some(pid);
+    }
+    let delimiters = Delimiters::default();
+    let identifiers = pid.fields.get(2)?.to_hl7(&delimiters);
+    let kept: Vec<&str> = identifiers
+        .split('~')
+        .filter(|cx| PatientIdentifier::from_cx(cx).is_some_and(|id| domains.contains(&id.authority)))
+        .collect();
+    if kept.is_empty() {
+        return None;
+    }
+    pid.fields[2] = parse_field(&kept.join("~"), &delimiters);
+    Some(pid)
+}
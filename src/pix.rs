@@ -0,0 +1,651 @@
+//! The HL7 v2 side of an IHE PIX Manager (ITI-8 and ITI-9)
+//!
+//! A `PixManager` keeps a cross-reference of patient identifiers from
+//! different assigning authorities. Patient identity feeds (ADT A01, A04,
+//! A05 and A08) link every identifier in a message's PID-3 as the same
+//! patient, and A40 merges fold the MRG-1 identifiers into the surviving
+//! patient's. PIX queries (QBP^Q23) are answered with RSP^K23 responses
+//! listing the patient's identifiers in the requested domains.
+//!
+//! The cross-reference is kept in an `IdentifierStore`: in memory by default,
+//! or in a `SqliteIdentifiers` database so links survive a restart.
+//!
+//! Give an MLLP server its handler both as the handler and as the query
+//! handler, so feeds are acknowledged as usual and queries get the responses
+//! it builds:
+//!
+//! ```
+//! use rust_hl7::pix::PixManager;
+//! use rust_hl7::Message;
+//! use std::sync::Arc;
+//!
+//! let pix = Arc::new(PixManager::new().sending_application("PIX_MGR"));
+//! pix.handle(&Message::parse("MSH|^~\\&|REG|HOSP|PIX_MGR||20240501||ADT^A04|1|P|2.5\r\
+//!     PID|1||12345^^^HOSP^MR~987^^^CLINIC^MR||DOE^JANE").unwrap()).unwrap();
+//!
+//! let response = pix.handle(&Message::parse("MSH|^~\\&|EHR|HOSP|PIX_MGR||20240501||QBP^Q23^QBP_Q21|2|P|2.5\r\
+//!     QPD|IHE PIX Query|Q1|12345^^^HOSP|^^^CLINIC\r\
+//!     RCP|I").unwrap()).unwrap();
+//! assert_eq!(response.message_type, "RSP^K23");
+//! assert_eq!(response.get_segment("QAK").unwrap().field(2).as_str(), Some("OK"));
+//! assert_eq!(response.get_segment("PID").unwrap().field(3).as_str(), Some("987"));
+//!
+//! // let server = MllpServer::builder().handler(pix.handler()).query_handler(pix.handler())...
+//! ```
+
+use crate::clock::{Clock, IdSource, SystemClock, UniqueIds};
+use crate::mllp::MessageHandler;
+use crate::store::StoreError;
+use crate::{Delimiters, HL7Error, Message, MessageBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+use std::sync::{Arc, RwLock};
+
+/// ERR-3 for an identifier or domain the manager doesn't know (table 0357)
+pub(crate) const UNKNOWN_KEY: &str = "204^Unknown Key Identifier^HL70357";
+
+/// A patient identifier in one assigning authority's domain
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PatientIdentifier {
+    pub id: String,
+    /// The assigning authority, CX-4: its namespace ID, or its universal ID when that's all there is
+    pub authority: String,
+}
+
+impl PatientIdentifier {
+    pub fn new<I: ToString, A: ToString>(id: I, authority: A) -> Self {
+        Self {
+            id: id.to_string(),
+            authority: authority.to_string(),
+        }
+    }
+
+    /// Read one repetition of a CX field, e.g. "12345^^^HOSP&1.2.3&ISO^MR"
+    ///
+    /// None if it has no ID or no assigning authority.
+    pub fn from_cx(cx: &str) -> Option<Self> {
+        let components: Vec<&str> = cx.split('^').collect();
+        let id = components.first().filter(|id| !id.is_empty())?;
+        let authority = domain(components.get(3).copied().unwrap_or_default())?;
+        Some(Self::new(id, authority))
+    }
+
+    /// The identifier as a CX value, e.g. "12345^^^HOSP"
+    pub fn to_cx(&self) -> String {
+        let escape = |text: &str| Delimiters::default().escape(text);
+        format!("{}^^^{}", escape(&self.id), escape(&self.authority))
+    }
+}
+
+/// The domain named by an assigning authority (HD): its namespace ID, else its universal ID
+fn domain(hd: &str) -> Option<&str> {
+    let mut parts = hd.split('&');
+    let namespace = parts.next().unwrap_or_default();
+    let universal = parts.next().unwrap_or_default();
+    Some(if namespace.is_empty() { universal } else { namespace }).filter(|domain| !domain.is_empty())
+}
+
+/// Every repetition of a CX field that has an ID and an assigning authority
+pub(crate) fn identifiers(message: &Message, segment: &str, field: usize) -> Vec<PatientIdentifier> {
+    let Some(field) = message.get_segment(segment).and_then(|s| s.fields.get(crate::terser::field_index(segment, field)?)) else {
+        return Vec::new();
+    };
+    field.to_hl7(&Delimiters::default()).split('~').filter_map(PatientIdentifier::from_cx).collect()
+}
+
+/// Where a cross-reference of patient identifiers is kept
+///
+/// Each patient has a number, which a merge retires rather than reuses.
+/// `CrossReference` keeps them in the process, and `SqliteIdentifiers` in a
+/// database file so they outlive a restart. Each call is atomic in the store.
+pub trait IdentifierStore: Send + Sync + Debug {
+    /// File these identifiers under one patient, returning it
+    ///
+    /// That's the oldest patient any of them are already filed under, or a
+    /// new one; the other patients they're filed under are merged into it.
+    fn link(&self, identifiers: &[PatientIdentifier]) -> Result<Option<u64>, StoreError>;
+
+    /// Link the surviving identifiers, then merge the patients of the prior ones into theirs
+    ///
+    /// The prior identifiers stay filed under the surviving patient.
+    fn merge(&self, surviving: &[PatientIdentifier], prior: &[PatientIdentifier]) -> Result<Option<u64>, StoreError>;
+
+    /// Forget these identifiers
+    fn remove(&self, identifiers: &[PatientIdentifier]) -> Result<(), StoreError>;
+
+    /// The patient an identifier is filed under
+    fn patient(&self, identifier: &PatientIdentifier) -> Result<Option<u64>, StoreError>;
+
+    /// The patient in use for one that may have been retired by a merge, or none if it has no identifiers
+    fn resolve(&self, patient: u64) -> Result<Option<u64>, StoreError>;
+
+    /// The identifiers filed under a patient, or under the one that replaced it
+    fn identifiers(&self, patient: u64) -> Result<BTreeSet<PatientIdentifier>, StoreError>;
+
+    /// Whether any identifier has been filed from this assigning authority
+    fn knows_domain(&self, authority: &str) -> Result<bool, StoreError>;
+
+    /// How many patients have identifiers filed under them
+    fn patients(&self) -> Result<usize, StoreError>;
+}
+
+/// A cross-reference kept in memory, lost when the process exits
+#[derive(Debug, Default)]
+pub struct CrossReference {
+    links: RwLock<Links>,
+}
+
+#[derive(Debug, Default)]
+struct Links {
+    /// Each identifier's patient
+    patient_of: HashMap<PatientIdentifier, u64>,
+    /// Each patient's identifiers
+    patients: BTreeMap<u64, BTreeSet<PatientIdentifier>>,
+    /// Patients retired by a merge, and the ones that took their place
+    retired: HashMap<u64, u64>,
+    domains: BTreeSet<String>,
+    last: u64,
+}
+
+impl Links {
+    fn link(&mut self, identifiers: &[PatientIdentifier]) -> Option<u64> {
+        let existing: BTreeSet<u64> = identifiers.iter().filter_map(|id| self.patient_of.get(id).copied()).collect();
+        let target = match existing.first() {
+            Some(&oldest) => oldest,
+            None if identifiers.is_empty() => return None,
+            None => {
+                self.last += 1;
+                self.last
+            }
+        };
+        for other in existing.into_iter().skip(1) {
+            self.absorb(target, other);
+        }
+        self.file(target, identifiers.iter().cloned());
+        Some(target)
+    }
+
+    fn file<I: IntoIterator<Item = PatientIdentifier>>(&mut self, target: u64, identifiers: I) {
+        for identifier in identifiers {
+            self.domains.insert(identifier.authority.clone());
+            if let Some(previous) = self.patient_of.insert(identifier.clone(), target).filter(|&p| p != target) {
+                self.unfile(previous, &identifier);
+            }
+            self.patients.entry(target).or_default().insert(identifier);
+        }
+    }
+
+    fn unfile(&mut self, patient: u64, identifier: &PatientIdentifier) {
+        if let Some(identifiers) = self.patients.get_mut(&patient) {
+            identifiers.remove(identifier);
+            if identifiers.is_empty() {
+                self.patients.remove(&patient);
+            }
+        }
+    }
+
+    /// Move every identifier of `other` to `target`, retiring `other`
+    fn absorb(&mut self, target: u64, other: u64) {
+        let identifiers = self.patients.remove(&other).unwrap_or_default();
+        self.file(target, identifiers);
+        self.retired.insert(other, target);
+    }
+
+    /// Follow retired patients to the one in use
+    fn current(&self, mut patient: u64) -> u64 {
+        while let Some(&next) = self.retired.get(&patient) {
+            patient = next;
+        }
+        patient
+    }
+}
+
+impl CrossReference {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Links> {
+        self.links.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Links> {
+        self.links.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl IdentifierStore for CrossReference {
+    fn link(&self, identifiers: &[PatientIdentifier]) -> Result<Option<u64>, StoreError> {
+        Ok(self.write().link(identifiers))
+    }
+
+    fn merge(&self, surviving: &[PatientIdentifier], prior: &[PatientIdentifier]) -> Result<Option<u64>, StoreError> {
+        let mut links = self.write();
+        let Some(target) = links.link(surviving) else {
+            return Ok(None);
+        };
+        let retired: BTreeSet<u64> = prior.iter().filter_map(|id| links.patient_of.get(id).copied()).collect();
+        for other in retired.into_iter().filter(|&other| other != target) {
+            links.absorb(target, other);
+        }
+        links.file(target, prior.iter().cloned());
+        Ok(Some(target))
+    }
+
+    fn remove(&self, identifiers: &[PatientIdentifier]) -> Result<(), StoreError> {
+        let mut links = self.write();
+        for identifier in identifiers {
+            if let Some(patient) = links.patient_of.remove(identifier) {
+                links.unfile(patient, identifier);
+            }
+        }
+        Ok(())
+    }
+
+    fn patient(&self, identifier: &PatientIdentifier) -> Result<Option<u64>, StoreError> {
+        Ok(self.read().patient_of.get(identifier).copied())
+    }
+
+    fn resolve(&self, patient: u64) -> Result<Option<u64>, StoreError> {
+        let links = self.read();
+        let patient = links.current(patient);
+        Ok(links.patients.contains_key(&patient).then_some(patient))
+    }
+
+    fn identifiers(&self, patient: u64) -> Result<BTreeSet<PatientIdentifier>, StoreError> {
+        let links = self.read();
+        Ok(links.patients.get(&links.current(patient)).cloned().unwrap_or_default())
+    }
+
+    fn knows_domain(&self, authority: &str) -> Result<bool, StoreError> {
+        Ok(self.read().domains.contains(authority))
+    }
+
+    fn patients(&self) -> Result<usize, StoreError> {
+        Ok(self.read().patients.len())
+    }
+}
+
+/// A cross-reference kept in a SQLite database file
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteIdentifiers {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteIdentifiers {
+    /// Open (or create) a cross-reference database at the given path
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, StoreError> {
+        Self::init(rusqlite::Connection::open(path)?)
+    }
+
+    /// Create a cross-reference in a private in-memory database
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn init(connection: rusqlite::Connection) -> Result<Self, StoreError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS patients (
+                patient INTEGER PRIMARY KEY AUTOINCREMENT,
+                replaced_by INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS identifiers (
+                id TEXT NOT NULL,
+                authority TEXT NOT NULL,
+                patient INTEGER NOT NULL,
+                PRIMARY KEY (id, authority)
+            );
+            CREATE INDEX IF NOT EXISTS identifiers_patient ON identifiers (patient);
+            CREATE TABLE IF NOT EXISTS domains (authority TEXT PRIMARY KEY);",
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<T>) -> Result<T, StoreError> {
+        Ok(f(&self.connection.lock().unwrap_or_else(|e| e.into_inner()))?)
+    }
+
+    /// Run `f` in a transaction, committing what it did if it succeeds
+    fn transaction<T>(&self, f: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<T>) -> Result<T, StoreError> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let result = f(&transaction)?;
+        transaction.commit()?;
+        Ok(result)
+    }
+
+    fn patient_in(connection: &rusqlite::Connection, identifier: &PatientIdentifier) -> rusqlite::Result<Option<u64>> {
+        use rusqlite::OptionalExtension;
+        connection
+            .query_row(
+                "SELECT patient FROM identifiers WHERE id = ?1 AND authority = ?2",
+                rusqlite::params![identifier.id, identifier.authority],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    fn link_in(connection: &rusqlite::Connection, identifiers: &[PatientIdentifier]) -> rusqlite::Result<Option<u64>> {
+        let mut existing = BTreeSet::new();
+        for identifier in identifiers {
+            existing.extend(Self::patient_in(connection, identifier)?);
+        }
+        let target = match existing.first() {
+            Some(&oldest) => oldest,
+            None if identifiers.is_empty() => return Ok(None),
+            None => {
+                connection.execute("INSERT INTO patients DEFAULT VALUES", [])?;
+                connection.last_insert_rowid() as u64
+            }
+        };
+        for other in existing.into_iter().skip(1) {
+            Self::absorb_in(connection, target, other)?;
+        }
+        Self::file_in(connection, target, identifiers)?;
+        Ok(Some(target))
+    }
+
+    fn file_in(connection: &rusqlite::Connection, target: u64, identifiers: &[PatientIdentifier]) -> rusqlite::Result<()> {
+        for identifier in identifiers {
+            connection.execute(
+                "INSERT INTO identifiers (id, authority, patient) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id, authority) DO UPDATE SET patient = excluded.patient",
+                rusqlite::params![identifier.id, identifier.authority, target],
+            )?;
+            connection.execute("INSERT OR IGNORE INTO domains (authority) VALUES (?1)", [&identifier.authority])?;
+        }
+        Ok(())
+    }
+
+    fn absorb_in(connection: &rusqlite::Connection, target: u64, other: u64) -> rusqlite::Result<()> {
+        connection.execute("UPDATE identifiers SET patient = ?1 WHERE patient = ?2", [target, other])?;
+        connection.execute("UPDATE patients SET replaced_by = ?1 WHERE patient = ?2", [target, other])?;
+        Ok(())
+    }
+
+    fn current_in(connection: &rusqlite::Connection, mut patient: u64) -> rusqlite::Result<u64> {
+        use rusqlite::OptionalExtension;
+        let mut statement = connection.prepare_cached("SELECT replaced_by FROM patients WHERE patient = ?1")?;
+        while let Some(Some(next)) = statement.query_row([patient], |row| row.get::<_, Option<u64>>(0)).optional()? {
+            patient = next;
+        }
+        Ok(patient)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl IdentifierStore for SqliteIdentifiers {
+    fn link(&self, identifiers: &[PatientIdentifier]) -> Result<Option<u64>, StoreError> {
+        self.transaction(|connection| Self::link_in(connection, identifiers))
+    }
+
+    fn merge(&self, surviving: &[PatientIdentifier], prior: &[PatientIdentifier]) -> Result<Option<u64>, StoreError> {
+        self.transaction(|connection| {
+            let Some(target) = Self::link_in(connection, surviving)? else {
+                return Ok(None);
+            };
+            let mut retired = BTreeSet::new();
+            for identifier in prior {
+                retired.extend(Self::patient_in(connection, identifier)?);
+            }
+            for other in retired.into_iter().filter(|&other| other != target) {
+                Self::absorb_in(connection, target, other)?;
+            }
+            Self::file_in(connection, target, prior)?;
+            Ok(Some(target))
+        })
+    }
+
+    fn remove(&self, identifiers: &[PatientIdentifier]) -> Result<(), StoreError> {
+        self.transaction(|connection| {
+            for identifier in identifiers {
+                connection.execute(
+                    "DELETE FROM identifiers WHERE id = ?1 AND authority = ?2",
+                    rusqlite::params![identifier.id, identifier.authority],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn patient(&self, identifier: &PatientIdentifier) -> Result<Option<u64>, StoreError> {
+        self.read(|connection| Self::patient_in(connection, identifier))
+    }
+
+    fn resolve(&self, patient: u64) -> Result<Option<u64>, StoreError> {
+        self.read(|connection| {
+            let patient = Self::current_in(connection, patient)?;
+            let count: i64 = connection.query_row("SELECT COUNT(*) FROM identifiers WHERE patient = ?1", [patient], |row| row.get(0))?;
+            Ok((count > 0).then_some(patient))
+        })
+    }
+
+    fn identifiers(&self, patient: u64) -> Result<BTreeSet<PatientIdentifier>, StoreError> {
+        self.read(|connection| {
+            let patient = Self::current_in(connection, patient)?;
+            let mut statement = connection.prepare_cached("SELECT id, authority FROM identifiers WHERE patient = ?1")?;
+            let rows = statement.query_map([patient], |row| Ok(PatientIdentifier::new(row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            rows.collect()
+        })
+    }
+
+    fn knows_domain(&self, authority: &str) -> Result<bool, StoreError> {
+        self.read(|connection| {
+            connection.query_row("SELECT EXISTS (SELECT 1 FROM domains WHERE authority = ?1)", [authority], |row| row.get(0))
+        })
+    }
+
+    fn patients(&self) -> Result<usize, StoreError> {
+        self.read(|connection| {
+            connection.query_row("SELECT COUNT(DISTINCT patient) FROM identifiers", [], |row| row.get::<_, i64>(0).map(|n| n as usize))
+        })
+    }
+}
+
+/// Maintains a patient identifier cross-reference from ADT feeds and answers PIX queries
+#[derive(Debug)]
+pub struct PixManager {
+    xref: Arc<dyn IdentifierStore>,
+    sending_application: String,
+    sending_facility: String,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdSource>,
+}
+
+impl Default for PixManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PixManager {
+    pub fn new() -> Self {
+        Self {
+            xref: Arc::new(CrossReference::new()),
+            sending_application: String::new(),
+            sending_facility: String::new(),
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(UniqueIds::default()),
+        }
+    }
+
+    /// Keep the cross-reference in this store instead of in memory
+    pub fn store(mut self, store: Arc<dyn IdentifierStore>) -> Self {
+        self.xref = store;
+        self
+    }
+
+    /// MSH-3 of the responses
+    pub fn sending_application<T: ToString>(mut self, application: T) -> Self {
+        self.sending_application = application.to_string();
+        self
+    }
+
+    /// MSH-4 of the responses
+    pub fn sending_facility<T: ToString>(mut self, facility: T) -> Self {
+        self.sending_facility = facility.to_string();
+        self
+    }
+
+    /// Take MSH-7 of the responses from this clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Take MSH-10 of the responses from this source
+    pub fn id_source(mut self, ids: Arc<dyn IdSource>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// The identifiers linked to this one, including itself, or none if it's unknown
+    pub fn linked(&self, identifier: &PatientIdentifier) -> Result<BTreeSet<PatientIdentifier>, StoreError> {
+        match self.xref.patient(identifier)? {
+            Some(patient) => self.xref.identifiers(patient),
+            None => Ok(BTreeSet::new()),
+        }
+    }
+
+    /// Apply a feed message or answer a query, returning the response to send
+    ///
+    /// Other messages are rejected.
+    pub fn handle(&self, message: &Message) -> Result<Message, HL7Error> {
+        let mut parts = message.message_type.split('^');
+        match (parts.next(), parts.next()) {
+            (Some("ADT"), Some("A01" | "A04" | "A05" | "A08")) => {
+                let identifiers = identifiers(message, "PID", 3);
+                if identifiers.is_empty() {
+                    return Err(HL7Error::MissingField("PID-3 with an assigning authority".to_string()));
+                }
+                self.xref.link(&identifiers).map_err(store_error)?;
+                self.ack(message)
+            }
+            (Some("ADT"), Some("A40")) => {
+                let surviving = identifiers(message, "PID", 3);
+                let prior = identifiers(message, "MRG", 1);
+                if surviving.is_empty() || prior.is_empty() {
+                    return Err(HL7Error::MissingField("PID-3 and MRG-1 with assigning authorities".to_string()));
+                }
+                // The prior identifiers are retired, so queries for them find nothing
+                self.xref.merge(&surviving, &prior).map_err(store_error)?;
+                self.xref.remove(&prior).map_err(store_error)?;
+                self.ack(message)
+            }
+            (Some("QBP"), Some("Q23")) => self.query(message),
+            _ => Err(HL7Error::Rejected(format!(
+                "{} isn't a PIX feed or query",
+                message.message_type
+            ))),
+        }
+    }
+
+    /// A handler for an MLLP server in `AckMode::Application`
+    pub fn handler(self: &Arc<Self>) -> MessageHandler {
+        let pix = self.clone();
+        Arc::new(move |message: Message| pix.handle(&message))
+    }
+
+    /// Answer a QBP^Q23 with an RSP^K23
+    fn query(&self, query: &Message) -> Result<Message, HL7Error> {
+        let qpd = query
+            .get_segment("QPD")
+            .ok_or_else(|| HL7Error::MissingField("QPD segment".to_string()))?;
+        let person = identifiers(query, "QPD", 3)
+            .into_iter()
+            .next()
+            .ok_or_else(|| HL7Error::MissingField("QPD-3 with an assigning authority".to_string()))?;
+        let domains = qpd.fields.get(3).map(|field| requested_domains(&field.to_hl7(&Delimiters::default()))).unwrap_or_default();
+
+        let linked = self.linked(&person).map_err(store_error)?;
+        // ERR-2 points at the unknown identifier, or the first unknown domain
+        let mut error = linked.is_empty().then(|| "QPD^1^3^1^1".to_string());
+        if error.is_none() {
+            for (index, domain) in domains.iter().enumerate() {
+                if !self.xref.knows_domain(domain).map_err(store_error)? {
+                    error = Some(format!("QPD^1^4^{}^4", index + 1));
+                    break;
+                }
+            }
+        }
+        let found: Vec<&PatientIdentifier> = linked
+            .iter()
+            .filter(|identifier| **identifier != person)
+            .filter(|identifier| domains.is_empty() || domains.contains(&identifier.authority))
+            .collect();
+
+        let escape = |text: &str| Delimiters::default().escape(text);
+        let control_id = query.control_id().unwrap_or_default();
+        let mut builder = self
+            .response("RSP^K23^RSP_K23", query)
+            .segment("MSA")
+            .set("MSA-1", if error.is_some() { "AE" } else { "AA" })
+            .set("MSA-2", escape(control_id));
+        if let Some(location) = &error {
+            builder = builder.segment("ERR").set("ERR-2", location).set("ERR-3", UNKNOWN_KEY).set("ERR-4", "E");
+        }
+        let status = match (&error, found.is_empty()) {
+            (Some(_), _) => "AE",
+            (None, true) => "NF",
+            (None, false) => "OK",
+        };
+        builder = builder
+            .segment("QAK")
+            .set("QAK-1", qpd.field(2).as_str().map(escape).unwrap_or_default())
+            .set("QAK-2", status);
+        if error.is_none() && !found.is_empty() {
+            let cx: Vec<String> = found.iter().map(|identifier| identifier.to_cx()).collect();
+            builder = builder.segment("PID").set("PID-1", "1").set("PID-3", cx.join("~"));
+        }
+
+        // The query parameters are echoed back as they were sent
+        let mut response = builder.build()?;
+        let qak = response.segments.iter().position(|s| s.name == "QAK").unwrap_or(response.segments.len() - 1);
+        response.segments.insert(qak + 1, qpd.clone());
+        Ok(response)
+    }
+
+    /// An ACK for a feed message
+    fn ack(&self, message: &Message) -> Result<Message, HL7Error> {
+        let trigger = message.message_type.split('^').nth(1).unwrap_or_default();
+        self.response(format!("ACK^{}^ACK", trigger), message)
+            .segment("MSA")
+            .set("MSA-1", "AA")
+            .set("MSA-2", Delimiters::default().escape(message.control_id().unwrap_or_default()))
+            .build()
+    }
+
+    /// A response addressed back to the sender of `request`
+    fn response(&self, message_type: impl ToString, request: &Message) -> MessageBuilder {
+        let escape = |text: Option<&str>| Delimiters::default().escape(text.unwrap_or_default());
+        MessageBuilder::new(message_type)
+            .sending_application(&self.sending_application)
+            .sending_facility(&self.sending_facility)
+            .receiving_application(escape(request.sending_application()))
+            .receiving_facility(escape(request.sending_facility()))
+            .version(if request.version.is_empty() { "2.5" } else { request.version.as_str() })
+            .clock(self.clock.clone())
+            .id_source(self.ids.clone())
+    }
+}
+
+/// A cross-reference store failure, NACKed like any other delivery failure
+fn store_error(e: StoreError) -> HL7Error {
+    HL7Error::DeliveryError(format!("Cross-reference store error: {}", e))
+}
+
+/// The assigning authorities in a QPD domains field, one per repetition, e.g. "^^^HOSP~^^^CLINIC"
+pub(crate) fn requested_domains(field: &str) -> Vec<String> {
+    field
+        .split('~')
+        .filter_map(|cx| domain(cx.split('^').nth(3).unwrap_or_default()))
+        .map(str::to_string)
+        .collect()
+}
@@ -0,0 +1,180 @@
+//! Transforms and handlers supplied as WebAssembly modules
+//!
+//! A plugin gets the message in its canonical JSON form (`Message::to_json`)
+//! and gives back the message to carry on with, so site-specific logic can be
+//! written in any language that compiles to WebAssembly and shipped without
+//! rebuilding the engine. A module has to export:
+//!
+//! - `memory` - its linear memory
+//! - `alloc(len: i32) -> i32` - space for `len` bytes, where the input is written
+//! - `transform(ptr: i32, len: i32) -> i64` - called with the input's address
+//!   and length; returns the output's address in the high 32 bits and its
+//!   length in the low 32
+//!
+//! The output is the modified message's canonical JSON, or
+//! `{"reject": "reason"}` to reject the message with an AR NACK. Modules can't
+//! import anything, so a plugin has no access to files, the network or the
+//! clock. Each call runs in a fresh instance limited to 64 MiB of memory and
+//! about a billion instructions; a module that traps or runs out fails the
+//! message rather than stalling the listener.
+//!
+//! ```
+//! use rust_hl7::plugins::Plugin;
+//! use rust_hl7::Message;
+//!
+//! // Gives every message back unchanged
+//! let plugin = Plugin::from_bytes("echo", r#"(module
+//!     (memory (export "memory") 1)
+//!     (global $next (mut i32) (i32.const 0))
+//!     (func (export "alloc") (param $len i32) (result i32)
+//!         (local $ptr i32)
+//!         (local.set $ptr (global.get $next))
+//!         (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+//!         (local.get $ptr))
+//!     (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+//!         (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+//!                 (i64.extend_i32_u (local.get $len)))))"#).unwrap();
+//! let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rPID|1||12345").unwrap();
+//! assert_eq!(plugin.call(&message).unwrap().to_hl7(), message.to_hl7());
+//! ```
+
+use crate::mllp::MessageHandler;
+use crate::transform::Transform;
+use crate::{HL7Error, Message};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use wasmtime::{Config, Engine, ExternType, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Most memory a plugin can grow to
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Fuel a plugin gets per message, roughly one unit per instruction
+const MAX_FUEL: u64 = 1_000_000_000;
+
+/// The engine every plugin is compiled for, with fuel metering on
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("default engine config is valid")
+    })
+}
+
+/// A plugin's answer when it rejects the message
+#[derive(Deserialize)]
+struct Rejection {
+    reject: String,
+}
+
+/// A compiled WebAssembly module, ready to run against messages
+///
+/// Cloning is cheap and clones share the compiled code.
+#[derive(Clone)]
+pub struct Plugin {
+    name: String,
+    module: InstancePre<StoreLimits>,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl Plugin {
+    /// Compile a module from its binary or text (`.wat`) form, checking its imports and exports
+    pub fn from_bytes<N: ToString>(name: N, bytes: impl AsRef<[u8]>) -> Result<Self, HL7Error> {
+        let name = name.to_string();
+        let invalid = |reason: String| HL7Error::ParseError(format!("Plugin '{}': {}", name, reason));
+        let module = Module::new(engine(), bytes).map_err(|e| invalid(e.to_string()))?;
+        if let Some(import) = module.imports().next() {
+            return Err(invalid(format!(
+                "imports {}::{}, but plugins can't import anything",
+                import.module(),
+                import.name()
+            )));
+        }
+        for (export, function) in [("memory", false), ("alloc", true), ("transform", true)] {
+            match module.get_export(export) {
+                Some(ExternType::Func(_)) if function => {}
+                Some(ExternType::Memory(_)) if !function => {}
+                _ => return Err(invalid(format!("doesn't export `{}`", export))),
+            }
+        }
+        let module = Linker::new(engine())
+            .instantiate_pre(&module)
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(Self { name, module })
+    }
+
+    /// Read and compile a `.wasm` or `.wat` file, named after the file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HL7Error> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| HL7Error::ParseError(format!("Can't read plugin {}: {}", path.display(), e)))?;
+        Self::from_bytes(path.display(), bytes)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the plugin on a message, returning the message it gives back
+    ///
+    /// A rejection from the plugin is a `Rejected` error, answered with an AR NACK.
+    pub fn call(&self, message: &Message) -> Result<Message, HL7Error> {
+        let failed = |reason: String| HL7Error::InvalidStructure(format!("Plugin '{}' failed: {}", self.name, reason));
+
+        let mut store = Store::new(engine(), StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build());
+        store.limiter(|limits| limits);
+        store.set_fuel(MAX_FUEL).map_err(|e| failed(e.to_string()))?;
+        let instance = self.module.instantiate(&mut store).map_err(|e| failed(e.to_string()))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| failed("no memory".to_string()))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(|e| failed(e.to_string()))?;
+        let transform = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "transform")
+            .map_err(|e| failed(e.to_string()))?;
+
+        let input = message.to_json();
+        let len = i32::try_from(input.len()).map_err(|_| failed("message is too large".to_string()))?;
+        let ptr = alloc.call(&mut store, len).map_err(|e| failed(e.to_string()))?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input.as_bytes())
+            .map_err(|e| failed(format!("`alloc` gave an address outside memory: {}", e)))?;
+        let packed = transform.call(&mut store, (ptr, len)).map_err(|e| failed(e.to_string()))? as u64;
+
+        // Check the range before allocating for it, so a bad length can't allocate 4 GiB
+        let (start, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if start.checked_add(len).is_none_or(|end| end > memory.data_size(&store)) {
+            return Err(failed(format!("output of {} bytes at {} is outside memory", len, start)));
+        }
+        let mut output = vec![0; len];
+        memory
+            .read(&store, start, &mut output)
+            .map_err(|e| failed(format!("output is outside memory: {}", e)))?;
+        let output = String::from_utf8(output).map_err(|e| failed(format!("output isn't UTF-8: {}", e)))?;
+        if let Ok(rejection) = serde_json::from_str::<Rejection>(&output) {
+            return Err(HL7Error::Rejected(rejection.reject));
+        }
+        Message::from_json(&output).map_err(|e| failed(e.to_string()))
+    }
+
+    /// The plugin as a handler, replying with the message it gives back
+    pub fn handler(self: &Arc<Self>) -> MessageHandler {
+        let plugin = self.clone();
+        Arc::new(move |message: Message| plugin.call(&message))
+    }
+}
+
+impl Transform for Plugin {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        *message = self.call(message)?;
+        Ok(())
+    }
+}
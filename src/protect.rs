@@ -0,0 +1,571 @@
+use crate::store::{ArchiveRecord, MessageStore, Query, StoreError};
+use crate::terser::TerserPath;
+use crate::{charset, parse_field, Delimiters, HL7Error, Message, Segment};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Start of an encrypted value: "ENC:<key ID>:<nonce and ciphertext in hex>"
+const ENCRYPTED: &str = "ENC:";
+
+/// What redacted values are replaced with
+pub const REDACTED: &str = "REDACTED";
+
+/// Characters a key ID can't contain, so encrypted values never need escaping
+const RESERVED: [char; 6] = ['|', '^', '~', '\\', '&', ':'];
+
+/// How a value is protected before it is archived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Encrypted with AES-256-GCM; decrypted again when read with the key
+    Encrypt,
+    /// Replaced with `REDACTED` for good
+    Redact,
+}
+
+/// Supplies the keys archived values are encrypted with
+///
+/// Every encrypted value records the ID of its key, so keys can be rotated:
+/// new values use the current key while older ones are still read with theirs.
+pub trait KeyProvider: Send + Sync {
+    /// ID of the key to encrypt new values with
+    fn current_key_id(&self) -> String;
+
+    /// A 256-bit key by ID, if it's known
+    fn key(&self, id: &str) -> Option<[u8; 32]>;
+}
+
+/// Keys held in memory, e.g. loaded from the environment or a secrets manager at startup
+#[derive(Clone)]
+pub struct StaticKeys {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl std::fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The keys stay out of logs
+        f.debug_struct("StaticKeys").field("current", &self.current).finish_non_exhaustive()
+    }
+}
+
+impl StaticKeys {
+    /// Encrypt with this key
+    pub fn new<I: ToString>(id: I, key: [u8; 32]) -> Self {
+        let current = id.to_string();
+        Self {
+            keys: HashMap::from([(current.clone(), key)]),
+            current,
+        }
+    }
+
+    /// A key given as 64 hex digits
+    pub fn from_hex<I: ToString>(id: I, key: &str) -> Result<Self, StoreError> {
+        Ok(Self::new(id, parse_key(key)?))
+    }
+
+    /// Also decrypt values encrypted with an older key
+    pub fn with_previous<I: ToString>(mut self, id: I, key: [u8; 32]) -> Self {
+        self.keys.entry(id.to_string()).or_insert(key);
+        self
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> String {
+        self.current.clone()
+    }
+
+    fn key(&self, id: &str) -> Option<[u8; 32]> {
+        self.keys.get(id).copied()
+    }
+}
+
+/// Which values are encrypted or redacted before messages are archived
+///
+/// Paths name a field, component or subcomponent in every occurrence of the
+/// segment, e.g. "PID-19" or "NK1-2.1"; "OBX(2)-5" names one occurrence. A
+/// component or subcomponent is protected in every repetition of its field.
+///
+/// ```
+/// use rust_hl7::protect::{FieldPolicy, StaticKeys};
+/// use std::sync::Arc;
+///
+/// let policy = FieldPolicy::new()
+///     .encrypt("PID-19").unwrap()
+///     .redact("PID-5").unwrap()
+///     .with_keys(Arc::new(StaticKeys::new("2024-05", [7; 32])));
+/// let mut message = rust_hl7::Message::parse(
+///     "MSH|^~\\&|EHR|HOSP|LAB|HOSP|20230401||ADT^A01|1|P|2.5\rPID|1||12345||DOE^JOHN||||||||||||||123-45-6789",
+/// )
+/// .unwrap();
+/// policy.protect(&mut message).unwrap();
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-5").as_deref(), Some("REDACTED"));
+/// assert!(rust_hl7::terser::get(&message, "PID-19").unwrap().starts_with("ENC:2024-05:"));
+///
+/// policy.reveal(&mut message).unwrap();
+/// assert_eq!(rust_hl7::terser::get(&message, "PID-19").as_deref(), Some("123-45-6789"));
+/// ```
+#[derive(Clone, Default)]
+pub struct FieldPolicy {
+    rules: Vec<Rule>,
+    keys: Option<Arc<dyn KeyProvider>>,
+}
+
+impl std::fmt::Debug for FieldPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldPolicy")
+            .field("rules", &self.rules)
+            .field("keys", &self.keys.as_ref().map(|keys| keys.current_key_id()))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    path: TerserPath,
+    /// Whether the path applies to every occurrence of its segment
+    every: bool,
+    protection: Protection,
+}
+
+impl Rule {
+    /// Whether every value `other` names is inside one this rule names
+    fn contains(&self, other: &Rule) -> bool {
+        let (outer, inner) = (&self.path, &other.path);
+        outer.segment == inner.segment
+            && outer.field == inner.field
+            && (self.every || (!other.every && outer.repetition == inner.repetition))
+            && match outer.component {
+                None => true,
+                Some(component) => {
+                    inner.component == Some(component)
+                        && (outer.subcomponent.is_none() || outer.subcomponent == inner.subcomponent)
+                }
+            }
+    }
+
+    /// The segments of a message the rule applies to
+    fn segments<'a>(&'a self, message: &'a mut Message) -> impl Iterator<Item = &'a mut Segment> + 'a {
+        message
+            .segments
+            .iter_mut()
+            .filter(move |segment| segment.name == self.path.segment)
+            .enumerate()
+            .filter(move |(index, _)| self.every || index + 1 == self.path.repetition)
+            .map(|(_, segment)| segment)
+    }
+}
+
+impl FieldPolicy {
+    /// A policy that protects nothing, but still decrypts values when given keys
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encrypt the value at a path
+    pub fn encrypt(self, path: &str) -> Result<Self, HL7Error> {
+        self.rule(path, Protection::Encrypt)
+    }
+
+    /// Redact the value at a path
+    pub fn redact(self, path: &str) -> Result<Self, HL7Error> {
+        self.rule(path, Protection::Redact)
+    }
+
+    /// Protect the value at a path
+    pub fn rule(mut self, path: &str, protection: Protection) -> Result<Self, HL7Error> {
+        self.rules.push(Rule {
+            path: path.parse()?,
+            every: !path.contains('('),
+            protection,
+        });
+        Ok(self)
+    }
+
+    /// Encrypt and decrypt with these keys
+    pub fn with_keys(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Whether a path is encrypted or redacted, which makes it unusable for queries
+    fn covers(&self, segment: &str, field: usize, component: usize) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.path.segment == segment && rule.path.field == field && rule.path.component.is_none_or(|c| c == component)
+        })
+    }
+
+    /// Encrypt and redact the message's designated values in place
+    ///
+    /// Every value at an encrypted path is encrypted, whatever it holds, so a
+    /// sender can't keep a value in the clear by making it look encrypted.
+    /// Redactions go first, and a path inside one already protected is left
+    /// to it, so nothing is encrypted twice.
+    pub fn protect(&self, message: &mut Message) -> Result<(), StoreError> {
+        let mut applied: Vec<&Rule> = Vec::new();
+        let ordered = self
+            .rules
+            .iter()
+            .filter(|rule| rule.protection == Protection::Redact)
+            .chain(self.rules.iter().filter(|rule| rule.protection == Protection::Encrypt));
+        for rule in ordered {
+            if applied.iter().any(|earlier| earlier.contains(rule)) {
+                continue;
+            }
+            applied.push(rule);
+            let cipher = match rule.protection {
+                Protection::Encrypt => {
+                    let keys = self
+                        .keys
+                        .as_deref()
+                        .ok_or_else(|| StoreError::EncryptionError("No keys to encrypt archived fields with".to_string()))?;
+                    Some(Encryptor::new(keys)?)
+                }
+                Protection::Redact => None,
+            };
+            let aad = format!("{}-{}", rule.path.segment, rule.path.field);
+            for segment in rule.segments(message) {
+                update(segment, &rule.path, |value| {
+                    if value.is_empty() {
+                        return Ok(None);
+                    }
+                    match &cipher {
+                        Some(cipher) => cipher.encrypt(value, &aad).map(Some),
+                        None => Ok((value != REDACTED).then(|| REDACTED.to_string())),
+                    }
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt the values `protect` encrypted, in place
+    ///
+    /// Only the paths this policy encrypts are read, so text elsewhere that
+    /// happens to look encrypted is left as it is. Returns whether anything
+    /// was decrypted.
+    pub fn reveal(&self, message: &mut Message) -> Result<bool, StoreError> {
+        let keys = self
+            .keys
+            .as_deref()
+            .ok_or_else(|| StoreError::EncryptionError("No keys to decrypt archived fields with".to_string()))?;
+        let mut applied: Vec<&Rule> = Vec::new();
+        let revealed = std::cell::Cell::new(false);
+        for rule in self.rules.iter().filter(|rule| rule.protection == Protection::Encrypt) {
+            if applied.iter().any(|earlier| earlier.contains(rule)) {
+                continue;
+            }
+            applied.push(rule);
+            let aad = format!("{}-{}", rule.path.segment, rule.path.field);
+            for segment in rule.segments(message) {
+                update(segment, &rule.path, |value| {
+                    if !value.starts_with(ENCRYPTED) {
+                        return Ok(None);
+                    }
+                    revealed.set(true);
+                    decrypt(keys, value, &aad).map(Some)
+                })?;
+            }
+        }
+        Ok(revealed.get())
+    }
+
+    /// Protect a record's message, clearing metadata taken from protected fields
+    ///
+    /// Records whose message can't be parsed are archived without it, since
+    /// the values to protect can't be found.
+    pub fn protect_record(&self, record: &ArchiveRecord) -> Result<ArchiveRecord, StoreError> {
+        let mut protected = record.clone();
+        let charset = charset::detect(&record.raw).unwrap_or_default();
+        let message = charset.decode(&record.raw).ok().and_then(|text| Message::parse(&text).ok());
+        match message {
+            Some(mut message) => {
+                // Messages without protected values are kept exactly as they were
+                let original = message.to_hl7();
+                self.protect(&mut message)?;
+                let text = message.to_hl7();
+                if text != original {
+                    protected.raw = charset.encode(&text);
+                }
+            }
+            None if !self.rules.is_empty() => {
+                warn!("Archiving message that doesn't parse without its contents");
+                protected.raw.clear();
+            }
+            None => {}
+        }
+
+        for (value, segment, field, component) in [
+            (&mut protected.control_id, "MSH", 10, 1),
+            (&mut protected.patient_id, "PID", 3, 1),
+            (&mut protected.sending_application, "MSH", 3, 1),
+            (&mut protected.sending_facility, "MSH", 4, 1),
+        ] {
+            if self.covers(segment, field, component) {
+                *value = None;
+            }
+        }
+        Ok(protected)
+    }
+}
+
+/// Decrypt every value in a message that looks encrypted, in place
+///
+/// Returns whether anything was decrypted. Redacted values stay redacted.
+/// Text a sender wrote to look encrypted fails to decrypt, so when the
+/// policy is at hand, `FieldPolicy::reveal` is the one to use.
+pub fn reveal(message: &mut Message, keys: &dyn KeyProvider) -> Result<bool, StoreError> {
+    let mut revealed = false;
+    for segment in &mut message.segments {
+        let offset = if segment.name == "MSH" { 2 } else { 1 };
+        for (index, field) in segment.fields.iter_mut().enumerate() {
+            let aad = format!("{}-{}", segment.name, index + offset);
+            let mut open = |value: &str| -> Result<Option<String>, StoreError> {
+                if !value.starts_with(ENCRYPTED) {
+                    return Ok(None);
+                }
+                revealed = true;
+                decrypt(keys, value, &aad).map(Some)
+            };
+
+            // Values are encrypted whole, so each one is a single subcomponent in one repetition
+            let delimiters = Delimiters::default();
+            let mut changed = false;
+            let mut repetitions = Vec::new();
+            for repetition in field.to_hl7(&delimiters).split(delimiters.repetition) {
+                let mut components = Vec::new();
+                for component in repetition.split(delimiters.component) {
+                    let mut parts = Vec::new();
+                    for part in component.split(delimiters.subcomponent) {
+                        match open(part)? {
+                            Some(plain) => {
+                                parts.push(plain);
+                                changed = true;
+                            }
+                            None => parts.push(part.to_string()),
+                        }
+                    }
+                    components.push(parts.join(&delimiters.subcomponent.to_string()));
+                }
+                repetitions.push(components.join(&delimiters.component.to_string()));
+            }
+            if changed {
+                *field = parse_field(&repetitions.join(&delimiters.repetition.to_string()), &delimiters);
+            }
+        }
+    }
+    Ok(revealed)
+}
+
+/// Replace the value a path names within one segment, if `change` returns a new one
+fn update<F>(segment: &mut Segment, path: &TerserPath, change: F) -> Result<(), StoreError>
+where
+    F: Fn(&str) -> Result<Option<String>, StoreError>,
+{
+    let delimiters = Delimiters::default();
+    let index = match crate::terser::field_index(&segment.name, path.field) {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let Some(field) = segment.fields.get_mut(index) else {
+        return Ok(());
+    };
+    let Some(component) = path.component else {
+        if let Some(value) = change(&field.to_hl7(&delimiters))? {
+            *field = parse_field(&value, &delimiters);
+        }
+        return Ok(());
+    };
+    // Components and subcomponents are changed in every repetition of the field
+    let mut changed = false;
+    let mut repetitions = Vec::new();
+    for repetition in field.to_hl7(&delimiters).split(delimiters.repetition) {
+        let mut components: Vec<String> = repetition.split(delimiters.component).map(|c| c.to_string()).collect();
+        if let Some(value) = components.get_mut(component - 1) {
+            match path.subcomponent {
+                None => {
+                    if let Some(new) = change(value)? {
+                        *value = new;
+                        changed = true;
+                    }
+                }
+                Some(subcomponent) => {
+                    let mut parts: Vec<String> = value.split(delimiters.subcomponent).map(|s| s.to_string()).collect();
+                    if let Some(part) = parts.get_mut(subcomponent - 1) {
+                        if let Some(new) = change(part)? {
+                            *part = new;
+                            *value = parts.join(&delimiters.subcomponent.to_string());
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+        repetitions.push(components.join(&delimiters.component.to_string()));
+    }
+    if changed {
+        *field = parse_field(&repetitions.join(&delimiters.repetition.to_string()), &delimiters);
+    }
+    Ok(())
+}
+
+/// The current key, ready to encrypt values with
+struct Encryptor {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl Encryptor {
+    fn new(keys: &dyn KeyProvider) -> Result<Self, StoreError> {
+        let id = keys.current_key_id();
+        if id.is_empty() || id.contains(RESERVED) {
+            return Err(StoreError::EncryptionError(format!("Invalid key ID '{}'", id)));
+        }
+        let key = keys
+            .key(&id)
+            .ok_or_else(|| StoreError::EncryptionError(format!("Unknown key '{}'", id)))?;
+        Ok(Self {
+            id,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        })
+    }
+
+    /// Encrypt a value, binding it to its field so it can't be moved to another
+    fn encrypt(&self, value: &str, aad: &str) -> Result<String, StoreError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: aad.as_bytes() })
+            .map_err(|_| StoreError::EncryptionError(format!("Failed to encrypt {}", aad)))?;
+        Ok(format!("{}{}:{}{}", ENCRYPTED, self.id, hex(&nonce), hex(&ciphertext)))
+    }
+}
+
+fn decrypt(keys: &dyn KeyProvider, value: &str, aad: &str) -> Result<String, StoreError> {
+    let invalid = || StoreError::EncryptionError(format!("Failed to decrypt {}", aad));
+    let (id, data) = value[ENCRYPTED.len()..].split_once(':').ok_or_else(invalid)?;
+    let key = keys
+        .key(id)
+        .ok_or_else(|| StoreError::EncryptionError(format!("Unknown key '{}' for {}", id, aad)))?;
+    let data = unhex(data).filter(|data| data.len() > 12).ok_or_else(invalid)?;
+    let (nonce, ciphertext) = data.split_at(12);
+    let plain = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: aad.as_bytes() })
+        .map_err(|_| invalid())?;
+    String::from_utf8(plain).map_err(|_| invalid())
+}
+
+/// Encrypt a whole text kept outside the archive, such as a journaled message, bound to `context`
+pub fn encrypt_text(keys: &dyn KeyProvider, text: &str, context: &str) -> Result<String, StoreError> {
+    Encryptor::new(keys)?.encrypt(text, context)
+}
+
+/// Decrypt a text `encrypt_text` encrypted with the same `context`, or return it as it is if it isn't encrypted
+pub fn decrypt_text(keys: &dyn KeyProvider, value: &str, context: &str) -> Result<String, StoreError> {
+    match value.starts_with(ENCRYPTED) {
+        true => decrypt(keys, value, context),
+        false => Ok(value.to_string()),
+    }
+}
+
+/// Whether a text was encrypted by `encrypt_text`
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED)
+}
+
+/// Parse a 256-bit key from 64 hex digits
+pub fn parse_key(key: &str) -> Result<[u8; 32], StoreError> {
+    unhex(key.trim())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| StoreError::EncryptionError("Keys must be 64 hex digits".to_string()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// An archive that protects designated fields before they reach the underlying store
+///
+/// Records are protected with a `FieldPolicy` as they're inserted and their
+/// encrypted values decrypted as they're queried, so the database on its own
+/// holds no readable copy. Values that can't be decrypted, e.g. because the
+/// key is gone, are returned encrypted. Metadata such as the patient ID is
+/// cleared when it comes from a protected field; other metadata stays
+/// queryable.
+pub struct ProtectedStore {
+    inner: Arc<dyn MessageStore>,
+    policy: FieldPolicy,
+}
+
+impl ProtectedStore {
+    /// Protect records stored in `inner`, failing if fields are to be encrypted without a usable key
+    pub fn new(inner: Arc<dyn MessageStore>, policy: FieldPolicy) -> Result<Self, StoreError> {
+        if policy.rules.iter().any(|rule| rule.protection == Protection::Encrypt) {
+            let keys = policy
+                .keys
+                .as_deref()
+                .ok_or_else(|| StoreError::EncryptionError("No keys to encrypt archived fields with".to_string()))?;
+            Encryptor::new(keys)?;
+        }
+        Ok(Self { inner, policy })
+    }
+}
+
+impl MessageStore for ProtectedStore {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        self.inner.insert(&self.policy.protect_record(record)?)
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let mut records = self.inner.query(query)?;
+        if self.policy.keys.is_none() {
+            return Ok(records);
+        }
+        for record in &mut records {
+            let charset = charset::detect(&record.raw).unwrap_or_default();
+            let Some(mut message) = charset.decode(&record.raw).ok().and_then(|text| Message::parse(&text).ok()) else {
+                continue;
+            };
+            match self.policy.reveal(&mut message) {
+                Ok(true) => record.raw = charset.encode(&message.to_hl7()),
+                Ok(false) => {}
+                Err(e) => warn!("Returning archived message {:?} still encrypted: {}", record.id, e),
+            }
+        }
+        Ok(records)
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        self.inner.prune(before)
+    }
+
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        self.inner.prune_excess(max_count, max_bytes)
+    }
+
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        self.inner.set_secure_delete(secure_delete)
+    }
+
+    fn vacuum(&self) -> Result<(), StoreError> {
+        self.inner.vacuum()
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        self.inner.check_writable()
+    }
+}
@@ -0,0 +1,270 @@
+use crate::capture::{self, CaptureWriter};
+use crate::charset;
+use crate::mllp::{extract_mllp_message, wrap_in_mllp, MllpError};
+use crate::store::Direction;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+/// One message relayed through the proxy and the response that came back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// When the message arrived from the client
+    pub received_at: DateTime<Utc>,
+    /// When the upstream's response arrived, or the exchange failed
+    pub responded_at: DateTime<Utc>,
+    /// Address of the connecting client
+    pub client: String,
+    /// Address the message was forwarded to
+    pub upstream: String,
+    /// The message exactly as the client framed it
+    pub request: String,
+    /// The upstream's response, if one arrived
+    pub response: Option<String>,
+    /// Why no response was relayed
+    pub error: Option<String>,
+}
+
+/// Relays MLLP traffic to another endpoint, optionally recording every exchange
+///
+/// Frames are passed through byte for byte without being parsed or
+/// acknowledged by the proxy, so both sides see exactly what the other sent.
+/// Each client connection gets its own upstream connection. If the upstream
+/// can't be reached or doesn't answer in time, the client connection is closed
+/// rather than answered, as it would be without the proxy in between.
+///
+/// With `record`, each exchange is appended as a JSON line to
+/// `<directory>/<YYYYMMDD>.jsonl`, named by the UTC day the message arrived.
+/// With `capture`, the raw frames are also written to a capture file that
+/// `capture::CaptureReplay` can send again.
+///
+/// ```ignore
+/// Proxy::new("0.0.0.0:2575", "lab.example.org:2575")
+///     .record("capture")
+///     .run()
+///     .await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    listen: String,
+    forward: String,
+    timeout: Duration,
+    recorder: Option<Arc<Recorder>>,
+    capture: Option<Arc<CaptureWriter>>,
+}
+
+impl Proxy {
+    /// Relay connections accepted on `listen` to `forward`
+    pub fn new<L: ToString, F: ToString>(listen: L, forward: F) -> Self {
+        Self {
+            listen: listen.to_string(),
+            forward: forward.to_string(),
+            timeout: Duration::from_secs(30),
+            recorder: None,
+            capture: None,
+        }
+    }
+
+    /// Record every exchange to JSON lines files in this directory
+    pub fn record<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.recorder = Some(Arc::new(Recorder {
+            directory: directory.into(),
+            lock: Mutex::new(()),
+        }));
+        self
+    }
+
+    /// Write every frame from clients and every response relayed back to a capture file
+    pub fn capture(mut self, capture: Arc<CaptureWriter>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Set how long to wait for connecting to the upstream and for each response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Accept and relay connections until an error occurs binding the listener
+    pub async fn run(&self) -> Result<(), MllpError> {
+        let listener = TcpListener::bind(&self.listen).await?;
+        self.serve(listener).await
+    }
+
+    /// Relay connections accepted on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), MllpError> {
+        if let Some(recorder) = &self.recorder {
+            fs::create_dir_all(&recorder.directory)?;
+        }
+        info!("MLLP proxy listening on {}, forwarding to {}", listener.local_addr()?, self.forward);
+
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            info!("New proxied connection from {}", addr);
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.relay(socket, addr).await {
+                    error!("Error relaying connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Relay one client connection until either side closes it
+    async fn relay(&self, mut client: TcpStream, addr: SocketAddr) -> Result<(), MllpError> {
+        let mut upstream: Option<TcpStream> = None;
+        let mut client_buffer = BytesMut::with_capacity(4096);
+        let mut upstream_buffer = BytesMut::with_capacity(4096);
+
+        loop {
+            if client.read_buf(&mut client_buffer).await? == 0 {
+                info!("Connection closed by {}", addr);
+                return Ok(());
+            }
+
+            while let Some(request) = extract_mllp_message(&mut client_buffer)? {
+                let received_at = Utc::now();
+                self.save_frame(&request, Direction::Inbound, addr).await;
+                let result = self.forward(&mut upstream, &mut upstream_buffer, &request).await;
+                let responded_at = Utc::now();
+
+                let (response, error) = match &result {
+                    Ok(response) => (Some(text(response)), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                self.save(Exchange {
+                    received_at,
+                    responded_at,
+                    client: addr.to_string(),
+                    upstream: self.forward.clone(),
+                    request: text(&request),
+                    response,
+                    error,
+                })
+                .await;
+
+                match result {
+                    Ok(response) => {
+                        client.write_all(&wrap_in_mllp(&response)).await?;
+                        self.save_frame(&response, Direction::Outbound, addr).await;
+                    }
+                    Err(e) => {
+                        warn!("Closing connection from {}: {}", addr, e);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a frame upstream, connecting first if needed, and read the response frame
+    async fn forward(
+        &self,
+        upstream: &mut Option<TcpStream>,
+        buffer: &mut BytesMut,
+        request: &[u8],
+    ) -> Result<Bytes, MllpError> {
+        let exchange = async {
+            if upstream.is_none() {
+                *upstream = Some(TcpStream::connect(&self.forward).await?);
+                buffer.clear();
+            }
+            let stream = upstream.as_mut().expect("connected above");
+            stream.write_all(&wrap_in_mllp(request)).await?;
+
+            loop {
+                if let Some(response) = extract_mllp_message(buffer)? {
+                    return Ok(response);
+                }
+                if stream.read_buf(buffer).await? == 0 {
+                    return Err(MllpError::InvalidFrame(format!(
+                        "Connection to {} closed before a response was received",
+                        self.forward
+                    )));
+                }
+            }
+        };
+
+        let result = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .unwrap_or_else(|_| Err(MllpError::Timeout(format!("No response from {} within {:?}", self.forward, self.timeout))));
+        if result.is_err() {
+            // Start over with a fresh connection rather than pairing a late response with the next message
+            *upstream = None;
+        }
+        result
+    }
+
+    /// Record an exchange on a blocking thread, logging rather than failing on errors
+    async fn save(&self, exchange: Exchange) {
+        let Some(recorder) = self.recorder.clone() else {
+            return;
+        };
+        let saved = tokio::task::spawn_blocking(move || {
+            if let Err(e) = recorder.append(&exchange) {
+                error!("Failed to record exchange in {}: {}", recorder.directory.display(), e);
+            }
+        });
+        if let Err(e) = saved.await {
+            error!("Failed to record exchange: {}", e);
+        }
+    }
+
+    /// Capture a frame on a blocking thread, logging rather than failing on errors
+    async fn save_frame(&self, frame: &[u8], direction: Direction, addr: SocketAddr) {
+        if let Some(capture) = &self.capture {
+            capture::record_in_background(capture, frame, direction, addr).await;
+        }
+    }
+}
+
+/// Appends exchanges to daily JSON lines files; appending blocks
+#[derive(Debug)]
+struct Recorder {
+    directory: PathBuf,
+    // Keeps lines from concurrent connections from interleaving
+    lock: Mutex<()>,
+}
+
+impl Recorder {
+    fn append(&self, exchange: &Exchange) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(exchange)?;
+        line.push('\n');
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(capture_path(&self.directory, exchange.received_at))?
+            .write_all(line.as_bytes())
+    }
+}
+
+/// The capture file exchanges received at `time` are recorded in
+pub fn capture_path(directory: &Path, time: DateTime<Utc>) -> PathBuf {
+    directory.join(format!("{}.jsonl", time.format("%Y%m%d")))
+}
+
+/// Decode a frame in the charset it declares, replacing anything that isn't valid
+fn text(bytes: &[u8]) -> String {
+    let charset = charset::detect(bytes).unwrap_or_default();
+    charset
+        .decode(bytes)
+        .unwrap_or_else(|_| String::from_utf8_lossy(bytes).into_owned())
+}
@@ -0,0 +1,393 @@
+use crate::terser::{self, TerserPath};
+use crate::{HL7Error, Message};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// A structural query over a message, e.g. `OBX[?(@.3.1=='WBC')].5.1`
+///
+/// A query names a segment, optionally narrows which occurrences of it to
+/// look at, and optionally picks a field, component and subcomponent by spec
+/// number:
+///
+/// - `PID.5.1` - the value in every PID segment
+/// - `OBX(2).5` - only the second OBX
+/// - `OBX[*].5` - every OBX (the same as `OBX.5`)
+/// - `OBX[?(@.3.1=='WBC' && @.8!='N')].5.1` - OBX segments matching a filter
+///
+/// Filters compare `@` paths (relative to the segment) with quoted strings or
+/// numbers using `==`, `!=`, `<`, `<=`, `>`, `>=` or `=~` (regex match), and
+/// combine them with `&&`, `||`, `!` and parentheses. A bare `@` path tests that
+/// the value is present. Values that both parse as numbers are compared
+/// numerically. Without a field the whole segment is returned as ER7.
+///
+/// It displays and serializes as the query it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    segment: String,
+    selector: Selector,
+    path: Vec<usize>,
+}
+
+/// Which occurrences of the segment a query looks at
+#[derive(Debug, Clone)]
+enum Selector {
+    All,
+    Repetition(usize),
+    Filter(Filter),
+}
+
+/// A filter condition
+#[derive(Debug, Clone)]
+enum Filter {
+    Present(Vec<usize>),
+    Compare(Operand, Operator, Operand),
+    Matches(Operand, Regex),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Path(Vec<usize>),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl FromStr for Expression {
+    type Err = HL7Error;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            query,
+            chars: query.char_indices().peekable(),
+        };
+        let expression = parser.expression()?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            None => Ok(expression),
+            Some(&(at, _)) => Err(parser.error(at, "unexpected input")),
+        }
+    }
+}
+
+/// Queries are the same if their text is
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Expression {
+    /// Values matched in the message, in segment order; empty values are skipped
+    pub fn select(&self, message: &Message) -> Vec<String> {
+        let count = message.segments.iter().filter(|s| s.name == self.segment).count();
+        let repetitions: Vec<usize> = match &self.selector {
+            Selector::All => (1..=count).collect(),
+            Selector::Repetition(n) => (*n <= count).then_some(*n).into_iter().collect(),
+            Selector::Filter(filter) => (1..=count).filter(|&n| filter.matches(message, &self.segment, n)).collect(),
+        };
+
+        repetitions
+            .into_iter()
+            .filter_map(|repetition| {
+                if self.path.is_empty() {
+                    let delimiters = crate::Delimiters::default();
+                    let segment = message.segments.iter().filter(|s| s.name == self.segment).nth(repetition - 1)?;
+                    return Some(segment.to_hl7(&delimiters));
+                }
+                value(message, &self.segment, repetition, &self.path)
+            })
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    /// Whether the query matches any non-empty value
+    pub fn matches(&self, message: &Message) -> bool {
+        !self.select(message).is_empty()
+    }
+}
+
+/// Evaluate a query against a message, e.g. `query::select(&message, "OBX[?(@.3.1=='WBC')].5.1")`
+pub fn select(message: &Message, query: &str) -> Result<Vec<String>, HL7Error> {
+    Ok(query.parse::<Expression>()?.select(message))
+}
+
+/// The value at a field/component/subcomponent path within one segment occurrence
+fn value(message: &Message, segment: &str, repetition: usize, path: &[usize]) -> Option<String> {
+    let path = TerserPath {
+        segment: segment.to_string(),
+        repetition,
+        field: path[0],
+        component: path.get(1).copied(),
+        subcomponent: path.get(2).copied(),
+    };
+    terser::get_path(message, &path)
+}
+
+impl Filter {
+    fn matches(&self, message: &Message, segment: &str, repetition: usize) -> bool {
+        let resolve = |operand: &Operand| match operand {
+            Operand::Path(path) => value(message, segment, repetition, path).filter(|v| !v.is_empty()),
+            Operand::Literal(literal) => Some(literal.clone()),
+        };
+
+        match self {
+            Filter::Present(path) => value(message, segment, repetition, path).is_some_and(|v| !v.is_empty()),
+            Filter::Compare(left, operator, right) => {
+                let (left, right) = (resolve(left), resolve(right));
+                let ordering = match (&left, &right) {
+                    (Some(l), Some(r)) => match (l.trim().parse::<f64>(), r.trim().parse::<f64>()) {
+                        (Ok(l), Ok(r)) => l.partial_cmp(&r),
+                        _ => Some(l.cmp(r)),
+                    },
+                    // A missing value only equals nothing
+                    _ => None,
+                };
+                match operator {
+                    Operator::Eq => ordering == Some(std::cmp::Ordering::Equal),
+                    Operator::Ne => ordering != Some(std::cmp::Ordering::Equal),
+                    Operator::Lt => ordering == Some(std::cmp::Ordering::Less),
+                    Operator::Le => ordering.is_some_and(|o| o != std::cmp::Ordering::Greater),
+                    Operator::Gt => ordering == Some(std::cmp::Ordering::Greater),
+                    Operator::Ge => ordering.is_some_and(|o| o != std::cmp::Ordering::Less),
+                }
+            }
+            Filter::Matches(operand, regex) => resolve(operand).is_some_and(|v| regex.is_match(&v)),
+            Filter::And(left, right) => {
+                left.matches(message, segment, repetition) && right.matches(message, segment, repetition)
+            }
+            Filter::Or(left, right) => {
+                left.matches(message, segment, repetition) || right.matches(message, segment, repetition)
+            }
+            Filter::Not(inner) => !inner.matches(message, segment, repetition),
+        }
+    }
+}
+
+/// Recursive descent parser for query expressions
+struct Parser<'a> {
+    query: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn error(&self, at: usize, problem: &str) -> HL7Error {
+        HL7Error::ParseError(format!("Invalid query '{}' at position {}: {}", self.query, at, problem))
+    }
+
+    fn position(&mut self) -> usize {
+        self.chars.peek().map_or(self.query.len(), |&(at, _)| at)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|&(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    /// Consume `expected` if it comes next, after any whitespace
+    fn eat(&mut self, expected: &str) -> bool {
+        self.skip_whitespace();
+        let at = self.position();
+        if self.query[at..].starts_with(expected) {
+            for _ in expected.chars() {
+                self.chars.next();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), HL7Error> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            let at = self.position();
+            Err(self.error(at, &format!("expected '{}'", expected)))
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, HL7Error> {
+        let at = self.position();
+        let mut digits = String::new();
+        while let Some((_, c)) = self.chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| self.error(at, "expected a number from 1"))
+    }
+
+    /// `.field[.component[.subcomponent]]`
+    fn path(&mut self) -> Result<Vec<usize>, HL7Error> {
+        let mut path = Vec::new();
+        while self.chars.next_if(|&(_, c)| c == '.').is_some() {
+            if path.len() == 3 {
+                let at = self.position();
+                return Err(self.error(at, "paths have at most a field, component and subcomponent"));
+            }
+            path.push(self.number()?);
+        }
+        Ok(path)
+    }
+
+    fn expression(&mut self) -> Result<Expression, HL7Error> {
+        self.skip_whitespace();
+        let at = self.position();
+        let segment: String = (0..3).filter_map(|_| self.chars.next_if(|&(_, c)| c.is_ascii_alphanumeric())).map(|(_, c)| c).collect();
+        if segment.len() != 3 {
+            return Err(self.error(at, "expected a segment name"));
+        }
+
+        let selector = if self.eat("(") {
+            let n = self.number()?;
+            self.expect(")")?;
+            Selector::Repetition(n)
+        } else if self.eat("[*]") {
+            Selector::All
+        } else if self.eat("[?(") {
+            let filter = self.or()?;
+            self.expect(")")?;
+            self.expect("]")?;
+            Selector::Filter(filter)
+        } else {
+            Selector::All
+        };
+
+        let path = self.path()?;
+        Ok(Expression {
+            source: self.query.trim().to_string(),
+            segment,
+            selector,
+            path,
+        })
+    }
+
+    fn or(&mut self) -> Result<Filter, HL7Error> {
+        let mut filter = self.and()?;
+        while self.eat("||") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, HL7Error> {
+        let mut filter = self.unary()?;
+        while self.eat("&&") {
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, HL7Error> {
+        if self.eat("!") {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let filter = self.or()?;
+            self.expect(")")?;
+            return Ok(filter);
+        }
+
+        let left = self.operand()?;
+        if self.eat("=~") {
+            let at = self.position();
+            let Operand::Literal(pattern) = self.operand()? else {
+                return Err(self.error(at, "expected a quoted regex"));
+            };
+            let regex = Regex::new(&pattern).map_err(|e| self.error(at, &e.to_string()))?;
+            return Ok(Filter::Matches(left, regex));
+        }
+
+        let operator = [
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            ("<=", Operator::Le),
+            (">=", Operator::Ge),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ]
+        .into_iter()
+        .find(|(symbol, _)| self.eat(symbol))
+        .map(|(_, operator)| operator);
+
+        match (operator, left) {
+            (Some(operator), left) => Ok(Filter::Compare(left, operator, self.operand()?)),
+            (None, Operand::Path(path)) => Ok(Filter::Present(path)),
+            (None, Operand::Literal(_)) => {
+                let at = self.position();
+                Err(self.error(at, "expected a comparison"))
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, HL7Error> {
+        self.skip_whitespace();
+        let at = self.position();
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('@') => {
+                self.chars.next();
+                let path = self.path()?;
+                if path.is_empty() {
+                    return Err(self.error(at, "expected a field after '@'"));
+                }
+                Ok(Operand::Path(path))
+            }
+            Some(quote @ ('\'' | '"')) => {
+                self.chars.next();
+                let mut literal = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '\\')) => match self.chars.next() {
+                            Some((_, c)) => literal.push(c),
+                            None => return Err(self.error(at, "unterminated string")),
+                        },
+                        Some((_, c)) if c == quote => return Ok(Operand::Literal(literal)),
+                        Some((_, c)) => literal.push(c),
+                        None => return Err(self.error(at, "unterminated string")),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' => {
+                let mut literal = String::new();
+                while let Some((_, c)) = self.chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.' || c == '-') {
+                    literal.push(c);
+                }
+                Ok(Operand::Literal(literal))
+            }
+            _ => Err(self.error(at, "expected '@', a quoted string or a number")),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::state::StateBackend;
+use crate::{terser, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Buckets kept before drained ones are dropped
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Buckets looked at for eviction per lookup, more than the one a lookup can add
+const EVICT_PER_ADMIT: usize = 2;
+
+/// What traffic shares a bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateKey {
+    /// Each source IP address
+    #[default]
+    Peer,
+    /// Each sending application (MSH-3)
+    Sender,
+    /// Everything the listener receives
+    Listener,
+}
+
+/// What happens to messages over the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverLimit {
+    /// Hold the message, and so its ACK, until the bucket has room
+    #[default]
+    Delay,
+    /// Answer with an AR NACK without processing the message
+    Reject,
+}
+
+/// A leaky-bucket limit on how fast messages are accepted
+///
+/// In config files, e.g. `rate_limit = { per_second = 20, burst = 100, by = "sender", exceeded = "reject" }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Sustained messages per second
+    pub per_second: f64,
+    /// Messages accepted in a burst before the rate applies
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    #[serde(default)]
+    pub by: RateKey,
+    #[serde(default)]
+    pub exceeded: OverLimit,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+impl RateLimit {
+    /// Allow `per_second` messages per second from each source IP, delaying the rest
+    pub fn new(per_second: f64) -> Self {
+        Self {
+            per_second,
+            burst: default_burst(),
+            by: RateKey::default(),
+            exceeded: OverLimit::default(),
+        }
+    }
+
+    /// Allow this many messages at once before the rate applies
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Set what traffic shares a bucket
+    pub fn by(mut self, key: RateKey) -> Self {
+        self.by = key;
+        self
+    }
+
+    /// Set what happens to messages over the limit
+    pub fn when_exceeded(mut self, action: OverLimit) -> Self {
+        self.exceeded = action;
+        self
+    }
+}
+
+/// Whether a message may be processed now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Now,
+    /// After waiting this long; the message already holds its place in the bucket
+    After(Duration),
+    Rejected,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    level: f64,
+    updated: Instant,
+}
+
+/// Buckets by key, dropping drained ones a few at a time once there are many
+#[derive(Debug)]
+pub(crate) struct Buckets<B> {
+    by_key: HashMap<String, B>,
+    // Every key once, oldest first, visited in turn for eviction
+    order: VecDeque<String>,
+}
+
+impl<B> Default for Buckets<B> {
+    fn default() -> Self {
+        Self {
+            by_key: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<B> Buckets<B> {
+    /// The bucket for `key`, made with `new` if there isn't one
+    ///
+    /// Past `MAX_IDLE_BUCKETS`, each call first looks at the oldest few and
+    /// drops those `drained` says are empty, so no call scans them all.
+    pub(crate) fn get(&mut self, key: &str, drained: impl Fn(&B) -> bool, new: impl FnOnce() -> B) -> &mut B {
+        if self.by_key.len() > MAX_IDLE_BUCKETS {
+            for _ in 0..EVICT_PER_ADMIT {
+                let Some(oldest) = self.order.pop_front() else { break };
+                match self.by_key.get(&oldest) {
+                    Some(bucket) if drained(bucket) => {
+                        self.by_key.remove(&oldest);
+                    }
+                    _ => self.order.push_back(oldest),
+                }
+            }
+        }
+        match self.by_key.entry(key.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                self.order.push_back(entry.key().clone());
+                entry.insert(new())
+            }
+        }
+    }
+}
+
+/// Applies a `RateLimit`, keeping one bucket per peer, sender or listener
+///
+/// Buckets are kept in this process unless `with_state` shares them between
+/// instances, so replicas behind a load balancer enforce one limit together.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets<Bucket>>,
+    shared: Option<(Arc<dyn StateBackend>, String)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+            shared: None,
+        }
+    }
+
+    /// Keep buckets in `state`, under keys starting with `scope`, e.g. the listener's address
+    ///
+    /// If the state can't be reached, the buckets in this process are used meanwhile.
+    pub fn with_state<S: ToString>(mut self, state: Arc<dyn StateBackend>, scope: S) -> Self {
+        self.shared = Some((state, scope.to_string()));
+        self
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// The bucket a message from `peer` counts against
+    pub fn key(&self, message: &Message, peer: SocketAddr) -> String {
+        match self.limit.by {
+            RateKey::Peer => peer.ip().to_string(),
+            RateKey::Sender => terser::get(message, "MSH-3.1").unwrap_or_default(),
+            RateKey::Listener => String::new(),
+        }
+    }
+
+    /// Count a message against the bucket for `key`
+    pub fn admit(&self, key: &str) -> Admission {
+        if let Some((state, scope)) = &self.shared {
+            match state.admit(&format!("rate:{}:{}", scope, key), &self.limit) {
+                Ok(admission) => return admission,
+                Err(e) => warn!("Can't reach shared rate limit state, limiting this instance alone: {}", e),
+            }
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let rate = self.limit.per_second;
+        let bucket = buckets.get(
+            key,
+            |bucket| bucket.level - now.duration_since(bucket.updated).as_secs_f64() * rate <= 0.0,
+            || Bucket { level: 0.0, updated: now },
+        );
+        let leaked = now.duration_since(bucket.updated).as_secs_f64() * self.limit.per_second;
+        bucket.level = (bucket.level - leaked).max(0.0);
+        bucket.updated = now;
+
+        let capacity = f64::from(self.limit.burst.max(1));
+        if bucket.level + 1.0 <= capacity {
+            bucket.level += 1.0;
+            return Admission::Now;
+        }
+        match self.limit.exceeded {
+            OverLimit::Reject => Admission::Rejected,
+            OverLimit::Delay => {
+                // Wait until the messages ahead of this one have leaked out
+                let wait = (bucket.level + 1.0 - capacity) / self.limit.per_second;
+                match Duration::try_from_secs_f64(wait) {
+                    Ok(wait) => {
+                        bucket.level += 1.0;
+                        Admission::After(wait)
+                    }
+                    // A rate of zero never admits anything over the burst
+                    Err(_) => Admission::Rejected,
+                }
+            }
+        }
+    }
+}
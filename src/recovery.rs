@@ -0,0 +1,353 @@
+use crate::clock::Sequences;
+use crate::protect::{self, KeyProvider};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Records written between rewrites of the journal down to what's still live
+const COMPACT_EVERY: u64 = 10_000;
+
+/// What journaled messages are bound to when they're encrypted
+const ENCRYPTION_CONTEXT: &str = "journal";
+
+/// A frame that was received but not yet fully dealt with when the journal was last written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InflightFrame {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub received_at: DateTime<Utc>,
+    /// The decoded message
+    pub text: String,
+    /// Whether the sender was sent an ACK for it, so won't send it again
+    pub acked: bool,
+}
+
+/// What a restarted server does with frames it never answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unanswered {
+    /// Record them as failed and leave them to the sender, which resends what it wasn't ACKed
+    #[default]
+    AwaitResend,
+    /// Run them through the handler anyway; pair with `Dedup` for senders that also resend
+    Reprocess,
+}
+
+/// One line of the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Received(InflightFrame),
+    Acked { id: u64 },
+    Finished { id: u64 },
+    Cursor { name: String, position: u64 },
+    Sequence { name: String, reserved: u64 },
+}
+
+#[derive(Debug, Default)]
+struct State {
+    inflight: BTreeMap<u64, InflightFrame>,
+    cursors: BTreeMap<String, u64>,
+    /// The first value of each sequence not yet handed out before a restart
+    sequences: BTreeMap<String, u64>,
+    next_id: u64,
+    written: u64,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Received(frame) => {
+                self.next_id = self.next_id.max(frame.id + 1);
+                self.inflight.insert(frame.id, frame);
+            }
+            Record::Acked { id } => {
+                if let Some(frame) = self.inflight.get_mut(&id) {
+                    frame.acked = true;
+                }
+            }
+            Record::Finished { id } => {
+                self.inflight.remove(&id);
+            }
+            Record::Cursor { name, position } => {
+                self.cursors.insert(name, position);
+            }
+            Record::Sequence { name, reserved } => {
+                self.sequences.insert(name, reserved);
+            }
+        }
+    }
+
+    /// The records that rebuild this state
+    fn snapshot(&self) -> Vec<Record> {
+        let frames = self.inflight.values().cloned().map(Record::Received);
+        let cursors = self.cursors.iter().map(|(name, &position)| Record::Cursor { name: name.clone(), position });
+        let sequences = self.sequences.iter().map(|(name, &reserved)| Record::Sequence { name: name.clone(), reserved });
+        frames.chain(cursors).chain(sequences).collect()
+    }
+}
+
+/// In-flight state kept on disk so a server or sender can pick up where it left off after a crash
+///
+/// A server given a journal with `MllpServer::with_recovery` writes each frame
+/// down before handling it, marks it once an ACK has gone out, and drops it
+/// once both the handler and the response are done. Frames still in the
+/// journal when the server starts again are the ones a crash interrupted:
+/// those already ACKed are handled again, since the sender won't resend them,
+/// and the rest are handled as `Unanswered` says. Senders keep their position
+/// in a file or feed with `cursor` and `set_cursor`, and `clock::ReservedIds`
+/// hands out control IDs from it that carry on counting across restarts.
+///
+/// The journal is a JSON lines file, rewritten to just what's still live when
+/// it's opened and every 10,000 records. Each record is written before the
+/// call returns, which survives the process crashing; `with_sync` also waits
+/// for the disk, to survive the machine going down, at a cost in throughput.
+/// Calls block on the file, so call them from blocking threads in async code.
+///
+/// Messages are journaled whole, so when the archive encrypts fields, give
+/// the journal the same keys with `with_keys` to encrypt the messages in it,
+/// and the archive's retention with `with_max_age`.
+///
+/// ```ignore
+/// let journal = Arc::new(StateJournal::open("state/listener.journal")?.with_keys(keys)?);
+/// let server = MllpServer::new("0.0.0.0:2575", handler).with_recovery(journal, Unanswered::AwaitResend);
+/// ```
+pub struct StateJournal {
+    path: PathBuf,
+    sync: bool,
+    /// What messages are encrypted with, if they are
+    keys: Option<Arc<dyn KeyProvider>>,
+    /// The file, and the state it describes
+    inner: Mutex<(File, State)>,
+    /// Frames that were in flight when the journal was opened
+    left_over: Mutex<Vec<InflightFrame>>,
+}
+
+impl std::fmt::Debug for StateJournal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateJournal")
+            .field("path", &self.path)
+            .field("sync", &self.sync)
+            .field("encrypted", &self.keys.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl StateJournal {
+    /// Open a journal, creating it if it doesn't exist
+    ///
+    /// A last line cut short by a crash is ignored.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut state = State::default();
+        if path.exists() {
+            let lines = BufReader::new(File::open(&path)?).lines().collect::<Result<Vec<_>, _>>()?;
+            let last = lines.len();
+            for (index, line) in lines.into_iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(record) => state.apply(record),
+                    Err(e) if index + 1 == last => warn!("Ignoring the incomplete last line of {}: {}", path.display(), e),
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} line {} is not a valid record: {}", path.display(), index + 1, e),
+                        ))
+                    }
+                }
+            }
+        }
+        let left_over = state.inflight.values().cloned().collect();
+        let file = rewrite(&path, &state, None)?;
+        Ok(Self {
+            path,
+            sync: false,
+            keys: None,
+            inner: Mutex::new((file, state)),
+            left_over: Mutex::new(left_over),
+        })
+    }
+
+    /// Wait for each record to reach the disk before returning
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Encrypt journaled messages with these keys, and decrypt those already in the journal
+    ///
+    /// The journal is rewritten with every message in it encrypted, including
+    /// any written in the clear before.
+    pub fn with_keys(mut self, keys: Arc<dyn KeyProvider>) -> io::Result<Self> {
+        let decrypt = |frame: &mut InflightFrame| -> io::Result<()> {
+            frame.text = protect::decrypt_text(keys.as_ref(), &frame.text, ENCRYPTION_CONTEXT)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("frame {}: {}", frame.id, e)))?;
+            Ok(())
+        };
+        {
+            let inner = self.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+            inner.1.inflight.values_mut().try_for_each(decrypt)?;
+            self.left_over.get_mut().unwrap_or_else(|e| e.into_inner()).iter_mut().try_for_each(decrypt)?;
+            inner.0 = rewrite(&self.path, &inner.1, Some(keys.as_ref()))?;
+        }
+        self.keys = Some(keys);
+        Ok(self)
+    }
+
+    /// Drop frames received longer ago than this rather than recover them, e.g. the archive's retention
+    pub fn with_max_age(mut self, max_age: Duration) -> io::Result<Self> {
+        let Some(cutoff) = chrono::Duration::from_std(max_age).ok().and_then(|age| Utc::now().checked_sub_signed(age)) else {
+            return Ok(self);
+        };
+        let keys = self.keys.clone();
+        let inner = self.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+        let before = inner.1.inflight.len();
+        inner.1.inflight.retain(|_, frame| frame.received_at >= cutoff);
+        self.left_over.get_mut().unwrap_or_else(|e| e.into_inner()).retain(|frame| frame.received_at >= cutoff);
+        let dropped = before - inner.1.inflight.len();
+        if dropped > 0 {
+            warn!("Dropping {} frames older than {:?} from {}", dropped, max_age, self.path.display());
+            inner.0 = rewrite(&self.path, &inner.1, keys.as_deref())?;
+        }
+        Ok(self)
+    }
+
+    /// The file the journal is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take the frames that were in flight when the journal was opened, leaving none for the next call
+    ///
+    /// Frames encrypted by an earlier run are left in the journal until it's given their keys.
+    pub fn take_unfinished(&self) -> Vec<InflightFrame> {
+        let mut frames = std::mem::take(&mut *self.left_over.lock().unwrap_or_else(|e| e.into_inner()));
+        frames.retain(|frame| {
+            let readable = !protect::is_encrypted(&frame.text);
+            if !readable {
+                error!("Frame {} in {} is encrypted and the journal has no keys", frame.id, self.path.display());
+            }
+            readable
+        });
+        frames
+    }
+
+    /// Frames received and not yet finished, including any from before the journal was opened
+    pub fn inflight(&self) -> Vec<InflightFrame> {
+        self.lock().1.inflight.values().cloned().collect()
+    }
+
+    /// Write a frame down before handling it, returning its ID in the journal
+    pub fn received(&self, peer: SocketAddr, text: &str) -> io::Result<u64> {
+        let mut inner = self.lock();
+        let frame = InflightFrame {
+            id: inner.1.next_id,
+            peer,
+            received_at: Utc::now(),
+            text: text.to_string(),
+            acked: false,
+        };
+        let id = frame.id;
+        self.write(&mut inner, Record::Received(frame))?;
+        Ok(id)
+    }
+
+    /// Note that a frame's ACK was sent
+    pub fn acked(&self, id: u64) -> io::Result<()> {
+        let mut inner = self.lock();
+        self.write(&mut inner, Record::Acked { id })
+    }
+
+    /// Drop a frame that's been handled and answered
+    pub fn finished(&self, id: u64) -> io::Result<()> {
+        let mut inner = self.lock();
+        self.write(&mut inner, Record::Finished { id })
+    }
+
+    /// A sender's saved position, e.g. how many messages of a file were accepted
+    pub fn cursor(&self, name: &str) -> Option<u64> {
+        self.lock().1.cursors.get(name).copied()
+    }
+
+    pub fn set_cursor(&self, name: &str, position: u64) -> io::Result<()> {
+        let mut inner = self.lock();
+        self.write(&mut inner, Record::Cursor { name: name.to_string(), position })
+    }
+
+    /// Reserve `count` values of a named sequence, returning the first
+    ///
+    /// Values reserved before a restart are never handed out again, even if
+    /// they weren't used.
+    pub fn reserve(&self, name: &str, count: u64) -> io::Result<u64> {
+        let mut inner = self.lock();
+        let first = inner.1.sequences.get(name).copied().unwrap_or(1);
+        self.write(&mut inner, Record::Sequence { name: name.to_string(), reserved: first + count })?;
+        Ok(first)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (File, State)> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Append a record and apply it, rewriting the file once enough have built up
+    fn write(&self, inner: &mut (File, State), record: Record) -> io::Result<()> {
+        let (file, state) = inner;
+        file.write_all(line(&record, self.keys.as_deref())?.as_bytes())?;
+        if self.sync {
+            file.sync_data()?;
+        }
+        state.apply(record);
+        state.written += 1;
+        if state.written >= COMPACT_EVERY {
+            *file = rewrite(&self.path, state, self.keys.as_deref())?;
+            state.written = 0;
+        }
+        Ok(())
+    }
+}
+
+/// A record as a line of the journal, with the message encrypted if there are keys
+fn line(record: &Record, keys: Option<&dyn KeyProvider>) -> io::Result<String> {
+    let mut line = match (record, keys) {
+        (Record::Received(frame), Some(keys)) => {
+            let text = protect::encrypt_text(keys, &frame.text, ENCRYPTION_CONTEXT).map_err(io::Error::other)?;
+            serde_json::to_string(&Record::Received(InflightFrame { text, ..frame.clone() }))?
+        }
+        _ => serde_json::to_string(record)?,
+    };
+    line.push('\n');
+    Ok(line)
+}
+
+/// Replace the journal with the records of `state`, returning it open for appending
+fn rewrite(path: &Path, state: &State, keys: Option<&dyn KeyProvider>) -> io::Result<File> {
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    for record in state.snapshot() {
+        file.write_all(line(&record, keys)?.as_bytes())?;
+    }
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Sequences kept in the journal, so they carry on from where they got to before a restart
+impl Sequences for StateJournal {
+    type Error = io::Error;
+
+    fn reserve(&self, name: &str, count: u64) -> io::Result<u64> {
+        StateJournal::reserve(self, name, count)
+    }
+}
@@ -0,0 +1,101 @@
+use std::error::Error;
+use std::net::SocketAddr;
+
+/// Where an error happened and which message it concerns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// What was being done: "connection", "parse" or "handler"
+    pub stage: &'static str,
+    /// The connection the message arrived on
+    pub peer: Option<SocketAddr>,
+    /// MSH-10 of the message, if it parsed
+    pub control_id: Option<String>,
+    /// MSH-9 of the message, if it parsed
+    pub message_type: Option<String>,
+}
+
+impl ErrorContext {
+    pub fn new(stage: &'static str) -> Self {
+        Self { stage, ..Self::default() }
+    }
+
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Identify the message by its control ID and type
+    pub fn with_message(mut self, control_id: &str, message_type: &str) -> Self {
+        self.control_id = Some(control_id.to_string());
+        self.message_type = Some(message_type.to_string());
+        self
+    }
+}
+
+/// Somewhere errors are sent besides the logs, e.g. an error tracker
+///
+/// Servers report connections that fail, messages that don't parse and
+/// handler errors. Rejections (AR) are an expected outcome and aren't
+/// reported. Any closure taking the error and its context can be used.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, error: &(dyn Error + 'static), context: &ErrorContext);
+}
+
+impl<F> ErrorReporter for F
+where
+    F: Fn(&(dyn Error + 'static), &ErrorContext) + Send + Sync,
+{
+    fn report(&self, error: &(dyn Error + 'static), context: &ErrorContext) {
+        self(error, context)
+    }
+}
+
+/// Reports errors to Sentry, tagged with their context
+///
+/// Requires the `sentry` feature. Events are sent until the reporter is dropped.
+///
+/// ```ignore
+/// let reporter = Arc::new(SentryReporter::init("https://key@o0.ingest.sentry.io/0", Some("production"), 1.0)?);
+/// let server = MllpServer::new("0.0.0.0:2575", handler).with_error_reporter(reporter);
+/// ```
+#[cfg(feature = "sentry")]
+pub struct SentryReporter {
+    _guard: sentry::ClientInitGuard,
+}
+
+#[cfg(feature = "sentry")]
+impl SentryReporter {
+    /// Start reporting to the project at `dsn`; `sample_rate` is the fraction of errors sent
+    pub fn init(dsn: &str, environment: Option<&str>, sample_rate: f32) -> Result<Self, String> {
+        let dsn = dsn.parse::<sentry::types::Dsn>().map_err(|e| format!("Invalid Sentry DSN: {}", e))?;
+        let guard = sentry::init(sentry::ClientOptions {
+            dsn: Some(dsn),
+            release: sentry::release_name!(),
+            environment: environment.map(|e| e.to_string().into()),
+            sample_rate,
+            ..Default::default()
+        });
+        Ok(Self { _guard: guard })
+    }
+}
+
+#[cfg(feature = "sentry")]
+impl ErrorReporter for SentryReporter {
+    fn report(&self, error: &(dyn Error + 'static), context: &ErrorContext) {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("stage", context.stage);
+                if let Some(peer) = context.peer {
+                    scope.set_tag("peer", peer);
+                }
+                if let Some(control_id) = &context.control_id {
+                    scope.set_tag("control_id", control_id);
+                }
+                if let Some(message_type) = &context.message_type {
+                    scope.set_tag("message_type", message_type);
+                }
+            },
+            || sentry::capture_error(error),
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::events::{self, ClinicalEvent, ObservationResult};
+use crate::mllp::MessageHandler;
+use crate::oru::ObservationValue;
+use crate::{HL7Error, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Where a result stands, from the OBX-11 codes of HL7 table 0085
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultStatus {
+    /// P, S, R or I: not yet verified, and replaced by what comes next
+    Preliminary,
+    /// F, or U made final without resending the value
+    Final,
+    /// C: a final result was changed
+    Corrected,
+    /// X, D, W or N: cancelled, deleted, for the wrong patient or not done
+    Cancelled,
+}
+
+impl ResultStatus {
+    /// The status an OBX-11 code stands for; unknown codes count as preliminary
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "F" | "U" => ResultStatus::Final,
+            "C" => ResultStatus::Corrected,
+            "X" | "D" | "W" | "N" => ResultStatus::Cancelled,
+            _ => ResultStatus::Preliminary,
+        }
+    }
+
+    /// Whether the value can be relied on rather than waiting for another
+    pub fn is_authoritative(self) -> bool {
+        matches!(self, ResultStatus::Final | ResultStatus::Corrected)
+    }
+}
+
+/// What identifies one observation across messages: the patient, the order's
+/// filler number (OBR-3, else the placer number in OBR-2), OBX-3 and OBX-4
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResultKey {
+    pub patient_id: String,
+    pub order_number: Option<String>,
+    pub code: String,
+    pub system: String,
+    pub sub_id: Option<String>,
+}
+
+impl ResultKey {
+    pub fn of(result: &ObservationResult) -> Self {
+        Self {
+            patient_id: result.patient.patient_id.clone(),
+            order_number: result.order_number.clone(),
+            code: result.code.code.clone(),
+            system: result.code.system.clone(),
+            sub_id: result.sub_id.clone(),
+        }
+    }
+}
+
+/// An observation as it stands after every message seen about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedResult {
+    /// The latest result that counts: a preliminary one never replaces a final one
+    pub result: ObservationResult,
+    pub status: ResultStatus,
+    /// Whether a final result was later changed
+    pub corrected: bool,
+    /// OBX-11 of each message about the observation, oldest first
+    pub history: Vec<String>,
+}
+
+/// How a message changed what's known about an observation
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultChange {
+    /// The first report of the observation
+    New,
+    /// A preliminary result replaced by a newer preliminary one
+    Updated,
+    /// A preliminary or cancelled result made final
+    Finalized,
+    /// A final result replaced by a corrected or different final one
+    Corrected { previous: Option<ObservationValue> },
+    /// The result was cancelled or deleted
+    Cancelled,
+    /// A resend, or a preliminary result arriving after the final one
+    Unchanged,
+}
+
+/// What one OBX did to its observation
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultUpdate {
+    pub key: ResultKey,
+    pub change: ResultChange,
+    /// The observation after the change
+    pub current: TrackedResult,
+}
+
+/// Follows observations through preliminary, final, corrected and cancelled
+/// results, so each is counted once at its latest authoritative value
+///
+/// Each OBX of an ORU^R01 updates the observation its `ResultKey` names. A
+/// preliminary result replaces an earlier preliminary one, but never a final
+/// one that's already arrived. A final result with a different value from the
+/// final one before, or a C, is a correction; U makes the preliminary value
+/// final. Resends change nothing. The `capacity` most recently updated
+/// observations are kept, so one still being reported on outlasts older ones.
+///
+/// ```
+/// use rust_hl7::results::{ResultChange, ResultStatus, ResultTracker};
+/// use rust_hl7::Message;
+///
+/// let result = |value: &str, status: &str| {
+///     Message::parse(&format!(
+///         "MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rPID|1||12345\rOBR|1||F42|GLU\rOBX|1|NM|2345-7^Glucose^LN||{}|mg/dL|||||{}",
+///         value, status
+///     ))
+///     .unwrap()
+/// };
+/// let tracker = ResultTracker::new(10_000);
+/// assert_eq!(tracker.track(&result("98", "P")).unwrap()[0].change, ResultChange::New);
+/// assert_eq!(tracker.track(&result("101", "F")).unwrap()[0].change, ResultChange::Finalized);
+/// let update = &tracker.track(&result("97", "P")).unwrap()[0];
+/// assert_eq!(update.change, ResultChange::Unchanged);
+/// assert_eq!(update.current.status, ResultStatus::Final);
+/// ```
+#[derive(Debug)]
+pub struct ResultTracker {
+    capacity: usize,
+    results: Mutex<Results>,
+}
+
+/// The observations being tracked, and the order they were last updated in
+#[derive(Debug, Default)]
+struct Results {
+    /// Each observation with the number of its last update
+    tracked: HashMap<ResultKey, (TrackedResult, u64)>,
+    /// Keys by update number, oldest first; an entry is stale once its key is updated again
+    order: VecDeque<(ResultKey, u64)>,
+    next: u64,
+}
+
+impl ResultTracker {
+    /// Remember the `capacity` most recently updated observations
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            results: Mutex::new(Results::default()),
+        }
+    }
+
+    /// Update the observations a message reports, returning what happened to each
+    pub fn track(&self, message: &Message) -> Result<Vec<ResultUpdate>, HL7Error> {
+        let mut guard = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        let Results { tracked, order, next } = &mut *guard;
+        let mut updates = Vec::new();
+        for event in events::events(message)? {
+            let ClinicalEvent::ObservationResulted(result) = event else {
+                continue;
+            };
+            let key = ResultKey::of(&result);
+            let (change, current) = match tracked.remove(&key) {
+                Some((previous, _)) => apply(previous, result),
+                None => {
+                    let status = ResultStatus::from_code(&result.status);
+                    let change = match status {
+                        ResultStatus::Cancelled => ResultChange::Cancelled,
+                        _ => ResultChange::New,
+                    };
+                    let history = vec![result.status.clone()];
+                    (change, TrackedResult { result, status, corrected: false, history })
+                }
+            };
+            *next += 1;
+            tracked.insert(key.clone(), (current.clone(), *next));
+            order.push_back((key.clone(), *next));
+            updates.push(ResultUpdate { key, change, current });
+        }
+        while tracked.len() > self.capacity {
+            let Some((oldest, update)) = order.pop_front() else {
+                break;
+            };
+            if tracked.get(&oldest).is_some_and(|(_, last)| *last == update) {
+                tracked.remove(&oldest);
+            }
+        }
+        // Drop stale entries once they outnumber the live ones
+        if order.len() > 2 * tracked.len().max(self.capacity) {
+            order.retain(|(key, update)| tracked.get(key).is_some_and(|(_, last)| last == update));
+        }
+        Ok(updates)
+    }
+
+    /// The observation a key names, as it stands
+    pub fn get(&self, key: &ResultKey) -> Option<TrackedResult> {
+        self.results.lock().unwrap_or_else(|e| e.into_inner()).tracked.get(key).map(|(tracked, _)| tracked.clone())
+    }
+
+    /// Every observation of an order, by its filler or placer number
+    pub fn order(&self, order_number: &str) -> Vec<TrackedResult> {
+        let guard = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        let mut results: Vec<TrackedResult> = guard
+            .tracked
+            .iter()
+            .filter(|(key, _)| key.order_number.as_deref() == Some(order_number))
+            .map(|(_, (tracked, _))| tracked.clone())
+            .collect();
+        results.sort_by(|a, b| (&a.result.code.code, &a.result.sub_id).cmp(&(&b.result.code.code, &b.result.sub_id)));
+        results
+    }
+
+    /// A handler for `MllpServer` passing each update to `on_update`, then returning the message
+    pub fn handler<F>(self: &Arc<Self>, on_update: F) -> MessageHandler
+    where
+        F: Fn(ResultUpdate) + Send + Sync + 'static,
+    {
+        let tracker = self.clone();
+        Arc::new(move |message: Message| {
+            for update in tracker.track(&message)? {
+                on_update(update);
+            }
+            Ok(message)
+        })
+    }
+}
+
+/// Apply a new report to an observation already seen
+fn apply(mut tracked: TrackedResult, mut result: ObservationResult) -> (ResultChange, TrackedResult) {
+    tracked.history.push(result.status.clone());
+    let status = ResultStatus::from_code(&result.status);
+    // U makes the value already sent final without sending it again
+    if result.status == "U" && result.value.is_none() {
+        result.value = tracked.result.value.clone();
+        result.units = tracked.result.units.clone();
+    }
+    let same = result.value == tracked.result.value && result.units == tracked.result.units;
+    let change = match (tracked.status, status) {
+        (ResultStatus::Cancelled, ResultStatus::Cancelled) => ResultChange::Unchanged,
+        (_, ResultStatus::Cancelled) => ResultChange::Cancelled,
+        (ResultStatus::Final | ResultStatus::Corrected, ResultStatus::Preliminary) => ResultChange::Unchanged,
+        (ResultStatus::Cancelled, ResultStatus::Preliminary) => ResultChange::Unchanged,
+        (ResultStatus::Preliminary, ResultStatus::Preliminary) if same => ResultChange::Unchanged,
+        (ResultStatus::Preliminary, ResultStatus::Preliminary) => ResultChange::Updated,
+        (ResultStatus::Preliminary | ResultStatus::Cancelled, ResultStatus::Final) => ResultChange::Finalized,
+        (ResultStatus::Preliminary | ResultStatus::Cancelled, ResultStatus::Corrected) => ResultChange::Finalized,
+        (ResultStatus::Final, ResultStatus::Corrected) => ResultChange::Corrected {
+            previous: tracked.result.value.clone(),
+        },
+        (ResultStatus::Final | ResultStatus::Corrected, _) if same => ResultChange::Unchanged,
+        (ResultStatus::Final | ResultStatus::Corrected, _) => ResultChange::Corrected {
+            previous: tracked.result.value.clone(),
+        },
+    };
+    if change != ResultChange::Unchanged {
+        tracked.corrected |= matches!(change, ResultChange::Corrected { .. });
+        // A correction stays marked as one when it's resent as final
+        tracked.status = match (tracked.corrected, status) {
+            (true, ResultStatus::Final) => ResultStatus::Corrected,
+            _ => status,
+        };
+        tracked.result = result;
+    }
+    (change, tracked)
+}
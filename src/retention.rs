@@ -0,0 +1,242 @@
+use crate::store::{MessageStore, StoreError};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Errors that can occur deleting old data
+#[derive(Debug, Error)]
+pub enum RetentionError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Store error: {0}")]
+    StoreError(#[from] StoreError),
+}
+
+/// How much of a store to keep; limits that aren't set don't apply
+///
+/// The oldest entries go first, whichever limit they're over.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Delete entries older than this many days
+    pub max_age_days: Option<u64>,
+    /// Keep at most this many entries
+    pub max_count: Option<usize>,
+    /// Keep at most this many bytes
+    pub max_bytes: Option<u64>,
+    /// Overwrite deleted data so it can't be recovered from the disk
+    pub secure_delete: bool,
+}
+
+impl RetentionPolicy {
+    /// Keep entries for this many days
+    pub fn max_age_days(days: u64) -> Self {
+        Self {
+            max_age_days: Some(days),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the policy deletes anything at all
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_count.is_none() && self.max_bytes.is_none()
+    }
+}
+
+/// Something whose old entries can be deleted under a `RetentionPolicy`
+pub trait Retained: Send + Sync {
+    /// What is being cleaned up, for logs
+    fn describe(&self) -> String;
+
+    /// Delete what the policy doesn't keep, returning how many entries were deleted
+    fn enforce(&self, policy: &RetentionPolicy) -> Result<usize, RetentionError>;
+}
+
+/// The message archive; entries are archived messages and their size is the raw message
+impl Retained for Arc<dyn MessageStore> {
+    fn describe(&self) -> String {
+        "message archive".to_string()
+    }
+
+    fn enforce(&self, policy: &RetentionPolicy) -> Result<usize, RetentionError> {
+        self.set_secure_delete(policy.secure_delete)?;
+        let mut deleted = 0;
+        // An age too great to subtract from now keeps everything
+        let cutoff = policy.max_age_days.and_then(|days| {
+            let age = chrono::TimeDelta::try_days(i64::try_from(days).ok()?)?;
+            Utc::now().checked_sub_signed(age)
+        });
+        if let Some(cutoff) = cutoff {
+            deleted += self.prune(cutoff)?;
+        }
+        if policy.max_count.is_some() || policy.max_bytes.is_some() {
+            deleted += self.prune_excess(policy.max_count, policy.max_bytes)?;
+        }
+        if policy.secure_delete && deleted > 0 {
+            self.vacuum()?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Files in a directory whose names start with a prefix, e.g. rotated logs
+///
+/// The newest file is always kept, since it may still be being written to.
+/// Entries are files and their age is the time they were last modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Files {
+    directory: PathBuf,
+    prefix: String,
+}
+
+impl Files {
+    pub fn new<P: Into<PathBuf>, S: ToString>(directory: P, prefix: S) -> Self {
+        Self {
+            directory: directory.into(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// The matching files, newest first, with their sizes and modification times
+    fn list(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        if !self.directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(&self.prefix) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((entry.path(), metadata.len(), metadata.modified()?));
+            }
+        }
+        files.sort_by_key(|file| std::cmp::Reverse(file.2));
+        Ok(files)
+    }
+}
+
+impl Retained for Files {
+    fn describe(&self) -> String {
+        format!("{}* in {}", self.prefix, self.directory.display())
+    }
+
+    fn enforce(&self, policy: &RetentionPolicy) -> Result<usize, RetentionError> {
+        let max_age = policy.max_age_days.and_then(|days| days.checked_mul(24 * 60 * 60)).map(Duration::from_secs);
+        let now = SystemTime::now();
+        let (mut kept_bytes, mut deleted) = (0, 0);
+        for (index, (path, size, modified)) in self.list()?.into_iter().enumerate() {
+            kept_bytes += size;
+            let expired = max_age.is_some_and(|max_age| now.duration_since(modified).is_ok_and(|age| age > max_age));
+            let excess = policy.max_count.is_some_and(|count| index >= count)
+                || policy.max_bytes.is_some_and(|bytes| kept_bytes > bytes);
+            if index == 0 || !(expired || excess) {
+                continue;
+            }
+            info!("Removing old file: {}", path.display());
+            if policy.secure_delete {
+                overwrite(&path, size)?;
+            }
+            fs::remove_file(&path)?;
+            kept_bytes -= size;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Overwrite a file's contents with zeros and flush them to disk
+pub(crate) fn overwrite(path: &Path, size: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.rewind()?;
+    let zeros = [0u8; 8192];
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()
+}
+
+/// Retention policies for every store, enforced together on a schedule
+///
+/// ```ignore
+/// let retention = Retention::new()
+///     .add(store, RetentionPolicy { max_age_days: Some(30), secure_delete: true, ..Default::default() })
+///     .add(Files::new("logs", "rust-hl7.log"), RetentionPolicy { max_bytes: Some(1 << 30), ..Default::default() });
+/// retention.spawn(Duration::from_secs(3600));
+/// ```
+#[derive(Default)]
+pub struct Retention {
+    targets: Vec<(Box<dyn Retained>, RetentionPolicy)>,
+}
+
+impl std::fmt::Debug for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let targets: Vec<String> = self.targets.iter().map(|(target, _)| target.describe()).collect();
+        f.debug_struct("Retention").field("targets", &targets).finish()
+    }
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enforce a policy on a store; stores with no limits are left alone
+    pub fn add<R: Retained + 'static>(mut self, target: R, policy: RetentionPolicy) -> Self {
+        if !policy.is_unlimited() {
+            self.targets.push((Box::new(target), policy));
+        }
+        self
+    }
+
+    /// Whether there is anything to enforce
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Enforce every policy now, logging rather than failing on errors, and
+    /// return how many entries were deleted
+    pub fn enforce(&self) -> usize {
+        let mut total = 0;
+        for (target, policy) in &self.targets {
+            match target.enforce(policy) {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    info!("Deleted {} old entries from {}", deleted, target.describe());
+                    total += deleted;
+                }
+                Err(e) => warn!("Failed to enforce retention on {}: {}", target.describe(), e),
+            }
+        }
+        total
+    }
+
+    /// Enforce every policy now and then every `interval`
+    ///
+    /// Deleting and vacuuming block, so each round runs on the blocking pool.
+    pub fn spawn(self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let retention = Arc::new(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let retention = retention.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || retention.enforce()).await {
+                    warn!("Retention task failed: {}", e);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,739 @@
+use crate::audit::{AuditDisposition, AuditEvent, AuditLog};
+use crate::filesink::FileSink;
+use crate::mllp::{MessageHandler, MllpClient, MllpError};
+use crate::ordering::OrderedSink;
+use crate::query::Expression;
+use crate::schedule::ScheduledSink;
+use crate::transform::{Pipeline, Transform};
+use crate::webhook::WebhookSink;
+use crate::{consent, terser, HL7Error, Message};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+/// A condition evaluated against a parsed message
+///
+/// In config files predicates are written in snake case, e.g.
+/// `{ message_type = "ORU" }` or `{ all = [{ message_type = "ADT" }, { patient_class = "I" }] }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Predicate {
+    /// Matches every message
+    Always,
+    /// Message code in MSH-9.1, e.g. "ADT"
+    MessageType(String),
+    /// Trigger event in MSH-9.2, e.g. "A01"
+    TriggerEvent(String),
+    /// Sending application in MSH-3
+    SendingApplication(String),
+    /// Sending facility in MSH-4
+    SendingFacility(String),
+    /// Patient class in PV1-2, e.g. "I" for inpatient
+    PatientClass(String),
+    /// The patient is flagged confidential; see `consent::is_confidential`
+    ///
+    /// In config files this is written `when = "confidential"`, or
+    /// `when = { not = "confidential" }` to keep flagged patients from a route.
+    Confidential,
+    /// Value at a terser path, e.g. `FieldEquals("OBR-4.1".into(), "CBC".into())`
+    FieldEquals(String, String),
+    /// A structural query matches a non-empty value, e.g. `OBX[?(@.3.1=='WBC' && @.5>11)]`
+    ///
+    /// See `query::Expression` for the syntax. The query is parsed when the
+    /// predicate is built or deserialized.
+    Query(Expression),
+    /// A Rhai script evaluates to `true`, e.g. `msg["PV1-2"] == "E" && msg.count("OBX") > 0`
+    ///
+    /// The script is compiled when the predicate is built or deserialized.
+    /// See `rules` for what scripts can read. Requires the `rules` feature.
+    #[cfg(feature = "rules")]
+    Script(crate::rules::Script),
+    /// All of the inner predicates match
+    All(Vec<Predicate>),
+    /// At least one of the inner predicates matches
+    Any(Vec<Predicate>),
+    /// The inner predicate doesn't match
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    /// Check whether the message satisfies this predicate
+    pub fn matches(&self, message: &Message) -> bool {
+        let field_is = |path: &str, expected: &str| {
+            terser::get(message, path).is_some_and(|v| v == expected)
+        };
+
+        match self {
+            Predicate::Always => true,
+            Predicate::MessageType(code) => field_is("MSH-9.1", code),
+            Predicate::TriggerEvent(event) => field_is("MSH-9.2", event),
+            Predicate::SendingApplication(app) => field_is("MSH-3.1", app),
+            Predicate::SendingFacility(facility) => field_is("MSH-4.1", facility),
+            Predicate::PatientClass(class) => field_is("PV1-2.1", class),
+            Predicate::Confidential => consent::is_confidential(message),
+            Predicate::FieldEquals(path, value) => field_is(path, value),
+            Predicate::Query(query) => query.matches(message),
+            #[cfg(feature = "rules")]
+            Predicate::Script(script) => match script.matches(message) {
+                Ok(matched) => matched,
+                Err(e) => {
+                    warn!("Treating script predicate as not matching: {}", e);
+                    false
+                }
+            },
+            Predicate::All(predicates) => predicates.iter().all(|p| p.matches(message)),
+            Predicate::Any(predicates) => predicates.iter().any(|p| p.matches(message)),
+            Predicate::Not(predicate) => !predicate.matches(message),
+        }
+    }
+}
+
+/// Where a routed message is delivered
+#[derive(Clone)]
+pub enum Destination {
+    /// Forward to a downstream MLLP endpoint
+    Mllp(MllpClient),
+    /// Forward to one of several MLLP endpoints chosen by the pool's strategy
+    Pool(Arc<EndpointPool>),
+    /// Write each message to a file, or append it to a batch file
+    File(FileSink),
+    /// Pass to an in-process handler
+    Handler(MessageHandler),
+    /// Hold messages and deliver them to another destination at scheduled windows
+    Scheduled(ScheduledSink),
+    /// Deliver to another destination one message at a time per patient or visit
+    Ordered(OrderedSink),
+    /// POST each message as JSON to an HTTP endpoint
+    Webhook(WebhookSink),
+    /// Publish to a NATS subject
+    #[cfg(feature = "nats")]
+    Nats(crate::nats::NatsSink),
+    /// Store clinical data in normalized Postgres tables
+    #[cfg(feature = "postgres")]
+    Postgres(crate::postgres::PostgresSink),
+}
+
+impl std::fmt::Debug for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Destination::Mllp(client) => write!(f, "Mllp({})", client.address()),
+            Destination::Pool(pool) => write!(f, "Pool({:?})", pool.addresses()),
+            Destination::File(sink) => write!(f, "File({})", sink.directory().display()),
+            Destination::Handler(_) => write!(f, "Handler"),
+            Destination::Scheduled(sink) => write!(f, "Scheduled({:?} via {})", sink.destination(), sink.path().display()),
+            Destination::Ordered(sink) if sink.keys().is_empty() => write!(f, "Spooled({:?})", sink.destination()),
+            Destination::Ordered(sink) => write!(f, "Ordered({:?} by {})", sink.destination(), sink.keys().join(", ")),
+            Destination::Webhook(sink) => write!(f, "Webhook({})", sink.url()),
+            #[cfg(feature = "nats")]
+            Destination::Nats(sink) => write!(f, "Nats({} {})", sink.url(), sink.subject()),
+            #[cfg(feature = "postgres")]
+            Destination::Postgres(sink) => write!(f, "Postgres({})", sink.display_url()),
+        }
+    }
+}
+
+/// Run once a destination has taken a message
+type Delivered = Box<dyn FnOnce() + Send>;
+
+/// Run a delivery callback on a blocking thread, since it may write to disk
+async fn delivered_in_background(delivered: Option<Delivered>) {
+    if let Some(delivered) = delivered {
+        if let Err(e) = tokio::task::spawn_blocking(delivered).await {
+            error!("Failed to record a delivery: {}", e);
+        }
+    }
+}
+
+impl Destination {
+    /// Deliver a message to this destination, running `delivered` once it's taken
+    ///
+    /// MLLP forwarding runs in the background so a slow downstream system doesn't
+    /// hold up the inbound connection; failures are logged within the caller's
+    /// span, so they can still be traced back to the message. For those
+    /// destinations `delivered` runs when the downstream accepts the message,
+    /// and not at all if it doesn't.
+    fn deliver(&self, message: &Message, mut delivered: Option<Delivered>) -> Result<(), HL7Error> {
+        let result = match self {
+            Destination::Mllp(client) => {
+                let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+                    HL7Error::DeliveryError("MLLP destinations require a Tokio runtime".to_string())
+                })?;
+                let client = client.clone();
+                let payload = message.to_hl7();
+                let delivered = delivered.take();
+                runtime.spawn(async move {
+                    match client.send(&payload).await {
+                        Ok(ack) => match check_ack(&ack) {
+                            Ok(()) => {
+                                info!("Forwarded message to {}", client.address());
+                                delivered_in_background(delivered).await;
+                            }
+                            Err(e) => error!("Failed to forward message to {}: {}", client.address(), e),
+                        },
+                        Err(e) => error!("Failed to forward message to {}: {}", client.address(), e),
+                    }
+                }.instrument(Span::current()));
+                Ok(())
+            }
+            Destination::Pool(pool) => {
+                let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+                    HL7Error::DeliveryError("MLLP destinations require a Tokio runtime".to_string())
+                })?;
+                let pool = pool.clone();
+                let payload = message.to_hl7();
+                let delivered = delivered.take();
+                runtime.spawn(async move {
+                    match pool.send(&payload).await {
+                        Ok(ack) => match check_ack(&ack) {
+                            Ok(()) => delivered_in_background(delivered).await,
+                            Err(e) => error!("Failed to forward message to any of {:?}: {}", pool.addresses(), e),
+                        },
+                        Err(e) => error!("Failed to forward message to any of {:?}: {}", pool.addresses(), e),
+                    }
+                }.instrument(Span::current()));
+                Ok(())
+            }
+            Destination::File(sink) => sink.write(message).map(|_| ()).map_err(|e| {
+                HL7Error::DeliveryError(format!("Failed to write to {}: {}", sink.directory().display(), e))
+            }),
+            Destination::Handler(handler) => handler(message.clone()).map(|_| ()),
+            Destination::Scheduled(sink) => sink.enqueue(message).map_err(|e| {
+                HL7Error::DeliveryError(format!("Failed to spool to {}: {}", sink.path().display(), e))
+            }),
+            Destination::Ordered(sink) => sink.enqueue(message),
+            Destination::Webhook(sink) => {
+                let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+                    HL7Error::DeliveryError("Webhook destinations require a Tokio runtime".to_string())
+                })?;
+                let posting = sink.spawn_post(message).map_err(|e| HL7Error::DeliveryError(e.to_string()))?;
+                if let Some(delivered) = delivered.take() {
+                    runtime.spawn(async move {
+                        if posting.await.unwrap_or(false) {
+                            delivered_in_background(Some(delivered)).await;
+                        }
+                    });
+                }
+                Ok(())
+            }
+            #[cfg(feature = "nats")]
+            Destination::Nats(sink) => {
+                let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+                    HL7Error::DeliveryError("NATS destinations require a Tokio runtime".to_string())
+                })?;
+                let sink = sink.clone();
+                let message = message.clone();
+                let delivered = delivered.take();
+                runtime.spawn(async move {
+                    match sink.publish(&message).await {
+                        Ok(()) => {
+                            info!("Published message to NATS subject {}", sink.subject());
+                            delivered_in_background(delivered).await;
+                        }
+                        Err(e) => error!("Failed to publish message to NATS subject {}: {}", sink.subject(), e),
+                    }
+                }.instrument(Span::current()));
+                Ok(())
+            }
+            #[cfg(feature = "postgres")]
+            Destination::Postgres(sink) => {
+                let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+                    HL7Error::DeliveryError("Postgres destinations require a Tokio runtime".to_string())
+                })?;
+                let sink = sink.clone();
+                let message = message.clone();
+                let delivered = delivered.take();
+                runtime.spawn(async move {
+                    match sink.write(&message).await {
+                        Ok(()) => {
+                            info!("Stored message in {}", sink.display_url());
+                            delivered_in_background(delivered).await;
+                        }
+                        Err(e) => error!("Failed to store message in {}: {}", sink.display_url(), e),
+                    }
+                }.instrument(Span::current()));
+                Ok(())
+            }
+        };
+        if let (Ok(()), Some(delivered)) = (&result, delivered) {
+            delivered();
+        }
+        result
+    }
+
+    /// Check that the destination could take a message now, without side effects
+    ///
+    /// MLLP endpoints aren't connected to, since peers may take an empty
+    /// connection for a fault: their address must resolve within `timeout`,
+    /// and a pool needs at least one endpoint it last found healthy. A
+    /// webhook's host must accept a connection. File destinations and spools
+    /// must be writable.
+    /// NATS and Postgres destinations aren't checked, since they reconnect on their own.
+    pub async fn check(&self, timeout: Duration) -> Result<(), HL7Error> {
+        match self {
+            Destination::Mllp(client) => match tokio::time::timeout(timeout, tokio::net::lookup_host(client.address()))
+                .await
+                .map(|resolved| resolved.map(|mut addresses| addresses.next().is_some()))
+            {
+                Ok(Ok(true)) => Ok(()),
+                Ok(Ok(false)) => Err(HL7Error::DeliveryError(format!("{} has no addresses", client.address()))),
+                Ok(Err(e)) => Err(HL7Error::DeliveryError(format!("{} doesn't resolve: {}", client.address(), e))),
+                Err(_) => Err(HL7Error::DeliveryError(format!("{} didn't resolve within {:?}", client.address(), timeout))),
+            },
+            Destination::Pool(pool) => {
+                if pool.health().iter().any(|(_, healthy)| *healthy) {
+                    Ok(())
+                } else {
+                    Err(HL7Error::DeliveryError(format!("None of {:?} are healthy", pool.addresses())))
+                }
+            }
+            Destination::File(sink) => sink.check_writable().map_err(|e| {
+                HL7Error::DeliveryError(format!("{} is not writable: {}", sink.directory().display(), e))
+            }),
+            Destination::Handler(_) => Ok(()),
+            Destination::Scheduled(sink) => sink.check_writable().map_err(|e| {
+                HL7Error::DeliveryError(format!("Spool {} is not writable: {}", sink.path().display(), e))
+            }),
+            Destination::Ordered(sink) => match sink.parked() {
+                parked if parked.is_empty() => Box::pin(sink.destination().check(timeout)).await,
+                parked => Err(HL7Error::DeliveryError(format!(
+                    "Ordered delivery to {:?} is held up for {}",
+                    sink.destination(),
+                    parked.join(", ")
+                ))),
+            },
+            Destination::Webhook(sink) => sink.check(timeout).await.map_err(|e| HL7Error::DeliveryError(e.to_string())),
+            #[cfg(feature = "nats")]
+            Destination::Nats(_) => Ok(()),
+            #[cfg(feature = "postgres")]
+            Destination::Postgres(_) => Ok(()),
+        }
+    }
+
+    /// Deliver a message and wait for it to be accepted
+    ///
+    /// Unlike routing from a server, MLLP sends are awaited and a downstream NACK
+    /// is an error, so callers such as replay know the outcome of each message.
+    pub async fn send(&self, message: &Message) -> Result<(), HL7Error> {
+        let response = match self {
+            Destination::Mllp(client) => client.send(&message.to_hl7()).await.map_err(|e| {
+                HL7Error::DeliveryError(format!("Failed to send to {}: {}", client.address(), e))
+            })?,
+            Destination::Pool(pool) => pool.send(&message.to_hl7()).await.map_err(|e| {
+                HL7Error::DeliveryError(format!("Failed to send to any of {:?}: {}", pool.addresses(), e))
+            })?,
+            Destination::Ordered(sink) => return sink.send(message).await,
+            Destination::Webhook(sink) => return sink.post(message).await.map_err(|e| HL7Error::DeliveryError(e.to_string())),
+            #[cfg(feature = "nats")]
+            Destination::Nats(sink) => {
+                return sink
+                    .publish(message)
+                    .await
+                    .map_err(|e| HL7Error::DeliveryError(e.to_string()))
+            }
+            #[cfg(feature = "postgres")]
+            Destination::Postgres(sink) => {
+                return sink
+                    .write(message)
+                    .await
+                    .map_err(|e| HL7Error::DeliveryError(e.to_string()))
+            }
+            Destination::File(_) | Destination::Handler(_) | Destination::Scheduled(_) => return self.deliver(message, None),
+        };
+        check_ack(&response)
+    }
+}
+
+/// Turn a downstream NACK into an error
+fn check_ack(response: &str) -> Result<(), HL7Error> {
+    let ack = Message::parse(response)?;
+    match terser::get(&ack, "MSA-1").as_deref() {
+        Some("AA") | Some("CA") => Ok(()),
+        code => Err(HL7Error::DeliveryError(format!(
+            "Downstream responded {}: {}",
+            code.unwrap_or("without MSA"),
+            terser::get(&ack, "MSA-3").unwrap_or_default()
+        ))),
+    }
+}
+
+/// How an `EndpointPool` picks the endpoint for each message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Always use the first healthy endpoint, in the order they were added
+    #[default]
+    Failover,
+    /// Rotate through the healthy endpoints
+    RoundRobin,
+    /// Use the healthy endpoint with the fewest messages in flight
+    LeastPending,
+}
+
+/// A downstream endpoint and its current state
+#[derive(Debug)]
+struct Endpoint {
+    client: MllpClient,
+    healthy: AtomicBool,
+    pending: AtomicUsize,
+}
+
+/// A group of interchangeable MLLP endpoints behind one destination
+///
+/// A failed send marks the endpoint unhealthy and the next candidate is tried.
+/// Unhealthy endpoints are only used once every healthy one has failed, and are
+/// restored by a successful send or health probe. An AR or CR response, where
+/// the endpoint refused the message rather than finding fault with it, also
+/// moves on to the next candidate, though the endpoint stays healthy.
+#[derive(Debug)]
+pub struct EndpointPool {
+    endpoints: Vec<Endpoint>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl EndpointPool {
+    /// Create a pool over the given clients
+    pub fn new<I: IntoIterator<Item = MllpClient>>(clients: I, strategy: Strategy) -> Self {
+        Self {
+            endpoints: clients
+                .into_iter()
+                .map(|client| Endpoint {
+                    client,
+                    healthy: AtomicBool::new(true),
+                    pending: AtomicUsize::new(0),
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Addresses of the endpoints in the pool
+    pub fn addresses(&self) -> Vec<String> {
+        self.endpoints.iter().map(|e| e.client.address().to_string()).collect()
+    }
+
+    /// Address and health of each endpoint
+    pub fn health(&self) -> Vec<(String, bool)> {
+        self.endpoints
+            .iter()
+            .map(|e| (e.client.address().to_string(), e.healthy.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Indexes of the endpoints to try for the next message, in order
+    fn candidates(&self) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let mut order: Vec<usize> = match self.strategy {
+            Strategy::Failover => (0..count).collect(),
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % count.max(1);
+                (0..count).map(|i| (start + i) % count).collect()
+            }
+            Strategy::LeastPending => {
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by_key(|&i| self.endpoints[i].pending.load(Ordering::Relaxed));
+                order
+            }
+        };
+
+        // Healthy endpoints first; the sort is stable so the strategy's order is kept
+        order.sort_by_key(|&i| !self.endpoints[i].healthy.load(Ordering::Relaxed));
+        order
+    }
+
+    /// Send a message to the first endpoint that accepts it, returning the response
+    ///
+    /// An AE response is returned as it is, since another endpoint would find
+    /// the same fault; if every endpoint answers AR or CR, the last of those is returned.
+    pub async fn send(&self, message: &str) -> Result<String, MllpError> {
+        let mut last_error = None;
+        let mut refused = None;
+
+        for index in self.candidates() {
+            let endpoint = &self.endpoints[index];
+            endpoint.pending.fetch_add(1, Ordering::Relaxed);
+            let result = endpoint.client.send(message).await;
+            endpoint.pending.fetch_sub(1, Ordering::Relaxed);
+
+            match result {
+                Ok(response) => {
+                    endpoint.healthy.store(true, Ordering::Relaxed);
+                    let code = Message::parse(&response).ok().and_then(|ack| terser::get(&ack, "MSA-1"));
+                    if matches!(code.as_deref(), Some("AR") | Some("CR")) {
+                        warn!("Endpoint {} refused the message, trying next", endpoint.client.address());
+                        refused = Some(response);
+                        continue;
+                    }
+                    info!("Forwarded message to {}", endpoint.client.address());
+                    return Ok(response);
+                }
+                Err(e) => {
+                    warn!("Endpoint {} failed, trying next: {}", endpoint.client.address(), e);
+                    endpoint.healthy.store(false, Ordering::Relaxed);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if let Some(response) = refused {
+            return Ok(response);
+        }
+        Err(last_error.unwrap_or_else(|| MllpError::InvalidFrame("Endpoint pool is empty".to_string())))
+    }
+
+    /// Probe every endpoint by opening a TCP connection, updating its health
+    pub async fn probe(&self, timeout: Duration) {
+        for endpoint in &self.endpoints {
+            let address = endpoint.client.address();
+            let healthy = matches!(
+                tokio::time::timeout(timeout, TcpStream::connect(address)).await,
+                Ok(Ok(_))
+            );
+            if healthy != endpoint.healthy.swap(healthy, Ordering::Relaxed) {
+                info!("Endpoint {} is now {}", address, if healthy { "healthy" } else { "unhealthy" });
+            }
+        }
+    }
+
+    /// Spawn a background task probing the endpoints at a fixed interval
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let pool = self.clone();
+        let timeout = interval.min(Duration::from_secs(5));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                pool.probe(timeout).await;
+            }
+        })
+    }
+}
+
+/// A named rule sending matching messages to one or more destinations
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub predicate: Predicate,
+    pub destinations: Vec<Destination>,
+    /// Transforms applied to a copy of the message before it is delivered
+    pub transform: Option<Arc<Pipeline>>,
+}
+
+impl Route {
+    /// Create a route with no destinations
+    pub fn new<N: ToString>(name: N, predicate: Predicate) -> Self {
+        Self {
+            name: name.to_string(),
+            predicate,
+            destinations: Vec::new(),
+            transform: None,
+        }
+    }
+
+    /// Transform messages matching this route before delivering them
+    pub fn with_transform(mut self, pipeline: Pipeline) -> Self {
+        self.transform = Some(Arc::new(pipeline));
+        self
+    }
+
+    /// Add a destination to this route
+    pub fn to(mut self, destination: Destination) -> Self {
+        self.destinations.push(destination);
+        self
+    }
+}
+
+/// Content-based router delivering each message to every route it matches
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    audit: Option<Arc<AuditLog>>,
+    #[cfg(feature = "rules")]
+    rules: Vec<crate::rules::Rule>,
+}
+
+impl Router {
+    /// Create an empty router
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route, evaluated after those already added
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// The configured routes
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The ordered destinations of every route, each listed once
+    pub fn ordered(&self) -> Vec<OrderedSink> {
+        let mut sinks: Vec<OrderedSink> = Vec::new();
+        for destination in self.routes.iter().flat_map(|route| &route.destinations) {
+            if let Destination::Ordered(sink) = destination {
+                if !sinks.iter().any(|s| s.same_as(sink)) {
+                    sinks.push(sink.clone());
+                }
+            }
+        }
+        sinks
+    }
+
+    /// The scheduled destinations of every route, each listed once
+    pub fn scheduled(&self) -> Vec<ScheduledSink> {
+        let mut sinks: Vec<ScheduledSink> = Vec::new();
+        for destination in self.routes.iter().flat_map(|route| &route.destinations) {
+            if let Destination::Scheduled(sink) = destination {
+                if !sinks.iter().any(|s| s.same_as(sink)) {
+                    sinks.push(sink.clone());
+                }
+            }
+        }
+        sinks
+    }
+
+    /// Check every message against an acceptance rule before routing it, after those already added
+    ///
+    /// A message the rule rejects isn't routed, and is answered with an AR NACK.
+    #[cfg(feature = "rules")]
+    pub fn rule(mut self, rule: crate::rules::Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Record every message handed to a destination in an audit log
+    pub fn with_audit(mut self, log: Arc<AuditLog>) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Auditing of a message's delivery, if an audit log is configured, logging rather than failing on errors
+    fn audit(&self, message: &Message, route: &Route, destination: &Destination) -> Option<Delivered> {
+        let log = self.audit.clone()?;
+        let event = AuditEvent::from_message(message, AuditDisposition::Forwarded)
+            .with_source(format!("route {}", route.name))
+            .with_destination(format!("{:?}", destination));
+        Some(Box::new(move || {
+            if let Err(e) = log.record(event) {
+                error!("Failed to audit delivery in {}: {}", log.path().display(), e);
+            }
+        }))
+    }
+
+    /// Fail with the first rejection by an acceptance rule
+    #[cfg_attr(not(feature = "rules"), allow(unused_variables))]
+    fn accept(&self, message: &Message) -> Result<(), HL7Error> {
+        #[cfg(feature = "rules")]
+        for rule in &self.rules {
+            rule.check(message)?;
+        }
+        Ok(())
+    }
+
+    /// The matching routes, each with its own transformed copy of the message
+    ///
+    /// Each route transforms its own copy so routes don't affect each other.
+    fn matching(&self, message: &Message) -> Vec<(&Route, Result<Message, HL7Error>)> {
+        let matches: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|r| r.predicate.matches(message))
+            .map(|route| {
+                let _span = info_span!("route", route = %route.name).entered();
+                info!("Message matched route '{}'", route.name);
+                let mut outbound = message.clone();
+                let result = match &route.transform {
+                    Some(pipeline) => pipeline.apply(&mut outbound).map(|_| outbound),
+                    None => Ok(outbound),
+                };
+                if let Err(e) = &result {
+                    error!("Route '{}' failed to transform message: {}", route.name, e);
+                }
+                (route, result)
+            })
+            .collect();
+
+        if matches.is_empty() {
+            warn!("No route matched message of type {}", message.message_type);
+        }
+        matches
+    }
+
+    /// Deliver a message to all matching routes, returning it unchanged
+    ///
+    /// Every destination is attempted even if an earlier one fails; the first
+    /// error is returned so the sender receives a NACK.
+    pub fn handle(&self, message: Message) -> Result<Message, HL7Error> {
+        self.accept(&message)?;
+        let mut first_error = None;
+
+        for (route, outbound) in self.matching(&message) {
+            let outbound = match outbound {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            let _span = info_span!("route", route = %route.name).entered();
+            for destination in &route.destinations {
+                match destination.deliver(&outbound, self.audit(&outbound, route, destination)) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        error!("Route '{}' failed to deliver to {:?}: {}", route.name, destination, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(message),
+        }
+    }
+
+    /// Like `handle`, but waits for every destination to accept the message
+    pub async fn send(&self, message: Message) -> Result<Message, HL7Error> {
+        self.accept(&message)?;
+        let mut first_error = None;
+
+        for (route, outbound) in self.matching(&message) {
+            let outbound = match outbound {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            for destination in &route.destinations {
+                let delivery = destination.send(&outbound).instrument(info_span!("route", route = %route.name));
+                match delivery.await {
+                    Ok(()) => delivered_in_background(self.audit(&outbound, route, destination)).await,
+                    Err(e) => {
+                        error!("Route '{}' failed to deliver to {:?}: {}", route.name, destination, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(message),
+        }
+    }
+
+    /// Wrap the router as a handler for `MllpServer`
+    pub fn into_handler(self) -> MessageHandler {
+        let router = Arc::new(self);
+        Arc::new(move |message| router.handle(message))
+    }
+}
@@ -0,0 +1,205 @@
+//! Routing and acceptance logic written as Rhai scripts
+//!
+//! Scripts see the message as `msg`, read with terser paths:
+//!
+//! - `msg["PID-3.1"]` or `msg.get("PID-3.1")` - the value, or `""` if it's missing
+//! - `msg.exists("PV1-19")` - whether the value is there and not empty
+//! - `msg.select("OBX[?(@.8=='HH')].3.1")` - every value a structural query
+//!   (`query::Expression`) finds, as an array
+//! - `msg.count("OBX")` - how many segments have that name
+//! - `msg.message_type`, `msg.trigger`, `msg.sender`, `msg.facility` and
+//!   `msg.control_id` - MSH-9.1, MSH-9.2, MSH-3, MSH-4 and MSH-10
+//!
+//! A route predicate (`Predicate::Script`) matches when its script evaluates
+//! to `true`. An acceptance rule (`Rule`) rejects a message when its script
+//! evaluates to `false`, or to a string giving the reason; `true` or nothing
+//! accepts it. Scripts are stopped after 100,000 operations, or when they
+//! build a string over 1 MiB, an array over 10,000 items or a map over 1,000
+//! entries, so a runaway script fails the message rather than stalling the
+//! listener or exhausting its memory.
+//!
+//! ```
+//! use rust_hl7::rules::{Decision, Rule};
+//! use rust_hl7::Message;
+//!
+//! let rule = Rule::new("no-test-patients", r#"
+//!     if msg["PID-5.1"] == "TEST" { return "Test patients aren't accepted"; }
+//!     msg.message_type != "ADT" || msg.exists("PV1-19")
+//! "#).unwrap();
+//! let message = Message::parse("MSH|^~\\&|REG|HOSP|||20240501||ADT^A01|1|P|2.5\rPID|1||12345||TEST^PAT\rPV1|1|I").unwrap();
+//! assert_eq!(rule.decide(&message).unwrap(), Decision::Reject("Test patients aren't accepted".to_string()));
+//! ```
+
+use crate::query::Expression;
+use crate::{terser, HL7Error, Message};
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// The message as scripts see it
+#[derive(Debug, Clone)]
+struct ScriptMessage(Arc<Message>);
+
+impl ScriptMessage {
+    fn get(&mut self, path: &str) -> String {
+        terser::get(&self.0, path).unwrap_or_default()
+    }
+}
+
+/// The engine every script runs on, with `msg`'s functions registered
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut engine = Engine::new();
+        engine.set_max_operations(100_000);
+        engine.set_max_string_size(1024 * 1024);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(1_000);
+        engine
+            .register_type_with_name::<ScriptMessage>("Message")
+            .register_fn("get", ScriptMessage::get)
+            .register_indexer_get(ScriptMessage::get)
+            .register_fn("exists", |msg: &mut ScriptMessage, path: &str| !msg.get(path).is_empty())
+            .register_fn("count", |msg: &mut ScriptMessage, segment: &str| msg.0.get_segments(segment).len() as i64)
+            .register_fn("select", |msg: &mut ScriptMessage, query: &str| -> Result<Array, Box<rhai::EvalAltResult>> {
+                let expression: Expression = query.parse().map_err(|e: HL7Error| e.to_string())?;
+                Ok(expression.select(&msg.0).into_iter().map(Dynamic::from).collect())
+            })
+            .register_get("message_type", |msg: &mut ScriptMessage| msg.get("MSH-9.1"))
+            .register_get("trigger", |msg: &mut ScriptMessage| msg.get("MSH-9.2"))
+            .register_get("sender", |msg: &mut ScriptMessage| msg.get("MSH-3.1"))
+            .register_get("facility", |msg: &mut ScriptMessage| msg.get("MSH-4.1"))
+            .register_get("control_id", |msg: &mut ScriptMessage| msg.get("MSH-10.1"));
+        engine
+    })
+}
+
+/// A compiled script
+///
+/// It serializes as its source, and is compiled as it's deserialized, so a
+/// script in a config file fails when the config is loaded.
+#[derive(Debug, Clone)]
+pub struct Script {
+    source: String,
+    ast: Arc<AST>,
+}
+
+impl Script {
+    /// Compile a script, failing on syntax errors
+    pub fn compile<S: ToString>(source: S) -> Result<Self, HL7Error> {
+        let source = source.to_string();
+        let ast = engine()
+            .compile(&source)
+            .map_err(|e| HL7Error::ParseError(format!("Script error: {}", e)))?;
+        Ok(Self { source, ast: Arc::new(ast) })
+    }
+
+    /// Read and compile a script file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HL7Error> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| HL7Error::ParseError(format!("Can't read script {}: {}", path.display(), e)))?;
+        Self::compile(source).map_err(|e| HL7Error::ParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The script's text
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Run the script against a message, returning the value of its last statement
+    pub fn eval(&self, message: &Message) -> Result<Dynamic, HL7Error> {
+        let mut scope = Scope::new();
+        scope.push("msg", ScriptMessage(Arc::new(message.clone())));
+        engine()
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|e| HL7Error::InvalidStructure(format!("Script failed: {}", e)))
+    }
+
+    /// Whether the script evaluates to `true` for a message
+    pub fn matches(&self, message: &Message) -> Result<bool, HL7Error> {
+        let value = self.eval(message)?;
+        value
+            .as_bool()
+            .map_err(|kind| HL7Error::InvalidStructure(format!("Script returned {} instead of true or false", kind)))
+    }
+}
+
+/// Scripts are the same if their source is
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::compile(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// What an acceptance rule made of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// Reject the message with this reason
+    Reject(String),
+}
+
+/// A script that accepts or rejects each message before it's routed
+#[derive(Debug, Clone)]
+pub struct Rule {
+    name: String,
+    script: Script,
+}
+
+impl Rule {
+    /// A rule running `source`
+    pub fn new<N: ToString, S: ToString>(name: N, source: S) -> Result<Self, HL7Error> {
+        let name = name.to_string();
+        let script = Script::compile(source).map_err(|e| HL7Error::ParseError(format!("Rule '{}': {}", name, e)))?;
+        Ok(Self { name, script })
+    }
+
+    /// A rule running an already compiled script, e.g. one read with `Script::load`
+    pub fn from_script<N: ToString>(name: N, script: Script) -> Self {
+        Self { name: name.to_string(), script }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Accept or reject a message; fails if the script does or returns something other than a bool, a string or nothing
+    pub fn decide(&self, message: &Message) -> Result<Decision, HL7Error> {
+        let value = self.script.eval(message).map_err(|e| HL7Error::InvalidStructure(format!("Rule '{}': {}", self.name, e)))?;
+        if value.is_unit() || value.as_bool() == Ok(true) {
+            Ok(Decision::Accept)
+        } else if value.as_bool() == Ok(false) {
+            Ok(Decision::Reject(format!("Rejected by rule '{}'", self.name)))
+        } else if value.is_string() {
+            Ok(Decision::Reject(value.into_string().unwrap_or_default()))
+        } else {
+            Err(HL7Error::InvalidStructure(format!(
+                "Rule '{}' returned {} instead of true, false or a reason",
+                self.name,
+                value.type_name()
+            )))
+        }
+    }
+
+    /// Like `decide`, but a rejection is a `Rejected` error, answered with an AR NACK
+    pub fn check(&self, message: &Message) -> Result<(), HL7Error> {
+        match self.decide(message)? {
+            Decision::Accept => Ok(()),
+            Decision::Reject(reason) => Err(HL7Error::Rejected(reason)),
+        }
+    }
+}
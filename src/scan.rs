@@ -0,0 +1,77 @@
+//! Delimiter scanning for the parser, using `memchr`
+//!
+//! `memchr` searches with SIMD where the target has it, and with `std` it
+//! picks the widest instructions the CPU supports at runtime. Delimiters are
+//! ASCII in every message the parser sees, since declared ones are rewritten
+//! to the standard set first; anything else falls back to `str::split`.
+
+/// Split on a delimiter, like `str::split`
+pub(crate) fn split(input: &str, delimiter: char) -> Split<'_> {
+    let inner = if delimiter.is_ascii() {
+        Inner::Byte {
+            rest: Some(input),
+            byte: delimiter as u8,
+        }
+    } else {
+        Inner::Char(input.split(delimiter))
+    };
+    Split(inner)
+}
+
+/// Whether the input contains a delimiter
+pub(crate) fn contains(input: &str, delimiter: char) -> bool {
+    if delimiter.is_ascii() {
+        memchr::memchr(delimiter as u8, input.as_bytes()).is_some()
+    } else {
+        input.contains(delimiter)
+    }
+}
+
+/// The segments of a message, which may end in `\r`, `\n` or `\r\n`, skipping empty lines
+pub(crate) fn lines(input: &str) -> impl Iterator<Item = &str> {
+    let mut rest = input;
+    core::iter::from_fn(move || loop {
+        if rest.is_empty() {
+            return None;
+        }
+        let (line, next) = match memchr::memchr2(b'\r', b'\n', rest.as_bytes()) {
+            Some(end) => (&rest[..end], &rest[end + 1..]),
+            None => (rest, ""),
+        };
+        rest = next;
+        if !line.is_empty() {
+            return Some(line);
+        }
+    })
+}
+
+pub(crate) struct Split<'a>(Inner<'a>);
+
+enum Inner<'a> {
+    Byte { rest: Option<&'a str>, byte: u8 },
+    Char(core::str::Split<'a, char>),
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        match &mut self.0 {
+            Inner::Byte { rest, byte } => {
+                let text = (*rest)?;
+                match memchr::memchr(*byte, text.as_bytes()) {
+                    // The delimiter is ASCII, so the bytes either side are character boundaries
+                    Some(end) => {
+                        *rest = Some(&text[end + 1..]);
+                        Some(&text[..end])
+                    }
+                    None => {
+                        *rest = None;
+                        Some(text)
+                    }
+                }
+            }
+            Inner::Char(split) => split.next(),
+        }
+    }
+}
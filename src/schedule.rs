@@ -0,0 +1,405 @@
+use crate::batch::BatchFile;
+use crate::clock::{Clock, SystemClock};
+use crate::router::Destination;
+use crate::{HL7Error, Message};
+use chrono::{DateTime, Days, Local, LocalResult, NaiveDateTime, NaiveTime, Offset, TimeDelta, TimeZone};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{error, info, warn};
+
+/// Times of day a scheduled destination delivers at
+///
+/// Times are wall-clock times in a time zone, which sinks take to be the
+/// system's local time zone.
+///
+/// ```
+/// use rust_hl7::schedule::Schedule;
+///
+/// let schedule = Schedule::parse(&["02:00", "14:30"]).unwrap();
+/// let now = chrono::DateTime::parse_from_rfc3339("2024-05-01T15:00:00+02:00").unwrap();
+/// assert_eq!(schedule.next_after(now).to_rfc3339(), "2024-05-02T02:00:00+02:00");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    times: Vec<NaiveTime>,
+}
+
+impl Schedule {
+    /// Deliver every day at these times
+    pub fn daily<I: IntoIterator<Item = NaiveTime>>(times: I) -> Self {
+        let mut times: Vec<NaiveTime> = times.into_iter().collect();
+        times.sort();
+        times.dedup();
+        Self { times }
+    }
+
+    /// Parse times written "HH:MM" or "HH:MM:SS"
+    pub fn parse<S: AsRef<str>>(times: &[S]) -> Result<Self, HL7Error> {
+        if times.is_empty() {
+            return Err(HL7Error::ParseError("A schedule needs at least one time".to_string()));
+        }
+        let times = times
+            .iter()
+            .map(|time| {
+                let time = time.as_ref();
+                NaiveTime::parse_from_str(time, "%H:%M:%S")
+                    .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+                    .map_err(|_| HL7Error::ParseError(format!("Invalid time of day '{}', expected HH:MM", time)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::daily(times))
+    }
+
+    /// The first delivery time strictly after `now`, in `now`'s time zone
+    ///
+    /// When daylight saving time starts, a time the clocks skip comes as
+    /// long after the change as it was meant to be after the hour; when it
+    /// ends, a time the clocks pass twice only comes the first time.
+    pub fn next_after<Tz: TimeZone>(&self, now: DateTime<Tz>) -> DateTime<Tz> {
+        let zone = now.timezone();
+        let today = now.date_naive();
+        [today, today + Days::new(1), today + Days::new(2)]
+            .into_iter()
+            .flat_map(|day| self.times.iter().map(move |time| day.and_time(*time)))
+            .filter_map(|naive| wall_clock(&zone, naive))
+            .find(|time| *time > now)
+            .unwrap_or_else(|| now.clone() + TimeDelta::days(1))
+    }
+}
+
+/// The instant a wall-clock time names in a time zone
+fn wall_clock<Tz: TimeZone>(zone: &Tz, naive: NaiveDateTime) -> Option<DateTime<Tz>> {
+    match zone.from_local_datetime(&naive) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => Some(time),
+        // Skipped, so read it with the offset from before the clocks went forward
+        LocalResult::None => {
+            let before = zone.from_local_datetime(&(naive - TimeDelta::days(1))).earliest()?;
+            let utc = naive - TimeDelta::seconds(before.offset().fix().local_minus_utc().into());
+            Some(zone.from_utc_datetime(&utc))
+        }
+    }
+}
+
+/// Locks on a spool file, shared by every sink on it
+#[derive(Debug, Default)]
+struct SpoolLocks {
+    /// Held while appending, and while the pending messages are claimed for delivery
+    append: Mutex<()>,
+    /// Held for a whole delivery, so two never run at once
+    delivering: tokio::sync::Mutex<()>,
+}
+
+/// The locks of every spool file in use
+///
+/// A reload builds new sinks on the same spools while the old ones may still
+/// be spooling or delivering, so sinks on one file have to share its locks.
+static SPOOL_LOCKS: Mutex<BTreeMap<PathBuf, Weak<SpoolLocks>>> = Mutex::new(BTreeMap::new());
+
+fn spool_locks(path: &Path) -> Arc<SpoolLocks> {
+    let mut spools = SPOOL_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    spools.retain(|_, locks| locks.strong_count() > 0);
+    let key = spool_key(path);
+    if let Some(locks) = spools.get(&key).and_then(Weak::upgrade) {
+        return locks;
+    }
+    let locks = Arc::new(SpoolLocks::default());
+    spools.insert(key, Arc::downgrade(&locks));
+    locks
+}
+
+/// A spool path made absolute, without `.` or `..` and with its directory's symlinks resolved,
+/// so two spellings of one file compare equal
+pub(crate) fn spool_key(path: &Path) -> PathBuf {
+    let absolute = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => std::env::current_dir().unwrap_or_default().join(path),
+    };
+    let mut key = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                key.pop();
+            }
+            other => key.push(other),
+        }
+    }
+    match (key.parent().and_then(|parent| fs::canonicalize(parent).ok()), key.file_name()) {
+        (Some(parent), Some(name)) => parent.join(name),
+        _ => key,
+    }
+}
+
+/// A spool file and the destination its messages are delivered to
+#[derive(Debug)]
+struct Spool {
+    destination: Destination,
+    schedule: Schedule,
+    path: PathBuf,
+    locks: Arc<SpoolLocks>,
+}
+
+/// Holds messages for a destination and delivers them at scheduled windows
+///
+/// For receivers that only take work at set times, like a billing vendor
+/// accepting one nightly batch. Each message routed here is appended to a
+/// spool file and synced, so nothing is lost if the engine restarts before
+/// the window. At each time in the `Schedule`, everything spooled is
+/// delivered: a file destination gets one batch file wrapped in FHS/BHS
+/// headers and BTS/FTS trailers, and other destinations get the messages
+/// one at a time in the order they arrived, each awaited before the next.
+///
+/// Messages are claimed by renaming the spool to `<spool>.sending` before
+/// they're delivered. If delivery fails, those not yet delivered stay there
+/// and go first at the next window; messages arriving meanwhile start a new
+/// spool. Entries that can't be read as messages are moved to
+/// `<spool>.quarantine` rather than holding up the rest.
+///
+/// Windows are in the system's local time zone. Sinks on the same spool
+/// file, such as those from before and after a config reload, take turns
+/// with it, and a delivery that has started finishes even if the task
+/// waiting for windows is aborted.
+///
+/// ```ignore
+/// let sink = ScheduledSink::new(Destination::File(FileSink::new("/mnt/share/billing")), Schedule::parse(&["02:00"])?, "billing.spool");
+/// let task = sink.spawn();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ScheduledSink {
+    spool: Arc<Spool>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ScheduledSink {
+    pub fn new<P: Into<PathBuf>>(destination: Destination, schedule: Schedule, spool: P) -> Self {
+        let spool = spool.into();
+        Self {
+            spool: Arc::new(Spool {
+                destination,
+                schedule,
+                locks: spool_locks(&spool),
+                path: spool,
+            }),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Tell the time with this clock rather than the system's; windows stay in the local time zone
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Spool file messages are held in
+    pub fn path(&self) -> &Path {
+        &self.spool.path
+    }
+
+    /// Destination messages are delivered to
+    pub fn destination(&self) -> &Destination {
+        &self.spool.destination
+    }
+
+    /// File entries of the spool that can't be read as messages are moved to
+    pub fn quarantine_path(&self) -> PathBuf {
+        self.spool_file("quarantine")
+    }
+
+    /// Whether two sinks share the same spool
+    pub fn same_as(&self, other: &ScheduledSink) -> bool {
+        Arc::ptr_eq(&self.spool, &other.spool)
+    }
+
+    /// Append a message to the spool until the next window
+    pub fn enqueue(&self, message: &Message) -> io::Result<()> {
+        let _lock = self.spool.locks.append.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(parent) = self.spool.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.spool.path)?;
+        file.write_all(message.to_hl7().as_bytes())?;
+        file.write_all(b"\r")?;
+        file.sync_all()
+    }
+
+    /// How many messages are waiting for a window, including any left from a failed one
+    pub fn pending(&self) -> io::Result<usize> {
+        let _lock = self.spool.locks.append.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(read_spool(&self.sending_path())?.0.len() + read_spool(&self.spool.path)?.0.len())
+    }
+
+    /// Check that messages can be spooled right now
+    pub fn check_writable(&self) -> io::Result<()> {
+        let _lock = self.spool.locks.append.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(parent) = self.spool.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.spool.path).map(|_| ())
+    }
+
+    /// Deliver everything spooled now, without waiting for a window
+    ///
+    /// Returns how many messages were delivered.
+    pub async fn deliver_now(&self) -> Result<usize, HL7Error> {
+        let _delivering = self.spool.locks.delivering.lock().await;
+        let sending = self.sending_path();
+        let spool_error = |e: io::Error| {
+            HL7Error::DeliveryError(format!("Failed to read spool {}: {}", self.spool.path.display(), e))
+        };
+
+        let mut delivered = 0;
+        for claim in [false, true] {
+            let messages = {
+                let _lock = self.spool.locks.append.lock().unwrap_or_else(|e| e.into_inner());
+                if claim && !sending.exists() && self.spool.path.exists() {
+                    fs::rename(&self.spool.path, &sending).map_err(spool_error)?;
+                }
+                let (messages, unreadable) = read_spool(&sending).map_err(spool_error)?;
+                if !unreadable.is_empty() {
+                    self.quarantine(&unreadable, &messages).map_err(spool_error)?;
+                }
+                messages
+            };
+            if messages.is_empty() {
+                let _ = fs::remove_file(&sending);
+                continue;
+            }
+            delivered += self.deliver(messages, &sending).await?;
+        }
+        Ok(delivered)
+    }
+
+    /// Move unreadable entries of the claimed file to the quarantine file, leaving the messages
+    fn quarantine(&self, unreadable: &[Vec<u8>], messages: &[Message]) -> io::Result<()> {
+        let path = self.quarantine_path();
+        warn!("Moving {} unreadable entries of {} to {}", unreadable.len(), self.spool.path.display(), path.display());
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        for entry in unreadable {
+            file.write_all(entry)?;
+            if !entry.ends_with(b"\r") {
+                file.write_all(b"\r")?;
+            }
+        }
+        file.sync_all()?;
+        write_spool(&self.sending_path(), messages)
+    }
+
+    /// Deliver claimed messages, leaving any that fail in the claimed file
+    async fn deliver(&self, messages: Vec<Message>, sending: &Path) -> Result<usize, HL7Error> {
+        let count = messages.len();
+        if let Destination::File(sink) = &self.spool.destination {
+            let path = sink.write_batch(&BatchFile::new(messages)).map_err(|e| {
+                HL7Error::DeliveryError(format!("Failed to write to {}: {}", sink.directory().display(), e))
+            })?;
+            info!("Delivered {} scheduled messages in {}", count, path.display());
+        } else {
+            for (index, message) in messages.iter().enumerate() {
+                if let Err(e) = self.spool.destination.send(message).await {
+                    // Keep the rest in order for the next window
+                    write_spool(sending, &messages[index..]).map_err(|e| {
+                        HL7Error::DeliveryError(format!("Failed to update {}: {}", sending.display(), e))
+                    })?;
+                    return Err(HL7Error::DeliveryError(format!(
+                        "Delivered {} of {} scheduled messages to {:?}: {}",
+                        index, count, self.spool.destination, e
+                    )));
+                }
+            }
+            info!("Delivered {} scheduled messages to {:?}", count, self.spool.destination);
+        }
+        fs::remove_file(sending)
+            .map_err(|e| HL7Error::DeliveryError(format!("Failed to remove {}: {}", sending.display(), e)))?;
+        Ok(count)
+    }
+
+    /// Deliver at each window of the schedule until the task is aborted
+    ///
+    /// Failures are logged, and the messages retried at the next window.
+    /// Aborting the task doesn't cut short a delivery that has started.
+    pub fn spawn(&self) -> tokio::task::JoinHandle<()> {
+        let sink = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let now = sink.clock.now().with_timezone(&Local);
+                let next = sink.spool.schedule.next_after(now);
+                tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+                let delivery = {
+                    let sink = sink.clone();
+                    tokio::spawn(async move { sink.deliver_now().await })
+                };
+                let failure = match delivery.await {
+                    Ok(result) => result.err().map(|e| e.to_string()),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(e) = failure {
+                    error!("Scheduled delivery from {} failed: {}", sink.spool.path.display(), e);
+                }
+            }
+        })
+    }
+
+    fn sending_path(&self) -> PathBuf {
+        self.spool_file("sending")
+    }
+
+    fn spool_file(&self, suffix: &str) -> PathBuf {
+        let name = self.spool.path.file_name().unwrap_or_default().to_string_lossy();
+        self.spool.path.with_file_name(format!("{}.{}", name, suffix))
+    }
+}
+
+/// Read the messages in a spool file, or none if it doesn't exist, with the entries that aren't messages
+fn read_spool(path: &Path) -> io::Result<(Vec<Message>, Vec<Vec<u8>>)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(e),
+    };
+    let mut messages = Vec::new();
+    let mut unreadable = Vec::new();
+    for entry in spool_entries(&bytes) {
+        let parsed = std::str::from_utf8(entry)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Message::parse(text.trim_end_matches(['\r', '\n'])).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(message) => messages.push(message),
+            Err(e) => {
+                warn!("Unreadable entry in spool {}: {}", path.display(), e);
+                unreadable.push(entry.to_vec());
+            }
+        }
+    }
+    Ok((messages, unreadable))
+}
+
+/// Split a spool file's bytes before each MSH segment, leaving out blank entries
+fn spool_entries(bytes: &[u8]) -> Vec<&[u8]> {
+    let starts = (0..bytes.len())
+        .filter(|&i| (i == 0 || matches!(bytes[i - 1], b'\r' | b'\n')) && bytes[i..].starts_with(b"MSH"))
+        .chain([bytes.len()]);
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for end in starts {
+        let entry = &bytes[start..end];
+        if !entry.iter().all(u8::is_ascii_whitespace) {
+            entries.push(entry);
+        }
+        start = end;
+    }
+    entries
+}
+
+/// Replace a spool file's contents under a temporary name, so a crash never loses them
+fn write_spool(path: &Path, messages: &[Message]) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp)?;
+    for message in messages {
+        file.write_all(message.to_hl7().as_bytes())?;
+        file.write_all(b"\r")?;
+    }
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
@@ -0,0 +1,295 @@
+/// The description of a segment, e.g. "Patient Identification" for PID
+pub fn segment_name(segment: &str) -> Option<&'static str> {
+    let name = match segment {
+        "MSH" => "Message Header",
+        "MSA" => "Message Acknowledgment",
+        "ERR" => "Error",
+        "EVN" => "Event Type",
+        "PID" => "Patient Identification",
+        "PD1" => "Patient Additional Demographic",
+        "NK1" => "Next of Kin / Associated Parties",
+        "PV1" => "Patient Visit",
+        "PV2" => "Patient Visit - Additional Information",
+        "MRG" => "Merge Patient Information",
+        "AL1" => "Patient Allergy Information",
+        "DG1" => "Diagnosis",
+        "IN1" => "Insurance",
+        "ORC" => "Common Order",
+        "OBR" => "Observation Request",
+        "OBX" => "Observation/Result",
+        "NTE" => "Notes and Comments",
+        "SPM" => "Specimen",
+        "RXE" => "Pharmacy/Treatment Encoded Order",
+        "RXR" => "Pharmacy/Treatment Route",
+        "FT1" => "Financial Transaction",
+        "QPD" => "Query Parameter Definition",
+        "RCP" => "Response Control Parameter",
+        "QAK" => "Query Acknowledgment",
+        "DSC" => "Continuation Pointer",
+        "ADD" => "Addendum",
+        "FHS" => "File Header",
+        "FTS" => "File Trailer",
+        "BHS" => "Batch Header",
+        "BTS" => "Batch Trailer",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The name of a field by its 1-based number, e.g. "Patient Name" for PID-5
+///
+/// Names are from HL7 v2.5 and label values for people, e.g. in
+/// `Message::pretty_print`, so only the segments this crate works with are
+/// covered rather than the whole standard.
+///
+/// ```
+/// use rust_hl7::schema::field_name;
+///
+/// assert_eq!(field_name("PID", 5), Some("Patient Name"));
+/// assert_eq!(field_name("MSH", 1), Some("Field Separator"));
+/// assert_eq!(field_name("ZPI", 1), None);
+/// ```
+pub fn field_name(segment: &str, field: usize) -> Option<&'static str> {
+    let fields: &[&str] = match segment {
+        "MSH" => MSH,
+        "FHS" | "BHS" => &[
+            "Field Separator",
+            "Encoding Characters",
+            "Sending Application",
+            "Sending Facility",
+            "Receiving Application",
+            "Receiving Facility",
+            "Creation Date/Time",
+            "Security",
+            "Name/ID/Type",
+            "Comment",
+            "Control ID",
+            "Reference Control ID",
+        ],
+        "MSA" => &[
+            "Acknowledgment Code",
+            "Message Control ID",
+            "Text Message",
+            "Expected Sequence Number",
+            "Delayed Acknowledgment Type",
+            "Error Condition",
+        ],
+        "ERR" => &[
+            "Error Code and Location",
+            "Error Location",
+            "HL7 Error Code",
+            "Severity",
+            "Application Error Code",
+            "Application Error Parameter",
+            "Diagnostic Information",
+            "User Message",
+        ],
+        "EVN" => &[
+            "Event Type Code",
+            "Recorded Date/Time",
+            "Date/Time Planned Event",
+            "Event Reason Code",
+            "Operator ID",
+            "Event Occurred",
+            "Event Facility",
+        ],
+        "PID" => PID,
+        "NK1" => &[
+            "Set ID",
+            "Name",
+            "Relationship",
+            "Address",
+            "Phone Number",
+            "Business Phone Number",
+            "Contact Role",
+            "Start Date",
+            "End Date",
+        ],
+        "PV1" => PV1,
+        "MRG" => &[
+            "Prior Patient Identifier List",
+            "Prior Alternate Patient ID",
+            "Prior Patient Account Number",
+            "Prior Patient ID",
+            "Prior Visit Number",
+            "Prior Alternate Visit ID",
+            "Prior Patient Name",
+        ],
+        "AL1" => &[
+            "Set ID",
+            "Allergen Type Code",
+            "Allergen Code/Mnemonic/Description",
+            "Allergy Severity Code",
+            "Allergy Reaction Code",
+            "Identification Date",
+        ],
+        "DG1" => &[
+            "Set ID",
+            "Diagnosis Coding Method",
+            "Diagnosis Code",
+            "Diagnosis Description",
+            "Diagnosis Date/Time",
+            "Diagnosis Type",
+        ],
+        "ORC" => &[
+            "Order Control",
+            "Placer Order Number",
+            "Filler Order Number",
+            "Placer Group Number",
+            "Order Status",
+            "Response Flag",
+            "Quantity/Timing",
+            "Parent",
+            "Date/Time of Transaction",
+            "Entered By",
+            "Verified By",
+            "Ordering Provider",
+        ],
+        "OBR" => OBR,
+        "OBX" => OBX,
+        "NTE" => &["Set ID", "Source of Comment", "Comment", "Comment Type"],
+        "RXE" => &[
+            "Quantity/Timing",
+            "Give Code",
+            "Give Amount - Minimum",
+            "Give Amount - Maximum",
+            "Give Units",
+            "Give Dosage Form",
+            "Provider's Administration Instructions",
+        ],
+        "RXR" => &["Route", "Administration Site", "Administration Device", "Administration Method"],
+        "DSC" => &["Continuation Pointer", "Continuation Style"],
+        "BTS" => &["Batch Message Count", "Batch Comment", "Batch Totals"],
+        "FTS" => &["File Batch Count", "File Trailer Comment"],
+        _ => return None,
+    };
+    field.checked_sub(1).and_then(|index| fields.get(index)).copied()
+}
+
+const MSH: &[&str] = &[
+    "Field Separator",
+    "Encoding Characters",
+    "Sending Application",
+    "Sending Facility",
+    "Receiving Application",
+    "Receiving Facility",
+    "Date/Time of Message",
+    "Security",
+    "Message Type",
+    "Message Control ID",
+    "Processing ID",
+    "Version ID",
+    "Sequence Number",
+    "Continuation Pointer",
+    "Accept Acknowledgment Type",
+    "Application Acknowledgment Type",
+    "Country Code",
+    "Character Set",
+    "Principal Language of Message",
+    "Alternate Character Set Handling Scheme",
+    "Message Profile Identifier",
+];
+
+const PID: &[&str] = &[
+    "Set ID",
+    "Patient ID",
+    "Patient Identifier List",
+    "Alternate Patient ID",
+    "Patient Name",
+    "Mother's Maiden Name",
+    "Date/Time of Birth",
+    "Administrative Sex",
+    "Patient Alias",
+    "Race",
+    "Patient Address",
+    "County Code",
+    "Phone Number - Home",
+    "Phone Number - Business",
+    "Primary Language",
+    "Marital Status",
+    "Religion",
+    "Patient Account Number",
+    "SSN Number - Patient",
+    "Driver's License Number - Patient",
+    "Mother's Identifier",
+    "Ethnic Group",
+    "Birth Place",
+    "Multiple Birth Indicator",
+    "Birth Order",
+    "Citizenship",
+    "Veterans Military Status",
+    "Nationality",
+    "Patient Death Date and Time",
+    "Patient Death Indicator",
+];
+
+const PV1: &[&str] = &[
+    "Set ID",
+    "Patient Class",
+    "Assigned Patient Location",
+    "Admission Type",
+    "Preadmit Number",
+    "Prior Patient Location",
+    "Attending Doctor",
+    "Referring Doctor",
+    "Consulting Doctor",
+    "Hospital Service",
+    "Temporary Location",
+    "Preadmit Test Indicator",
+    "Re-admission Indicator",
+    "Admit Source",
+    "Ambulatory Status",
+    "VIP Indicator",
+    "Admitting Doctor",
+    "Patient Type",
+    "Visit Number",
+    "Financial Class",
+];
+
+const OBR: &[&str] = &[
+    "Set ID",
+    "Placer Order Number",
+    "Filler Order Number",
+    "Universal Service Identifier",
+    "Priority",
+    "Requested Date/Time",
+    "Observation Date/Time",
+    "Observation End Date/Time",
+    "Collection Volume",
+    "Collector Identifier",
+    "Specimen Action Code",
+    "Danger Code",
+    "Relevant Clinical Information",
+    "Specimen Received Date/Time",
+    "Specimen Source",
+    "Ordering Provider",
+    "Order Callback Phone Number",
+    "Placer Field 1",
+    "Placer Field 2",
+    "Filler Field 1",
+    "Filler Field 2",
+    "Results Rpt/Status Chng - Date/Time",
+    "Charge to Practice",
+    "Diagnostic Serv Sect ID",
+    "Result Status",
+];
+
+const OBX: &[&str] = &[
+    "Set ID",
+    "Value Type",
+    "Observation Identifier",
+    "Observation Sub-ID",
+    "Observation Value",
+    "Units",
+    "References Range",
+    "Abnormal Flags",
+    "Probability",
+    "Nature of Abnormal Test",
+    "Observation Result Status",
+    "Effective Date of Reference Range",
+    "User Defined Access Checks",
+    "Date/Time of the Observation",
+    "Producer's ID",
+    "Responsible Observer",
+    "Observation Method",
+];
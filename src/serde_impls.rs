@@ -0,0 +1,139 @@
+//! Serialize and Deserialize for the message tree, written out so the parser
+//! core doesn't need serde's derive macros
+//!
+//! The format is the one `#[derive(Serialize, Deserialize)]` gives: a struct
+//! with the fields in declaration order, read from a map or a sequence, with
+//! unknown keys ignored and missing `Option` fields read as `None`.
+
+#[cfg(not(feature = "std"))]
+use crate::prelude::*;
+use crate::adt::AdtMessage;
+use crate::oru::{Observation, OruMessage};
+use crate::rde::{MedicationOrder, RdeMessage};
+use crate::{Component, Components, Field, Message, Segment};
+use core::fmt;
+use serde::de::{self, IntoDeserializer, MapAccess, SeqAccess};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The value of a field that isn't there, which is only allowed for options
+fn missing<'de, T: Deserialize<'de>, E: de::Error>(field: &'static str) -> Result<T, E> {
+    T::deserialize(().into_deserializer()).map_err(|_: de::value::Error| E::missing_field(field))
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Fields after a `;` are only written when they aren't the default, which they take when missing
+macro_rules! impl_serde {
+    ($name:ident { $($field:ident),* $(; $($defaulted:ident),*)? $(,)? }) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let defaulted: &[bool] = &[$($(!is_default(&self.$defaulted)),*)?];
+                let len = [$(stringify!($field)),*].len() + defaulted.iter().filter(|&&set| set).count();
+                let mut state = serializer.serialize_struct(stringify!($name), len)?;
+                $(state.serialize_field(stringify!($field), &self.$field)?;)*
+                $($(if !is_default(&self.$defaulted) {
+                    state.serialize_field(stringify!($defaulted), &self.$defaulted)?;
+                })*)?
+                state.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct Visitor;
+
+                impl<'de> de::Visitor<'de> for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str(concat!("struct ", stringify!($name)))
+                    }
+
+                    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<$name, A::Error> {
+                        let mut read = 0;
+                        $(
+                            let $field = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(read, &self))?;
+                            read += 1;
+                        )*
+                        let _ = read;
+                        $($(let $defaulted = seq.next_element()?.unwrap_or_default();)*)?
+                        Ok($name { $($field,)* $($($defaulted),*)? })
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<$name, A::Error> {
+                        $(let mut $field = None;)*
+                        $($(let mut $defaulted = None;)*)?
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
+                                $(stringify!($field) => {
+                                    if $field.is_some() {
+                                        return Err(de::Error::duplicate_field(stringify!($field)));
+                                    }
+                                    $field = Some(map.next_value()?);
+                                })*
+                                $($(stringify!($defaulted) => {
+                                    if $defaulted.is_some() {
+                                        return Err(de::Error::duplicate_field(stringify!($defaulted)));
+                                    }
+                                    $defaulted = Some(map.next_value()?);
+                                })*)?
+                                _ => {
+                                    map.next_value::<de::IgnoredAny>()?;
+                                }
+                            }
+                        }
+                        $(
+                            let $field = match $field {
+                                Some(value) => value,
+                                None => missing(stringify!($field))?,
+                            };
+                        )*
+                        $($(let $defaulted = $defaulted.unwrap_or_default();)*)?
+                        Ok($name { $($field,)* $($($defaulted),*)? })
+                    }
+                }
+
+                const FIELDS: &[&str] = &[$(stringify!($field),)* $($(stringify!($defaulted)),*)?];
+                deserializer.deserialize_struct(stringify!($name), FIELDS, Visitor)
+            }
+        }
+    };
+}
+
+impl_serde!(Message { segments, message_type, version });
+impl_serde!(Segment { name, fields; raw });
+impl_serde!(Field { components });
+impl_serde!(Component { value, subcomponents });
+
+impl Serialize for Components {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Components {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<Component>::deserialize(deserializer).map(Components::from)
+    }
+}
+
+impl_serde!(AdtMessage { message_type, patient_id, patient_name, date_of_birth, gender, event_type });
+impl_serde!(OruMessage { message_type, patient_id, observations });
+impl_serde!(Observation { test_id, test_name, value, units, reference_range, abnormal_flags });
+impl_serde!(RdeMessage { message_type, patient_id, order_control, order_number, medication_orders });
+impl_serde!(MedicationOrder {
+    rx_id,
+    medication_id,
+    medication_name,
+    strength,
+    form,
+    dosage,
+    frequency,
+    quantity,
+    route,
+    start_date,
+    stop_date,
+});
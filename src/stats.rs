@@ -0,0 +1,194 @@
+use crate::validation::Validator;
+use crate::{terser, Message};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// How many error reasons a report lists
+const TOP_ERRORS: usize = 10;
+
+/// A value and how many messages had it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Count {
+    pub value: String,
+    pub count: usize,
+}
+
+/// Message sizes in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct Sizes {
+    pub min: usize,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize,
+    pub total: usize,
+}
+
+/// Summary of a set of messages
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsReport {
+    /// Messages seen, including those that didn't parse
+    pub messages: usize,
+    /// Messages that didn't parse
+    pub unparseable: usize,
+    /// Counts by message code (MSH-9.1), most common first
+    pub message_types: Vec<Count>,
+    /// Counts by message code and trigger event (MSH-9.1^MSH-9.2)
+    pub events: Vec<Count>,
+    /// Counts by sending facility (MSH-4.1)
+    pub sending_facilities: Vec<Count>,
+    pub sizes: Sizes,
+    /// Earliest MSH-7, as sent
+    pub first_timestamp: Option<String>,
+    /// Latest MSH-7, as sent
+    pub last_timestamp: Option<String>,
+    /// Messages with validation errors; `None` when validation wasn't enabled
+    pub invalid: Option<usize>,
+    /// The most common parse and validation errors
+    pub top_errors: Vec<Count>,
+}
+
+/// Accumulates counts, sizes and timestamp ranges over a feed
+///
+/// ```
+/// use rust_hl7::stats::Stats;
+///
+/// let mut stats = Stats::new();
+/// stats.add("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20230401123000||ORU^R01|MSG1|P|2.5");
+/// stats.add("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20230402090000||ORU^R01|MSG2|P|2.5");
+/// let report = stats.report();
+/// assert_eq!(report.events[0].count, 2);
+/// assert_eq!(report.last_timestamp.as_deref(), Some("20230402090000"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    validator: Option<Validator>,
+    messages: usize,
+    unparseable: usize,
+    invalid: usize,
+    message_types: HashMap<String, usize>,
+    events: HashMap<String, usize>,
+    sending_facilities: HashMap<String, usize>,
+    sizes: Vec<usize>,
+    first_timestamp: Option<(String, String)>,
+    last_timestamp: Option<(String, String)>,
+    errors: HashMap<String, usize>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validate each message and count the reasons messages fail
+    pub fn validate(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Count one message
+    pub fn add(&mut self, text: &str) {
+        self.messages += 1;
+        self.sizes.push(text.len());
+
+        let message = match Message::parse(text) {
+            Ok(message) => message,
+            Err(e) => {
+                self.unparseable += 1;
+                *self.errors.entry(format!("message: {}", e)).or_default() += 1;
+                return;
+            }
+        };
+
+        let value = |path: &str| terser::get(&message, path).filter(|v| !v.is_empty());
+        let code = value("MSH-9.1").unwrap_or_else(|| "(none)".to_string());
+        let event = match value("MSH-9.2") {
+            Some(trigger) => format!("{}^{}", code, trigger),
+            None => code.clone(),
+        };
+        *self.message_types.entry(code).or_default() += 1;
+        *self.events.entry(event).or_default() += 1;
+        *self.sending_facilities.entry(value("MSH-4.1").unwrap_or_else(|| "(none)".to_string())).or_default() += 1;
+
+        if let Some(timestamp) = value("MSH-7.1") {
+            // Compare the digits only, so an offset doesn't affect the ordering
+            let key = timestamp.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect::<String>();
+            if self.first_timestamp.as_ref().is_none_or(|(first, _)| key < *first) {
+                self.first_timestamp = Some((key.clone(), timestamp.clone()));
+            }
+            if self.last_timestamp.as_ref().is_none_or(|(last, _)| key > *last) {
+                self.last_timestamp = Some((key, timestamp));
+            }
+        }
+
+        if let Some(validator) = &self.validator {
+            let mut failed = false;
+            for issue in validator.validate(&message).into_iter().filter(|i| i.is_error()) {
+                failed = true;
+                *self.errors.entry(reason(&issue.location, &issue.message)).or_default() += 1;
+            }
+            if failed {
+                self.invalid += 1;
+            }
+        }
+    }
+
+    /// Summarize the messages counted so far
+    pub fn report(&self) -> StatsReport {
+        let mut sizes = self.sizes.clone();
+        sizes.sort_unstable();
+        let percentile = |p: usize| match sizes.len() {
+            0 => 0,
+            n => sizes[((n * p).div_ceil(100)).clamp(1, n) - 1],
+        };
+
+        let mut top_errors = ranked(&self.errors);
+        top_errors.truncate(TOP_ERRORS);
+
+        StatsReport {
+            messages: self.messages,
+            unparseable: self.unparseable,
+            message_types: ranked(&self.message_types),
+            events: ranked(&self.events),
+            sending_facilities: ranked(&self.sending_facilities),
+            sizes: Sizes {
+                min: sizes.first().copied().unwrap_or_default(),
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max: sizes.last().copied().unwrap_or_default(),
+                total: sizes.iter().sum(),
+            },
+            first_timestamp: self.first_timestamp.as_ref().map(|(_, t)| t.clone()),
+            last_timestamp: self.last_timestamp.as_ref().map(|(_, t)| t.clone()),
+            invalid: self.validator.as_ref().map(|_| self.invalid),
+            top_errors,
+        }
+    }
+}
+
+/// Counts ordered from most to least common, then by value
+fn ranked(counts: &HashMap<String, usize>) -> Vec<Count> {
+    let mut ranked: Vec<Count> = counts
+        .iter()
+        .map(|(value, count)| Count { value: value.clone(), count: *count })
+        .collect();
+    ranked.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    ranked
+}
+
+/// An issue with the segment repetition and quoted values left out, so that
+/// `OBX(2)-5: 'high' is not numeric` and `OBX-5: 'low' is not numeric` count as one reason
+fn reason(location: &str, message: &str) -> String {
+    let location = match (location.find('('), location.find(')')) {
+        (Some(open), Some(close)) if open < close => format!("{}{}", &location[..open], &location[close + 1..]),
+        _ => location.to_string(),
+    };
+    let message = message
+        .split('\'')
+        .enumerate()
+        .map(|(index, part)| if index % 2 == 1 { "..." } else { part })
+        .collect::<Vec<_>>()
+        .join("'");
+    format!("{}: {}", location, message)
+}
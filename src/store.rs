@@ -0,0 +1,566 @@
+use crate::{charset, terser, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Errors that can occur reading or writing the message archive
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+
+    #[error("Invalid stored value: {0}")]
+    InvalidValue(String),
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+
+    #[error("Blob store error: {0}")]
+    BlobError(String),
+}
+
+/// Whether a message was received by or sent from this engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// What happened to an archived message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    /// Processed and positively acknowledged
+    Accepted,
+    /// Rejected by a handler or middleware (AR)
+    Rejected,
+    /// Failed to parse or process (AE)
+    Failed,
+    /// Sent to another system, including ACKs sent back to senders
+    Sent,
+}
+
+impl Direction {
+    /// Name used when storing the direction
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+
+    /// Parse a stored direction name
+    pub fn parse(value: &str) -> Result<Self, StoreError> {
+        match value {
+            "inbound" => Ok(Direction::Inbound),
+            "outbound" => Ok(Direction::Outbound),
+            _ => Err(StoreError::InvalidValue(format!("direction '{}'", value))),
+        }
+    }
+}
+
+impl Disposition {
+    /// Name used when storing the disposition
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Disposition::Accepted => "accepted",
+            Disposition::Rejected => "rejected",
+            Disposition::Failed => "failed",
+            Disposition::Sent => "sent",
+        }
+    }
+
+    /// Parse a stored disposition name
+    pub fn parse(value: &str) -> Result<Self, StoreError> {
+        match value {
+            "accepted" => Ok(Disposition::Accepted),
+            "rejected" => Ok(Disposition::Rejected),
+            "failed" => Ok(Disposition::Failed),
+            "sent" => Ok(Disposition::Sent),
+            _ => Err(StoreError::InvalidValue(format!("disposition '{}'", value))),
+        }
+    }
+}
+
+/// A message and its metadata as kept in the archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+    /// Assigned by the store when the record is inserted
+    pub id: Option<i64>,
+    /// The message exactly as received or sent
+    pub raw: Vec<u8>,
+    pub direction: Direction,
+    pub disposition: Disposition,
+    /// Message type, e.g. "ORU^R01"
+    pub message_type: Option<String>,
+    /// MSH-10
+    pub control_id: Option<String>,
+    /// PID-3.1
+    pub patient_id: Option<String>,
+    /// MSH-3.1
+    pub sending_application: Option<String>,
+    /// MSH-4.1
+    pub sending_facility: Option<String>,
+    /// Peer address or other description of where the message came from or went to
+    pub source: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl ArchiveRecord {
+    /// Build a record from raw bytes, extracting metadata if the message parses
+    pub fn new(raw: &[u8], direction: Direction, disposition: Disposition) -> Self {
+        let parsed = charset::detect(raw)
+            .unwrap_or_default()
+            .decode(raw)
+            .ok()
+            .and_then(|s| Message::parse(&s).ok());
+
+        let mut record = ArchiveRecord {
+            id: None,
+            raw: raw.to_vec(),
+            direction,
+            disposition,
+            message_type: None,
+            control_id: None,
+            patient_id: None,
+            sending_application: None,
+            sending_facility: None,
+            source: None,
+            timestamp: Utc::now(),
+        };
+
+        if let Some(message) = parsed {
+            let value = |path: &str| terser::get(&message, path).filter(|v| !v.is_empty());
+            record.message_type = Some(message.message_type.clone());
+            let header = |value: Option<&str>| value.filter(|v| !v.is_empty()).map(str::to_string);
+            record.control_id = header(message.control_id());
+            record.patient_id = value("PID-3.1");
+            record.sending_application = header(message.sending_application());
+            record.sending_facility = header(message.sending_facility());
+        }
+
+        record
+    }
+
+    /// Build a record from a parsed message
+    pub fn from_message(message: &Message, direction: Direction, disposition: Disposition) -> Self {
+        Self::new(message.to_hl7().as_bytes(), direction, disposition)
+    }
+
+    /// Set where the message came from or went to
+    pub fn with_source<S: ToString>(mut self, source: S) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+}
+
+/// Criteria for finding archived messages; unset criteria match everything
+///
+/// ```ignore
+/// // All ORU messages for MRN 12345 in the last 24 hours
+/// let query = Query::new()
+///     .message_type("ORU")
+///     .patient_id("12345")
+///     .since(Utc::now() - chrono::Duration::hours(24));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    /// Matches message types starting with this, so "ORU" matches "ORU^R01"
+    pub message_type: Option<String>,
+    pub control_id: Option<String>,
+    pub patient_id: Option<String>,
+    pub sending_application: Option<String>,
+    pub direction: Option<Direction>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// A query matching every message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only messages whose type starts with this, e.g. "ORU" or "ADT^A08"
+    pub fn message_type<S: ToString>(mut self, message_type: S) -> Self {
+        self.message_type = Some(message_type.to_string());
+        self
+    }
+
+    /// Only messages with this control ID
+    pub fn control_id<S: ToString>(mut self, control_id: S) -> Self {
+        self.control_id = Some(control_id.to_string());
+        self
+    }
+
+    /// Only messages about this patient
+    pub fn patient_id<S: ToString>(mut self, patient_id: S) -> Self {
+        self.patient_id = Some(patient_id.to_string());
+        self
+    }
+
+    /// Only messages from this sending application
+    pub fn sending_application<S: ToString>(mut self, application: S) -> Self {
+        self.sending_application = Some(application.to_string());
+        self
+    }
+
+    /// Only inbound or outbound messages
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Only messages archived at or after this time
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only messages archived before this time
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Return at most this many messages
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Check a record against the criteria
+    pub fn matches(&self, record: &ArchiveRecord) -> bool {
+        let equals = |wanted: &Option<String>, actual: &Option<String>| {
+            wanted.as_ref().is_none_or(|w| actual.as_ref() == Some(w))
+        };
+
+        self.message_type.as_ref().is_none_or(|t| {
+            record.message_type.as_ref().is_some_and(|m| m.starts_with(t.as_str()))
+        }) && equals(&self.control_id, &record.control_id)
+            && equals(&self.patient_id, &record.patient_id)
+            && equals(&self.sending_application, &record.sending_application)
+            && self.direction.is_none_or(|d| d == record.direction)
+            && self.since.is_none_or(|s| record.timestamp >= s)
+            && self.until.is_none_or(|u| record.timestamp < u)
+    }
+}
+
+/// Persistent storage for received and sent messages
+pub trait MessageStore: Send + Sync {
+    /// Archive a record, returning its assigned ID
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError>;
+
+    /// Find records matching the query, oldest first
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError>;
+
+    /// Delete records archived before the cutoff, returning how many were removed
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError>;
+
+    /// Delete the oldest records beyond a number of records or total size of raw
+    /// messages, returning how many were removed
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        let _ = (max_count, max_bytes);
+        Err(StoreError::InvalidValue("this store can't limit its size".to_string()))
+    }
+
+    /// Whether deleted records are overwritten on disk rather than just unlinked
+    ///
+    /// Stores that don't write to disk have nothing to overwrite.
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        let _ = secure_delete;
+        Ok(())
+    }
+
+    /// Rebuild the store's files so deleted records leave nothing behind in free pages or journals
+    ///
+    /// Retention runs it after a secure delete. Stores that don't write to disk have nothing to rebuild.
+    fn vacuum(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Check that records could be archived right now, without archiving anything
+    fn check_writable(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Periodically delete records older than `retention`
+pub fn spawn_pruning(
+    store: Arc<dyn MessageStore>,
+    retention: chrono::Duration,
+    interval: std::time::Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match store.prune(Utc::now() - retention) {
+                Ok(0) => {}
+                Ok(count) => info!("Pruned {} archived messages", count),
+                Err(e) => warn!("Failed to prune message archive: {}", e),
+            }
+        }
+    })
+}
+
+/// Store that keeps records in memory, for tests and short-lived tools
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<Vec<ArchiveRecord>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        let mut records = self.records.lock().unwrap();
+        let id = records.last().and_then(|r| r.id).unwrap_or(0) + 1;
+        let mut record = record.clone();
+        record.id = Some(id);
+        records.push(record);
+        Ok(id)
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        let records = self.records.lock().unwrap();
+        Ok(records
+            .iter()
+            .filter(|r| query.matches(r))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut records = self.records.lock().unwrap();
+        let count = records.len();
+        records.retain(|r| r.timestamp >= before);
+        Ok(count - records.len())
+    }
+
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        let mut records = self.records.lock().unwrap();
+        let mut newest: Vec<usize> = (0..records.len()).collect();
+        newest.sort_by(|&a, &b| records[b].timestamp.cmp(&records[a].timestamp).then(b.cmp(&a)));
+
+        let mut excess = vec![false; records.len()];
+        let mut kept_bytes = 0;
+        for (rank, index) in newest.into_iter().enumerate() {
+            kept_bytes += records[index].raw.len() as u64;
+            excess[index] = max_count.is_some_and(|count| rank >= count) || max_bytes.is_some_and(|bytes| kept_bytes > bytes);
+        }
+        let count = records.len();
+        let mut excess = excess.into_iter();
+        records.retain(|_| !excess.next().unwrap_or_default());
+        Ok(count - records.len())
+    }
+}
+
+/// Store backed by a SQLite database file
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Open (or create) an archive database at the given path
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, StoreError> {
+        Self::init(rusqlite::Connection::open(path)?)
+    }
+
+    /// Create an archive in a private in-memory database
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn init(connection: rusqlite::Connection) -> Result<Self, StoreError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                raw BLOB NOT NULL,
+                direction TEXT NOT NULL,
+                disposition TEXT NOT NULL,
+                message_type TEXT,
+                control_id TEXT,
+                patient_id TEXT,
+                sending_application TEXT,
+                sending_facility TEXT,
+                source TEXT,
+                timestamp_ms INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp_ms);
+            CREATE INDEX IF NOT EXISTS messages_patient ON messages (patient_id, timestamp_ms);
+            CREATE INDEX IF NOT EXISTS messages_control_id ON messages (control_id);",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn read_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<(ArchiveRecord, String, String)> {
+        let timestamp_ms: i64 = row.get("timestamp_ms")?;
+        let record = ArchiveRecord {
+            id: Some(row.get("id")?),
+            raw: row.get("raw")?,
+            // Filled in by the caller, which can report invalid values as StoreError
+            direction: Direction::Inbound,
+            disposition: Disposition::Accepted,
+            message_type: row.get("message_type")?,
+            control_id: row.get("control_id")?,
+            patient_id: row.get("patient_id")?,
+            sending_application: row.get("sending_application")?,
+            sending_facility: row.get("sending_facility")?,
+            source: row.get("source")?,
+            timestamp: DateTime::from_timestamp_millis(timestamp_ms).unwrap_or_default(),
+        };
+        Ok((record, row.get("direction")?, row.get("disposition")?))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl MessageStore for SqliteStore {
+    fn insert(&self, record: &ArchiveRecord) -> Result<i64, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO messages (raw, direction, disposition, message_type, control_id, patient_id,
+                sending_application, sending_facility, source, timestamp_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                record.raw,
+                record.direction.as_str(),
+                record.disposition.as_str(),
+                record.message_type,
+                record.control_id,
+                record.patient_id,
+                record.sending_application,
+                record.sending_facility,
+                record.source,
+                record.timestamp.timestamp_millis(),
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    fn query(&self, query: &Query) -> Result<Vec<ArchiveRecord>, StoreError> {
+        use rusqlite::types::Value;
+
+        let mut sql = "SELECT * FROM messages WHERE 1 = 1".to_string();
+        let mut params: Vec<Value> = Vec::new();
+
+        let mut filter = |clause: &str, value: Value| {
+            params.push(value);
+            sql.push_str(&format!(" AND {} ?{}", clause, params.len()));
+            if clause.ends_with("LIKE") {
+                sql.push_str(" ESCAPE '\\'");
+            }
+        };
+
+        if let Some(message_type) = &query.message_type {
+            // Prefix match, escaping any wildcards in the type itself
+            let escaped = message_type.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            filter("message_type LIKE", Value::Text(format!("{}%", escaped)));
+        }
+        if let Some(control_id) = &query.control_id {
+            filter("control_id =", Value::Text(control_id.clone()));
+        }
+        if let Some(patient_id) = &query.patient_id {
+            filter("patient_id =", Value::Text(patient_id.clone()));
+        }
+        if let Some(application) = &query.sending_application {
+            filter("sending_application =", Value::Text(application.clone()));
+        }
+        if let Some(direction) = query.direction {
+            filter("direction =", Value::Text(direction.as_str().to_string()));
+        }
+        if let Some(since) = query.since {
+            filter("timestamp_ms >=", Value::Integer(since.timestamp_millis()));
+        }
+        if let Some(until) = query.until {
+            filter("timestamp_ms <", Value::Integer(until.timestamp_millis()));
+        }
+
+        sql.push_str(" ORDER BY timestamp_ms, id");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement.query_map(rusqlite::params_from_iter(params), Self::read_row)?;
+
+        rows.map(|row| {
+            let (mut record, direction, disposition) = row?;
+            record.direction = Direction::parse(&direction)?;
+            record.disposition = Disposition::parse(&disposition)?;
+            Ok(record)
+        })
+        .collect()
+    }
+
+    fn prune(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.execute(
+            "DELETE FROM messages WHERE timestamp_ms < ?1",
+            rusqlite::params![before.timestamp_millis()],
+        )?)
+    }
+
+    fn prune_excess(&self, max_count: Option<usize>, max_bytes: Option<u64>) -> Result<usize, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut deleted = 0;
+        if let Some(count) = max_count {
+            deleted += connection.execute(
+                "DELETE FROM messages WHERE id IN
+                    (SELECT id FROM messages ORDER BY timestamp_ms DESC, id DESC LIMIT -1 OFFSET ?1)",
+                rusqlite::params![count as i64],
+            )?;
+        }
+        if let Some(bytes) = max_bytes {
+            deleted += connection.execute(
+                "DELETE FROM messages WHERE id IN
+                    (SELECT id FROM (SELECT id, SUM(length(raw)) OVER (ORDER BY timestamp_ms DESC, id DESC) AS total
+                                     FROM messages)
+                     WHERE total > ?1)",
+                rusqlite::params![bytes.min(i64::MAX as u64) as i64],
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn set_secure_delete(&self, secure_delete: bool) -> Result<(), StoreError> {
+        // SQLite overwrites deleted content with zeros itself
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.pragma_update(None, "secure_delete", secure_delete)?)
+    }
+
+    fn vacuum(&self) -> Result<(), StoreError> {
+        // Secure delete zeroes freed pages, but VACUUM also drops them and the
+        // checkpoint clears copies of deleted rows left in the write-ahead log
+        let connection = self.connection.lock().unwrap();
+        connection.execute_batch("VACUUM")?;
+        Ok(connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?)
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        // Take the write lock and let it go again
+        let connection = self.connection.lock().unwrap();
+        let result = connection.execute_batch("BEGIN IMMEDIATE; DELETE FROM messages WHERE id IS NULL;");
+        if !connection.is_autocommit() {
+            connection.execute_batch("ROLLBACK")?;
+        }
+        Ok(result?)
+    }
+}
@@ -0,0 +1,354 @@
+//! Mapping local codes to standard code systems
+//!
+//! A `CodeMap` holds mappings such as local lab codes to LOINC or local
+//! medication codes to RxNorm, loaded from CSV or a FHIR ConceptMap. The
+//! `MapCodes` transform rewrites coded fields (OBX-3 and RXE-2 unless told
+//! otherwise) with the standard code, keeping the local code as the
+//! alternate coding, and records the codes it couldn't map in an
+//! `UnmappedCodes` report for someone to review.
+//!
+//! ```
+//! use rust_hl7::terminology::{CodeMap, MapCodes};
+//! use rust_hl7::transform::Transform;
+//! use rust_hl7::{terser, Message};
+//! use std::sync::Arc;
+//!
+//! let map = CodeMap::from_csv("source_system,source_code,target_system,target_code,target_display\n\
+//!     L,GLU,LN,2345-7,Glucose SerPl-mCnc\n").unwrap();
+//! let step = MapCodes::new(Arc::new(map));
+//! let mut message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rOBX|1|NM|GLU^Glucose^L||98|mg/dL").unwrap();
+//! step.apply(&mut message).unwrap();
+//! assert_eq!(terser::get(&message, "OBX-3").unwrap(), "2345-7^Glucose SerPl-mCnc^LN^GLU^Glucose^L");
+//! ```
+
+use crate::terser::TerserPath;
+use crate::transform::Transform;
+use crate::{terser, Delimiters, HL7Error, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// FHIR system URI for an HL7 v2 coding system name (HL7 table 0396)
+pub fn code_system(name: &str) -> Option<&'static str> {
+    match name {
+        "LN" => Some("http://loinc.org"),
+        "SCT" | "SNM" => Some("http://snomed.info/sct"),
+        "UCUM" => Some("http://unitsofmeasure.org"),
+        "I10" | "I10C" => Some("http://hl7.org/fhir/sid/icd-10"),
+        "RXNORM" => Some("http://www.nlm.nih.gov/research/umls/rxnorm"),
+        "CVX" => Some("http://hl7.org/fhir/sid/cvx"),
+        "NDC" => Some("http://hl7.org/fhir/sid/ndc"),
+        _ => None,
+    }
+}
+
+/// HL7 coding system name for a FHIR system URI, the inverse of `code_system`
+pub fn system_name(uri: &str) -> Option<&'static str> {
+    ["LN", "SCT", "UCUM", "I10", "RXNORM", "CVX", "NDC"]
+        .into_iter()
+        .find(|name| code_system(name) == Some(uri))
+}
+
+/// A code in a code system, as in the first three components of a CWE
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coding {
+    pub code: String,
+    #[serde(default)]
+    pub display: String,
+    /// The HL7 coding system name, e.g. "LN"
+    #[serde(default)]
+    pub system: String,
+}
+
+impl Coding {
+    pub fn new<C: ToString, D: ToString, S: ToString>(code: C, display: D, system: S) -> Self {
+        Self {
+            code: code.to_string(),
+            display: display.to_string(),
+            system: system.to_string(),
+        }
+    }
+}
+
+/// Mappings from local codes to standard ones
+///
+/// A mapping can name the local code system or leave it empty to apply to
+/// the code in any system; one naming the system is preferred.
+#[derive(Debug, Clone, Default)]
+pub struct CodeMap {
+    mappings: HashMap<(String, String), Coding>,
+    /// The systems codes are mapped to
+    targets: BTreeSet<String>,
+}
+
+impl CodeMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map a local code, in `system` or in any system if it's empty
+    pub fn insert<S: ToString, C: ToString>(&mut self, system: S, code: C, target: Coding) {
+        self.targets.insert(target.system.clone());
+        self.mappings.insert((system.to_string(), code.to_string()), target);
+    }
+
+    /// The standard coding for a local code
+    pub fn get(&self, system: &str, code: &str) -> Option<&Coding> {
+        self.mappings
+            .get(&(system.to_string(), code.to_string()))
+            .or_else(|| self.mappings.get(&(String::new(), code.to_string())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Read mappings from CSV with a header row
+    ///
+    /// The columns are found by name: `source_code` and `target_code` are
+    /// required, and `source_system`, `target_system` and `target_display`
+    /// are optional. Values can be quoted, with `""` for a quote inside them.
+    pub fn from_csv(text: &str) -> Result<Self, HL7Error> {
+        let mut rows = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let header = rows.next().map(|(_, line)| split_csv(line)).unwrap_or_default();
+        let column = |name: &str| header.iter().position(|h| h.trim() == name);
+        let required = |name: &str| column(name).ok_or_else(|| HL7Error::ParseError(format!("Code map CSV has no {} column", name)));
+        let (source_code, target_code) = (required("source_code")?, required("target_code")?);
+        let (source_system, target_system, target_display) = (column("source_system"), column("target_system"), column("target_display"));
+
+        let mut map = Self::new();
+        for (number, line) in rows {
+            let values = split_csv(line);
+            let value = |index: Option<usize>| index.and_then(|i| values.get(i)).map_or("", |v| v.trim());
+            let (code, target) = (value(Some(source_code)), value(Some(target_code)));
+            if code.is_empty() || target.is_empty() {
+                return Err(HL7Error::ParseError(format!("Code map CSV line {} is missing a code", number + 1)));
+            }
+            map.insert(value(source_system), code, Coding::new(target, value(target_display), value(target_system)));
+        }
+        Ok(map)
+    }
+
+    /// Read mappings from a FHIR R4 ConceptMap
+    ///
+    /// Each group's source and target systems are given as URIs and turned
+    /// into HL7 coding system names where they're known ones, such as
+    /// `http://loinc.org` into "LN". Mappings from a source system without
+    /// an HL7 name, such as a local one, apply to the code in any system.
+    /// Targets marked `unmatched` or
+    /// `disjoint` aren't mappings and are skipped; otherwise an element's
+    /// first target is used.
+    pub fn from_concept_map(json: &str) -> Result<Self, HL7Error> {
+        let resource: Value = serde_json::from_str(json).map_err(|e| HL7Error::ParseError(format!("Invalid ConceptMap: {}", e)))?;
+        if resource["resourceType"] != "ConceptMap" {
+            return Err(HL7Error::ParseError("Not a ConceptMap resource".to_string()));
+        }
+        let system = |group: &Value, key: &str| {
+            let uri = group[key].as_str().unwrap_or_default();
+            system_name(uri).unwrap_or(uri).to_string()
+        };
+        let mut map = Self::new();
+        for group in resource["group"].as_array().into_iter().flatten() {
+            // Local systems have no HL7 name to match in messages, so their mappings apply to the code in any system
+            let source = group["source"].as_str().and_then(system_name).unwrap_or_default();
+            let target_system = system(group, "target");
+            for element in group["element"].as_array().into_iter().flatten() {
+                let Some(code) = element["code"].as_str() else {
+                    continue;
+                };
+                let target = element["target"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find(|target| !matches!(target["equivalence"].as_str(), Some("unmatched" | "disjoint")));
+                if let Some(target_code) = target.and_then(|target| target["code"].as_str()) {
+                    let display = target.and_then(|target| target["display"].as_str()).unwrap_or_default();
+                    map.insert(source, code, Coding::new(target_code, display, &target_system));
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Load mappings from a `.csv` file or a `.json` ConceptMap
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HL7Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| HL7Error::ParseError(format!("Failed to read {}: {}", path.display(), e)))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Self::from_csv(&text),
+            Some("json") => Self::from_concept_map(&text),
+            _ => Err(HL7Error::ParseError(format!("Unknown code map format: {}", path.display()))),
+        }
+    }
+}
+
+/// Split a CSV line into values, unquoting quoted ones
+fn split_csv(line: &str) -> Vec<String> {
+    let mut values = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let value = values.last_mut().expect("values");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                value.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => values.push(String::new()),
+            c => value.push(c),
+        }
+    }
+    values
+}
+
+/// A code `MapCodes` had no mapping for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnmappedCode {
+    /// Where it was found, e.g. "OBX-3"
+    pub path: String,
+    pub system: String,
+    pub code: String,
+    /// The text the sender gave it, the first time it was seen
+    pub display: String,
+    /// How many times it was seen
+    pub count: u64,
+}
+
+/// The codes a `MapCodes` step couldn't map, for someone to add mappings for
+#[derive(Debug, Default)]
+pub struct UnmappedCodes {
+    codes: Mutex<BTreeMap<(String, String, String), UnmappedCode>>,
+}
+
+impl UnmappedCodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, path: &str, system: &str, code: &str, display: &str) {
+        let mut codes = self.codes.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (path.to_string(), system.to_string(), code.to_string());
+        let entry = codes.entry(key).or_insert_with(|| {
+            warn!(path, system, code, "No mapping for code");
+            UnmappedCode {
+                path: path.to_string(),
+                system: system.to_string(),
+                code: code.to_string(),
+                display: display.to_string(),
+                count: 0,
+            }
+        });
+        entry.count += 1;
+    }
+
+    /// The unmapped codes seen so far, by path, system and code
+    pub fn entries(&self) -> Vec<UnmappedCode> {
+        self.codes.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+    }
+
+    /// Forget the codes seen so far, e.g. once they've been reviewed
+    pub fn clear(&self) {
+        self.codes.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+/// A transform that rewrites coded fields with their standard codes
+///
+/// The standard code, display and system go in the first three components
+/// and the local ones move to the alternate components (4 to 6), with the
+/// local coding system version moving from CWE-7 to CWE-8. The original text
+/// (CWE-9) and any later components are kept. Fields already coded in a
+/// target system, or that already have an alternate coding, are left alone;
+/// every other code without a mapping is recorded in the `UnmappedCodes`
+/// report and left as it was.
+#[derive(Debug, Clone)]
+pub struct MapCodes {
+    map: Arc<CodeMap>,
+    paths: Vec<TerserPath>,
+    unmapped: Arc<UnmappedCodes>,
+}
+
+impl MapCodes {
+    /// Map OBX-3 and RXE-2
+    pub fn new(map: Arc<CodeMap>) -> Self {
+        Self {
+            map,
+            paths: ["OBX-3", "RXE-2"].iter().map(|p| p.parse().expect("valid path")).collect(),
+            unmapped: Arc::new(UnmappedCodes::new()),
+        }
+    }
+
+    /// Map the coded fields at these paths instead, e.g. "OBR-4"
+    pub fn paths<I, S>(mut self, paths: I) -> Result<Self, HL7Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.paths = paths.into_iter().map(|p| p.as_ref().parse()).collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    /// Record unmapped codes in this report, e.g. one shared by several routes
+    pub fn unmapped_to(mut self, unmapped: Arc<UnmappedCodes>) -> Self {
+        self.unmapped = unmapped;
+        self
+    }
+
+    /// The codes this step couldn't map
+    pub fn unmapped(&self) -> Arc<UnmappedCodes> {
+        self.unmapped.clone()
+    }
+}
+
+impl Transform for MapCodes {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        let delimiters = Delimiters::default();
+        for path in &self.paths {
+            let count = message.segments.iter().filter(|s| s.name == path.segment).count();
+            for repetition in 1..=count {
+                let path = TerserPath { repetition, component: None, subcomponent: None, ..path.clone() };
+                let Some(value) = terser::get_path(message, &path) else {
+                    continue;
+                };
+                let components: Vec<&str> = value.split(delimiters.component).collect();
+                let part = |n: usize| components.get(n).copied().unwrap_or_default();
+                let (code, display, system) = (delimiters.unescape(part(0)), part(1), delimiters.unescape(part(2)));
+                if code.is_empty() || (!system.is_empty() && self.map.targets.contains(&system)) {
+                    continue;
+                }
+                let name = format!("{}-{}", path.segment, path.field);
+                match self.map.get(&system, &code) {
+                    // An alternate coding already there would be overwritten
+                    Some(_) if [3, 4, 5, 7].iter().any(|&n| !part(n).is_empty()) => {}
+                    Some(target) => {
+                        let escape = |text: &str| delimiters.escape(text);
+                        // The local code's version moves with it to CWE-8; CWE-9 onward stays put
+                        let mut coded = vec![
+                            escape(&target.code),
+                            escape(&target.display),
+                            escape(&target.system),
+                            part(0).to_string(),
+                            display.to_string(),
+                            part(2).to_string(),
+                            String::new(),
+                            part(6).to_string(),
+                        ];
+                        coded.extend(components.iter().skip(8).map(|c| c.to_string()));
+                        terser::set_path(message, &path, coded.join("^").trim_end_matches('^'))?;
+                    }
+                    None => self.unmapped.record(&name, &system, &code, &delimiters.unescape(display)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(not(feature = "std"))]
+use crate::prelude::*;
+use crate::{Delimiters, HL7Error, Message, Segment};
+use core::str::FromStr;
+
+/// A parsed location of a value within a message, such as `PID-3.1` or `OBX(2)-5`
+///
+/// Field, component and subcomponent numbers are 1-based as in the HL7 spec.
+/// The optional segment repetition in parentheses is also 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerserPath {
+    pub segment: String,
+    pub repetition: usize,
+    pub field: usize,
+    pub component: Option<usize>,
+    pub subcomponent: Option<usize>,
+}
+
+impl FromStr for TerserPath {
+    type Err = HL7Error;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = || HL7Error::ParseError(format!("Invalid terser path: {}", path));
+
+        let (segment_part, rest) = path.trim().split_once('-').ok_or_else(invalid)?;
+
+        // Segment name with optional repetition, e.g. "OBX(2)"
+        let (segment, repetition) = match segment_part.split_once('(') {
+            Some((name, rep)) => {
+                let rep = rep.strip_suffix(')').ok_or_else(invalid)?;
+                (name, rep.parse::<usize>().map_err(|_| invalid())?)
+            }
+            None => (segment_part, 1),
+        };
+
+        if segment.len() != 3 || repetition == 0 {
+            return Err(invalid());
+        }
+
+        // Field, component and subcomponent numbers ("3.1.2" or "3-1-2")
+        let numbers = rest
+            .split(['.', '-'])
+            .map(|n| n.parse::<usize>().ok().filter(|&n| n > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        if numbers.is_empty() || numbers.len() > 3 {
+            return Err(invalid());
+        }
+
+        Ok(TerserPath {
+            segment: segment.to_string(),
+            repetition,
+            field: numbers[0],
+            component: numbers.get(1).copied(),
+            subcomponent: numbers.get(2).copied(),
+        })
+    }
+}
+
+/// Map a 1-based field number to an index into `Segment::fields`
+///
+/// MSH-1 is the field separator itself and isn't stored, so MSH fields are offset by one
+/// more than other segments. Returns None for MSH-1.
+pub fn field_index(segment_name: &str, field: usize) -> Option<usize> {
+    if segment_name == "MSH" {
+        field.checked_sub(2)
+    } else {
+        field.checked_sub(1)
+    }
+}
+
+/// Find the segment a path refers to
+fn find_segment<'a>(message: &'a Message, path: &TerserPath) -> Option<&'a Segment> {
+    message
+        .segments
+        .iter()
+        .filter(|s| s.name == path.segment)
+        .nth(path.repetition - 1)
+}
+
+/// Get the value at a terser path, e.g. `terser::get(&message, "PID-5.1")`
+///
+/// Returns None if the path is invalid or the segment/field doesn't exist.
+pub fn get(message: &Message, path: &str) -> Option<String> {
+    let path = path.parse::<TerserPath>().ok()?;
+    get_path(message, &path)
+}
+
+/// Get the value at an already parsed terser path
+pub fn get_path(message: &Message, path: &TerserPath) -> Option<String> {
+    let delimiters = Delimiters::default();
+    let segment = find_segment(message, path)?;
+
+    // MSH-1 is always the field separator
+    if path.segment == "MSH" && path.field == 1 {
+        return Some(delimiters.field.to_string());
+    }
+
+    let field = segment.fields.get(field_index(&segment.name, path.field)?)?;
+
+    let component = match path.component {
+        Some(component) => field.components.get(component - 1)?,
+        None => return Some(field.to_hl7(&delimiters)),
+    };
+
+    match path.subcomponent {
+        Some(subcomponent) => component
+            .value
+            .split(delimiters.subcomponent)
+            .nth(subcomponent - 1)
+            .map(|s| s.to_string()),
+        None => Some(component.value.clone()),
+    }
+}
+
+/// Set the value at a terser path, e.g. `terser::set(&mut message, "PV1-3.1", "ICU")`
+///
+/// Missing fields, components and subcomponents are added as empty values. The
+/// segment must already exist. When setting a whole field, `^` and `&` in the value
+/// act as delimiters; when setting a component, `&` does.
+pub fn set(message: &mut Message, path: &str, value: &str) -> Result<(), HL7Error> {
+    let path = path.parse::<TerserPath>()?;
+    set_path(message, &path, value)
+}
+
+/// Set the value at an already parsed terser path
+pub fn set_path(message: &mut Message, path: &TerserPath, value: &str) -> Result<(), HL7Error> {
+    let delimiters = Delimiters::default();
+
+    let segment = message
+        .segments
+        .iter_mut()
+        .filter(|s| s.name == path.segment)
+        .nth(path.repetition - 1)
+        .ok_or_else(|| HL7Error::MissingField(format!("{} segment", path.segment)))?;
+
+    let index = field_index(&segment.name, path.field).ok_or_else(|| {
+        HL7Error::InvalidStructure("MSH-1 (field separator) can't be changed".to_string())
+    })?;
+
+    // Pad with empty fields up to the one being set
+    while segment.fields.len() <= index {
+        segment.fields.push(crate::parse_field("", &delimiters));
+    }
+    let field = &mut segment.fields[index];
+
+    match (path.component, path.subcomponent) {
+        (None, _) => *field = crate::parse_field(value, &delimiters),
+        (Some(component), subcomponent) => {
+            while field.components.len() < component {
+                field.components.push(crate::parse_component("", &delimiters));
+            }
+            let target = &mut field.components[component - 1];
+
+            let new_value = match subcomponent {
+                Some(subcomponent) => {
+                    let mut parts: Vec<String> = target
+                        .value
+                        .split(delimiters.subcomponent)
+                        .map(|s| s.to_string())
+                        .collect();
+                    while parts.len() < subcomponent {
+                        parts.push(String::new());
+                    }
+                    parts[subcomponent - 1] = value.to_string();
+                    parts.join(&delimiters.subcomponent.to_string())
+                }
+                None => value.to_string(),
+            };
+
+            *target = crate::parse_component(&new_value, &delimiters);
+        }
+    }
+
+    // Keep the cached header values in sync with MSH-9 and MSH-12
+    if path.segment == "MSH" {
+        message.refresh_header();
+    }
+
+    Ok(())
+}
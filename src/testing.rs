@@ -0,0 +1,411 @@
+use crate::charset;
+use crate::clock::SystemClock;
+use crate::mllp::{extract_mllp_message, generate_nack, generate_response, wrap_in_mllp, MllpClient, MllpError, MllpServer};
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+// Include sample messages and assertion macros
+pub mod fixtures;
+
+// Include proptest strategies for messages and MLLP streams
+#[cfg(feature = "proptest")]
+pub mod strategies;
+
+/// How a `MockEndpoint` answers a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockReply {
+    /// An AA acknowledgment
+    Ack,
+    /// A negative acknowledgment with this MSA-1 code (AE or AR) and text
+    Nack { code: String, text: String },
+    /// This response, sent as-is
+    Raw(String),
+    /// Another reply, after waiting
+    Delay(Duration, Box<MockReply>),
+    /// Half of an acknowledgment frame, then the connection is closed
+    DropMidFrame,
+    /// The connection is closed without a response
+    Close,
+    /// No response; the connection stays open
+    Silence,
+    /// An acknowledgment written with a fault in its framing
+    Faulty(Fault),
+}
+
+impl MockReply {
+    pub fn nack<C: ToString, T: ToString>(code: C, text: T) -> Self {
+        MockReply::Nack {
+            code: code.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Send this reply after waiting `delay`
+    pub fn after(self, delay: Duration) -> Self {
+        MockReply::Delay(delay, Box::new(self))
+    }
+}
+
+/// A way of damaging a frame as it's written, to check that a receiver recovers
+///
+/// After any of these a well-behaved receiver may lose or reject the damaged
+/// message, but must go on to answer the next good frame on the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Write only the first this many bytes of the frame
+    Truncate(usize),
+    /// Write these bytes between the end block and the carriage return ending the frame
+    InjectBeforeCr(Vec<u8>),
+    /// Interleave the frame with another message's frame, this many bytes of each at a time
+    Interleave { other: String, chunk: usize },
+    /// Write this many bytes of the frame, wait, then write the rest
+    Stall { at: usize, pause: Duration },
+}
+
+impl Fault {
+    /// The writes making up a damaged frame, each after an optional pause
+    fn writes(&self, frame: &[u8]) -> Vec<(Option<Duration>, Vec<u8>)> {
+        match self {
+            Fault::Truncate(length) => vec![(None, frame[..(*length).min(frame.len())].to_vec())],
+            Fault::InjectBeforeCr(bytes) => {
+                // Frames end with the end block and a carriage return
+                let (body, cr) = frame.split_at(frame.len() - 1);
+                vec![(None, [body, bytes, cr].concat())]
+            }
+            Fault::Interleave { other, chunk } => {
+                let other = wrap_in_mllp(&charset::detect(other.as_bytes()).unwrap_or_default().encode(other));
+                let chunk = (*chunk).max(1);
+                let mut mixed = Vec::with_capacity(frame.len() + other.len());
+                let (mut left, mut right) = (frame.chunks(chunk), other.chunks(chunk));
+                loop {
+                    match (left.next(), right.next()) {
+                        (None, None) => break,
+                        (l, r) => mixed.extend(l.into_iter().chain(r).flatten()),
+                    }
+                }
+                vec![(None, mixed)]
+            }
+            Fault::Stall { at, pause } => {
+                let (first, rest) = frame.split_at((*at).min(frame.len()));
+                vec![(None, first.to_vec()), (Some(*pause), rest.to_vec())]
+            }
+        }
+    }
+}
+
+/// Write a frame damaged by `fault`
+async fn write_faulty<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8], fault: &Fault) -> std::io::Result<()> {
+    for (pause, bytes) in fault.writes(frame) {
+        if let Some(pause) = pause {
+            stream.flush().await?;
+            tokio::time::sleep(pause).await;
+        }
+        stream.write_all(&bytes).await?;
+    }
+    stream.flush().await
+}
+
+#[derive(Debug)]
+struct MockState {
+    script: VecDeque<MockReply>,
+    default: MockReply,
+    received: Vec<String>,
+    connections: usize,
+}
+
+/// A scriptable MLLP endpoint standing in for a remote system in tests
+///
+/// Each message received takes the next reply queued with `then`, or the
+/// `always` reply (an ACK unless changed) once the queue is empty, so tests can
+/// check how an application copes with slow ACKs, NACKs and connections dropped
+/// mid-frame. The endpoint listens on a loopback port so `MllpClient` and
+/// router destinations can reach it by address; `connect` skips the socket.
+/// It stops when dropped.
+///
+/// ```ignore
+/// let mock = MockEndpoint::start().await?;
+/// mock.then(MockReply::Ack.after(Duration::from_secs(2)))
+///     .then(MockReply::nack("AE", "Database unavailable"))
+///     .then(MockReply::DropMidFrame);
+/// let router = Router::new().route(Route::new("all", Predicate::Always).to(Destination::Mllp(mock.client())));
+/// // ... exercise the application, then
+/// assert_eq!(mock.wait_for(3, Duration::from_secs(5)).await.len(), 3);
+/// ```
+#[derive(Debug)]
+pub struct MockEndpoint {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    notify: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl MockEndpoint {
+    /// Start listening on an unused loopback port
+    pub async fn start() -> Result<Self, MllpError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            script: VecDeque::new(),
+            default: MockReply::Ack,
+            received: Vec::new(),
+            connections: 0,
+        }));
+        let notify = Arc::new(Notify::new());
+
+        let (accept_state, accept_notify) = (state.clone(), notify.clone());
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(answer(socket, accept_state.clone(), accept_notify.clone()));
+            }
+        });
+
+        Ok(Self { address, state, notify, task })
+    }
+
+    /// The address the endpoint listens on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// A client sending to this endpoint
+    pub fn client(&self) -> MllpClient {
+        MllpClient::new(self.address)
+    }
+
+    /// A client connected to this endpoint over an in-memory pipe
+    pub fn connect(&self) -> TestClient<DuplexStream> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(answer(server, self.state.clone(), self.notify.clone()));
+        TestClient::new(client)
+    }
+
+    /// Queue the reply to the next message not yet answered by an earlier `then`
+    pub fn then(&self, reply: MockReply) -> &Self {
+        self.lock().script.push_back(reply);
+        self
+    }
+
+    /// Answer messages with `reply` once the queued replies run out
+    pub fn always(&self, reply: MockReply) -> &Self {
+        self.lock().default = reply;
+        self
+    }
+
+    /// Every message received so far, in order
+    pub fn received(&self) -> Vec<String> {
+        self.lock().received.clone()
+    }
+
+    /// How many connections have been made
+    pub fn connections(&self) -> usize {
+        self.lock().connections
+    }
+
+    /// Wait until at least `count` messages were received, returning what arrived by then
+    ///
+    /// Returns early with fewer messages if `timeout` passes first.
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before checking so a message arriving in between isn't missed
+            let notified = self.notify.notified();
+            let received = self.received();
+            if received.len() >= count {
+                return received;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return self.received();
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockEndpoint {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answer messages on one connection to a mock endpoint as scripted
+async fn answer<S>(mut stream: S, state: Arc<Mutex<MockState>>, notify: Arc<Notify>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    state.lock().unwrap_or_else(|e| e.into_inner()).connections += 1;
+    let mut buffer = BytesMut::with_capacity(4096);
+    loop {
+        let frame = match extract_mllp_message(&mut buffer) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => match stream.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+            Err(_) => return,
+        };
+        let charset = charset::detect(&frame).unwrap_or_default();
+        let message = charset
+            .decode(&frame)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&frame).into_owned());
+
+        let mut reply = {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            state.received.push(message.clone());
+            let default = state.default.clone();
+            state.script.pop_front().unwrap_or(default)
+        };
+        notify.notify_waiters();
+
+        while let MockReply::Delay(delay, next) = reply {
+            tokio::time::sleep(delay).await;
+            reply = *next;
+        }
+        let control_id = message
+            .lines()
+            .next()
+            .and_then(|header| header.split('|').nth(9))
+            .unwrap_or("UNKNOWN");
+        let response = match reply {
+            MockReply::Ack => generate_response(control_id, "Message received", &SystemClock, None),
+            MockReply::Nack { code, text } => generate_nack(&message, &code, &text, &SystemClock, None),
+            MockReply::Raw(response) => Ok(response),
+            MockReply::DropMidFrame => {
+                let ack = generate_response(control_id, "Message received", &SystemClock, None).unwrap_or_default();
+                let frame = wrap_in_mllp(&charset.encode(&ack));
+                let _ = stream.write_all(&frame[..frame.len() / 2]).await;
+                let _ = stream.shutdown().await;
+                return;
+            }
+            MockReply::Close => {
+                let _ = stream.shutdown().await;
+                return;
+            }
+            MockReply::Faulty(fault) => {
+                let ack = generate_response(control_id, "Message received", &SystemClock, None).unwrap_or_default();
+                if write_faulty(&mut stream, &wrap_in_mllp(&charset.encode(&ack)), &fault).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            MockReply::Silence | MockReply::Delay(..) => continue,
+        };
+        let Ok(response) = response else { return };
+        if stream.write_all(&wrap_in_mllp(&charset.encode(&response))).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A client holding one MLLP connection open, for driving servers in tests
+///
+/// Unlike `MllpClient`, which connects for each message, every `send` goes over
+/// the same connection, and `send_raw` and `send_faulty` can write bytes that
+/// aren't a whole frame. `in_memory` connects to an `MllpServer` without a socket.
+///
+/// ```ignore
+/// let server = MllpServer::new("127.0.0.1:0", handler);
+/// let mut client = TestClient::in_memory(&server);
+/// let ack = client.send("MSH|^~\\&|REG|HOSP|EHR|HOSP|20230401||ADT^A01|1|P|2.5").await?;
+/// ```
+#[derive(Debug)]
+pub struct TestClient<S> {
+    stream: S,
+    buffer: BytesMut,
+    timeout: Duration,
+}
+
+impl TestClient<DuplexStream> {
+    /// Connect to a server over an in-memory pipe; the server doesn't need to be running
+    pub fn in_memory(server: &MllpServer) -> Self {
+        Self::new(server.connect_in_memory())
+    }
+}
+
+impl TestClient<TcpStream> {
+    /// Connect to a listening server
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(address: A) -> Result<Self, MllpError> {
+        Ok(Self::new(TcpStream::connect(address).await?))
+    }
+}
+
+impl<S> TestClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Use an already connected stream
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::with_capacity(4096),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Set how long `send` and `receive` wait for a response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a framed message and wait for the response
+    pub async fn send(&mut self, message: &str) -> Result<String, MllpError> {
+        let charset = charset::detect(message.as_bytes()).unwrap_or_default();
+        self.send_raw(&wrap_in_mllp(&charset.encode(message))).await?;
+        self.receive().await
+    }
+
+    /// Write bytes as they are, e.g. half a frame
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<(), MllpError> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Write a message with damaged framing, without waiting for a response
+    ///
+    /// ```ignore
+    /// client.send_faulty(&message, &Fault::Truncate(20)).await?;
+    /// // The server should still answer the next message
+    /// assert_ack!(client.send(&next).await?, "AA");
+    /// ```
+    pub async fn send_faulty(&mut self, message: &str, fault: &Fault) -> Result<(), MllpError> {
+        let charset = charset::detect(message.as_bytes()).unwrap_or_default();
+        write_faulty(&mut self.stream, &wrap_in_mllp(&charset.encode(message)), fault).await?;
+        Ok(())
+    }
+
+    /// Wait for the next framed response
+    pub async fn receive(&mut self) -> Result<String, MllpError> {
+        let timeout = self.timeout;
+        tokio::time::timeout(timeout, self.read_frame())
+            .await
+            .unwrap_or_else(|_| Err(MllpError::Timeout(format!("No response within {:?}", timeout))))
+    }
+
+    async fn read_frame(&mut self) -> Result<String, MllpError> {
+        loop {
+            if let Some(frame) = extract_mllp_message(&mut self.buffer)? {
+                let charset = charset::detect(&frame).unwrap_or_default();
+                return Ok(charset.decode(&frame)?);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(MllpError::InvalidFrame("Connection closed before a response was received".to_string()));
+            }
+        }
+    }
+
+    /// Close the connection
+    pub async fn close(mut self) -> Result<(), MllpError> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
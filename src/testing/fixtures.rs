@@ -0,0 +1,244 @@
+//! Canonical sample messages and assertions for interface tests
+//!
+//! ```
+//! use rust_hl7::{assert_ack, assert_field_eq, assert_field_empty};
+//! use rust_hl7::testing::fixtures;
+//!
+//! let message = fixtures::sample("ADT^A01");
+//! assert_field_eq!(message, "PID-5.1", "DOE");
+//! assert_field_eq!(message, "MSH-12", "2.5", "fixtures default to v2.5");
+//! assert_field_empty!(message, "PID-29");
+//! assert_ack!(fixtures::raw("ACK", "2.4").unwrap(), "AA");
+//! ```
+
+use crate::{terser, Message};
+use std::fmt;
+
+/// Message types with a sample, as in MSH-9.1^MSH-9.2
+pub const TYPES: [&str; 10] = [
+    "ADT^A01", "ADT^A02", "ADT^A03", "ADT^A04", "ADT^A08", "ADT^A40", "ORU^R01", "RDE^O11", "VXU^V04", "ACK",
+];
+
+/// Versions samples are available in
+pub const VERSIONS: [&str; 5] = ["2.3", "2.3.1", "2.4", "2.5", "2.5.1"];
+
+/// The version `sample` uses
+pub const DEFAULT_VERSION: &str = "2.5";
+
+const PATIENT: &str = "PID|1||123456^^^GH^MR||DOE^JOHN^Q||19800101|M|||123 MAIN ST^^SPRINGFIELD^IL^62701||(217)555-0100|||||ACCT1001";
+const INPATIENT: &str = "PV1|1|I|MED^101^A^GH||||004777^ATTEND^AARON|||MED|||||||||VN1001|||||||||||||||||||||||||20240501083000";
+
+/// The segments after MSH, the message structure (MSH-9.3) and the first version it exists in
+fn body(message_type: &str) -> Option<(Vec<&'static str>, &'static str, &'static str)> {
+    let body = match message_type {
+        "ADT^A01" => (vec!["EVN|A01|20240501083000", PATIENT, "NK1|1|DOE^JANE|SPO|123 MAIN ST^^SPRINGFIELD^IL^62701|(217)555-0101", INPATIENT], "ADT_A01", "2.3"),
+        "ADT^A02" => (
+            vec![
+                "EVN|A02|20240502101500",
+                PATIENT,
+                "PV1|1|I|SUR^204^B^GH|||MED^101^A^GH|004777^ATTEND^AARON|||SUR|||||||||VN1001|||||||||||||||||||||||||20240501083000",
+            ],
+            "ADT_A02",
+            "2.3",
+        ),
+        "ADT^A03" => (
+            vec![
+                "EVN|A03|20240505110000",
+                PATIENT,
+                "PV1|1|I|SUR^204^B^GH||||004777^ATTEND^AARON|||SUR|||||||||VN1001|||||||||||||||||01||||||||20240501083000|20240505110000",
+            ],
+            "ADT_A03",
+            "2.3",
+        ),
+        "ADT^A04" => (
+            vec![
+                "EVN|A04|20240501083000",
+                PATIENT,
+                "PV1|1|O|CLINIC^^^GH||||004777^ATTEND^AARON|||MED|||||||||VN1002|||||||||||||||||||||||||20240501083000",
+            ],
+            "ADT_A01",
+            "2.3",
+        ),
+        "ADT^A08" => (
+            vec![
+                "EVN|A08|20240503141000",
+                "PID|1||123456^^^GH^MR||DOE^JOHN^Q||19800101|M|||456 OAK AVE^^SPRINGFIELD^IL^62704||(217)555-0199|||||ACCT1001",
+                INPATIENT,
+            ],
+            "ADT_A01",
+            "2.3",
+        ),
+        "ADT^A40" => (vec!["EVN|A40|20240504090000", PATIENT, "MRG|654321^^^GH^MR"], "ADT_A39", "2.3"),
+        "ORU^R01" => (
+            vec![
+                PATIENT,
+                "OBR|1|ORD1001|LAB1001|58410-2^CBC panel^LN|||20240501080000|||||||||004777^ATTEND^AARON||||||20240501093000|||F",
+                "OBX|1|NM|6690-2^Leukocytes^LN||7.2|10*3/uL|4.0-11.0|N|||F|||20240501090000",
+                "OBX|2|NM|718-7^Hemoglobin^LN||11.1|g/dL|12.0-17.5|L|||F|||20240501090000",
+                "OBX|3|NM|777-3^Platelets^LN||250|10*3/uL|150-450|N|||F|||20240501090000",
+            ],
+            "ORU_R01",
+            "2.3",
+        ),
+        "RDE^O11" => (
+            vec![
+                PATIENT,
+                "ORC|NW|ORD2001|||||||20240501083000|||004777^ATTEND^AARON",
+                "RXE|^BID^^20240501^20240508|308191^AMOXICILLIN 500 MG ORAL CAPSULE^RXNORM|500||MG|CAP||||14|CAP",
+                "RXR|PO",
+            ],
+            "RDE_O11",
+            // RDE^O01 before v2.4
+            "2.4",
+        ),
+        "VXU^V04" => (
+            vec![
+                PATIENT,
+                "ORC|RE|ORD3001",
+                "RXA|0|1|20240501100000|20240501100000|141^Influenza, seasonal, injectable^CVX|0.5|mL^mL^UCUM||||||||LOT1234||SKB^GlaxoSmithKline^MVX|||CP",
+                "RXR|IM^Intramuscular^HL70162|LD^Left Deltoid^HL70163",
+            ],
+            "VXU_V04",
+            "2.3",
+        ),
+        "ACK" => (vec!["MSA|AA|ADT00001|Message accepted"], "ACK", "2.3"),
+        _ => return None,
+    };
+    Some(body)
+}
+
+/// The sample of `message_type` in `version`, as ER7 text with `\r` between segments
+///
+/// None if there's no sample for the type, the version isn't in `VERSIONS`,
+/// or the type doesn't exist in that version.
+pub fn raw(message_type: &str, version: &str) -> Option<String> {
+    let (segments, structure, since) = body(message_type)?;
+    let position = |version: &str| VERSIONS.iter().position(|v| *v == version);
+    if position(version)? < position(since)? {
+        return None;
+    }
+
+    let index = TYPES.iter().position(|t| *t == message_type)?;
+    let (code, sending) = match message_type.split('^').next() {
+        Some("ORU") => ("LAB", "LAB"),
+        Some("RDE") => ("RX", "PHARMACY"),
+        Some("VXU") => ("VXU", "IMMUNIZATIONS"),
+        Some("ACK") => ("ACK", "EHR"),
+        _ => ("ADT", "REG"),
+    };
+    // Acknowledgments carry the trigger event of the message they answer
+    let msh9 = if message_type == "ACK" { "ACK^A01" } else { message_type };
+    // The message structure in MSH-9.3 was added in v2.3.1
+    let msh9 = if version == "2.3" { msh9.to_string() } else { format!("{}^{}", msh9, structure) };
+    let mut message = format!(
+        "MSH|^~\\&|{}|GENERAL HOSPITAL|EHR|GENERAL HOSPITAL|20240501083000||{}|{}{:05}|P|{}",
+        sending,
+        msh9,
+        code,
+        index + 1,
+        version
+    );
+    for segment in segments {
+        message.push('\r');
+        message.push_str(segment);
+    }
+    Some(message)
+}
+
+/// The parsed sample of `message_type` in `DEFAULT_VERSION`
+///
+/// Panics if there's no such sample, as a test should.
+pub fn sample(message_type: &str) -> Message {
+    sample_in(message_type, DEFAULT_VERSION)
+}
+
+/// The parsed sample of `message_type` in `version`, panicking if there's no such sample
+pub fn sample_in(message_type: &str, version: &str) -> Message {
+    let raw = raw(message_type, version).unwrap_or_else(|| panic!("No {} sample for v{}", message_type, version));
+    Message::parse(&raw).unwrap_or_else(|e| panic!("The {} sample for v{} doesn't parse: {}", message_type, version, e))
+}
+
+/// Every sample: its type, version and parsed message
+pub fn all() -> impl Iterator<Item = (&'static str, &'static str, Message)> {
+    TYPES.into_iter().flat_map(|message_type| {
+        VERSIONS
+            .into_iter()
+            .filter(move |version| raw(message_type, version).is_some())
+            .map(move |version| (message_type, version, sample_in(message_type, version)))
+    })
+}
+
+/// Panic unless the field at `path` has the `expected` value (`None` for empty or missing)
+///
+/// Used by `assert_field_eq!` and `assert_field_empty!`.
+#[doc(hidden)]
+#[track_caller]
+pub fn check_field(message: &Message, path: &str, expected: Option<&str>, context: Option<fmt::Arguments<'_>>) {
+    let actual = terser::get(message, path).filter(|value| !value.is_empty());
+    if actual.as_deref() != expected {
+        let context = context.map(|c| format!(": {}", c)).unwrap_or_default();
+        match expected {
+            Some(expected) => panic!("{} is {:?}, expected {:?}{}", path, actual.unwrap_or_default(), expected, context),
+            None => panic!("{} is {:?}, expected it to be empty{}", path, actual.unwrap_or_default(), context),
+        }
+    }
+}
+
+/// Panic unless `response` parses and its MSA-1 acknowledgment code is `code`
+///
+/// Used by `assert_ack!`.
+#[doc(hidden)]
+#[track_caller]
+pub fn check_ack(response: &str, code: &str, context: Option<fmt::Arguments<'_>>) {
+    let context = context.map(|c| format!(": {}", c)).unwrap_or_default();
+    let message = Message::parse(response).unwrap_or_else(|e| panic!("Response doesn't parse ({}){}: {:?}", e, context, response));
+    let actual = terser::get(&message, "MSA-1").unwrap_or_default();
+    if actual != code {
+        let text = terser::get(&message, "MSA-3").unwrap_or_default();
+        panic!("MSA-1 is {:?} ({}), expected {:?}{}", actual, text, code, context);
+    }
+}
+
+/// Assert that a field of a message has a value, e.g. `assert_field_eq!(msg, "PID-5.1", "DOE")`
+///
+/// Takes any terser path and, like `assert_eq!`, an optional message to show on failure.
+#[macro_export]
+macro_rules! assert_field_eq {
+    ($message:expr, $path:expr, $expected:expr $(,)?) => {
+        $crate::testing::fixtures::check_field(&$message, $path, Some(::std::convert::AsRef::<str>::as_ref(&$expected)), None)
+    };
+    ($message:expr, $path:expr, $expected:expr, $($arg:tt)+) => {
+        $crate::testing::fixtures::check_field(
+            &$message,
+            $path,
+            Some(::std::convert::AsRef::<str>::as_ref(&$expected)),
+            Some(format_args!($($arg)+)),
+        )
+    };
+}
+
+/// Assert that a field of a message is empty or missing, e.g. `assert_field_empty!(msg, "PID-29")`
+#[macro_export]
+macro_rules! assert_field_empty {
+    ($message:expr, $path:expr $(,)?) => {
+        $crate::testing::fixtures::check_field(&$message, $path, None, None)
+    };
+    ($message:expr, $path:expr, $($arg:tt)+) => {
+        $crate::testing::fixtures::check_field(&$message, $path, None, Some(format_args!($($arg)+)))
+    };
+}
+
+/// Assert that a response is an acknowledgment with this MSA-1 code, e.g. `assert_ack!(response, "AA")`
+#[macro_export]
+macro_rules! assert_ack {
+    ($response:expr, $code:expr $(,)?) => {
+        $crate::testing::fixtures::check_ack(::std::convert::AsRef::<str>::as_ref(&$response), $code, None)
+    };
+    ($response:expr, $code:expr, $($arg:tt)+) => {
+        $crate::testing::fixtures::check_ack(
+            ::std::convert::AsRef::<str>::as_ref(&$response),
+            $code,
+            Some(format_args!($($arg)+)),
+        )
+    };
+}
@@ -0,0 +1,162 @@
+//! Proptest strategies for messages and MLLP byte streams
+//!
+//! Messages are generated as the values they carry, as a `MessageSpec`, and
+//! written out with any delimiters, so properties can check that parsing gets
+//! every value back:
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn parses_any_delimiters(spec in strategies::message(), delimiters in strategies::delimiters()) {
+//!         let message = Message::parse(&spec.encode(&delimiters)).unwrap();
+//!         prop_assert_eq!(MessageSpec::from_message(&message), spec);
+//!     }
+//! }
+//! ```
+
+use crate::mllp::wrap_in_mllp;
+use crate::{Delimiters, Field, Message};
+use bytes::Bytes;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::{select, subsequence, Index};
+
+/// Characters delimiters are drawn from
+const DELIMITER_POOL: [char; 16] = ['|', '^', '~', '\\', '&', '#', '!', '$', '%', '*', '+', '@', '/', ':', ';', '?'];
+
+/// Characters values are drawn from, including every possible delimiter so escaping is exercised
+const VALUE_CHARS: [char; 26] = [
+    'A', 'B', 'Z', 'a', 'z', '0', '1', '9', ' ', '.', '-', 'é', '中', '|', '^', '~', '\\', '&', '#', '!', '$', '%',
+    '*', '+', '@', '/',
+];
+
+/// A field's values before escaping: its repetitions, their components and their subcomponents
+pub type FieldValues = Vec<Vec<Vec<String>>>;
+
+/// A message as the values it carries, which can be written with any delimiters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageSpec {
+    /// MSH-3 onwards
+    pub header: Vec<FieldValues>,
+    /// The segments after MSH: names and fields from 1 onwards
+    pub segments: Vec<(String, Vec<FieldValues>)>,
+}
+
+impl MessageSpec {
+    /// Write the message as ER7 with these delimiters, escaping the values and ending segments with `\r`
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        let d = delimiters;
+        let mut output = format!("MSH{}{}{}{}{}", d.field, d.component, d.repetition, d.escape, d.subcomponent);
+        for field in &self.header {
+            output.push(d.field);
+            output.push_str(&encode_field(field, d));
+        }
+        for (name, fields) in &self.segments {
+            output.push('\r');
+            output.push_str(name);
+            for field in fields {
+                output.push(d.field);
+                output.push_str(&encode_field(field, d));
+            }
+        }
+        output
+    }
+
+    /// Read the values back out of a parsed message
+    pub fn from_message(message: &Message) -> Self {
+        let fields = |fields: &[Field]| fields.iter().map(field_values).collect();
+        let mut segments = message.segments.iter();
+        let header = segments.next().map(|msh| fields(msh.fields.get(1..).unwrap_or_default())).unwrap_or_default();
+        Self {
+            header,
+            segments: segments.map(|segment| (segment.name.clone(), fields(&segment.fields))).collect(),
+        }
+    }
+}
+
+fn encode_field(field: &FieldValues, d: &Delimiters) -> String {
+    field
+        .iter()
+        .map(|repetition| {
+            repetition
+                .iter()
+                .map(|component| component.iter().map(|value| d.escape(value)).collect::<Vec<_>>().join(&d.subcomponent.to_string()))
+                .collect::<Vec<_>>()
+                .join(&d.component.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(&d.repetition.to_string())
+}
+
+/// The unescaped values of a parsed field
+pub fn field_values(field: &Field) -> FieldValues {
+    let d = Delimiters::default();
+    field
+        .to_hl7(&d)
+        .split(d.repetition)
+        .map(|repetition| {
+            repetition
+                .split(d.component)
+                .map(|component| component.split(d.subcomponent).map(|value| d.unescape(value)).collect())
+                .collect()
+        })
+        .collect()
+}
+
+/// Five distinct delimiter characters
+pub fn delimiters() -> impl Strategy<Value = Delimiters> {
+    subsequence(DELIMITER_POOL.to_vec(), 5).prop_shuffle().prop_map(|c| Delimiters {
+        field: c[0],
+        component: c[1],
+        repetition: c[2],
+        escape: c[3],
+        subcomponent: c[4],
+    })
+}
+
+/// A value of up to eight characters, often including delimiters
+pub fn value() -> impl Strategy<Value = String> {
+    vec(select(VALUE_CHARS.to_vec()), 0..8).prop_map(|chars| chars.into_iter().collect())
+}
+
+/// A field with up to three repetitions of up to four components of up to three subcomponents
+pub fn field() -> impl Strategy<Value = FieldValues> {
+    vec(vec(vec(value(), 1..=3), 1..=4), 1..=3)
+}
+
+/// A segment name other than MSH, e.g. "PID" or "Z01"
+pub fn segment_name() -> impl Strategy<Value = String> {
+    "[A-Z][A-Z0-9]{2}".prop_filter("MSH only starts a message", |name| name != "MSH")
+}
+
+/// A message with a known type and version in MSH-9 and MSH-12 and up to six other segments
+pub fn message() -> impl Strategy<Value = MessageSpec> {
+    let types = select(crate::generate::MESSAGE_TYPES.to_vec());
+    let versions = select(crate::testing::fixtures::VERSIONS.to_vec());
+    let plain = |text: &str| text.split('^').map(|component| vec![component.to_string()]).collect::<Vec<_>>();
+    (vec(field(), 10), vec(field(), 0..3), types, versions, vec((segment_name(), vec(field(), 0..6)), 0..6)).prop_map(
+        move |(mut header, extra, message_type, version, segments)| {
+            // MSH-9 and MSH-12 are the 7th and 10th fields from MSH-3
+            header[6] = vec![plain(message_type)];
+            header[9] = vec![plain(version)];
+            header.extend(extra);
+            MessageSpec { header, segments }
+        },
+    )
+}
+
+/// Up to four messages as standard ER7, and the MLLP stream carrying them cut into chunks at arbitrary points
+///
+/// Chunk boundaries fall anywhere, including inside the start and end markers.
+pub fn mllp_stream() -> impl Strategy<Value = (Vec<String>, Vec<Bytes>)> {
+    (vec(message(), 1..=4), vec(any::<Index>(), 0..8)).prop_map(|(specs, cuts)| {
+        let messages: Vec<String> = specs.iter().map(|spec| spec.encode(&Delimiters::default())).collect();
+        let stream: Vec<u8> = messages.iter().flat_map(|message| wrap_in_mllp(message.as_bytes())).collect();
+        let mut cuts: Vec<usize> = cuts.iter().map(|cut| cut.index(stream.len())).collect();
+        cuts.extend([0, stream.len()]);
+        cuts.sort_unstable();
+        cuts.dedup();
+        let chunks = cuts.windows(2).map(|w| Bytes::copy_from_slice(&stream[w[0]..w[1]])).collect();
+        (messages, chunks)
+    })
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+/// Errors that can occur setting up TLS
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Certificate error: {0}")]
+    CertificateError(String),
+}
+
+/// Client settings that trust the system's CA certificates and any in `ca_file`
+///
+/// `ca_file` is a PEM file, typically holding the private CA that signed an
+/// interface engine's certificate.
+pub fn client_config(ca_file: Option<&Path>) -> Result<Arc<ClientConfig>, TlsError> {
+    let mut roots = RootCertStore::empty();
+
+    // Unreadable system certificates are skipped rather than failing every connection
+    let native = rustls_native_certs::load_native_certs();
+    roots.add_parsable_certificates(native.certs);
+
+    if let Some(path) = ca_file {
+        add_certificates(&mut roots, path)?;
+    }
+
+    if roots.is_empty() {
+        return Err(TlsError::CertificateError("No trusted CA certificates found".to_string()));
+    }
+
+    let config = ClientConfig::builder_with_provider(Arc::new(tokio_rustls::rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TlsError::CertificateError(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Server settings presenting the certificate chain in `cert_file` with the key in `key_file`
+///
+/// Both are PEM files. With `client_ca_file`, clients must present a
+/// certificate signed by one of the CAs in it (mutual TLS).
+pub fn server_config(cert_file: &Path, key_file: &Path, client_ca_file: Option<&Path>) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = read_certificates(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| TlsError::CertificateError(format!("{}: {}", key_file.display(), e)))?;
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TlsError::CertificateError(e.to_string()))?;
+    let builder = match client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            add_certificates(&mut roots, path)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| TlsError::CertificateError(format!("{}: {}", path.display(), e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| TlsError::CertificateError(format!("{}: {}", cert_file.display(), e)))?;
+    Ok(Arc::new(config))
+}
+
+/// Read every certificate in a PEM file, requiring at least one
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::CertificateError(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(TlsError::CertificateError(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Trust the CA certificates in a PEM file
+fn add_certificates(roots: &mut RootCertStore, path: &Path) -> Result<(), TlsError> {
+    for cert in read_certificates(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| TlsError::CertificateError(format!("{}: {}", path.display(), e)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use crate::consent::StripConfidential;
+use crate::deident::{SafeHarbor, Scrub};
+use crate::mllp::MessageHandler;
+use crate::terminology::{CodeMap, MapCodes};
+use crate::units::NormalizeUnits;
+use crate::terser::{self, TerserPath};
+use crate::{HL7Error, Message};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A single modification applied to a message before it is forwarded
+pub trait Transform: Send + Sync {
+    /// Apply the transform to the message in place
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error>;
+}
+
+/// Any closure over a mutable message can be used as a transform
+impl<F> Transform for F
+where
+    F: Fn(&mut Message) -> Result<(), HL7Error> + Send + Sync,
+{
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        self(message)
+    }
+}
+
+/// Set the value at a path to a constant
+#[derive(Debug, Clone)]
+pub struct SetField {
+    pub path: TerserPath,
+    pub value: String,
+}
+
+impl Transform for SetField {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        terser::set_path(message, &self.path, &self.value)
+    }
+}
+
+/// Copy the value at one path to another, skipping if the source is missing
+#[derive(Debug, Clone)]
+pub struct CopyField {
+    pub from: TerserPath,
+    pub to: TerserPath,
+}
+
+impl Transform for CopyField {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        match terser::get_path(message, &self.from) {
+            Some(value) => terser::set_path(message, &self.to, &value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Remove every segment with the given name
+#[derive(Debug, Clone)]
+pub struct DeleteSegment {
+    pub segment: String,
+}
+
+impl Transform for DeleteSegment {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        if self.segment == "MSH" {
+            return Err(HL7Error::InvalidStructure("MSH segment can't be deleted".to_string()));
+        }
+        message.segments.retain(|s| s.name != self.segment);
+        Ok(())
+    }
+}
+
+/// Replace a coded value using a lookup table
+///
+/// Values not in the table are replaced with `default` if set, otherwise left alone.
+#[derive(Debug, Clone)]
+pub struct MapValue {
+    pub path: TerserPath,
+    pub table: HashMap<String, String>,
+    pub default: Option<String>,
+}
+
+impl Transform for MapValue {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        let Some(value) = terser::get_path(message, &self.path) else {
+            return Ok(());
+        };
+
+        match self.table.get(&value).or(self.default.as_ref()) {
+            Some(mapped) => terser::set_path(message, &self.path, mapped),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Replace all regex matches in the value at a path
+#[derive(Debug, Clone)]
+pub struct RegexReplace {
+    pub path: TerserPath,
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl Transform for RegexReplace {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        let Some(value) = terser::get_path(message, &self.path) else {
+            return Ok(());
+        };
+
+        let replaced = self.pattern.replace_all(&value, self.replacement.as_str());
+        terser::set_path(message, &self.path, &replaced)
+    }
+}
+
+/// Declarative form of a transform step, as loaded from a JSON or YAML config
+///
+/// ```yaml
+/// - op: set_field
+///   path: MSH-5
+///   value: EHR
+/// - op: map_value
+///   path: PV1-2
+///   table: { IN: I, OUT: O }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TransformStep {
+    SetField { path: String, value: String },
+    CopyField { from: String, to: String },
+    DeleteSegment { segment: String },
+    MapValue {
+        path: String,
+        table: HashMap<String, String>,
+        #[serde(default)]
+        default: Option<String>,
+    },
+    RegexReplace { path: String, pattern: String, replacement: String },
+    /// Remove the HIPAA Safe Harbor identifiers, with rules changed per field
+    SafeHarbor {
+        #[serde(default)]
+        rules: HashMap<String, Scrub>,
+        /// Keep NTE-3 and textual OBX-5 instead of clearing them
+        #[serde(default)]
+        keep_free_text: bool,
+    },
+    /// Remove segments from messages about confidential patients
+    StripConfidential { segments: Vec<String> },
+    /// Rewrite coded fields with standard codes from a CSV or ConceptMap file
+    MapCodes {
+        file: String,
+        #[serde(default)]
+        paths: Vec<String>,
+    },
+    /// Rewrite OBX-6 with UCUM codes, converting values in some units to others
+    NormalizeUnits {
+        #[serde(default)]
+        convert: HashMap<String, String>,
+    },
+    /// Run a WebAssembly plugin (`.wasm` or `.wat`) on the message
+    #[cfg(feature = "plugins")]
+    Wasm { file: String },
+}
+
+impl TransformStep {
+    /// Validate paths and compile patterns into a runnable transform
+    pub fn compile(&self) -> Result<Box<dyn Transform>, HL7Error> {
+        Ok(match self {
+            TransformStep::SetField { path, value } => Box::new(SetField {
+                path: path.parse()?,
+                value: value.clone(),
+            }),
+            TransformStep::CopyField { from, to } => Box::new(CopyField {
+                from: from.parse()?,
+                to: to.parse()?,
+            }),
+            TransformStep::DeleteSegment { segment } => Box::new(DeleteSegment {
+                segment: segment.clone(),
+            }),
+            TransformStep::MapValue { path, table, default } => Box::new(MapValue {
+                path: path.parse()?,
+                table: table.clone(),
+                default: default.clone(),
+            }),
+            TransformStep::RegexReplace { path, pattern, replacement } => Box::new(RegexReplace {
+                path: path.parse()?,
+                pattern: Regex::new(pattern)
+                    .map_err(|e| HL7Error::ParseError(format!("Invalid regex '{}': {}", pattern, e)))?,
+                replacement: replacement.clone(),
+            }),
+            TransformStep::SafeHarbor { rules, keep_free_text } => Box::new(
+                SafeHarbor::new().keep_free_text(*keep_free_text).rules(rules.iter().map(|(path, &scrub)| (path, scrub)))?,
+            ),
+            TransformStep::StripConfidential { segments } => Box::new(StripConfidential::new(segments)?),
+            TransformStep::MapCodes { file, paths } => {
+                let step = MapCodes::new(Arc::new(CodeMap::load(file)?));
+                Box::new(if paths.is_empty() { step } else { step.paths(paths)? })
+            }
+            TransformStep::NormalizeUnits { convert } => Box::new(
+                convert
+                    .iter()
+                    .try_fold(NormalizeUnits::new(), |step, (from, to)| step.convert(from, to))?,
+            ),
+            #[cfg(feature = "plugins")]
+            TransformStep::Wasm { file } => Box::new(crate::plugins::Plugin::load(file)?),
+        })
+    }
+}
+
+/// An ordered list of transforms applied one after another
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn Transform>>,
+}
+
+impl std::fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pipeline({} steps)", self.steps.len())
+    }
+}
+
+impl Pipeline {
+    /// Create an empty pipeline
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a transform to the pipeline
+    pub fn then<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.steps.push(Box::new(transform));
+        self
+    }
+
+    /// Build a pipeline from declarative steps
+    pub fn from_steps(steps: &[TransformStep]) -> Result<Self, HL7Error> {
+        let steps = steps
+            .iter()
+            .map(|s| s.compile())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { steps })
+    }
+
+    /// Build a pipeline from a JSON array of steps
+    pub fn from_json(config: &str) -> Result<Self, HL7Error> {
+        let steps: Vec<TransformStep> = serde_json::from_str(config)
+            .map_err(|e| HL7Error::ParseError(format!("Invalid transform config: {}", e)))?;
+        Self::from_steps(&steps)
+    }
+
+    /// Build a pipeline from a YAML list of steps
+    pub fn from_yaml(config: &str) -> Result<Self, HL7Error> {
+        let steps: Vec<TransformStep> = serde_yaml::from_str(config)
+            .map_err(|e| HL7Error::ParseError(format!("Invalid transform config: {}", e)))?;
+        Self::from_steps(&steps)
+    }
+
+    /// Load a pipeline from a `.json`, `.yaml` or `.yml` file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HL7Error> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| HL7Error::ParseError(format!("Failed to read {}: {}", path.display(), e)))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&config),
+            Some("yaml") | Some("yml") => Self::from_yaml(&config),
+            _ => Err(HL7Error::ParseError(format!(
+                "Unknown transform config format: {}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Number of steps in the pipeline
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Whether the pipeline has no steps
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Wrap a handler so messages are transformed before it sees them
+    pub fn wrap(self, handler: MessageHandler) -> MessageHandler {
+        let pipeline = Arc::new(self);
+        Arc::new(move |mut message| {
+            pipeline.apply(&mut message)?;
+            handler(message)
+        })
+    }
+}
+
+impl Transform for Pipeline {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        for step in &self.steps {
+            step.apply(message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+//! Normalizing observation units to UCUM
+//!
+//! Senders write the same unit many ways: `mg/dl`, `MG/DL`, `K/uL` or
+//! `x10^3/uL`. The `NormalizeUnits` transform rewrites OBX-6 with the UCUM
+//! code for each unit it recognizes, can convert values to a preferred unit
+//! of the same kind (pounds to kilograms, °F to °C), and records the units it
+//! doesn't recognize in an `UnrecognizedUnits` report.
+//!
+//! ```
+//! use rust_hl7::transform::Transform;
+//! use rust_hl7::units::NormalizeUnits;
+//! use rust_hl7::{terser, Message};
+//!
+//! let step = NormalizeUnits::new().convert("[lb_av]", "kg").unwrap();
+//! let mut message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\r\
+//!     OBX|1|NM|2345-7^Glucose^LN||98|mg/dl|70-99\r\
+//!     OBX|2|NM|29463-7^Weight^LN||150|lbs").unwrap();
+//! step.apply(&mut message).unwrap();
+//! assert_eq!(terser::get(&message, "OBX(1)-6").unwrap(), "mg/dL^^UCUM");
+//! assert_eq!(terser::get(&message, "OBX(2)-5").unwrap(), "68.0");
+//! assert_eq!(terser::get(&message, "OBX(2)-6").unwrap(), "kg^^UCUM");
+//! ```
+
+use crate::transform::Transform;
+use crate::{terser, Delimiters, HL7Error, Message};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// A UCUM unit, and how to convert it to the base unit of its kind
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    /// The UCUM code, e.g. "mg/dL"
+    pub code: &'static str,
+    /// What the unit measures; units of the same kind convert to each other
+    pub kind: &'static str,
+    factor: f64,
+    offset: f64,
+}
+
+const fn unit(code: &'static str, kind: &'static str, factor: f64) -> Unit {
+    Unit { code, kind, factor, offset: 0.0 }
+}
+
+/// The units recognized, by UCUM code
+const UNITS: &[Unit] = &[
+    unit("g/L", "mass concentration", 1.0),
+    unit("g/dL", "mass concentration", 10.0),
+    unit("mg/dL", "mass concentration", 0.01),
+    unit("mg/L", "mass concentration", 1e-3),
+    unit("ug/dL", "mass concentration", 1e-5),
+    unit("ug/L", "mass concentration", 1e-6),
+    unit("ug/mL", "mass concentration", 1e-3),
+    unit("ng/mL", "mass concentration", 1e-6),
+    unit("ng/dL", "mass concentration", 1e-8),
+    unit("ng/L", "mass concentration", 1e-9),
+    unit("pg/mL", "mass concentration", 1e-9),
+    unit("mol/L", "substance concentration", 1.0),
+    unit("mmol/L", "substance concentration", 1e-3),
+    unit("umol/L", "substance concentration", 1e-6),
+    unit("nmol/L", "substance concentration", 1e-9),
+    unit("pmol/L", "substance concentration", 1e-12),
+    unit("meq/L", "equivalent concentration", 1.0),
+    unit("U/L", "catalytic concentration", 1.0),
+    unit("[IU]/L", "arbitrary concentration", 1.0),
+    unit("[IU]/mL", "arbitrary concentration", 1e3),
+    unit("m[IU]/L", "arbitrary concentration", 1e-3),
+    unit("10*3/uL", "number concentration", 1e9),
+    unit("10*6/uL", "number concentration", 1e12),
+    unit("10*9/L", "number concentration", 1e9),
+    unit("10*12/L", "number concentration", 1e12),
+    unit("/uL", "number concentration", 1e6),
+    unit("%", "fraction", 1.0),
+    unit("fL", "volume", 1e-15),
+    unit("uL", "volume", 1e-6),
+    unit("mL", "volume", 1e-3),
+    unit("dL", "volume", 0.1),
+    unit("L", "volume", 1.0),
+    unit("pg", "mass", 1e-12),
+    unit("ug", "mass", 1e-6),
+    unit("mg", "mass", 1e-3),
+    unit("g", "mass", 1.0),
+    unit("kg", "mass", 1e3),
+    unit("[oz_av]", "mass", 28.349523125),
+    unit("[lb_av]", "mass", 453.59237),
+    unit("mm", "length", 1e-3),
+    unit("cm", "length", 1e-2),
+    unit("m", "length", 1.0),
+    unit("[in_i]", "length", 0.0254),
+    unit("[ft_i]", "length", 0.3048),
+    unit("Cel", "temperature", 1.0),
+    Unit { code: "[degF]", kind: "temperature", factor: 5.0 / 9.0, offset: -32.0 },
+    Unit { code: "K", kind: "temperature", factor: 1.0, offset: -273.15 },
+    unit("mm[Hg]", "pressure", 1.0),
+    unit("/min", "rate", 1.0),
+    unit("s", "time", 1.0),
+    unit("min", "time", 60.0),
+    unit("h", "time", 3600.0),
+    unit("d", "time", 86400.0),
+    unit("mL/min/{1.73_m2}", "filtration rate", 1.0),
+    unit("kg/m2", "body mass index", 1.0),
+    unit("{ratio}", "ratio", 1.0),
+];
+
+/// Common ways of writing units that aren't UCUM, lowercased, and the UCUM code for each
+const ALIASES: &[(&str, &str)] = &[
+    ("mcg/dl", "ug/dL"),
+    ("mcg/l", "ug/L"),
+    ("mcg/ml", "ug/mL"),
+    ("iu/l", "[IU]/L"),
+    ("iu/ml", "[IU]/mL"),
+    ("miu/l", "m[IU]/L"),
+    ("uiu/ml", "m[IU]/L"),
+    ("k/ul", "10*3/uL"),
+    ("k/mm3", "10*3/uL"),
+    ("thou/ul", "10*3/uL"),
+    ("10^3/ul", "10*3/uL"),
+    ("x10^3/ul", "10*3/uL"),
+    ("x10e3/ul", "10*3/uL"),
+    ("10e3/ul", "10*3/uL"),
+    ("m/ul", "10*6/uL"),
+    ("mil/ul", "10*6/uL"),
+    ("10^6/ul", "10*6/uL"),
+    ("x10^6/ul", "10*6/uL"),
+    ("x10e6/ul", "10*6/uL"),
+    ("10^9/l", "10*9/L"),
+    ("x10^9/l", "10*9/L"),
+    ("10^12/l", "10*12/L"),
+    ("x10^12/l", "10*12/L"),
+    ("cells/ul", "/uL"),
+    ("percent", "%"),
+    ("mcg", "ug"),
+    ("gm", "g"),
+    ("kgs", "kg"),
+    ("oz", "[oz_av]"),
+    ("lb", "[lb_av]"),
+    ("lbs", "[lb_av]"),
+    ("in", "[in_i]"),
+    ("inch", "[in_i]"),
+    ("inches", "[in_i]"),
+    ("ft", "[ft_i]"),
+    ("c", "Cel"),
+    ("degc", "Cel"),
+    ("deg c", "Cel"),
+    ("°c", "Cel"),
+    ("f", "[degF]"),
+    ("degf", "[degF]"),
+    ("deg f", "[degF]"),
+    ("°f", "[degF]"),
+    ("mmhg", "mm[Hg]"),
+    ("mm hg", "mm[Hg]"),
+    ("bpm", "/min"),
+    ("beats/min", "/min"),
+    ("breaths/min", "/min"),
+    ("sec", "s"),
+    ("secs", "s"),
+    ("seconds", "s"),
+    ("mins", "min"),
+    ("hr", "h"),
+    ("hrs", "h"),
+    ("days", "d"),
+    ("ml/min/1.73m2", "mL/min/{1.73_m2}"),
+    ("ml/min/1.73 m2", "mL/min/{1.73_m2}"),
+    ("kg/m^2", "kg/m2"),
+    ("ratio", "{ratio}"),
+];
+
+/// The UCUM unit for a unit as a sender wrote it, if it's recognized
+///
+/// UCUM codes are recognized as they are, then ignoring case, then as one
+/// of the common ways of writing units that aren't UCUM, e.g. "mcg/dL" for
+/// "ug/dL".
+pub fn recognize(unit: &str) -> Option<Unit> {
+    let unit = unit.trim();
+    let find = |code: &str| UNITS.iter().find(|u| u.code == code).copied();
+    // Single letters differ by case alone, as "m" (metre) and "M" (molar) do
+    find(unit).or_else(|| {
+        let lower = unit.to_lowercase();
+        if unit.chars().count() == 1 {
+            return ALIASES.iter().find(|(alias, _)| *alias == lower).and_then(|(_, code)| find(code));
+        }
+        UNITS
+            .iter()
+            .find(|u| u.code.to_lowercase() == lower)
+            .copied()
+            .or_else(|| ALIASES.iter().find(|(alias, _)| *alias == lower).and_then(|(_, code)| find(code)))
+    })
+}
+
+/// Convert a value between units of the same kind, or None if they're of different kinds
+pub fn convert(value: f64, from: &Unit, to: &Unit) -> Option<f64> {
+    (from.kind == to.kind).then(|| (value + from.offset) * from.factor / to.factor - to.offset)
+}
+
+/// Write a converted value with as many significant digits as the original had, and at least three
+fn format_converted(value: f64, original: &str) -> String {
+    let digits = original.chars().filter(char::is_ascii_digit).collect::<String>();
+    let significant = digits.trim_start_matches('0').len().max(3) as i32;
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    let magnitude = value.abs().log10().floor() as i32;
+    let decimals = (significant - 1 - magnitude).max(0) as usize;
+    format!("{:.*}", decimals, value)
+}
+
+/// Convert a reference range, "low-high" or a bound such as "<200", with `convert`
+///
+/// Either end of "low-high" may be negative, as in "-5-5". Gives None if
+/// the range isn't one of those forms or a number in it doesn't convert.
+fn convert_range(range: &str, convert: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let range = range.trim();
+    for bound in ["<=", ">=", "<", ">"] {
+        if let Some(limit) = range.strip_prefix(bound) {
+            return convert(limit).map(|limit| format!("{}{}", bound, limit));
+        }
+    }
+    // The separator is a '-' after a digit, so a leading minus on either end isn't one
+    let separator = range
+        .char_indices()
+        .skip(1)
+        .find(|&(i, c)| c == '-' && range[..i].ends_with(|p: char| p.is_ascii_digit() || p == '.'))
+        .map(|(i, _)| i);
+    match separator {
+        Some(i) => Some(format!("{}-{}", convert(&range[..i])?, convert(&range[i + 1..])?)),
+        None => convert(range),
+    }
+}
+
+/// A unit `NormalizeUnits` didn't recognize
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnrecognizedUnit {
+    pub unit: String,
+    /// OBX-3 of the first observation it was seen on
+    pub observation: String,
+    /// How many times it was seen
+    pub count: u64,
+}
+
+/// The units a `NormalizeUnits` step didn't recognize, for someone to review
+#[derive(Debug, Default)]
+pub struct UnrecognizedUnits {
+    units: Mutex<BTreeMap<String, UnrecognizedUnit>>,
+}
+
+impl UnrecognizedUnits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, unit: &str, observation: &str) {
+        let mut units = self.units.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = units.entry(unit.to_string()).or_insert_with(|| {
+            warn!(unit, observation, "Unrecognized unit");
+            UnrecognizedUnit {
+                unit: unit.to_string(),
+                observation: observation.to_string(),
+                count: 0,
+            }
+        });
+        entry.count += 1;
+    }
+
+    /// The unrecognized units seen so far
+    pub fn entries(&self) -> Vec<UnrecognizedUnit> {
+        self.units.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+    }
+
+    /// Forget the units seen so far, e.g. once they've been reviewed
+    pub fn clear(&self) {
+        self.units.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+/// A transform that rewrites OBX-6 with UCUM codes, optionally converting values
+///
+/// OBX-6 becomes the UCUM code with "UCUM" as its coding system, keeping any
+/// text the sender gave. With a conversion set for a unit, numeric OBX-5
+/// values and OBX-7 reference ranges in it are converted too, and the
+/// sender's text, which described the old unit, is cleared. A value is only
+/// converted if its range is empty or can be converted with it. Observations
+/// with units it doesn't recognize are left alone and recorded.
+#[derive(Debug, Clone, Default)]
+pub struct NormalizeUnits {
+    /// UCUM codes to convert values from, and the unit to convert them to
+    conversions: HashMap<&'static str, Unit>,
+    unrecognized: Arc<UnrecognizedUnits>,
+}
+
+impl NormalizeUnits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert values in one unit to another of the same kind, e.g. "[lb_av]" to "kg"
+    ///
+    /// Either unit can be written any way `recognize` accepts.
+    pub fn convert(mut self, from: &str, to: &str) -> Result<Self, HL7Error> {
+        let unknown = |unit: &str| HL7Error::ParseError(format!("Unrecognized unit: {}", unit));
+        let from = recognize(from).ok_or_else(|| unknown(from))?;
+        let to = recognize(to).ok_or_else(|| unknown(to))?;
+        if from.kind != to.kind {
+            return Err(HL7Error::ParseError(format!("Can't convert {} ({}) to {} ({})", from.code, from.kind, to.code, to.kind)));
+        }
+        self.conversions.insert(from.code, to);
+        Ok(self)
+    }
+
+    /// Record unrecognized units in this report, e.g. one shared by several routes
+    pub fn unrecognized_to(mut self, unrecognized: Arc<UnrecognizedUnits>) -> Self {
+        self.unrecognized = unrecognized;
+        self
+    }
+
+    /// The units this step didn't recognize
+    pub fn unrecognized(&self) -> Arc<UnrecognizedUnits> {
+        self.unrecognized.clone()
+    }
+}
+
+impl Transform for NormalizeUnits {
+    fn apply(&self, message: &mut Message) -> Result<(), HL7Error> {
+        let delimiters = Delimiters::default();
+        let count = message.segments.iter().filter(|s| s.name == "OBX").count();
+        for n in 1..=count {
+            let path = |field: &str| format!("OBX({})-{}", n, field);
+            let Some(written) = terser::get(message, &path("6.1")).map(|u| delimiters.unescape(&u)).filter(|u| !u.trim().is_empty()) else {
+                continue;
+            };
+            let Some(mut unit) = recognize(&written) else {
+                let observation = terser::get(message, &path("3.1")).unwrap_or_default();
+                self.unrecognized.record(written.trim(), &observation);
+                continue;
+            };
+
+            if let Some(&target) = self.conversions.get(unit.code) {
+                let value = terser::get(message, &path("5")).unwrap_or_default();
+                let range = terser::get(message, &path("7")).unwrap_or_default();
+                let converted = |text: &str| -> Option<String> {
+                    let number = text.trim().parse::<f64>().ok()?;
+                    convert(number, &unit, &target).map(|v| format_converted(v, text))
+                };
+                // Only numbers are converted; "<5" or "POS" can't be, so the unit stays as it is
+                let converted_range = match range.trim() {
+                    "" => Some(None),
+                    range => convert_range(range, converted).map(Some),
+                };
+                match (converted(&value), converted_range) {
+                    (Some(value), Some(converted_range)) => {
+                        terser::set(message, &path("5"), &value)?;
+                        if let Some(converted_range) = converted_range {
+                            terser::set(message, &path("7"), &converted_range)?;
+                        }
+                        terser::set(message, &path("6.2"), "")?;
+                        unit = target;
+                    }
+                    (Some(_), None) => {
+                        let observation = terser::get(message, &path("3.1")).unwrap_or_default();
+                        warn!(observation, range, "Reference range can't be converted, so the value isn't either");
+                    }
+                    (None, _) => {}
+                }
+            }
+            terser::set(message, &path("6.1"), &delimiters.escape(unit.code))?;
+            terser::set(message, &path("6.3"), "UCUM")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,505 @@
+use crate::terser::{self, TerserPath};
+use crate::{HL7Error, Message};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How serious a validation problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found while validating a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Where the problem is, e.g. `MSH-10`, `OBX(2)-5` or `PV1`
+    pub location: String,
+    pub message: String,
+}
+
+impl Issue {
+    /// An error at a location
+    pub fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Issue {
+            severity: Severity::Error,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    /// A warning at a location
+    pub fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Issue {
+            severity: Severity::Warning,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Whether this is an error rather than a warning
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{} {}: {}", severity, self.location, self.message)
+    }
+}
+
+/// HL7 v2 versions recognised in MSH-12
+const VERSIONS: [&str; 13] = [
+    "2.1", "2.2", "2.3", "2.3.1", "2.4", "2.5", "2.5.1", "2.6", "2.7", "2.7.1", "2.8", "2.8.1", "2.8.2",
+];
+
+/// Segments each supported message type must contain
+const REQUIRED_SEGMENTS: [(&str, &[&str]); 6] = [
+    ("ADT^A40", &["EVN", "PID", "MRG"]),
+    ("ADT", &["EVN", "PID"]),
+    ("ORU^R01", &["OBR"]),
+    ("RDE^O11", &["ORC", "RXE"]),
+    ("VXU^V04", &["PID"]),
+    ("ACK", &["MSA"]),
+];
+
+/// Timestamp fields checked for a valid DTM value
+const TIMESTAMPS: [(&str, usize); 7] = [
+    ("MSH", 7),
+    ("EVN", 2),
+    ("PID", 7),
+    ("PV1", 44),
+    ("PV1", 45),
+    ("OBR", 7),
+    ("OBX", 14),
+];
+
+/// Validates messages structurally and, optionally, against a conformance profile
+///
+/// Structural validation checks segment names, the MSH header fields, the
+/// segments each supported message type requires, timestamp formats and OBX
+/// value types. A profile adds site-specific rules on top.
+///
+/// ```
+/// use rust_hl7::validation::Validator;
+///
+/// let message = rust_hl7::Message::parse("MSH|^~\\&|LAB|HOSP|EHR|HOSP|20230401123000||ORU^R01|MSG1|P|2.4").unwrap();
+/// let issues = Validator::new().version("2.5").validate(&message);
+/// assert!(issues.iter().any(|issue| issue.location == "MSH-12"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Validator {
+    version: Option<String>,
+    profile: Option<Profile>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require MSH-12 to be this version
+    pub fn version<T: ToString>(mut self, version: T) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    /// Also check messages against a profile
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// All problems found in the message, errors and warnings alike
+    pub fn validate(&self, message: &Message) -> Vec<Issue> {
+        let mut issues = structure(message, self.version.as_deref());
+        if let Some(profile) = &self.profile {
+            issues.extend(profile.check(message));
+        }
+        issues
+    }
+}
+
+/// Structural checks that apply to every message
+fn structure(message: &Message, version: Option<&str>) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let value = |path: &str| terser::get(message, path).filter(|v| !v.is_empty());
+
+    for (index, segment) in message.segments.iter().enumerate() {
+        let valid = segment.name.len() == 3
+            && segment.name.starts_with(|c: char| c.is_ascii_uppercase())
+            && segment.name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        if !valid {
+            issues.push(Issue::error(
+                format!("segment {}", index + 1),
+                format!("invalid segment name '{}'", segment.name),
+            ));
+        } else if segment.name == "MSH" && index > 0 {
+            issues.push(Issue::error(format!("segment {}", index + 1), "MSH may only appear first"));
+        }
+    }
+
+    for (path, name) in [("MSH-7", "date/time of message"), ("MSH-10", "control ID"), ("MSH-11", "processing ID")] {
+        if value(path).is_none() {
+            issues.push(Issue::error(path, format!("missing {}", name)));
+        }
+    }
+    if value("MSH-9.2").is_none() && !message.message_type.starts_with("ACK") {
+        issues.push(Issue::error("MSH-9", "missing trigger event"));
+    }
+
+    match version {
+        Some(expected) if message.version != expected => issues.push(Issue::error(
+            "MSH-12",
+            format!("version is {}, expected {}", message.version, expected),
+        )),
+        None if !VERSIONS.contains(&message.version.as_str()) => {
+            issues.push(Issue::warning("MSH-12", format!("unknown version {}", message.version)))
+        }
+        _ => {}
+    }
+
+    // Entries with a trigger event match exactly, others match the message code
+    let code = message.message_type.split('^').next().unwrap_or_default();
+    if let Some((_, required)) = REQUIRED_SEGMENTS
+        .iter()
+        .find(|(message_type, _)| *message_type == message.message_type || *message_type == code)
+    {
+        for name in required.iter() {
+            if message.get_segment(name).is_none() {
+                issues.push(Issue::error(*name, format!("segment is required in {}", message.message_type)));
+            }
+        }
+    }
+
+    for (name, field) in TIMESTAMPS {
+        for (repetition, segment) in message.get_segments(name).into_iter().enumerate() {
+            let location = location(name, repetition + 1, &field.to_string());
+            let Some(text) = terser::field_index(name, field)
+                .and_then(|i| segment.fields.get(i))
+                .and_then(|f| f.components.first())
+                .map(|c| c.value.as_str())
+                .filter(|v| !v.is_empty())
+            else {
+                continue;
+            };
+            if !is_timestamp(text) {
+                issues.push(Issue::warning(location, format!("'{}' is not a valid timestamp", text)));
+            }
+        }
+    }
+
+    for (index, _) in message.get_segments("OBX").into_iter().enumerate() {
+        let repetition = index + 1;
+        let get = |field: usize| {
+            let path = TerserPath {
+                segment: "OBX".to_string(),
+                repetition,
+                field,
+                component: None,
+                subcomponent: None,
+            };
+            terser::get_path(message, &path).filter(|v| !v.is_empty())
+        };
+        match (get(2), get(5)) {
+            (None, Some(_)) => issues.push(Issue::error(
+                location("OBX", repetition, "2"),
+                "value type is required when OBX-5 is valued",
+            )),
+            (Some(value_type), Some(value)) if value_type == "NM" && value.trim().parse::<f64>().is_err() => {
+                issues.push(Issue::error(
+                    location("OBX", repetition, "5"),
+                    format!("'{}' is not numeric but OBX-2 is NM", value),
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    issues
+}
+
+/// A location such as `PID-3.1`, or `OBX(2)-5` for later occurrences of a segment
+fn location(segment: &str, repetition: usize, path: &str) -> String {
+    match (repetition, path.is_empty()) {
+        (1, true) => segment.to_string(),
+        (_, true) => format!("{}({})", segment, repetition),
+        (1, false) => format!("{}-{}", segment, path),
+        (_, false) => format!("{}({})-{}", segment, repetition, path),
+    }
+}
+
+/// Whether text is an HL7 DTM value: `YYYY[MM[DD[HH[MM[SS[.S[S[S[S]]]]]]]]][+/-ZZZZ]`
+fn is_timestamp(text: &str) -> bool {
+    let (datetime, offset) = match text.find(['+', '-']) {
+        Some(at) => (&text[..at], Some(&text[at + 1..])),
+        None => (text, None),
+    };
+    let (digits, fraction) = match datetime.split_once('.') {
+        Some((digits, fraction)) => (digits, Some(fraction)),
+        None => (datetime, None),
+    };
+
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    let valid_digits = all_digits(digits) && matches!(digits.len(), 4 | 6 | 8 | 10 | 12 | 14);
+    let valid_fraction = fraction.is_none_or(|f| digits.len() == 14 && (1..=4).contains(&f.len()) && all_digits(f));
+    let valid_offset = offset.is_none_or(|o| o.len() == 4 && all_digits(o));
+    if !(valid_digits && valid_fraction && valid_offset) {
+        return false;
+    }
+
+    // Check each part that is present is in range
+    let part = |start: usize| digits.get(start..start + 2).map(|p| p.parse::<u32>().unwrap_or(0));
+    part(4).is_none_or(|month| (1..=12).contains(&month))
+        && part(6).is_none_or(|day| (1..=31).contains(&day))
+        && part(8).is_none_or(|hour| hour < 24)
+        && part(10).is_none_or(|minute| minute < 60)
+        && part(12).is_none_or(|second| second < 60)
+}
+
+/// Conformance usage of a segment or field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Usage {
+    /// Required: missing is an error
+    R,
+    /// Required but may be empty: missing is a warning
+    RE,
+    /// Optional
+    #[default]
+    O,
+    /// Not supported: present is an error
+    X,
+}
+
+/// Site-specific conformance rules, loaded from JSON
+///
+/// ```json
+/// {
+///   "name": "Lab results",
+///   "message_type": "ORU^R01",
+///   "version": "2.5.1",
+///   "segments": [
+///     { "name": "PID", "usage": "R", "max": 1, "fields": [
+///         { "path": "3.1", "usage": "R", "max_length": 20 },
+///         { "path": "8", "values": ["F", "M", "O", "U"] }
+///     ] },
+///     { "name": "OBX", "usage": "RE", "fields": [
+///         { "path": "3.1", "usage": "R", "pattern": "^\\d+-\\d$" }
+///     ] },
+///     { "name": "NTE", "usage": "X" }
+///   ],
+///   "allow_unlisted_segments": false
+/// }
+/// ```
+///
+/// Field rules apply to every occurrence of their segment. `usage` is one of
+/// `R`, `RE`, `O` (the default) or `X`; `max` limits how many times a segment
+/// may occur. Values are checked as they appear in the message, without
+/// unescaping. A message of another type than `message_type` gets a single
+/// error rather than being checked against the rest of the profile.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Message type the profile applies to, e.g. `ORU^R01`
+    #[serde(default)]
+    pub message_type: Option<String>,
+    /// Required MSH-12 version
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub segments: Vec<SegmentRule>,
+    /// Whether segments not listed in `segments` may appear
+    #[serde(default = "default_true")]
+    pub allow_unlisted_segments: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Rules for one segment in a profile
+///
+/// Field paths and patterns are checked and compiled as the rule is deserialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "SegmentSpec")]
+pub struct SegmentRule {
+    pub name: String,
+    pub usage: Usage,
+    /// Maximum number of occurrences
+    pub max: Option<usize>,
+    pub fields: Vec<FieldRule>,
+}
+
+/// A segment rule as written, before its fields are compiled
+#[derive(Deserialize)]
+struct SegmentSpec {
+    name: String,
+    #[serde(default)]
+    usage: Usage,
+    #[serde(default)]
+    max: Option<usize>,
+    #[serde(default)]
+    fields: Vec<FieldRule>,
+}
+
+impl TryFrom<SegmentSpec> for SegmentRule {
+    type Error = HL7Error;
+
+    fn try_from(spec: SegmentSpec) -> Result<Self, HL7Error> {
+        let mut fields = spec.fields;
+        for field in &mut fields {
+            let path: TerserPath = format!("{}-{}", spec.name, field.path)
+                .parse()
+                .map_err(|_| HL7Error::ParseError(format!("Invalid profile path: {}-{}", spec.name, field.path)))?;
+            let pattern = field
+                .pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| HL7Error::ParseError(format!("Invalid pattern for {}: {}", path.segment, e)))?;
+            field.compiled = Some((path, pattern));
+        }
+        Ok(Self { name: spec.name, usage: spec.usage, max: spec.max, fields })
+    }
+}
+
+/// Rules for one field, component or subcomponent in a profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldRule {
+    /// Field number with optional component and subcomponent, e.g. `3` or `3.1`
+    pub path: String,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub max_length: Option<usize>,
+    /// Allowed values
+    #[serde(default)]
+    pub values: Option<Vec<String>>,
+    /// Regex the value must match
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Set by `SegmentRule`'s `TryFrom`, which knows the segment
+    #[serde(skip)]
+    compiled: Option<(TerserPath, Option<Regex>)>,
+}
+
+impl Profile {
+    /// Read a profile from JSON, checking its paths and patterns
+    pub fn from_json(json: &str) -> Result<Self, HL7Error> {
+        serde_json::from_str(json).map_err(|e| HL7Error::ParseError(format!("Invalid profile: {}", e)))
+    }
+
+    /// Problems found checking the message against the profile
+    pub fn check(&self, message: &Message) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        if let Some(message_type) = &self.message_type {
+            if message.message_type != *message_type {
+                // The rest of the profile doesn't apply to other message types
+                return vec![Issue::error(
+                    "MSH-9",
+                    format!("message type is {}, the profile is for {}", message.message_type, message_type),
+                )];
+            }
+        }
+        if let Some(version) = &self.version {
+            if message.version != *version {
+                issues.push(Issue::error(
+                    "MSH-12",
+                    format!("version is {}, the profile requires {}", message.version, version),
+                ));
+            }
+        }
+
+        if !self.allow_unlisted_segments {
+            for segment in &message.segments {
+                if segment.name != "MSH" && !self.segments.iter().any(|rule| rule.name == segment.name) {
+                    issues.push(Issue::error(segment.name.as_str(), "segment is not allowed by the profile"));
+                }
+            }
+        }
+
+        for rule in &self.segments {
+            let count = message.get_segments(&rule.name).len();
+            match rule.usage {
+                Usage::R if count == 0 => issues.push(Issue::error(rule.name.as_str(), "required segment is missing")),
+                Usage::RE if count == 0 => issues.push(Issue::warning(rule.name.as_str(), "segment is missing")),
+                Usage::X if count > 0 => issues.push(Issue::error(rule.name.as_str(), "segment is not supported")),
+                _ => {}
+            }
+            if let Some(max) = rule.max {
+                if count > max {
+                    issues.push(Issue::error(
+                        rule.name.as_str(),
+                        format!("segment occurs {} times, at most {} allowed", count, max),
+                    ));
+                }
+            }
+
+            for repetition in 1..=count {
+                for field in &rule.fields {
+                    field.check(message, repetition, &mut issues);
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+impl FieldRule {
+    fn check(&self, message: &Message, repetition: usize, issues: &mut Vec<Issue>) {
+        let Some((path, pattern)) = &self.compiled else {
+            return;
+        };
+        let path = TerserPath {
+            repetition,
+            ..path.clone()
+        };
+        let location = location(&path.segment, repetition, &self.path);
+        let value = terser::get_path(message, &path).filter(|v| !v.is_empty());
+
+        let Some(value) = value else {
+            match self.usage {
+                Usage::R => issues.push(Issue::error(location, "required value is missing")),
+                Usage::RE => issues.push(Issue::warning(location, "value is missing")),
+                _ => {}
+            }
+            return;
+        };
+
+        if self.usage == Usage::X {
+            issues.push(Issue::error(location.clone(), "value is not supported"));
+        }
+        if let Some(max_length) = self.max_length {
+            if value.chars().count() > max_length {
+                issues.push(Issue::error(
+                    location.clone(),
+                    format!("value is {} characters, at most {} allowed", value.chars().count(), max_length),
+                ));
+            }
+        }
+        if let Some(values) = &self.values {
+            if !values.contains(&value) {
+                issues.push(Issue::error(location.clone(), format!("'{}' is not an allowed value", value)));
+            }
+        }
+        if let Some(pattern) = pattern {
+            if !pattern.is_match(&value) {
+                issues.push(Issue::error(
+                    location,
+                    format!("'{}' does not match {}", value, pattern.as_str()),
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(not(feature = "std"))]
+use crate::prelude::*;
+use crate::{Component, Delimiters, Field};
+#[cfg(feature = "std")]
+use chrono::{NaiveDate, NaiveDateTime};
+use core::fmt;
+
+/// A field, component or subcomponent of a segment, or nothing if it's missing
+///
+/// Returned by `Segment::field` and narrowed with `component` and
+/// `subcomponent`, using 1-based numbers as in terser paths. Missing parts
+/// give a missing value rather than an error, so a chain only needs checking
+/// at the end:
+///
+/// ```
+/// use rust_hl7::Message;
+///
+/// let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\r\
+///     PID|1||12345^^^HOSP^MR||DOE^JANE||19800101\r\
+///     OBX|1|NM|WBC^LEUKOCYTES||10.5|10*3/uL").unwrap();
+/// let pid = message.get_segment("PID").unwrap();
+/// assert_eq!(pid.field(5).component(2).as_str(), Some("JANE"));
+/// assert_eq!(pid.field(7).as_date(), chrono::NaiveDate::from_ymd_opt(1980, 1, 1));
+/// assert_eq!(pid.field(5).component(9).as_str(), None);
+/// assert_eq!(message.get_segment("OBX").unwrap().field(5).as_number(), Some(10.5));
+/// ```
+///
+/// A value without components is its own first component and subcomponent,
+/// so `field(8).component(1)` reads a plain field as well as a composite one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Value<'a>(Inner<'a>);
+
+#[derive(Debug, Clone, Copy, Default)]
+enum Inner<'a> {
+    #[default]
+    Missing,
+    Field(&'a Field),
+    Component(&'a Component),
+    Text(&'a str),
+}
+
+impl<'a> Value<'a> {
+    pub(crate) fn field(field: Option<&'a Field>) -> Self {
+        Self(field.map_or(Inner::Missing, Inner::Field))
+    }
+
+    pub(crate) fn text(text: &'a str) -> Self {
+        Self(Inner::Text(text))
+    }
+
+    /// The component at a 1-based position
+    pub fn component(self, number: usize) -> Value<'a> {
+        match self.0 {
+            Inner::Field(field) => {
+                Self(number.checked_sub(1).and_then(|i| field.components.get(i)).map_or(Inner::Missing, Inner::Component))
+            }
+            Inner::Component(_) | Inner::Text(_) if number == 1 => self,
+            _ => Self::default(),
+        }
+    }
+
+    /// The subcomponent at a 1-based position, of the first component if this is a field
+    pub fn subcomponent(self, number: usize) -> Value<'a> {
+        let text = match self.0 {
+            Inner::Field(_) => return self.component(1).subcomponent(number),
+            Inner::Component(component) => component.value.as_str(),
+            Inner::Text(text) => text,
+            Inner::Missing => return self,
+        };
+        match number.checked_sub(1).and_then(|i| text.split(Delimiters::default().subcomponent).nth(i)) {
+            Some(subcomponent) => Self::text(subcomponent),
+            None => Self::default(),
+        }
+    }
+
+    /// Whether the value is in the message, even if empty
+    pub fn exists(&self) -> bool {
+        !matches!(self.0, Inner::Missing)
+    }
+
+    /// Whether the value is missing or empty
+    pub fn is_empty(&self) -> bool {
+        match self.0 {
+            Inner::Missing => true,
+            Inner::Field(field) => field.components.iter().all(|c| c.value.is_empty()),
+            Inner::Component(component) => component.value.is_empty(),
+            Inner::Text(text) => text.is_empty(),
+        }
+    }
+
+    /// The text of the value, still escaped; a field's text is its first component
+    ///
+    /// Use `to_string()` for a whole field with all its components.
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self.0 {
+            Inner::Missing => None,
+            Inner::Field(field) => field.components.first().map(|c| c.value.as_str()),
+            Inner::Component(component) => Some(&component.value),
+            Inner::Text(text) => Some(text),
+        }
+    }
+
+    /// The text with escape sequences such as `\F\` decoded
+    pub fn unescaped(&self) -> Option<String> {
+        self.as_str().map(|text| Delimiters::default().unescape(text))
+    }
+
+    /// The text as a number, e.g. an NM value
+    pub fn as_number(&self) -> Option<f64> {
+        self.as_str()?.trim().parse().ok()
+    }
+
+    /// The date of a DT or DTM value, which must give at least the day
+    #[cfg(feature = "std")]
+    pub fn as_date(&self) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(self.as_str()?.get(..8)?, "%Y%m%d").ok()
+    }
+
+    /// The date and time of a DTM value, ignoring any fraction or offset
+    ///
+    /// Hours, minutes and seconds that aren't given count as zero.
+    #[cfg(feature = "std")]
+    pub fn as_datetime(&self) -> Option<NaiveDateTime> {
+        let text = self.as_str()?;
+        let digits = text.split(['.', '+', '-']).next().unwrap_or_default();
+        if digits.len() < 8 || digits.len() > 14 || digits.len() % 2 != 0 {
+            return None;
+        }
+        let padded = format!("{:0<14}", digits);
+        NaiveDateTime::parse_from_str(&padded, "%Y%m%d%H%M%S").ok()
+    }
+}
+
+/// Writes the value as ER7, or nothing if it's missing
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Inner::Missing => Ok(()),
+            Inner::Field(field) => f.write_str(&field.to_hl7(&Delimiters::default())),
+            Inner::Component(component) => f.write_str(&component.value),
+            Inner::Text(text) => f.write_str(text),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! JavaScript bindings for the browser build
+//!
+//! Build for `wasm32-unknown-unknown` with the `wasm` feature and run
+//! `wasm-bindgen` over the result:
+//!
+//! ```text
+//! cargo rustc --release --lib --target wasm32-unknown-unknown --no-default-features --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/rust_hl7.wasm
+//! ```
+//!
+//! ```js
+//! import init, { parse } from "./pkg/rust_hl7.js";
+//!
+//! await init();
+//! const message = parse(text);           // throws on an invalid message
+//! message.get("PID-5.1");                // undefined if it's missing
+//! message.pretty();
+//! JSON.parse(message.validate("2.5"));   // [{ severity, location, message }]
+//! ```
+
+use crate::validation::Validator;
+use crate::{terser, Message};
+use wasm_bindgen::prelude::*;
+
+fn js_error<E: core::fmt::Display>(error: E) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+/// A parsed message
+#[wasm_bindgen(js_name = Message)]
+pub struct WasmMessage(Message);
+
+#[wasm_bindgen(js_class = Message)]
+impl WasmMessage {
+    /// Read a message from its canonical JSON form
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<WasmMessage, JsError> {
+        Message::from_json(json).map(WasmMessage).map_err(js_error)
+    }
+
+    /// The value at a terser path such as `PID-3.1`
+    pub fn get(&self, path: &str) -> Option<String> {
+        terser::get(&self.0, path)
+    }
+
+    /// Set the value at a terser path; the segment must exist
+    pub fn set(&mut self, path: &str, value: &str) -> Result<(), JsError> {
+        terser::set(&mut self.0, path, value).map_err(js_error)
+    }
+
+    /// MSH-9 as `type^trigger`
+    #[wasm_bindgen(getter, js_name = messageType)]
+    pub fn message_type(&self) -> String {
+        self.0.message_type.clone()
+    }
+
+    /// MSH-12
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> String {
+        self.0.version.clone()
+    }
+
+    /// MSH-10
+    #[wasm_bindgen(getter, js_name = controlId)]
+    pub fn control_id(&self) -> Option<String> {
+        self.0.control_id().map(str::to_string)
+    }
+
+    /// The segment names, in order
+    #[wasm_bindgen(getter)]
+    pub fn segments(&self) -> Vec<String> {
+        self.0.segments.iter().map(|segment| segment.name.clone()).collect()
+    }
+
+    /// The message as ER7 text
+    #[wasm_bindgen(js_name = toHl7)]
+    pub fn to_hl7(&self) -> String {
+        self.0.to_hl7()
+    }
+
+    /// The message in its canonical JSON form
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> String {
+        self.0.to_json()
+    }
+
+    /// One segment per line with each field's name
+    pub fn pretty(&self) -> String {
+        self.0.pretty_print()
+    }
+
+    /// Every problem found, as a JSON array of `{ severity, location, message }`
+    ///
+    /// `version`, if given, is the HL7 version MSH-12 must be.
+    pub fn validate(&self, version: Option<String>) -> Result<String, JsError> {
+        let validator = match version {
+            Some(version) => Validator::new().version(version),
+            None => Validator::new(),
+        };
+        serde_json::to_string(&validator.validate(&self.0)).map_err(js_error)
+    }
+}
+
+/// Parse a message from its ER7 (pipe-delimited) text
+#[wasm_bindgen]
+pub fn parse(text: &str) -> Result<WasmMessage, JsError> {
+    Message::parse(text).map(WasmMessage).map_err(js_error)
+}
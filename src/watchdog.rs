@@ -0,0 +1,299 @@
+use crate::middleware::{Middleware, Next};
+use crate::mllp::AckMode;
+use crate::{terser, HL7Error, Message};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// What an alert is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// A watched sender hasn't sent anything for too long
+    Silence,
+    /// Too many messages were answered with a NACK
+    NackRate,
+}
+
+/// A watched condition starting or clearing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    /// The sending application, for silence alerts
+    pub source: Option<String>,
+    /// False when the condition starts, true when it clears
+    pub resolved: bool,
+    /// A description for people, e.g. "No messages from 'REG' for 120 minutes"
+    pub message: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Called with each alert a watchdog fires
+pub type AlertHandler = Arc<dyn Fn(&Alert) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+struct NackRule {
+    threshold: f64,
+    window: Duration,
+    min_messages: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // When each sending application was last seen
+    last_seen: HashMap<String, (Instant, DateTime<Utc>)>,
+    // When each message was answered and whether it was NACKed
+    outcomes: VecDeque<(Instant, bool)>,
+    // Conditions currently alerting
+    firing: HashSet<(AlertKind, Option<String>)>,
+}
+
+/// Fires alerts when a feed goes quiet or its NACK rate climbs
+///
+/// Layer an `Arc<Watchdog>` in a middleware chain so it sees every message and
+/// whether it was acknowledged, and call `spawn` to check the conditions
+/// periodically. Each condition fires once when it starts and once more, with
+/// `resolved` set, when it clears. Messages that fail to parse never reach the
+/// middleware chain, so they don't count towards the NACK rate. Set `ack_mode`
+/// to the server's so only responses the sender saw as NACKs are counted.
+///
+/// ```ignore
+/// let watchdog = Arc::new(
+///     Watchdog::new()
+///         .watch_source("REG", Duration::from_secs(30 * 60))
+///         .nack_rate(0.1, Duration::from_secs(15 * 60), 20)
+///         .webhook("https://hooks.example.org/hl7")
+///         .on_alert(|alert| eprintln!("{}", alert.message)),
+/// );
+/// watchdog.spawn(Duration::from_secs(30));
+/// let server = MllpServer::new("0.0.0.0:2575", handler).with_middleware(Chain::new().layer(watchdog));
+/// ```
+pub struct Watchdog {
+    sources: Vec<(String, Duration)>,
+    nack_rule: Option<NackRule>,
+    ack_mode: AckMode,
+    handlers: Vec<AlertHandler>,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            sources: Vec::new(),
+            nack_rule: None,
+            ack_mode: AckMode::default(),
+            handlers: Vec::new(),
+            started: Instant::now(),
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("sources", &self.sources)
+            .field("nack_rule", &self.nack_rule)
+            .field("ack_mode", &self.ack_mode)
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Alert when the sending application (MSH-3) hasn't sent a message for `quiet_after`
+    ///
+    /// Until its first message, a sender is measured from when the watchdog was created.
+    pub fn watch_source<S: ToString>(mut self, sender: S, quiet_after: Duration) -> Self {
+        self.sources.push((sender.to_string(), quiet_after));
+        self
+    }
+
+    /// Alert when more than `threshold` (0.0 to 1.0) of the messages answered
+    /// within `window` were NACKed, once at least `min_messages` were answered
+    pub fn nack_rate(mut self, threshold: f64, window: Duration, min_messages: usize) -> Self {
+        self.nack_rule = Some(NackRule { threshold, window, min_messages });
+        self
+    }
+
+    /// How the server in front of this watchdog acknowledges, `AfterProcessing` by default
+    ///
+    /// In `Immediate` mode the sender was ACKed before the handler ran, so
+    /// handler errors aren't NACKs. In `Application` mode the handler's
+    /// response is a NACK when its MSA-1 isn't AA or CA.
+    pub fn ack_mode(mut self, mode: AckMode) -> Self {
+        self.ack_mode = mode;
+        self
+    }
+
+    /// Call `handler` with every alert
+    pub fn on_alert<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Alert) + Send + Sync + 'static,
+    {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// POST every alert as JSON to an `http://` or `https://` URL
+    ///
+    /// Requests are sent in the background and failures are logged. HTTPS needs
+    /// the `tls` feature.
+    pub fn webhook<U: ToString>(self, url: U) -> Self {
+        let url = url.to_string();
+        self.on_alert(move |alert| {
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                error!("Can't send alert to {} outside a Tokio runtime", url);
+                return;
+            };
+            let url = url.clone();
+            let body = serde_json::to_string(alert).unwrap_or_default();
+            runtime.spawn(async move {
+                if let Err(e) = post_json(&url, &body).await {
+                    error!("Failed to send alert to {}: {}", url, e);
+                }
+            });
+        })
+    }
+
+    /// Evaluate every condition now, firing and returning alerts for those that started or cleared
+    pub fn check(&self) -> Vec<Alert> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut alerts = Vec::new();
+
+        for (sender, quiet_after) in &self.sources {
+            let last_seen = state.last_seen.get(sender).copied();
+            let quiet_for = now.duration_since(last_seen.map_or(self.started, |(at, _)| at));
+            let key = (AlertKind::Silence, Some(sender.clone()));
+            let message = match last_seen {
+                _ if quiet_for < *quiet_after => format!("Messages from '{}' resumed", sender),
+                Some((_, at)) => format!(
+                    "No messages from '{}' for {} minutes, since {}",
+                    sender,
+                    quiet_for.as_secs() / 60,
+                    at.to_rfc3339()
+                ),
+                None => format!("No messages from '{}' in the {} minutes since startup", sender, quiet_for.as_secs() / 60),
+            };
+            if let Some(alert) = transition(&mut state.firing, key, quiet_for >= *quiet_after, message) {
+                alerts.push(alert);
+            }
+        }
+
+        if let Some(rule) = self.nack_rule {
+            while state.outcomes.front().is_some_and(|(at, _)| now.duration_since(*at) > rule.window) {
+                state.outcomes.pop_front();
+            }
+            let messages = state.outcomes.len();
+            let nacks = state.outcomes.iter().filter(|(_, nack)| *nack).count();
+            let rate = if messages == 0 { 0.0 } else { nacks as f64 / messages as f64 };
+            let firing = messages >= rule.min_messages && rate > rule.threshold;
+            let message = format!(
+                "NACK rate {:.0}% ({} of {}) over the last {} minutes, threshold {:.0}%",
+                rate * 100.0,
+                nacks,
+                messages,
+                rule.window.as_secs() / 60,
+                rule.threshold * 100.0
+            );
+            if let Some(alert) = transition(&mut state.firing, (AlertKind::NackRate, None), firing, message) {
+                alerts.push(alert);
+            }
+        }
+        drop(state);
+
+        for alert in &alerts {
+            if alert.resolved {
+                info!("Watchdog: {}", alert.message);
+            } else {
+                warn!("Watchdog: {}", alert.message);
+            }
+            for handler in &self.handlers {
+                handler(alert);
+            }
+        }
+        alerts
+    }
+
+    /// Check the conditions every `interval` in the background
+    pub fn spawn(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let watchdog = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                watchdog.check();
+            }
+        })
+    }
+
+    /// Run `next` for a message received by a server acknowledging in `mode`, recording the outcome
+    ///
+    /// For a watchdog shared by servers with different ack modes; the middleware uses `ack_mode`.
+    pub fn observe(&self, mode: AckMode, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        let sender = terser::get(&message, "MSH-3.1").unwrap_or_default();
+        let result = next(message);
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.last_seen.insert(sender, (now, Utc::now()));
+        if self.nack_rule.is_some() {
+            state.outcomes.push_back((now, nacked(mode, &result)));
+        }
+        result
+    }
+}
+
+impl Middleware for Watchdog {
+    fn handle(&self, message: Message, next: Next<'_>) -> Result<Message, HL7Error> {
+        self.observe(self.ack_mode, message, next)
+    }
+}
+
+/// Whether the sender was answered with a NACK for a handler's result
+fn nacked(mode: AckMode, result: &Result<Message, HL7Error>) -> bool {
+    match (mode, result) {
+        (AckMode::Immediate, _) => false,
+        (_, Err(_)) => true,
+        (AckMode::AfterProcessing, Ok(_)) => false,
+        (AckMode::Application, Ok(response)) => {
+            !matches!(terser::get(response, "MSA-1").as_deref(), Some("AA" | "CA"))
+        }
+    }
+}
+
+/// An alert if a condition changed from or to firing
+fn transition(
+    firing: &mut HashSet<(AlertKind, Option<String>)>,
+    key: (AlertKind, Option<String>),
+    now_firing: bool,
+    message: String,
+) -> Option<Alert> {
+    let changed = if now_firing { firing.insert(key.clone()) } else { firing.remove(&key) };
+    changed.then(|| Alert {
+        kind: key.0,
+        source: key.1,
+        resolved: !now_firing,
+        message,
+        at: Utc::now(),
+    })
+}
+
+/// POST a JSON body, failing unless the response status is 2xx
+async fn post_json(url: &str, body: &str) -> Result<(), String> {
+    match crate::webhook::post(url, "application/json", &[], body, Duration::from_secs(10)).await? {
+        status if (200..300).contains(&status) => Ok(()),
+        status => Err(format!("unexpected response status {}", status)),
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{HL7Error, Message};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn, Instrument, Span};
+
+/// Errors that can occur posting to a webhook
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Webhook request error: {0}")]
+    RequestError(String),
+
+    #[error("Webhook responded {0}")]
+    Status(u16),
+
+    #[error("Message can't be posted: {0}")]
+    InvalidMessage(#[from] HL7Error),
+}
+
+/// What a webhook receives for each message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The canonical JSON form (`Message::to_json`)
+    #[default]
+    Json,
+    /// A FHIR `message` bundle; requires the `fhir` feature
+    Fhir,
+}
+
+/// POSTs each message as JSON to an `http://` or `https://` URL
+///
+/// With a secret, each request is signed: `X-HL7-Timestamp` holds the Unix
+/// time it was sent and `X-HL7-Signature` is `sha256=` followed by the hex
+/// HMAC-SHA256 of the timestamp, a `.` and the body. Receivers should
+/// recompute it and reject old timestamps. `X-HL7-Message-Type` and
+/// `X-HL7-Control-ID` let them filter without parsing.
+///
+/// Failed requests are retried, waiting `retry_delay`, then twice that, and
+/// so on; responses other than 408 and 429 in the 4xx range aren't, since the
+/// same request would fail again. At most `max_concurrent` requests are open
+/// at once, shared by clones. HTTPS needs the `tls` feature.
+///
+/// Messages routed straight to a webhook are posted in the background, and
+/// one that fails every try is only logged. At most `max_backlog` posts wait
+/// at once, 1,000 unless set; past that, routed messages are refused so the
+/// sender gets a NACK. For delivery that survives failures and restarts, wrap
+/// the sink in a spooled `OrderedSink`, as a configured webhook with a
+/// `spool` is.
+///
+/// ```
+/// use rust_hl7::router::Destination;
+/// use rust_hl7::webhook::{WebhookFormat, WebhookSink};
+/// use std::time::Duration;
+///
+/// let sink = WebhookSink::new("https://hooks.example.org/hl7")
+///     .format(WebhookFormat::Json)
+///     .secret("s3cret")
+///     .with_retries(5, Duration::from_secs(1))
+///     .max_concurrent(8);
+/// let destination = Destination::Webhook(sink);
+/// ```
+#[derive(Clone)]
+pub struct WebhookSink {
+    url: String,
+    format: WebhookFormat,
+    secret: Option<Vec<u8>>,
+    max_attempts: u32,
+    retry_delay: Duration,
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+    max_backlog: usize,
+    /// Posts running in the background, shared by clones
+    backlog: Arc<AtomicUsize>,
+}
+
+impl std::fmt::Debug for WebhookSink {
+    // The secret stays out of logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookSink")
+            .field("url", &self.url)
+            .field("format", &self.format)
+            .field("signed", &self.secret.is_some())
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebhookSink {
+    /// Post canonical JSON to `url`, unsigned, trying each message 3 times with 4 requests open at most
+    pub fn new<U: ToString>(url: U) -> Self {
+        Self {
+            url: url.to_string(),
+            format: WebhookFormat::default(),
+            secret: None,
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            permits: Arc::new(Semaphore::new(4)),
+            max_backlog: 1000,
+            backlog: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Post messages in this form
+    pub fn format(mut self, format: WebhookFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sign requests with this key
+    pub fn secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.secret = Some(secret.as_ref().to_vec());
+        self
+    }
+
+    /// Try each message this many times, waiting `retry_delay`, then twice that, and so on between tries
+    pub fn with_retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Give up on a request that hasn't been answered after this long (10 seconds unless set)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep at most this many requests open at once
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.permits = Arc::new(Semaphore::new(max_concurrent.max(1)));
+        self
+    }
+
+    /// Refuse routed messages once this many are waiting to be posted
+    pub fn max_backlog(mut self, max_backlog: usize) -> Self {
+        self.max_backlog = max_backlog.max(1);
+        self
+    }
+
+    /// URL messages are posted to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The body posted for a message, and its content type
+    pub fn body(&self, message: &Message) -> Result<(String, &'static str), WebhookError> {
+        match self.format {
+            WebhookFormat::Json => Ok((message.to_json(), "application/json")),
+            #[cfg(feature = "fhir")]
+            WebhookFormat::Fhir => {
+                let bundle = crate::fhir::bundle(message, &crate::fhir::BundleOptions::default())?;
+                Ok((bundle.to_string(), "application/fhir+json"))
+            }
+            #[cfg(not(feature = "fhir"))]
+            WebhookFormat::Fhir => Err(WebhookError::RequestError("FHIR webhooks require the `fhir` feature".to_string())),
+        }
+    }
+
+    /// The signature headers for a body sent at `timestamp`, if requests are signed
+    pub fn signature(&self, timestamp: u64, body: &str) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}.{}", timestamp, body).as_bytes());
+        Some(format!("sha256={}", hex(&mac.finalize().into_bytes())))
+    }
+
+    /// Post a message, retrying until it's accepted or the tries run out
+    pub async fn post(&self, message: &Message) -> Result<(), WebhookError> {
+        let (body, content_type) = self.body(message)?;
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let mut headers = vec![("X-HL7-Message-Type", message.message_type.clone())];
+            if let Some(control_id) = message.control_id() {
+                headers.push(("X-HL7-Control-ID", control_id.to_string()));
+            }
+            if let Some(signature) = self.signature(timestamp, &body) {
+                headers.push(("X-HL7-Timestamp", timestamp.to_string()));
+                headers.push(("X-HL7-Signature", signature));
+            }
+            // Held for the request only, so a retry's wait doesn't hold up other messages
+            let permit = self.permits.acquire().await.map_err(|e| WebhookError::RequestError(e.to_string()))?;
+            let result = post(&self.url, content_type, &headers, &body, self.timeout).await;
+            drop(permit);
+            let error = match result {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                Ok(status) => WebhookError::Status(status),
+                Err(e) => WebhookError::RequestError(e),
+            };
+            let permanent = matches!(error, WebhookError::Status(status) if (400..500).contains(&status) && status != 408 && status != 429);
+            if permanent || attempt >= self.max_attempts {
+                return Err(error);
+            }
+            warn!("Webhook {} failed on try {} of {}: {}", self.url, attempt, self.max_attempts, error);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Post a message in the background, logging the outcome, and resolving to whether it was posted
+    ///
+    /// Fails straight away if `max_backlog` posts are already waiting.
+    pub fn spawn_post(&self, message: &Message) -> Result<tokio::task::JoinHandle<bool>, WebhookError> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| WebhookError::RequestError("Webhook destinations require a Tokio runtime".to_string()))?;
+        self.backlog
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < self.max_backlog).then_some(count + 1))
+            .map_err(|count| WebhookError::RequestError(format!("{} posts to {} are already waiting", count, self.url)))?;
+        let sink = self.clone();
+        let message = message.clone();
+        Ok(runtime.spawn(
+            async move {
+                let posted = match sink.post(&message).await {
+                    Ok(()) => {
+                        info!("Posted message to {}", sink.url);
+                        true
+                    }
+                    Err(e) => {
+                        error!("Failed to post message to {}: {}", sink.url, e);
+                        false
+                    }
+                };
+                sink.backlog.fetch_sub(1, Ordering::SeqCst);
+                posted
+            }
+            .instrument(Span::current()),
+        ))
+    }
+
+    /// Check that the webhook's host accepts a connection within `timeout`
+    pub async fn check(&self, timeout: Duration) -> Result<(), WebhookError> {
+        let url = reqwest::Url::parse(&self.url).map_err(|e| WebhookError::RequestError(format!("{}: {}", self.url, e)))?;
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(WebhookError::RequestError(format!("Unsupported webhook URL: {}", self.url)));
+        };
+        // IPv6 hosts come bracketed, as written in the URL
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(WebhookError::RequestError(format!("{}:{} is unreachable: {}", host, port, e))),
+            Err(_) => Err(WebhookError::RequestError(format!("{}:{} didn't accept a connection within {:?}", host, port, timeout))),
+        }
+    }
+}
+
+/// The HTTP client shared by webhooks, watchdog alerts and FHIR posts
+fn client() -> Result<&'static reqwest::Client, String> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let builder = reqwest::Client::builder();
+    #[cfg(feature = "tls")]
+    let builder = builder.use_preconfigured_tls(crate::tls::client_config(None).map_err(|e| e.to_string())?.as_ref().clone());
+    let client = builder.build().map_err(|e| e.to_string())?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// POST a body, returning the response status
+pub(crate) async fn post(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &str,
+    timeout: Duration,
+) -> Result<u16, String> {
+    if url.starts_with("https://") && cfg!(not(feature = "tls")) {
+        return Err("HTTPS webhooks require the `tls` feature".to_string());
+    }
+    let mut request = client()?
+        .post(url)
+        .timeout(timeout)
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .body(body.to_string());
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    Ok(response.status().as_u16())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that run message handlers
+///
+/// Handlers that convert to FHIR or validate large messages tie up a thread
+/// for a while. Run on a pool of their own, they can't use up Tokio's
+/// blocking threads or starve other work, and how many run at once is
+/// capped by the pool's size. Jobs wait in order for a free thread, those
+/// with a higher priority ahead of the rest (see `lanes::PriorityLanes`). A
+/// panicking job is reported to its caller and the thread carries on.
+///
+/// ```
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// use rust_hl7::workers::WorkerPool;
+///
+/// let pool = WorkerPool::new(4);
+/// assert_eq!(pool.run(|| 6 * 7).await.unwrap(), 42);
+/// assert!(pool.run(|| panic!("bad message")).await.is_err());
+/// # });
+/// ```
+pub struct WorkerPool {
+    queue: Arc<Queue>,
+    threads: usize,
+}
+
+/// Jobs waiting for a thread, one list per priority with 0 the highest
+#[derive(Default)]
+struct Queue {
+    waiting: Mutex<Waiting>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Waiting {
+    by_priority: Vec<VecDeque<Job>>,
+    closed: bool,
+}
+
+impl Queue {
+    /// The next job, from the highest priority that has one, or `None` once the pool is dropped
+    fn take(&self) -> Option<Job> {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(job) = waiting.by_priority.iter_mut().find_map(VecDeque::pop_front) {
+                return Some(job);
+            }
+            if waiting.closed {
+                return None;
+            }
+            waiting = self.ready.wait(waiting).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn push(&self, priority: usize, job: Job) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        if waiting.by_priority.len() <= priority {
+            waiting.by_priority.resize_with(priority + 1, VecDeque::new);
+        }
+        waiting.by_priority[priority].push_back(job);
+        self.ready.notify_one();
+    }
+}
+
+/// A job that panicked, or whose pool shut down before running it
+#[derive(Debug, thiserror::Error)]
+#[error("Worker job failed: {0}")]
+pub struct WorkerError(String);
+
+impl WorkerPool {
+    /// Start a pool of `threads` threads, at least one
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let queue = Arc::new(Queue::default());
+        for n in 0..threads {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("hl7-worker-{}", n))
+                .spawn(move || {
+                    // The lock is released before the job runs, so other threads can take the next one
+                    while let Some(job) = queue.take() {
+                        job();
+                    }
+                })
+                .expect("failed to start worker thread");
+        }
+        Self { queue, threads }
+    }
+
+    /// How many threads the pool has
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// How many jobs are waiting for a thread
+    pub fn queued(&self) -> usize {
+        let waiting = self.queue.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        waiting.by_priority.iter().map(VecDeque::len).sum()
+    }
+
+    /// Run a job on the pool and wait for its result
+    pub async fn run<F, T>(&self, job: F) -> Result<T, WorkerError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_at(0, job).await
+    }
+
+    /// Run a job ahead of any waiting with a lower priority, where 0 is the highest
+    pub async fn run_at<F, T>(&self, priority: usize, job: F) -> Result<T, WorkerError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.spawn_at(priority, move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        match receiver.await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(panic)) => Err(WorkerError(panic_message(&panic))),
+            Err(_) => Err(WorkerError("the pool shut down".to_string())),
+        }
+    }
+
+    /// Run a job on the pool without waiting for it
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.spawn_at(0, job);
+    }
+
+    /// Run a job without waiting for it, ahead of any waiting with a lower priority
+    pub fn spawn_at<F: FnOnce() + Send + 'static>(&self, priority: usize, job: F) {
+        self.queue.push(
+            priority,
+            Box::new(move || {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }),
+        );
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Let the threads finish the jobs already waiting and stop
+    fn drop(&mut self) {
+        self.queue.waiting.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.queue.ready.notify_all();
+    }
+}
+
+impl std::fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerPool").field("threads", &self.threads).finish()
+    }
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => format!("panicked: {}", message),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => format!("panicked: {}", message),
+            None => "panicked".to_string(),
+        },
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{Delimiters, Message};
+
+impl Message {
+    /// Convert the message to HL7 v2 XML
+    ///
+    /// ```xml
+    /// <?xml version="1.0" encoding="UTF-8"?>
+    /// <ORU_R01 xmlns="urn:hl7-org:v2xml">
+    ///   <MSH>
+    ///     <MSH.1>|</MSH.1>
+    ///     <MSH.2>^~\&amp;</MSH.2>
+    ///     <MSH.9><MSH.9.1>ORU</MSH.9.1><MSH.9.2>R01</MSH.9.2></MSH.9>
+    ///     ...
+    ///   </MSH>
+    ///   <PID>
+    ///     <PID.3><PID.3.1>12345</PID.3.1><PID.3.4>HOSP</PID.3.4></PID.3>
+    ///     <PID.8>M</PID.8>
+    ///   </PID>
+    /// </ORU_R01>
+    /// ```
+    ///
+    /// The root element is named after the message structure in MSH-9.3, or the
+    /// message code and trigger event. Segments appear in order without group
+    /// elements, and components are named by position (`PID.5.1`) rather than
+    /// by data type, since the crate has no message structure or data type
+    /// tables. Repetitions become repeated field elements, empty values are
+    /// left out, and escape sequences are decoded.
+    pub fn to_xml(&self) -> String {
+        let delimiters = Delimiters::default();
+        let root = crate::terser::get(self, "MSH-9.3")
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| self.message_type.replace(delimiters.component, "_"));
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<{} xmlns=\"urn:hl7-org:v2xml\">\n", root));
+        for segment in &self.segments {
+            xml.push_str(&format!("  <{}>\n", segment.name));
+            if segment.name == "MSH" {
+                xml.push_str(&format!("    <MSH.1>{}</MSH.1>\n", escape(&delimiters.field.to_string())));
+            }
+            for (index, field) in segment.fields.iter().enumerate() {
+                let number = if segment.name == "MSH" { index + 2 } else { index + 1 };
+                let name = format!("{}.{}", segment.name, number);
+                let text = field.to_hl7(&delimiters);
+                // The encoding characters are never split
+                if segment.name == "MSH" && number == 2 {
+                    xml.push_str(&format!("    <{0}>{1}</{0}>\n", name, escape(&text)));
+                    continue;
+                }
+                for repetition in text.split(delimiters.repetition).filter(|r| !r.is_empty()) {
+                    xml.push_str(&format!("    <{0}>{1}</{0}>\n", name, field_content(&name, repetition, &delimiters)));
+                }
+            }
+            xml.push_str(&format!("  </{}>\n", segment.name));
+        }
+        xml.push_str(&format!("</{}>\n", root));
+        xml
+    }
+}
+
+/// The content of one field repetition: its text, or an element per component
+fn field_content(name: &str, text: &str, delimiters: &Delimiters) -> String {
+    if !text.contains([delimiters.component, delimiters.subcomponent]) {
+        return escape(&delimiters.unescape(text));
+    }
+    let mut content = String::new();
+    for (index, component) in text.split(delimiters.component).enumerate() {
+        if component.is_empty() {
+            continue;
+        }
+        let component_name = format!("{}.{}", name, index + 1);
+        if !component.contains(delimiters.subcomponent) {
+            content.push_str(&element(&component_name, component, delimiters));
+            continue;
+        }
+        content.push_str(&format!("<{}>", component_name));
+        for (index, subcomponent) in component.split(delimiters.subcomponent).enumerate() {
+            if !subcomponent.is_empty() {
+                content.push_str(&element(&format!("{}.{}", component_name, index + 1), subcomponent, delimiters));
+            }
+        }
+        content.push_str(&format!("</{}>", component_name));
+    }
+    content
+}
+
+fn element(name: &str, text: &str, delimiters: &Delimiters) -> String {
+    format!("<{0}>{1}</{0}>", name, escape(&delimiters.unescape(text)))
+}
+
+/// Escape text for XML element content
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,34 @@
+//! `#[derive(Hl7Message)]` in a `no_std` crate, where only `core` and `alloc` can be named
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use rust_hl7::mapping::Hl7Message;
+use rust_hl7::{Message, Segment};
+
+#[derive(Hl7Message)]
+#[hl7(message = "ORU^R01")]
+struct LabResult {
+    #[hl7(segment = "PID")]
+    patient: Segment,
+    #[hl7(group)]
+    orders: Vec<Order>,
+}
+
+#[derive(Hl7Message)]
+struct Order {
+    #[hl7(segment = "OBR")]
+    request: Segment,
+    #[hl7(segment = "OBX")]
+    results: Vec<Segment>,
+}
+
+#[test]
+fn derive_without_std() {
+    let message = Message::parse("MSH|^~\\&|LAB|HOSP|||20240501||ORU^R01|1|P|2.5\rPID|1||12345\rOBR|1||A1|CBC\rOBX|1|NM|WBC||10.5").unwrap();
+    let result = LabResult::from_message(&message).unwrap();
+    assert_eq!(result.patient.field(3).as_str(), Some("12345"));
+    assert_eq!(result.orders[0].request.field(4).as_str(), Some("CBC"));
+    assert_eq!(result.orders[0].results.len(), 1);
+}